@@ -0,0 +1,470 @@
+//! The bitmanip module decodes and executes the instructions of the bit-manipulation extensions
+//! Zba (address generation), Zbb (basic bit manipulation), Zbc (carry-less multiplication) and
+//! Zbs (single-bit instructions), and of the ones for cryptography, Zbkb, Zbkc and Zbkx, which
+//! mostly overlap them. They share the opcodes of the integer register-immediate and
+//! register-register instructions, OP-IMM, OP-IMM-32, OP and OP-32, and only differ from the base
+//! instructions in funct7 or the upper bits of the immediate.
+
+use crate::isa::{ZBA, ZBB, ZBC, ZBKB, ZBKC, ZBKX, ZBS};
+
+/// A bit-manipulation instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    // Zba
+    AddUw,
+    Sh1add,
+    Sh1addUw,
+    Sh2add,
+    Sh2addUw,
+    Sh3add,
+    Sh3addUw,
+    SlliUw,
+    // Zbb
+    Andn,
+    Orn,
+    Xnor,
+    Clz,
+    Clzw,
+    Ctz,
+    Ctzw,
+    Cpop,
+    Cpopw,
+    Max,
+    Maxu,
+    Min,
+    Minu,
+    SextB,
+    SextH,
+    ZextH,
+    Rol,
+    Rolw,
+    Ror,
+    Rori,
+    Roriw,
+    Rorw,
+    OrcB,
+    Rev8,
+    // Zbc
+    Clmul,
+    Clmulh,
+    Clmulr,
+    // Zbkb
+    Pack,
+    Packh,
+    Packw,
+    Brev8,
+    // Zbkx
+    Xperm4,
+    Xperm8,
+    // Zbs
+    Bclr,
+    Bclri,
+    Bext,
+    Bexti,
+    Binv,
+    Binvi,
+    Bset,
+    Bseti,
+}
+
+/// Return the bit-manipulation instruction `inst` encodes, or `None` if it's none.
+pub fn decode(inst: u32) -> Option<Op> {
+    let opcode = inst & 0x0000007f;
+    let funct3 = (inst & 0x00007000) >> 12;
+    let funct7 = (inst & 0xfe000000) >> 25;
+    // The rs2 field, which selects the operation of the unary instructions.
+    let rs2 = (inst & 0x01f00000) >> 20;
+    // The upper bits of the immediate of the instructions with a 6-bit shift amount.
+    let funct6 = funct7 >> 1;
+    let imm = inst >> 20;
+
+    let op = match (opcode, funct3) {
+        (0x13, 0x1) => match (funct6, funct7, rs2) {
+            (_, 0x30, 0x00) => Op::Clz,
+            (_, 0x30, 0x01) => Op::Ctz,
+            (_, 0x30, 0x02) => Op::Cpop,
+            (_, 0x30, 0x04) => Op::SextB,
+            (_, 0x30, 0x05) => Op::SextH,
+            (0x0a, _, _) => Op::Bseti,
+            (0x12, _, _) => Op::Bclri,
+            (0x1a, _, _) => Op::Binvi,
+            _ => return None,
+        },
+        (0x13, 0x5) => match (imm, funct6) {
+            (0x287, _) => Op::OrcB,
+            (0x687, _) => Op::Brev8,
+            (0x6b8, _) => Op::Rev8,
+            (_, 0x12) => Op::Bexti,
+            (_, 0x18) => Op::Rori,
+            _ => return None,
+        },
+        (0x1b, 0x1) => match (funct6, funct7, rs2) {
+            (0x02, _, _) => Op::SlliUw,
+            (_, 0x30, 0x00) => Op::Clzw,
+            (_, 0x30, 0x01) => Op::Ctzw,
+            (_, 0x30, 0x02) => Op::Cpopw,
+            _ => return None,
+        },
+        (0x1b, 0x5) if funct7 == 0x30 => Op::Roriw,
+        (0x33, _) => match (funct7, funct3) {
+            (0x05, 0x1) => Op::Clmul,
+            (0x05, 0x2) => Op::Clmulr,
+            (0x05, 0x3) => Op::Clmulh,
+            (0x05, 0x4) => Op::Min,
+            (0x05, 0x5) => Op::Minu,
+            (0x05, 0x6) => Op::Max,
+            (0x05, 0x7) => Op::Maxu,
+            (0x04, 0x4) => Op::Pack,
+            (0x04, 0x7) => Op::Packh,
+            (0x10, 0x2) => Op::Sh1add,
+            (0x10, 0x4) => Op::Sh2add,
+            (0x10, 0x6) => Op::Sh3add,
+            (0x14, 0x1) => Op::Bset,
+            (0x14, 0x2) => Op::Xperm4,
+            (0x14, 0x4) => Op::Xperm8,
+            (0x20, 0x4) => Op::Xnor,
+            (0x20, 0x6) => Op::Orn,
+            (0x20, 0x7) => Op::Andn,
+            (0x24, 0x1) => Op::Bclr,
+            (0x24, 0x5) => Op::Bext,
+            (0x30, 0x1) => Op::Rol,
+            (0x30, 0x5) => Op::Ror,
+            (0x34, 0x1) => Op::Binv,
+            _ => return None,
+        },
+        (0x3b, _) => match (funct7, funct3) {
+            (0x04, 0x0) => Op::AddUw,
+            (0x04, 0x4) if rs2 == 0 => Op::ZextH,
+            (0x04, 0x4) => Op::Packw,
+            (0x10, 0x2) => Op::Sh1addUw,
+            (0x10, 0x4) => Op::Sh2addUw,
+            (0x10, 0x6) => Op::Sh3addUw,
+            (0x30, 0x1) => Op::Rolw,
+            (0x30, 0x5) => Op::Rorw,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(op)
+}
+
+/// Return the high 64 bits and the low 64 bits of the carry-less product of `a` and `b`.
+fn clmul(a: u64, b: u64) -> (u64, u64) {
+    let (mut high, mut low) = (0, 0);
+    for i in 0..64 {
+        if (b >> i) & 1 != 0 {
+            low ^= a << i;
+            if i > 0 {
+                high ^= a >> (64 - i);
+            }
+        }
+    }
+    (high, low)
+}
+
+impl Op {
+    /// Return the extensions that have the instruction, as `Z*` constants. Any of them
+    /// implements it.
+    pub fn extension(self) -> u64 {
+        use Op::*;
+        match self {
+            AddUw | Sh1add | Sh1addUw | Sh2add | Sh2addUw | Sh3add | Sh3addUw | SlliUw => ZBA,
+            Clmul | Clmulh => ZBC | ZBKC,
+            Clmulr => ZBC,
+            Bclr | Bclri | Bext | Bexti | Binv | Binvi | Bset | Bseti => ZBS,
+            // zext.h is packw with rs2 = x0.
+            Andn | Orn | Xnor | Rol | Rolw | Ror | Rori | Roriw | Rorw | Rev8 | ZextH => ZBB | ZBKB,
+            Pack | Packh | Packw | Brev8 => ZBKB,
+            Xperm4 | Xperm8 => ZBKX,
+            _ => ZBB,
+        }
+    }
+
+    /// Return true if the instruction only has the source operand rs1.
+    pub fn is_unary(self) -> bool {
+        use Op::*;
+        matches!(
+            self,
+            Clz | Clzw | Ctz | Ctzw | Cpop | Cpopw | SextB | SextH | ZextH | OrcB | Rev8 | Brev8
+        )
+    }
+
+    /// Return true if the second source operand is a shift amount in the immediate.
+    pub fn is_immediate(self) -> bool {
+        use Op::*;
+        matches!(self, SlliUw | Rori | Roriw | Bclri | Bexti | Binvi | Bseti)
+    }
+
+    /// Return the assembler mnemonic of the instruction.
+    pub fn name(self) -> &'static str {
+        use Op::*;
+        match self {
+            AddUw => "add.uw",
+            Sh1add => "sh1add",
+            Sh1addUw => "sh1add.uw",
+            Sh2add => "sh2add",
+            Sh2addUw => "sh2add.uw",
+            Sh3add => "sh3add",
+            Sh3addUw => "sh3add.uw",
+            SlliUw => "slli.uw",
+            Andn => "andn",
+            Orn => "orn",
+            Xnor => "xnor",
+            Clz => "clz",
+            Clzw => "clzw",
+            Ctz => "ctz",
+            Ctzw => "ctzw",
+            Cpop => "cpop",
+            Cpopw => "cpopw",
+            Max => "max",
+            Maxu => "maxu",
+            Min => "min",
+            Minu => "minu",
+            SextB => "sext.b",
+            SextH => "sext.h",
+            ZextH => "zext.h",
+            Rol => "rol",
+            Rolw => "rolw",
+            Ror => "ror",
+            Rori => "rori",
+            Roriw => "roriw",
+            Rorw => "rorw",
+            OrcB => "orc.b",
+            Rev8 => "rev8",
+            Clmul => "clmul",
+            Clmulh => "clmulh",
+            Clmulr => "clmulr",
+            Pack => "pack",
+            Packh => "packh",
+            Packw => "packw",
+            Brev8 => "brev8",
+            Xperm4 => "xperm4",
+            Xperm8 => "xperm8",
+            Bclr => "bclr",
+            Bclri => "bclri",
+            Bext => "bext",
+            Bexti => "bexti",
+            Binv => "binv",
+            Binvi => "binvi",
+            Bset => "bset",
+            Bseti => "bseti",
+        }
+    }
+
+    /// Return the result of the instruction for the value `a` of rs1 and `b`, the value of rs2
+    /// or the shift amount of the immediate.
+    pub fn execute(self, a: u64, b: u64) -> u64 {
+        use Op::*;
+        let sext32 = |value: u32| value as i32 as i64 as u64;
+        let uw = a as u32 as u64;
+        // Shift amounts and bit indexes only use the lower bits, as with the base shifts.
+        let shamt = (b & 0x3f) as u32;
+        let bit = 1u64 << shamt;
+        match self {
+            AddUw => b.wrapping_add(uw),
+            Sh1add => b.wrapping_add(a << 1),
+            Sh1addUw => b.wrapping_add(uw << 1),
+            Sh2add => b.wrapping_add(a << 2),
+            Sh2addUw => b.wrapping_add(uw << 2),
+            Sh3add => b.wrapping_add(a << 3),
+            Sh3addUw => b.wrapping_add(uw << 3),
+            SlliUw => uw << shamt,
+            Andn => a & !b,
+            Orn => a | !b,
+            Xnor => !(a ^ b),
+            Clz => a.leading_zeros() as u64,
+            Clzw => (a as u32).leading_zeros() as u64,
+            Ctz => a.trailing_zeros() as u64,
+            Ctzw => (a as u32).trailing_zeros() as u64,
+            Cpop => a.count_ones() as u64,
+            Cpopw => (a as u32).count_ones() as u64,
+            Max => (a as i64).max(b as i64) as u64,
+            Maxu => a.max(b),
+            Min => (a as i64).min(b as i64) as u64,
+            Minu => a.min(b),
+            SextB => a as i8 as i64 as u64,
+            SextH => a as i16 as i64 as u64,
+            ZextH => a as u16 as u64,
+            Rol => a.rotate_left(shamt),
+            Rolw => sext32((a as u32).rotate_left(shamt & 0x1f)),
+            Ror | Rori => a.rotate_right(shamt),
+            Roriw | Rorw => sext32((a as u32).rotate_right(shamt & 0x1f)),
+            OrcB => (0..8).fold(0, |result, i| {
+                let byte = 0xff << (i * 8);
+                result | if a & byte != 0 { byte } else { 0 }
+            }),
+            Rev8 => a.swap_bytes(),
+            Clmul => clmul(a, b).1,
+            Clmulh => clmul(a, b).0,
+            // Bits 126 to 63 of the product.
+            Clmulr => {
+                let (high, low) = clmul(a, b);
+                high << 1 | low >> 63
+            }
+            Pack => (b << 32) | uw,
+            Packh => ((b & 0xff) << 8) | (a & 0xff),
+            Packw => sext32(((b as u32 & 0xffff) << 16) | (a as u32 & 0xffff)),
+            Brev8 => a.reverse_bits().swap_bytes(),
+            // Each nibble or byte of rs2 selects one of rs1, or 0 if it's out of range.
+            Xperm4 => (0..16).fold(0, |result, i| {
+                let index = (b >> (i * 4)) & 0xf;
+                result | ((a >> (index * 4)) & 0xf) << (i * 4)
+            }),
+            Xperm8 => (0..8).fold(0, |result, i| {
+                let index = (b >> (i * 8)) & 0xff;
+                let byte = if index < 8 {
+                    (a >> (index * 8)) & 0xff
+                } else {
+                    0
+                };
+                result | byte << (i * 8)
+            }),
+            Bclr | Bclri => a & !bit,
+            Bext | Bexti => (a >> shamt) & 1,
+            Binv | Binvi => a ^ bit,
+            Bset | Bseti => a | bit,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Op::*;
+
+    #[test]
+    fn decode_known_encodings() {
+        // Encodings from llvm-mc, with rd a0, rs1 a1 and rs2 a2.
+        for (inst, op) in [
+            (0x0ac59533, Clmul),
+            (0x0ac5b533, Clmulh),
+            (0x0ac5a533, Clmulr),
+            (0x2875d513, OrcB),
+            (0x6b85d513, Rev8),
+            (0x60c5953b, Rolw),
+            (0x60c5d53b, Rorw),
+            (0x6055d51b, Roriw),
+            (0x63f5d513, Rori),
+            (0x20c5a53b, Sh1addUw),
+            (0x4bf5d513, Bexti),
+            (0x6875d513, Brev8),
+            (0x0805c53b, ZextH),
+        ] {
+            assert_eq!(decode(inst), Some(op), "{:#010x}", inst);
+        }
+        // add and addw aren't bit-manipulation instructions.
+        assert_eq!(decode(0x00c58533), None);
+        assert_eq!(decode(0x00c5853b), None);
+    }
+
+    #[test]
+    fn carry_less_multiplication() {
+        // a, b, clmul, clmulh, clmulr.
+        for (a, b, low, high, reversed) in [
+            (
+                0x8000000000000001,
+                0x8000000000000001,
+                0x0000000000000001,
+                0x4000000000000000,
+                0x8000000000000000,
+            ),
+            (
+                0x123456789abcdef0,
+                0xfedcba9876543210,
+                0x0a0789828c810f00,
+                0x0e038d8688850b04,
+                0x1c071b0d110a1608,
+            ),
+            (
+                u64::MAX,
+                u64::MAX,
+                0x5555555555555555,
+                0x5555555555555555,
+                0xaaaaaaaaaaaaaaaa,
+            ),
+            (3, 7, 9, 0, 0),
+        ] {
+            assert_eq!(Clmul.execute(a, b), low, "clmul {:#x} {:#x}", a, b);
+            assert_eq!(Clmulh.execute(a, b), high, "clmulh {:#x} {:#x}", a, b);
+            assert_eq!(Clmulr.execute(a, b), reversed, "clmulr {:#x} {:#x}", a, b);
+        }
+    }
+
+    #[test]
+    fn byte_operations() {
+        // a, orc.b, rev8.
+        for (a, orc, rev) in [
+            (0x0001000000ff8000, 0x00ff000000ffff00, 0x0080ff0000000100),
+            (0, 0, 0),
+            (0x8000000000000080, 0xff000000000000ff, 0x8000000000000080),
+            (0x0102030405060708, u64::MAX, 0x0807060504030201),
+        ] {
+            assert_eq!(OrcB.execute(a, 0), orc, "orc.b {:#x}", a);
+            assert_eq!(Rev8.execute(a, 0), rev, "rev8 {:#x}", a);
+        }
+        assert_eq!(Brev8.execute(0x0102030405060780, 0), 0x8040c020a060e001);
+        assert_eq!(
+            Xperm8.execute(0x0807060504030201, 0x00010203_08ff0700),
+            0x01020304_00000801
+        );
+        assert_eq!(
+            Xperm4.execute(0xfedcba9876543210, 0x0123456789abcdef),
+            0x0123456789abcdef
+        );
+    }
+
+    #[test]
+    fn word_rotations_sign_extend() {
+        // a, the amount, rolw, rorw.
+        for (a, amount, left, right) in [
+            (0x80000001, 1, 0x0000000000000003, 0xffffffffc0000000),
+            (
+                0xffffffff40000000,
+                1,
+                0xffffffff80000000,
+                0x0000000020000000,
+            ),
+            // The amount is taken modulo 32.
+            (0x12345678, 36, 0x0000000023456781, 0xffffffff81234567),
+            (0x87654321, 4, 0x0000000076543218, 0x0000000018765432),
+            (
+                0xdeadbeef00000001,
+                31,
+                0xffffffff80000000,
+                0x0000000000000002,
+            ),
+        ] {
+            assert_eq!(Rolw.execute(a, amount), left, "rolw {:#x} {}", a, amount);
+            assert_eq!(Rorw.execute(a, amount), right, "rorw {:#x} {}", a, amount);
+            assert_eq!(
+                Roriw.execute(a, amount & 0x1f),
+                right,
+                "roriw {:#x} {}",
+                a,
+                amount
+            );
+        }
+        assert_eq!(Rol.execute(0x8000000000000001, 65), 0x0000000000000003);
+        assert_eq!(Ror.execute(1, 63), 2);
+    }
+
+    #[test]
+    fn counts_and_words() {
+        assert_eq!(Clz.execute(0, 0), 64);
+        assert_eq!(Clzw.execute(0xffffffff_00000000, 0), 32);
+        assert_eq!(Ctzw.execute(0x1_00000000, 0), 32);
+        assert_eq!(Cpopw.execute(0xffffffff_0000000f, 0), 4);
+        assert_eq!(Packw.execute(0x1234_8765, 0x5678_cdef), 0xffffffff_cdef8765);
+        assert_eq!(
+            Pack.execute(0xffffffff_12345678, 0x9abcdef0),
+            0x9abcdef0_12345678
+        );
+        assert_eq!(AddUw.execute(0xffffffff_ffffffff, 1), 0x1_00000000);
+        assert_eq!(Sh3addUw.execute(0x80000000_00000001, 1), 9);
+        assert_eq!(SlliUw.execute(0xffffffff_80000000, 1), 0x1_00000000);
+        assert_eq!(Max.execute(u64::MAX, 1), 1);
+        assert_eq!(Minu.execute(u64::MAX, 1), 1);
+        assert_eq!(Bexti.execute(1 << 63, 63), 1);
+        assert_eq!(Binv.execute(0, 64 + 3), 8);
+    }
+}
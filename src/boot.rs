@@ -0,0 +1,174 @@
+//! The boot module places the boot images in RAM the way firmware and kernels expect on the QEMU
+//! virt machine. Without firmware the kernel is loaded at the start of RAM, where execution
+//! starts. With firmware, e.g. OpenSBI, the firmware is loaded there instead and the kernel
+//! `KERNEL_OFFSET` bytes later. The device tree and the initrd go to the end of RAM, and the
+//! initrd's location is written to the `/chosen` node of the device tree.
+
+use std::convert::TryInto;
+use std::io;
+
+use crate::bus::{Memory, MEMORY_BASE};
+
+/// The offset of the kernel from the start of RAM when firmware is loaded, the address OpenSBI's
+/// fw_jump jumps to.
+pub const KERNEL_OFFSET: u64 = 0x20_0000;
+
+/// The images loaded into RAM at boot.
+#[derive(Default)]
+pub struct Images {
+    pub kernel: Vec<u8>,
+    pub firmware: Option<Vec<u8>>,
+    pub initrd: Option<Vec<u8>>,
+    pub dtb: Option<Vec<u8>>,
+}
+
+/// Where the images ended up.
+pub struct Layout {
+    /// The address of the device tree, passed to the boot hart in a1.
+    pub dtb: Option<u64>,
+    /// The lowest address used at the end of RAM, which is where the stack starts.
+    pub top: u64,
+}
+
+/// Copy `images` into `memory`. Fail if they don't fit or overlap.
+pub fn load(memory: &mut Memory, mut images: Images) -> io::Result<Layout> {
+    let mut end = match &images.firmware {
+        Some(firmware) => {
+            memory.load_image(MEMORY_BASE, firmware)?;
+            check_overlap(
+                "firmware",
+                MEMORY_BASE + firmware.len() as u64,
+                "kernel",
+                MEMORY_BASE + KERNEL_OFFSET,
+            )?;
+            memory.load_image(MEMORY_BASE + KERNEL_OFFSET, &images.kernel)?;
+            MEMORY_BASE + KERNEL_OFFSET + images.kernel.len() as u64
+        }
+        None => {
+            memory.load_image(MEMORY_BASE, &images.kernel)?;
+            MEMORY_BASE + images.kernel.len() as u64
+        }
+    };
+    let mut top = MEMORY_BASE + memory.size();
+
+    let dtb = match &images.dtb {
+        Some(dtb) => {
+            top = top.saturating_sub(dtb.len() as u64) & !0xfff;
+            Some(top)
+        }
+        None => None,
+    };
+    if let Some(initrd) = &images.initrd {
+        let start = top.saturating_sub(initrd.len() as u64) & !0xfff;
+        check_overlap("the kernel", end, "initrd", start)?;
+        memory.load_image(start, initrd)?;
+        if let Some(dtb) = &mut images.dtb {
+            set_initrd(dtb, start, start + initrd.len() as u64)?;
+        }
+        end = start + initrd.len() as u64;
+        top = start;
+    }
+    if let (Some(dtb), Some(address)) = (&images.dtb, dtb) {
+        check_overlap("the kernel", end, "device tree", address)?;
+        memory.load_image(address, dtb)?;
+    }
+    Ok(Layout { dtb, top })
+}
+
+fn check_overlap(first: &str, end: u64, second: &str, start: u64) -> io::Result<()> {
+    if end > start {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} ends at {:#x} and overlaps the {} at {:#x}; use more RAM",
+                first, end, second, start
+            ),
+        ));
+    }
+    Ok(())
+}
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+fn invalid_dtb() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "the device tree is malformed")
+}
+
+fn be32(dtb: &[u8], offset: usize) -> io::Result<u32> {
+    let bytes = dtb.get(offset..offset + 4).ok_or_else(invalid_dtb)?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// Write `start` and `end` into the `linux,initrd-start` and `linux,initrd-end` properties of the
+/// `/chosen` node. The properties must already exist, since the device tree isn't resized.
+fn set_initrd(dtb: &mut [u8], start: u64, end: u64) -> io::Result<()> {
+    if be32(dtb, 0)? != FDT_MAGIC {
+        return Err(invalid_dtb());
+    }
+    let structs = be32(dtb, 8)? as usize;
+    let strings = be32(dtb, 12)? as usize;
+
+    let mut offset = structs;
+    let mut depth = 0;
+    let mut in_chosen = false;
+    let mut found = 0;
+    loop {
+        let token = be32(dtb, offset)?;
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name_end = dtb[offset..]
+                    .iter()
+                    .position(|b| *b == 0)
+                    .ok_or_else(invalid_dtb)?;
+                depth += 1;
+                in_chosen = depth == 2 && &dtb[offset..offset + name_end] == b"chosen";
+                offset = (offset + name_end + 4) & !3;
+            }
+            FDT_END_NODE => {
+                depth -= 1;
+                in_chosen = false;
+            }
+            FDT_PROP => {
+                let len = be32(dtb, offset)? as usize;
+                let name = strings + be32(dtb, offset + 4)? as usize;
+                let value = offset + 8;
+                offset = (value + len + 3) & !3;
+                if !in_chosen {
+                    continue;
+                }
+                let name = &dtb.get(name..).ok_or_else(invalid_dtb)?;
+                let address = if name.starts_with(b"linux,initrd-start\0") {
+                    start
+                } else if name.starts_with(b"linux,initrd-end\0") {
+                    end
+                } else {
+                    continue;
+                };
+                let cell = dtb.get_mut(value..value + len).ok_or_else(invalid_dtb)?;
+                match len {
+                    4 => cell.copy_from_slice(&(address as u32).to_be_bytes()),
+                    8 => cell.copy_from_slice(&address.to_be_bytes()),
+                    _ => return Err(invalid_dtb()),
+                }
+                found += 1;
+            }
+            FDT_NOP => {}
+            FDT_END => break,
+            _ => return Err(invalid_dtb()),
+        }
+    }
+    if found != 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the device tree needs linux,initrd-start and linux,initrd-end properties in /chosen \
+             for the initrd",
+        ));
+    }
+    Ok(())
+}
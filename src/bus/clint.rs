@@ -2,61 +2,206 @@
 //! block holds memory-mapped control and status registers associated with
 //! software and timer interrupts. It generates per-hart software interrupts and timer.
 
+use std::io;
+
 use crate::bus::Device;
+use crate::cpu::{MIP_MSIP, MIP_MTIP};
 use crate::exception::Exception;
+use crate::snapshot::{Reader, Snapshot, Writer};
 
 pub const CLINT_BASE: u64 = 0x200_0000;
 pub const CLINT_SIZE: u64 = 0x10000;
+/// The address of the msip register of hart 0. Writing 1 to a hart's msip raises its machine
+/// software interrupt. The registers of the other harts follow, 4 bytes apart.
+pub const CLINT_MSIP: u64 = 0x0;
 /// The address of a mtimecmp register starts. A mtimecmp is a memory mapped machine mode timer
 /// compare register, used to trigger an interrupt when mtimecmp is greater than or equal to mtime.
-pub const CLINT_MTIMECMP: u64 = CLINT_BASE + 0x4000;
+/// Every hart has one, 8 bytes apart.
+pub const CLINT_MTIMECMP: u64 = 0x4000;
 /// The address of a timer register. A mtime is a machine mode timer register which runs at a
 /// constant frequency.
-pub const CLINT_MTIME: u64 = CLINT_BASE + 0xbff8;
+pub const CLINT_MTIME: u64 = 0xbff8;
+/// The default frequency of mtime, the one of the QEMU virt machine.
+pub const TIMEBASE_FREQUENCY: u64 = 10_000_000;
+/// The clock of the emulated hart. Every instruction takes one cycle, so time only depends on the
+/// number of instructions executed and runs the same in a replay.
+pub const CPU_FREQUENCY: u64 = 100_000_000;
 
 /// The core-local interruptor (CLINT).
 pub struct Clint {
     mtime: u64,
-    mtimecmp: u64,
+    msip: Vec<u32>,
+    mtimecmp: Vec<u64>,
+    /// The number of cycles of a hart per tick of mtime.
+    cycles: u64,
+    /// The number of instructions per tick of mtime: the cycles of all harts whose instructions
+    /// are counted together.
+    period: u64,
+    /// The instruction count at which mtime ticks next. It's derived from the instruction count
+    /// again after a snapshot is restored.
+    next_tick: Option<u64>,
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Device for Clint {
-    fn load(&self, addr: u64, size: usize) -> Result<u64, Exception> {
-        match size {
-            64 => Ok(self.load64(addr)),
+    fn load(&self, offset: u64, size: usize) -> Result<u64, Exception> {
+        match (size, offset) {
+            (32, offset) if offset < CLINT_MTIMECMP => Ok(self.load32(offset)),
+            (64, offset) if offset >= CLINT_MTIMECMP => Ok(self.load64(offset)),
             _ => Err(Exception::LoadAccessFault),
         }
     }
 
-    fn store(&mut self, addr: u64, size: usize, value: u64) -> Result<(), Exception> {
-        match size {
-            64 => Ok(self.store64(addr, value)),
-            _ => Err(Exception::StoreAMOAccessFault),
+    fn store(&mut self, offset: u64, size: usize, value: u64) -> Result<(), Exception> {
+        match (size, offset) {
+            (32, offset) if offset < CLINT_MTIMECMP => self.store32(offset, value),
+            (64, offset) if offset >= CLINT_MTIMECMP => self.store64(offset, value),
+            _ => return Err(Exception::StoreAMOAccessFault),
         }
+        Ok(())
     }
 }
 
 impl Clint {
+    /// The mip bits the CLINT drives.
+    pub const LINES: u64 = MIP_MSIP | MIP_MTIP;
+
     pub fn new() -> Self {
+        Self::with_timebase(TIMEBASE_FREQUENCY, 1)
+    }
+
+    /// Create a CLINT for `harts` harts whose mtime runs at `frequency` Hz, at most
+    /// `CPU_FREQUENCY`. The harts take turns, so mtime is driven by the instructions of all of
+    /// them.
+    pub fn with_timebase(frequency: u64, harts: usize) -> Self {
+        let cycles = (CPU_FREQUENCY / frequency.max(1)).max(1);
+        let harts = harts.max(1);
         Self {
             mtime: 0,
-            mtimecmp: 0,
+            msip: vec![0; harts],
+            mtimecmp: vec![0; harts],
+            cycles,
+            period: cycles * harts as u64,
+            next_tick: None,
+        }
+    }
+
+    /// Drive mtime by the instructions of `harts` harts counted together. Harts that run on
+    /// threads of their own drive it by the instructions of one of them.
+    pub fn set_interleave(&mut self, harts: usize) {
+        self.period = self.cycles * harts.max(1) as u64;
+        self.next_tick = None;
+    }
+
+    /// Return the mip bits raised for `hart`.
+    pub fn lines(&self, hart: usize) -> u64 {
+        let mut lines = 0;
+        if self.msip.get(hart).is_some_and(|msip| msip & 1 != 0) {
+            lines |= MIP_MSIP;
+        }
+        if self
+            .mtimecmp
+            .get(hart)
+            .is_some_and(|mtimecmp| self.mtime >= *mtimecmp)
+        {
+            lines |= MIP_MTIP;
+        }
+        lines
+    }
+
+    /// Return the instruction count at which mtime ticks next.
+    pub fn next_tick(&self) -> u64 {
+        self.next_tick.unwrap_or(0)
+    }
+
+    /// Return the instruction count at which mtime reaches the mtimecmp of a hart whose timer
+    /// line is low, or 0 if mtime hasn't been advanced yet.
+    pub fn deadline(&self) -> u64 {
+        let next_tick = match self.next_tick {
+            Some(next_tick) => next_tick,
+            None => return 0,
+        };
+        self.mtimecmp
+            .iter()
+            .filter(|&&mtimecmp| mtimecmp > self.mtime)
+            .map(|&mtimecmp| {
+                (mtimecmp - self.mtime - 1)
+                    .saturating_mul(self.period)
+                    .saturating_add(next_tick)
+            })
+            .min()
+            .unwrap_or(u64::MAX)
+    }
+
+    /// Advance mtime to the time of instruction `icount`.
+    pub fn update(&mut self, icount: u64) {
+        let period = self.period;
+        let next_tick = self
+            .next_tick
+            .get_or_insert_with(|| (icount / period + 1) * period);
+        while icount >= *next_tick {
+            self.mtime = self.mtime.wrapping_add(1);
+            *next_tick += period;
+        }
+    }
+
+    fn load32(&self, offset: u64) -> u64 {
+        let hart = ((offset - CLINT_MSIP) / 4) as usize;
+        self.msip.get(hart).copied().unwrap_or(0) as u64
+    }
+
+    fn store32(&mut self, offset: u64, value: u64) {
+        let hart = ((offset - CLINT_MSIP) / 4) as usize;
+        if let Some(msip) = self.msip.get_mut(hart) {
+            *msip = value as u32 & 1;
         }
     }
 
-    fn load64(&self, addr: u64) -> u64 {
-        match addr {
-            CLINT_MTIMECMP => self.mtimecmp,
+    fn load64(&self, offset: u64) -> u64 {
+        match offset {
             CLINT_MTIME => self.mtime,
-            _ => 0,
+            _ => {
+                let hart = ((offset - CLINT_MTIMECMP) / 8) as usize;
+                self.mtimecmp.get(hart).copied().unwrap_or(0)
+            }
         }
     }
 
-    fn store64(&mut self, addr: u64, value: u64) {
-        match addr {
-            CLINT_MTIMECMP => self.mtimecmp = value,
+    fn store64(&mut self, offset: u64, value: u64) {
+        match offset {
             CLINT_MTIME => self.mtime = value,
-            _ => {}
+            _ => {
+                let hart = ((offset - CLINT_MTIMECMP) / 8) as usize;
+                if let Some(mtimecmp) = self.mtimecmp.get_mut(hart) {
+                    *mtimecmp = value;
+                }
+            }
+        }
+    }
+}
+
+impl Snapshot for Clint {
+    fn save(&self, w: &mut Writer) -> io::Result<()> {
+        w.put_u64(self.mtime);
+        for (msip, mtimecmp) in self.msip.iter().zip(self.mtimecmp.iter()) {
+            w.put_u32(*msip);
+            w.put_u64(*mtimecmp);
+        }
+        Ok(())
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.mtime = r.get_u64()?;
+        for (msip, mtimecmp) in self.msip.iter_mut().zip(self.mtimecmp.iter_mut()) {
+            *msip = r.get_u32()?;
+            *mtimecmp = r.get_u64()?;
         }
+        self.next_tick = None;
+        Ok(())
     }
 }
@@ -0,0 +1,65 @@
+//! The finisher module contains the SiFive test finisher found in the QEMU virt machine. Writing
+//! to it powers the machine off, which lets a guest report that it is done and whether it passed.
+
+use std::io;
+
+use crate::bus::Device;
+use crate::exception::Exception;
+use crate::snapshot::{Reader, Snapshot, Writer};
+
+pub const FINISHER_BASE: u64 = 0x10_0000;
+pub const FINISHER_SIZE: u64 = 0x1000;
+/// Power off and report success.
+pub const FINISHER_PASS: u64 = 0x5555;
+/// Power off and report the failure code in bits 31:16.
+pub const FINISHER_FAIL: u64 = 0x3333;
+
+/// The test finisher.
+#[derive(Default)]
+pub struct Finisher {
+    /// The exit status the guest powered off with, if it did.
+    status: Option<u32>,
+}
+
+impl Device for Finisher {
+    fn load(&self, _offset: u64, size: usize) -> Result<u64, Exception> {
+        match size {
+            32 => Ok(0),
+            _ => Err(Exception::LoadAccessFault),
+        }
+    }
+
+    fn store(&mut self, offset: u64, size: usize, value: u64) -> Result<(), Exception> {
+        if size != 32 || offset != 0 {
+            return Err(Exception::StoreAMOAccessFault);
+        }
+        match value & 0xffff {
+            FINISHER_PASS => self.status = Some(0),
+            FINISHER_FAIL => self.status = Some((value >> 16) as u32 & 0xffff),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl Finisher {
+    pub fn new() -> Self {
+        Self { status: None }
+    }
+
+    /// Return the exit status if the guest has powered the machine off.
+    pub fn poweroff(&self) -> Option<u32> {
+        self.status
+    }
+}
+
+/// A powered off machine isn't saved, so the finisher has no state to save.
+impl Snapshot for Finisher {
+    fn save(&self, _w: &mut Writer) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn restore(&mut self, _r: &mut Reader) -> io::Result<()> {
+        Ok(())
+    }
+}
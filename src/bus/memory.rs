@@ -1,28 +1,169 @@
+use std::io;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+
 use crate::exception::Exception;
+use crate::snapshot::{invalid, Reader, Snapshot, Writer};
 
-/// Set memory size to 128MiB.
+/// The default memory size, 128MiB.
 pub const MEMORY_SIZE: u64 = 128 * 1024 * 1024;
 /// Address where QEMU virtual machine memory starts.
 pub const MEMORY_BASE: u64 = 0x8000_0000;
+/// The granularity at which writes invalidate decoded instructions.
+const CODE_PAGE_SHIFT: u64 = 12;
 
 /// Random-access memory.
-pub struct Memory(pub Vec<u8>);
+///
+/// The bytes are kept in little-endian 64-bit words that clones of a `Memory` share, so that
+/// harts on different host threads see the same RAM. A naturally aligned access never straddles
+/// two words and is atomic, as RISC-V requires; a misaligned access may be torn.
+///
+/// Every page has a generation that changes when a page that instructions were decoded from is
+/// written, so that caches of decoded instructions can tell whether they are still valid.
+#[derive(Clone)]
+pub struct Memory {
+    words: Arc<[AtomicU64]>,
+    /// The generation of every page. It's odd while instructions of the page may be cached, and
+    /// a write makes it even again.
+    generations: Arc<[AtomicU32]>,
+    size: u64,
+    /// True while other threads may access the memory, so that stores of less than a word must
+    /// not overwrite the rest of it.
+    shared: bool,
+}
+
+/// Return the mask of the lowest `size` bits.
+fn mask(size: usize) -> u64 {
+    if size == 64 {
+        u64::MAX
+    } else {
+        (1 << size) - 1
+    }
+}
 
 impl Memory {
-    /// Create `Memory` with fixed memory size.
-    pub fn new(binary: Vec<u8>) -> Self {
-        let mut memory = vec![0u8; MEMORY_SIZE as usize];
-        memory.splice(..binary.len(), binary);
-        Self(memory)
+    /// Create zeroed `Memory` of `size` bytes.
+    pub fn new(size: u64) -> Self {
+        // One more word so that an access at the last byte can read past it.
+        let words = (0..size / 8 + 2).map(|_| AtomicU64::new(0)).collect();
+        let generations = (0..=(size + 8) >> CODE_PAGE_SHIFT)
+            .map(|_| AtomicU32::new(0))
+            .collect();
+        Self {
+            words,
+            generations,
+            size,
+            shared: false,
+        }
+    }
+
+    /// Copy `image` to `address`. Fail if it doesn't fit in the memory.
+    pub fn load_image(&mut self, address: u64, image: &[u8]) -> io::Result<()> {
+        let start = address.wrapping_sub(MEMORY_BASE);
+        match start.checked_add(image.len() as u64) {
+            Some(end) if address >= MEMORY_BASE && end <= self.size() => {
+                self.write_bytes(start, image);
+                Ok(())
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} bytes at {:#x} don't fit in {} MiB of memory",
+                    image.len(),
+                    address,
+                    self.size() >> 20
+                ),
+            )),
+        }
+    }
+
+    /// Return the size of the memory in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Let other threads access the memory through clones of it, or stop doing so.
+    pub fn set_shared(&mut self, shared: bool) {
+        self.shared = shared;
+    }
+
+    /// Note that instructions at `address` are about to be cached and return the generation of
+    /// their page. The cached instructions are valid as long as `generation` returns the same.
+    pub fn cache_code(&self, address: u64) -> u32 {
+        let generation = &self.generations[((address - MEMORY_BASE) >> CODE_PAGE_SHIFT) as usize];
+        let value = generation.load(Ordering::Acquire);
+        if value & 1 != 0 {
+            return value;
+        }
+        generation.store(value + 1, Ordering::Release);
+        value + 1
+    }
+
+    /// Return the generation of the page of `address`.
+    #[inline]
+    pub fn generation(&self, address: u64) -> u32 {
+        self.generations[((address - MEMORY_BASE) >> CODE_PAGE_SHIFT) as usize]
+            .load(Ordering::Acquire)
+    }
+
+    /// Start a new generation of the page of word `index` if instructions of it may be cached.
+    #[inline]
+    fn invalidate(&self, index: usize) {
+        let generation = &self.generations[index >> (CODE_PAGE_SHIFT - 3)];
+        let value = generation.load(Ordering::Relaxed);
+        if value & 1 != 0 {
+            generation.store(value + 1, Ordering::Release);
+        }
+    }
+
+    /// Copy all bytes out of the memory.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = self
+            .words
+            .iter()
+            .flat_map(|word| word.load(Ordering::Relaxed).to_le_bytes())
+            .collect();
+        bytes.truncate(self.size as usize);
+        bytes
+    }
+
+    fn write_bytes(&mut self, offset: u64, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.write(offset + i as u64, 8, *byte as u64);
+        }
+    }
+
+    /// Return the offset of the `len` bytes at `address` if they're all in the memory.
+    fn range(&self, address: u64, len: usize) -> Option<u64> {
+        let offset = address.checked_sub(MEMORY_BASE)?;
+        (offset.checked_add(len as u64)? <= self.size).then_some(offset)
+    }
+
+    /// Copy the bytes at `address` to `buf`, for devices that access memory directly (DMA).
+    /// Fail if they aren't all in the memory.
+    pub fn load_bytes(&self, address: u64, buf: &mut [u8]) -> Result<(), Exception> {
+        let offset = self
+            .range(address, buf.len())
+            .ok_or(Exception::LoadAccessFault)?;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = self.read(offset + i as u64, 8) as u8;
+        }
+        Ok(())
+    }
+
+    /// Copy `bytes` to `address`. Fail if they don't all fit in the memory.
+    pub fn store_bytes(&mut self, address: u64, bytes: &[u8]) -> Result<(), Exception> {
+        let offset = self
+            .range(address, bytes.len())
+            .ok_or(Exception::StoreAMOAccessFault)?;
+        self.write_bytes(offset, bytes);
+        Ok(())
     }
 
     /// Load bytes with requested size from little-endian memory.
     pub fn load(&self, address: u64, size: usize) -> Result<u64, Exception> {
         match size {
-            8 => Ok(self.load_8bits(address)),
-            16 => Ok(self.load_16bits(address)),
-            32 => Ok(self.load_32bits(address)),
-            64 => Ok(self.load_64bits(address)),
+            8 | 16 | 32 | 64 => Ok(self.read(address - MEMORY_BASE, size)),
             _ => Err(Exception::LoadAddressMisaligned),
         }
     }
@@ -30,72 +171,108 @@ impl Memory {
     /// Store bytes with requested size to little-endian memory.
     pub fn store(&mut self, address: u64, size: usize, value: u64) -> Result<(), Exception> {
         match size {
-            8 => Ok(self.store_8bits(address, value)),
-            16 => Ok(self.store_16bits(address, value)),
-            32 => Ok(self.store_32bits(address, value)),
-            64 => Ok(self.store_64bits(address, value)),
-            _ => Err(Exception::LoadAddressMisaligned),
+            8 | 16 | 32 | 64 => self.write(address - MEMORY_BASE, size, value),
+            _ => return Err(Exception::StoreAMOAddressMisaligned),
         }
+        Ok(())
     }
 
-    fn load_8bits(&self, address: u64) -> u64 {
-        let index = (address - MEMORY_BASE) as usize;
-        self.0[index] as u64
-    }
-
-    fn load_16bits(&self, address: u64) -> u64 {
-        let index = (address - MEMORY_BASE) as usize;
-        self.0[index] as u64 | ((self.0[index + 1] as u64) << 8)
-    }
-
-    fn load_32bits(&self, address: u64) -> u64 {
-        let index = (address - MEMORY_BASE) as usize;
-        self.0[index] as u64
-            | ((self.0[index + 1] as u64) << 8)
-            | ((self.0[index + 2] as u64) << 16)
-            | ((self.0[index + 3] as u64) << 24)
+    /// Atomically replace the naturally aligned value at `address` with `f` of it, unless `f`
+    /// returns `None`. Return the old value and whether it was replaced.
+    pub fn update(
+        &self,
+        address: u64,
+        size: usize,
+        mut f: impl FnMut(u64) -> Option<u64>,
+    ) -> Result<(u64, bool), Exception> {
+        let offset = address - MEMORY_BASE;
+        if !matches!(size, 32 | 64) || !offset.is_multiple_of(size as u64 / 8) {
+            return Err(Exception::StoreAMOAddressMisaligned);
+        }
+        let shift = (offset % 8) * 8;
+        let mask = mask(size) << shift;
+        let mut updated = false;
+        let index = (offset / 8) as usize;
+        let word = self.words[index]
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |word| {
+                let value = f((word & mask) >> shift)?;
+                updated = true;
+                Some((word & !mask) | ((value << shift) & mask))
+            })
+            .unwrap_or_else(|word| {
+                updated = false;
+                word
+            });
+        if updated {
+            self.invalidate(index);
+        }
+        Ok(((word & mask) >> shift, updated))
     }
 
-    fn load_64bits(&self, address: u64) -> u64 {
-        let index = (address - MEMORY_BASE) as usize;
-        self.0[index] as u64
-            | ((self.0[index + 1] as u64) << 8)
-            | ((self.0[index + 2] as u64) << 16)
-            | ((self.0[index + 3] as u64) << 24)
-            | ((self.0[index + 4] as u64) << 32)
-            | ((self.0[index + 5] as u64) << 40)
-            | ((self.0[index + 6] as u64) << 48)
-            | ((self.0[index + 7] as u64) << 56)
+    /// Read `size` bits at `offset` from the start of the memory.
+    #[inline]
+    fn read(&self, offset: u64, size: usize) -> u64 {
+        let index = (offset / 8) as usize;
+        let shift = (offset % 8) * 8;
+        let low = self.words[index].load(Ordering::Relaxed) >> shift;
+        if shift as usize + size <= 64 {
+            return low & mask(size);
+        }
+        let high = self.words[index + 1].load(Ordering::Relaxed) << (64 - shift);
+        (low | high) & mask(size)
     }
 
-    fn store_8bits(&mut self, address: u64, value: u64) {
-        let index = (address - MEMORY_BASE) as usize;
-        self.0[index] = value as u8;
+    /// Write the low `size` bits of `value` at `offset` from the start of the memory.
+    #[inline]
+    fn write(&mut self, offset: u64, size: usize, value: u64) {
+        let index = (offset / 8) as usize;
+        let shift = (offset % 8) * 8;
+        let value = value & mask(size);
+        self.write_word(index, mask(size) << shift, value << shift);
+        if shift as usize + size > 64 {
+            let rest = shift as usize + size - 64;
+            self.write_word(index + 1, mask(rest), value >> (64 - shift));
+        }
     }
 
-    fn store_16bits(&mut self, address: u64, value: u64) {
-        let index = (address - MEMORY_BASE) as usize;
-        self.0[index] = (value & 0xff) as u8;
-        self.0[index + 1] = ((value >> 8) & 0xff) as u8;
+    /// Replace the bits of `mask` in the word `index` with those of `value`.
+    #[inline]
+    fn write_word(&self, index: usize, mask: u64, value: u64) {
+        self.invalidate(index);
+        let word = &self.words[index];
+        if mask == u64::MAX {
+            word.store(value, Ordering::Relaxed);
+        } else if self.shared {
+            word.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |old| {
+                Some((old & !mask) | (value & mask))
+            })
+            .ok();
+        } else {
+            let old = word.load(Ordering::Relaxed);
+            word.store((old & !mask) | (value & mask), Ordering::Relaxed);
+        }
     }
+}
 
-    fn store_32bits(&mut self, address: u64, value: u64) {
-        let index = (address - MEMORY_BASE) as usize;
-        self.0[index] = (value & 0xff) as u8;
-        self.0[index + 1] = ((value >> 8) & 0xff) as u8;
-        self.0[index + 2] = ((value >> 16) & 0xff) as u8;
-        self.0[index + 3] = ((value >> 24) & 0xff) as u8;
+impl Snapshot for Memory {
+    fn save(&self, w: &mut Writer) -> io::Result<()> {
+        w.put_sparse(&self.to_bytes());
+        Ok(())
     }
 
-    fn store_64bits(&mut self, address: u64, value: u64) {
-        let index = (address - MEMORY_BASE) as usize;
-        self.0[index] = (value & 0xff) as u8;
-        self.0[index + 1] = ((value >> 8) & 0xff) as u8;
-        self.0[index + 2] = ((value >> 16) & 0xff) as u8;
-        self.0[index + 3] = ((value >> 24) & 0xff) as u8;
-        self.0[index + 4] = ((value >> 32) & 0xff) as u8;
-        self.0[index + 5] = ((value >> 40) & 0xff) as u8;
-        self.0[index + 6] = ((value >> 48) & 0xff) as u8;
-        self.0[index + 7] = ((value >> 56) & 0xff) as u8;
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        let memory = r.get_sparse(self.size)?;
+        if memory.len() as u64 != self.size {
+            return Err(invalid("memory size differs from the snapshot"));
+        }
+        for (word, bytes) in self.words.iter().zip(memory.chunks(8)) {
+            let mut le = [0; 8];
+            le[..bytes.len()].copy_from_slice(bytes);
+            word.store(u64::from_le_bytes(le), Ordering::Relaxed);
+        }
+        for index in (0..self.words.len()).step_by(1 << (CODE_PAGE_SHIFT - 3)) {
+            self.invalidate(index);
+        }
+        Ok(())
     }
 }
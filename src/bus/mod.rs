@@ -1,84 +1,647 @@
 //! System bus contains memory & memory-mapped peripheral devices.
+//!
+//! Devices are registered at a base address and see offsets from it, so several instances of a
+//! device can be mapped at different addresses. `Machine` lays them out like the QEMU virt
+//! machine.
+//!
+//! The CLINT and the PLIC drive the interrupt lines of every hart, which the bus keeps in one
+//! word per hart that the harts read between instructions. For harts on host threads, the bus is
+//! shared: every thread gets a clone with the same RAM and lines, and devices are locked on
+//! access.
 
 mod clint;
+mod finisher;
 mod memory;
 mod plic;
 mod uart;
 pub mod virtio;
 
-pub use clint::{CLINT_BASE, CLINT_SIZE};
+pub use clint::{CLINT_BASE, CLINT_SIZE, CPU_FREQUENCY, TIMEBASE_FREQUENCY};
+pub use finisher::{FINISHER_BASE, FINISHER_FAIL, FINISHER_PASS, FINISHER_SIZE};
 pub use memory::{MEMORY_BASE, MEMORY_SIZE};
-pub use plic::{PLIC_BASE, PLIC_SCLAIM, PLIC_SIZE};
+pub use plic::{PLIC_BASE, PLIC_SIZE};
 pub use uart::{UART_BASE, UART_IRQ, UART_SIZE};
-pub use virtio::{VIRTIO_BASE, VIRTIO_IRQ, VIRTIO_SIZE};
+pub use virtio::{VIRTIO_BASE, VIRTIO_IRQ, VIRTIO_SIZE, VIRTIO_SLOTS};
+
+use std::any::Any;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 use crate::exception::Exception;
-use clint::Clint;
-use memory::Memory;
-use plic::Plic;
-use uart::Uart;
-use virtio::Virtio;
-
-trait Device {
-    fn load(&self, addr: u64, size: usize) -> Result<u64, Exception>;
-    fn store(&mut self, addr: u64, size: usize, value: u64) -> Result<(), Exception>;
+use crate::snapshot::{Reader, Snapshot, Writer};
+pub use clint::Clint;
+pub use finisher::Finisher;
+pub use memory::Memory;
+pub use plic::Plic;
+pub use uart::{Uart, UartBackend};
+pub use virtio::{
+    open_image, open_network, Block, Console, ConsolePort, FileStorage, MemoryOverlay, Net,
+    NetBackend, Network, Qcow2, Rng, RngBackend, Storage, Virtio, VirtioDevice, VirtioTransport,
+    MAX_CONSOLE_PORTS,
+};
+
+/// A memory-mapped device. Offsets are relative to the base address the device is mapped at and
+/// sizes are in bits.
+pub trait Device: Snapshot + Any + Send {
+    fn load(&self, offset: u64, size: usize) -> Result<u64, Exception>;
+    fn store(&mut self, offset: u64, size: usize, value: u64) -> Result<(), Exception>;
+
+    /// Return true if the device raises its interrupt. This is called between instructions with
+    /// the RAM, which the device may access directly (DMA).
+    fn interrupting(&mut self, _memory: &mut Memory) -> bool {
+        false
+    }
+}
+
+/// The index of a device in the order it was registered.
+pub type DeviceId = usize;
+
+/// A device, owned by the bus or shared with the buses of other threads.
+enum Slot {
+    Owned(Box<dyn Device>),
+    Shared(Arc<Mutex<Box<dyn Device>>>),
+}
+
+impl Slot {
+    /// Call `f` with the device, locking it if it's shared.
+    fn with<R>(&self, f: impl FnOnce(&dyn Device) -> R) -> R {
+        match self {
+            Slot::Owned(device) => f(device.as_ref()),
+            Slot::Shared(device) => f(device.lock().unwrap().as_ref()),
+        }
+    }
+
+    fn with_mut<R>(&mut self, f: impl FnOnce(&mut dyn Device) -> R) -> R {
+        match self {
+            Slot::Owned(device) => f(device.as_mut()),
+            Slot::Shared(device) => f(device.lock().unwrap().as_mut()),
+        }
+    }
+
+    fn into_shared(self) -> Slot {
+        match self {
+            Slot::Owned(device) => Slot::Shared(Arc::new(Mutex::new(device))),
+            shared => shared,
+        }
+    }
+
+    /// Own the device again if no other bus shares it.
+    fn into_owned(self) -> Slot {
+        match self {
+            Slot::Shared(device) => match Arc::try_unwrap(device) {
+                Ok(device) => Slot::Owned(device.into_inner().unwrap()),
+                Err(device) => Slot::Shared(device),
+            },
+            owned => owned,
+        }
+    }
+}
+
+/// A device mapped into the physical address space.
+struct Region {
+    name: String,
+    base: u64,
+    size: u64,
+    /// The PLIC source the device interrupts on, if it has one.
+    irq: Option<u64>,
+    slot: Slot,
 }
 
 /// System bus.
 pub struct Bus {
-    clint: Clint,
-    memory: Memory,
-    plic: Plic,
-    pub uart: Uart,
-    pub virtio: Virtio,
+    pub memory: Memory,
+    /// Devices in the order they were registered.
+    regions: Vec<Region>,
+    /// (base, end, id) of every device, sorted by base address.
+    map: Vec<(u64, u64, DeviceId)>,
+    /// The first UART, which is the console.
+    console: Option<DeviceId>,
+    /// The first CLINT, which provides the timer and the software interrupts.
+    clint: Option<DeviceId>,
+    /// The first PLIC, which routes the interrupts of devices.
+    plic: Option<DeviceId>,
+    /// The instruction count at which the timer changes next, unless the CLINT is written.
+    timer_update: u64,
+    /// The mip bits the CLINT and the PLIC raise for each hart.
+    lines: Arc<[AtomicU64]>,
+    /// False for the clones of a shared bus that leave the timer, input and device interrupts to
+    /// the bus they were cloned from.
+    polls: bool,
 }
 
 impl Bus {
-    pub fn new(binary: Vec<u8>, image: Vec<u8>) -> Bus {
+    /// Create a bus with RAM and no devices for `harts` harts.
+    pub fn with_memory(memory: Memory, harts: usize) -> Bus {
         Self {
-            memory: Memory::new(binary),
-            clint: Clint::new(),
-            plic: Plic::new(),
-            uart: Uart::new(),
-            virtio: Virtio::new(image),
+            memory,
+            regions: Vec::new(),
+            map: Vec::new(),
+            console: None,
+            clint: None,
+            plic: None,
+            timer_update: 0,
+            lines: (0..harts.max(1)).map(|_| AtomicU64::new(0)).collect(),
+            polls: true,
         }
     }
 
-    pub fn load(&self, addr: u64, size: usize) -> Result<u64, Exception> {
-        if CLINT_BASE <= addr && addr < CLINT_BASE + CLINT_SIZE {
-            return self.clint.load(addr, size);
+    /// Return the number of harts.
+    pub fn harts(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Return the mip bits the interrupt controllers raise for `hart`.
+    #[inline]
+    pub fn lines(&self, hart: usize) -> u64 {
+        self.lines[hart].load(Ordering::Acquire)
+    }
+
+    /// Return false if another bus polls devices and the timer for this one.
+    #[inline]
+    pub fn polls(&self) -> bool {
+        self.polls
+    }
+
+    /// Map `device` at `base..base + size`. If `irq` is set, the device's interrupt is routed to
+    /// that PLIC source. Fail if the range overlaps the RAM or another device.
+    pub fn register(
+        &mut self,
+        name: &str,
+        base: u64,
+        size: u64,
+        irq: Option<u64>,
+        device: Box<dyn Device>,
+    ) -> io::Result<DeviceId> {
+        let end = base
+            .checked_add(size)
+            .filter(|_| size > 0)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid device range"))?;
+        let memory_end = MEMORY_BASE + self.memory.size();
+        let overlap = if base < memory_end && MEMORY_BASE < end {
+            Some("memory")
+        } else {
+            self.regions
+                .iter()
+                .find(|r| base < r.base + r.size && r.base < end)
+                .map(|r| r.name.as_str())
+        };
+        if let Some(other) = overlap {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} at {:#x}..{:#x} overlaps {}", name, base, end, other),
+            ));
+        }
+
+        let id = self.regions.len();
+        let any = device.as_ref() as &dyn Any;
+        if self.console.is_none() && any.is::<Uart>() {
+            self.console = Some(id);
+        }
+        if self.clint.is_none() && any.is::<Clint>() {
+            self.clint = Some(id);
+        }
+        if self.plic.is_none() && any.is::<Plic>() {
+            self.plic = Some(id);
+        }
+        self.regions.push(Region {
+            name: name.to_string(),
+            base,
+            size,
+            irq,
+            slot: Slot::Owned(device),
+        });
+        let i = self.map.partition_point(|(b, _, _)| *b < base);
+        self.map.insert(i, (base, end, id));
+        Ok(id)
+    }
+
+    /// Return the device `id` if it is a `T`. Devices can't be borrowed while the bus is shared;
+    /// use `with` then.
+    pub fn get<T: Device>(&self, id: DeviceId) -> Option<&T> {
+        match &self.regions.get(id)?.slot {
+            Slot::Owned(device) => (device.as_ref() as &dyn Any).downcast_ref(),
+            Slot::Shared(_) => None,
+        }
+    }
+
+    pub fn get_mut<T: Device>(&mut self, id: DeviceId) -> Option<&mut T> {
+        match &mut self.regions.get_mut(id)?.slot {
+            Slot::Owned(device) => (device.as_mut() as &mut dyn Any).downcast_mut(),
+            Slot::Shared(_) => None,
+        }
+    }
+
+    /// Call `f` with the device `id` if it is a `T`, locking it while the bus is shared.
+    pub fn with<T: Device, R>(&self, id: DeviceId, f: impl FnOnce(&T) -> R) -> Option<R> {
+        self.regions
+            .get(id)?
+            .slot
+            .with(|device| (device as &dyn Any).downcast_ref().map(f))
+    }
+
+    pub fn with_mut<T: Device, R>(
+        &mut self,
+        id: DeviceId,
+        f: impl FnOnce(&mut T) -> R,
+    ) -> Option<R> {
+        self.regions
+            .get_mut(id)?
+            .slot
+            .with_mut(|device| (device as &mut dyn Any).downcast_mut().map(f))
+    }
+
+    /// Return the id of the first registered device of type `T`.
+    pub fn find<T: Device>(&self) -> Option<DeviceId> {
+        (0..self.regions.len()).find(|id| self.with::<T, _>(*id, |_| ()).is_some())
+    }
+
+    /// Return the id of the console UART.
+    pub fn console_id(&self) -> Option<DeviceId> {
+        self.console
+    }
+
+    /// Return the first registered device of type `T`.
+    pub fn device<T: Device>(&self) -> Option<&T> {
+        self.get(self.find::<T>()?)
+    }
+
+    pub fn device_mut<T: Device>(&mut self) -> Option<&mut T> {
+        let id = self.find::<T>()?;
+        self.get_mut(id)
+    }
+
+    /// Return the console UART.
+    pub fn console(&self) -> Option<&Uart> {
+        self.get(self.console?)
+    }
+
+    pub fn console_mut(&mut self) -> Option<&mut Uart> {
+        self.get_mut(self.console?)
+    }
+
+    /// Discard or print the output of all UARTs.
+    pub fn set_silent(&mut self, silent: bool) {
+        for region in self.regions.iter_mut() {
+            region.slot.with_mut(|device| {
+                if let Some(uart) = (device as &mut dyn Any).downcast_mut::<Uart>() {
+                    uart.silent = silent;
+                }
+            });
+        }
+    }
+
+    /// Return the name, base address and size of every device, ordered by address.
+    pub fn devices(&self) -> impl Iterator<Item = (&str, u64, u64)> + '_ {
+        self.map.iter().map(move |(_, _, id)| {
+            let region = &self.regions[*id];
+            (region.name.as_str(), region.base, region.size)
+        })
+    }
+
+    /// Advance the timer to instruction `icount` if it ticks then.
+    #[inline]
+    pub fn update_timer(&mut self, icount: u64) {
+        if icount < self.timer_update {
+            return;
+        }
+        let id = match self.clint {
+            Some(id) => id,
+            None => return,
+        };
+        if let Some(next_tick) = self.with_mut::<Clint, _>(id, |clint| {
+            clint.update(icount);
+            clint.next_tick()
+        }) {
+            self.timer_update = next_tick;
+            self.update_lines(id);
+        }
+    }
+
+    /// Return the instruction count at which the timer raises an interrupt line next, if nothing
+    /// but instructions happens until then.
+    pub fn timer_deadline(&self) -> u64 {
+        self.clint
+            .and_then(|id| self.with::<Clint, _>(id, |clint| clint.deadline()))
+            .unwrap_or(u64::MAX)
+    }
+
+    /// Return the first device that raises an interrupt and route it to the PLIC.
+    pub fn pending_irq(&mut self) -> Option<u64> {
+        let memory = &mut self.memory;
+        let irq = self
+            .regions
+            .iter_mut()
+            .find_map(|region| match region.irq {
+                Some(irq) if region.slot.with_mut(|device| device.interrupting(memory)) => {
+                    Some(irq)
+                }
+                _ => None,
+            })?;
+        if let Some(id) = self.plic {
+            self.with_mut::<Plic, _>(id, |plic| plic.raise(irq));
+            self.update_lines(id);
+        }
+        Some(irq)
+    }
+
+    /// Recompute the interrupt lines of every hart after the device `id` may have changed them.
+    fn update_lines(&self, id: DeviceId) {
+        let mask = if Some(id) == self.clint {
+            Clint::LINES
+        } else if Some(id) == self.plic {
+            Plic::LINES
+        } else {
+            return;
+        };
+        // The lines that `device` raises for `hart`.
+        let bits = |device: &dyn Any, hart| {
+            if let Some(clint) = device.downcast_ref::<Clint>() {
+                clint.lines(hart)
+            } else if let Some(plic) = device.downcast_ref::<Plic>() {
+                plic.lines(hart)
+            } else {
+                0
+            }
+        };
+        self.regions[id].slot.with(|device| {
+            for (hart, line) in self.lines.iter().enumerate() {
+                let bits = bits(device as &dyn Any, hart);
+                let old = line.load(Ordering::Relaxed);
+                if old & mask != bits {
+                    line.fetch_update(Ordering::AcqRel, Ordering::Relaxed, |old| {
+                        Some((old & !mask) | bits)
+                    })
+                    .ok();
+                }
+            }
+        });
+    }
+
+    /// Return a clone of the bus for a hart on another thread. Both share the RAM, the interrupt
+    /// lines and the devices, which are locked on access from then on. Only the original bus
+    /// polls devices and advances the timer, which counts the instructions of the hart that
+    /// uses it as those of all harts.
+    pub fn share(&mut self) -> Bus {
+        self.memory.set_shared(true);
+        for region in std::mem::take(&mut self.regions) {
+            self.regions.push(Region {
+                slot: region.slot.into_shared(),
+                ..region
+            });
         }
-        if PLIC_BASE <= addr && addr < PLIC_BASE + PLIC_SIZE {
-            return self.plic.load(addr, size);
+        if let Some(id) = self.clint {
+            self.with_mut::<Clint, _>(id, |clint| clint.set_interleave(1));
         }
-        if UART_BASE <= addr && addr < UART_BASE + UART_SIZE {
-            return self.uart.load(addr, size);
+        self.timer_update = 0;
+        let regions = self
+            .regions
+            .iter()
+            .map(|region| Region {
+                name: region.name.clone(),
+                base: region.base,
+                size: region.size,
+                irq: region.irq,
+                slot: match &region.slot {
+                    Slot::Shared(device) => Slot::Shared(device.clone()),
+                    Slot::Owned(_) => unreachable!(),
+                },
+            })
+            .collect();
+        Bus {
+            memory: self.memory.clone(),
+            regions,
+            map: self.map.clone(),
+            console: self.console,
+            clint: self.clint,
+            plic: self.plic,
+            timer_update: u64::MAX,
+            lines: self.lines.clone(),
+            polls: false,
         }
-        if VIRTIO_BASE <= addr && addr < VIRTIO_BASE + VIRTIO_SIZE {
-            return self.virtio.load(addr, size);
+    }
+
+    /// Take the devices back once the clones made by `share` are gone.
+    pub fn unshare(&mut self) {
+        self.memory.set_shared(false);
+        for region in std::mem::take(&mut self.regions) {
+            self.regions.push(Region {
+                slot: region.slot.into_owned(),
+                ..region
+            });
         }
-        if MEMORY_BASE <= addr {
+        let harts = self.harts();
+        if let Some(id) = self.clint {
+            self.with_mut::<Clint, _>(id, |clint| clint.set_interleave(harts));
+        }
+        self.timer_update = 0;
+    }
+
+    /// Return the device mapped at `addr` and the offset of `addr` in it.
+    fn lookup(&self, addr: u64) -> Option<(DeviceId, u64)> {
+        let i = self.map.partition_point(|(base, _, _)| *base <= addr);
+        let (base, end, id) = *self.map.get(i.checked_sub(1)?)?;
+        if addr < end {
+            Some((id, addr - base))
+        } else {
+            None
+        }
+    }
+
+    pub fn load(&self, addr: u64, size: usize) -> Result<u64, Exception> {
+        if addr >= MEMORY_BASE && addr - MEMORY_BASE < self.memory.size() {
             return self.memory.load(addr, size);
         }
-        Err(Exception::LoadAccessFault)
+        match self.lookup(addr) {
+            Some((id, offset)) => {
+                let value = self.regions[id]
+                    .slot
+                    .with(|device| device.load(offset, size));
+                if self.plic == Some(id) {
+                    // Claiming an interrupt clears it.
+                    self.update_lines(id);
+                }
+                value
+            }
+            None => Err(Exception::LoadAccessFault),
+        }
+    }
+
+    /// Atomically replace the naturally aligned value at `addr` with `f` of it, unless `f`
+    /// returns `None`. Return the old value and whether it was replaced. A device register is
+    /// loaded and stored without other accesses in between only if the bus isn't shared.
+    pub fn update(
+        &mut self,
+        addr: u64,
+        size: usize,
+        mut f: impl FnMut(u64) -> Option<u64>,
+    ) -> Result<(u64, bool), Exception> {
+        if addr >= MEMORY_BASE && addr - MEMORY_BASE < self.memory.size() {
+            return self.memory.update(addr, size, f);
+        }
+        let old = self
+            .load(addr, size)
+            .map_err(|_| Exception::StoreAMOAccessFault)?;
+        match f(old) {
+            Some(value) => self.store(addr, size, value).map(|_| (old, true)),
+            None => Ok((old, false)),
+        }
     }
 
     pub fn store(&mut self, addr: u64, size: usize, value: u64) -> Result<(), Exception> {
-        if CLINT_BASE <= addr && addr < CLINT_BASE + CLINT_SIZE {
-            return self.clint.store(addr, size, value);
+        if addr >= MEMORY_BASE && addr - MEMORY_BASE < self.memory.size() {
+            return self.memory.store(addr, size, value);
+        }
+        match self.lookup(addr) {
+            Some((id, offset)) => {
+                let result = self.regions[id]
+                    .slot
+                    .with_mut(|device| device.store(offset, size, value));
+                // mtime, mtimecmp, msip or the PLIC's routing may have changed.
+                self.update_lines(id);
+                result
+            }
+            None => Err(Exception::StoreAMOAccessFault),
+        }
+    }
+}
+
+impl Snapshot for Bus {
+    fn save(&self, w: &mut Writer) -> io::Result<()> {
+        self.memory.save(w)?;
+        for region in self.regions.iter() {
+            region.slot.with(|device| device.save(w))?;
+        }
+        Ok(())
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.memory.restore(r)?;
+        for region in self.regions.iter_mut() {
+            region.slot.with_mut(|device| device.restore(r))?;
         }
-        if PLIC_BASE <= addr && addr < PLIC_BASE + PLIC_SIZE {
-            return self.plic.store(addr, size, value);
+        self.timer_update = 0;
+        for id in self.clint.iter().chain(self.plic.iter()) {
+            self.update_lines(*id);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A device with a single register that reads back what was last stored anywhere in it.
+    struct Register(u64);
+
+    impl Device for Register {
+        fn load(&self, _offset: u64, _size: usize) -> Result<u64, Exception> {
+            Ok(self.0)
         }
-        if UART_BASE <= addr && addr < UART_BASE + UART_SIZE {
-            return self.uart.store(addr, size, value);
+
+        fn store(&mut self, offset: u64, _size: usize, value: u64) -> Result<(), Exception> {
+            self.0 = value + offset;
+            Ok(())
         }
-        if VIRTIO_BASE <= addr && addr < VIRTIO_BASE + VIRTIO_SIZE {
-            return self.virtio.store(addr, size, value);
+    }
+
+    impl Snapshot for Register {
+        fn save(&self, w: &mut Writer) -> io::Result<()> {
+            w.put_u64(self.0);
+            Ok(())
         }
-        if MEMORY_BASE <= addr {
-            return self.memory.store(addr, size, value);
+
+        fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+            self.0 = r.get_u64()?;
+            Ok(())
         }
-        Err(Exception::StoreAMOAccessFault)
+    }
+
+    fn bus() -> Bus {
+        Bus::with_memory(Memory::new(0x1000), 1)
+    }
+
+    fn register(bus: &mut Bus, name: &str, base: u64, size: u64) -> io::Result<DeviceId> {
+        bus.register(name, base, size, None, Box::new(Register(0)))
+    }
+
+    #[test]
+    fn overlapping_ranges_are_rejected() {
+        let mut bus = bus();
+        register(&mut bus, "a", 0x1000, 0x100).unwrap();
+        for (base, size) in [(0x1000, 0x100), (0xf00, 0x101), (0x10ff, 1), (0x1080, 0x10)] {
+            let e = register(&mut bus, "b", base, size).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+            assert!(e.to_string().ends_with("overlaps a"), "{}", e);
+        }
+        let e = register(&mut bus, "b", MEMORY_BASE + 0xfff, 0x10).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+        assert!(e.to_string().ends_with("overlaps memory"), "{}", e);
+        for (base, size) in [(0x2000, 0), (u64::MAX, 2)] {
+            let e = register(&mut bus, "b", base, size).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn adjacent_ranges_are_accepted() {
+        let mut bus = bus();
+        register(&mut bus, "a", 0x1000, 0x100).unwrap();
+        register(&mut bus, "b", 0x1100, 0x100).unwrap();
+        register(&mut bus, "c", 0xf00, 0x100).unwrap();
+        register(&mut bus, "d", MEMORY_BASE - 0x100, 0x100).unwrap();
+        register(&mut bus, "e", MEMORY_BASE + 0x1000, 0x100).unwrap();
+    }
+
+    #[test]
+    fn lookup_finds_the_device_and_offset() {
+        let mut bus = bus();
+        // Registered out of order, so that the map and the ids are in different orders.
+        let b = register(&mut bus, "b", 0x2000, 0x100).unwrap();
+        let a = register(&mut bus, "a", 0x1000, 0x100).unwrap();
+        let c = register(&mut bus, "c", 0x1100, 0x10).unwrap();
+        assert_eq!(bus.lookup(0), None);
+        assert_eq!(bus.lookup(0xfff), None);
+        assert_eq!(bus.lookup(0x1000), Some((a, 0)));
+        assert_eq!(bus.lookup(0x10ff), Some((a, 0xff)));
+        assert_eq!(bus.lookup(0x1100), Some((c, 0)));
+        assert_eq!(bus.lookup(0x110f), Some((c, 0xf)));
+        assert_eq!(bus.lookup(0x1110), None);
+        assert_eq!(bus.lookup(0x1fff), None);
+        assert_eq!(bus.lookup(0x2000), Some((b, 0)));
+        assert_eq!(bus.lookup(0x20ff), Some((b, 0xff)));
+        assert_eq!(bus.lookup(0x2100), None);
+        assert_eq!(bus.lookup(u64::MAX), None);
+
+        bus.store(0x1004, 64, 0x10).unwrap();
+        assert_eq!(bus.load(0x1000, 64), Ok(0x14));
+        assert_eq!(bus.get::<Register>(a).map(|r| r.0), Some(0x14));
+        assert_eq!(bus.load(0x1110, 64), Err(Exception::LoadAccessFault));
+        assert_eq!(
+            bus.store(0x1110, 64, 0),
+            Err(Exception::StoreAMOAccessFault)
+        );
+    }
+
+    #[test]
+    fn shared_devices_are_seen_by_every_clone() {
+        let mut bus = bus();
+        let id = register(&mut bus, "a", 0x1000, 0x100).unwrap();
+        let mut clone = bus.share();
+        assert!(!clone.polls());
+        // A shared device can't be borrowed, only locked.
+        assert!(bus.get::<Register>(id).is_none());
+        clone.store(0x1000, 64, 7).unwrap();
+        assert_eq!(bus.with::<Register, _>(id, |r| r.0), Some(7));
+        bus.store(0x1000, 64, 9).unwrap();
+        assert_eq!(clone.load(0x1000, 64), Ok(9));
+        clone.store(MEMORY_BASE, 64, 0x55).unwrap();
+        assert_eq!(bus.load(MEMORY_BASE, 64), Ok(0x55));
+
+        drop(clone);
+        bus.unshare();
+        assert_eq!(bus.get::<Register>(id).map(|r| r.0), Some(9));
+        assert_eq!(bus.find::<Register>(), Some(id));
     }
 }
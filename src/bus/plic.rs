@@ -2,72 +2,260 @@
 //! The plic connects all external interrupts in the system to all hart
 //! contexts in the system, via the external interrupt source in each hart.
 //! It's the global interrupt controller in a RISC-V system.
+//!
+//! As on the QEMU virt machine, every hart has two contexts: context 2n is the M-mode of hart n
+//! and context 2n + 1 its S-mode.
+
+use std::cell::Cell;
+use std::io;
 
 use crate::bus::Device;
+use crate::cpu::{MIP_MEIP, MIP_SEIP};
 use crate::exception::Exception;
+use crate::snapshot::{Reader, Snapshot, Writer};
 
 pub const PLIC_BASE: u64 = 0xc00_0000;
 pub const PLIC_SIZE: u64 = 0x4000000;
-/// The address of interrupt pending bits.
-pub const PLIC_PENDING: u64 = PLIC_BASE + 0x1000;
-/// The address of the regsiters to enable interrupts for S-mode.
-pub const PLIC_SENABLE: u64 = PLIC_BASE + 0x2080;
-/// The address of the registers to set a priority for S-mode.
-pub const PLIC_SPRIORITY: u64 = PLIC_BASE + 0x201000;
-/// The address of the claim/complete registers for S-mode.
-pub const PLIC_SCLAIM: u64 = PLIC_BASE + 0x201004;
+/// The number of interrupt sources. Source 0 means "no interrupt".
+pub const PLIC_SOURCES: usize = 64;
+/// The offset of the priority registers, one per source.
+pub const PLIC_PRIORITY: u64 = 0x0;
+/// The offset of interrupt pending bits.
+pub const PLIC_PENDING: u64 = 0x1000;
+/// The offset of the registers to enable interrupts for context 0. The registers of the other
+/// contexts follow, `PLIC_ENABLE_STRIDE` bytes apart.
+pub const PLIC_ENABLE: u64 = 0x2000;
+pub const PLIC_ENABLE_STRIDE: u64 = 0x80;
+/// The offset of the priority threshold register of context 0. The registers of the other
+/// contexts follow, `PLIC_CONTEXT_STRIDE` bytes apart.
+pub const PLIC_THRESHOLD: u64 = 0x20_0000;
+/// The offset of the claim/complete register of context 0.
+pub const PLIC_CLAIM: u64 = 0x20_0004;
+pub const PLIC_CONTEXT_STRIDE: u64 = 0x1000;
 
 /// The platform-level-interrupt controller (PLIC).
 pub struct Plic {
-    pending: u64,
-    senable: u64,
-    spriority: u64,
-    sclaim: u64,
+    priority: [u32; PLIC_SOURCES],
+    /// Reading the claim register clears a pending bit and puts the source in service, hence the
+    /// cells.
+    pending: Cell<u64>,
+    /// The sources claimed but not completed yet. The gateway doesn't forward their requests
+    /// until they complete, so that one hart handles an interrupt at a time.
+    in_service: Cell<u64>,
+    /// The sources that raised an interrupt while in service. They become pending again when
+    /// they complete.
+    deferred: u64,
+    /// The enabled sources of every context.
+    enable: Vec<u64>,
+    threshold: Vec<u32>,
+}
+
+impl Default for Plic {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Device for Plic {
-    fn load(&self, addr: u64, size: usize) -> Result<u64, Exception> {
+    fn load(&self, offset: u64, size: usize) -> Result<u64, Exception> {
         match size {
-            32 => Ok(self.load32(addr)),
+            32 => Ok(self.load32(offset)),
             _ => Err(Exception::LoadAccessFault),
         }
     }
 
-    fn store(&mut self, addr: u64, size: usize, value: u64) -> Result<(), Exception> {
+    fn store(&mut self, offset: u64, size: usize, value: u64) -> Result<(), Exception> {
         match size {
-            32 => Ok(self.store32(addr, value)),
-            _ => Err(Exception::StoreAMOAccessFault),
+            32 => self.store32(offset, value),
+            _ => return Err(Exception::StoreAMOAccessFault),
         }
+        Ok(())
     }
 }
 
 impl Plic {
+    /// The mip bits the PLIC drives.
+    pub const LINES: u64 = MIP_MEIP | MIP_SEIP;
+
     pub fn new() -> Self {
+        Self::with_harts(1)
+    }
+
+    /// Create a PLIC with the contexts of `harts` harts.
+    pub fn with_harts(harts: usize) -> Self {
+        let contexts = 2 * harts.max(1);
         Self {
-            pending: 0,
-            senable: 0,
-            spriority: 0,
-            sclaim: 0,
+            priority: [0; PLIC_SOURCES],
+            pending: Cell::new(0),
+            in_service: Cell::new(0),
+            deferred: 0,
+            enable: vec![0; contexts],
+            threshold: vec![0; contexts],
         }
     }
 
-    fn load32(&self, addr: u64) -> u64 {
-        match addr {
-            PLIC_PENDING => self.pending,
-            PLIC_SENABLE => self.senable,
-            PLIC_SPRIORITY => self.spriority,
-            PLIC_SCLAIM => self.sclaim,
-            _ => 0,
+    /// Make `irq` pending, or pending once it completes if it's in service.
+    pub fn raise(&mut self, irq: u64) {
+        if !(1..PLIC_SOURCES as u64).contains(&irq) {
+            return;
+        }
+        if self.in_service.get() & 1 << irq != 0 {
+            self.deferred |= 1 << irq;
+        } else {
+            self.pending.set(self.pending.get() | 1 << irq);
         }
     }
 
-    fn store32(&mut self, addr: u64, value: u64) {
-        match addr {
-            PLIC_PENDING => self.pending = value,
-            PLIC_SENABLE => self.senable = value,
-            PLIC_SPRIORITY => self.spriority = value,
-            PLIC_SCLAIM => self.sclaim = value,
+    /// Complete the interrupt `irq` claimed before.
+    fn complete(&mut self, irq: u64) {
+        if !(1..PLIC_SOURCES as u64).contains(&irq) || self.in_service.get() & 1 << irq == 0 {
+            return;
+        }
+        self.in_service.set(self.in_service.get() & !(1 << irq));
+        if self.deferred & 1 << irq != 0 {
+            self.deferred &= !(1 << irq);
+            self.pending.set(self.pending.get() | 1 << irq);
+        }
+    }
+
+    /// Return the pending interrupt with the highest priority that `context` takes, if any.
+    /// Ties go to the lowest source.
+    fn best(&self, context: usize) -> Option<usize> {
+        let candidates = self.pending.get() & *self.enable.get(context)?;
+        let threshold = self.threshold[context];
+        (1..PLIC_SOURCES)
+            .filter(|irq| candidates & 1 << irq != 0 && self.priority[*irq] > threshold)
+            .min_by_key(|irq| std::cmp::Reverse(self.priority[*irq]))
+    }
+
+    /// Return the mip bits raised for `hart`.
+    pub fn lines(&self, hart: usize) -> u64 {
+        let mut lines = 0;
+        if self.best(2 * hart).is_some() {
+            lines |= MIP_MEIP;
+        }
+        if self.best(2 * hart + 1).is_some() {
+            lines |= MIP_SEIP;
+        }
+        lines
+    }
+
+    /// Claim the interrupt `context` takes, which stops it from being pending until it
+    /// completes.
+    fn claim(&self, context: usize) -> u64 {
+        match self.best(context) {
+            Some(irq) => {
+                self.pending.set(self.pending.get() & !(1 << irq));
+                self.in_service.set(self.in_service.get() | 1 << irq);
+                irq as u64
+            }
+            None => 0,
+        }
+    }
+
+    fn load32(&self, offset: u64) -> u64 {
+        let half = |word: u64| {
+            if offset.is_multiple_of(8) {
+                word & 0xffff_ffff
+            } else {
+                word >> 32
+            }
+        };
+        match offset {
+            PLIC_PRIORITY..PLIC_PENDING => self
+                .priority
+                .get((offset / 4) as usize)
+                .copied()
+                .unwrap_or(0) as u64,
+            PLIC_PENDING..PLIC_ENABLE => match offset - PLIC_PENDING {
+                0 | 4 => half(self.pending.get()),
+                _ => 0,
+            },
+            PLIC_ENABLE..PLIC_THRESHOLD => {
+                let context = ((offset - PLIC_ENABLE) / PLIC_ENABLE_STRIDE) as usize;
+                match (offset - PLIC_ENABLE) % PLIC_ENABLE_STRIDE {
+                    0 | 4 => half(self.enable.get(context).copied().unwrap_or(0)),
+                    _ => 0,
+                }
+            }
+            _ => {
+                let context = ((offset - PLIC_THRESHOLD) / PLIC_CONTEXT_STRIDE) as usize;
+                if context >= self.threshold.len() {
+                    return 0;
+                }
+                match PLIC_THRESHOLD + (offset - PLIC_THRESHOLD) % PLIC_CONTEXT_STRIDE {
+                    PLIC_THRESHOLD => self.threshold[context] as u64,
+                    PLIC_CLAIM => self.claim(context),
+                    _ => 0,
+                }
+            }
+        }
+    }
+
+    fn store32(&mut self, offset: u64, value: u64) {
+        let value = value & 0xffff_ffff;
+        match offset {
+            PLIC_PRIORITY..PLIC_PENDING => {
+                if let Some(priority) = self.priority.get_mut((offset / 4) as usize) {
+                    *priority = value as u32;
+                }
+            }
+            PLIC_ENABLE..PLIC_THRESHOLD => {
+                let context = ((offset - PLIC_ENABLE) / PLIC_ENABLE_STRIDE) as usize;
+                let shift = match (offset - PLIC_ENABLE) % PLIC_ENABLE_STRIDE {
+                    0 => 0,
+                    4 => 32,
+                    _ => return,
+                };
+                if let Some(enable) = self.enable.get_mut(context) {
+                    // Source 0 doesn't exist.
+                    *enable = ((*enable & !(0xffff_ffff << shift)) | value << shift) & !1;
+                }
+            }
+            PLIC_THRESHOLD.. => {
+                let context = ((offset - PLIC_THRESHOLD) / PLIC_CONTEXT_STRIDE) as usize;
+                if context >= self.threshold.len() {
+                    return;
+                }
+                match PLIC_THRESHOLD + (offset - PLIC_THRESHOLD) % PLIC_CONTEXT_STRIDE {
+                    PLIC_THRESHOLD => self.threshold[context] = value as u32,
+                    // Writing the claim register completes an interrupt.
+                    PLIC_CLAIM => self.complete(value),
+                    _ => {}
+                }
+            }
+            // The pending bits are read-only.
             _ => {}
         }
     }
 }
+
+impl Snapshot for Plic {
+    fn save(&self, w: &mut Writer) -> io::Result<()> {
+        for priority in self.priority.iter() {
+            w.put_u32(*priority);
+        }
+        w.put_u64(self.pending.get());
+        w.put_u64(self.in_service.get());
+        w.put_u64(self.deferred);
+        for (enable, threshold) in self.enable.iter().zip(self.threshold.iter()) {
+            w.put_u64(*enable);
+            w.put_u32(*threshold);
+        }
+        Ok(())
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        for priority in self.priority.iter_mut() {
+            *priority = r.get_u32()?;
+        }
+        self.pending.set(r.get_u64()?);
+        self.in_service.set(r.get_u64()?);
+        self.deferred = r.get_u64()?;
+        for (enable, threshold) in self.enable.iter_mut().zip(self.threshold.iter_mut()) {
+            *enable = r.get_u64()?;
+            *threshold = r.get_u32()?;
+        }
+        Ok(())
+    }
+}
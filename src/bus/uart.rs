@@ -4,25 +4,29 @@
 
 #![allow(dead_code)]
 
+use std::fs::File;
 use std::io;
 use std::io::prelude::*;
+use std::path::PathBuf;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, Condvar, Mutex,
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    mpsc::{self, Receiver},
+    Arc, Mutex,
 };
 use std::thread;
 
-use crate::bus::Device;
+use crate::bus::{Device, Memory};
 use crate::exception::*;
+use crate::snapshot::{invalid, Reader, Snapshot, Writer};
 
 pub const UART_BASE: u64 = 0x1000_0000;
 pub const UART_SIZE: u64 = 0x100;
 /// Receive holding register (for input bytes).
-pub const UART_RHR: u64 = UART_BASE + 0;
+pub const UART_RHR: u64 = 0;
 /// Transmit holding register (for output bytes).
-pub const UART_THR: u64 = UART_BASE + 0;
+pub const UART_THR: u64 = 0;
 /// Line control register.
-pub const UART_LCR: u64 = UART_BASE + 3;
+pub const UART_LCR: u64 = 3;
 /// Line status register.
 /// LSR BIT 0:
 ///     0 = no data in receive holding register or FIFO.
@@ -30,7 +34,7 @@ pub const UART_LCR: u64 = UART_BASE + 3;
 /// LSR BIT 5:
 ///     0 = transmit holding register is full. 16550 will not accept any data for transmission.
 ///     1 = transmitter hold register (or FIFO) is empty. CPU can load the next character.
-pub const UART_LSR: u64 = UART_BASE + 5;
+pub const UART_LSR: u64 = 5;
 
 /// The receiver (RX) bit.
 pub const UART_LSR_RX: u8 = 1;
@@ -39,85 +43,181 @@ pub const UART_LSR_TX: u8 = 1 << 5;
 /// The interrupt request of UART.
 pub const UART_IRQ: u64 = 10;
 
+/// Where the output of a UART goes and its input comes from.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum UartBackend {
+    /// Print to stdout and read from stdin.
+    #[default]
+    Stdio,
+    /// Discard output and never receive input.
+    Null,
+    /// Write output to a file and never receive input.
+    File(PathBuf),
+}
+
 pub struct Uart {
-    /// Pair of an array for UART buffer and a conditional variable.
-    uart: Arc<(Mutex<[u8; UART_SIZE as usize]>, Condvar)>,
+    /// An array for UART buffer.
+    uart: Mutex<[u8; UART_SIZE as usize]>,
     /// Bit if an interrupt happens.
-    interrupt: Arc<AtomicBool>,
+    interrupt: AtomicBool,
+    /// Bytes read from stdin by a background thread, waiting to be received.
+    input: Option<Receiver<u8>>,
+    /// The number of bytes sent by the background thread and not received yet. Checking it is
+    /// much cheaper than locking the registers, which happens before every instruction.
+    waiting: Arc<AtomicUsize>,
+    /// Where transmitted bytes are written.
+    output: Box<dyn Write + Send>,
+    /// Discard output, e.g. while instructions are executed again for reverse debugging.
+    pub silent: bool,
 }
 
 impl Uart {
+    /// Create a UART connected to stdin and stdout.
     pub fn new() -> Self {
-        let uart = Arc::new((Mutex::new([0; UART_SIZE as usize]), Condvar::new()));
-        let interrupt = Arc::new(AtomicBool::new(false));
-        {
-            let (uart, _) = &*uart;
-            let mut uart = uart.lock().unwrap();
-            uart[(UART_LSR - UART_BASE) as usize] |= UART_LSR_TX;
+        let waiting = Arc::new(AtomicUsize::new(0));
+        let input = Self::read_stdin(Arc::clone(&waiting));
+        let mut uart = Self::with_output(Box::new(io::stdout()), Some(input));
+        uart.waiting = waiting;
+        uart
+    }
+
+    /// Create a UART connected to `backend`.
+    pub fn with_backend(backend: &UartBackend) -> io::Result<Self> {
+        Ok(match backend {
+            UartBackend::Stdio => Self::new(),
+            UartBackend::Null => Self::with_output(Box::new(io::sink()), None),
+            UartBackend::File(path) => Self::with_output(Box::new(File::create(path)?), None),
+        })
+    }
+
+    fn with_output(output: Box<dyn Write + Send>, input: Option<Receiver<u8>>) -> Self {
+        let mut uart = [0; UART_SIZE as usize];
+        uart[UART_LSR as usize] |= UART_LSR_TX;
+        Self {
+            uart: Mutex::new(uart),
+            interrupt: AtomicBool::new(false),
+            input,
+            waiting: Arc::new(AtomicUsize::new(0)),
+            output,
+            silent: false,
         }
+    }
 
+    /// Read stdin in a background thread. Input is handed to the emulator thread, which delivers
+    /// it at an instruction boundary. This keeps the moment a byte arrives under the emulator's
+    /// control.
+    fn read_stdin(waiting: Arc<AtomicUsize>) -> Receiver<u8> {
+        let (sender, input) = mpsc::sync_channel(1);
         let mut byte = [0];
-        let cloned_uart = uart.clone();
-        let cloned_interrupt = interrupt.clone();
         let _uart_thread_for_read = thread::spawn(move || loop {
             match io::stdin().read(&mut byte) {
+                // Stop reading once stdin reaches end-of-file.
+                Ok(0) => return,
                 Ok(_) => {
-                    let (uart, cvar) = &*cloned_uart;
-                    let mut uart = uart.lock().unwrap();
-                    while (uart[(UART_LSR - UART_BASE) as usize] & UART_LSR_RX) == 1 {
-                        uart = cvar.wait(uart).unwrap();
+                    if sender.send(byte[0]).is_err() {
+                        return;
                     }
-
-                    uart[0] = byte[0];
-                    cloned_interrupt.store(true, Ordering::Release);
-                    uart[(UART_LSR - UART_BASE) as usize] |= UART_LSR_RX;
+                    waiting.fetch_add(1, Ordering::Release);
                 }
                 Err(e) => eprintln!("{}", e),
             }
         });
-        Self { uart, interrupt }
+        input
     }
 
-    /// Return true if an interrupt is pending. Clear the flag by swapping a value.
-    pub fn is_interrupting(&self) -> bool {
-        self.interrupt.swap(false, Ordering::Acquire)
+    /// Return true if the receive holding register is empty and can take another byte.
+    pub fn can_receive(&self) -> bool {
+        let uart = self.uart.lock().unwrap();
+        (uart[UART_LSR as usize] & UART_LSR_RX) == 0
+    }
+
+    /// Return true if a byte typed on stdin may be waiting to be received.
+    pub fn has_input(&self) -> bool {
+        self.waiting.load(Ordering::Acquire) > 0
+    }
+
+    /// Return the next byte typed on stdin, if there is one.
+    pub fn poll_input(&self) -> Option<u8> {
+        let byte = self.input.as_ref()?.try_recv().ok()?;
+        // The sender counts a byte only after sending it, so this may briefly wrap below zero;
+        // `has_input` then reports input until the count catches up, which is harmless.
+        self.waiting.fetch_sub(1, Ordering::AcqRel);
+        Some(byte)
+    }
+
+    /// Put `byte` into the receive holding register and raise an interrupt.
+    pub fn receive(&self, byte: u8) {
+        let mut uart = self.uart.lock().unwrap();
+        uart[UART_RHR as usize] = byte;
+        uart[UART_LSR as usize] |= UART_LSR_RX;
+        self.interrupt.store(true, Ordering::Release);
+    }
+}
+
+impl Default for Uart {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl Device for Uart {
-    fn load(&self, addr: u64, size: usize) -> Result<u64, Exception> {
+    fn load(&self, offset: u64, size: usize) -> Result<u64, Exception> {
         match size {
             8 => {
-                let (uart, cvar) = &*self.uart;
-                let mut uart = uart.lock().unwrap();
-                match addr {
+                let mut uart = self.uart.lock().unwrap();
+                match offset {
                     UART_RHR => {
-                        cvar.notify_one();
-                        uart[(UART_LSR - UART_BASE) as usize] &= !UART_LSR_RX;
-                        Ok(uart[(UART_RHR - UART_BASE) as usize] as u64)
+                        uart[UART_LSR as usize] &= !UART_LSR_RX;
+                        Ok(uart[UART_RHR as usize] as u64)
                     }
-                    _ => Ok(uart[(addr - UART_BASE) as usize] as u64),
+                    _ => Ok(uart[offset as usize] as u64),
                 }
             }
             _ => Err(Exception::LoadAccessFault),
         }
     }
 
-    fn store(&mut self, addr: u64, size: usize, value: u64) -> Result<(), Exception> {
+    fn store(&mut self, offset: u64, size: usize, value: u64) -> Result<(), Exception> {
         match size {
             8 => {
-                let (uart, _) = &*self.uart;
-                let mut uart = uart.lock().unwrap();
-                match addr {
+                let mut uart = self.uart.lock().unwrap();
+                match offset {
+                    UART_THR if self.silent => {}
                     UART_THR => {
-                        print!("{}", value as u8 as char);
-                        io::stdout().flush().expect("failed to flush stdout");
+                        // Output is best effort: a closed stdout mustn't stop the machine.
+                        let _ = self.output.write_all(&[value as u8]);
+                        let _ = self.output.flush();
                     }
-                    _ => uart[(addr - UART_BASE) as usize] = value as u8,
+                    _ => uart[offset as usize] = value as u8,
                 }
                 Ok(())
             }
             _ => Err(Exception::StoreAMOAccessFault),
         }
     }
+
+    /// Return true if an interrupt is pending. Clear the flag by swapping a value.
+    fn interrupting(&mut self, _memory: &mut Memory) -> bool {
+        self.interrupt.swap(false, Ordering::Acquire)
+    }
+}
+
+impl Snapshot for Uart {
+    fn save(&self, w: &mut Writer) -> io::Result<()> {
+        let uart = self.uart.lock().unwrap();
+        w.put_bytes(&uart[..]);
+        w.put_bool(self.interrupt.load(Ordering::Acquire));
+        Ok(())
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        let registers = r.get_bytes()?;
+        if registers.len() != UART_SIZE as usize {
+            return Err(invalid("UART registers have the wrong size"));
+        }
+        let mut uart = self.uart.lock().unwrap();
+        uart.copy_from_slice(&registers);
+        self.interrupt.store(r.get_bool()?, Ordering::Release);
+        Ok(())
+    }
 }
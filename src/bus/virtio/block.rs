@@ -0,0 +1,271 @@
+//! The block module is the virtio block device, a disk whose contents are kept by a `Storage`.
+//! Besides reading and writing sectors, it flushes, tells its ID, and discards and zeroes ranges
+//! of sectors.
+
+use std::io;
+
+use super::queue::{Chain, Queue};
+use super::storage::Storage;
+use super::{VirtioDevice, VIRTIO_F_EVENT_IDX};
+use crate::bus::Memory;
+use crate::snapshot::{Reader, Snapshot, Writer};
+
+/// The size of a sector, the unit of the disk's capacity and of the sectors requests give.
+const SECTOR_SIZE: u64 = 512;
+/// The most sectors a discard or a write-zeroes segment may cover.
+const MAX_SECTORS: u32 = 1 << 22;
+/// The most segments a discard or a write-zeroes request may have.
+const MAX_SEGMENTS: u32 = 32;
+/// The ID a GET_ID request returns, zero-padded to 20 bytes.
+const ID: &[u8] = b"honga-disk";
+const ID_SIZE: usize = 20;
+
+/// The most bytes zeroed at once.
+const ZERO_CHUNK: u64 = 1 << 16;
+
+// Features.
+/// The disk is read-only.
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+/// blk_size in the config space is valid.
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+/// The device handles FLUSH.
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+/// The device handles DISCARD.
+const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+/// The device handles WRITE_ZEROES.
+const VIRTIO_BLK_F_WRITE_ZEROES: u64 = 1 << 14;
+
+// Request types.
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+
+// Request status.
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// The flag of a write-zeroes segment that lets the device unmap the sectors.
+const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
+
+/// Return the little-endian value of `bytes`.
+fn le(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |value, byte| value << 8 | *byte as u64)
+}
+
+/// A virtio disk.
+pub struct Block {
+    storage: Box<dyn Storage>,
+}
+
+impl VirtioDevice for Block {
+    fn device_id(&self) -> u32 {
+        2
+    }
+
+    /// A disk has one queue, the requestq.
+    fn queues(&self) -> usize {
+        1
+    }
+
+    fn features(&self) -> u64 {
+        let features = VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH;
+        if self.storage.read_only() {
+            features | VIRTIO_BLK_F_RO
+        } else {
+            features | VIRTIO_BLK_F_DISCARD | VIRTIO_BLK_F_WRITE_ZEROES
+        }
+    }
+
+    /// Return the config space, `struct virtio_blk_config`, up to write_zeroes_may_unmap.
+    fn config(&self) -> Vec<u8> {
+        let mut config = vec![0; 60];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            config[offset..offset + bytes.len()].copy_from_slice(bytes)
+        };
+        put(0, &self.capacity().to_le_bytes());
+        put(20, &(SECTOR_SIZE as u32).to_le_bytes());
+        // max_discard_sectors, max_discard_seg and discard_sector_alignment.
+        put(36, &MAX_SECTORS.to_le_bytes());
+        put(40, &MAX_SEGMENTS.to_le_bytes());
+        put(44, &1u32.to_le_bytes());
+        // max_write_zeroes_sectors, max_write_zeroes_seg and write_zeroes_may_unmap.
+        put(48, &MAX_SECTORS.to_le_bytes());
+        put(52, &MAX_SEGMENTS.to_le_bytes());
+        put(56, &[1]);
+        config
+    }
+
+    fn process(
+        &mut self,
+        memory: &mut Memory,
+        queues: &mut [Queue],
+        notified: u32,
+        features: u64,
+    ) -> bool {
+        if notified & 1 == 0 {
+            return false;
+        }
+        let event_idx = features & VIRTIO_F_EVENT_IDX != 0;
+        queues[0].serve(memory, event_idx, |memory, chain| {
+            self.request(memory, chain)
+        })
+    }
+}
+
+impl Block {
+    /// Create a disk whose contents `storage` keeps.
+    pub fn new(storage: Box<dyn Storage>) -> Self {
+        Self { storage }
+    }
+
+    /// Return the capacity of the disk in sectors.
+    fn capacity(&self) -> u64 {
+        self.storage.size() / SECTOR_SIZE
+    }
+
+    /// Return the byte offset of `count` sectors from `sector` if they're all on the disk.
+    fn offset(&self, sector: u64, count: u64) -> Option<u64> {
+        let end = sector.checked_add(count)?;
+        (end <= self.capacity()).then_some(sector * SECTOR_SIZE)
+    }
+
+    /// Carry out the request in `chain`, reading and writing `memory` directly (DMA), and
+    /// return the number of bytes written to its buffers, status byte included.
+    ///
+    /// A request is a header, `struct virtio_blk_req { le32 type; le32 reserved; le64 sector; }`,
+    /// the data, and the status byte in the last writable byte.
+    fn request(&mut self, memory: &mut Memory, chain: &Chain) -> u32 {
+        let writable = chain.writable_len();
+        let out = match chain.read(memory) {
+            Ok(out) if out.len() >= 16 && writable > 0 => out,
+            // There's no header or no room for the status.
+            _ => return 0,
+        };
+        let kind = le(&out[0..4]) as u32;
+        let sector = le(&out[8..16]);
+        let data = &out[16..];
+        // The writable bytes before the status byte.
+        let data_len = writable - 1;
+
+        let (status, written) = match kind {
+            VIRTIO_BLK_T_IN => match self.offset(sector, data_len / SECTOR_SIZE) {
+                Some(offset) if data_len.is_multiple_of(SECTOR_SIZE) => {
+                    let mut buf = vec![0; data_len as usize];
+                    match self.storage.read_at(offset, &mut buf) {
+                        Ok(()) if chain.write(memory, 0, &buf).is_ok() => {
+                            (VIRTIO_BLK_S_OK, data_len)
+                        }
+                        _ => (VIRTIO_BLK_S_IOERR, 0),
+                    }
+                }
+                _ => (VIRTIO_BLK_S_IOERR, 0),
+            },
+            VIRTIO_BLK_T_OUT => {
+                let count = data.len() as u64 / SECTOR_SIZE;
+                match self.offset(sector, count) {
+                    Some(offset) if (data.len() as u64).is_multiple_of(SECTOR_SIZE) => {
+                        match self.storage.write_at(offset, data) {
+                            Ok(()) => (VIRTIO_BLK_S_OK, 0),
+                            Err(_) => (VIRTIO_BLK_S_IOERR, 0),
+                        }
+                    }
+                    _ => (VIRTIO_BLK_S_IOERR, 0),
+                }
+            }
+            VIRTIO_BLK_T_FLUSH => match self.storage.flush() {
+                Ok(()) => (VIRTIO_BLK_S_OK, 0),
+                Err(_) => (VIRTIO_BLK_S_IOERR, 0),
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = [0; ID_SIZE];
+                id[..ID.len()].copy_from_slice(ID);
+                let len = data_len.min(ID_SIZE as u64);
+                match chain.write(memory, 0, &id[..len as usize]) {
+                    Ok(()) => (VIRTIO_BLK_S_OK, len),
+                    Err(_) => (VIRTIO_BLK_S_IOERR, 0),
+                }
+            }
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+                (self.zero(data, kind == VIRTIO_BLK_T_DISCARD), 0)
+            }
+            _ => (VIRTIO_BLK_S_UNSUPP, 0),
+        };
+
+        match chain.write(memory, data_len, &[status]) {
+            Ok(()) => written as u32 + 1,
+            Err(_) => 0,
+        }
+    }
+
+    /// Zero the sectors of the segments in `data` for a discard or a write-zeroes request, and
+    /// return the status. A segment is
+    /// `struct virtio_blk_discard_write_zeroes { le64 sector; le32 num_sectors; le32 flags; }`.
+    fn zero(&mut self, data: &[u8], discard: bool) -> u8 {
+        if self.storage.read_only() {
+            return VIRTIO_BLK_S_IOERR;
+        }
+        if data.is_empty()
+            || !data.len().is_multiple_of(16)
+            || data.len() / 16 > MAX_SEGMENTS as usize
+        {
+            return VIRTIO_BLK_S_IOERR;
+        }
+        let mut segments = Vec::new();
+        for segment in data.chunks(16) {
+            let sector = le(&segment[0..8]);
+            let count = le(&segment[8..12]) as u32;
+            let flags = le(&segment[12..16]) as u32;
+            // Discarding has no flags, and the only flag of write-zeroes is UNMAP.
+            let known = if discard {
+                0
+            } else {
+                VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP
+            };
+            if flags & !known != 0 {
+                return VIRTIO_BLK_S_UNSUPP;
+            }
+            match self.offset(sector, count as u64) {
+                Some(offset) if count <= MAX_SECTORS => {
+                    segments.push((offset, count as u64 * SECTOR_SIZE))
+                }
+                _ => return VIRTIO_BLK_S_IOERR,
+            }
+        }
+        // Discarded sectors read as zeros, like unmapped ones.
+        let zeros = vec![0; ZERO_CHUNK as usize];
+        for (offset, len) in segments {
+            let mut done = 0;
+            while done < len {
+                let count = (len - done).min(ZERO_CHUNK);
+                if self
+                    .storage
+                    .write_at(offset + done, &zeros[..count as usize])
+                    .is_err()
+                {
+                    return VIRTIO_BLK_S_IOERR;
+                }
+                done += count;
+            }
+        }
+        VIRTIO_BLK_S_OK
+    }
+}
+
+impl Snapshot for Block {
+    fn save(&self, w: &mut Writer) -> io::Result<()> {
+        w.put_sparse(&self.storage.contents()?);
+        Ok(())
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        let contents = r.get_sparse(self.storage.max_restore())?;
+        self.storage.restore(contents)
+    }
+}
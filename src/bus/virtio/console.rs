@@ -0,0 +1,631 @@
+//! The console module is the virtio console, a channel between the guest and the host that is
+//! much faster than the UART. Its console port, which Linux calls hvc0, is connected to one of
+//! the character backends of the UART, and every named port is a Unix socket on the host that
+//! one program at a time may connect to, e.g. to stream logs or talk to an agent in the guest.
+//! The guest finds a named port at /dev/virtio-ports/<name>.
+//!
+//! The ports are set up with control messages, which need VIRTIO_CONSOLE_F_MULTIPORT. A driver
+//! that doesn't accept it only has the console port.
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::inbox::{Inbox, Outbox};
+use super::queue::Queue;
+use super::{VirtioDevice, VIRTIO_F_EVENT_IDX};
+use crate::bus::{Memory, UartBackend};
+use crate::snapshot::{invalid, Reader, Snapshot, Writer};
+
+/// The largest number of ports, with the console port. Every port has two queues and so do
+/// the control messages, and each queue has a bit in the notifications of the transport.
+pub const MAX_CONSOLE_PORTS: usize = 15;
+/// The most chunks of input a port keeps for the guest before the host waits.
+const INBOX_CHUNKS: usize = 16;
+/// The largest chunk of input read from the host at once.
+const CHUNK_SIZE: usize = 4096;
+
+/// The device has ports besides the console port, which are set up with control messages.
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+
+// The events of `struct virtio_console_control`.
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// The queues of control messages, between the ones of the console port and the others.
+const CONTROL_RECEIVEQ: usize = 2;
+const CONTROL_TRANSMITQ: usize = 3;
+
+/// A named port of a virtio console, which a program on the host reaches through a Unix socket
+/// at `path`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsolePort {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// Where the output of a port goes.
+enum Host {
+    /// The character backend of the console port, which is always connected.
+    Console(Box<dyn Write + Send>),
+    /// A Unix socket, through which one program at a time is connected.
+    Socket {
+        peer: Arc<Mutex<Option<UnixStream>>>,
+        connected: Arc<AtomicBool>,
+    },
+}
+
+/// A port: the console port or a named one.
+struct Port {
+    /// The name the guest sees. The console port has none.
+    name: String,
+    host: Host,
+    /// Input for the guest from the host, if the port receives any.
+    inbox: Option<Inbox>,
+    /// Input that didn't fit in the buffers of the driver yet.
+    pending: Vec<u8>,
+    /// The driver added the port, so that the device may tell it about the port.
+    ready: bool,
+    /// A program in the guest has the port open.
+    guest_open: bool,
+    /// Whether the driver was told that the host is connected.
+    host_open: bool,
+}
+
+impl Port {
+    fn new(name: String, host: Host, inbox: Option<Inbox>) -> Self {
+        Self {
+            name,
+            host,
+            inbox,
+            pending: Vec::new(),
+            ready: false,
+            guest_open: false,
+            host_open: false,
+        }
+    }
+
+    /// Return true if a program on the host is connected to the port.
+    fn host_connected(&self) -> bool {
+        match &self.host {
+            Host::Console(_) => true,
+            Host::Socket { connected, .. } => connected.load(Ordering::Acquire),
+        }
+    }
+
+    /// Send output from the guest to the host. Output nobody is connected to is dropped, as on
+    /// a serial line.
+    fn send(&mut self, data: &[u8]) {
+        match &mut self.host {
+            Host::Console(output) => {
+                // Output is best effort: a closed stdout mustn't stop the machine.
+                let _ = output.write_all(data);
+                let _ = output.flush();
+            }
+            Host::Socket { peer, .. } => {
+                let mut peer = peer.lock().unwrap();
+                if let Some(stream) = peer.as_mut() {
+                    if stream.write_all(data).is_err() {
+                        *peer = None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Hang up, so that the program on the host sees it and the thread reading the socket stops.
+impl Drop for Port {
+    fn drop(&mut self) {
+        if let Host::Socket { peer, .. } = &self.host {
+            if let Some(stream) = peer.lock().unwrap().take() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+}
+
+/// A virtio console.
+pub struct Console {
+    /// The console port, followed by the named ports.
+    ports: Vec<Port>,
+    /// Control messages for the driver, waiting for its buffers.
+    control: VecDeque<Vec<u8>>,
+}
+
+impl Console {
+    /// Create a console whose console port is connected to `backend` and which has a Unix
+    /// socket for each of `ports`.
+    pub fn open(backend: &UartBackend, ports: &[ConsolePort]) -> io::Result<Self> {
+        if ports.len() >= MAX_CONSOLE_PORTS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} named ports; a console has at most {}",
+                    ports.len(),
+                    MAX_CONSOLE_PORTS - 1
+                ),
+            ));
+        }
+        let console = match backend {
+            UartBackend::Stdio => {
+                let (outbox, inbox) = Inbox::new(INBOX_CHUNKS);
+                Self::read_stdin(outbox);
+                Port::new(
+                    String::new(),
+                    Host::Console(Box::new(io::stdout())),
+                    Some(inbox),
+                )
+            }
+            UartBackend::Null => {
+                Port::new(String::new(), Host::Console(Box::new(io::sink())), None)
+            }
+            UartBackend::File(path) => Port::new(
+                String::new(),
+                Host::Console(Box::new(File::create(path)?)),
+                None,
+            ),
+        };
+        let mut console = Self {
+            ports: vec![console],
+            control: VecDeque::new(),
+        };
+        for port in ports {
+            console.ports.push(Self::listen(port)?);
+        }
+        Ok(console)
+    }
+
+    /// Read stdin in a background thread, which hands it over in chunks.
+    fn read_stdin(outbox: Outbox) {
+        let _console_thread_for_read = thread::spawn(move || {
+            let mut buffer = vec![0; CHUNK_SIZE];
+            loop {
+                match io::stdin().read(&mut buffer) {
+                    // Stop reading once stdin reaches end-of-file.
+                    Ok(0) => return,
+                    Ok(len) => {
+                        if !outbox.send(buffer[..len].to_vec()) {
+                            return;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Listen on the socket of `port` for a program on the host, which may connect and
+    /// reconnect while the machine runs. A socket left at its path by an earlier run is
+    /// replaced.
+    fn listen(port: &ConsolePort) -> io::Result<Port> {
+        let path = &port.path;
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                fs::remove_file(path)?;
+            }
+        }
+        let listener = UnixListener::bind(path).map_err(|e| socket_error(path, e))?;
+        let (outbox, inbox) = Inbox::new(INBOX_CHUNKS);
+        let peer = Arc::new(Mutex::new(None));
+        let connected = Arc::new(AtomicBool::new(false));
+        let accepted = Arc::clone(&peer);
+        let accepted_connected = Arc::clone(&connected);
+        let _console_thread_for_accept = thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (stream, mut reader) = match stream.and_then(|s| Ok((s.try_clone()?, s))) {
+                    Ok(streams) => streams,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        continue;
+                    }
+                };
+                *accepted.lock().unwrap() = Some(stream);
+                accepted_connected.store(true, Ordering::Release);
+                // One program at a time: the next is accepted once this one hangs up.
+                let mut buffer = vec![0; CHUNK_SIZE];
+                loop {
+                    match reader.read(&mut buffer) {
+                        Ok(0) => break,
+                        Ok(len) => {
+                            if !outbox.send(buffer[..len].to_vec()) {
+                                return;
+                            }
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(_) => break,
+                    }
+                }
+                accepted_connected.store(false, Ordering::Release);
+                *accepted.lock().unwrap() = None;
+            }
+        });
+        Ok(Port::new(
+            port.name.clone(),
+            Host::Socket { peer, connected },
+            Some(inbox),
+        ))
+    }
+
+    /// Return the receiveq of port `id`; its transmitq follows it.
+    fn receiveq(id: usize) -> usize {
+        match id {
+            0 => 0,
+            _ => 2 + 2 * id,
+        }
+    }
+
+    /// Queue the control message `event` about port `id` for the driver.
+    fn tell(&mut self, id: usize, event: u16, value: u16, extra: &[u8]) {
+        // struct virtio_console_control {
+        //     le32 id;
+        //     le16 event;
+        //     le16 value;
+        // };
+        let mut message = (id as u32).to_le_bytes().to_vec();
+        message.extend_from_slice(&event.to_le_bytes());
+        message.extend_from_slice(&value.to_le_bytes());
+        message.extend_from_slice(extra);
+        self.control.push_back(message);
+    }
+
+    /// Act on a control message from the driver.
+    fn handle_control(&mut self, message: &[u8]) {
+        if message.len() < 8 {
+            return;
+        }
+        let id = u32::from_le_bytes([message[0], message[1], message[2], message[3]]) as usize;
+        let event = u16::from_le_bytes([message[4], message[5]]);
+        let value = u16::from_le_bytes([message[6], message[7]]);
+        match event {
+            // The driver is set up; it adds the ports it's told about.
+            VIRTIO_CONSOLE_DEVICE_READY if value == 1 => {
+                for id in 0..self.ports.len() {
+                    self.tell(id, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY if value == 1 && id < self.ports.len() => {
+                if id == 0 {
+                    self.tell(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]);
+                } else {
+                    let name = self.ports[id].name.clone();
+                    self.tell(id, VIRTIO_CONSOLE_PORT_NAME, 1, name.as_bytes());
+                }
+                let port = &mut self.ports[id];
+                port.ready = true;
+                port.host_open = port.host_connected();
+                if port.host_open {
+                    self.tell(id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_OPEN if id < self.ports.len() => {
+                self.ports[id].guest_open = value != 0;
+            }
+            _ => {}
+        }
+    }
+
+    /// Tell the driver about the programs that connected to or hung up on the named ports.
+    fn watch_hosts(&mut self) {
+        for id in 1..self.ports.len() {
+            let port = &mut self.ports[id];
+            if !port.ready {
+                continue;
+            }
+            let connected = port.host_connected();
+            if connected != port.host_open {
+                port.host_open = connected;
+                self.tell(id, VIRTIO_CONSOLE_PORT_OPEN, connected as u16, &[]);
+            }
+        }
+    }
+
+    /// Hand the waiting control messages to the driver while it has buffers for them. Return
+    /// true if the driver wants to know.
+    fn send_control(&mut self, memory: &mut Memory, queue: &mut Queue, event_idx: bool) -> bool {
+        if !queue.usable() || self.control.is_empty() {
+            return false;
+        }
+        let old = queue.used_idx();
+        while !self.control.is_empty() {
+            let chain = match queue.pop(memory, event_idx) {
+                Some(chain) => chain,
+                None => break,
+            };
+            let message = self.control.pop_front().unwrap_or_default();
+            let len = match chain.write(memory, 0, &message) {
+                Ok(()) => message.len() as u32,
+                Err(_) => 0,
+            };
+            queue.push(memory, chain.head, len);
+        }
+        queue.should_interrupt(memory, old, event_idx)
+    }
+
+    /// Hand the input of port `id` to the driver while it has buffers for it. Return true if
+    /// the driver wants to know.
+    fn receive(
+        &mut self,
+        id: usize,
+        memory: &mut Memory,
+        queue: &mut Queue,
+        event_idx: bool,
+    ) -> bool {
+        let port = &mut self.ports[id];
+        if !queue.usable() || !port.guest_open {
+            return false;
+        }
+        let inbox = match port.inbox.as_mut() {
+            Some(inbox) => inbox,
+            None => return false,
+        };
+        let old = queue.used_idx();
+        loop {
+            if port.pending.is_empty() {
+                match inbox.recv() {
+                    Some(data) => port.pending = data,
+                    None => break,
+                }
+            }
+            let chain = match queue.pop(memory, event_idx) {
+                Some(chain) => chain,
+                None => break,
+            };
+            // Input is a stream, so whatever doesn't fit goes in the next buffers.
+            let len = port.pending.len().min(chain.writable_len() as usize);
+            let len = match chain.write(memory, 0, &port.pending[..len]) {
+                Ok(()) => len,
+                Err(_) => 0,
+            };
+            port.pending.drain(..len);
+            queue.push(memory, chain.head, len as u32);
+        }
+        queue.should_interrupt(memory, old, event_idx)
+    }
+}
+
+fn socket_error(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+impl VirtioDevice for Console {
+    fn device_id(&self) -> u32 {
+        3
+    }
+
+    /// Every port has a receiveq and a transmitq, and so do the control messages, after the
+    /// ones of the console port.
+    fn queues(&self) -> usize {
+        2 * (self.ports.len() + 1)
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_MULTIPORT
+    }
+
+    /// Return the config space, `struct virtio_console_config`: cols and rows, which are only
+    /// valid with VIRTIO_CONSOLE_F_SIZE, max_nr_ports and emerg_wr.
+    fn config(&self) -> Vec<u8> {
+        let mut config = vec![0; 4];
+        config.extend_from_slice(&(self.ports.len() as u32).to_le_bytes());
+        config.extend_from_slice(&[0; 4]);
+        config
+    }
+
+    fn process(
+        &mut self,
+        memory: &mut Memory,
+        queues: &mut [Queue],
+        notified: u32,
+        features: u64,
+    ) -> bool {
+        let event_idx = features & VIRTIO_F_EVENT_IDX != 0;
+        let mut interrupt = false;
+        let ports = if features & VIRTIO_CONSOLE_F_MULTIPORT != 0 {
+            if notified & 1 << CONTROL_TRANSMITQ != 0 {
+                let mut messages = Vec::new();
+                interrupt |= queues[CONTROL_TRANSMITQ].serve(memory, event_idx, |memory, chain| {
+                    if let Ok(message) = chain.read(memory) {
+                        messages.push(message);
+                    }
+                    0
+                });
+                for message in messages {
+                    self.handle_control(&message);
+                }
+            }
+            self.watch_hosts();
+            interrupt |= self.send_control(memory, &mut queues[CONTROL_RECEIVEQ], event_idx);
+            self.ports.len()
+        } else {
+            // Without control messages, the console port is the only one, and always open.
+            self.ports[0].guest_open = true;
+            1
+        };
+        for id in 0..ports {
+            let receiveq = Self::receiveq(id);
+            if notified & 1 << (receiveq + 1) != 0 {
+                let port = &mut self.ports[id];
+                interrupt |= queues[receiveq + 1].serve(memory, event_idx, |memory, chain| {
+                    if let Ok(data) = chain.read(memory) {
+                        port.send(&data);
+                    }
+                    0
+                });
+            }
+            interrupt |= self.receive(id, memory, &mut queues[receiveq], event_idx);
+        }
+        interrupt
+    }
+
+    fn reset(&mut self) {
+        self.control.clear();
+        for port in &mut self.ports {
+            port.pending.clear();
+            port.ready = false;
+            port.guest_open = false;
+            port.host_open = false;
+        }
+    }
+}
+
+/// The state of the ports the driver knows is saved, so that it can go on using them. Input on
+/// its way isn't; a restored machine has lost it.
+impl Snapshot for Console {
+    fn save(&self, w: &mut Writer) -> io::Result<()> {
+        w.put_u32(self.ports.len() as u32);
+        for port in &self.ports {
+            w.put_bool(port.ready);
+            w.put_bool(port.guest_open);
+            w.put_bool(port.host_open);
+        }
+        w.put_u32(self.control.len() as u32);
+        for message in &self.control {
+            w.put_bytes(message);
+        }
+        Ok(())
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        if r.get_u32()? as usize != self.ports.len() {
+            return Err(invalid("the console has a different number of ports"));
+        }
+        for port in &mut self.ports {
+            port.pending.clear();
+            port.ready = r.get_bool()?;
+            port.guest_open = r.get_bool()?;
+            port.host_open = r.get_bool()?;
+        }
+        self.control.clear();
+        for _ in 0..r.get_u32()? {
+            self.control.push_back(r.get_bytes()?);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::time::{Duration, Instant};
+
+    fn control(id: u32, event: u16, value: u16) -> Vec<u8> {
+        let mut message = id.to_le_bytes().to_vec();
+        message.extend_from_slice(&event.to_le_bytes());
+        message.extend_from_slice(&value.to_le_bytes());
+        message
+    }
+
+    /// Return the control messages waiting for the driver as (id, event, value, extra).
+    fn told(console: &mut Console) -> Vec<(u32, u16, u16, Vec<u8>)> {
+        console
+            .control
+            .drain(..)
+            .map(|message| {
+                (
+                    u32::from_le_bytes([message[0], message[1], message[2], message[3]]),
+                    u16::from_le_bytes([message[4], message[5]]),
+                    u16::from_le_bytes([message[6], message[7]]),
+                    message[8..].to_vec(),
+                )
+            })
+            .collect()
+    }
+
+    /// Wait until a program on the host has connected to port `id`, or hung up.
+    fn wait_for_host(console: &Console, id: usize, connected: bool) {
+        let start = Instant::now();
+        while console.ports[id].host_connected() != connected {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn control_messages() {
+        let dir = TempDir::new("console-control");
+        let path = dir.join("serial0");
+        let port = ConsolePort {
+            name: "org.honga.serial0".to_string(),
+            path: path.clone(),
+        };
+        let mut console = Console::open(&UartBackend::Null, &[port]).unwrap();
+        assert_eq!(console.queues(), 6);
+        assert_eq!(console.config()[4..8], 2u32.to_le_bytes());
+
+        // Short messages and unknown ports are ignored.
+        console.handle_control(&control(0, VIRTIO_CONSOLE_DEVICE_READY, 1)[..7]);
+        console.handle_control(&control(2, VIRTIO_CONSOLE_PORT_READY, 1));
+        console.handle_control(&control(0, VIRTIO_CONSOLE_DEVICE_READY, 0));
+        assert!(told(&mut console).is_empty());
+
+        console.handle_control(&control(0, VIRTIO_CONSOLE_DEVICE_READY, 1));
+        assert_eq!(
+            told(&mut console),
+            [
+                (0, VIRTIO_CONSOLE_DEVICE_ADD, 0, Vec::new()),
+                (1, VIRTIO_CONSOLE_DEVICE_ADD, 0, Vec::new()),
+            ]
+        );
+        // The console port is always connected.
+        console.handle_control(&control(0, VIRTIO_CONSOLE_PORT_READY, 1));
+        assert_eq!(
+            told(&mut console),
+            [
+                (0, VIRTIO_CONSOLE_CONSOLE_PORT, 1, Vec::new()),
+                (0, VIRTIO_CONSOLE_PORT_OPEN, 1, Vec::new()),
+            ]
+        );
+        // A named port isn't until a program connects to its socket.
+        console.handle_control(&control(1, VIRTIO_CONSOLE_PORT_READY, 1));
+        assert_eq!(
+            told(&mut console),
+            [(
+                1,
+                VIRTIO_CONSOLE_PORT_NAME,
+                1,
+                b"org.honga.serial0".to_vec()
+            )]
+        );
+        console.handle_control(&control(1, VIRTIO_CONSOLE_PORT_OPEN, 1));
+        assert!(console.ports[1].guest_open);
+
+        let stream = UnixStream::connect(&path).unwrap();
+        wait_for_host(&console, 1, true);
+        console.watch_hosts();
+        assert_eq!(
+            told(&mut console),
+            [(1, VIRTIO_CONSOLE_PORT_OPEN, 1, Vec::new())]
+        );
+        console.watch_hosts();
+        assert!(told(&mut console).is_empty());
+        drop(stream);
+        wait_for_host(&console, 1, false);
+        console.watch_hosts();
+        assert_eq!(
+            told(&mut console),
+            [(1, VIRTIO_CONSOLE_PORT_OPEN, 0, Vec::new())]
+        );
+
+        console.handle_control(&control(1, VIRTIO_CONSOLE_PORT_OPEN, 0));
+        assert!(!console.ports[1].guest_open);
+        console.reset();
+        assert!(!console.ports[1].ready);
+    }
+}
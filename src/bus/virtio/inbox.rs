@@ -0,0 +1,57 @@
+//! The inbox module hands data from the background threads of the host backends to a device,
+//! which takes it between instructions.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+
+/// Data for the guest that the background thread of a backend hands over, a frame or a chunk of
+/// a stream at a time.
+pub struct Inbox {
+    buffers: Receiver<Vec<u8>>,
+    /// The number of buffers sent and not received yet. Checking it is much cheaper than the
+    /// channel, which happens before every instruction.
+    waiting: Arc<AtomicUsize>,
+}
+
+/// The sending side of an `Inbox`.
+#[derive(Clone)]
+pub struct Outbox {
+    buffers: SyncSender<Vec<u8>>,
+    waiting: Arc<AtomicUsize>,
+}
+
+impl Inbox {
+    /// Create an inbox that keeps up to `capacity` buffers before the sender waits.
+    pub fn new(capacity: usize) -> (Outbox, Inbox) {
+        let (sender, buffers) = mpsc::sync_channel(capacity);
+        let waiting = Arc::new(AtomicUsize::new(0));
+        let outbox = Outbox {
+            buffers: sender,
+            waiting: Arc::clone(&waiting),
+        };
+        (outbox, Inbox { buffers, waiting })
+    }
+
+    /// Return the next buffer, if one arrived.
+    pub fn recv(&mut self) -> Option<Vec<u8>> {
+        if self.waiting.load(Ordering::Acquire) == 0 {
+            return None;
+        }
+        let buffer = self.buffers.try_recv().ok()?;
+        self.waiting.fetch_sub(1, Ordering::AcqRel);
+        Some(buffer)
+    }
+}
+
+impl Outbox {
+    /// Hand `buffer` to the guest, waiting while the inbox is full. Return false once the device
+    /// is gone.
+    pub fn send(&self, buffer: Vec<u8>) -> bool {
+        if self.buffers.send(buffer).is_err() {
+            return false;
+        }
+        self.waiting.fetch_add(1, Ordering::Release);
+        true
+    }
+}
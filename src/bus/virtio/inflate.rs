@@ -0,0 +1,245 @@
+//! The inflate module decompresses raw deflate streams (RFC 1951), the format of compressed
+//! qcow2 clusters.
+
+/// The most bits of a Huffman code.
+const MAX_BITS: usize = 15;
+
+/// The base lengths and extra bits of the length symbols 257 to 285.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+/// The base distances and extra bits of the distance symbols 0 to 29.
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order in which the lengths of the code length code are given.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// A canonical Huffman code: the number of codes of every length, and the symbols ordered by
+/// their codes.
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    /// Build the code of symbols with code lengths `lengths`, or fail if it's oversubscribed.
+    fn new(lengths: &[u8]) -> Result<Self, &'static str> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        let mut left: i32 = 1;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err("oversubscribed Huffman code");
+            }
+        }
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for length in 1..=MAX_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        counts[0] = 0;
+        Ok(Self { counts, symbols })
+    }
+}
+
+/// A reader of the bits of a stream, least significant first.
+struct Bits<'a> {
+    input: &'a [u8],
+    position: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl<'a> Bits<'a> {
+    fn bits(&mut self, n: u32) -> Result<u32, &'static str> {
+        while self.count < n {
+            let byte = *self
+                .input
+                .get(self.position)
+                .ok_or("unexpected end of the stream")?;
+            self.position += 1;
+            self.buffer |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1u64 << n) - 1) as u32;
+        self.buffer >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// Decode a symbol of `code`, whose bits come most significant first.
+    fn decode(&mut self, code: &Huffman) -> Result<u16, &'static str> {
+        let mut value: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for length in 1..=MAX_BITS {
+            value |= self.bits(1)? as i32;
+            let count = code.counts[length] as i32;
+            if value - count < first {
+                return Ok(code.symbols[(index + value - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            value <<= 1;
+        }
+        Err("invalid Huffman code")
+    }
+}
+
+/// Decompress the raw deflate stream `input`, stopping after its last block or once there are
+/// `limit` bytes.
+pub fn inflate(input: &[u8], limit: usize) -> Result<Vec<u8>, &'static str> {
+    let mut output = Vec::with_capacity(limit);
+    let mut bits = Bits {
+        input,
+        position: 0,
+        buffer: 0,
+        count: 0,
+    };
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => {
+                // A stored block starts at the next byte.
+                bits.buffer = 0;
+                bits.count = 0;
+                let header = input
+                    .get(bits.position..bits.position + 4)
+                    .ok_or("unexpected end of the stream")?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err("invalid stored block length");
+                }
+                let start = bits.position + 4;
+                let data = input
+                    .get(start..start + len as usize)
+                    .ok_or("unexpected end of the stream")?;
+                output.extend_from_slice(data);
+                bits.position = start + len as usize;
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                let literals = Huffman::new(&lengths)?;
+                let distances = Huffman::new(&[5; 30])?;
+                codes(&mut bits, &mut output, limit, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic(&mut bits)?;
+                codes(&mut bits, &mut output, limit, &literals, &distances)?;
+            }
+            _ => return Err("invalid block type"),
+        }
+        if last || output.len() >= limit {
+            output.truncate(limit);
+            return Ok(output);
+        }
+    }
+}
+
+/// Read the codes of a block with dynamic Huffman codes.
+fn dynamic(bits: &mut Bits) -> Result<(Huffman, Huffman), &'static str> {
+    let literals = bits.bits(5)? as usize + 257;
+    let distances = bits.bits(5)? as usize + 1;
+    let code_lengths = bits.bits(4)? as usize + 4;
+    if literals > 286 || distances > 30 {
+        return Err("too many length or distance codes");
+    }
+    let mut lengths = [0u8; 19];
+    for &index in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[index] = bits.bits(3)? as u8;
+    }
+    let code = Huffman::new(&lengths)?;
+
+    let mut lengths = vec![0u8; literals + distances];
+    let mut index = 0;
+    while index < lengths.len() {
+        let symbol = bits.decode(&code)?;
+        let (length, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => match index {
+                0 => return Err("repeat with no first length"),
+                _ => (lengths[index - 1], 3 + bits.bits(2)? as usize),
+            },
+            17 => (0, 3 + bits.bits(3)? as usize),
+            _ => (0, 11 + bits.bits(7)? as usize),
+        };
+        if index + repeat > lengths.len() {
+            return Err("too many lengths");
+        }
+        lengths[index..index + repeat].fill(length);
+        index += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err("no end-of-block code");
+    }
+    Ok((
+        Huffman::new(&lengths[..literals])?,
+        Huffman::new(&lengths[literals..])?,
+    ))
+}
+
+/// Decode the literals and the length and distance pairs of a block until its end.
+fn codes(
+    bits: &mut Bits,
+    output: &mut Vec<u8>,
+    limit: usize,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<(), &'static str> {
+    loop {
+        let symbol = bits.decode(literals)? as usize;
+        match symbol {
+            0..=255 => output.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let symbol = symbol - 257;
+                if symbol >= LENGTH_BASE.len() {
+                    return Err("invalid length symbol");
+                }
+                let len =
+                    LENGTH_BASE[symbol] as usize + bits.bits(LENGTH_EXTRA[symbol] as u32)? as usize;
+                let symbol = bits.decode(distances)? as usize;
+                if symbol >= DIST_BASE.len() {
+                    return Err("invalid distance symbol");
+                }
+                let dist =
+                    DIST_BASE[symbol] as usize + bits.bits(DIST_EXTRA[symbol] as u32)? as usize;
+                if dist > output.len() {
+                    return Err("distance too far back");
+                }
+                let start = output.len() - dist;
+                for i in 0..len {
+                    output.push(output[start + i]);
+                }
+            }
+        }
+        if output.len() >= limit {
+            return Ok(());
+        }
+    }
+}
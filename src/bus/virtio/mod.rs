@@ -0,0 +1,611 @@
+//! The virtio module contains a virtualization standard for network, disk, console and entropy
+//! device drivers.
+//! The devices are attached through virtio-mmio, either with the legacy interface (version 1),
+//! which places a queue by its page frame number, or with the modern one of virtio 1.x (version
+//! 2), which has 64-bit features and takes the addresses of the three parts of a queue. The
+//! transport, `Virtio`, is the same for every type of device, and a `VirtioDevice` serves the
+//! queues.
+//!
+//! The virtio spec:
+//! https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.pdf
+
+mod block;
+mod console;
+mod inbox;
+mod inflate;
+mod net;
+mod qcow2;
+mod queue;
+mod rng;
+mod storage;
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use crate::bus::{Device, Memory};
+use crate::exception::*;
+use crate::snapshot::{Reader, Snapshot, Writer};
+pub use block::Block;
+pub use console::{Console, ConsolePort, MAX_CONSOLE_PORTS};
+pub use net::{open_network, Net, NetBackend, Network, Pcap, SocketNetwork, Tap, UserNetwork};
+pub use qcow2::Qcow2;
+pub use queue::{Chain, Queue};
+pub use rng::{Rng, RngBackend};
+pub use storage::{open_image, FileStorage, MemoryOverlay, Storage};
+
+/// The interrupt request of virtio.
+pub const VIRTIO_IRQ: u64 = 1;
+
+/// The address which virtio starts.
+pub const VIRTIO_BASE: u64 = 0x1000_1000;
+/// The size of virtio.
+pub const VIRTIO_SIZE: u64 = 0x1000;
+/// The number of virtio-mmio slots of the virt machine, at `VIRTIO_BASE + n * VIRTIO_SIZE` with
+/// the interrupt `VIRTIO_IRQ + n`.
+pub const VIRTIO_SLOTS: usize = 8;
+/// The largest number of descriptors of a queue. A driver may choose any power of two up to it.
+const QUEUE_NUM_MAX: u32 = 256;
+
+/// Always return 0x74726976.
+pub const VIRTIO_MAGIC: u64 = 0x000;
+/// The version. 1 is legacy, 2 is virtio 1.x.
+pub const VIRTIO_VERSION: u64 = 0x004;
+/// device type; 1 is net, 2 is disk, 3 is console, 4 is entropy.
+pub const VIRTIO_DEVICE_ID: u64 = 0x008;
+/// Always return 0x554d4551
+pub const VIRTIO_VENDOR_ID: u64 = 0x00c;
+/// Device features, the 32 bits selected by `VIRTIO_DEVICE_FEATURES_SEL`.
+pub const VIRTIO_DEVICE_FEATURES: u64 = 0x010;
+/// Which 32 bits of the device features to read, write-only.
+pub const VIRTIO_DEVICE_FEATURES_SEL: u64 = 0x014;
+/// Driver features, the 32 bits selected by `VIRTIO_DRIVER_FEATURES_SEL`.
+pub const VIRTIO_DRIVER_FEATURES: u64 = 0x020;
+/// Which 32 bits of the driver features to write, write-only.
+pub const VIRTIO_DRIVER_FEATURES_SEL: u64 = 0x024;
+/// Page size for PFN, write-only. Legacy only.
+pub const VIRTIO_GUEST_PAGE_SIZE: u64 = 0x028;
+/// Select queue, write-only.
+pub const VIRTIO_QUEUE_SEL: u64 = 0x030;
+/// Max size of current queue, read-only. 0 if the queue doesn't exist.
+pub const VIRTIO_QUEUE_NUM_MAX: u64 = 0x034;
+/// Size of current queue, write-only.
+pub const VIRTIO_QUEUE_NUM: u64 = 0x038;
+/// The alignment of the used ring, write-only. Legacy only.
+pub const VIRTIO_QUEUE_ALIGN: u64 = 0x03c;
+/// Physical page number for queue, read and write. Legacy only.
+pub const VIRTIO_QUEUE_PFN: u64 = 0x040;
+/// Whether the device may use the queue, read and write. Modern only.
+pub const VIRTIO_QUEUE_READY: u64 = 0x044;
+/// Notify the queue number, write-only.
+pub const VIRTIO_QUEUE_NOTIFY: u64 = 0x050;
+/// Why the device interrupted, read-only.
+pub const VIRTIO_INTERRUPT_STATUS: u64 = 0x060;
+/// Clear bits of the interrupt status, write-only.
+pub const VIRTIO_INTERRUPT_ACK: u64 = 0x064;
+/// Device status, read and write. Reading from this register returns the current device status flags.
+/// Writing non-zero values to this register sets the status flags, indicating the OS/driver
+/// progress. Writing zero (0x0) to this register triggers a device reset.
+pub const VIRTIO_STATUS: u64 = 0x070;
+/// The addresses of the descriptor table, the driver area (available ring) and the device area
+/// (used ring) of the current queue, in 32-bit halves, write-only. Modern only.
+pub const VIRTIO_QUEUE_DESC_LOW: u64 = 0x080;
+pub const VIRTIO_QUEUE_DESC_HIGH: u64 = 0x084;
+pub const VIRTIO_QUEUE_DRIVER_LOW: u64 = 0x090;
+pub const VIRTIO_QUEUE_DRIVER_HIGH: u64 = 0x094;
+pub const VIRTIO_QUEUE_DEVICE_LOW: u64 = 0x0a0;
+pub const VIRTIO_QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+/// Changes whenever the device config space does, read-only. Modern only.
+pub const VIRTIO_CONFIG_GENERATION: u64 = 0x0fc;
+/// The device config space, whose layout depends on the device type.
+pub const VIRTIO_CONFIG: u64 = 0x100;
+
+// Device status flags.
+/// The driver is set up and the device may use the queues.
+pub const STATUS_DRIVER_OK: u32 = 4;
+/// The driver has accepted the features it wrote, and the device checks them.
+pub const STATUS_FEATURES_OK: u32 = 8;
+
+/// The driver may give descriptors that point to a table of descriptors.
+pub const VIRTIO_F_INDIRECT_DESC: u64 = 1 << 28;
+/// The driver and the device say in the rings when they want to be notified.
+pub const VIRTIO_F_EVENT_IDX: u64 = 1 << 29;
+/// The device complies with virtio 1.x. Modern drivers require it and legacy ones don't know it.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// The interrupt status bit that says the device used buffers.
+pub const INTERRUPT_USED_BUFFER: u32 = 1;
+
+/// Which interface of virtio-mmio the devices have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VirtioTransport {
+    /// Version 1, which older kernels such as the xv6 sample need.
+    #[default]
+    Legacy,
+    /// Version 2, from virtio 1.x.
+    Modern,
+}
+
+impl FromStr for VirtioTransport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "legacy" => Ok(VirtioTransport::Legacy),
+            "modern" => Ok(VirtioTransport::Modern),
+            _ => Err(format!(
+                "unknown virtio transport `{}`; expected `legacy` or `modern`",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for VirtioTransport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VirtioTransport::Legacy => write!(f, "legacy"),
+            VirtioTransport::Modern => write!(f, "modern"),
+        }
+    }
+}
+
+/// A type of virtio device, such as a disk, behind the transport. The transport negotiates the
+/// features and sets up the queues, and the device reads and writes their buffers.
+pub trait VirtioDevice: Snapshot + Send {
+    /// Return the device type, e.g. 2 for a disk.
+    fn device_id(&self) -> u32;
+
+    /// Return the number of queues.
+    fn queues(&self) -> usize;
+
+    /// Return the features of the device type. The transport adds the ones of its own.
+    fn features(&self) -> u64;
+
+    /// Return the config space.
+    fn config(&self) -> Vec<u8>;
+
+    /// Serve the queues, a bit of `notified` for each one the driver notified, with the features
+    /// the driver accepted. Return true if the device used buffers and the driver wants to know.
+    /// This is called between instructions while the driver is ready, so that a device can
+    /// also deliver input when nothing was notified.
+    fn process(
+        &mut self,
+        memory: &mut Memory,
+        queues: &mut [Queue],
+        notified: u32,
+        features: u64,
+    ) -> bool;
+
+    /// Return to the initial state when the driver resets the device. What a device holds,
+    /// such as the contents of a disk, is kept.
+    fn reset(&mut self) {}
+}
+
+/// Paravirtualized drivers for IO virtualization.
+pub struct Virtio {
+    transport: VirtioTransport,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    page_size: u32,
+    queue_sel: u32,
+    queues: Vec<Queue>,
+    /// A bit for every queue that was notified and not processed yet.
+    notified: u32,
+    interrupt_status: u32,
+    status: u32,
+    device: Box<dyn VirtioDevice>,
+}
+
+impl Device for Virtio {
+    fn load(&self, offset: u64, size: usize) -> Result<u64, Exception> {
+        match size {
+            _ if offset >= VIRTIO_CONFIG => self
+                .load_config(offset - VIRTIO_CONFIG, size)
+                .ok_or(Exception::LoadAccessFault),
+            32 => Ok(self.load32(offset)),
+            _ => Err(Exception::LoadAccessFault),
+        }
+    }
+
+    fn store(&mut self, offset: u64, size: usize, value: u64) -> Result<(), Exception> {
+        match size {
+            // The config space is read-only.
+            _ if offset >= VIRTIO_CONFIG => {}
+            32 => self.store32(offset, value),
+            _ => return Err(Exception::StoreAMOAccessFault),
+        }
+        Ok(())
+    }
+
+    /// Return true if the device used buffers and the driver wants to know. The device reads
+    /// and writes memory directly (DMA), so the interrupt is raised once the accesses are done.
+    fn interrupting(&mut self, memory: &mut Memory) -> bool {
+        if self.notified == 0 && self.status & STATUS_DRIVER_OK == 0 {
+            return false;
+        }
+        let notified = std::mem::take(&mut self.notified);
+        let interrupt =
+            self.device
+                .process(memory, &mut self.queues, notified, self.driver_features);
+        if interrupt {
+            self.interrupt_status |= INTERRUPT_USED_BUFFER;
+        }
+        interrupt
+    }
+}
+
+impl Virtio {
+    /// Create a disk with the contents `image` and the legacy interface.
+    pub fn new(image: Vec<u8>) -> Self {
+        Self::with_transport(image, VirtioTransport::Legacy)
+    }
+
+    /// Create a disk with the contents `image` and the interface `transport`.
+    pub fn with_transport(image: Vec<u8>, transport: VirtioTransport) -> Self {
+        Self::with_storage(Box::new(image), transport)
+    }
+
+    /// Create a disk whose contents `storage` keeps, with the interface `transport`.
+    pub fn with_storage(storage: Box<dyn Storage>, transport: VirtioTransport) -> Self {
+        Self::with_device(Box::new(Block::new(storage)), transport)
+    }
+
+    /// Put `device` behind the interface `transport`.
+    pub fn with_device(device: Box<dyn VirtioDevice>, transport: VirtioTransport) -> Self {
+        Self {
+            transport,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            page_size: 0,
+            queue_sel: 0,
+            queues: vec![Queue::new(); device.queues()],
+            notified: 0,
+            interrupt_status: 0,
+            status: 0,
+            device,
+        }
+    }
+
+    fn modern(&self) -> bool {
+        self.transport == VirtioTransport::Modern
+    }
+
+    /// Return the features the device offers. A modern device must offer VIRTIO_F_VERSION_1.
+    fn device_features(&self) -> u64 {
+        let version = if self.modern() { VIRTIO_F_VERSION_1 } else { 0 };
+        self.device.features() | VIRTIO_F_INDIRECT_DESC | VIRTIO_F_EVENT_IDX | version
+    }
+
+    /// Return the selected queue, if it exists.
+    fn queue(&self) -> Option<&Queue> {
+        self.queues.get(self.queue_sel as usize)
+    }
+
+    fn queue_mut(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    /// Load 4 bytes from virtio only if the offset is valid. Otherwise, return 0.
+    pub fn load32(&self, offset: u64) -> u64 {
+        let modern = self.modern();
+        let value = match offset {
+            VIRTIO_MAGIC => 0x74726976,
+            VIRTIO_VERSION if modern => 0x2,
+            VIRTIO_VERSION => 0x1,
+            VIRTIO_DEVICE_ID => self.device.device_id(),
+            VIRTIO_VENDOR_ID => 0x554d4551,
+            VIRTIO_DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                _ => 0,
+            },
+            VIRTIO_QUEUE_NUM_MAX => match self.queue() {
+                Some(_) => QUEUE_NUM_MAX,
+                None => 0,
+            },
+            VIRTIO_QUEUE_PFN if !modern => self.queue().map_or(0, |queue| queue.pfn),
+            VIRTIO_QUEUE_READY if modern => self.queue().map_or(0, |queue| queue.ready as u32),
+            VIRTIO_INTERRUPT_STATUS => self.interrupt_status,
+            VIRTIO_STATUS => self.status,
+            // No device changes its config space, so its generation stays 0.
+            VIRTIO_CONFIG_GENERATION => 0,
+            _ => 0,
+        };
+        value as u64
+    }
+
+    /// Store 4 bytes to virtio only if the offset is valid. Otherwise, does nothing.
+    pub fn store32(&mut self, offset: u64, value: u64) {
+        let val = value as u32;
+        let modern = self.modern();
+        let page_size = self.page_size;
+        match offset {
+            VIRTIO_DEVICE_FEATURES_SEL => self.device_features_sel = val,
+            VIRTIO_DRIVER_FEATURES => {
+                let shift = match self.driver_features_sel {
+                    0 => 0,
+                    1 => 32,
+                    _ => return,
+                };
+                self.driver_features =
+                    (self.driver_features & !(0xffff_ffff << shift)) | (val as u64) << shift;
+            }
+            VIRTIO_DRIVER_FEATURES_SEL => self.driver_features_sel = val,
+            VIRTIO_GUEST_PAGE_SIZE if !modern => self.page_size = val,
+            VIRTIO_QUEUE_SEL => self.queue_sel = val,
+            VIRTIO_QUEUE_NOTIFY => {
+                if (val as usize) < self.queues.len() {
+                    self.notified |= 1 << val;
+                }
+            }
+            VIRTIO_INTERRUPT_ACK => self.interrupt_status &= !val,
+            VIRTIO_STATUS => self.set_status(val),
+            _ => {
+                let queue = match self.queue_mut() {
+                    Some(queue) => queue,
+                    None => return,
+                };
+                match offset {
+                    // The number of descriptors must be a power of two.
+                    VIRTIO_QUEUE_NUM if val.is_power_of_two() && val <= QUEUE_NUM_MAX => {
+                        queue.num = val
+                    }
+                    VIRTIO_QUEUE_ALIGN if !modern && val.is_power_of_two() => queue.align = val,
+                    VIRTIO_QUEUE_PFN if !modern => queue.place(val, page_size),
+                    VIRTIO_QUEUE_READY if modern => queue.ready = val & 1 != 0,
+                    VIRTIO_QUEUE_DESC_LOW | VIRTIO_QUEUE_DESC_HIGH if modern => {
+                        Queue::set_half(&mut queue.desc, offset == VIRTIO_QUEUE_DESC_HIGH, val)
+                    }
+                    VIRTIO_QUEUE_DRIVER_LOW | VIRTIO_QUEUE_DRIVER_HIGH if modern => {
+                        Queue::set_half(&mut queue.driver, offset == VIRTIO_QUEUE_DRIVER_HIGH, val)
+                    }
+                    VIRTIO_QUEUE_DEVICE_LOW | VIRTIO_QUEUE_DEVICE_HIGH if modern => {
+                        Queue::set_half(&mut queue.device, offset == VIRTIO_QUEUE_DEVICE_HIGH, val)
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Write the device status. 0 resets the device, and FEATURES_OK is only kept if the
+    /// device supports the features the driver accepted.
+    fn set_status(&mut self, status: u32) {
+        if status == 0 {
+            self.reset();
+            return;
+        }
+        let device_features = self.device_features();
+        let supported = self.driver_features & !device_features == 0
+            && (!self.modern() || self.driver_features & VIRTIO_F_VERSION_1 != 0);
+        self.status = if status & STATUS_FEATURES_OK != 0 && !supported {
+            status & !STATUS_FEATURES_OK
+        } else {
+            status
+        };
+    }
+
+    /// Return the device to its initial state, keeping what it holds.
+    fn reset(&mut self) {
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+        self.queues.fill(Queue::new());
+        self.notified = 0;
+        self.interrupt_status = 0;
+        self.status = 0;
+        self.device.reset();
+    }
+
+    /// Load `size` bits at `offset` of the config space, or `None` if the access is outside it.
+    fn load_config(&self, offset: u64, size: usize) -> Option<u64> {
+        let config = self.device.config();
+        let bytes = config.get(offset as usize..offset as usize + size / 8)?;
+        Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |value, byte| value << 8 | *byte as u64),
+        )
+    }
+}
+
+impl Snapshot for Virtio {
+    fn save(&self, w: &mut Writer) -> io::Result<()> {
+        w.put_u32(self.device_features_sel);
+        w.put_u64(self.driver_features);
+        w.put_u32(self.driver_features_sel);
+        w.put_u32(self.page_size);
+        w.put_u32(self.queue_sel);
+        for queue in &self.queues {
+            queue.save(w);
+        }
+        w.put_u32(self.notified);
+        w.put_u32(self.interrupt_status);
+        w.put_u32(self.status);
+        self.device.save(w)?;
+        Ok(())
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.device_features_sel = r.get_u32()?;
+        self.driver_features = r.get_u64()?;
+        self.driver_features_sel = r.get_u32()?;
+        self.page_size = r.get_u32()?;
+        self.queue_sel = r.get_u32()?;
+        for queue in &mut self.queues {
+            queue.restore(r)?;
+        }
+        self.notified = r.get_u32()?;
+        self.interrupt_status = r.get_u32()?;
+        self.status = r.get_u32()?;
+        self.device.restore(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A disk of 4 sectors.
+    fn disk(transport: VirtioTransport) -> Virtio {
+        Virtio::with_transport(vec![0; 2048], transport)
+    }
+
+    fn features(virtio: &mut Virtio) -> u64 {
+        virtio.store32(VIRTIO_DEVICE_FEATURES_SEL, 0);
+        let low = virtio.load32(VIRTIO_DEVICE_FEATURES);
+        virtio.store32(VIRTIO_DEVICE_FEATURES_SEL, 1);
+        let high = virtio.load32(VIRTIO_DEVICE_FEATURES);
+        high << 32 | low
+    }
+
+    fn accept(virtio: &mut Virtio, features: u64) {
+        virtio.store32(VIRTIO_DRIVER_FEATURES_SEL, 0);
+        virtio.store32(VIRTIO_DRIVER_FEATURES, features & 0xffff_ffff);
+        virtio.store32(VIRTIO_DRIVER_FEATURES_SEL, 1);
+        virtio.store32(VIRTIO_DRIVER_FEATURES, features >> 32);
+    }
+
+    #[test]
+    fn identification() {
+        for (transport, version) in [(VirtioTransport::Legacy, 1), (VirtioTransport::Modern, 2)] {
+            let virtio = disk(transport);
+            assert_eq!(virtio.load32(VIRTIO_MAGIC), 0x74726976);
+            assert_eq!(virtio.load32(VIRTIO_VERSION), version);
+            assert_eq!(virtio.load32(VIRTIO_DEVICE_ID), 2);
+            assert_eq!(virtio.load32(VIRTIO_VENDOR_ID), 0x554d4551);
+        }
+        // The registers are 32 bits wide, but the config space can be read by the byte.
+        let mut virtio = disk(VirtioTransport::Modern);
+        assert_eq!(
+            virtio.load(VIRTIO_MAGIC, 64),
+            Err(Exception::LoadAccessFault)
+        );
+        assert_eq!(virtio.load(VIRTIO_CONFIG, 64), Ok(4));
+        assert_eq!(virtio.load(VIRTIO_CONFIG + 20, 8), Ok(0));
+        assert_eq!(virtio.load(VIRTIO_CONFIG + 21, 8), Ok(2));
+        assert_eq!(
+            virtio.load(VIRTIO_CONFIG + 58, 32),
+            Err(Exception::LoadAccessFault)
+        );
+        virtio.store(VIRTIO_CONFIG, 64, 0).unwrap();
+        assert_eq!(virtio.load(VIRTIO_CONFIG, 64), Ok(4));
+    }
+
+    #[test]
+    fn feature_negotiation() {
+        let mut legacy = disk(VirtioTransport::Legacy);
+        let offered = features(&mut legacy);
+        assert_eq!(offered & VIRTIO_F_VERSION_1, 0);
+        assert_ne!(offered & VIRTIO_F_INDIRECT_DESC, 0);
+        assert_ne!(offered & VIRTIO_F_EVENT_IDX, 0);
+        // Past the 64 feature bits.
+        legacy.store32(VIRTIO_DEVICE_FEATURES_SEL, 2);
+        assert_eq!(legacy.load32(VIRTIO_DEVICE_FEATURES), 0);
+        accept(&mut legacy, VIRTIO_F_EVENT_IDX);
+        legacy.store32(VIRTIO_STATUS, STATUS_FEATURES_OK as u64);
+        assert_eq!(legacy.load32(VIRTIO_STATUS), STATUS_FEATURES_OK as u64);
+
+        let mut modern = disk(VirtioTransport::Modern);
+        let offered = features(&mut modern);
+        assert_eq!(offered & !VIRTIO_F_VERSION_1, features(&mut legacy));
+        assert_ne!(offered & VIRTIO_F_VERSION_1, 0);
+        // A modern driver must accept VIRTIO_F_VERSION_1.
+        accept(&mut modern, VIRTIO_F_EVENT_IDX);
+        modern.store32(VIRTIO_STATUS, 3 | STATUS_FEATURES_OK as u64);
+        assert_eq!(modern.load32(VIRTIO_STATUS), 3);
+        // And no feature the device didn't offer.
+        accept(&mut modern, VIRTIO_F_VERSION_1 | 1 << 40);
+        modern.store32(VIRTIO_STATUS, 3 | STATUS_FEATURES_OK as u64);
+        assert_eq!(modern.load32(VIRTIO_STATUS), 3);
+        accept(&mut modern, offered);
+        modern.store32(VIRTIO_STATUS, 3 | STATUS_FEATURES_OK as u64);
+        assert_eq!(modern.load32(VIRTIO_STATUS), 3 | STATUS_FEATURES_OK as u64);
+        assert_eq!(modern.driver_features, offered);
+    }
+
+    #[test]
+    fn modern_queue_setup() {
+        let mut virtio = disk(VirtioTransport::Modern);
+        assert_eq!(virtio.load32(VIRTIO_QUEUE_NUM_MAX), QUEUE_NUM_MAX as u64);
+        virtio.store32(VIRTIO_QUEUE_NUM, 48);
+        assert_eq!(virtio.queues[0].num, 0);
+        virtio.store32(VIRTIO_QUEUE_NUM, 2 * QUEUE_NUM_MAX as u64);
+        assert_eq!(virtio.queues[0].num, 0);
+        virtio.store32(VIRTIO_QUEUE_NUM, 64);
+        virtio.store32(VIRTIO_QUEUE_DESC_LOW, 0x8000_1000);
+        virtio.store32(VIRTIO_QUEUE_DESC_HIGH, 0x1);
+        virtio.store32(VIRTIO_QUEUE_DRIVER_LOW, 0x8000_2000);
+        virtio.store32(VIRTIO_QUEUE_DEVICE_LOW, 0x8000_3000);
+        // The legacy registers do nothing.
+        virtio.store32(VIRTIO_QUEUE_PFN, 0x80004);
+        assert_eq!(virtio.load32(VIRTIO_QUEUE_PFN), 0);
+        assert_eq!(virtio.load32(VIRTIO_QUEUE_READY), 0);
+        virtio.store32(VIRTIO_QUEUE_READY, 1);
+        assert_eq!(virtio.load32(VIRTIO_QUEUE_READY), 1);
+        let queue = &virtio.queues[0];
+        assert_eq!(queue.num, 64);
+        assert_eq!(queue.desc, 0x1_8000_1000);
+        assert_eq!(queue.driver, 0x8000_2000);
+        assert_eq!(queue.device, 0x8000_3000);
+        assert!(queue.usable());
+
+        // A disk has a single queue.
+        virtio.store32(VIRTIO_QUEUE_SEL, 1);
+        assert_eq!(virtio.load32(VIRTIO_QUEUE_NUM_MAX), 0);
+        virtio.store32(VIRTIO_QUEUE_READY, 1);
+        assert_eq!(virtio.load32(VIRTIO_QUEUE_READY), 0);
+        virtio.store32(VIRTIO_QUEUE_NOTIFY, 1);
+        assert_eq!(virtio.notified, 0);
+        virtio.store32(VIRTIO_QUEUE_NOTIFY, 0);
+        assert_eq!(virtio.notified, 1);
+
+        // Writing 0 to the status resets the device.
+        virtio.interrupt_status = INTERRUPT_USED_BUFFER;
+        virtio.store32(VIRTIO_INTERRUPT_ACK, INTERRUPT_USED_BUFFER as u64);
+        assert_eq!(virtio.load32(VIRTIO_INTERRUPT_STATUS), 0);
+        accept(&mut virtio, VIRTIO_F_VERSION_1);
+        virtio.store32(VIRTIO_STATUS, 0xf);
+        virtio.store32(VIRTIO_STATUS, 0);
+        assert_eq!(virtio.load32(VIRTIO_STATUS), 0);
+        assert_eq!(virtio.queue_sel, 0);
+        assert_eq!(virtio.driver_features, 0);
+        assert_eq!(virtio.notified, 0);
+        assert_eq!(virtio.queues[0], Queue::new());
+    }
+
+    #[test]
+    fn legacy_queue_setup() {
+        let mut virtio = disk(VirtioTransport::Legacy);
+        virtio.store32(VIRTIO_GUEST_PAGE_SIZE, 4096);
+        virtio.store32(VIRTIO_QUEUE_NUM, 8);
+        // The modern registers do nothing.
+        virtio.store32(VIRTIO_QUEUE_READY, 1);
+        virtio.store32(VIRTIO_QUEUE_DESC_LOW, 0x8000_1000);
+        assert!(!virtio.queues[0].usable());
+        assert_eq!(virtio.queues[0].desc, 0);
+
+        virtio.store32(VIRTIO_QUEUE_PFN, 0x80004);
+        assert_eq!(virtio.load32(VIRTIO_QUEUE_PFN), 0x80004);
+        let queue = &virtio.queues[0];
+        assert!(queue.usable());
+        assert_eq!(queue.desc, 0x8000_4000);
+        assert_eq!(queue.driver, 0x8000_4000 + 8 * 16);
+        // The used ring is at the next multiple of the alignment.
+        assert_eq!(queue.device, 0x8000_5000);
+
+        virtio.store32(VIRTIO_QUEUE_ALIGN, 3);
+        assert_eq!(virtio.queues[0].align, 4096);
+        virtio.store32(VIRTIO_QUEUE_ALIGN, 64);
+        virtio.store32(VIRTIO_QUEUE_PFN, 0x80004);
+        assert_eq!(virtio.queues[0].device, 0x8000_4000 + 8 * 16 + 64);
+        // A PFN of 0 takes the queue away.
+        virtio.store32(VIRTIO_QUEUE_PFN, 0);
+        assert!(!virtio.queues[0].usable());
+    }
+}
@@ -0,0 +1,267 @@
+//! The net module is the virtio network device, an Ethernet card whose frames go to and come
+//! from a `Network` on the host: a TAP interface, a Unix socket to another honga instance, or
+//! the built-in user-mode stack. Any of them can be captured to a pcap file.
+//!
+//! The driver may leave the checksums of the frames it sends to the device and give it TCP
+//! segments larger than the link takes, which the device completes and splits in software.
+
+mod offload;
+mod pcap;
+mod socket;
+mod tap;
+mod user;
+
+use std::io;
+use std::path::PathBuf;
+
+use super::queue::{Chain, Queue};
+use super::{VirtioDevice, VIRTIO_F_EVENT_IDX, VIRTIO_F_VERSION_1};
+use crate::bus::Memory;
+use crate::snapshot::{Reader, Snapshot, Writer};
+pub use pcap::Pcap;
+pub use socket::SocketNetwork;
+pub use tap::Tap;
+pub use user::UserNetwork;
+
+/// The most frames a backend keeps for the guest before it waits or drops them.
+const INBOX_FRAMES: usize = 256;
+/// The largest frame the device sends or receives, a TSO segment with its headers.
+const MAX_FRAME: usize = 65550;
+
+// Features.
+/// The device completes the checksums the driver leaves partial.
+const VIRTIO_NET_F_CSUM: u64 = 1 << 0;
+/// mac in the config space is valid.
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+/// The device splits TCP segments over IPv4 and IPv6 that the driver gives it.
+const VIRTIO_NET_F_HOST_TSO4: u64 = 1 << 11;
+const VIRTIO_NET_F_HOST_TSO6: u64 = 1 << 12;
+/// status in the config space is valid.
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+/// The link is up.
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+// The flags and the GSO types of a frame header.
+/// The checksum from csum_start to the end goes at csum_start + csum_offset.
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+/// The flag of the GSO type that says the segments carry ECN.
+const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+/// A link from a virtio network device to the host, which carries Ethernet frames.
+pub trait Network: Send {
+    /// Send a frame from the guest. A frame that can't be delivered is dropped, as on a wire.
+    fn send(&mut self, frame: &[u8]) -> io::Result<()>;
+
+    /// Return the next frame for the guest, if one arrived. This is called between instructions,
+    /// so it must be cheap when there's none.
+    fn recv(&mut self) -> Option<Vec<u8>>;
+}
+
+/// Where the frames of a network device go and come from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetBackend {
+    /// The built-in user-mode stack, which hands out an address by DHCP and forwards TCP and UDP
+    /// to the gateway to the host's loopback. `forwards` are the TCP ports of the host's loopback
+    /// forwarded to ports of the guest.
+    User { forwards: Vec<(u16, u16)> },
+    /// A Linux TAP interface.
+    Tap(String),
+    /// A Unix socket that another instance connects to.
+    Listen(PathBuf),
+    /// A Unix socket that another instance listens on.
+    Connect(PathBuf),
+}
+
+/// Open a link to `backend`, capturing its frames to `pcap` if it's given.
+pub fn open_network(backend: &NetBackend, pcap: Option<&PathBuf>) -> io::Result<Box<dyn Network>> {
+    let network: Box<dyn Network> = match backend {
+        NetBackend::User { forwards } => Box::new(UserNetwork::new(forwards)?),
+        NetBackend::Tap(name) => Box::new(Tap::open(name)?),
+        NetBackend::Listen(path) => Box::new(SocketNetwork::listen(path)?),
+        NetBackend::Connect(path) => Box::new(SocketNetwork::connect(path)?),
+    };
+    match pcap {
+        Some(path) => Ok(Box::new(Pcap::create(path, network)?)),
+        None => Ok(network),
+    }
+}
+
+/// A virtio network card.
+pub struct Net {
+    mac: [u8; 6],
+    network: Box<dyn Network>,
+    /// A frame that arrived while the driver had no receive buffers.
+    pending: Option<Vec<u8>>,
+}
+
+impl Net {
+    /// Create a network card with the address `mac`, connected to `network`.
+    pub fn new(mac: [u8; 6], network: Box<dyn Network>) -> Self {
+        Self {
+            mac,
+            network,
+            pending: None,
+        }
+    }
+
+    /// Return the size of the header before every frame, `struct virtio_net_hdr`. Legacy
+    /// devices leave out num_buffers unless the driver merges receive buffers, which this one
+    /// doesn't offer.
+    fn header_len(features: u64) -> usize {
+        if features & VIRTIO_F_VERSION_1 != 0 {
+            12
+        } else {
+            10
+        }
+    }
+
+    /// Send the frame in `chain` to the network, completing and splitting it as its header
+    /// asks.
+    fn transmit(&mut self, memory: &Memory, chain: &Chain, features: u64) {
+        let data = match chain.read(memory) {
+            Ok(data) if data.len() >= Self::header_len(features) => data,
+            _ => return,
+        };
+        let header_len = Self::header_len(features);
+        let flags = data[0];
+        let gso_type = data[1];
+        let gso_size = u16::from_le_bytes([data[4], data[5]]) as usize;
+        let csum_start = u16::from_le_bytes([data[6], data[7]]) as usize;
+        let csum_offset = u16::from_le_bytes([data[8], data[9]]) as usize;
+        let mut frame = data[header_len..].to_vec();
+        if frame.len() > MAX_FRAME {
+            return;
+        }
+
+        let tso = match gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+            VIRTIO_NET_HDR_GSO_NONE => false,
+            VIRTIO_NET_HDR_GSO_TCPV4 if features & VIRTIO_NET_F_HOST_TSO4 != 0 => true,
+            VIRTIO_NET_HDR_GSO_TCPV6 if features & VIRTIO_NET_F_HOST_TSO6 != 0 => true,
+            // The driver mustn't give segments the device didn't offer to split.
+            _ => return,
+        };
+        if flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0
+            && features & VIRTIO_NET_F_CSUM != 0
+            && !offload::complete_checksum(&mut frame, csum_start, csum_offset)
+        {
+            return;
+        }
+        if tso && gso_size > 0 {
+            if let Some(segments) = offload::segment(&frame, gso_size) {
+                for segment in segments {
+                    // A frame the host can't take is lost, as on a wire.
+                    let _ = self.network.send(&segment);
+                }
+            }
+        } else {
+            let _ = self.network.send(&frame);
+        }
+    }
+
+    /// Hand the frames that arrived to the driver while it has receive buffers. Return true if
+    /// the driver wants to know.
+    fn receive(
+        &mut self,
+        memory: &mut Memory,
+        queue: &mut Queue,
+        event_idx: bool,
+        features: u64,
+    ) -> bool {
+        if !queue.usable() {
+            return false;
+        }
+        let old = queue.used_idx();
+        loop {
+            if self.pending.is_none() {
+                self.pending = self.network.recv();
+            }
+            if self.pending.is_none() {
+                break;
+            }
+            let chain = match queue.pop(memory, event_idx) {
+                Some(chain) => chain,
+                None => break,
+            };
+            let frame = self.pending.take().unwrap_or_default();
+            // The header says the frame is complete, and that it takes one buffer.
+            let header_len = Self::header_len(features);
+            let mut data = vec![0; header_len];
+            if header_len == 12 {
+                data[10] = 1;
+            }
+            data.extend_from_slice(&frame);
+            // A frame too large for the buffers is dropped; the driver sees an empty one.
+            let len = match chain.write(memory, 0, &data) {
+                Ok(()) => data.len() as u32,
+                Err(_) => 0,
+            };
+            queue.push(memory, chain.head, len);
+        }
+        queue.should_interrupt(memory, old, event_idx)
+    }
+}
+
+impl VirtioDevice for Net {
+    fn device_id(&self) -> u32 {
+        1
+    }
+
+    /// A network card has a receiveq and a transmitq.
+    fn queues(&self) -> usize {
+        2
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_CSUM
+            | VIRTIO_NET_F_MAC
+            | VIRTIO_NET_F_HOST_TSO4
+            | VIRTIO_NET_F_HOST_TSO6
+            | VIRTIO_NET_F_STATUS
+    }
+
+    /// Return the config space, `struct virtio_net_config`, up to status.
+    fn config(&self) -> Vec<u8> {
+        let mut config = self.mac.to_vec();
+        config.extend_from_slice(&VIRTIO_NET_S_LINK_UP.to_le_bytes());
+        config
+    }
+
+    fn process(
+        &mut self,
+        memory: &mut Memory,
+        queues: &mut [Queue],
+        notified: u32,
+        features: u64,
+    ) -> bool {
+        let event_idx = features & VIRTIO_F_EVENT_IDX != 0;
+        let (receiveq, transmitq) = queues.split_at_mut(1);
+        let mut interrupt = false;
+        if notified & 2 != 0 {
+            interrupt |= transmitq[0].serve(memory, event_idx, |memory, chain| {
+                self.transmit(memory, chain, features);
+                0
+            });
+        }
+        interrupt | self.receive(memory, &mut receiveq[0], event_idx, features)
+    }
+
+    fn reset(&mut self) {
+        self.pending = None;
+    }
+}
+
+/// Frames on their way aren't saved; a restored machine has lost them, as a network may.
+impl Snapshot for Net {
+    fn save(&self, _w: &mut Writer) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn restore(&mut self, _r: &mut Reader) -> io::Result<()> {
+        self.pending = None;
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+//! The offload module does in software what the driver may leave to the device: it completes
+//! checksums and splits large TCP segments into ones the link takes.
+
+/// The Ethernet types of IPv4 and IPv6, and of an 802.1Q tag in front of them.
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+/// The IP protocol number of TCP.
+pub const PROTOCOL_TCP: u8 = 6;
+
+// TCP flags.
+const TCP_FIN: u8 = 0x01;
+const TCP_PSH: u8 = 0x08;
+const TCP_CWR: u8 = 0x80;
+
+/// Return the big-endian 16-bit value at `offset` of `bytes`.
+pub fn be16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+pub fn put16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+/// Add `bytes` to the ones' complement sum `sum`, as 16-bit big-endian words.
+pub fn sum(mut sum: u32, bytes: &[u8]) -> u32 {
+    let mut words = bytes.chunks_exact(2);
+    for word in &mut words {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    if let [last] = words.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+/// Fold `sum` into the 16-bit Internet checksum.
+pub fn checksum(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Complete the checksum the driver left partial in `frame`: the checksum of the bytes from
+/// `start` to the end goes at `start + offset`, where the sum of the pseudo-header already is.
+/// Return false if it's outside the frame.
+pub fn complete_checksum(frame: &mut [u8], start: usize, offset: usize) -> bool {
+    let at = start + offset;
+    if start > frame.len() || at + 2 > frame.len() {
+        return false;
+    }
+    let value = checksum(sum(0, &frame[start..]));
+    put16(frame, at, value);
+    true
+}
+
+/// Return the offset of the IP header and the Ethernet type of `frame`, past a VLAN tag.
+pub fn network_header(frame: &[u8]) -> Option<(usize, u16)> {
+    if frame.len() < 14 {
+        return None;
+    }
+    match be16(frame, 12) {
+        ETHERTYPE_VLAN if frame.len() >= 18 => Some((18, be16(frame, 16))),
+        ETHERTYPE_VLAN => None,
+        ethertype => Some((14, ethertype)),
+    }
+}
+
+/// Return the TCP or UDP checksum of `segment` of the IP protocol `protocol`, with the
+/// pseudo-header of the addresses `addresses` (source then destination, 4 or 16 bytes each).
+pub fn transport_checksum(protocol: u8, addresses: &[u8], segment: &[u8]) -> u16 {
+    let mut total = sum(0, addresses);
+    total += protocol as u32;
+    total += segment.len() as u32;
+    checksum(sum(total, segment))
+}
+
+/// Split the TCP segment in `frame` into segments with at most `mss` bytes of data each, with
+/// complete checksums. Return `None` if `frame` isn't a TCP segment over IPv4 or IPv6.
+pub fn segment(frame: &[u8], mss: usize) -> Option<Vec<Vec<u8>>> {
+    let (ip, ethertype) = network_header(frame)?;
+    let (tcp, addresses) = match ethertype {
+        ETHERTYPE_IPV4 => {
+            let ihl = (*frame.get(ip)? as usize & 0xf) * 4;
+            if ihl < 20 || frame.len() < ip + ihl || frame[ip + 9] != PROTOCOL_TCP {
+                return None;
+            }
+            (ip + ihl, ip + 12..ip + 20)
+        }
+        // Extension headers aren't followed.
+        ETHERTYPE_IPV6 => {
+            if frame.len() < ip + 40 || frame[ip + 6] != PROTOCOL_TCP {
+                return None;
+            }
+            (ip + 40, ip + 8..ip + 40)
+        }
+        _ => return None,
+    };
+    let data = tcp + (*frame.get(tcp + 12)? as usize >> 4) * 4;
+    if data > frame.len() || data < tcp + 20 {
+        return None;
+    }
+    let headers = &frame[..data];
+    let payload = &frame[data..];
+    let seq = u32::from_be_bytes([
+        frame[tcp + 4],
+        frame[tcp + 5],
+        frame[tcp + 6],
+        frame[tcp + 7],
+    ]);
+    let flags = frame[tcp + 13];
+    let count = payload.len().div_ceil(mss).max(1);
+
+    let mut segments = Vec::with_capacity(count);
+    for (n, start) in (0..count).map(|n| (n, n * mss)) {
+        let chunk = &payload[start..(start + mss).min(payload.len())];
+        let mut segment = headers.to_vec();
+        segment.extend_from_slice(chunk);
+        let ip_len = (segment.len() - ip) as u16;
+        if ethertype == ETHERTYPE_IPV4 {
+            put16(&mut segment, ip + 2, ip_len);
+            let id = be16(headers, ip + 4).wrapping_add(n as u16);
+            put16(&mut segment, ip + 4, id);
+            put16(&mut segment, ip + 10, 0);
+            let value = checksum(sum(0, &segment[ip..tcp]));
+            put16(&mut segment, ip + 10, value);
+        } else {
+            put16(&mut segment, ip + 4, ip_len - 40);
+        }
+        segment[tcp + 4..tcp + 8].copy_from_slice(&seq.wrapping_add(start as u32).to_be_bytes());
+        // FIN and PSH belong to the last segment, and CWR to the first.
+        let mut segment_flags = flags;
+        if n + 1 < count {
+            segment_flags &= !(TCP_FIN | TCP_PSH);
+        }
+        if n > 0 {
+            segment_flags &= !TCP_CWR;
+        }
+        segment[tcp + 13] = segment_flags;
+        put16(&mut segment, tcp + 16, 0);
+        let value = transport_checksum(PROTOCOL_TCP, &frame[addresses.clone()], &segment[tcp..]);
+        put16(&mut segment, tcp + 16, value);
+        segments.push(segment);
+    }
+    Some(segments)
+}
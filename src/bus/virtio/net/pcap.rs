@@ -0,0 +1,67 @@
+//! The pcap module captures the frames of a network device to a file in the pcap format, which
+//! tcpdump and Wireshark read.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::Network;
+
+/// The magic number of a pcap file with timestamps in microseconds.
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+/// The largest number of bytes of a frame that is captured.
+const SNAPLEN: u32 = 65535;
+/// The link type of Ethernet.
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// A network whose frames, in both directions, are written to a pcap file.
+pub struct Pcap {
+    network: Box<dyn Network>,
+    file: BufWriter<File>,
+}
+
+impl Pcap {
+    /// Create the pcap file at `path` for the frames of `network`.
+    pub fn create(path: &Path, network: Box<dyn Network>) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&PCAP_MAGIC.to_le_bytes())?;
+        // Version 2.4, UTC, and no timestamp accuracy.
+        file.write_all(&2u16.to_le_bytes())?;
+        file.write_all(&4u16.to_le_bytes())?;
+        file.write_all(&[0; 8])?;
+        file.write_all(&SNAPLEN.to_le_bytes())?;
+        file.write_all(&LINKTYPE_ETHERNET.to_le_bytes())?;
+        file.flush()?;
+        Ok(Self { network, file })
+    }
+
+    /// Write `frame` with the host's time, so that the file can be read while the machine runs.
+    fn capture(&mut self, frame: &[u8]) -> io::Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let len = frame.len().min(SNAPLEN as usize);
+        self.file
+            .write_all(&(time.as_secs() as u32).to_le_bytes())?;
+        self.file.write_all(&time.subsec_micros().to_le_bytes())?;
+        self.file.write_all(&(len as u32).to_le_bytes())?;
+        self.file.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.file.write_all(&frame[..len])?;
+        self.file.flush()
+    }
+}
+
+impl Network for Pcap {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.capture(frame)?;
+        self.network.send(frame)
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        let frame = self.network.recv()?;
+        // A capture that fails mustn't lose the frame.
+        let _ = self.capture(&frame);
+        Some(frame)
+    }
+}
@@ -0,0 +1,134 @@
+//! The socket module links the network devices of two honga instances through a Unix stream
+//! socket. Each frame is sent as its length, a 32-bit big-endian value, followed by the frame,
+//! which is also how QEMU's stream network backend frames them.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::{Network, INBOX_FRAMES, MAX_FRAME};
+use crate::bus::virtio::inbox::{Inbox, Outbox};
+
+fn socket_error(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+/// One end of a Unix socket between two instances.
+pub struct SocketNetwork {
+    /// The other instance, once it's connected.
+    peer: Arc<Mutex<Option<UnixStream>>>,
+    inbox: Inbox,
+}
+
+impl SocketNetwork {
+    /// Listen on a socket at `path` for the other instance, which may connect and reconnect
+    /// while the machine runs. A socket left at `path` by an earlier run is replaced.
+    pub fn listen(path: &Path) -> io::Result<Self> {
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                fs::remove_file(path)?;
+            }
+        }
+        let listener = UnixListener::bind(path).map_err(|e| socket_error(path, e))?;
+        let (outbox, inbox) = Inbox::new(INBOX_FRAMES);
+        let peer = Arc::new(Mutex::new(None));
+        let accepted = Arc::clone(&peer);
+        let _net_thread_for_accept = thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        continue;
+                    }
+                };
+                let reader = match stream.try_clone() {
+                    Ok(reader) => reader,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        continue;
+                    }
+                };
+                *accepted.lock().unwrap() = Some(stream);
+                // One instance at a time: the next is accepted once this one hangs up.
+                if !Self::read_frames(reader, &outbox) {
+                    return;
+                }
+                *accepted.lock().unwrap() = None;
+            }
+        });
+        Ok(Self { peer, inbox })
+    }
+
+    /// Connect to the socket at `path`, on which the other instance listens.
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        let stream = UnixStream::connect(path).map_err(|e| socket_error(path, e))?;
+        let reader = stream.try_clone()?;
+        let (outbox, inbox) = Inbox::new(INBOX_FRAMES);
+        let _net_thread_for_read = thread::spawn(move || Self::read_frames(reader, &outbox));
+        Ok(Self {
+            peer: Arc::new(Mutex::new(Some(stream))),
+            inbox,
+        })
+    }
+
+    /// Hand the frames that arrive on `stream` to `outbox` until the other instance hangs up.
+    /// Return false once the device is gone.
+    fn read_frames(mut stream: UnixStream, outbox: &Outbox) -> bool {
+        let mut len = [0; 4];
+        loop {
+            if stream.read_exact(&mut len).is_err() {
+                return true;
+            }
+            let len = u32::from_be_bytes(len) as usize;
+            // The stream can't be followed past a frame too long to read, so hang up as if the
+            // other instance had.
+            if len > MAX_FRAME {
+                return true;
+            }
+            let mut frame = vec![0; len];
+            if stream.read_exact(&mut frame).is_err() {
+                return true;
+            }
+            if !outbox.send(frame) {
+                return false;
+            }
+        }
+    }
+}
+
+impl Network for SocketNetwork {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        let mut peer = self.peer.lock().unwrap();
+        let stream = match peer.as_mut() {
+            Some(stream) => stream,
+            // Nobody's on the other end of the wire.
+            None => return Ok(()),
+        };
+        let mut message = (frame.len() as u32).to_be_bytes().to_vec();
+        message.extend_from_slice(frame);
+        if let Err(e) = stream.write_all(&message) {
+            *peer = None;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.inbox.recv()
+    }
+}
+
+/// Hang up, so that the other instance sees it and the thread reading the socket stops.
+impl Drop for SocketNetwork {
+    fn drop(&mut self) {
+        if let Some(stream) = self.peer.lock().unwrap().take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! The tap module connects a network device to a Linux TAP interface, through which the guest
+//! is on a link of the host like any other machine. The interface is set up on the host, e.g.
+//! with `ip tuntap add tap0 mode tap`.
+
+use std::fs::File;
+use std::io::{self, Write};
+
+use super::Network;
+use crate::bus::virtio::inbox::Inbox;
+
+/// A Linux TAP interface.
+pub struct Tap {
+    file: File,
+    inbox: Inbox,
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::os::raw::{c_int, c_ulong};
+
+    /// The ioctl that attaches a file of /dev/net/tun to an interface.
+    pub const TUNSETIFF: c_ulong = 0x4004_54ca;
+    /// The interface carries Ethernet frames, without a header of packet information.
+    pub const IFF_TAP: i16 = 0x0002;
+    pub const IFF_NO_PI: i16 = 0x1000;
+    /// The size of the name in `struct ifreq`, with its terminating nul.
+    pub const IFNAMSIZ: usize = 16;
+
+    extern "C" {
+        pub fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    }
+}
+
+impl Tap {
+    /// Attach to the TAP interface `name`, which is created if it doesn't exist.
+    #[cfg(target_os = "linux")]
+    pub fn open(name: &str) -> io::Result<Self> {
+        use std::fs::OpenOptions;
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+        use std::thread;
+
+        use super::{INBOX_FRAMES, MAX_FRAME};
+
+        if name.is_empty() || name.len() >= sys::IFNAMSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid TAP interface name: {:?}", name),
+            ));
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/net/tun")?;
+        // struct ifreq { char ifr_name[IFNAMSIZ]; short ifr_flags; ... }, 40 bytes on 64-bit
+        // hosts.
+        let mut ifreq = [0u8; 40];
+        ifreq[..name.len()].copy_from_slice(name.as_bytes());
+        ifreq[sys::IFNAMSIZ..sys::IFNAMSIZ + 2]
+            .copy_from_slice(&(sys::IFF_TAP | sys::IFF_NO_PI).to_ne_bytes());
+        if unsafe { sys::ioctl(file.as_raw_fd(), sys::TUNSETIFF, ifreq.as_mut_ptr()) } < 0 {
+            let e = io::Error::last_os_error();
+            return Err(io::Error::new(
+                e.kind(),
+                format!("TAP interface {}: {}", name, e),
+            ));
+        }
+
+        let mut reader = file.try_clone()?;
+        let (outbox, inbox) = Inbox::new(INBOX_FRAMES);
+        let _net_thread_for_read = thread::spawn(move || {
+            let mut buffer = vec![0; MAX_FRAME];
+            loop {
+                // Every read returns one frame.
+                match reader.read(&mut buffer) {
+                    Ok(0) => return,
+                    Ok(len) => {
+                        if !outbox.send(buffer[..len].to_vec()) {
+                            return;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(Self { file, inbox })
+    }
+
+    /// TAP interfaces are only supported on Linux.
+    #[cfg(not(target_os = "linux"))]
+    pub fn open(_name: &str) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "TAP interfaces are only supported on Linux",
+        ))
+    }
+}
+
+impl Network for Tap {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.file.write_all(frame)
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.inbox.recv()
+    }
+}
@@ -0,0 +1,1291 @@
+//! The user module is a network stack in user mode, which needs no privileges and no setup on
+//! the host. It's a small router on the network 10.0.2.0/24, as in QEMU: the gateway 10.0.2.2
+//! hands out 10.0.2.15 by DHCP, answers ARP and pings, and connects the TCP connections and the
+//! UDP datagrams the guest sends to it to the same ports of the host's loopback. Nothing else is
+//! reachable, so the guest only sees the services the host offers on localhost. TCP ports of the
+//! host's loopback can also be forwarded to ports of the guest.
+//!
+//! The stack runs in a thread of its own, which polls the host's sockets.
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::offload::{
+    be16, checksum, put16, sum, transport_checksum, ETHERTYPE_IPV4, PROTOCOL_TCP,
+};
+use super::{Network, INBOX_FRAMES};
+use crate::bus::virtio::inbox::{Inbox, Outbox};
+
+/// The addresses of the gateway and of the guest, and the mask of the network.
+const GATEWAY: [u8; 4] = [10, 0, 2, 2];
+const GUEST: [u8; 4] = [10, 0, 2, 15];
+const NETMASK: [u8; 4] = [255, 255, 255, 0];
+const BROADCAST: [u8; 4] = [255; 4];
+/// The Ethernet addresses of the gateway and of every station.
+const GATEWAY_MAC: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+const BROADCAST_MAC: [u8; 6] = [0xff; 6];
+
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+const PROTOCOL_ICMP: u8 = 1;
+const PROTOCOL_UDP: u8 = 17;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DHCP_MAGIC: [u8; 4] = [0x63, 0x82, 0x53, 0x63];
+// DHCP options and message types.
+const DHCP_OPTION_NETMASK: u8 = 1;
+const DHCP_OPTION_ROUTER: u8 = 3;
+const DHCP_OPTION_LEASE_TIME: u8 = 51;
+const DHCP_OPTION_MESSAGE_TYPE: u8 = 53;
+const DHCP_OPTION_SERVER: u8 = 54;
+const DHCP_OPTION_END: u8 = 255;
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+const DHCP_LEASE_SECONDS: u32 = 86400;
+/// The smallest BOOTP message, which some clients insist on.
+const DHCP_MIN_LEN: usize = 300;
+
+// TCP flags.
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+/// The TCP option that carries the maximum segment size.
+const TCP_OPTION_MSS: u8 = 2;
+/// The maximum segment size the gateway takes, which fills an Ethernet frame.
+const TCP_MSS: u16 = 1460;
+/// The maximum segment size of a guest that doesn't say.
+const TCP_DEFAULT_MSS: usize = 536;
+/// The bytes from the host that the guest may not have acknowledged, and the bytes from the
+/// guest that the host may not have taken.
+const SEND_BUFFER: usize = 256 * 1024;
+const RECEIVE_BUFFER: usize = 256 * 1024;
+/// How long the gateway waits for an acknowledgement before it sends again, and how often.
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_RETRANSMITS: u32 = 8;
+/// The number of duplicate acknowledgements after which the gateway sends again.
+const DUPLICATE_ACKS: u32 = 3;
+/// How long the gateway waits for a port of the host's loopback to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// The ports of the gateway from which forwarded connections come.
+const FIRST_FORWARD_PORT: u16 = 49152;
+
+/// How long a UDP flow lives after its last datagram.
+const UDP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The built-in user-mode network.
+pub struct UserNetwork {
+    /// Frames from the guest, for the thread of the stack.
+    frames: Sender<Vec<u8>>,
+    inbox: Inbox,
+}
+
+impl UserNetwork {
+    /// Start the stack, with the TCP ports `forwards` of the host's loopback forwarded to ports
+    /// of the guest.
+    pub fn new(forwards: &[(u16, u16)]) -> io::Result<Self> {
+        let mut listeners = Vec::new();
+        for &(host_port, guest_port) in forwards {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, host_port)).map_err(|e| {
+                io::Error::new(e.kind(), format!("forwarding port {}: {}", host_port, e))
+            })?;
+            listener.set_nonblocking(true)?;
+            listeners.push((listener, guest_port));
+        }
+        let (frames, received) = mpsc::channel();
+        let (outbox, inbox) = Inbox::new(INBOX_FRAMES);
+        let mut stack = Stack::new(outbox, listeners);
+        let _net_thread_for_stack = thread::spawn(move || stack.run(received));
+        Ok(Self { frames, inbox })
+    }
+}
+
+impl Network for UserNetwork {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.frames
+            .send(frame.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the user-mode network stopped"))
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.inbox.recv()
+    }
+}
+
+/// Return the 4 bytes at `offset` of `bytes`, e.g. an IPv4 address.
+fn take4(bytes: &[u8], offset: usize) -> [u8; 4] {
+    [
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ]
+}
+
+fn be32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(take4(bytes, offset))
+}
+
+/// Return true if the sequence number `a` comes before `b`.
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// The gateway's end of the link to the guest.
+struct Link {
+    outbox: Outbox,
+    /// The addresses the guest last sent from.
+    guest_mac: Option<[u8; 6]>,
+    guest_ip: [u8; 4],
+    /// The identification of the next IPv4 packet.
+    ip_id: u16,
+}
+
+/// The header of a TCP segment the gateway sends.
+struct TcpHeader {
+    /// The gateway's port and the guest's.
+    ports: (u16, u16),
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+}
+
+impl Link {
+    fn send_frame(&mut self, destination: [u8; 6], ethertype: u16, payload: &[u8]) {
+        let mut frame = Vec::with_capacity(14 + payload.len());
+        frame.extend_from_slice(&destination);
+        frame.extend_from_slice(&GATEWAY_MAC);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        // The device is gone if this fails, and the stack stops with the next frame.
+        self.outbox.send(frame);
+    }
+
+    fn send_ipv4(&mut self, destination: [u8; 4], protocol: u8, payload: &[u8]) {
+        let mut packet = vec![0; 20];
+        packet[0] = 0x45;
+        put16(&mut packet, 2, (20 + payload.len()) as u16);
+        put16(&mut packet, 4, self.ip_id);
+        self.ip_id = self.ip_id.wrapping_add(1);
+        packet[8] = 64;
+        packet[9] = protocol;
+        packet[12..16].copy_from_slice(&GATEWAY);
+        packet[16..20].copy_from_slice(&destination);
+        let value = checksum(sum(0, &packet));
+        put16(&mut packet, 10, value);
+        packet.extend_from_slice(payload);
+        // Until the guest sends something, it's only reachable by broadcast.
+        let mac = match (destination, self.guest_mac) {
+            (BROADCAST, _) | (_, None) => BROADCAST_MAC,
+            (_, Some(mac)) => mac,
+        };
+        self.send_frame(mac, ETHERTYPE_IPV4, &packet);
+    }
+
+    fn addresses(destination: [u8; 4]) -> [u8; 8] {
+        let mut addresses = [0; 8];
+        addresses[..4].copy_from_slice(&GATEWAY);
+        addresses[4..].copy_from_slice(&destination);
+        addresses
+    }
+
+    fn send_udp(&mut self, destination: [u8; 4], ports: (u16, u16), data: &[u8]) {
+        let mut datagram = vec![0; 8];
+        put16(&mut datagram, 0, ports.0);
+        put16(&mut datagram, 2, ports.1);
+        put16(&mut datagram, 4, (8 + data.len()) as u16);
+        datagram.extend_from_slice(data);
+        // A checksum of 0 means there's none.
+        let value = match transport_checksum(PROTOCOL_UDP, &Self::addresses(destination), &datagram)
+        {
+            0 => 0xffff,
+            value => value,
+        };
+        put16(&mut datagram, 6, value);
+        self.send_ipv4(destination, PROTOCOL_UDP, &datagram);
+    }
+
+    fn send_tcp(&mut self, header: TcpHeader, data: &[u8]) {
+        // A SYN carries the maximum segment size the gateway takes.
+        let options = match header.flags & TCP_SYN {
+            0 => Vec::new(),
+            _ => {
+                let mut options = vec![TCP_OPTION_MSS, 4];
+                options.extend_from_slice(&TCP_MSS.to_be_bytes());
+                options
+            }
+        };
+        let mut segment = vec![0; 20];
+        put16(&mut segment, 0, header.ports.0);
+        put16(&mut segment, 2, header.ports.1);
+        segment[4..8].copy_from_slice(&header.seq.to_be_bytes());
+        segment[8..12].copy_from_slice(&header.ack.to_be_bytes());
+        segment[12] = (((20 + options.len()) / 4) << 4) as u8;
+        segment[13] = header.flags;
+        put16(&mut segment, 14, header.window);
+        segment.extend_from_slice(&options);
+        segment.extend_from_slice(data);
+        let destination = self.guest_ip;
+        let value = transport_checksum(PROTOCOL_TCP, &Self::addresses(destination), &segment);
+        put16(&mut segment, 16, value);
+        self.send_ipv4(destination, PROTOCOL_TCP, &segment);
+    }
+
+    /// Answer `segment`, which belongs to no connection, with a reset.
+    fn send_reset(&mut self, ports: (u16, u16), segment: &Segment) {
+        if segment.flags & TCP_RST != 0 {
+            return;
+        }
+        let header = match segment.flags & TCP_ACK {
+            0 => TcpHeader {
+                ports,
+                seq: 0,
+                ack: segment.seq.wrapping_add(segment.len()),
+                flags: TCP_RST | TCP_ACK,
+                window: 0,
+            },
+            _ => TcpHeader {
+                ports,
+                seq: segment.ack,
+                ack: 0,
+                flags: TCP_RST,
+                window: 0,
+            },
+        };
+        self.send_tcp(header, &[]);
+    }
+}
+
+/// A TCP segment from the guest.
+struct Segment<'a> {
+    /// The guest's port and the gateway's.
+    ports: (u16, u16),
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    /// The maximum segment size the guest takes, from a SYN.
+    mss: Option<usize>,
+    data: &'a [u8],
+}
+
+impl<'a> Segment<'a> {
+    fn parse(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < 20 {
+            return None;
+        }
+        let offset = (bytes[12] >> 4) as usize * 4;
+        if offset < 20 || offset > bytes.len() {
+            return None;
+        }
+        let mut mss = None;
+        let mut options = &bytes[20..offset];
+        while let [kind, rest @ ..] = options {
+            match *kind {
+                0 => break,
+                1 => options = rest,
+                _ => {
+                    let len = *rest.first()? as usize;
+                    if len < 2 || len > options.len() {
+                        break;
+                    }
+                    if *kind == TCP_OPTION_MSS && len == 4 {
+                        mss = Some(be16(options, 2) as usize);
+                    }
+                    options = &options[len..];
+                }
+            }
+        }
+        Some(Self {
+            ports: (be16(bytes, 0), be16(bytes, 2)),
+            seq: be32(bytes, 4),
+            ack: be32(bytes, 8),
+            flags: bytes[13],
+            window: be16(bytes, 14),
+            mss,
+            data: &bytes[offset..],
+        })
+    }
+
+    /// Return the number of sequence numbers the segment takes.
+    fn len(&self) -> u32 {
+        let mut len = self.data.len() as u32;
+        if self.flags & TCP_SYN != 0 {
+            len += 1;
+        }
+        if self.flags & TCP_FIN != 0 {
+            len += 1;
+        }
+        len
+    }
+}
+
+/// The state of a TCP connection until it's established.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// The gateway sent a SYN for a forwarded connection.
+    SynSent,
+    /// The gateway answered the guest's SYN.
+    SynReceived,
+    Established,
+}
+
+/// A TCP connection between the guest and the host's loopback, through the gateway.
+struct Connection {
+    stream: TcpStream,
+    /// The gateway's port and the guest's.
+    ports: (u16, u16),
+    state: State,
+    /// The oldest sequence number the guest hasn't acknowledged, the next one to send, and the
+    /// one after the last sent.
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_max: u32,
+    /// The next sequence number expected from the guest.
+    rcv_nxt: u32,
+    /// The guest's window and maximum segment size.
+    window: usize,
+    mss: usize,
+    /// The bytes from the host from snd_una on, sent or not.
+    unacked: VecDeque<u8>,
+    /// The bytes from the guest that the host hasn't taken yet.
+    to_host: VecDeque<u8>,
+    /// The window last advertised to the guest.
+    advertised: usize,
+    /// The host closed its end, so a FIN follows the data.
+    host_eof: bool,
+    /// The guest acknowledged the FIN.
+    fin_acked: bool,
+    /// The guest sent a FIN, and the host's end was shut down after the data before it.
+    guest_fin: bool,
+    host_shut: bool,
+    /// When the oldest unacknowledged segment was sent, and how many times it was sent again.
+    sent_at: Instant,
+    retransmits: u32,
+    /// How many times in a row the guest acknowledged the same data.
+    duplicates: u32,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, ports: (u16, u16), isn: u32, state: State) -> Self {
+        Self {
+            stream,
+            ports,
+            state,
+            snd_una: isn,
+            snd_nxt: isn.wrapping_add(1),
+            snd_max: isn.wrapping_add(1),
+            rcv_nxt: 0,
+            window: 0,
+            mss: TCP_DEFAULT_MSS,
+            unacked: VecDeque::new(),
+            to_host: VecDeque::new(),
+            advertised: 0,
+            host_eof: false,
+            fin_acked: false,
+            guest_fin: false,
+            host_shut: false,
+            sent_at: Instant::now(),
+            retransmits: 0,
+            duplicates: 0,
+        }
+    }
+
+    /// Return the window the gateway advertises: the room left for the host's data.
+    fn room(&self) -> usize {
+        (RECEIVE_BUFFER - self.to_host.len()).min(u16::MAX as usize)
+    }
+
+    fn send(&mut self, link: &mut Link, seq: u32, flags: u8, data: &[u8]) {
+        self.advertised = self.room();
+        let header = TcpHeader {
+            ports: self.ports,
+            seq,
+            ack: self.rcv_nxt,
+            flags,
+            window: self.advertised as u16,
+        };
+        link.send_tcp(header, data);
+    }
+
+    /// Send the SYN, or the SYN-ACK to the guest's SYN.
+    fn send_syn(&mut self, link: &mut Link) {
+        let flags = match self.state {
+            State::SynSent => TCP_SYN,
+            _ => TCP_SYN | TCP_ACK,
+        };
+        self.sent_at = Instant::now();
+        self.send(link, self.snd_una, flags, &[]);
+    }
+
+    fn send_ack(&mut self, link: &mut Link) {
+        self.send(link, self.snd_nxt, TCP_ACK, &[]);
+    }
+
+    /// Handle `segment` from the guest. Return false once the connection is over.
+    fn receive(&mut self, link: &mut Link, segment: &Segment) -> bool {
+        if segment.flags & TCP_RST != 0 {
+            return false;
+        }
+        if segment.flags & TCP_SYN != 0 {
+            match self.state {
+                // The guest accepted a forwarded connection.
+                State::SynSent if segment.flags & TCP_ACK != 0 && segment.ack == self.snd_nxt => {
+                    self.state = State::Established;
+                    self.snd_una = segment.ack;
+                    self.rcv_nxt = segment.seq.wrapping_add(1);
+                    self.window = segment.window as usize;
+                    self.mss = segment.mss.unwrap_or(TCP_DEFAULT_MSS);
+                    self.retransmits = 0;
+                    self.send_ack(link);
+                }
+                // The SYN-ACK was lost.
+                State::SynReceived => self.send_syn(link),
+                _ => self.send_ack(link),
+            }
+            return true;
+        }
+        if self.state == State::SynSent {
+            return true;
+        }
+        if segment.flags & TCP_ACK != 0 {
+            self.acknowledge(segment);
+        }
+
+        if !segment.data.is_empty() || segment.flags & TCP_FIN != 0 {
+            // Only the next data in order is taken, as much as there's room for. The guest
+            // sends the rest again.
+            if segment.seq == self.rcv_nxt && !self.guest_fin {
+                let count = segment.data.len().min(RECEIVE_BUFFER - self.to_host.len());
+                self.to_host.extend(&segment.data[..count]);
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(count as u32);
+                if count == segment.data.len() && segment.flags & TCP_FIN != 0 {
+                    self.guest_fin = true;
+                    self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+                }
+            }
+            if !self.flush(link) {
+                return false;
+            }
+            self.send_ack(link);
+        }
+        self.transmit(link);
+        !self.finished()
+    }
+
+    /// Take the guest's acknowledgement of the sequence numbers before `ack` in `segment`.
+    fn acknowledge(&mut self, segment: &Segment) {
+        let ack = segment.ack;
+        let acked = ack.wrapping_sub(self.snd_una) as usize;
+        if acked > self.snd_max.wrapping_sub(self.snd_una) as usize {
+            return;
+        }
+        let window = segment.window as usize;
+        if acked == 0 {
+            // The guest repeats its acknowledgement when a segment went missing. The third
+            // time, everything from the missing one on is sent again, without waiting.
+            if segment.data.is_empty()
+                && window == self.window
+                && self.snd_max != self.snd_una
+                && self.state == State::Established
+            {
+                self.duplicates += 1;
+                if self.duplicates == DUPLICATE_ACKS {
+                    self.snd_nxt = self.snd_una;
+                    self.sent_at = Instant::now();
+                }
+            }
+            self.window = window;
+            return;
+        }
+        self.window = window;
+        self.duplicates = 0;
+        let mut data = acked;
+        if self.state == State::SynReceived {
+            self.state = State::Established;
+            data -= 1;
+        }
+        let count = data.min(self.unacked.len());
+        self.unacked.drain(..count);
+        // Only the FIN follows the data.
+        if data > count {
+            self.fin_acked = true;
+        }
+        self.snd_una = ack;
+        if before(self.snd_nxt, ack) {
+            self.snd_nxt = ack;
+        }
+        self.sent_at = Instant::now();
+        self.retransmits = 0;
+    }
+
+    /// Send the host's data that the guest's window takes, and the FIN after it.
+    fn transmit(&mut self, link: &mut Link) {
+        if self.state != State::Established || self.fin_acked {
+            return;
+        }
+        loop {
+            let offset = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            let count = self
+                .unacked
+                .len()
+                .saturating_sub(offset)
+                .min(self.window.saturating_sub(offset))
+                .min(self.mss);
+            if count == 0 {
+                break;
+            }
+            if self.snd_max == self.snd_una {
+                self.sent_at = Instant::now();
+            }
+            let data: Vec<u8> = self
+                .unacked
+                .range(offset..offset + count)
+                .copied()
+                .collect();
+            self.send(link, self.snd_nxt, TCP_ACK | TCP_PSH, &data);
+            self.advance(count as u32);
+        }
+        let offset = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+        if self.host_eof && offset == self.unacked.len() {
+            if self.snd_max == self.snd_una {
+                self.sent_at = Instant::now();
+            }
+            self.send(link, self.snd_nxt, TCP_FIN | TCP_ACK, &[]);
+            self.advance(1);
+        }
+    }
+
+    fn advance(&mut self, count: u32) {
+        self.snd_nxt = self.snd_nxt.wrapping_add(count);
+        if before(self.snd_max, self.snd_nxt) {
+            self.snd_max = self.snd_nxt;
+        }
+    }
+
+    /// Write the guest's data to the host. Return false if the host is gone.
+    fn flush(&mut self, link: &mut Link) -> bool {
+        while !self.to_host.is_empty() {
+            let (data, _) = self.to_host.as_slices();
+            match self.stream.write(data) {
+                Ok(0) => return false,
+                Ok(count) => {
+                    self.to_host.drain(..count);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return false,
+            }
+        }
+        if self.to_host.is_empty() && self.guest_fin && !self.host_shut {
+            let _ = self.stream.shutdown(Shutdown::Write);
+            self.host_shut = true;
+        }
+        // The guest waits to be told that the window opened again.
+        if self.state == State::Established && self.advertised < self.mss && self.room() >= self.mss
+        {
+            self.send_ack(link);
+        }
+        true
+    }
+
+    /// Move data between the host and the guest, and send again what the guest didn't
+    /// acknowledge in time. Return false once the connection is over.
+    fn poll(&mut self, link: &mut Link, buffer: &mut [u8]) -> bool {
+        if !self.flush(link) {
+            self.send(link, self.snd_nxt, TCP_RST | TCP_ACK, &[]);
+            return false;
+        }
+        if self.state == State::Established && !self.host_eof {
+            while self.unacked.len() < SEND_BUFFER {
+                let count = (SEND_BUFFER - self.unacked.len()).min(buffer.len());
+                match self.stream.read(&mut buffer[..count]) {
+                    Ok(0) => self.host_eof = true,
+                    Ok(count) => {
+                        self.unacked.extend(&buffer[..count]);
+                        continue;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(_) => {
+                        self.send(link, self.snd_nxt, TCP_RST | TCP_ACK, &[]);
+                        return false;
+                    }
+                }
+                break;
+            }
+        }
+        if self.snd_max != self.snd_una && self.sent_at.elapsed() >= RETRANSMIT_TIMEOUT {
+            self.retransmits += 1;
+            if self.retransmits > MAX_RETRANSMITS {
+                self.send(link, self.snd_nxt, TCP_RST | TCP_ACK, &[]);
+                return false;
+            }
+            match self.state {
+                State::SynSent | State::SynReceived => self.send_syn(link),
+                // Go back to the oldest segment, and send everything after it again.
+                State::Established => {
+                    self.snd_nxt = self.snd_una;
+                    self.sent_at = Instant::now();
+                }
+            }
+        }
+        self.transmit(link);
+        !self.finished()
+    }
+
+    /// Return true once both ends closed and all data went through.
+    fn finished(&self) -> bool {
+        self.fin_acked && self.host_shut
+    }
+}
+
+/// UDP datagrams between a port of the guest and a port of the host's loopback.
+struct UdpFlow {
+    socket: UdpSocket,
+    /// When the last datagram went through.
+    last: Instant,
+}
+
+impl UdpFlow {
+    fn open(port: u16) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        socket.connect((Ipv4Addr::LOCALHOST, port))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            last: Instant::now(),
+        })
+    }
+}
+
+/// The network stack, which runs in its own thread.
+struct Stack {
+    link: Link,
+    /// The TCP connections and UDP flows by the guest's port and the gateway's.
+    tcp: HashMap<(u16, u16), Connection>,
+    udp: HashMap<(u16, u16), UdpFlow>,
+    /// The forwarded ports of the host's loopback, with the ports of the guest.
+    listeners: Vec<(TcpListener, u16)>,
+    /// The gateway's port of the next forwarded connection.
+    next_port: u16,
+    /// The initial sequence number of the last connection.
+    isn: u32,
+}
+
+impl Stack {
+    fn new(outbox: Outbox, listeners: Vec<(TcpListener, u16)>) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            link: Link {
+                outbox,
+                guest_mac: None,
+                guest_ip: GUEST,
+                ip_id: 0,
+            },
+            tcp: HashMap::new(),
+            udp: HashMap::new(),
+            listeners,
+            next_port: FIRST_FORWARD_PORT,
+            isn: time.subsec_nanos(),
+        }
+    }
+
+    /// Handle the guest's frames and poll the host's sockets until the device is gone.
+    fn run(&mut self, frames: Receiver<Vec<u8>>) {
+        let mut buffer = vec![0; 65536];
+        loop {
+            // Open connections are polled often, forwarded ports less so.
+            let timeout = match self.tcp.is_empty() && self.udp.is_empty() {
+                true => Duration::from_millis(50),
+                false => Duration::from_millis(1),
+            };
+            match frames.recv_timeout(timeout) {
+                Ok(frame) => self.receive(&frame),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            self.poll(&mut buffer);
+        }
+    }
+
+    fn receive(&mut self, frame: &[u8]) {
+        if frame.len() < 14 {
+            return;
+        }
+        if frame[6] & 1 == 0 {
+            let mut mac = [0; 6];
+            mac.copy_from_slice(&frame[6..12]);
+            self.link.guest_mac = Some(mac);
+        }
+        match be16(frame, 12) {
+            ETHERTYPE_ARP => self.arp(&frame[14..]),
+            ETHERTYPE_IPV4 => self.ipv4(&frame[14..]),
+            // IPv6 isn't routed.
+            _ => {}
+        }
+    }
+
+    /// Answer the guest's question for the gateway's Ethernet address.
+    fn arp(&mut self, packet: &[u8]) {
+        // Ethernet and IPv4 addresses.
+        if packet.len() < 28
+            || be16(packet, 0) != 1
+            || be16(packet, 2) != ETHERTYPE_IPV4
+            || be16(packet, 6) != ARP_REQUEST
+            || take4(packet, 24) != GATEWAY
+        {
+            return;
+        }
+        let mut reply = packet[..28].to_vec();
+        put16(&mut reply, 6, ARP_REPLY);
+        reply[8..14].copy_from_slice(&GATEWAY_MAC);
+        reply[14..18].copy_from_slice(&GATEWAY);
+        reply[18..28].copy_from_slice(&packet[8..18]);
+        let mut mac = [0; 6];
+        mac.copy_from_slice(&packet[8..14]);
+        self.link.send_frame(mac, ETHERTYPE_ARP, &reply);
+    }
+
+    fn ipv4(&mut self, packet: &[u8]) {
+        if packet.len() < 20 || packet[0] >> 4 != 4 {
+            return;
+        }
+        let header_len = (packet[0] & 0xf) as usize * 4;
+        let len = be16(packet, 2) as usize;
+        // Fragments aren't reassembled.
+        if header_len < 20
+            || len < header_len
+            || len > packet.len()
+            || be16(packet, 6) & 0x3fff != 0
+        {
+            return;
+        }
+        let source = take4(packet, 12);
+        let destination = take4(packet, 16);
+        if source != [0; 4] {
+            self.link.guest_ip = source;
+        }
+        let payload = &packet[header_len..len];
+        match packet[9] {
+            PROTOCOL_UDP => self.udp(destination, payload),
+            PROTOCOL_ICMP if destination == GATEWAY => self.icmp(payload),
+            PROTOCOL_TCP if destination == GATEWAY => self.tcp(payload),
+            _ => {}
+        }
+    }
+
+    /// Answer the guest's pings of the gateway.
+    fn icmp(&mut self, message: &[u8]) {
+        if message.len() < 8 || message[0] != ICMP_ECHO_REQUEST {
+            return;
+        }
+        let mut reply = message.to_vec();
+        reply[0] = ICMP_ECHO_REPLY;
+        put16(&mut reply, 2, 0);
+        let value = checksum(sum(0, &reply));
+        put16(&mut reply, 2, value);
+        let destination = self.link.guest_ip;
+        self.link.send_ipv4(destination, PROTOCOL_ICMP, &reply);
+    }
+
+    fn udp(&mut self, destination: [u8; 4], datagram: &[u8]) {
+        if datagram.len() < 8 {
+            return;
+        }
+        let len = be16(datagram, 4) as usize;
+        if len < 8 || len > datagram.len() {
+            return;
+        }
+        let ports = (be16(datagram, 0), be16(datagram, 2));
+        let data = &datagram[8..len];
+        if ports.1 == DHCP_SERVER_PORT {
+            self.dhcp(data);
+            return;
+        }
+        if destination != GATEWAY {
+            return;
+        }
+        let flow = match self.udp.entry(ports) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match UdpFlow::open(ports.1) {
+                Ok(flow) => entry.insert(flow),
+                Err(_) => return,
+            },
+        };
+        flow.last = Instant::now();
+        // A datagram the host refuses is lost.
+        let _ = flow.socket.send(data);
+    }
+
+    /// Offer the guest its address, and acknowledge its request for it.
+    fn dhcp(&mut self, message: &[u8]) {
+        // op, htype, hlen, hops, xid, secs, flags, ciaddr, yiaddr, siaddr, giaddr, chaddr[16],
+        // sname[64], file[128], the magic cookie and the options.
+        if message.len() < 240 || message[0] != 1 || message[236..240] != DHCP_MAGIC {
+            return;
+        }
+        let mut message_type = None;
+        let mut options = &message[240..];
+        while let [code, rest @ ..] = options {
+            match *code {
+                0 => options = rest,
+                DHCP_OPTION_END => break,
+                _ => {
+                    let len = match rest.first() {
+                        Some(&len) if (len as usize) < rest.len() => len as usize,
+                        _ => return,
+                    };
+                    if *code == DHCP_OPTION_MESSAGE_TYPE && len == 1 {
+                        message_type = Some(rest[1]);
+                    }
+                    options = &rest[1 + len..];
+                }
+            }
+        }
+        let reply_type = match message_type {
+            Some(DHCP_DISCOVER) => DHCP_OFFER,
+            Some(DHCP_REQUEST) => DHCP_ACK,
+            _ => return,
+        };
+
+        let mut reply = vec![0; 240];
+        reply[0] = 2;
+        reply[1..3].copy_from_slice(&message[1..3]);
+        reply[4..8].copy_from_slice(&message[4..8]);
+        reply[10..12].copy_from_slice(&message[10..12]);
+        reply[16..20].copy_from_slice(&GUEST);
+        reply[20..24].copy_from_slice(&GATEWAY);
+        reply[28..44].copy_from_slice(&message[28..44]);
+        reply[236..240].copy_from_slice(&DHCP_MAGIC);
+        reply.extend_from_slice(&[DHCP_OPTION_MESSAGE_TYPE, 1, reply_type]);
+        reply.extend_from_slice(&[DHCP_OPTION_SERVER, 4]);
+        reply.extend_from_slice(&GATEWAY);
+        reply.extend_from_slice(&[DHCP_OPTION_LEASE_TIME, 4]);
+        reply.extend_from_slice(&DHCP_LEASE_SECONDS.to_be_bytes());
+        reply.extend_from_slice(&[DHCP_OPTION_NETMASK, 4]);
+        reply.extend_from_slice(&NETMASK);
+        reply.extend_from_slice(&[DHCP_OPTION_ROUTER, 4]);
+        reply.extend_from_slice(&GATEWAY);
+        reply.push(DHCP_OPTION_END);
+        reply.resize(reply.len().max(DHCP_MIN_LEN), 0);
+        // The guest has no address yet.
+        self.link
+            .send_udp(BROADCAST, (DHCP_SERVER_PORT, DHCP_CLIENT_PORT), &reply);
+    }
+
+    fn tcp(&mut self, bytes: &[u8]) {
+        let segment = match Segment::parse(bytes) {
+            Some(segment) => segment,
+            None => return,
+        };
+        let connection = match self.tcp.get_mut(&segment.ports) {
+            Some(connection) => connection,
+            None => {
+                self.connect(&segment);
+                return;
+            }
+        };
+        if !connection.receive(&mut self.link, &segment) {
+            self.tcp.remove(&segment.ports);
+        }
+    }
+
+    /// Connect the guest's SYN to the same port of the host's loopback.
+    fn connect(&mut self, segment: &Segment) {
+        let ports = (segment.ports.1, segment.ports.0);
+        if segment.flags & (TCP_SYN | TCP_ACK | TCP_RST) != TCP_SYN {
+            self.link.send_reset(ports, segment);
+            return;
+        }
+        let address = SocketAddr::from((Ipv4Addr::LOCALHOST, ports.0));
+        let stream = match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)
+            .and_then(|stream| stream.set_nonblocking(true).map(|()| stream))
+        {
+            Ok(stream) => stream,
+            // Nothing listens there.
+            Err(_) => {
+                self.link.send_reset(ports, segment);
+                return;
+            }
+        };
+        let _ = stream.set_nodelay(true);
+        let isn = self.next_isn();
+        let mut connection = Connection::new(stream, ports, isn, State::SynReceived);
+        connection.rcv_nxt = segment.seq.wrapping_add(1);
+        connection.window = segment.window as usize;
+        connection.mss = segment.mss.unwrap_or(TCP_DEFAULT_MSS);
+        connection.send_syn(&mut self.link);
+        self.tcp.insert(segment.ports, connection);
+    }
+
+    /// Connect `stream`, accepted on a forwarded port, to `guest_port`.
+    fn forward(&mut self, stream: TcpStream, guest_port: u16) {
+        if stream.set_nonblocking(true).is_err() {
+            return;
+        }
+        let _ = stream.set_nodelay(true);
+        let port = loop {
+            let port = self.next_port;
+            self.next_port = match port {
+                u16::MAX => FIRST_FORWARD_PORT,
+                _ => port + 1,
+            };
+            if !self.tcp.contains_key(&(guest_port, port)) {
+                break port;
+            }
+        };
+        let isn = self.next_isn();
+        let mut connection = Connection::new(stream, (port, guest_port), isn, State::SynSent);
+        connection.send_syn(&mut self.link);
+        self.tcp.insert((guest_port, port), connection);
+    }
+
+    fn next_isn(&mut self) -> u32 {
+        self.isn = self.isn.wrapping_add(0x0100_0000);
+        self.isn
+    }
+
+    /// Accept connections on the forwarded ports, and move data between the host's sockets and
+    /// the guest.
+    fn poll(&mut self, buffer: &mut [u8]) {
+        for index in 0..self.listeners.len() {
+            while let Ok((stream, _)) = self.listeners[index].0.accept() {
+                let guest_port = self.listeners[index].1;
+                self.forward(stream, guest_port);
+            }
+        }
+
+        let link = &mut self.link;
+        self.tcp
+            .retain(|_, connection| connection.poll(link, buffer));
+
+        let now = Instant::now();
+        self.udp.retain(|&(guest_port, port), flow| {
+            while let Ok(count) = flow.socket.recv(buffer) {
+                flow.last = now;
+                let destination = link.guest_ip;
+                link.send_udp(destination, (port, guest_port), &buffer[..count]);
+            }
+            now.duration_since(flow.last) < UDP_TIMEOUT
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUEST_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    fn stack() -> (Stack, Inbox) {
+        let (outbox, inbox) = Inbox::new(INBOX_FRAMES);
+        (Stack::new(outbox, Vec::new()), inbox)
+    }
+
+    fn frame(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = GATEWAY_MAC.to_vec();
+        frame.extend_from_slice(&GUEST_MAC);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn ipv4(source: [u8; 4], destination: [u8; 4], protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; 20];
+        packet[0] = 0x45;
+        put16(&mut packet, 2, (20 + payload.len()) as u16);
+        packet[8] = 64;
+        packet[9] = protocol;
+        packet[12..16].copy_from_slice(&source);
+        packet[16..20].copy_from_slice(&destination);
+        packet.extend_from_slice(payload);
+        frame(ETHERTYPE_IPV4, &packet)
+    }
+
+    /// Return the IPv4 packet of the next frame the stack sent, after checking its header.
+    fn reply(inbox: &mut Inbox, protocol: u8) -> (Vec<u8>, Vec<u8>) {
+        let frame = inbox.recv().expect("no reply");
+        assert_eq!(be16(&frame, 12), ETHERTYPE_IPV4);
+        let packet = &frame[14..];
+        assert_eq!(checksum(sum(0, &packet[..20])), 0);
+        assert_eq!(be16(packet, 2) as usize, packet.len());
+        assert_eq!(packet[9], protocol);
+        assert_eq!(take4(packet, 12), GATEWAY);
+        (frame[..6].to_vec(), packet.to_vec())
+    }
+
+    fn arp(operation: u16, target: [u8; 4]) -> Vec<u8> {
+        let mut packet = vec![0, 1, 0x08, 0x00, 6, 4];
+        packet.extend_from_slice(&operation.to_be_bytes());
+        packet.extend_from_slice(&GUEST_MAC);
+        packet.extend_from_slice(&GUEST);
+        packet.extend_from_slice(&[0; 6]);
+        packet.extend_from_slice(&target);
+        frame(ETHERTYPE_ARP, &packet)
+    }
+
+    #[test]
+    fn arp_requests_for_the_gateway() {
+        let (mut stack, mut inbox) = stack();
+        stack.receive(&arp(ARP_REQUEST, GATEWAY));
+        let reply = inbox.recv().unwrap();
+        assert_eq!(reply[..6], GUEST_MAC);
+        assert_eq!(be16(&reply, 12), ETHERTYPE_ARP);
+        let packet = &reply[14..];
+        assert_eq!(packet.len(), 28);
+        assert_eq!(be16(packet, 6), ARP_REPLY);
+        assert_eq!(packet[8..14], GATEWAY_MAC);
+        assert_eq!(take4(packet, 14), GATEWAY);
+        assert_eq!(packet[18..24], GUEST_MAC);
+        assert_eq!(take4(packet, 24), GUEST);
+
+        // Other addresses, replies and truncated requests aren't answered.
+        stack.receive(&arp(ARP_REQUEST, [10, 0, 2, 3]));
+        stack.receive(&arp(ARP_REPLY, GATEWAY));
+        let request = arp(ARP_REQUEST, GATEWAY);
+        stack.receive(&request[..request.len() - 1]);
+        stack.receive(&request[..13]);
+        assert!(inbox.recv().is_none());
+    }
+
+    #[test]
+    fn pings_of_the_gateway() {
+        let (mut stack, mut inbox) = stack();
+        let mut echo = vec![ICMP_ECHO_REQUEST, 0, 0, 0, 0x12, 0x34, 0, 1];
+        echo.extend_from_slice(b"ping");
+        let value = checksum(sum(0, &echo));
+        put16(&mut echo, 2, value);
+        stack.receive(&ipv4(GUEST, GATEWAY, PROTOCOL_ICMP, &echo));
+        let (mac, packet) = reply(&mut inbox, PROTOCOL_ICMP);
+        assert_eq!(mac, GUEST_MAC);
+        assert_eq!(take4(&packet, 16), GUEST);
+        let message = &packet[20..];
+        assert_eq!(message[0], ICMP_ECHO_REPLY);
+        assert_eq!(checksum(sum(0, message)), 0);
+        assert_eq!(message[4..], echo[4..]);
+
+        // Only the gateway answers, and only whole packets.
+        stack.receive(&ipv4(GUEST, [10, 0, 2, 3], PROTOCOL_ICMP, &echo));
+        let mut truncated = ipv4(GUEST, GATEWAY, PROTOCOL_ICMP, &echo);
+        put16(&mut truncated[14..], 2, 20 + echo.len() as u16 + 1);
+        stack.receive(&truncated);
+        let mut fragment = ipv4(GUEST, GATEWAY, PROTOCOL_ICMP, &echo);
+        put16(&mut fragment[14..], 6, 0x2000);
+        stack.receive(&fragment);
+        assert!(inbox.recv().is_none());
+    }
+
+    /// A DHCP message from the guest, with `options` after the magic cookie.
+    fn dhcp(options: &[u8]) -> Vec<u8> {
+        let mut message = vec![0; 240];
+        message[..3].copy_from_slice(&[1, 1, 6]);
+        message[4..8].copy_from_slice(&0xdead_beef_u32.to_be_bytes());
+        message[28..34].copy_from_slice(&GUEST_MAC);
+        message[236..240].copy_from_slice(&DHCP_MAGIC);
+        message.extend_from_slice(options);
+        let mut datagram = vec![0; 8];
+        put16(&mut datagram, 0, DHCP_CLIENT_PORT);
+        put16(&mut datagram, 2, DHCP_SERVER_PORT);
+        put16(&mut datagram, 4, (8 + message.len()) as u16);
+        datagram.extend_from_slice(&message);
+        ipv4([0; 4], BROADCAST, PROTOCOL_UDP, &datagram)
+    }
+
+    /// Return the value of the DHCP option `code` in `options`.
+    fn option(mut options: &[u8], code: u8) -> Option<&[u8]> {
+        while let [kind, len, rest @ ..] = options {
+            let len = *len as usize;
+            if *kind == code {
+                return Some(&rest[..len]);
+            }
+            options = &rest[len..];
+        }
+        None
+    }
+
+    #[test]
+    fn dhcp_hands_out_the_guest_address() {
+        let (mut stack, mut inbox) = stack();
+        for (request, reply_type) in [(DHCP_DISCOVER, DHCP_OFFER), (DHCP_REQUEST, DHCP_ACK)] {
+            // Padding before the message type.
+            stack.receive(&dhcp(&[
+                0,
+                DHCP_OPTION_MESSAGE_TYPE,
+                1,
+                request,
+                DHCP_OPTION_END,
+            ]));
+            let (mac, packet) = reply(&mut inbox, PROTOCOL_UDP);
+            assert_eq!(mac, BROADCAST_MAC);
+            assert_eq!(take4(&packet, 16), BROADCAST);
+            let datagram = &packet[20..];
+            assert_eq!(be16(datagram, 0), DHCP_SERVER_PORT);
+            assert_eq!(be16(datagram, 2), DHCP_CLIENT_PORT);
+            assert_eq!(
+                transport_checksum(PROTOCOL_UDP, &Link::addresses(BROADCAST), datagram),
+                0
+            );
+            let message = &datagram[8..];
+            assert!(message.len() >= DHCP_MIN_LEN);
+            assert_eq!(message[0], 2);
+            assert_eq!(be32(message, 4), 0xdead_beef);
+            assert_eq!(take4(message, 16), GUEST);
+            assert_eq!(message[28..34], GUEST_MAC);
+            let options = &message[240..];
+            assert_eq!(
+                option(options, DHCP_OPTION_MESSAGE_TYPE),
+                Some(&[reply_type][..])
+            );
+            assert_eq!(option(options, DHCP_OPTION_SERVER), Some(&GATEWAY[..]));
+            assert_eq!(option(options, DHCP_OPTION_ROUTER), Some(&GATEWAY[..]));
+            assert_eq!(option(options, DHCP_OPTION_NETMASK), Some(&NETMASK[..]));
+        }
+
+        // Messages that aren't a discover or a request, or that don't parse, aren't answered.
+        stack.receive(&dhcp(&[
+            DHCP_OPTION_MESSAGE_TYPE,
+            1,
+            DHCP_ACK,
+            DHCP_OPTION_END,
+        ]));
+        stack.receive(&dhcp(&[DHCP_OPTION_END]));
+        stack.receive(&dhcp(&[DHCP_OPTION_MESSAGE_TYPE, 2, DHCP_DISCOVER]));
+        stack.receive(&dhcp(&[DHCP_OPTION_MESSAGE_TYPE]));
+        let mut reply = dhcp(&[DHCP_OPTION_MESSAGE_TYPE, 1, DHCP_DISCOVER]);
+        reply[14 + 20 + 8] = 2;
+        stack.receive(&reply);
+        let mut magic = dhcp(&[DHCP_OPTION_MESSAGE_TYPE, 1, DHCP_DISCOVER]);
+        magic[14 + 20 + 8 + 236] = 0;
+        stack.receive(&magic);
+        assert!(inbox.recv().is_none());
+    }
+
+    fn tcp_segment(flags: u8, seq: u32, ack: u32, options: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0; 20];
+        put16(&mut segment, 0, 40000);
+        put16(&mut segment, 2, 8080);
+        segment[4..8].copy_from_slice(&seq.to_be_bytes());
+        segment[8..12].copy_from_slice(&ack.to_be_bytes());
+        segment[12] = (((20 + options.len()) / 4) << 4) as u8;
+        segment[13] = flags;
+        put16(&mut segment, 14, 0xffff);
+        segment.extend_from_slice(options);
+        segment.extend_from_slice(data);
+        segment
+    }
+
+    #[test]
+    fn tcp_segments_are_parsed() {
+        let bytes = tcp_segment(
+            TCP_SYN,
+            7,
+            0,
+            &[1, 1, TCP_OPTION_MSS, 4, 0x05, 0xb4, 0, 0],
+            b"data",
+        );
+        let segment = Segment::parse(&bytes).unwrap();
+        assert_eq!(segment.ports, (40000, 8080));
+        assert_eq!(segment.seq, 7);
+        assert_eq!(segment.flags, TCP_SYN);
+        assert_eq!(segment.window, 0xffff);
+        assert_eq!(segment.mss, Some(1460));
+        assert_eq!(segment.data, b"data");
+        assert_eq!(segment.len(), 5);
+
+        // Options after the end of the list, or with a bad length, are ignored.
+        let bytes = tcp_segment(
+            TCP_FIN,
+            0,
+            0,
+            &[0, TCP_OPTION_MSS, 4, 0x05, 0xb4, 0, 0, 0],
+            &[],
+        );
+        let segment = Segment::parse(&bytes).unwrap();
+        assert_eq!(segment.mss, None);
+        assert_eq!(segment.len(), 1);
+        let bytes = tcp_segment(TCP_SYN, 0, 0, &[TCP_OPTION_MSS, 8, 0x05, 0xb4], &[]);
+        assert_eq!(Segment::parse(&bytes).unwrap().mss, None);
+        let bytes = tcp_segment(TCP_SYN, 0, 0, &[TCP_OPTION_MSS, 1, 0, 0], &[]);
+        assert_eq!(Segment::parse(&bytes).unwrap().mss, None);
+
+        // The data offset must be in the segment and cover the header.
+        let mut bytes = tcp_segment(TCP_SYN, 0, 0, &[], &[]);
+        bytes[12] = 4 << 4;
+        assert!(Segment::parse(&bytes).is_none());
+        bytes[12] = 6 << 4;
+        assert!(Segment::parse(&bytes).is_none());
+        assert!(Segment::parse(&bytes[..19]).is_none());
+    }
+
+    /// Send `segment` to the gateway and return the flags, sequence and acknowledgement numbers
+    /// and options of the answer.
+    fn answer(stack: &mut Stack, inbox: &mut Inbox, segment: &[u8]) -> (u8, u32, u32, Vec<u8>) {
+        stack.receive(&ipv4(GUEST, GATEWAY, PROTOCOL_TCP, segment));
+        let (_, packet) = reply(inbox, PROTOCOL_TCP);
+        let answer = &packet[20..];
+        assert_eq!(
+            transport_checksum(PROTOCOL_TCP, &Link::addresses(GUEST), answer),
+            0
+        );
+        let offset = (answer[12] >> 4) as usize * 4;
+        (
+            answer[13],
+            be32(answer, 4),
+            be32(answer, 8),
+            answer[20..offset].to_vec(),
+        )
+    }
+
+    #[test]
+    fn tcp_connections_to_the_host() {
+        let (mut stack, mut inbox) = stack();
+        // A segment of no connection is reset.
+        let segment = tcp_segment(TCP_ACK, 100, 5000, &[], b"late");
+        assert_eq!(
+            answer(&mut stack, &mut inbox, &segment),
+            (TCP_RST, 5000, 0, Vec::new())
+        );
+        // But not a reset.
+        let segment = tcp_segment(TCP_RST, 100, 0, &[], &[]);
+        stack.receive(&ipv4(GUEST, GATEWAY, PROTOCOL_TCP, &segment));
+        assert!(inbox.recv().is_none());
+
+        // A port of the host's loopback where nothing listens.
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut segment = tcp_segment(TCP_SYN, 100, 0, &[], &[]);
+        put16(&mut segment, 2, port);
+        assert_eq!(
+            answer(&mut stack, &mut inbox, &segment),
+            (TCP_RST | TCP_ACK, 0, 101, Vec::new())
+        );
+
+        // And one where something does.
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        put16(&mut segment, 2, listener.local_addr().unwrap().port());
+        let (flags, _, ack, options) = answer(&mut stack, &mut inbox, &segment);
+        assert_eq!(flags, TCP_SYN | TCP_ACK);
+        assert_eq!(ack, 101);
+        assert_eq!(options, [TCP_OPTION_MSS, 4, 0x05, 0xb4]);
+        assert!(listener.accept().is_ok());
+    }
+}
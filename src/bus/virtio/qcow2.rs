@@ -0,0 +1,825 @@
+//! The qcow2 module reads and writes disk images in QEMU's qcow2 format, version 2 or 3. A guest
+//! cluster is found through a two-level table, the L1 table and an L2 table, and clusters that
+//! aren't allocated read from the backing file, if there's one, or as zeros. New clusters are
+//! appended to the file and counted in the refcount blocks, so that `qemu-img check` finds the
+//! image consistent. Compressed clusters are read, and a write to one moves it to a new,
+//! uncompressed cluster.
+//!
+//! The qcow2 spec:
+//! https://gitlab.com/qemu-project/qemu/-/blob/master/docs/interop/qcow2.txt
+
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::inflate::inflate;
+use super::storage::{open_image, FileStorage, Storage};
+
+/// The first 4 bytes of a qcow2 image, "QFI\xfb".
+pub const QCOW2_MAGIC: [u8; 4] = *b"QFI\xfb";
+
+/// The offset bits of L1, L2 and refcount table entries.
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// The cluster of an L1 or L2 entry has a refcount of exactly 1 and may be written in place.
+const COPIED: u64 = 1 << 63;
+/// The cluster of an L2 entry is compressed.
+const COMPRESSED: u64 = 1 << 62;
+/// The cluster of an L2 entry reads as zeros (version 3).
+const ZERO: u64 = 1;
+
+/// The incompatible feature bit that says refcounts may be wrong.
+const INCOMPATIBLE_DIRTY: u64 = 1;
+/// The header extension that names the format of the backing file.
+const EXTENSION_BACKING_FORMAT: u32 = 0xe279_2aca;
+/// The end of the header extensions.
+const EXTENSION_END: u32 = 0;
+/// The longest backing file name.
+const MAX_BACKING_NAME: usize = 1023;
+/// The most images in a chain of backing files, which mustn't loop.
+const MAX_CHAIN: usize = 16;
+/// The largest L1 table and refcount table, as in QEMU. The sizes in the header are checked
+/// against them before the tables are read, so that an image can't make us allocate any amount.
+const MAX_L1_SIZE: u64 = 32 << 20;
+const MAX_REFCOUNT_TABLE_SIZE: u64 = 8 << 20;
+
+/// The cluster size of the images `create` makes, 64 KiB.
+const CLUSTER_BITS: u32 = 16;
+/// The size of the version 3 header without optional fields.
+const HEADER_LENGTH: u32 = 104;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn be32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+fn be64(bytes: &[u8], offset: usize) -> u64 {
+    (be32(bytes, offset) as u64) << 32 | be32(bytes, offset + 4) as u64
+}
+
+/// Where the data of a guest cluster is.
+enum Cluster {
+    /// In the backing file, or zeros without one.
+    Unallocated,
+    /// Zeros.
+    Zero,
+    /// At this offset of the file.
+    Normal(u64),
+    /// Compressed, at this offset of the file and this long at most.
+    Compressed(u64, u64),
+}
+
+/// A qcow2 image.
+pub struct Qcow2 {
+    file: File,
+    read_only: bool,
+    cluster_bits: u32,
+    /// The size of the disk the image holds.
+    size: u64,
+    l1: Vec<u64>,
+    l1_offset: u64,
+    refcount_table: Vec<u64>,
+    refcount_table_offset: u64,
+    /// The log2 of the width of a refcount in bits.
+    refcount_order: u32,
+    backing: Option<Box<dyn Storage>>,
+    backing_path: Option<PathBuf>,
+    /// The end of the file, where clusters are allocated.
+    end: u64,
+    /// The guest offset and the contents of the cluster decompressed last.
+    inflated: RefCell<Option<(u64, Vec<u8>)>>,
+}
+
+impl Qcow2 {
+    /// Open the qcow2 image at `path`, for reading only if `read_only` is set. Its backing
+    /// file, if it has one, is opened for reading only.
+    pub fn open(path: &Path, read_only: bool) -> io::Result<Self> {
+        Self::open_chain(path, read_only, 0)
+    }
+
+    /// Open the image at `path`, the backing file of `depth` others.
+    fn open_chain(path: &Path, read_only: bool, depth: usize) -> io::Result<Self> {
+        if depth > MAX_CHAIN {
+            return Err(invalid(format!(
+                "the chain of backing files is longer than {} images",
+                MAX_CHAIN
+            )));
+        }
+        let mut file = OpenOptions::new().read(true).write(!read_only).open(path)?;
+        let mut header = vec![0; HEADER_LENGTH as usize];
+        file.read_exact(&mut header[..72])?;
+        if header[..4] != QCOW2_MAGIC {
+            return Err(invalid("not a qcow2 image".to_string()));
+        }
+        let version = be32(&header, 4);
+        let (incompatible, autoclear, refcount_order, header_length) = match version {
+            2 => (0, 0, 4, 72),
+            3 => {
+                file.read_exact(&mut header[72..])?;
+                (
+                    be64(&header, 72),
+                    be64(&header, 88),
+                    be32(&header, 96),
+                    be32(&header, 100),
+                )
+            }
+            _ => return Err(invalid(format!("qcow2 version {} is unsupported", version))),
+        };
+        let cluster_bits = be32(&header, 20);
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(invalid(format!("invalid cluster bits {}", cluster_bits)));
+        }
+        if be32(&header, 32) != 0 {
+            return Err(invalid(
+                "encrypted qcow2 images are unsupported".to_string(),
+            ));
+        }
+        let unsupported = if read_only {
+            incompatible & !INCOMPATIBLE_DIRTY
+        } else {
+            incompatible
+        };
+        if unsupported != 0 {
+            return Err(invalid(format!(
+                "qcow2 incompatible features {:#x} are unsupported",
+                unsupported
+            )));
+        }
+        if refcount_order > 6 {
+            return Err(invalid(format!(
+                "invalid refcount order {}",
+                refcount_order
+            )));
+        }
+
+        let file_len = file.metadata()?.len();
+        let l1_size = be32(&header, 36) as u64;
+        let l1_offset = be64(&header, 40);
+        let l1 = read_table(
+            &file,
+            file_len,
+            l1_offset,
+            l1_size * 8,
+            MAX_L1_SIZE,
+            "L1 table",
+        )?;
+        let l1 = l1.chunks(8).map(|entry| be64(entry, 0)).collect();
+        let refcount_table_offset = be64(&header, 48);
+        let refcount_clusters = be32(&header, 56) as u64;
+        let table = read_table(
+            &file,
+            file_len,
+            refcount_table_offset,
+            refcount_clusters << cluster_bits,
+            MAX_REFCOUNT_TABLE_SIZE,
+            "refcount table",
+        )?;
+        let refcount_table = table.chunks(8).map(|entry| be64(entry, 0)).collect();
+
+        let (backing, backing_path) = match be64(&header, 8) {
+            0 => (None, None),
+            offset => {
+                let len = be32(&header, 16) as u64;
+                let name = read_table(
+                    &file,
+                    file_len,
+                    offset,
+                    len,
+                    MAX_BACKING_NAME as u64,
+                    "backing file name",
+                )?;
+                let name = String::from_utf8(name)
+                    .map_err(|_| invalid("backing file name isn't UTF-8".to_string()))?;
+                let backing_path = path.parent().unwrap_or(Path::new("")).join(name);
+                let format = backing_format(&file, header_length as u64, cluster_bits)?;
+                let qcow2 = match format.as_deref() {
+                    Some("raw") => false,
+                    Some("qcow2") => true,
+                    None => is_qcow2(&backing_path)?,
+                    Some(format) => {
+                        return Err(invalid(format!(
+                            "backing file format `{}` is unsupported",
+                            format
+                        )))
+                    }
+                };
+                let backing: Box<dyn Storage> = if qcow2 {
+                    Box::new(Self::open_chain(&backing_path, true, depth + 1)?)
+                } else {
+                    Box::new(FileStorage::open(&backing_path, true)?)
+                };
+                (Some(backing), Some(backing_path))
+            }
+        };
+
+        let cluster_size = 1 << cluster_bits;
+        let end = file_len.next_multiple_of(cluster_size);
+        let mut image = Self {
+            file,
+            read_only,
+            cluster_bits,
+            size: be64(&header, 24),
+            l1,
+            l1_offset,
+            refcount_table,
+            refcount_table_offset,
+            refcount_order,
+            backing,
+            backing_path,
+            end,
+            inflated: RefCell::new(None),
+        };
+        // A writer must clear the autoclear features it doesn't know.
+        if !read_only && autoclear != 0 {
+            image.write_file(88, &0u64.to_be_bytes())?;
+        }
+        Ok(image)
+    }
+
+    /// Create an empty qcow2 image at `path` over the read-only image `base`, which keeps the
+    /// clusters the guest hasn't written. Fail if `path` exists.
+    pub fn create(path: &Path, base: &Path) -> io::Result<()> {
+        let base = fs::canonicalize(base)?;
+        let format = if is_qcow2(&base)? { "qcow2" } else { "raw" };
+        let size = open_image(&base, true)?.size();
+        let name = base
+            .to_str()
+            .ok_or_else(|| invalid(format!("the path of {} isn't UTF-8", base.display())))?;
+        if name.len() > MAX_BACKING_NAME {
+            return Err(invalid("backing file name too long".to_string()));
+        }
+
+        let cluster_size = 1u64 << CLUSTER_BITS;
+        // An L2 table maps cluster_size / 8 clusters.
+        let l1_size = size.div_ceil(cluster_size * cluster_size / 8).max(1);
+        let l1_clusters = (l1_size * 8).div_ceil(cluster_size);
+        // The header, the refcount table, a refcount block and the L1 table.
+        let clusters = 3 + l1_clusters;
+        let mut image = vec![0; (clusters * cluster_size) as usize];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            image[offset..offset + bytes.len()].copy_from_slice(bytes)
+        };
+
+        let mut extensions = Vec::new();
+        extensions.extend_from_slice(&EXTENSION_BACKING_FORMAT.to_be_bytes());
+        extensions.extend_from_slice(&(format.len() as u32).to_be_bytes());
+        extensions.extend_from_slice(format.as_bytes());
+        extensions.resize(extensions.len().next_multiple_of(8), 0);
+        extensions.extend_from_slice(&EXTENSION_END.to_be_bytes());
+        extensions.extend_from_slice(&0u32.to_be_bytes());
+        let name_offset = HEADER_LENGTH as u64 + extensions.len() as u64;
+
+        put(0, &QCOW2_MAGIC);
+        put(4, &3u32.to_be_bytes());
+        put(8, &name_offset.to_be_bytes());
+        put(16, &(name.len() as u32).to_be_bytes());
+        put(20, &CLUSTER_BITS.to_be_bytes());
+        put(24, &size.to_be_bytes());
+        put(36, &(l1_size as u32).to_be_bytes());
+        put(40, &(3 * cluster_size).to_be_bytes());
+        put(48, &cluster_size.to_be_bytes());
+        put(56, &1u32.to_be_bytes());
+        put(96, &4u32.to_be_bytes());
+        put(100, &HEADER_LENGTH.to_be_bytes());
+        put(HEADER_LENGTH as usize, &extensions);
+        put(name_offset as usize, name.as_bytes());
+        // The refcount table points to the refcount block, which counts the clusters above.
+        put(cluster_size as usize, &(2 * cluster_size).to_be_bytes());
+        for cluster in 0..clusters as usize {
+            put(2 * cluster_size as usize + 2 * cluster, &1u16.to_be_bytes());
+        }
+
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.write_all(&image)
+    }
+
+    /// Open the qcow2 image at `path` for writing, creating it over `base` if it doesn't exist.
+    /// Fail if it exists with another backing file.
+    pub fn overlay(path: &Path, base: &Path) -> io::Result<Self> {
+        if !path.exists() {
+            Self::create(path, base)?;
+        }
+        let image = Self::open(path, false)?;
+        let same = match &image.backing_path {
+            Some(backing) => fs::canonicalize(backing)? == fs::canonicalize(base)?,
+            None => false,
+        };
+        if !same {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "the overlay {} exists, but isn't over {}",
+                    path.display(),
+                    base.display()
+                ),
+            ));
+        }
+        Ok(image)
+    }
+
+    /// Return the backing file, if there's one.
+    pub fn backing_file(&self) -> Option<&Path> {
+        self.backing_path.as_deref()
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// Return the number of entries of an L2 table.
+    fn l2_entries(&self) -> u64 {
+        self.cluster_size() / 8
+    }
+
+    fn write_file(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)
+    }
+
+    /// Return the address of the L2 entry of guest cluster `index`, or `None` if it has no L2
+    /// table.
+    fn l2_entry_address(&self, index: u64) -> Option<u64> {
+        let l1_entry = *self.l1.get((index / self.l2_entries()) as usize)?;
+        match l1_entry & OFFSET_MASK {
+            0 => None,
+            table => Some(table + 8 * (index % self.l2_entries())),
+        }
+    }
+
+    /// Return the L2 entry of guest cluster `index`.
+    fn l2_entry(&self, index: u64) -> io::Result<u64> {
+        match self.l2_entry_address(index) {
+            Some(address) => {
+                let mut entry = [0; 8];
+                read_at(&self.file, address, &mut entry)?;
+                Ok(u64::from_be_bytes(entry))
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Return where the data of guest cluster `index` is.
+    fn cluster(&self, index: u64) -> io::Result<Cluster> {
+        let entry = self.l2_entry(index)?;
+        if entry & COMPRESSED != 0 {
+            // The offset and the number of additional 512-byte sectors the data spans.
+            let shift = 62 - (self.cluster_bits - 8);
+            let offset = entry & ((1 << shift) - 1);
+            let sectors = (entry & !(COPIED | COMPRESSED)) >> shift;
+            return Ok(Cluster::Compressed(
+                offset,
+                (sectors + 1) * 512 - (offset & 511),
+            ));
+        }
+        Ok(match entry & OFFSET_MASK {
+            _ if entry & ZERO != 0 => Cluster::Zero,
+            0 => Cluster::Unallocated,
+            offset => Cluster::Normal(offset),
+        })
+    }
+
+    /// Read `buf.len()` bytes at `within` in guest cluster `index`.
+    fn read_cluster(&self, index: u64, within: u64, buf: &mut [u8]) -> io::Result<()> {
+        let cluster_size = self.cluster_size();
+        match self.cluster(index)? {
+            Cluster::Unallocated => {
+                buf.fill(0);
+                if let Some(backing) = &self.backing {
+                    // A smaller backing file reads as zeros past its end.
+                    let offset = index * cluster_size + within;
+                    let available = backing.size().saturating_sub(offset).min(buf.len() as u64);
+                    backing.read_at(offset, &mut buf[..available as usize])?;
+                }
+                Ok(())
+            }
+            Cluster::Zero => {
+                buf.fill(0);
+                Ok(())
+            }
+            Cluster::Normal(offset) => read_at(&self.file, offset + within, buf),
+            Cluster::Compressed(offset, len) => {
+                let mut inflated = self.inflated.borrow_mut();
+                match &*inflated {
+                    Some((cached, _)) if *cached == index => {}
+                    _ => {
+                        // The data spans at most two clusters, and ends with the file.
+                        let file_len = self.file.metadata()?.len();
+                        let len = len.min(file_len.saturating_sub(offset));
+                        let data = read_table(
+                            &self.file,
+                            file_len,
+                            offset,
+                            len,
+                            2 * cluster_size,
+                            "compressed cluster",
+                        )?;
+                        let mut cluster = inflate(&data, cluster_size as usize).map_err(|e| {
+                            invalid(format!("compressed cluster at {:#x}: {}", offset, e))
+                        })?;
+                        cluster.resize(cluster_size as usize, 0);
+                        *inflated = Some((index, cluster));
+                    }
+                }
+                let cluster = &inflated.as_ref().unwrap().1;
+                buf.copy_from_slice(&cluster[within as usize..within as usize + buf.len()]);
+                Ok(())
+            }
+        }
+    }
+
+    /// Return the address of the refcount of host cluster `cluster`, allocating its refcount
+    /// block if it has none.
+    fn refcount_address(&mut self, cluster: u64) -> io::Result<(u64, u32)> {
+        let bits = 1 << self.refcount_order;
+        let per_block = self.cluster_size() * 8 / bits;
+        let index = (cluster / per_block) as usize;
+        let entry = match self.refcount_table.get(index) {
+            Some(entry) => *entry,
+            None => {
+                return Err(io::Error::other(
+                    "the refcount table of the qcow2 image is full",
+                ))
+            }
+        };
+        let block = match entry & OFFSET_MASK {
+            0 => {
+                let block = self.end;
+                self.end += self.cluster_size();
+                self.write_file(block, &vec![0; self.cluster_size() as usize])?;
+                self.refcount_table[index] = block;
+                self.write_file(
+                    self.refcount_table_offset + 8 * index as u64,
+                    &block.to_be_bytes(),
+                )?;
+                // The block counts itself if it covers itself, or is counted by another.
+                self.add_refcount(block, 1)?;
+                block
+            }
+            block => block,
+        };
+        let bit = (cluster % per_block) * bits;
+        Ok((block + bit / 8, (bit % 8) as u32))
+    }
+
+    /// Add `delta` to the refcount of the host cluster at `offset`.
+    fn add_refcount(&mut self, offset: u64, delta: i64) -> io::Result<()> {
+        let bits = 1u32 << self.refcount_order;
+        let (address, shift) = self.refcount_address(offset >> self.cluster_bits)?;
+        let len = bits.div_ceil(8) as usize;
+        let mut bytes = [0; 8];
+        read_at(&self.file, address, &mut bytes[8 - len..])?;
+        let word = u64::from_be_bytes(bytes);
+        let mask = if bits == 64 {
+            u64::MAX
+        } else {
+            (1 << bits) - 1
+        };
+        // Refcounts narrower than a byte are packed from its least significant bit.
+        let refcount = (word >> shift) & mask;
+        let refcount = if delta < 0 {
+            refcount.saturating_sub(delta.unsigned_abs())
+        } else {
+            refcount.saturating_add(delta as u64).min(mask)
+        };
+        let word = (word & !(mask << shift)) | refcount << shift;
+        self.write_file(address, &word.to_be_bytes()[8 - len..])
+    }
+
+    /// Append a cluster to the file with a refcount of 1, and return its offset.
+    fn allocate(&mut self) -> io::Result<u64> {
+        let offset = self.end;
+        self.end += self.cluster_size();
+        self.add_refcount(offset, 1)?;
+        Ok(offset)
+    }
+
+    /// Drop a reference to the data of a guest cluster that's been moved.
+    fn release(&mut self, cluster: Cluster) -> io::Result<()> {
+        match cluster {
+            Cluster::Normal(offset) => self.add_refcount(offset, -1),
+            Cluster::Compressed(offset, len) => {
+                let cluster_size = self.cluster_size();
+                let last = (offset + len - 1) / cluster_size;
+                for host in offset / cluster_size..=last {
+                    self.add_refcount(host * cluster_size, -1)?;
+                }
+                Ok(())
+            }
+            Cluster::Unallocated | Cluster::Zero => Ok(()),
+        }
+    }
+
+    /// Return the offset of the L2 table of guest cluster `index`, which may be written in
+    /// place, allocating or copying it first if needed.
+    fn writable_l2(&mut self, index: u64) -> io::Result<u64> {
+        let l1_index = (index / self.l2_entries()) as usize;
+        let entry = match self.l1.get(l1_index) {
+            Some(entry) => *entry,
+            None => return Err(invalid("the L1 table is too small".to_string())),
+        };
+        if entry & COPIED != 0 && entry & OFFSET_MASK != 0 {
+            return Ok(entry & OFFSET_MASK);
+        }
+        let table = self.allocate()?;
+        let mut contents = vec![0; self.cluster_size() as usize];
+        if entry & OFFSET_MASK != 0 {
+            // The table is shared with a snapshot.
+            read_at(&self.file, entry & OFFSET_MASK, &mut contents)?;
+            self.add_refcount(entry & OFFSET_MASK, -1)?;
+        }
+        self.write_file(table, &contents)?;
+        self.l1[l1_index] = table | COPIED;
+        self.write_file(
+            self.l1_offset + 8 * l1_index as u64,
+            &(table | COPIED).to_be_bytes(),
+        )?;
+        Ok(table)
+    }
+
+    /// Return the offset of the host cluster of guest cluster `index`, which may be written in
+    /// place, moving its data to a new cluster first if needed. Unless `whole`, when the caller
+    /// writes all of it, the data is copied.
+    fn writable_cluster(&mut self, index: u64, whole: bool) -> io::Result<u64> {
+        let entry = self.l2_entry(index)?;
+        if entry & (COPIED | COMPRESSED | ZERO) == COPIED && entry & OFFSET_MASK != 0 {
+            return Ok(entry & OFFSET_MASK);
+        }
+        let cluster_size = self.cluster_size();
+        let mut contents = vec![0; cluster_size as usize];
+        if !whole {
+            self.read_cluster(index, 0, &mut contents)?;
+        }
+        let old = match self.cluster(index)? {
+            // A zero cluster may keep a preallocated one.
+            Cluster::Zero if entry & OFFSET_MASK != 0 => Cluster::Normal(entry & OFFSET_MASK),
+            old => old,
+        };
+        let table = self.writable_l2(index)?;
+        let offset = self.allocate()?;
+        self.write_file(offset, &contents)?;
+        let address = table + 8 * (index % self.l2_entries());
+        self.write_file(address, &(offset | COPIED).to_be_bytes())?;
+        if matches!(old, Cluster::Compressed(..)) {
+            *self.inflated.borrow_mut() = None;
+        }
+        self.release(old)?;
+        Ok(offset)
+    }
+
+    /// Call `f` with every cluster that `len` bytes at `offset` touch: its index, the offset
+    /// within it, the offset within the access, and the length.
+    fn split(
+        &self,
+        offset: u64,
+        len: usize,
+        mut f: impl FnMut(u64, u64, usize, usize) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let cluster_size = self.cluster_size();
+        let mut done = 0;
+        while done < len {
+            let at = offset + done as u64;
+            let within = at % cluster_size;
+            let count = ((cluster_size - within) as usize).min(len - done);
+            f(at / cluster_size, within, done, count)?;
+            done += count;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for Qcow2 {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.split(offset, buf.len(), |index, within, done, count| {
+            self.read_cluster(index, within, &mut buf[done..done + count])
+        })
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the disk is read-only",
+            ));
+        }
+        let cluster_size = self.cluster_size();
+        let mut clusters = Vec::new();
+        self.split(offset, data.len(), |index, within, done, count| {
+            clusters.push((index, within, done, count));
+            Ok(())
+        })?;
+        for (index, within, done, count) in clusters {
+            let host = self.writable_cluster(index, count as u64 == cluster_size)?;
+            self.write_file(host + within, &data[done..done + count])?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// Read `buf.len()` bytes at `offset` of `file`.
+fn read_at(mut file: &File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+/// Read the `len` bytes of a `what` at `offset` of `file`, which is `file_len` bytes long.
+/// Fail if they're more than `max` or past the end of the file, before allocating anything.
+fn read_table(
+    file: &File,
+    file_len: u64,
+    offset: u64,
+    len: u64,
+    max: u64,
+    what: &str,
+) -> io::Result<Vec<u8>> {
+    if len > max {
+        return Err(invalid(format!(
+            "the {} is {} bytes long, more than {}",
+            what, len, max
+        )));
+    }
+    if offset.checked_add(len).is_none_or(|end| end > file_len) {
+        return Err(invalid(format!(
+            "the {} at {:#x} is past the end of the file",
+            what, offset
+        )));
+    }
+    let mut bytes = vec![0; len as usize];
+    read_at(file, offset, &mut bytes)?;
+    Ok(bytes)
+}
+
+/// Return the format of the backing file from the header extensions, which follow the header
+/// in the first cluster.
+fn backing_format(file: &File, mut offset: u64, cluster_bits: u32) -> io::Result<Option<String>> {
+    while offset + 8 <= 1 << cluster_bits {
+        let mut extension = [0; 8];
+        read_at(file, offset, &mut extension)?;
+        let kind = be32(&extension, 0);
+        let len = be32(&extension, 4) as u64;
+        match kind {
+            EXTENSION_END => break,
+            EXTENSION_BACKING_FORMAT if len <= 16 => {
+                let mut format = vec![0; len as usize];
+                read_at(file, offset + 8, &mut format)?;
+                return Ok(Some(String::from_utf8_lossy(&format).into_owned()));
+            }
+            _ => {}
+        }
+        offset += 8 + len.next_multiple_of(8);
+    }
+    Ok(None)
+}
+
+/// Return true if the file at `path` is a qcow2 image.
+pub fn is_qcow2(path: &Path) -> io::Result<bool> {
+    let mut magic = [0; 4];
+    let mut file = File::open(path)?;
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == QCOW2_MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    const CLUSTER: u64 = 1 << CLUSTER_BITS;
+
+    /// Write a raw image of `clusters` clusters with a pattern to `path`, and return it.
+    fn base(path: &Path, clusters: u64) -> Vec<u8> {
+        let data: Vec<u8> = (0..clusters * CLUSTER)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect();
+        fs::write(path, &data).unwrap();
+        data
+    }
+
+    fn read(image: &Qcow2, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        image.read_at(offset, &mut buf).unwrap();
+        buf
+    }
+
+    /// Overwrite the big-endian u32 at `offset` of the file at `path`.
+    fn patch(path: &Path, offset: u64, value: u32) {
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&value.to_be_bytes()).unwrap();
+    }
+
+    fn open_error(path: &Path) -> io::Error {
+        match Qcow2::open(path, true) {
+            Ok(_) => panic!("{} opened", path.display()),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn overlay_round_trip() {
+        let dir = TempDir::new("qcow2-round-trip");
+        let (base_path, overlay_path) = (dir.join("base.img"), dir.join("overlay.qcow2"));
+        let mut expected = base(&base_path, 4);
+
+        let mut image = Qcow2::overlay(&overlay_path, &base_path).unwrap();
+        assert_eq!(image.size(), 4 * CLUSTER);
+        assert_eq!(read(&image, 0, expected.len()), expected);
+        // A write across two clusters, and a write of a whole cluster.
+        let across = vec![0xa5; 300];
+        let whole = vec![0x5a; CLUSTER as usize];
+        image.write_at(CLUSTER - 100, &across).unwrap();
+        image.write_at(3 * CLUSTER, &whole).unwrap();
+        drop(image);
+        expected[CLUSTER as usize - 100..CLUSTER as usize + 200].copy_from_slice(&across);
+        expected[3 * CLUSTER as usize..].copy_from_slice(&whole);
+
+        let image = Qcow2::open(&overlay_path, false).unwrap();
+        assert_eq!(
+            image.backing_file(),
+            Some(fs::canonicalize(&base_path).unwrap().as_path())
+        );
+        assert_eq!(read(&image, 0, expected.len()), expected);
+        // The base keeps its contents.
+        assert_eq!(
+            fs::read(&base_path).unwrap(),
+            base(&dir.join("again.img"), 4)
+        );
+    }
+
+    #[test]
+    fn overlay_over_another_base_is_refused() {
+        let dir = TempDir::new("qcow2-other-base");
+        let (first, second) = (dir.join("first.img"), dir.join("second.img"));
+        base(&first, 1);
+        base(&second, 1);
+        let overlay = dir.join("overlay.qcow2");
+        Qcow2::overlay(&overlay, &first).unwrap();
+        let e = Qcow2::overlay(&overlay, &second).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn oversized_tables_are_refused() {
+        let dir = TempDir::new("qcow2-oversized");
+        let base_path = dir.join("base.img");
+        base(&base_path, 1);
+        for (offset, what) in [
+            (36, "L1 table"),
+            (56, "refcount table"),
+            (16, "backing file name"),
+        ] {
+            let path = dir.join(format!("{}.qcow2", offset));
+            Qcow2::create(&path, &base_path).unwrap();
+            patch(&path, offset, u32::MAX);
+            let e = open_error(&path);
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{}", what);
+            assert!(e.to_string().contains(what), "{}", e);
+        }
+    }
+
+    #[test]
+    fn truncated_images_are_refused() {
+        let dir = TempDir::new("qcow2-truncated");
+        let base_path = dir.join("base.img");
+        base(&base_path, 1);
+        let path = dir.join("overlay.qcow2");
+        Qcow2::create(&path, &base_path).unwrap();
+
+        // The L1 table is the 4th cluster.
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(3 * CLUSTER + 4).unwrap();
+        let e = open_error(&path);
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("past the end"), "{}", e);
+
+        // A table that fits under the limit must still be in the file.
+        file.set_len(4 * CLUSTER).unwrap();
+        patch(&path, 36, 1 << 20);
+        assert_eq!(open_error(&path).kind(), io::ErrorKind::InvalidData);
+
+        file.set_len(40).unwrap();
+        assert_eq!(open_error(&path).kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,613 @@
+//! The queue module implements the split virtqueue: the driver makes chains of descriptors
+//! available in the available ring, and the device hands them back in the used ring once it
+//! has read or written their buffers. A descriptor may also point to a table of descriptors
+//! (VIRTIO_F_INDIRECT_DESC), and the two sides may tell each other when they want to be
+//! notified (VIRTIO_F_EVENT_IDX).
+
+use std::io;
+
+use crate::bus::Memory;
+use crate::exception::Exception;
+use crate::snapshot::{Reader, Writer};
+
+const VRING_DESC_SIZE: u64 = 16;
+/// The alignment of the used ring of a legacy queue until the driver sets it.
+const LEGACY_ALIGN: u32 = 4096;
+
+// Descriptor flags.
+/// The chain continues with the descriptor in `next`.
+const VRING_DESC_F_NEXT: u64 = 1;
+/// The device writes the buffer instead of reading it.
+const VRING_DESC_F_WRITE: u64 = 2;
+/// The buffer is a table of descriptors.
+const VRING_DESC_F_INDIRECT: u64 = 4;
+
+/// The available ring flag that asks the device not to interrupt when it uses buffers.
+const VRING_AVAIL_F_NO_INTERRUPT: u64 = 1;
+
+/// Load the `size`-bit value at `addr`, which the driver gave and may be outside the memory.
+fn load(memory: &Memory, addr: u64, size: usize) -> Option<u64> {
+    let mut bytes = [0; 8];
+    memory.load_bytes(addr, &mut bytes[..size / 8]).ok()?;
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// Store the low `size` bits of `value` at `addr`, unless it's outside the memory.
+fn store(memory: &mut Memory, addr: u64, size: usize, value: u64) {
+    memory
+        .store_bytes(addr, &value.to_le_bytes()[..size / 8])
+        .ok();
+}
+
+/// A virtqueue, as the driver set it up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Queue {
+    /// The number of descriptors.
+    pub num: u32,
+    pub ready: bool,
+    /// The addresses of the descriptor table, the available ring and the used ring.
+    pub desc: u64,
+    pub driver: u64,
+    pub device: u64,
+    /// The alignment of the used ring and the page frame number of a legacy queue.
+    pub align: u32,
+    pub pfn: u32,
+    /// The index of the next available ring entry to process.
+    last_avail: u16,
+    /// The index of the next used ring entry to fill.
+    used_idx: u16,
+}
+
+/// A buffer in guest memory.
+#[derive(Debug, Clone, Copy)]
+struct Buffer {
+    addr: u64,
+    len: u64,
+}
+
+/// A descriptor chain the driver made available: buffers the device reads, followed by buffers
+/// it writes.
+#[derive(Debug)]
+pub struct Chain {
+    /// The first descriptor, which identifies the chain in the used ring.
+    pub head: u16,
+    readable: Vec<Buffer>,
+    writable: Vec<Buffer>,
+}
+
+impl Chain {
+    /// Return the contents of the readable buffers. Together they can't be larger than the
+    /// memory, however many times the driver lists the same one.
+    pub fn read(&self, memory: &Memory) -> Result<Vec<u8>, Exception> {
+        let total = self
+            .readable
+            .iter()
+            .try_fold(0u64, |total, buffer| total.checked_add(buffer.len));
+        match total {
+            Some(total) if total <= memory.size() => {}
+            _ => return Err(Exception::LoadAccessFault),
+        }
+        let mut bytes = Vec::new();
+        for buffer in &self.readable {
+            let start = bytes.len();
+            bytes.resize(start + buffer.len as usize, 0);
+            memory.load_bytes(buffer.addr, &mut bytes[start..])?;
+        }
+        Ok(bytes)
+    }
+
+    /// Return the total length of the writable buffers.
+    pub fn writable_len(&self) -> u64 {
+        self.writable.iter().map(|buffer| buffer.len).sum()
+    }
+
+    /// Write `bytes` to the writable buffers, `offset` bytes into them.
+    pub fn write(&self, memory: &mut Memory, offset: u64, bytes: &[u8]) -> Result<(), Exception> {
+        let mut offset = offset;
+        let mut bytes = bytes;
+        for buffer in &self.writable {
+            if bytes.is_empty() {
+                break;
+            }
+            if offset >= buffer.len {
+                offset -= buffer.len;
+                continue;
+            }
+            let count = (buffer.len - offset).min(bytes.len() as u64) as usize;
+            memory.store_bytes(buffer.addr.wrapping_add(offset), &bytes[..count])?;
+            bytes = &bytes[count..];
+            offset = 0;
+        }
+        match bytes.is_empty() {
+            true => Ok(()),
+            false => Err(Exception::StoreAMOAccessFault),
+        }
+    }
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Queue {
+    pub fn new() -> Self {
+        Self {
+            num: 0,
+            ready: false,
+            desc: 0,
+            driver: 0,
+            device: 0,
+            align: LEGACY_ALIGN,
+            pfn: 0,
+            last_avail: 0,
+            used_idx: 0,
+        }
+    }
+
+    /// Place the parts of a legacy queue one after the other from page `pfn`: the descriptor
+    /// table, the available ring, and the used ring at the next multiple of the alignment.
+    pub fn place(&mut self, pfn: u32, page_size: u32) {
+        let num = self.num as u64;
+        let align = self.align.max(1) as u64;
+        self.pfn = pfn;
+        self.ready = pfn != 0;
+        self.desc = pfn as u64 * page_size as u64;
+        self.driver = self.desc + num * VRING_DESC_SIZE;
+        // flags, idx, ring[num] and used_event.
+        self.device = (self.driver + 6 + 2 * num).next_multiple_of(align);
+    }
+
+    /// Set the low or the high half of `address` to `value`.
+    pub fn set_half(address: &mut u64, high: bool, value: u32) {
+        *address = if high {
+            (*address & 0xffff_ffff) | (value as u64) << 32
+        } else {
+            (*address & !0xffff_ffff) | value as u64
+        };
+    }
+
+    /// Return the index of the next used ring entry to fill.
+    pub fn used_idx(&self) -> u16 {
+        self.used_idx
+    }
+
+    /// Return true if the driver set the queue up, so that the device may use it.
+    pub fn usable(&self) -> bool {
+        self.ready && self.num != 0
+    }
+
+    /// Hand every chain the driver made available to `f`, which reads and writes its buffers
+    /// and returns the number of bytes it wrote, and give them back. Return true if the driver
+    /// wants an interrupt.
+    pub fn serve(
+        &mut self,
+        memory: &mut Memory,
+        event_idx: bool,
+        mut f: impl FnMut(&mut Memory, &Chain) -> u32,
+    ) -> bool {
+        if !self.usable() {
+            return false;
+        }
+        let old = self.used_idx;
+        while let Some(chain) = self.pop(memory, event_idx) {
+            let len = f(memory, &chain);
+            self.push(memory, chain.head, len);
+        }
+        self.should_interrupt(memory, old, event_idx)
+    }
+
+    /// Return the next chain the driver made available, or `None` if there's none. A chain
+    /// that can't be followed, e.g. because it loops, is returned without buffers. With
+    /// `event_idx`, the driver is asked to notify again once it makes the next chain available.
+    pub fn pop(&mut self, memory: &mut Memory, event_idx: bool) -> Option<Chain> {
+        let num = self.num as u64;
+        // struct virtq_avail {
+        //   le16 flags;
+        //   le16 idx;
+        //   le16 ring[num];
+        //   le16 used_event;
+        // };
+        let idx = load(memory, self.driver.wrapping_add(2), 16)? as u16;
+        if idx == self.last_avail {
+            if event_idx {
+                // avail_event follows the used ring.
+                let avail_event = self.device.wrapping_add(4 + 8 * num);
+                store(memory, avail_event, 16, idx as u64);
+            }
+            return None;
+        }
+        let entry = self
+            .driver
+            .wrapping_add(4 + 2 * (self.last_avail as u64 % num));
+        let head = load(memory, entry, 16)? as u16;
+        self.last_avail = self.last_avail.wrapping_add(1);
+        Some(self.chain(memory, head).unwrap_or(Chain {
+            head,
+            readable: Vec::new(),
+            writable: Vec::new(),
+        }))
+    }
+
+    /// Follow the chain that starts at descriptor `head`, through an indirect table if there's
+    /// one.
+    fn chain(&self, memory: &Memory, head: u16) -> Option<Chain> {
+        let mut chain = Chain {
+            head,
+            readable: Vec::new(),
+            writable: Vec::new(),
+        };
+        let (mut table, mut size) = (self.desc, self.num as u64);
+        let mut indirect = false;
+        let mut index = head as u64;
+        let mut count = 0;
+        loop {
+            // A chain is at most as long as its table, or it loops.
+            count += 1;
+            if index >= size || count > size {
+                return None;
+            }
+            // struct virtq_desc {
+            //   le64 addr;
+            //   le32 len;
+            //   le16 flags;
+            //   le16 next;
+            // };
+            let desc = table.wrapping_add(VRING_DESC_SIZE * index);
+            let addr = load(memory, desc, 64)?;
+            let len = load(memory, desc.wrapping_add(8), 32)?;
+            let flags = load(memory, desc.wrapping_add(12), 16)?;
+            let next = load(memory, desc.wrapping_add(14), 16)?;
+            if flags & VRING_DESC_F_INDIRECT != 0 {
+                // A table can't be nested or followed by more descriptors.
+                if indirect || flags & VRING_DESC_F_NEXT != 0 || len % VRING_DESC_SIZE != 0 {
+                    return None;
+                }
+                table = addr;
+                size = len / VRING_DESC_SIZE;
+                indirect = true;
+                index = 0;
+                count = 0;
+                continue;
+            }
+            let buffer = Buffer { addr, len };
+            if flags & VRING_DESC_F_WRITE != 0 {
+                chain.writable.push(buffer);
+            } else if chain.writable.is_empty() {
+                chain.readable.push(buffer);
+            } else {
+                // Readable buffers must come first.
+                return None;
+            }
+            if flags & VRING_DESC_F_NEXT == 0 {
+                return Some(chain);
+            }
+            index = next;
+        }
+    }
+
+    /// Hand the chain starting at `head` back to the driver, having written `len` bytes to its
+    /// buffers.
+    pub fn push(&mut self, memory: &mut Memory, head: u16, len: u32) {
+        // struct virtq_used {
+        //   le16 flags;
+        //   le16 idx;
+        //   struct virtq_used_elem { le32 id; le32 len; } ring[num];
+        //   le16 avail_event;
+        // };
+        let elem = self
+            .device
+            .wrapping_add(4 + 8 * (self.used_idx as u64 % self.num as u64));
+        store(memory, elem, 32, head as u64);
+        store(memory, elem.wrapping_add(4), 32, len as u64);
+        self.used_idx = self.used_idx.wrapping_add(1);
+        store(
+            memory,
+            self.device.wrapping_add(2),
+            16,
+            self.used_idx as u64,
+        );
+    }
+
+    /// Return true if the driver wants an interrupt for the chains used since the used index
+    /// was `old`: with `event_idx`, if used_event is among them, and otherwise unless the
+    /// driver suppressed interrupts.
+    pub fn should_interrupt(&self, memory: &Memory, old: u16, event_idx: bool) -> bool {
+        if self.used_idx == old {
+            return false;
+        }
+        if event_idx {
+            let used_event = self.driver.wrapping_add(4 + 2 * self.num as u64);
+            return match load(memory, used_event, 16) {
+                Some(event) => {
+                    self.used_idx.wrapping_sub(event as u16).wrapping_sub(1)
+                        < self.used_idx.wrapping_sub(old)
+                }
+                None => true,
+            };
+        }
+        match load(memory, self.driver, 16) {
+            Some(flags) => flags & VRING_AVAIL_F_NO_INTERRUPT == 0,
+            None => true,
+        }
+    }
+
+    pub fn save(&self, w: &mut Writer) {
+        w.put_u32(self.num);
+        w.put_bool(self.ready);
+        w.put_u64(self.desc);
+        w.put_u64(self.driver);
+        w.put_u64(self.device);
+        w.put_u32(self.align);
+        w.put_u32(self.pfn);
+        w.put_u32(self.last_avail as u32);
+        w.put_u32(self.used_idx as u32);
+    }
+
+    pub fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.num = r.get_u32()?;
+        self.ready = r.get_bool()?;
+        self.desc = r.get_u64()?;
+        self.driver = r.get_u64()?;
+        self.device = r.get_u64()?;
+        self.align = r.get_u32()?;
+        self.pfn = r.get_u32()?;
+        self.last_avail = r.get_u32()? as u16;
+        self.used_idx = r.get_u32()? as u16;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::MEMORY_BASE;
+
+    const NUM: u32 = 8;
+    const DESC: u64 = MEMORY_BASE;
+    const DRIVER: u64 = MEMORY_BASE + 0x1000;
+    const DEVICE: u64 = MEMORY_BASE + 0x2000;
+    const TABLE: u64 = MEMORY_BASE + 0x3000;
+    const DATA: u64 = MEMORY_BASE + 0x4000;
+
+    fn setup() -> (Queue, Memory) {
+        let mut queue = Queue::new();
+        queue.num = NUM;
+        queue.ready = true;
+        queue.desc = DESC;
+        queue.driver = DRIVER;
+        queue.device = DEVICE;
+        (queue, Memory::new(0x10000))
+    }
+
+    fn descriptor(memory: &mut Memory, table: u64, index: u64, addr: u64, len: u32, flags: u64) {
+        let desc = table + VRING_DESC_SIZE * index;
+        store(memory, desc, 64, addr);
+        store(memory, desc + 8, 32, len as u64);
+        store(memory, desc + 12, 16, flags);
+        store(memory, desc + 14, 16, index + 1);
+    }
+
+    /// Make the chain starting at `head` available.
+    fn offer(memory: &mut Memory, head: u16) {
+        let idx = load(memory, DRIVER + 2, 16).unwrap();
+        store(memory, DRIVER + 4 + 2 * (idx % NUM as u64), 16, head as u64);
+        store(memory, DRIVER + 2, 16, (idx as u16).wrapping_add(1) as u64);
+    }
+
+    #[test]
+    fn readable_then_writable_buffers() {
+        let (mut queue, mut memory) = setup();
+        memory.store_bytes(DATA, b"request").unwrap();
+        descriptor(&mut memory, DESC, 0, DATA, 3, VRING_DESC_F_NEXT);
+        descriptor(&mut memory, DESC, 1, DATA + 3, 4, VRING_DESC_F_NEXT);
+        descriptor(
+            &mut memory,
+            DESC,
+            2,
+            DATA + 0x100,
+            2,
+            VRING_DESC_F_WRITE | VRING_DESC_F_NEXT,
+        );
+        descriptor(&mut memory, DESC, 3, DATA + 0x200, 4, VRING_DESC_F_WRITE);
+        offer(&mut memory, 0);
+
+        let chain = queue.pop(&mut memory, false).unwrap();
+        assert_eq!(chain.head, 0);
+        assert_eq!(chain.read(&memory).unwrap(), b"request");
+        assert_eq!(chain.writable_len(), 6);
+        chain.write(&mut memory, 1, b"reply").unwrap();
+        let mut reply = [0; 6];
+        memory.load_bytes(DATA + 0x100, &mut reply[..2]).unwrap();
+        memory.load_bytes(DATA + 0x200, &mut reply[2..]).unwrap();
+        assert_eq!(&reply, b"\0reply");
+        assert_eq!(
+            chain.write(&mut memory, 0, b"too long"),
+            Err(Exception::StoreAMOAccessFault)
+        );
+
+        queue.push(&mut memory, chain.head, 6);
+        assert_eq!(load(&memory, DEVICE + 2, 16), Some(1));
+        assert_eq!(load(&memory, DEVICE + 4, 32), Some(0));
+        assert_eq!(load(&memory, DEVICE + 8, 32), Some(6));
+        assert!(queue.should_interrupt(&memory, 0, false));
+        store(&mut memory, DRIVER, 16, VRING_AVAIL_F_NO_INTERRUPT);
+        assert!(!queue.should_interrupt(&memory, 0, false));
+        assert!(!queue.should_interrupt(&memory, 1, false));
+        assert!(queue.pop(&mut memory, false).is_none());
+    }
+
+    /// Return the chain that starts at descriptor 0, with or without buffers.
+    fn resolve(queue: &mut Queue, memory: &mut Memory) -> Chain {
+        offer(memory, 0);
+        let chain = queue.pop(memory, false).unwrap();
+        assert_eq!(chain.head, 0);
+        chain
+    }
+
+    fn is_rejected(chain: &Chain) -> bool {
+        chain.readable.is_empty() && chain.writable.is_empty()
+    }
+
+    #[test]
+    fn loops_are_detected() {
+        let (mut queue, mut memory) = setup();
+        descriptor(&mut memory, DESC, 0, DATA, 16, VRING_DESC_F_NEXT);
+        descriptor(&mut memory, DESC, 1, DATA, 16, VRING_DESC_F_NEXT);
+        store(&mut memory, DESC + VRING_DESC_SIZE + 14, 16, 0);
+        assert!(is_rejected(&resolve(&mut queue, &mut memory)));
+
+        // The same in an indirect table.
+        descriptor(&mut memory, DESC, 0, TABLE, 32, VRING_DESC_F_INDIRECT);
+        descriptor(&mut memory, TABLE, 0, DATA, 16, VRING_DESC_F_NEXT);
+        descriptor(&mut memory, TABLE, 1, DATA, 16, VRING_DESC_F_NEXT);
+        store(&mut memory, TABLE + VRING_DESC_SIZE + 14, 16, 0);
+        assert!(is_rejected(&resolve(&mut queue, &mut memory)));
+
+        // A next descriptor past the end of the table.
+        descriptor(&mut memory, DESC, 0, DATA, 16, VRING_DESC_F_NEXT);
+        store(&mut memory, DESC + 14, 16, NUM as u64);
+        assert!(is_rejected(&resolve(&mut queue, &mut memory)));
+    }
+
+    #[test]
+    fn indirect_tables() {
+        let (mut queue, mut memory) = setup();
+        descriptor(&mut memory, DESC, 0, TABLE, 32, VRING_DESC_F_INDIRECT);
+        descriptor(&mut memory, TABLE, 0, DATA, 5, VRING_DESC_F_NEXT);
+        descriptor(&mut memory, TABLE, 1, DATA + 0x100, 7, VRING_DESC_F_WRITE);
+        let chain = resolve(&mut queue, &mut memory);
+        assert_eq!(chain.read(&memory).unwrap().len(), 5);
+        assert_eq!(chain.writable_len(), 7);
+
+        // With NEXT.
+        descriptor(
+            &mut memory,
+            DESC,
+            0,
+            TABLE,
+            32,
+            VRING_DESC_F_INDIRECT | VRING_DESC_F_NEXT,
+        );
+        assert!(is_rejected(&resolve(&mut queue, &mut memory)));
+
+        // Of a length that isn't a multiple of a descriptor.
+        descriptor(&mut memory, DESC, 0, TABLE, 24, VRING_DESC_F_INDIRECT);
+        assert!(is_rejected(&resolve(&mut queue, &mut memory)));
+
+        // Nested.
+        descriptor(&mut memory, DESC, 0, TABLE, 32, VRING_DESC_F_INDIRECT);
+        descriptor(
+            &mut memory,
+            TABLE,
+            1,
+            TABLE + 0x100,
+            16,
+            VRING_DESC_F_INDIRECT,
+        );
+        descriptor(&mut memory, TABLE + 0x100, 0, DATA, 5, 0);
+        assert!(is_rejected(&resolve(&mut queue, &mut memory)));
+
+        // Outside the memory.
+        descriptor(
+            &mut memory,
+            DESC,
+            0,
+            MEMORY_BASE - 0x1000,
+            32,
+            VRING_DESC_F_INDIRECT,
+        );
+        assert!(is_rejected(&resolve(&mut queue, &mut memory)));
+    }
+
+    #[test]
+    fn readable_buffers_come_first() {
+        let (mut queue, mut memory) = setup();
+        descriptor(
+            &mut memory,
+            DESC,
+            0,
+            DATA,
+            4,
+            VRING_DESC_F_WRITE | VRING_DESC_F_NEXT,
+        );
+        descriptor(&mut memory, DESC, 1, DATA, 4, 0);
+        assert!(is_rejected(&resolve(&mut queue, &mut memory)));
+    }
+
+    #[test]
+    fn chains_are_no_longer_than_the_memory() {
+        let (mut queue, mut memory) = setup();
+        let size = memory.size() as u32;
+        // Every buffer fits, but together they are larger than the memory.
+        for index in 0..NUM as u64 {
+            descriptor(
+                &mut memory,
+                DESC,
+                index,
+                MEMORY_BASE,
+                size / 4,
+                VRING_DESC_F_NEXT,
+            );
+        }
+        store(&mut memory, DESC + VRING_DESC_SIZE * 7 + 12, 16, 0);
+        let chain = resolve(&mut queue, &mut memory);
+        assert_eq!(chain.readable.len(), NUM as usize);
+        assert_eq!(chain.read(&memory), Err(Exception::LoadAccessFault));
+
+        // Enough to fill it exactly.
+        store(&mut memory, DESC + VRING_DESC_SIZE * 3 + 12, 16, 0);
+        let chain = resolve(&mut queue, &mut memory);
+        assert_eq!(chain.read(&memory).unwrap().len(), size as usize);
+
+        // A buffer outside the memory.
+        descriptor(&mut memory, DESC, 0, MEMORY_BASE + 0xfff0, 32, 0);
+        let chain = resolve(&mut queue, &mut memory);
+        assert_eq!(chain.read(&memory), Err(Exception::LoadAccessFault));
+    }
+
+    #[test]
+    fn rings_wrap_around() {
+        let (mut queue, mut memory) = setup();
+        queue.last_avail = 0xfffe;
+        queue.used_idx = 0xfffe;
+        store(&mut memory, DRIVER + 2, 16, 0xfffe);
+        for head in 0..3 {
+            descriptor(&mut memory, DESC, head, DATA, 4, VRING_DESC_F_WRITE);
+            offer(&mut memory, head as u16);
+        }
+        assert_eq!(load(&memory, DRIVER + 2, 16), Some(1));
+
+        // The driver wants an interrupt once the second chain is used.
+        store(&mut memory, DRIVER + 4 + 2 * NUM as u64, 16, 0xffff);
+        let old = queue.used_idx();
+        let mut heads = Vec::new();
+        let interrupt = queue.serve(&mut memory, true, |_, chain| {
+            heads.push(chain.head);
+            chain.head as u32 + 10
+        });
+        assert!(interrupt);
+        assert_eq!(heads, [0, 1, 2]);
+        assert_eq!(queue.used_idx(), 1);
+        assert_eq!(load(&memory, DEVICE + 2, 16), Some(1));
+        // Entries 0xfffe, 0xffff and 0 of the ring.
+        for (slot, head) in [(6, 0), (7, 1), (0, 2)] {
+            let elem = DEVICE + 4 + 8 * slot;
+            assert_eq!(load(&memory, elem, 32), Some(head));
+            assert_eq!(load(&memory, elem + 4, 32), Some(head + 10));
+        }
+        // The next notification is wanted for the next chain.
+        assert_eq!(load(&memory, DEVICE + 4 + 8 * NUM as u64, 16), Some(1));
+
+        // Not if used_event is outside the chains used since `old`.
+        assert!(queue.should_interrupt(&memory, old, true));
+        store(&mut memory, DRIVER + 4 + 2 * NUM as u64, 16, 1);
+        assert!(!queue.should_interrupt(&memory, old, true));
+        store(&mut memory, DRIVER + 4 + 2 * NUM as u64, 16, 0xfffd);
+        assert!(!queue.should_interrupt(&memory, old, true));
+        store(&mut memory, DRIVER + 4 + 2 * NUM as u64, 16, 0);
+        assert!(queue.should_interrupt(&memory, old, true));
+        assert!(!queue.should_interrupt(&memory, 1, true));
+    }
+}
@@ -0,0 +1,197 @@
+//! The rng module is the virtio entropy device, which fills the buffers the driver gives it
+//! with random bytes. Linux waits for entropy while it boots, e.g. to seed its own generator,
+//! and without a source it may stall for a long time.
+//!
+//! The bytes come from the host, or from a deterministic generator with a seed, so that runs
+//! that must repeat, such as recorded, replayed or debugged ones, see the same bytes every time.
+
+use std::fs::File;
+use std::io::{self, Read};
+
+use super::queue::Queue;
+use super::{VirtioDevice, VIRTIO_F_EVENT_IDX};
+use crate::bus::Memory;
+use crate::snapshot::{Reader, Snapshot, Writer};
+
+/// The most bytes given to the driver at once. A larger buffer is filled up to it.
+const MAX_REQUEST: u64 = 64 << 10;
+
+/// Where the bytes of an entropy device come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RngBackend {
+    /// The host's /dev/urandom.
+    #[default]
+    Host,
+    /// A deterministic generator, seeded with the value.
+    Seeded(u64),
+}
+
+/// A virtio entropy device.
+pub struct Rng {
+    /// /dev/urandom, unless the bytes come from the generator.
+    host: Option<File>,
+    /// The state of the generator, splitmix64.
+    state: u64,
+}
+
+impl Rng {
+    /// Create an entropy device whose bytes come from `backend`.
+    pub fn open(backend: RngBackend) -> io::Result<Self> {
+        Ok(match backend {
+            RngBackend::Host => Self {
+                host: Some(File::open("/dev/urandom")?),
+                state: 0,
+            },
+            RngBackend::Seeded(seed) => Self {
+                host: None,
+                state: seed,
+            },
+        })
+    }
+
+    /// Return the next value of the generator.
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Fill `bytes` with entropy.
+    fn fill(&mut self, bytes: &mut [u8]) -> io::Result<()> {
+        if let Some(host) = &mut self.host {
+            return host.read_exact(bytes);
+        }
+        for chunk in bytes.chunks_mut(8) {
+            let value = self.next().to_le_bytes();
+            chunk.copy_from_slice(&value[..chunk.len()]);
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for Rng {
+    fn device_id(&self) -> u32 {
+        4
+    }
+
+    /// An entropy device has a requestq.
+    fn queues(&self) -> usize {
+        1
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    /// There's no config space.
+    fn config(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn process(
+        &mut self,
+        memory: &mut Memory,
+        queues: &mut [Queue],
+        notified: u32,
+        features: u64,
+    ) -> bool {
+        if notified & 1 == 0 {
+            return false;
+        }
+        let event_idx = features & VIRTIO_F_EVENT_IDX != 0;
+        queues[0].serve(memory, event_idx, |memory, chain| {
+            let mut bytes = vec![0; chain.writable_len().min(MAX_REQUEST) as usize];
+            // A buffer that can't be filled is given back empty.
+            if self.fill(&mut bytes).is_err() || chain.write(memory, 0, &bytes).is_err() {
+                return 0;
+            }
+            bytes.len() as u32
+        })
+    }
+}
+
+/// The state of the generator is saved, so that a restored machine goes on with the same
+/// bytes.
+impl Snapshot for Rng {
+    fn save(&self, w: &mut Writer) -> io::Result<()> {
+        w.put_u64(self.state);
+        Ok(())
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.state = r.get_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::MEMORY_BASE;
+
+    #[test]
+    fn seeded_bytes() {
+        let mut rng = Rng::open(RngBackend::Seeded(0)).unwrap();
+        // The first values of splitmix64 seeded with 0.
+        let mut bytes = [0; 20];
+        rng.fill(&mut bytes).unwrap();
+        assert_eq!(bytes[..8], 0xe220a8397b1dcdaf_u64.to_le_bytes());
+        assert_eq!(bytes[8..16], 0x6e789e6aa1b965f4_u64.to_le_bytes());
+        assert_eq!(bytes[16..], 0x06c45d188009454f_u64.to_le_bytes()[..4]);
+
+        // A restored generator goes on with the same bytes.
+        let mut w = Writer::new();
+        rng.save(&mut w).unwrap();
+        let mut restored = Rng::open(RngBackend::Seeded(1)).unwrap();
+        restored
+            .restore(&mut Reader::new(w.as_bytes()).unwrap())
+            .unwrap();
+        let (mut a, mut b) = ([0; 8], [0; 8]);
+        rng.fill(&mut a).unwrap();
+        restored.fill(&mut b).unwrap();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn requests_are_filled() {
+        let mut rng = Rng::open(RngBackend::Seeded(0)).unwrap();
+        let mut memory = Memory::new(0x40000);
+        let mut queues = [Queue::new()];
+        let queue = &mut queues[0];
+        queue.num = 4;
+        queue.ready = true;
+        queue.desc = MEMORY_BASE;
+        queue.driver = MEMORY_BASE + 0x100;
+        queue.device = MEMORY_BASE + 0x200;
+        // A writable buffer of 16 bytes, and one larger than a request.
+        let buffers = [(MEMORY_BASE + 0x1000, 16), (MEMORY_BASE + 0x2000, 0x20000)];
+        for (index, (addr, len)) in buffers.iter().enumerate() {
+            let desc = MEMORY_BASE + 16 * index as u64;
+            memory.store(desc, 64, *addr).unwrap();
+            memory.store(desc + 8, 32, *len).unwrap();
+            memory.store(desc + 12, 16, 2).unwrap();
+            memory
+                .store(MEMORY_BASE + 0x104 + 2 * index as u64, 16, index as u64)
+                .unwrap();
+        }
+        memory.store(MEMORY_BASE + 0x102, 16, 2).unwrap();
+
+        assert!(!rng.process(&mut memory, &mut queues, 0, 0));
+        assert!(rng.process(&mut memory, &mut queues, 1, 0));
+        assert_eq!(memory.load(MEMORY_BASE + 0x202, 16), Ok(2));
+        assert_eq!(memory.load(MEMORY_BASE + 0x208, 32), Ok(16));
+        assert_eq!(memory.load(MEMORY_BASE + 0x210, 32), Ok(MAX_REQUEST));
+        assert_eq!(
+            memory.load(MEMORY_BASE + 0x1000, 64),
+            Ok(0xe220a8397b1dcdaf)
+        );
+        // The rest of a large buffer is left alone.
+        assert_ne!(
+            memory.load(MEMORY_BASE + 0x2000 + MAX_REQUEST - 8, 64),
+            Ok(0)
+        );
+        assert_eq!(memory.load(MEMORY_BASE + 0x2000 + MAX_REQUEST, 64), Ok(0));
+    }
+}
@@ -0,0 +1,231 @@
+//! The storage module holds the contents of virtio disks: in memory, in a host file that is read
+//! and written in place, or in memory over a host file that is only read.
+
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::qcow2::{is_qcow2, Qcow2};
+use crate::snapshot::invalid;
+
+/// The granularity at which an overlay keeps written bytes.
+const BLOCK_SIZE: u64 = 4096;
+
+/// The contents of a disk. Accesses are within `0..size()`; the device checks them.
+pub trait Storage: Send {
+    /// Return the size of the contents in bytes.
+    fn size(&self) -> u64;
+
+    /// Return true if the contents can't be written.
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Read `buf.len()` bytes at `offset`.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Write `data` at `offset`.
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    /// Make the writes so far durable.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Return all of the contents, for a snapshot.
+    fn contents(&self) -> io::Result<Vec<u8>> {
+        let mut contents = vec![0; self.size() as usize];
+        self.read_at(0, &mut contents)?;
+        Ok(contents)
+    }
+
+    /// Return the size of the largest contents that `restore` takes.
+    fn max_restore(&self) -> u64 {
+        self.size()
+    }
+
+    /// Replace the contents with those of a snapshot, writing only what differs.
+    fn restore(&mut self, contents: Vec<u8>) -> io::Result<()> {
+        if contents.len() as u64 != self.size() {
+            return Err(invalid("disk size differs from the snapshot"));
+        }
+        let mut current = vec![0; BLOCK_SIZE as usize];
+        for (n, chunk) in contents.chunks(BLOCK_SIZE as usize).enumerate() {
+            let offset = n as u64 * BLOCK_SIZE;
+            let current = &mut current[..chunk.len()];
+            self.read_at(offset, current)?;
+            if current != chunk {
+                if self.read_only() {
+                    return Err(invalid("read-only disk differs from the snapshot"));
+                }
+                self.write_at(offset, chunk)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Open the disk image at `path`, a qcow2 image or a raw one, for reading only if `read_only`.
+pub fn open_image(path: &Path, read_only: bool) -> io::Result<Box<dyn Storage>> {
+    if is_qcow2(path)? {
+        Ok(Box::new(Qcow2::open(path, read_only)?))
+    } else {
+        Ok(Box::new(FileStorage::open(path, read_only)?))
+    }
+}
+
+/// Contents kept in memory, which are lost on exit.
+impl Storage for Vec<u8> {
+    fn size(&self) -> u64 {
+        self.len() as u64
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        buf.copy_from_slice(&self[offset as usize..][..buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self[offset as usize..][..data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn contents(&self) -> io::Result<Vec<u8>> {
+        Ok(self.clone())
+    }
+
+    fn max_restore(&self) -> u64 {
+        u64::MAX
+    }
+
+    /// Take the contents of the snapshot whatever their size, so that a machine can be restored
+    /// without the disk it was saved with.
+    fn restore(&mut self, contents: Vec<u8>) -> io::Result<()> {
+        *self = contents;
+        Ok(())
+    }
+}
+
+/// A host file read and written in place, so that writes outlive the run.
+pub struct FileStorage {
+    file: File,
+    size: u64,
+    read_only: bool,
+}
+
+impl FileStorage {
+    /// Open the file at `path`, for reading only if `read_only` is set.
+    pub fn open(path: &Path, read_only: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(!read_only).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            file,
+            size,
+            read_only,
+        })
+    }
+}
+
+impl Storage for FileStorage {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the disk is read-only",
+            ));
+        }
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// Writes kept in memory over contents that are only read, and discarded on exit.
+pub struct MemoryOverlay {
+    base: Box<dyn Storage>,
+    /// The blocks written so far, by their index.
+    blocks: BTreeMap<u64, Vec<u8>>,
+}
+
+impl MemoryOverlay {
+    pub fn new(base: Box<dyn Storage>) -> Self {
+        Self {
+            base,
+            blocks: BTreeMap::new(),
+        }
+    }
+
+    /// Call `f` with every block that `len` bytes at `offset` touch: its index, the offset
+    /// within it, and the offset within the access.
+    fn split(offset: u64, len: usize, mut f: impl FnMut(u64, usize, usize, usize)) {
+        let mut done = 0;
+        while done < len {
+            let at = offset + done as u64;
+            let within = (at % BLOCK_SIZE) as usize;
+            let count = (BLOCK_SIZE as usize - within).min(len - done);
+            f(at / BLOCK_SIZE, within, done, count);
+            done += count;
+        }
+    }
+}
+
+impl Storage for MemoryOverlay {
+    fn size(&self) -> u64 {
+        self.base.size()
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.base.read_at(offset, buf)?;
+        Self::split(offset, buf.len(), |index, within, done, count| {
+            if let Some(block) = self.blocks.get(&index) {
+                buf[done..done + count].copy_from_slice(&block[within..within + count]);
+            }
+        });
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut result = Ok(());
+        let size = self.size();
+        let Self { base, blocks } = self;
+        Self::split(offset, data.len(), |index, within, done, count| {
+            if result.is_err() {
+                return;
+            }
+            let block = match blocks.entry(index) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    // The last block may be partial.
+                    let start = index * BLOCK_SIZE;
+                    let mut block = vec![0; (size - start).min(BLOCK_SIZE) as usize];
+                    if let Err(e) = base.read_at(start, &mut block) {
+                        result = Err(e);
+                        return;
+                    }
+                    entry.insert(block)
+                }
+            };
+            block[within..within + count].copy_from_slice(&data[done..done + count]);
+        });
+        result
+    }
+}
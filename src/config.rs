@@ -0,0 +1,1292 @@
+//! The config module describes a machine and a run: the machine profile, RAM, boot images, disks,
+//! network cards, UART, virtio console and entropy device, and the tracing, debugging, snapshot
+//! and replay options. A `Config` is read from a TOML file, from the command line, or both, and validated
+//! before a machine is built from it.
+//!
+//! ```toml
+//! profile = "virt"
+//! ram = "256M"
+//! harts = 3
+//! timebase = 10_000_000
+//! virtio = "modern"
+//!
+//! [boot]
+//! kernel = "xv6-kernel.bin"
+//!
+//! [[disk]]
+//! path = "xv6-fs.img"
+//!
+//! [[nic]]
+//! backend = "user"
+//! forward = ["2222:22"]
+//!
+//! [uart]
+//! backend = "file"
+//! path = "console.log"
+//!
+//! [console]
+//! backend = "stdio"
+//! ports = ["org.honga.agent:agent.sock"]
+//!
+//! [rng]
+//! backend = "host"
+//! ```
+//!
+//! Relative paths in a file are relative to the directory of the file.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::bus::{
+    ConsolePort, NetBackend, RngBackend, UartBackend, VirtioTransport, CPU_FREQUENCY,
+    MAX_CONSOLE_PORTS, MEMORY_SIZE, TIMEBASE_FREQUENCY, VIRTIO_SLOTS,
+};
+use crate::cpu::QUANTUM;
+use crate::isa::Isa;
+use crate::jit::{self, Engine};
+use crate::reverse::CHECKPOINT_INTERVAL;
+use crate::toml::{self, Entry, Table, Value};
+
+/// The largest number of harts.
+pub const MAX_HARTS: usize = 64;
+/// The smallest and the largest RAM size.
+const MIN_RAM: u64 = 1 << 20;
+const MAX_RAM: u64 = 64 << 30;
+
+pub const USAGE: &str = "Usage: honga [options] [<kernel> [<disk>]]";
+
+pub const HELP: &str = "\
+Usage: honga [options] [<kernel> [<disk>]]
+
+Machine:
+  --config <file>             Read the machine description from a TOML file. Options given
+                              on the command line override it.
+  --profile <virt|bare>       The machine to emulate [default: virt]
+  --ram <size>                RAM size, e.g. 256M or 1G [default: 128M]
+  --isa, --cpu <string>       The ISA of the hart, e.g. rv64ima_zicsr [default: all
+                              extensions honga implements]. The guest can only turn M
+                              and A off and on through misa
+  --harts <n>                 The number of harts [default: 1]
+  --scheduler <round-robin|threads>
+                              Let the harts take turns on one thread, deterministically,
+                              or run each on a thread of its own [default: round-robin]
+  --quantum <n>               Instructions per turn of a hart [default: 1000]
+  --engine <interpreter|jit|compare>
+                              Interpret every instruction, translate hot blocks to x86-64
+                              code, or do both and compare them [default: interpreter]
+  --timebase <hz>             The frequency of mtime [default: 10000000]
+  --kernel <file>             The kernel, loaded at the start of RAM
+  --firmware <file>           Firmware loaded at the start of RAM; the kernel follows at +2M
+  --initrd <file>             An initial RAM disk, loaded at the end of RAM
+  --dtb <file>                A device tree, loaded at the end of RAM and passed in a1
+  --disk <file>[,backend=<memory|file|readonly|snapshot|overlay:<path>>]
+                              Attach a virtio disk, raw or qcow2; may be repeated. The
+                              image is copied to memory, written in place, read-only,
+                              read in place with writes kept in memory, or read in
+                              place with writes in a qcow2 overlay [default: memory]
+  --nic <user|tap:<ifname>|listen:<path>|connect:<path>>[,mac=<mac>][,pcap=<file>]
+                              Attach a virtio network card; may be repeated. Its frames
+                              go to the user-mode network, which reaches the host's
+                              localhost, a TAP interface, or a Unix socket to another
+                              instance, and may be captured to a pcap file
+  --nic user,forward=<host port>:<guest port>
+                              Forward a TCP port of the host's localhost to the guest;
+                              may be repeated
+  --virtio <legacy|modern>    The virtio-mmio interface: version 1, or version 2 of
+                              virtio 1.x [default: legacy]
+  --uart <stdio|null|file:<path>>
+                              Where the console goes [default: stdio]
+  --console <stdio|null|file:<path>>[,port=<name>:<path>]
+                              Attach a virtio console, whose console port (hvc0) goes
+                              to stdio, nowhere or a file. Every named port is a Unix
+                              socket on the host; `port=` may be repeated
+  --rng <host|seed:<n>>       Attach a virtio entropy device, whose bytes come from the
+                              host or from a generator with a seed, so that runs repeat.
+                              Runs with GDB or event logs always use a seed
+
+Tracing:
+  --log-commits <file>        Write a Spike-style commit log
+  --no-disasm                 Leave the disassembly out of the commit log
+  --lockstep <trace>          Compare every instruction with a Spike commit log, and
+                              exit with status 1 at the first difference
+
+Debugging:
+  --gdb <port>                Wait for GDB on a TCP port of localhost
+  --checkpoint-interval <n>   Instructions between checkpoints for reverse execution
+
+Snapshots and replay:
+  --save-snapshot <file>      Save the machine state on exit, or at --save-at
+  --save-at <instructions>    When to save the snapshot
+  --restore <file>            Start from a snapshot instead of the kernel and disk
+  --record <file>             Record input to an event log
+  --replay <file>             Replay input from an event log
+
+  -h, --help                  Print this help
+";
+
+/// The machine to emulate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Profile {
+    /// The QEMU virt machine: CLINT, PLIC, UART, test finisher and virtio devices.
+    #[default]
+    Virt,
+    /// RAM, CLINT, UART and test finisher, without interrupt routing or disks. Enough for
+    /// bare-metal tests.
+    Bare,
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "virt" => Ok(Profile::Virt),
+            "bare" => Ok(Profile::Bare),
+            _ => Err(format!(
+                "unknown machine profile `{}`; expected `virt` or `bare`",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Profile::Virt => write!(f, "virt"),
+            Profile::Bare => write!(f, "bare"),
+        }
+    }
+}
+
+/// How the harts share the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Scheduler {
+    /// The harts take turns on one thread, each running a quantum of instructions. Runs are
+    /// deterministic.
+    #[default]
+    RoundRobin,
+    /// Every hart runs on a host thread of its own.
+    Threads,
+}
+
+impl FromStr for Scheduler {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "round-robin" => Ok(Scheduler::RoundRobin),
+            "threads" => Ok(Scheduler::Threads),
+            _ => Err(format!(
+                "unknown scheduler `{}`; expected `round-robin` or `threads`",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Scheduler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Scheduler::RoundRobin => write!(f, "round-robin"),
+            Scheduler::Threads => write!(f, "threads"),
+        }
+    }
+}
+
+/// How a disk image is stored while the machine runs. Images may be raw or qcow2.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum DiskBackend {
+    /// The image is read into memory and writes are lost on exit.
+    #[default]
+    Memory,
+    /// The image file is read and written in place, so writes outlive the run.
+    File,
+    /// The image file is read in place, and the guest sees a read-only disk.
+    ReadOnly,
+    /// The image file is read in place, and writes are kept in memory and lost on exit.
+    Snapshot,
+    /// The image file is read in place, and writes go to a qcow2 overlay over it, which is
+    /// created if it doesn't exist.
+    Overlay(PathBuf),
+}
+
+impl FromStr for DiskBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "memory" => Ok(DiskBackend::Memory),
+            "file" => Ok(DiskBackend::File),
+            "readonly" => Ok(DiskBackend::ReadOnly),
+            "snapshot" => Ok(DiskBackend::Snapshot),
+            _ => match s.strip_prefix("overlay:") {
+                Some(path) if !path.is_empty() => Ok(DiskBackend::Overlay(path.into())),
+                _ => Err(format!(
+                    "unknown disk backend `{}`; expected `memory`, `file`, `readonly`, \
+                     `snapshot` or `overlay:<path>`",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// A virtio disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disk {
+    pub path: PathBuf,
+    pub backend: DiskBackend,
+}
+
+/// A virtio network card.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nic {
+    pub backend: NetBackend,
+    /// The Ethernet address, or one made from the number of the card.
+    pub mac: Option<[u8; 6]>,
+    /// A file that captures the frames in both directions.
+    pub pcap: Option<PathBuf>,
+}
+
+/// A virtio console.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Console {
+    /// Where the console port goes.
+    pub backend: UartBackend,
+    /// The named ports, each a Unix socket on the host.
+    pub ports: Vec<ConsolePort>,
+}
+
+/// Everything needed to build a machine and run it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub profile: Profile,
+    /// RAM size in bytes.
+    pub ram: u64,
+    pub isa: String,
+    pub harts: usize,
+    pub scheduler: Scheduler,
+    /// The number of instructions per turn of a hart with the round-robin scheduler.
+    pub quantum: u64,
+    pub engine: Engine,
+    /// The frequency of mtime in Hz.
+    pub timebase: u64,
+    pub kernel: Option<PathBuf>,
+    pub firmware: Option<PathBuf>,
+    pub initrd: Option<PathBuf>,
+    pub dtb: Option<PathBuf>,
+    pub disks: Vec<Disk>,
+    pub nics: Vec<Nic>,
+    pub virtio: VirtioTransport,
+    pub uart: UartBackend,
+    pub console: Option<Console>,
+    pub rng: Option<RngBackend>,
+    pub log_commits: Option<PathBuf>,
+    pub disasm: bool,
+    pub lockstep: Option<PathBuf>,
+    pub gdb: Option<u16>,
+    pub checkpoint_interval: u64,
+    pub save_snapshot: Option<PathBuf>,
+    pub save_at: Option<u64>,
+    pub restore: Option<PathBuf>,
+    pub record: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            profile: Profile::Virt,
+            ram: MEMORY_SIZE,
+            isa: Isa::default().to_string(),
+            harts: 1,
+            scheduler: Scheduler::RoundRobin,
+            quantum: QUANTUM,
+            engine: Engine::Interpreter,
+            timebase: TIMEBASE_FREQUENCY,
+            kernel: None,
+            firmware: None,
+            initrd: None,
+            dtb: None,
+            disks: Vec::new(),
+            nics: Vec::new(),
+            virtio: VirtioTransport::Legacy,
+            uart: UartBackend::Stdio,
+            console: None,
+            rng: None,
+            log_commits: None,
+            disasm: true,
+            lockstep: None,
+            gdb: None,
+            checkpoint_interval: CHECKPOINT_INTERVAL,
+            save_snapshot: None,
+            save_at: None,
+            restore: None,
+            record: None,
+            replay: None,
+        }
+    }
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+impl Config {
+    /// Read the configuration file at `path`. The result isn't validated yet.
+    pub fn load(path: &Path) -> io::Result<Config> {
+        let text = fs::read_to_string(path).map_err(|e| {
+            io::Error::new(e.kind(), format!("can't read {}: {}", path.display(), e))
+        })?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut config = Config::default();
+        config.apply_toml(&text, dir).map_err(|(line, message)| {
+            invalid_input(format!("{}:{}: {}", path.display(), line, message))
+        })?;
+        Ok(config)
+    }
+
+    /// Parse command-line arguments, without the program name. A file given with `--config` is
+    /// read first, and the other options override it. The result is validated.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> io::Result<Config> {
+        let args: Vec<String> = args.into_iter().collect();
+        let mut config = match args.iter().position(|arg| arg == "--config") {
+            Some(i) => {
+                let path = args
+                    .get(i + 1)
+                    .ok_or_else(|| invalid_input("`--config` needs a file".to_string()))?;
+                Config::load(Path::new(path))?
+            }
+            None => Config::default(),
+        };
+        config.apply_args(args).map_err(invalid_input)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_args(&mut self, args: Vec<String>) -> Result<(), String> {
+        let mut args = args.into_iter();
+        let mut positional = Vec::new();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                positional.push(arg);
+                continue;
+            }
+            if arg == "--no-disasm" {
+                self.disasm = false;
+                continue;
+            }
+            // Don't take the next argument as the value of an option that doesn't exist.
+            if !VALUE_OPTIONS.contains(&arg.as_str()) {
+                return Err(format!("unknown option `{}`", arg));
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| format!("`{}` needs a value", arg))?;
+            let number = |value: &str| {
+                parse_number(value).ok_or_else(|| format!("`{}` needs a number", arg))
+            };
+            match arg.as_str() {
+                "--config" => {}
+                "--profile" => self.profile = value.parse()?,
+                "--ram" => {
+                    self.ram = parse_size(&value).ok_or_else(|| {
+                        format!("invalid RAM size `{}`; use e.g. 256M or 1G", value)
+                    })?
+                }
+                "--isa" | "--cpu" => self.isa = value,
+                "--harts" => self.harts = number(&value)? as usize,
+                "--scheduler" => self.scheduler = value.parse()?,
+                "--quantum" => self.quantum = number(&value)?,
+                "--engine" => self.engine = value.parse()?,
+                "--timebase" => self.timebase = number(&value)?,
+                "--kernel" => self.kernel = Some(value.into()),
+                "--firmware" => self.firmware = Some(value.into()),
+                "--initrd" => self.initrd = Some(value.into()),
+                "--dtb" => self.dtb = Some(value.into()),
+                "--disk" => self.disks.push(parse_disk(&value)?),
+                "--nic" => self.nics.push(parse_nic(&value)?),
+                "--virtio" => self.virtio = value.parse()?,
+                "--uart" => self.uart = parse_char_backend("UART", &value)?,
+                "--console" => self.console = Some(parse_console(&value)?),
+                "--rng" => self.rng = Some(parse_rng(&value)?),
+                "--log-commits" => self.log_commits = Some(value.into()),
+                "--lockstep" => self.lockstep = Some(value.into()),
+                "--gdb" => {
+                    self.gdb = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("invalid TCP port `{}`", value))?,
+                    )
+                }
+                "--checkpoint-interval" => self.checkpoint_interval = number(&value)?,
+                "--save-snapshot" => self.save_snapshot = Some(value.into()),
+                "--save-at" => self.save_at = Some(number(&value)?),
+                "--restore" => self.restore = Some(value.into()),
+                "--record" => self.record = Some(value.into()),
+                "--replay" => self.replay = Some(value.into()),
+                _ => return Err(format!("unknown option `{}`", arg)),
+            }
+        }
+
+        // The positional arguments are kept for compatibility: `honga <kernel> [<disk>]`.
+        let mut positional = positional.into_iter();
+        if let Some(kernel) = positional.next() {
+            if self.kernel.is_some() {
+                return Err(format!(
+                    "`{}` is given as the kernel but `--kernel` is set too",
+                    kernel
+                ));
+            }
+            self.kernel = Some(kernel.into());
+        }
+        if let Some(disk) = positional.next() {
+            self.disks.insert(0, parse_disk(&disk)?);
+        }
+        if let Some(extra) = positional.next() {
+            return Err(format!(
+                "unexpected argument `{}`; attach more disks with `--disk`",
+                extra
+            ));
+        }
+        Ok(())
+    }
+
+    fn apply_toml(&mut self, text: &str, dir: &Path) -> Result<(), toml::Error> {
+        let path = |entry: &Entry| string(entry).map(|s| dir.join(s));
+        for table in toml::parse(text)? {
+            match (table.name.as_str(), table.array) {
+                ("", false) => {
+                    for entry in &table.entries {
+                        match entry.key.as_str() {
+                            "profile" => self.profile = parse(entry, string(entry)?)?,
+                            "ram" => self.ram = ram(entry)?,
+                            "isa" => self.isa = string(entry)?.to_string(),
+                            "harts" => self.harts = integer(entry)? as usize,
+                            "scheduler" => self.scheduler = parse(entry, string(entry)?)?,
+                            "quantum" => self.quantum = integer(entry)?,
+                            "engine" => self.engine = parse(entry, string(entry)?)?,
+                            "timebase" => self.timebase = integer(entry)?,
+                            "virtio" => self.virtio = parse(entry, string(entry)?)?,
+                            _ => {
+                                return unknown(
+                                    entry,
+                                    &[
+                                        "profile",
+                                        "ram",
+                                        "isa",
+                                        "harts",
+                                        "scheduler",
+                                        "quantum",
+                                        "engine",
+                                        "timebase",
+                                        "virtio",
+                                    ],
+                                )
+                            }
+                        }
+                    }
+                }
+                ("boot", false) => {
+                    for entry in &table.entries {
+                        match entry.key.as_str() {
+                            "kernel" => self.kernel = Some(path(entry)?),
+                            "firmware" => self.firmware = Some(path(entry)?),
+                            "initrd" => self.initrd = Some(path(entry)?),
+                            "dtb" => self.dtb = Some(path(entry)?),
+                            _ => return unknown(entry, &["kernel", "firmware", "initrd", "dtb"]),
+                        }
+                    }
+                }
+                ("disk", true) => {
+                    let mut disk_path = None;
+                    let mut backend = None;
+                    let mut overlay = None;
+                    for entry in &table.entries {
+                        match entry.key.as_str() {
+                            "path" => disk_path = Some(path(entry)?),
+                            "backend" => backend = Some((entry, string(entry)?)),
+                            "overlay" => overlay = Some((entry, path(entry)?)),
+                            _ => return unknown(entry, &["path", "backend", "overlay"]),
+                        }
+                    }
+                    let path = disk_path
+                        .ok_or_else(|| (table.line, "a disk needs a `path`".to_string()))?;
+                    let backend = match (backend, overlay) {
+                        (Some((_, "overlay")), Some((_, overlay))) | (None, Some((_, overlay))) => {
+                            DiskBackend::Overlay(overlay)
+                        }
+                        (Some((_, "overlay")), None) => {
+                            return Err((
+                                table.line,
+                                "the `overlay` backend needs an `overlay` path".into(),
+                            ))
+                        }
+                        (Some(_), Some((entry, _))) => {
+                            return Err((
+                                entry.line,
+                                "only the `overlay` backend takes an `overlay` path".into(),
+                            ))
+                        }
+                        (Some((entry, backend)), None) => parse(entry, backend)?,
+                        (None, None) => DiskBackend::default(),
+                    };
+                    self.disks.push(Disk { path, backend });
+                }
+                ("nic", true) => {
+                    let mut backend = None;
+                    let mut ifname = None;
+                    let mut socket = None;
+                    let mut forwards = None;
+                    let mut nic = Nic {
+                        backend: NetBackend::User {
+                            forwards: Vec::new(),
+                        },
+                        mac: None,
+                        pcap: None,
+                    };
+                    for entry in &table.entries {
+                        match entry.key.as_str() {
+                            "backend" => backend = Some((entry, string(entry)?)),
+                            "ifname" => ifname = Some((entry, string(entry)?.to_string())),
+                            "path" => socket = Some((entry, path(entry)?)),
+                            "forward" => forwards = Some((entry, forward_list(entry)?)),
+                            "mac" => {
+                                nic.mac =
+                                    Some(parse_mac(string(entry)?).map_err(|e| (entry.line, e))?)
+                            }
+                            "pcap" => nic.pcap = Some(path(entry)?),
+                            _ => {
+                                return unknown(
+                                    entry,
+                                    &["backend", "ifname", "path", "forward", "mac", "pcap"],
+                                )
+                            }
+                        }
+                    }
+                    let needs = |backend: &str, key: &str| {
+                        (
+                            table.line,
+                            format!("the `{}` backend needs `{}`", backend, key),
+                        )
+                    };
+                    nic.backend = match backend {
+                        Some((_, "user")) | None => NetBackend::User {
+                            forwards: forwards.take().map(|(_, f)| f).unwrap_or_default(),
+                        },
+                        Some((_, "tap")) => match ifname.take() {
+                            Some((_, ifname)) => NetBackend::Tap(ifname),
+                            None => return Err(needs("tap", "ifname")),
+                        },
+                        Some((_, "listen")) => match socket.take() {
+                            Some((_, path)) => NetBackend::Listen(path),
+                            None => return Err(needs("listen", "path")),
+                        },
+                        Some((_, "connect")) => match socket.take() {
+                            Some((_, path)) => NetBackend::Connect(path),
+                            None => return Err(needs("connect", "path")),
+                        },
+                        Some((entry, other)) => {
+                            return Err((
+                                entry.line,
+                                format!(
+                                    "unknown network backend `{}`; expected `user`, `tap`, \
+                                     `listen` or `connect`",
+                                    other
+                                ),
+                            ))
+                        }
+                    };
+                    // The keys the backend didn't take.
+                    if let Some((entry, _)) = ifname {
+                        return Err((entry.line, "only the `tap` backend takes `ifname`".into()));
+                    }
+                    if let Some((entry, _)) = socket {
+                        return Err((
+                            entry.line,
+                            "only the `listen` and `connect` backends take `path`".into(),
+                        ));
+                    }
+                    if let Some((entry, _)) = forwards {
+                        return Err((entry.line, "only the `user` backend takes `forward`".into()));
+                    }
+                    self.nics.push(nic);
+                }
+                ("uart", false) => {
+                    let mut backend = None;
+                    let mut file = None;
+                    for entry in &table.entries {
+                        match entry.key.as_str() {
+                            "backend" => backend = Some((entry, string(entry)?)),
+                            "path" => file = Some(path(entry)?),
+                            _ => return unknown(entry, &["backend", "path"]),
+                        }
+                    }
+                    self.uart = char_backend(&table, "UART", backend, file)?;
+                }
+                ("console", false) => {
+                    let mut backend = None;
+                    let mut file = None;
+                    let mut ports = Vec::new();
+                    for entry in &table.entries {
+                        match entry.key.as_str() {
+                            "backend" => backend = Some((entry, string(entry)?)),
+                            "path" => file = Some(path(entry)?),
+                            "ports" => ports = port_list(entry, dir)?,
+                            _ => return unknown(entry, &["backend", "path", "ports"]),
+                        }
+                    }
+                    self.console = Some(Console {
+                        backend: char_backend(&table, "console", backend, file)?,
+                        ports,
+                    });
+                }
+                ("rng", false) => {
+                    let mut backend = None;
+                    let mut seed = None;
+                    for entry in &table.entries {
+                        match entry.key.as_str() {
+                            "backend" => backend = Some((entry, string(entry)?)),
+                            "seed" => seed = Some(integer(entry)?),
+                            _ => return unknown(entry, &["backend", "seed"]),
+                        }
+                    }
+                    self.rng = Some(match (backend, seed) {
+                        (Some((_, "host")), None) | (None, None) => RngBackend::Host,
+                        (Some((_, "seed")), Some(seed)) | (None, Some(seed)) => {
+                            RngBackend::Seeded(seed)
+                        }
+                        (Some((_, "seed")), None) => {
+                            return Err((table.line, "the `seed` backend needs a `seed`".into()))
+                        }
+                        (Some((entry, "host")), Some(_)) => {
+                            return Err((
+                                entry.line,
+                                "only the `seed` backend takes a `seed`".into(),
+                            ))
+                        }
+                        (Some((entry, other)), _) => {
+                            return Err((
+                                entry.line,
+                                format!(
+                                    "unknown entropy backend `{}`; expected `host` or `seed`",
+                                    other
+                                ),
+                            ))
+                        }
+                    });
+                }
+                ("trace", false) => {
+                    for entry in &table.entries {
+                        match entry.key.as_str() {
+                            "log_commits" => self.log_commits = Some(path(entry)?),
+                            "disasm" => self.disasm = boolean(entry)?,
+                            "lockstep" => self.lockstep = Some(path(entry)?),
+                            _ => return unknown(entry, &["log_commits", "disasm", "lockstep"]),
+                        }
+                    }
+                }
+                ("debug", false) => {
+                    for entry in &table.entries {
+                        match entry.key.as_str() {
+                            "gdb" => {
+                                let port = integer(entry)?;
+                                self.gdb = Some(u16::try_from(port).map_err(|_| {
+                                    (entry.line, format!("invalid TCP port {}", port))
+                                })?);
+                            }
+                            "checkpoint_interval" => self.checkpoint_interval = integer(entry)?,
+                            _ => return unknown(entry, &["gdb", "checkpoint_interval"]),
+                        }
+                    }
+                }
+                ("snapshot", false) => {
+                    for entry in &table.entries {
+                        match entry.key.as_str() {
+                            "save" => self.save_snapshot = Some(path(entry)?),
+                            "save_at" => self.save_at = Some(integer(entry)?),
+                            "restore" => self.restore = Some(path(entry)?),
+                            _ => return unknown(entry, &["save", "save_at", "restore"]),
+                        }
+                    }
+                }
+                ("events", false) => {
+                    for entry in &table.entries {
+                        match entry.key.as_str() {
+                            "record" => self.record = Some(path(entry)?),
+                            "replay" => self.replay = Some(path(entry)?),
+                            _ => return unknown(entry, &["record", "replay"]),
+                        }
+                    }
+                }
+                (name, array) => return unknown_table(&table, name, array),
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the configuration describes a machine honga can build and a run it can do.
+    pub fn validate(&self) -> io::Result<()> {
+        self.check().map_err(invalid_input)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.kernel.is_none() && self.firmware.is_none() && self.restore.is_none() {
+            return Err(
+                "no kernel given; pass a kernel image, `--firmware` or `--restore` \
+                        a snapshot"
+                    .to_string(),
+            );
+        }
+        if !(MIN_RAM..=MAX_RAM).contains(&self.ram) || !self.ram.is_multiple_of(4096) {
+            return Err(format!(
+                "RAM size {} is invalid; it must be a multiple of 4K between 1M and 64G",
+                self.ram
+            ));
+        }
+        self.isa.parse::<Isa>()?;
+        if !(1..=MAX_HARTS).contains(&self.harts) {
+            return Err(format!(
+                "{} harts requested; there must be between 1 and {}",
+                self.harts, MAX_HARTS
+            ));
+        }
+        if self.quantum == 0 {
+            return Err("the quantum must be at least 1 instruction".to_string());
+        }
+        if self.scheduler == Scheduler::Threads
+            && (self.gdb.is_some()
+                || self.log_commits.is_some()
+                || self.lockstep.is_some()
+                || self.save_snapshot.is_some()
+                || self.record.is_some()
+                || self.replay.is_some())
+        {
+            return Err(
+                "harts on threads of their own don't run deterministically, so the threads \
+                        scheduler can't be combined with GDB, a commit log, lockstep checking, \
+                        snapshots or event logs"
+                    .to_string(),
+            );
+        }
+        if self.engine != Engine::Interpreter {
+            if !jit::SUPPORTED {
+                return Err(format!(
+                    "the {} engine needs an x86-64 Linux host; use the interpreter",
+                    self.engine
+                ));
+            }
+            if self.gdb.is_some()
+                || self.log_commits.is_some()
+                || self.lockstep.is_some()
+                || self.record.is_some()
+                || self.replay.is_some()
+            {
+                return Err(format!(
+                    "the {} engine executes whole blocks of instructions, so it can't be \
+                            combined with GDB, a commit log, lockstep checking or event logs",
+                    self.engine
+                ));
+            }
+            if self.engine == Engine::Compare && self.scheduler == Scheduler::Threads {
+                return Err(
+                    "the compare engine undoes what blocks write to memory, which harts on \
+                            other threads may see; use the round-robin scheduler"
+                        .to_string(),
+                );
+            }
+        }
+        if !(1..=CPU_FREQUENCY).contains(&self.timebase) {
+            return Err(format!(
+                "timebase {} Hz is invalid; it must be between 1 Hz and the {} Hz of the hart",
+                self.timebase, CPU_FREQUENCY
+            ));
+        }
+        let consoles = self.console.iter().count();
+        let rngs = self.rng.iter().count();
+        let virtio_devices = self.disks.len() + self.nics.len() + consoles + rngs;
+        if self.profile == Profile::Bare && virtio_devices > 0 {
+            return Err(
+                "the bare profile has no virtio slots; use the virt profile for disks, \
+                        network cards, consoles and entropy devices"
+                    .to_string(),
+            );
+        }
+        if virtio_devices > VIRTIO_SLOTS {
+            return Err(format!(
+                "{} disks, {} network cards, {} consoles and {} entropy devices given, but the \
+                 virt machine has {} virtio slots",
+                self.disks.len(),
+                self.nics.len(),
+                consoles,
+                rngs,
+                VIRTIO_SLOTS
+            ));
+        }
+        if !self.nics.is_empty()
+            && (self.gdb.is_some() || self.record.is_some() || self.replay.is_some())
+        {
+            return Err(
+                "frames from the network aren't recorded, and executing instructions again \
+                        would send frames again, so network cards can't be combined with GDB \
+                        or event logs"
+                    .to_string(),
+            );
+        }
+        if let Some(console) = &self.console {
+            self.check_console(console)?;
+        }
+        if self.record.is_some() && self.replay.is_some() {
+            return Err("a run can't both record and replay an event log".to_string());
+        }
+        if self.gdb.is_some()
+            && (self.log_commits.is_some()
+                || self.lockstep.is_some()
+                || self.save_snapshot.is_some())
+        {
+            return Err(
+                "the GDB stub can't be combined with a commit log, lockstep checking \
+                        or saving a snapshot"
+                    .to_string(),
+            );
+        }
+        if self.save_at.is_some() && self.save_snapshot.is_none() {
+            return Err("`save_at` needs a snapshot file to save to".to_string());
+        }
+        if self.checkpoint_interval == 0 {
+            return Err("the checkpoint interval must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    fn check_console(&self, console: &Console) -> Result<(), String> {
+        if console.backend == UartBackend::Stdio && self.uart == UartBackend::Stdio {
+            return Err(
+                "the UART and the console port of the virtio console can't both use stdio; \
+                        give the UART `null` or `file:<path>`"
+                    .to_string(),
+            );
+        }
+        if console.ports.len() >= MAX_CONSOLE_PORTS {
+            return Err(format!(
+                "{} console ports given, but a virtio console has at most {}",
+                console.ports.len(),
+                MAX_CONSOLE_PORTS - 1
+            ));
+        }
+        for (i, port) in console.ports.iter().enumerate() {
+            if console.ports[..i]
+                .iter()
+                .any(|other| other.name == port.name)
+            {
+                return Err(format!("the console port `{}` is given twice", port.name));
+            }
+        }
+        if self.gdb.is_some() || self.record.is_some() || self.replay.is_some() {
+            return Err(
+                "input to the virtio console isn't recorded, and executing instructions again \
+                        would write its output again, so it can't be combined with GDB or \
+                        event logs"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The command-line options that take a value, which is the argument after them. Any other
+/// option but `--no-disasm` is unknown. Keep this in sync with the options of `apply_args`.
+const VALUE_OPTIONS: &[&str] = &[
+    "--config",
+    "--profile",
+    "--ram",
+    "--isa",
+    "--cpu",
+    "--harts",
+    "--scheduler",
+    "--quantum",
+    "--engine",
+    "--timebase",
+    "--kernel",
+    "--firmware",
+    "--initrd",
+    "--dtb",
+    "--disk",
+    "--nic",
+    "--virtio",
+    "--uart",
+    "--console",
+    "--rng",
+    "--log-commits",
+    "--lockstep",
+    "--gdb",
+    "--checkpoint-interval",
+    "--save-snapshot",
+    "--save-at",
+    "--restore",
+    "--record",
+    "--replay",
+];
+
+/// Parse a decimal or 0x-prefixed hexadecimal number, which may contain underscores.
+fn parse_number(s: &str) -> Option<u64> {
+    let s = s.replace('_', "");
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Parse a size in bytes with an optional K, M or G suffix, e.g. `128M`.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let s = s.strip_suffix(['B', 'b']).unwrap_or(s);
+    let (digits, shift) = match s.char_indices().last()? {
+        (i, 'K' | 'k') => (&s[..i], 10),
+        (i, 'M' | 'm') => (&s[..i], 20),
+        (i, 'G' | 'g') => (&s[..i], 30),
+        _ => (s, 0),
+    };
+    parse_number(digits)?.checked_mul(1 << shift)
+}
+
+/// Parse `<path>[,backend=<backend>]`, where the backend may be `overlay:<path>`.
+fn parse_disk(s: &str) -> Result<Disk, String> {
+    let mut fields = s.split(',');
+    let path = PathBuf::from(fields.next().unwrap_or_default());
+    let mut backend = DiskBackend::default();
+    for field in fields {
+        match field.split_once('=') {
+            Some(("backend", value)) => backend = value.parse()?,
+            _ => {
+                return Err(format!(
+                    "unknown disk option `{}`; expected `backend=...`",
+                    field
+                ))
+            }
+        }
+    }
+    Ok(Disk { path, backend })
+}
+
+/// Parse `<backend>[,mac=<mac>][,pcap=<file>][,forward=<host>:<guest>]...`, where the backend
+/// is `user`, `tap:<ifname>`, `listen:<path>` or `connect:<path>`, and only `user` forwards.
+fn parse_nic(s: &str) -> Result<Nic, String> {
+    let mut fields = s.split(',');
+    let backend = fields.next().unwrap_or_default();
+    let mut forwards = Vec::new();
+    let mut nic = Nic {
+        backend: NetBackend::User {
+            forwards: Vec::new(),
+        },
+        mac: None,
+        pcap: None,
+    };
+    for field in fields {
+        match field.split_once('=') {
+            Some(("mac", value)) => nic.mac = Some(parse_mac(value)?),
+            Some(("pcap", value)) if !value.is_empty() => nic.pcap = Some(value.into()),
+            Some(("forward", value)) => forwards.push(parse_forward(value)?),
+            _ => {
+                return Err(format!(
+                    "unknown network option `{}`; expected `mac=...`, `pcap=...` or \
+                     `forward=...`",
+                    field
+                ))
+            }
+        }
+    }
+    let (kind, argument) = match backend.split_once(':') {
+        Some((kind, argument)) if !argument.is_empty() => (kind, Some(argument)),
+        _ => (backend, None),
+    };
+    if !forwards.is_empty() && kind != "user" {
+        return Err("only the `user` network backend forwards ports".to_string());
+    }
+    nic.backend = match (kind, argument) {
+        ("user", None) => NetBackend::User { forwards },
+        ("tap", Some(ifname)) => NetBackend::Tap(ifname.to_string()),
+        ("listen", Some(path)) => NetBackend::Listen(path.into()),
+        ("connect", Some(path)) => NetBackend::Connect(path.into()),
+        _ => {
+            return Err(format!(
+                "unknown network backend `{}`; expected `user`, `tap:<ifname>`, \
+                 `listen:<path>` or `connect:<path>`",
+                backend
+            ))
+        }
+    };
+    Ok(nic)
+}
+
+/// Parse an Ethernet address, e.g. `52:54:00:12:34:56`, which must not be a multicast one.
+fn parse_mac(s: &str) -> Result<[u8; 6], String> {
+    let invalid = || format!("invalid MAC address `{}`; use e.g. 52:54:00:12:34:56", s);
+    let mut mac = [0; 6];
+    let mut bytes = s.split(':');
+    for byte in mac.iter_mut() {
+        let digits = bytes.next().filter(|d| d.len() == 2).ok_or_else(invalid)?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+    }
+    if bytes.next().is_some() {
+        return Err(invalid());
+    }
+    if mac[0] & 1 != 0 {
+        return Err(format!("MAC address `{}` is a multicast address", s));
+    }
+    Ok(mac)
+}
+
+/// Parse `<host port>:<guest port>`.
+fn parse_forward(s: &str) -> Result<(u16, u16), String> {
+    s.split_once(':')
+        .and_then(|(host, guest)| Some((host.parse().ok()?, guest.parse().ok()?)))
+        .ok_or_else(|| {
+            format!(
+                "invalid port forward `{}`; use <host port>:<guest port>, e.g. 2222:22",
+                s
+            )
+        })
+}
+
+/// Parse `stdio`, `null` or `file:<path>`, the backend of the UART or of the console port of
+/// the virtio console, `device`.
+fn parse_char_backend(device: &str, s: &str) -> Result<UartBackend, String> {
+    match s {
+        "stdio" => Ok(UartBackend::Stdio),
+        "null" => Ok(UartBackend::Null),
+        _ => match s.strip_prefix("file:") {
+            Some(path) if !path.is_empty() => Ok(UartBackend::File(path.into())),
+            _ => Err(format!(
+                "unknown {} backend `{}`; expected `stdio`, `null` or `file:<path>`",
+                device, s
+            )),
+        },
+    }
+}
+
+/// Parse `host` or `seed:<n>`.
+fn parse_rng(s: &str) -> Result<RngBackend, String> {
+    match s {
+        "host" => Ok(RngBackend::Host),
+        _ => match s.strip_prefix("seed:").map(parse_number) {
+            Some(Some(seed)) => Ok(RngBackend::Seeded(seed)),
+            _ => Err(format!(
+                "unknown entropy backend `{}`; expected `host` or `seed:<n>`",
+                s
+            )),
+        },
+    }
+}
+
+/// Parse `<backend>[,port=<name>:<path>]...`.
+fn parse_console(s: &str) -> Result<Console, String> {
+    let mut fields = s.split(',');
+    let backend = parse_char_backend("console", fields.next().unwrap_or_default())?;
+    let mut ports = Vec::new();
+    for field in fields {
+        match field.split_once('=') {
+            Some(("port", value)) => ports.push(parse_console_port(value, Path::new(""))?),
+            _ => {
+                return Err(format!(
+                    "unknown console option `{}`; expected `port=...`",
+                    field
+                ))
+            }
+        }
+    }
+    Ok(Console { backend, ports })
+}
+
+/// Parse `<name>:<path>`, where a relative path is relative to `dir`.
+fn parse_console_port(s: &str, dir: &Path) -> Result<ConsolePort, String> {
+    match s.split_once(':') {
+        Some((name, path)) if !name.is_empty() && !path.is_empty() => Ok(ConsolePort {
+            name: name.to_string(),
+            path: dir.join(path),
+        }),
+        _ => Err(format!(
+            "invalid console port `{}`; use <name>:<path>, e.g. org.honga.agent:agent.sock",
+            s
+        )),
+    }
+}
+
+fn string(entry: &Entry) -> Result<&str, toml::Error> {
+    match &entry.value {
+        Value::String(s) => Ok(s),
+        value => Err(mismatch(entry, value, "a string")),
+    }
+}
+
+fn integer(entry: &Entry) -> Result<u64, toml::Error> {
+    match &entry.value {
+        Value::Integer(n) if *n >= 0 => Ok(*n as u64),
+        Value::Integer(n) => Err((
+            entry.line,
+            format!("`{}` can't be negative, found {}", entry.key, n),
+        )),
+        value => Err(mismatch(entry, value, "an integer")),
+    }
+}
+
+fn boolean(entry: &Entry) -> Result<bool, toml::Error> {
+    match &entry.value {
+        Value::Boolean(b) => Ok(*b),
+        value => Err(mismatch(entry, value, "a boolean")),
+    }
+}
+
+/// Port forwards are an array of `"<host port>:<guest port>"` strings.
+fn forward_list(entry: &Entry) -> Result<Vec<(u16, u16)>, toml::Error> {
+    match &entry.value {
+        Value::Array(values) => values
+            .iter()
+            .map(|value| match value {
+                Value::String(s) => parse_forward(s).map_err(|e| (entry.line, e)),
+                value => Err(mismatch(entry, value, "an array of strings")),
+            })
+            .collect(),
+        value => Err(mismatch(entry, value, "an array of strings")),
+    }
+}
+
+/// Console ports are an array of `"<name>:<path>"` strings.
+fn port_list(entry: &Entry, dir: &Path) -> Result<Vec<ConsolePort>, toml::Error> {
+    match &entry.value {
+        Value::Array(values) => values
+            .iter()
+            .map(|value| match value {
+                Value::String(s) => parse_console_port(s, dir).map_err(|e| (entry.line, e)),
+                value => Err(mismatch(entry, value, "an array of strings")),
+            })
+            .collect(),
+        value => Err(mismatch(entry, value, "an array of strings")),
+    }
+}
+
+/// Return the character backend `backend`, which a `[uart]` or `[console]` table of `device`
+/// gives with a `file` path.
+fn char_backend(
+    table: &Table,
+    device: &str,
+    backend: Option<(&Entry, &str)>,
+    file: Option<PathBuf>,
+) -> Result<UartBackend, toml::Error> {
+    match (backend, file) {
+        (Some((_, "stdio")), None) | (None, None) => Ok(UartBackend::Stdio),
+        (Some((_, "null")), None) => Ok(UartBackend::Null),
+        (Some((_, "file")), Some(file)) | (None, Some(file)) => Ok(UartBackend::File(file)),
+        (Some((_, "file")), None) => Err((table.line, "the `file` backend needs a `path`".into())),
+        (Some((entry, "stdio" | "null")), Some(_)) => {
+            Err((entry.line, "only the `file` backend takes a `path`".into()))
+        }
+        (Some((entry, other)), _) => Err((
+            entry.line,
+            format!(
+                "unknown {} backend `{}`; expected `stdio`, `null` or `file`",
+                device, other
+            ),
+        )),
+    }
+}
+
+/// A RAM size is either a number of bytes or a string with a suffix.
+fn ram(entry: &Entry) -> Result<u64, toml::Error> {
+    match &entry.value {
+        Value::String(s) => parse_size(s).ok_or_else(|| {
+            (
+                entry.line,
+                format!("invalid RAM size \"{}\"; use e.g. \"256M\" or \"1G\"", s),
+            )
+        }),
+        _ => integer(entry),
+    }
+}
+
+fn parse<T: FromStr<Err = String>>(entry: &Entry, s: &str) -> Result<T, toml::Error> {
+    s.parse().map_err(|message| (entry.line, message))
+}
+
+fn mismatch(entry: &Entry, value: &Value, expected: &str) -> toml::Error {
+    (
+        entry.line,
+        format!(
+            "`{}` must be {}, found {}",
+            entry.key,
+            expected,
+            value.type_name()
+        ),
+    )
+}
+
+fn unknown<T>(entry: &Entry, keys: &[&str]) -> Result<T, toml::Error> {
+    Err((
+        entry.line,
+        format!(
+            "unknown key `{}`; expected one of {}",
+            entry.key,
+            keys.join(", ")
+        ),
+    ))
+}
+
+fn unknown_table<T>(table: &Table, name: &str, array: bool) -> Result<T, toml::Error> {
+    let message = match name {
+        "disk" => "disks are an array of tables; write `[[disk]]`".to_string(),
+        "nic" => "network cards are an array of tables; write `[[nic]]`".to_string(),
+        "boot" | "uart" | "console" | "rng" | "trace" | "debug" | "snapshot" | "events"
+            if array =>
+        {
+            format!("`[[{}]]` can't be repeated; write `[{}]`", name, name)
+        }
+        _ => format!(
+            "unknown table `{}`; expected one of boot, [[disk]], [[nic]], uart, console, rng, \
+             trace, debug, snapshot, events",
+            name
+        ),
+    };
+    Err((table.line, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(args: &[&str]) -> Result<Config, String> {
+        let mut config = Config::default();
+        config.apply_args(args.iter().map(|arg| arg.to_string()).collect())?;
+        Ok(config)
+    }
+
+    #[test]
+    fn unknown_options_take_no_value() {
+        assert_eq!(apply(&["--bogus"]).unwrap_err(), "unknown option `--bogus`");
+        assert_eq!(
+            apply(&["--bogus", "--harts", "2"]).unwrap_err(),
+            "unknown option `--bogus`"
+        );
+        assert_eq!(
+            apply(&["--kernel", "Image", "--bogus", "disk.img"]).unwrap_err(),
+            "unknown option `--bogus`"
+        );
+    }
+
+    #[test]
+    fn options_with_values() {
+        assert_eq!(apply(&["--harts"]).unwrap_err(), "`--harts` needs a value");
+        assert_eq!(
+            apply(&["--harts", "two"]).unwrap_err(),
+            "`--harts` needs a number"
+        );
+        let config = apply(&["--harts", "2", "--no-disasm", "Image"]).unwrap();
+        assert_eq!(config.harts, 2);
+        assert!(!config.disasm);
+        assert_eq!(config.kernel, Some("Image".into()));
+    }
+}
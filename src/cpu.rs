@@ -1,20 +1,61 @@
-use crate::bus::{
-    virtio::Virtio, Bus, MEMORY_BASE, MEMORY_SIZE, PLIC_SCLAIM, UART_IRQ, VIRTIO_IRQ,
-};
+use crate::bitmanip;
+use crate::bus::{Bus, Uart, MEMORY_BASE};
+use crate::crypto::{self, MSECCFG_SSEED, MSECCFG_USEED};
 use crate::csr::*;
+use crate::decode::{decode, DecodeCache, Decoded};
 use crate::exception::Exception;
+use crate::hypervisor::{
+    HEDELEG_WRITABLE, HSTATUS_SPV, HSTATUS_VSXL, HSTATUS_VTSR, HSTATUS_VTVM, HSTATUS_WRITABLE,
+};
 use crate::interrupt::Interrupt;
+use crate::isa::{Isa, MISA_A, MISA_F, MISA_H, MISA_M, ZKR, ZVE32X};
+use crate::jit::{Engine, Jit};
+use crate::replay::EventLog;
+use crate::snapshot::{invalid, Reader, Snapshot, Writer};
+use crate::trace::{destination, Commit};
+
+use std::io;
+use std::mem;
+use std::sync::atomic::{fence, Ordering};
 
 // MIP fields.
-const MIP_SSIP: u64 = 1 << 1;
-const MIP_MSIP: u64 = 1 << 3;
-const MIP_STIP: u64 = 1 << 5;
-const MIP_MTIP: u64 = 1 << 7;
-const MIP_SEIP: u64 = 1 << 9;
-const MIP_MEIP: u64 = 1 << 11;
+pub const MIP_SSIP: u64 = 1 << 1;
+pub const MIP_VSSIP: u64 = 1 << 2;
+pub const MIP_MSIP: u64 = 1 << 3;
+pub const MIP_STIP: u64 = 1 << 5;
+pub const MIP_VSTIP: u64 = 1 << 6;
+pub const MIP_MTIP: u64 = 1 << 7;
+pub const MIP_SEIP: u64 = 1 << 9;
+pub const MIP_VSEIP: u64 = 1 << 10;
+pub const MIP_MEIP: u64 = 1 << 11;
+/// The MIP fields the CLINT and the PLIC drive.
+const MIP_LINES: u64 = MIP_MSIP | MIP_MTIP | MIP_SEIP | MIP_MEIP;
+/// The supervisor-level interrupts.
+const MIP_S: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+/// The VS-level interrupts of the hypervisor extension, which the hypervisor raises through hvip.
+pub const MIP_VS: u64 = MIP_VSSIP | MIP_VSTIP | MIP_VSEIP;
+
+// MSTATUS fields.
+/// The state of the vector unit: Off, Initial, Clean or Dirty.
+pub const MSTATUS_VS: u64 = 3 << 9;
+/// The state of the floating-point unit.
+pub const MSTATUS_FS: u64 = 3 << 13;
+/// Makes the pages that can be executed readable too.
+pub const MSTATUS_MXR: u64 = 1 << 19;
+/// Set if a trap into M mode wrote a guest virtual address to mtval.
+pub const MSTATUS_GVA: u64 = 1 << 38;
+/// The virtualization mode before a trap into M mode, which MRET returns to.
+pub const MSTATUS_MPV: u64 = 1 << 39;
+/// Set if the floating-point or vector state is Dirty.
+pub const MSTATUS_SD: u64 = 1 << 63;
+/// The fields of mstatus that sstatus shows.
+const SSTATUS_SHARED: u64 = MSTATUS_VS | MSTATUS_FS | MSTATUS_SD;
+
+/// The default number of instructions a hart runs before the next one takes its turn.
+pub const QUANTUM: u64 = 1000;
 
 /// The page size (4 KiB) for the virtual memory system.
-const PAGE_SIZE: u64 = 4096;
+pub(crate) const PAGE_SIZE: u64 = 4096;
 
 /// Privileged mode.
 #[repr(u8)]
@@ -25,47 +66,383 @@ pub enum Mode {
     Machine = 3,
 }
 
-#[derive(Debug, PartialEq, PartialOrd)]
+impl Mode {
+    /// Return the mode encoded as `value` in e.g. mstatus.MPP, if it's a supported mode.
+    pub fn from_bits(value: u64) -> Option<Mode> {
+        match value {
+            0 => Some(Mode::User),
+            1 => Some(Mode::Supervisor),
+            3 => Some(Mode::Machine),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub enum AccessType {
     Instruction,
     Load,
     Store,
 }
 
-/// The CPU contains registers, a program coutner, and memory.
+/// A stage of address translation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stage {
+    /// Sv39 with the page table of satp.
+    Supervisor,
+    /// Sv39 with the page table of vsatp, which maps a guest's virtual addresses to guest physical
+    /// ones. Its page table is at guest physical addresses too.
+    VirtualSupervisor,
+    /// Sv39x4 with the page table of hgatp, which maps guest physical addresses to physical ones.
+    Guest,
+}
+
+/// The value an LR loaded, which an SC stores to only if it's still there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Reservation {
+    /// The physical address.
+    address: u64,
+    size: usize,
+    value: u64,
+}
+
+/// The state of a hart while another one runs.
+struct Hart {
+    regs: [u64; 32],
+    fregs: [u64; 32],
+    vregs: Box<[u8]>,
+    pc: u64,
+    csr: Box<[u64; 4096]>,
+    mode: Mode,
+    virt: bool,
+    enable_paging: bool,
+    page_table: u64,
+    reservation: Option<Reservation>,
+}
+
+impl Snapshot for Hart {
+    fn save(&self, w: &mut Writer) -> io::Result<()> {
+        for reg in self.regs.iter().chain(self.fregs.iter()) {
+            w.put_u64(*reg);
+        }
+        w.put_bytes(&self.vregs);
+        w.put_u64(self.pc);
+        for csr in self.csr.iter() {
+            w.put_u64(*csr);
+        }
+        w.put_u8(self.mode as u8);
+        w.put_bool(self.virt);
+        w.put_bool(self.enable_paging);
+        w.put_u64(self.page_table);
+        match self.reservation {
+            Some(reservation) => {
+                w.put_u8(reservation.size as u8);
+                w.put_u64(reservation.address);
+                w.put_u64(reservation.value);
+            }
+            None => w.put_u8(0),
+        }
+        Ok(())
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        for reg in self.regs.iter_mut().chain(self.fregs.iter_mut()) {
+            *reg = r.get_u64()?;
+        }
+        let vregs = r.get_bytes()?;
+        if vregs.len() != self.vregs.len() {
+            return Err(invalid(&format!(
+                "the snapshot has a VLEN of {}, not {}",
+                vregs.len() / 4,
+                self.vregs.len() / 4
+            )));
+        }
+        self.vregs = vregs.into_boxed_slice();
+        self.pc = r.get_u64()?;
+        for csr in self.csr.iter_mut() {
+            *csr = r.get_u64()?;
+        }
+        self.mode = Mode::from_bits(r.get_u8()? as u64).ok_or_else(|| invalid("invalid mode"))?;
+        self.virt = r.get_bool()?;
+        self.enable_paging = r.get_bool()?;
+        self.page_table = r.get_u64()?;
+        self.reservation = match r.get_u8()? as usize {
+            0 => None,
+            size => Some(Reservation {
+                size,
+                address: r.get_u64()?,
+                value: r.get_u64()?,
+            }),
+        };
+        Ok(())
+    }
+}
+
+/// The CPU contains the harts, which take turns, and the bus they share. The fields of the
+/// running hart are kept in the `Cpu` itself.
 pub struct Cpu {
     /// 32 64-bit integer registers.
-    regs: [u64; 32],
+    pub(crate) regs: [u64; 32],
+    /// 32 64-bit floating-point registers. Single-precision values are NaN-boxed.
+    pub(crate) fregs: [u64; 32],
+    /// 32 vector registers of VLEN bits each, one after the other, so that a register group is
+    /// contiguous. Elements are little-endian.
+    pub(crate) vregs: Box<[u8]>,
     /// Program counter point to the the memory address of the next instruction that would be executed.
     pub pc: u64,
     /// Memory to store executable instructions.
     pub bus: Bus,
     /// Control & status registers. RISC-V has 12-bit encoding space csr[11:0] which contain 4096
     /// csr.
-    pub csr: [u64; 4096],
+    pub csr: Box<[u64; 4096]>,
     /// Current privilege mode.
     pub mode: Mode,
+    /// The virtualization mode V of the hypervisor extension: set while a guest runs in VS or VU
+    /// mode, which `mode` holds as S or U.
+    pub virt: bool,
+    /// The guest physical address the last guest-page fault failed to translate.
+    pub(crate) guest_fault: u64,
     /// SV39 paging flag.
     pub enable_paging: bool,
     /// physical page number (PPN) × PAGE_SIZE (4096).
     pub page_table: u64,
+    /// The reservation set of LR/SC.
+    reservation: Option<Reservation>,
+    /// The id of the running hart.
+    hart: usize,
+    /// The state of every hart, indexed by id. The entry of the running hart is unused.
+    parked: Vec<Hart>,
+    /// The number of instructions a hart runs before the next one takes its turn.
+    quantum: u64,
+    /// The instruction count at which the next hart's turn starts.
+    next_switch: u64,
+    /// Decoded instructions, shared by the harts that take turns.
+    cache: DecodeCache,
+    /// Translated blocks, shared by the harts that take turns, if the JIT is used.
+    jit: Option<Box<Jit>>,
+    /// Side effects of the current instruction. Only collected while a commit log is written.
+    pub commit: Option<Commit>,
+    /// The number of instructions executed so far, including ones that trapped.
+    pub icount: u64,
+    /// The log that external events are recorded to or replayed from.
+    pub events: Option<EventLog>,
+    /// The extensions the hart implements. Single-letter ones can be turned off through misa.
+    pub isa: Isa,
 }
 
 impl Cpu {
-    /// Create a new `Cpu` object.
-    pub fn new(binary: Vec<u8>, image: Vec<u8>) -> Self {
+    /// Create a new `Cpu` object with a hart for every hart of `bus`, each with the extensions
+    /// of `isa`, that start at the beginning of the RAM of `bus` with their id in a0.
+    pub fn new(bus: Bus, isa: Isa) -> Self {
         let mut regs = [0; 32];
         // Set the register x2 with the size of a memory when a CPU is instantiated.
-        regs[2] = MEMORY_SIZE + MEMORY_BASE;
+        regs[2] = bus.memory.size() + MEMORY_BASE;
+        let vregs = vec![0; isa.vlen() / 8 * 32].into_boxed_slice();
+        let parked = (0..bus.harts())
+            .map(|id| {
+                let mut hart = Hart {
+                    regs,
+                    fregs: [0; 32],
+                    vregs: vregs.clone(),
+                    pc: MEMORY_BASE,
+                    csr: Box::new([0; 4096]),
+                    mode: Mode::Machine,
+                    virt: false,
+                    enable_paging: false,
+                    page_table: 0,
+                    reservation: None,
+                };
+                hart.regs[10] = id as u64;
+                hart.csr[MISA] = isa.misa();
+                hart.csr[MHARTID] = id as u64;
+                hart.csr[VLENB] = isa.vlen() as u64 / 8;
+                // vtype is illegal until vsetvl sets it.
+                hart.csr[VTYPE] = 1 << 63;
+                hart
+            })
+            .collect();
 
-        Self {
+        let mut cpu = Self {
             regs,
+            fregs: [0; 32],
+            vregs,
             pc: MEMORY_BASE,
-            bus: Bus::new(binary, image),
-            csr: [0; 4096],
+            bus,
+            csr: Box::new([0; 4096]),
             mode: Mode::Machine,
+            virt: false,
+            guest_fault: 0,
             enable_paging: false,
             page_table: 0,
+            reservation: None,
+            hart: 0,
+            parked,
+            quantum: QUANTUM,
+            next_switch: 0,
+            cache: DecodeCache::new(),
+            jit: None,
+            commit: None,
+            icount: 0,
+            events: None,
+            isa,
+        };
+        cpu.exchange(0);
+        cpu
+    }
+
+    /// Return the number of harts.
+    pub fn harts(&self) -> usize {
+        self.parked.len()
+    }
+
+    /// Return the id of the running hart.
+    pub fn hart(&self) -> usize {
+        self.hart
+    }
+
+    /// Let each hart run `quantum` instructions before the next one takes its turn.
+    pub fn set_quantum(&mut self, quantum: u64) {
+        self.quantum = quantum.max(1);
+        self.next_switch = 0;
+    }
+
+    /// Exchange the state of the running hart with the one kept for hart `id`.
+    fn exchange(&mut self, id: usize) {
+        let hart = &mut self.parked[id];
+        mem::swap(&mut self.regs, &mut hart.regs);
+        mem::swap(&mut self.fregs, &mut hart.fregs);
+        mem::swap(&mut self.vregs, &mut hart.vregs);
+        mem::swap(&mut self.pc, &mut hart.pc);
+        mem::swap(&mut self.csr, &mut hart.csr);
+        mem::swap(&mut self.mode, &mut hart.mode);
+        mem::swap(&mut self.virt, &mut hart.virt);
+        mem::swap(&mut self.enable_paging, &mut hart.enable_paging);
+        mem::swap(&mut self.page_table, &mut hart.page_table);
+        mem::swap(&mut self.reservation, &mut hart.reservation);
+    }
+
+    /// Make hart `id` the running hart, e.g. to inspect it. Whose turn it is only depends on the
+    /// instruction count, so the next instruction is still executed by that hart.
+    pub fn switch_to(&mut self, id: usize) {
+        if id != self.hart && id < self.parked.len() {
+            self.exchange(self.hart);
+            self.exchange(id);
+            self.hart = id;
+        }
+        self.next_switch = 0;
+    }
+
+    /// Switch to the hart whose turn it is.
+    fn schedule(&mut self) {
+        let harts = self.parked.len() as u64;
+        if harts <= 1 {
+            self.next_switch = u64::MAX;
+            return;
+        }
+        let turn = self.icount / self.quantum;
+        self.switch_to((turn % harts) as usize);
+        self.next_switch = (turn + 1) * self.quantum;
+    }
+
+    /// Move every hart to a `Cpu` of its own that shares the bus, so that it can run on a host
+    /// thread. Hart 0 polls devices and advances the timer for all of them. `join` takes the
+    /// harts back.
+    pub fn split(&mut self) -> Vec<Cpu> {
+        self.exchange(self.hart);
+        let mut cpus: Vec<Cpu> = mem::take(&mut self.parked)
+            .into_iter()
+            .enumerate()
+            .map(|(id, hart)| Cpu {
+                regs: hart.regs,
+                fregs: hart.fregs,
+                vregs: hart.vregs,
+                pc: hart.pc,
+                bus: self.bus.share(),
+                csr: hart.csr,
+                mode: hart.mode,
+                virt: hart.virt,
+                guest_fault: 0,
+                enable_paging: hart.enable_paging,
+                page_table: hart.page_table,
+                reservation: hart.reservation,
+                hart: id,
+                parked: Vec::new(),
+                quantum: self.quantum,
+                next_switch: u64::MAX,
+                cache: DecodeCache::new(),
+                jit: self
+                    .jit
+                    .as_ref()
+                    .and_then(|jit| Jit::new(jit.compares()).ok())
+                    .map(Box::new),
+                commit: None,
+                icount: self.icount,
+                events: None,
+                isa: self.isa,
+            })
+            .collect();
+        mem::swap(&mut self.bus, &mut cpus[0].bus);
+        cpus
+    }
+
+    /// Take back the harts that `split` moved out. The instruction count grows by the
+    /// instructions all of them executed.
+    pub fn join(&mut self, cpus: Vec<Cpu>) {
+        let start = self.icount;
+        for mut cpu in cpus {
+            if cpu.hart == 0 {
+                mem::swap(&mut self.bus, &mut cpu.bus);
+            }
+            self.icount += cpu.icount - start;
+            self.parked.push(Hart {
+                regs: cpu.regs,
+                fregs: cpu.fregs,
+                vregs: cpu.vregs,
+                pc: cpu.pc,
+                csr: cpu.csr,
+                mode: cpu.mode,
+                virt: cpu.virt,
+                enable_paging: cpu.enable_paging,
+                page_table: cpu.page_table,
+                reservation: cpu.reservation,
+            });
+        }
+        self.bus.unshare();
+        self.exchange(self.hart);
+        self.next_switch = 0;
+    }
+
+    /// Execute instructions with `engine`. Fail if the JIT can't run on this host.
+    pub fn set_engine(&mut self, engine: Engine) -> io::Result<()> {
+        self.jit = match engine {
+            Engine::Interpreter => None,
+            Engine::Jit => Some(Box::new(Jit::new(false)?)),
+            Engine::Compare => Some(Box::new(Jit::new(true)?)),
+        };
+        Ok(())
+    }
+
+    /// Return the first difference found between the JIT and the interpreter with
+    /// `Engine::Compare`.
+    pub fn jit_mismatch(&self) -> Option<&str> {
+        self.jit.as_ref()?.mismatch()
+    }
+
+    /// Return true if the single-letter extension `bit` of misa is turned on.
+    #[inline]
+    pub(crate) fn enabled(&self, bit: u64) -> bool {
+        self.csr[MISA] & bit != 0
+    }
+
+    /// Return the value of the integer register `index`.
+    pub fn reg(&self, index: usize) -> u64 {
+        self.regs[index]
+    }
+
+    /// Set the integer register `index` to `value`. Writes to x0 are ignored.
+    pub fn set_reg(&mut self, index: usize, value: u64) {
+        if index != 0 {
+            self.regs[index] = value;
         }
     }
 
@@ -77,8 +454,8 @@ impl Cpu {
             " s6 ", " s7 ", " s8 ", " s9 ", " s10", " s11", " t3 ", " t4 ", " t5 ", " t6 ",
         ];
 
-        for i in 0..32 {
-            println!("x{:02}({})={:>#18x}", i, abi[i], self.regs[i],)
+        for (i, name) in abi.iter().enumerate() {
+            println!("x{:02}({})={:>#18x}", i, name, self.regs[i],)
         }
     }
 
@@ -94,10 +471,104 @@ impl Cpu {
         println!("mcause ={:>#18x}", self.load_csr(MCAUSE));
     }
 
+    /// Return true if floating-point instructions can run: F is turned on and mstatus.FS isn't
+    /// Off, nor vsstatus.FS in a guest.
+    pub(crate) fn fp_enabled(&self) -> bool {
+        self.enabled(MISA_F)
+            && self.csr[MSTATUS] & MSTATUS_FS != 0
+            && (!self.virt || self.csr[VSSTATUS] & MSTATUS_FS != 0)
+    }
+
+    /// Return true if vector instructions can run: the hart has a vector unit and mstatus.VS
+    /// isn't Off, nor vsstatus.VS in a guest.
+    pub(crate) fn vector_enabled(&self) -> bool {
+        self.isa.has(ZVE32X)
+            && self.csr[MSTATUS] & MSTATUS_VS != 0
+            && (!self.virt || self.csr[VSSTATUS] & MSTATUS_VS != 0)
+    }
+
+    /// Set the state field `field` of mstatus, `MSTATUS_FS` or `MSTATUS_VS`, to Dirty, and the one
+    /// of vsstatus too in a guest.
+    #[inline]
+    pub(crate) fn set_dirty(&mut self, field: u64) {
+        self.csr[MSTATUS] |= field | MSTATUS_SD;
+        if self.virt {
+            self.csr[VSSTATUS] |= field | MSTATUS_SD;
+        }
+    }
+
+    /// Return `value` for mstatus, or vsstatus, with the state fields of missing units and the
+    /// hypervisor fields without H read-only zero, and SD summarizing the state fields.
+    fn status(&self, mut value: u64) -> u64 {
+        if !self.isa.has(ZVE32X) {
+            value &= !MSTATUS_VS;
+        }
+        if self.isa.misa() & MISA_F == 0 {
+            value &= !MSTATUS_FS;
+        }
+        if self.isa.misa() & MISA_H == 0 {
+            value &= !(MSTATUS_MPV | MSTATUS_GVA);
+        }
+        let dirty = value & MSTATUS_FS == MSTATUS_FS || value & MSTATUS_VS == MSTATUS_VS;
+        (value & !MSTATUS_SD) | if dirty { MSTATUS_SD } else { 0 }
+    }
+
+    /// Check that the CSR at `address` can be accessed, and written if `write`. The
+    /// floating-point and vector CSRs are only accessible while their unit is on, and the
+    /// vector configuration can only be changed by vsetvl. The hypervisor and VS CSRs need H,
+    /// and a guest can only access the CSRs of its own mode and below. mseccfg decides who can
+    /// access seed.
+    fn check_csr(&self, address: usize, write: bool) -> Result<(), Exception> {
+        // Bits 9:8 of the address are the lowest privilege level that can access a CSR, where the
+        // hypervisor CSRs are at level 2.
+        let level = (address >> 8) & 0x3;
+        match address {
+            _ if level == 2 && !self.enabled(MISA_H) => Err(Exception::IllegalInstruction),
+            _ if self.virt && level == 3 => Err(Exception::IllegalInstruction),
+            _ if self.virt && (level == 2 || (level == 1 && matches!(self.mode, Mode::User))) => {
+                Err(Exception::VirtualInstruction)
+            }
+            SATP if self.virt && self.csr[HSTATUS] & HSTATUS_VTVM != 0 => {
+                Err(Exception::VirtualInstruction)
+            }
+            FFLAGS | FRM | FCSR if !self.fp_enabled() => Err(Exception::IllegalInstruction),
+            VSTART | VXSAT | VXRM | VCSR | VL | VTYPE | VLENB if !self.vector_enabled() => {
+                Err(Exception::IllegalInstruction)
+            }
+            VL | VTYPE | VLENB if write => Err(Exception::IllegalInstruction),
+            // seed can only be read together with a write, and below M mode only if mseccfg lets
+            // it. A guest's access can be emulated by the hypervisor if S mode may access it.
+            SEED if !self.isa.has(ZKR) || !write => Err(Exception::IllegalInstruction),
+            SEED if self.virt && self.csr[MSECCFG] & MSECCFG_SSEED != 0 => {
+                Err(Exception::VirtualInstruction)
+            }
+            SEED => match self.mode {
+                Mode::Machine => Ok(()),
+                Mode::Supervisor if !self.virt && self.csr[MSECCFG] & MSECCFG_SSEED != 0 => Ok(()),
+                Mode::User if !self.virt && self.csr[MSECCFG] & MSECCFG_USEED != 0 => Ok(()),
+                _ => Err(Exception::IllegalInstruction),
+            },
+            _ => Ok(()),
+        }
+    }
+
     /// Load the value from the CSR
     pub fn load_csr(&self, address: usize) -> u64 {
         match address {
             SIE => self.csr[MIE] & self.csr[MIDELEG],
+            SIP => self.csr[MIP] & self.csr[MIDELEG],
+            // The VS-level interrupts are always delegated to HS mode.
+            MIDELEG if self.enabled(MISA_H) => self.csr[MIDELEG] | MIP_VS,
+            HSTATUS => self.csr[HSTATUS] | HSTATUS_VSXL,
+            HIE => self.csr[MIE] & MIP_VS,
+            HIP | HVIP => self.csr[MIP] & MIP_VS,
+            // A guest sees the VS-level interrupts delegated to it as the supervisor ones.
+            VSIE => (self.csr[MIE] & self.csr[HIDELEG]) >> 1,
+            VSIP => (self.csr[MIP] & self.csr[HIDELEG]) >> 1,
+            SSTATUS => (self.csr[SSTATUS] & !SSTATUS_SHARED) | (self.csr[MSTATUS] & SSTATUS_SHARED),
+            FCSR => (self.csr[FRM] << 5) | self.csr[FFLAGS],
+            VCSR => (self.csr[VXRM] << 1) | self.csr[VXSAT],
+            SEED => self.seed(),
             _ => self.csr[address],
         }
     }
@@ -108,63 +579,147 @@ impl Cpu {
             SIE => {
                 self.csr[MIE] = (self.csr[MIE] & !self.csr[MIDELEG]) | (value & self.csr[MIDELEG])
             }
+            MISA => self.csr[MISA] = self.isa.write_misa(self.csr[MISA], value),
+            MSTATUS => self.csr[MSTATUS] = self.status(value),
+            VSSTATUS => self.csr[VSSTATUS] = self.status(value),
+            SSTATUS => {
+                self.csr[SSTATUS] = value & !SSTATUS_SHARED;
+                let shared = MSTATUS_FS | MSTATUS_VS;
+                self.csr[MSTATUS] = self.status((self.csr[MSTATUS] & !shared) | (value & shared));
+            }
+            FFLAGS | FRM | FCSR => {
+                if address == FCSR {
+                    self.csr[FFLAGS] = value & 0x1f;
+                    self.csr[FRM] = (value >> 5) & 0x7;
+                } else {
+                    self.csr[address] = value & if address == FFLAGS { 0x1f } else { 0x7 };
+                }
+                self.set_dirty(MSTATUS_FS);
+            }
+            VSTART | VXSAT | VXRM | VCSR => {
+                match address {
+                    VCSR => {
+                        self.csr[VXSAT] = value & 1;
+                        self.csr[VXRM] = (value >> 1) & 0x3;
+                    }
+                    VXSAT => self.csr[VXSAT] = value & 1,
+                    VXRM => self.csr[VXRM] = value & 0x3,
+                    _ => self.csr[VSTART] = value & (self.isa.vlen() as u64).wrapping_sub(1),
+                }
+                self.set_dirty(MSTATUS_VS);
+            }
+            // Writes to seed are ignored, and logging it would consume entropy.
+            SEED => return,
+            MSECCFG => self.csr[MSECCFG] = value & (MSECCFG_SSEED | MSECCFG_USEED),
+            // Machine-level interrupts can't be delegated.
+            MIDELEG => self.csr[MIDELEG] = value & MIP_S,
+            HSTATUS => self.csr[HSTATUS] = value & HSTATUS_WRITABLE,
+            HEDELEG => self.csr[HEDELEG] = value & HEDELEG_WRITABLE,
+            HIDELEG => self.csr[HIDELEG] = value & MIP_VS,
+            HIE => self.csr[MIE] = (self.csr[MIE] & !MIP_VS) | (value & MIP_VS),
+            HVIP => self.csr[MIP] = (self.csr[MIP] & !MIP_VS) | (value & MIP_VS),
+            // Only VSSIP is writable through hip, as an alias of the bit in hvip.
+            HIP => self.csr[MIP] = (self.csr[MIP] & !MIP_VSSIP) | (value & MIP_VSSIP),
+            VSIE => {
+                let mask = self.csr[HIDELEG];
+                self.csr[MIE] = (self.csr[MIE] & !mask) | ((value << 1) & mask)
+            }
+            VSIP => {
+                let mask = self.csr[HIDELEG] & MIP_VSSIP;
+                self.csr[MIP] = (self.csr[MIP] & !mask) | ((value << 1) & mask)
+            }
+            // The root of the G-stage page table is 16 KiB aligned.
+            HGATP => self.csr[HGATP] = value & !0x3,
+            // Only the supervisor software interrupt can be raised or cleared through sip.
+            SIP => {
+                let mask = self.csr[MIDELEG] & MIP_SSIP;
+                self.csr[MIP] = (self.csr[MIP] & !mask) | (value & mask)
+            }
             _ => self.csr[address] = value,
         }
+        if self.commit.is_some() {
+            let value = self.load_csr(address);
+            if let Some(commit) = &mut self.commit {
+                commit.write_csr(address, value);
+            }
+        }
+    }
+
+    /// Deliver pending input to the UART. Input is only ever delivered here, between two
+    /// instructions, so that recording the instruction count makes it reproducible.
+    fn deliver_input(&mut self) {
+        let id = match self.bus.console_id() {
+            Some(id) => id,
+            None => return,
+        };
+        let events = &mut self.events;
+        let icount = self.icount;
+        self.bus.with::<Uart, _>(id, |uart| {
+            // A replay delivers recorded input, so only skip the check when running live.
+            if events.is_none() && !uart.has_input() {
+                return;
+            }
+            if !uart.can_receive() {
+                return;
+            }
+            let byte = match events {
+                Some(events) => events.uart_input(icount, || uart.poll_input()),
+                None => uart.poll_input(),
+            };
+            if let Some(byte) = byte {
+                uart.receive(byte);
+            }
+        });
     }
 
     pub fn check_pending_interrupt(&mut self) -> Option<Interrupt> {
+        if self.bus.polls() {
+            self.deliver_input();
+            self.bus.update_timer(self.icount);
+        }
+
+        // The CLINT and the PLIC drive the software, timer and external interrupt bits of each
+        // hart. They aren't side effects of an instruction, so they're not traced.
+        self.csr[MIP] = (self.csr[MIP] & !MIP_LINES) | self.bus.lines(self.hart);
+
         match self.mode {
-            Mode::Machine => {
-                // Check if the MIE bit is enabled.
-                if (self.load_csr(MSTATUS) >> 3) & 1 == 0 {
-                    return None;
-                }
-            }
-            Mode::Supervisor => {
-                // Check if the SIE bit is enabled.
-                if (self.load_csr(SSTATUS) >> 1) & 1 == 0 {
-                    return None;
-                }
+            // Check if the MIE bit is enabled.
+            Mode::Machine if (self.load_csr(MSTATUS) >> 3) & 1 == 0 => return None,
+            // Check if the SIE bit is enabled. A guest's SIE only masks the interrupts delegated
+            // to it.
+            Mode::Supervisor if !self.virt && (self.load_csr(SSTATUS) >> 1) & 1 == 0 => {
+                return None
             }
             _ => {}
         }
 
-        // Check external interrupt for uart.
-        let irq;
-        if self.bus.uart.is_interrupting() {
-            irq = UART_IRQ;
-        } else if self.bus.virtio.is_interrupting() {
-            // Access disk by direct memory access (DMA). An interrupt is raised after a disk
-            // access is done.
-            Virtio::disk_access(self);
-            irq = VIRTIO_IRQ;
-        } else {
-            irq = 0;
+        // Check external interrupts from devices, which the bus routes to the PLIC.
+        if self.bus.polls() && self.bus.pending_irq().is_some() {
+            self.csr[MIP] = (self.csr[MIP] & !MIP_LINES) | self.bus.lines(self.hart);
         }
 
-        if irq != 0 {
-            self.bus
-                .store(PLIC_SCLAIM, 32, irq)
-                .expect("failed to write an IRQ to the PLIC_SCLAIM");
-            self.store_csr(MIP, self.load_csr(MIP) | MIP_SEIP);
+        let mut pending = self.load_csr(MIE) & self.load_csr(MIP);
+        // The interrupts delegated to a guest wait until it runs with them enabled.
+        let guest_enabled = match self.mode {
+            Mode::User => true,
+            _ => (self.load_csr(VSSTATUS) >> 1) & 1 == 1,
+        };
+        if !self.virt || !guest_enabled {
+            pending &= !self.load_csr(HIDELEG);
         }
 
-        let pending = self.load_csr(MIE) & self.load_csr(MIP);
-
+        // The bits driven by the interrupt controllers stay set until the cause is cleared
+        // there.
         if (pending & MIP_MEIP) != 0 {
-            self.store_csr(MIP, self.load_csr(MIP) & !MIP_MEIP);
             return Some(Interrupt::MachineExternalInterrupt);
         }
         if (pending & MIP_MSIP) != 0 {
-            self.store_csr(MIP, self.load_csr(MIP) & !MIP_MSIP);
             return Some(Interrupt::MachineSoftwareInterrupt);
         }
         if (pending & MIP_MTIP) != 0 {
-            self.store_csr(MIP, self.load_csr(MIP) & !MIP_MTIP);
             return Some(Interrupt::MachineTimerInterrupt);
         }
         if (pending & MIP_SEIP) != 0 {
-            self.store_csr(MIP, self.load_csr(MIP) & !MIP_SEIP);
             return Some(Interrupt::SupervisorExternalInterrupt);
         }
         if (pending & MIP_SSIP) != 0 {
@@ -175,6 +730,16 @@ impl Cpu {
             self.store_csr(MIP, self.load_csr(MIP) & !MIP_STIP);
             return Some(Interrupt::SupervisorTimerInterrupt);
         }
+        // The hypervisor clears the VS-level interrupts through hvip.
+        if (pending & MIP_VSEIP) != 0 {
+            return Some(Interrupt::VirtualSupervisorExternalInterrupt);
+        }
+        if (pending & MIP_VSSIP) != 0 {
+            return Some(Interrupt::VirtualSupervisorSoftwareInterrupt);
+        }
+        if (pending & MIP_VSTIP) != 0 {
+            return Some(Interrupt::VirtualSupervisorTimerInterrupt);
+        }
         None
     }
 
@@ -185,17 +750,67 @@ impl Cpu {
 
         self.page_table = (self.load_csr(SATP) & ((1 << 44) - 1)) * PAGE_SIZE;
         let mode = self.load_csr(SATP) >> 60;
-        if mode == 8 {
-            self.enable_paging = true;
-        } else {
-            self.enable_paging = false;
-        }
+        self.enable_paging = mode == 8;
     }
 
-    fn translate(&mut self, addr: u64, access_type: AccessType) -> Result<u64, Exception> {
+    pub(crate) fn translate(
+        &mut self,
+        addr: u64,
+        access_type: AccessType,
+    ) -> Result<u64, Exception> {
+        if self.virt {
+            return self.translate_guest(addr, access_type, access_type);
+        }
         if !self.enable_paging {
             return Ok(addr);
         }
+        self.walk(
+            self.page_table,
+            addr,
+            access_type,
+            access_type,
+            Stage::Supervisor,
+        )
+    }
+
+    /// Return the page fault of `stage` for `access_type`. A fault of the G-stage is a
+    /// guest-page fault, which remembers the guest physical address `addr`.
+    fn page_fault(&mut self, addr: u64, access_type: AccessType, stage: Stage) -> Exception {
+        if stage == Stage::Guest {
+            self.guest_fault = addr;
+            return match access_type {
+                AccessType::Instruction => Exception::InstructionGuestPageFault,
+                AccessType::Load => Exception::LoadGuestPageFault,
+                AccessType::Store => Exception::StoreAMOGuestPageFault,
+            };
+        }
+        match access_type {
+            AccessType::Instruction => Exception::InstructionPageFault,
+            AccessType::Load => Exception::LoadPageFault,
+            AccessType::Store => Exception::StoreAMOPageFault,
+        }
+    }
+
+    /// Translate `addr` by one `stage` with the page table at `root`. The leaf must permit
+    /// `permission`, which differs from `access_type` for an HLVX, that reads executable pages,
+    /// and for the reads of a VS-stage page table.
+    pub(crate) fn walk(
+        &mut self,
+        root: u64,
+        addr: u64,
+        access_type: AccessType,
+        permission: AccessType,
+        stage: Stage,
+    ) -> Result<u64, Exception> {
+        // Sv39x4 widens the guest physical address by 2 bits, which the root page table of 16 KiB
+        // instead of 4 KiB indexes.
+        let vpn2_mask = match stage {
+            Stage::Guest if addr >> 41 != 0 => {
+                return Err(self.page_fault(addr, access_type, stage))
+            }
+            Stage::Guest => 0x7ff,
+            _ => 0x1ff,
+        };
 
         // The following comments are cited from 4.3.2 Virtual Address Translation Process
         // in "The RISC-V Instruction Set Manual Volume II-Privileged Architecture_20190608".
@@ -205,19 +820,28 @@ impl Cpu {
         let vpn = [
             (addr >> 12) & 0x1ff,
             (addr >> 21) & 0x1ff,
-            (addr >> 30) & 0x1ff,
+            (addr >> 30) & vpn2_mask,
         ];
 
         // "1. Let a be satp.ppn × PAGESIZE, and let i = LEVELS − 1. (For Sv32, PAGESIZE=212
         //     and LEVELS=2.)"
-        let mut a = self.page_table;
+        let mut a = root;
         let mut i: i64 = levels - 1;
         let mut pte;
         loop {
             // "2. Let pte be the value of the PTE at address a+va.vpn[i]×PTESIZE. (For Sv32,
             //     PTESIZE=4.) If accessing pte violates a PMA or PMP check, raise an access
             //     exception corresponding to the original access type."
-            pte = self.bus.load(a + vpn[i as usize] * 8, 64)?;
+            // The page table of the VS-stage is at guest physical addresses, which the G-stage
+            // translates. It must let them be read, but a fault is one of the original access.
+            let pte_addr = a + vpn[i as usize] * 8;
+            let pte_addr = match stage {
+                Stage::VirtualSupervisor => {
+                    self.translate_physical(pte_addr, access_type, AccessType::Load)?
+                }
+                _ => pte_addr,
+            };
+            pte = self.bus.load(pte_addr, 64)?;
 
             // "3. If pte.v = 0, or if pte.r = 0 and pte.w = 1, stop and raise a page-fault
             //     exception corresponding to the original access type."
@@ -226,11 +850,7 @@ impl Cpu {
             let w = (pte >> 2) & 1;
             let x = (pte >> 3) & 1;
             if v == 0 || (r == 0 && w == 1) {
-                match access_type {
-                    AccessType::Instruction => return Err(Exception::InstructionPageFault),
-                    AccessType::Load => return Err(Exception::LoadPageFault),
-                    AccessType::Store => return Err(Exception::StoreAMOPageFault),
-                }
+                return Err(self.page_fault(addr, access_type, stage));
             }
 
             // "4. Otherwise, the PTE is valid. If pte.r = 1 or pte.x = 1, go to step 5.
@@ -245,11 +865,7 @@ impl Cpu {
             let ppn = (pte >> 10) & 0x0fff_ffff_ffff;
             a = ppn * PAGE_SIZE;
             if i < 0 {
-                match access_type {
-                    AccessType::Instruction => return Err(Exception::InstructionPageFault),
-                    AccessType::Load => return Err(Exception::LoadPageFault),
-                    AccessType::Store => return Err(Exception::StoreAMOPageFault),
-                }
+                return Err(self.page_fault(addr, access_type, stage));
             }
         }
 
@@ -260,12 +876,35 @@ impl Cpu {
             (pte >> 28) & 0x03ff_ffff,
         ];
 
-        // We skip implementing from step 5 to 7.
-
         // "5. A leaf PTE has been found. Determine if the requested dram access is allowed by
         //     the pte.r, pte.w, pte.x, and pte.u bits, given the current privilege mode and the
         //     value of the SUM and MXR fields of the mstatus register. If not, stop and raise a
         //     page-fault exception corresponding to the original access type."
+        // Only the G-stage checks the permissions, where every access counts as a U-mode one.
+        // The other stages only check that an HLVX reads an executable page. MXR is in
+        // mstatus or in sstatus, which keeps its own copy.
+        let mxr = (self.csr[MSTATUS] | self.csr[SSTATUS]) & MSTATUS_MXR != 0;
+        let (r, w, x, u) = (
+            (pte >> 1) & 1 == 1,
+            (pte >> 2) & 1 == 1,
+            (pte >> 3) & 1 == 1,
+            (pte >> 4) & 1 == 1,
+        );
+        let allowed = match permission {
+            AccessType::Instruction => x,
+            AccessType::Load => r || (mxr && x),
+            AccessType::Store => w,
+        };
+        let permitted = match stage {
+            Stage::Guest => u && allowed,
+            _ if permission != access_type => allowed,
+            _ => true,
+        };
+        if !permitted {
+            return Err(self.page_fault(addr, access_type, stage));
+        }
+
+        // We skip implementing steps 6 and 7.
 
         // "6. If i > 0 and pte.ppn[i − 1 : 0] ̸= 0, this is a misaligned superpage; stop and
         //     raise a page-fault exception corresponding to the original access type."
@@ -300,24 +939,120 @@ impl Cpu {
                 // ordinary page (4 KiB). It reduces TLB misses and improves performance.
                 Ok((ppn[2] << 30) | (vpn[1] << 21) | (vpn[0] << 12) | offset)
             }
-            _ => match access_type {
-                AccessType::Instruction => return Err(Exception::InstructionPageFault),
-                AccessType::Load => return Err(Exception::LoadPageFault),
-                AccessType::Store => return Err(Exception::StoreAMOPageFault),
-            },
+            _ => Err(self.page_fault(addr, access_type, stage)),
         }
     }
 
     /// Load a value from a memory.
     pub fn load(&mut self, addr: u64, size: usize) -> Result<u64, Exception> {
         let p_addr = self.translate(addr, AccessType::Load)?;
-        self.bus.load(p_addr, size)
+        let value = self.bus.load(p_addr, size)?;
+        if let Some(commit) = &mut self.commit {
+            commit.loads.push((addr, size));
+        }
+        Ok(value)
     }
 
     /// Store a value to a memory.
     pub fn store(&mut self, addr: u64, size: usize, value: u64) -> Result<(), Exception> {
         let p_addr = self.translate(addr, AccessType::Store)?;
-        self.bus.store(p_addr, size, value)
+        self.bus.store(p_addr, size, value)?;
+        if let Some(commit) = &mut self.commit {
+            commit.write_mem(addr, value, size);
+        }
+        Ok(())
+    }
+
+    /// Read a byte of RAM at the virtual address `addr` on behalf of a debugger. The access is
+    /// not traced and device registers can't be read, so that it has no side effects.
+    pub fn peek(&mut self, addr: u64) -> Result<u8, Exception> {
+        let p_addr = self.translate(addr, AccessType::Load)?;
+        if !(MEMORY_BASE..MEMORY_BASE + self.bus.memory.size()).contains(&p_addr) {
+            return Err(Exception::LoadAccessFault);
+        }
+        self.bus.load(p_addr, 8).map(|value| value as u8)
+    }
+
+    /// Write a byte of RAM at the virtual address `addr` on behalf of a debugger.
+    pub fn poke(&mut self, addr: u64, value: u8) -> Result<(), Exception> {
+        let p_addr = self.translate(addr, AccessType::Store)?;
+        if !(MEMORY_BASE..MEMORY_BASE + self.bus.memory.size()).contains(&p_addr) {
+            return Err(Exception::StoreAMOAccessFault);
+        }
+        self.bus.store(p_addr, 8, value as u64)
+    }
+
+    /// Atomically replace the value at `addr` with `f` of it, unless `f` returns `None`. Return
+    /// the old value and whether it was replaced.
+    fn amo(
+        &mut self,
+        addr: u64,
+        size: usize,
+        mut f: impl FnMut(u64) -> Option<u64>,
+    ) -> Result<(u64, bool), Exception> {
+        if !addr.is_multiple_of(size as u64 / 8) {
+            return Err(Exception::StoreAMOAddressMisaligned);
+        }
+        let p_addr = self.translate(addr, AccessType::Store)?;
+        let mut new = 0;
+        let (old, stored) = self.bus.update(p_addr, size, |old| {
+            let value = f(old)?;
+            new = value;
+            Some(value)
+        })?;
+        if let Some(commit) = &mut self.commit {
+            commit.loads.push((addr, size));
+            if stored {
+                commit.write_mem(addr, new, size);
+            }
+        }
+        Ok((old, stored))
+    }
+
+    /// Load the value at `addr` and reserve it for a store-conditional.
+    fn load_reserved(&mut self, addr: u64, size: usize) -> Result<u64, Exception> {
+        if !addr.is_multiple_of(size as u64 / 8) {
+            return Err(Exception::LoadAddressMisaligned);
+        }
+        let p_addr = self.translate(addr, AccessType::Load)?;
+        let value = self.bus.load(p_addr, size)?;
+        if let Some(commit) = &mut self.commit {
+            commit.loads.push((addr, size));
+        }
+        self.reservation = Some(Reservation {
+            address: p_addr,
+            size,
+            value,
+        });
+        Ok(value)
+    }
+
+    /// Store `value` at `addr` if it's reserved and still holds the value that was loaded, and
+    /// return whether it was stored. Any store-conditional ends the reservation.
+    fn store_conditional(&mut self, addr: u64, size: usize, value: u64) -> Result<bool, Exception> {
+        if !addr.is_multiple_of(size as u64 / 8) {
+            return Err(Exception::StoreAMOAddressMisaligned);
+        }
+        let p_addr = self.translate(addr, AccessType::Store)?;
+        let reservation = match self.reservation.take() {
+            Some(reservation) if reservation.address == p_addr && reservation.size == size => {
+                reservation
+            }
+            _ => return Ok(false),
+        };
+        let (_, stored) = self.bus.update(p_addr, size, |old| {
+            if old == reservation.value {
+                Some(value)
+            } else {
+                None
+            }
+        })?;
+        if stored {
+            if let Some(commit) = &mut self.commit {
+                commit.write_mem(addr, value, size);
+            }
+        }
+        Ok(stored)
     }
 
     /// Fetch the instruction from memory.
@@ -329,8 +1064,162 @@ impl Cpu {
         }
     }
 
+    /// Fetch and decode the instruction at the pc. Instructions in RAM come from the decode
+    /// cache.
+    #[inline]
+    fn fetch_decoded(&mut self) -> Result<Decoded, Exception> {
+        let p_pc = self.translate(self.pc, AccessType::Instruction)?;
+        let memory = &self.bus.memory;
+        if p_pc < MEMORY_BASE || p_pc - MEMORY_BASE >= memory.size() {
+            return match self.bus.load(p_pc, 32) {
+                Ok(v) => Ok(decode(v as u32)),
+                Err(_) => Err(Exception::InstructionAccessFault),
+            };
+        }
+        match self.cache.get(memory, p_pc) {
+            Some(decoded) => Ok(decoded),
+            None => self
+                .cache
+                .fill(memory, p_pc)
+                .map_err(|_| Exception::InstructionAccessFault),
+        }
+    }
+
+    /// Run one instruction cycle: take a pending interrupt, then fetch, decode and execute an
+    /// instruction. Return the pc and the word of the instruction if it retired, `None` if it
+    /// trapped, or the exception if the trap is fatal.
+    #[inline]
+    pub fn step(&mut self) -> Result<Option<(u64, u32)>, Exception> {
+        self.begin_cycle();
+        self.execute_next()
+    }
+
+    /// Run one instruction cycle like `step`, but let the JIT execute a whole block if it has
+    /// one at the pc that ends before instruction `end`. Return `None` if a block was executed.
+    #[inline]
+    pub fn step_block(&mut self, end: u64) -> Result<Option<(u64, u32)>, Exception> {
+        self.begin_cycle();
+        if let Some(mut jit) = self.jit.take() {
+            let budget = self.budget(end);
+            let ran = jit.run(self, budget);
+            self.jit = Some(jit);
+            if ran {
+                return Ok(None);
+            }
+        }
+        self.execute_next()
+    }
+
+    /// Return how many instructions can be executed before instruction `end`, the next hart's
+    /// turn or the timer raising an interrupt line, whichever comes first.
+    fn budget(&self, end: u64) -> u64 {
+        let mut end = end.min(self.next_switch);
+        if self.bus.polls() {
+            end = end.min(self.bus.timer_deadline());
+        }
+        end.saturating_sub(self.icount)
+    }
+
+    /// Switch to the hart whose turn it is and take a pending interrupt, before an instruction.
+    #[inline]
+    fn begin_cycle(&mut self) {
+        if self.icount >= self.next_switch {
+            self.schedule();
+        }
+        if let Some(interrupt) = self.check_pending_interrupt() {
+            interrupt.get_trap(self);
+        }
+        if let Some(commit) = &mut self.commit {
+            commit.clear();
+        }
+    }
+
+    /// Fetch, decode and execute the instruction at the pc. Return like `step`.
+    #[inline]
+    pub(crate) fn execute_next(&mut self) -> Result<Option<(u64, u32)>, Exception> {
+        // Fetch instruction
+        let pc = self.pc;
+        let fetched = self.fetch_decoded();
+
+        // Add 4 to the program counter
+        self.pc = self.pc.wrapping_add(4);
+        self.icount += 1;
+
+        // Decode & Execute
+        let result = match fetched {
+            Ok(decoded) => self.execute_decoded(&decoded).map(|_| decoded.inst),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(inst) => Ok(Some((pc, inst))),
+            Err(e) => {
+                e.get_trap(self);
+                if e.is_fatal() {
+                    return Err(e);
+                }
+                Ok(None)
+            }
+        }
+    }
+
     /// Decode and execute an instruction.
     pub fn decode_execute(&mut self, inst: u32) -> Result<(), Exception> {
+        self.execute_decoded(&decode(inst))
+    }
+
+    /// Execute a decoded instruction.
+    #[inline]
+    fn execute_decoded(&mut self, decoded: &Decoded) -> Result<(), Exception> {
+        // Emulate that register x0 is hardwired with all bits equal to 0.
+        self.regs[0] = 0;
+        decoded.execute(self)?;
+        self.regs[0] = 0;
+        if let Some(commit) = &mut self.commit {
+            if let Some(rd) = destination(decoded.inst) {
+                commit.write_reg(rd, self.regs[rd]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute a bit-manipulation or scalar cryptography instruction, which share their opcodes
+    /// with the integer computational instructions, if the hart implements its extension.
+    fn execute_bitmanip(&mut self, inst: u32) -> Result<(), Exception> {
+        let opcode = inst & 0x0000007f;
+        let rd = ((inst & 0x00000f80) >> 7) as usize;
+        let rs1 = ((inst & 0x000f8000) >> 15) as usize;
+        let rs2 = ((inst & 0x01f00000) >> 20) as usize;
+        let (a, b) = (self.regs[rs1], self.regs[rs2]);
+        let (extension, result) = match (bitmanip::decode(inst), crypto::decode(inst)) {
+            (Some(op), _) => {
+                let b = if op.is_immediate() {
+                    (inst >> 20) as u64
+                } else {
+                    b
+                };
+                (op.extension(), op.execute(a, b))
+            }
+            (None, Some(op)) => (op.extension(), op.execute(a, b)),
+            (None, None) => {
+                println!(
+                    "Unsupported instruction: opcode {:x} funct3 {:x} funct7 {:x}",
+                    opcode,
+                    (inst & 0x00007000) >> 12,
+                    (inst & 0xfe000000) >> 25
+                );
+                return Err(Exception::IllegalInstruction);
+            }
+        };
+        if !self.isa.has(extension) {
+            return Err(Exception::IllegalInstruction);
+        }
+        self.regs[rd] = result;
+        Ok(())
+    }
+
+    /// Execute an instruction from its word. The handlers of decoded instructions fall back to
+    /// this for the instructions they don't implement.
+    pub(crate) fn execute(&mut self, inst: u32) -> Result<(), Exception> {
         let opcode = inst & 0x0000007f;
         let rd = ((inst & 0x00000f80) >> 7) as usize;
         let rs1 = ((inst & 0x000f8000) >> 15) as usize;
@@ -389,14 +1278,30 @@ impl Cpu {
                     }
                 }
             }
+            0x07 | 0x27 if funct3 == 0x2 || funct3 == 0x3 => return self.execute_fp(inst),
+            // The other widths of LOAD-FP and STORE-FP are vector loads and stores.
+            0x07 | 0x27 => return self.execute_vector(inst),
             0x0f => {
-                // A fence instruction does nothing because this emulator executes an
-                // instruction sequentially on a single thread.
+                // A fence orders the memory accesses of harts on other host threads. Harts that
+                // take turns see each other's accesses in order anyway.
                 match funct3 {
-                    0x0 => {} // fence
+                    0x0 => fence(Ordering::SeqCst), // fence
+                    0x1 => {
+                        // fence.i
+                        // Stores of this hart and DMA of devices invalidate the decoded and
+                        // translated instructions of their page as they happen. A store of a hart
+                        // on another host thread may race with decoding the page, though, so
+                        // fence.i also forgets what this hart has cached: every instruction is
+                        // decoded again, and every block compared with memory, before it runs.
+                        fence(Ordering::SeqCst);
+                        self.cache.flush();
+                        if let Some(jit) = &mut self.jit {
+                            jit.invalidate();
+                        }
+                    }
                     _ => {
                         println!(
-                            "not implemented yet: opcode {:#x} funct3 {:#x}",
+                            "Unsupported instruction: opcode {:#x} funct3 {:#x}",
                             opcode, funct3
                         );
                         return Err(Exception::IllegalInstruction);
@@ -410,7 +1315,7 @@ impl Cpu {
                     // ADDI
                     0x0 => self.regs[rd] = self.regs[rs1].wrapping_add(imm),
                     // SLLI
-                    0x1 => self.regs[rd] = self.regs[rs1] << shamt,
+                    0x1 if funct7 >> 1 == 0x00 => self.regs[rd] = self.regs[rs1] << shamt,
                     // SLTI
                     0x2 => self.regs[rd] = ((self.regs[rs1] as i64) < (imm as i64)) as u64,
                     // SLTIU
@@ -422,13 +1327,13 @@ impl Cpu {
                         0x00 => self.regs[rd] = self.regs[rs1].wrapping_shr(shamt),
                         // SRAI
                         0x10 => self.regs[rd] = (self.regs[rs1] as i64).wrapping_shr(shamt) as u64,
-                        _ => (),
+                        _ => return self.execute_bitmanip(inst),
                     },
                     // ORI
                     0x6 => self.regs[rd] = self.regs[rs1] | imm,
                     // ANDI
                     0x7 => self.regs[rd] = self.regs[rs1] & imm,
-                    _ => (),
+                    _ => return self.execute_bitmanip(inst),
                 }
             }
             // AUIPC
@@ -443,7 +1348,9 @@ impl Cpu {
                     // ADDIW
                     0x0 => self.regs[rd] = self.regs[rs1].wrapping_add(imm) as i32 as i64 as u64,
                     // SLLIW
-                    0x1 => self.regs[rd] = self.regs[rs1].wrapping_shl(shamnt) as i32 as i64 as u64,
+                    0x1 if funct7 == 0x00 => {
+                        self.regs[rd] = self.regs[rs1].wrapping_shl(shamnt) as i32 as i64 as u64
+                    }
                     0x5 => {
                         match funct7 {
                             // SRLIW
@@ -456,22 +1363,10 @@ impl Cpu {
                                 self.regs[rd] =
                                     (self.regs[rs1] as i32).wrapping_shr(shamnt) as i64 as u64
                             }
-                            _ => {
-                                println!(
-                                    "Unsupported instruction: opcode {:x} funct3 {:x} funct7 {:x}",
-                                    opcode, funct3, funct7
-                                );
-                                return Err(Exception::IllegalInstruction);
-                            }
+                            _ => return self.execute_bitmanip(inst),
                         }
                     }
-                    _ => {
-                        println!(
-                            "Unsupported instruction: opcode {:x} funct3 {:x}",
-                            opcode, funct3
-                        );
-                        return Err(Exception::IllegalInstruction);
-                    }
+                    _ => return self.execute_bitmanip(inst),
                 }
             }
             0x23 => {
@@ -491,44 +1386,57 @@ impl Cpu {
                 }
             }
             // RV64A: "A" standard extension for atomic instructions
+            0x2f if !self.enabled(MISA_A) => return Err(Exception::IllegalInstruction),
             0x2f => {
                 let funct5 = (funct7 & 0x7c) >> 2;
-                let _aq = (funct7 & 0x02) >> 1;
-                let _rl = funct7 & 0x01;
-
-                match (funct3, funct5) {
-                    // AMOADD.W
-                    (0x2, 0x00) => {
-                        let tmp = self.load(self.regs[rs1], 32)?;
-                        self.store(
-                            self.regs[rs1],
-                            32,
-                            tmp.wrapping_add(self.regs[rs2]),
-                        )?;
-                        self.regs[rd] = tmp;
-                    }
-                    // AMOADD.D
-                    (0x3, 0x00) => {
-                        let tmp = self.load(self.regs[rs1], 64)?;
-                        self.store(
-                            self.regs[rs1],
-                            64,
-                            tmp.wrapping_add(self.regs[rs2]),
-                        )?;
-                        self.regs[rd] = tmp;
+                // The aq and rl bits need no handling: every AMO is sequentially consistent.
+                let size = match funct3 {
+                    0x2 => 32,
+                    0x3 => 64,
+                    _ => {
+                        println!(
+                            "Unsupported instruction: opcode {:x} funct3 {:x} funct7 {:x}",
+                            opcode, funct3, funct7
+                        );
+                        return Err(Exception::IllegalInstruction);
                     }
-                    // AMOSWAP.W
-                    (0x2, 0x01) => {
-                        let tmp = self.load(self.regs[rs1], 32)?;
-                        self.store(self.regs[rs1], 32, self.regs[rs2])?;
-                        self.regs[rd] = tmp;
+                };
+                // Word operations work on the sign-extended low 32 bits.
+                let extend = |value: u64| match size {
+                    32 => value as i32 as i64 as u64,
+                    _ => value,
+                };
+                let addr = self.regs[rs1];
+                let src = extend(self.regs[rs2]);
+                let op: fn(u64, u64) -> u64 = match funct5 {
+                    // LR.W, LR.D
+                    0x02 => {
+                        self.regs[rd] = extend(self.load_reserved(addr, size)?);
+                        return Ok(());
                     }
-                    // AMOSWAP.D
-                    (0x3, 0x01) => {
-                        let tmp = self.load(self.regs[rs1], 64)?;
-                        self.store(self.regs[rs1], 64, self.regs[rs2])?;
-                        self.regs[rd] = tmp;
+                    // SC.W, SC.D
+                    0x03 => {
+                        self.regs[rd] = !self.store_conditional(addr, size, src)? as u64;
+                        return Ok(());
                     }
+                    // AMOADD.W, AMOADD.D
+                    0x00 => |old, src| old.wrapping_add(src),
+                    // AMOSWAP.W, AMOSWAP.D
+                    0x01 => |_, src| src,
+                    // AMOXOR.W, AMOXOR.D
+                    0x04 => |old, src| old ^ src,
+                    // AMOOR.W, AMOOR.D
+                    0x08 => |old, src| old | src,
+                    // AMOAND.W, AMOAND.D
+                    0x0c => |old, src| old & src,
+                    // AMOMIN.W, AMOMIN.D
+                    0x10 => |old, src| (old as i64).min(src as i64) as u64,
+                    // AMOMAX.W, AMOMAX.D
+                    0x14 => |old, src| (old as i64).max(src as i64) as u64,
+                    // AMOMINU.W, AMOMINU.D
+                    0x18 => |old, src| old.min(src),
+                    // AMOMAXU.W, AMOMAXU.D
+                    0x1c => |old, src| old.max(src),
                     _ => {
                         println!(
                             "Unsupported instruction: opcode {:x} funct3 {:x} funct7 {:x}",
@@ -536,10 +1444,16 @@ impl Cpu {
                         );
                         return Err(Exception::IllegalInstruction);
                     }
-                }
+                };
+                let (old, _) = self.amo(addr, size, |old| Some(op(extend(old), src)))?;
+                self.regs[rd] = extend(old);
+            }
+            // RV64M: "M" standard extension for integer multiplication and division
+            0x33 | 0x3b if funct7 == 0x01 && !self.enabled(MISA_M) => {
+                return Err(Exception::IllegalInstruction)
             }
             0x33 => {
-                let shamt = ((self.regs[rs2] & 0x3f) as u64) as u32;
+                let shamt = (self.regs[rs2] & 0x3f) as u32;
                 match (funct3, funct7) {
                     // ADD
                     (0x0, 0x00) => self.regs[rd] = self.regs[rs1].wrapping_add(self.regs[rs2]),
@@ -574,13 +1488,7 @@ impl Cpu {
                     (0x6, 0x00) => self.regs[rd] = self.regs[rs1] | self.regs[rs2],
                     // AND
                     (0x7, 0x00) => self.regs[rd] = self.regs[rs1] & self.regs[rs2],
-                    _ => {
-                        println!(
-                            "Unsupported instruction: opcode {:x} funct3 {:x} funct7 {:x}",
-                            opcode, funct3, funct7
-                        );
-                        return Err(Exception::IllegalInstruction);
-                    }
+                    _ => return self.execute_bitmanip(inst),
                 }
             }
             // LUI
@@ -625,15 +1533,11 @@ impl Cpu {
                                 as u64,
                         };
                     }
-                    _ => {
-                        println!(
-                            "Unsupported instruction: opcode {:x} funct3 {:x} funct7 {:x}",
-                            opcode, funct3, funct7
-                        );
-                        return Err(Exception::IllegalInstruction);
-                    }
+                    _ => return self.execute_bitmanip(inst),
                 }
             }
+            0x43 | 0x47 | 0x4b | 0x4f | 0x53 => return self.execute_fp(inst),
+            0x57 => return self.execute_vector(inst),
             0x63 => {
                 let imm = ((inst & 0x80000000) as i32 as i64 >> 19) as u64
                     | ((inst >> 20) & 0x7e0) as u64
@@ -702,8 +1606,17 @@ impl Cpu {
                     | (inst & 0xff000) as u64;
                 self.pc = self.pc.wrapping_sub(4).wrapping_add(imm);
             }
+            // HLV, HLVX and HSV
+            0x73 if funct3 == 0x4 => return self.execute_hypervisor(inst),
             0x73 => {
                 let address = ((inst & 0xfff00000) >> 20) as usize;
+                if funct3 != 0x0 {
+                    // CSRRS and CSRRC, and their immediate forms, don't write the CSR if rs1 or
+                    // the immediate is 0.
+                    let write = funct3 & 0x3 == 0x1 || rs1 != 0;
+                    self.check_csr(address, write)?;
+                }
+                let address = self.guest_csr(address);
                 match funct3 {
                     0x0 => {
                         match (rs2, funct7) {
@@ -712,6 +1625,9 @@ impl Cpu {
                                 Mode::User => {
                                     return Err(Exception::EnvironmentCallFromUMode);
                                 }
+                                Mode::Supervisor if self.virt => {
+                                    return Err(Exception::EnvironmentCallFromVSMode);
+                                }
                                 Mode::Supervisor => {
                                     return Err(Exception::EnvironmentCallFromSMode);
                                 }
@@ -724,21 +1640,38 @@ impl Cpu {
                                 return Err(Exception::Breakpoint);
                             }
                             // SRET
+                            (0x2, 0x8)
+                                if self.virt
+                                    && (matches!(self.mode, Mode::User)
+                                        || self.csr[HSTATUS] & HSTATUS_VTSR != 0) =>
+                            {
+                                return Err(Exception::VirtualInstruction);
+                            }
                             (0x2, 0x8) => {
-                                self.pc = self.load_csr(SEPC);
+                                // A guest returns with its own copies of sepc and sstatus.
+                                let (epc, status) = match self.virt {
+                                    true => (VSEPC, VSSTATUS),
+                                    false => (SEPC, SSTATUS),
+                                };
+                                self.pc = self.load_csr(epc);
 
-                                let sstatus = self.load_csr(SSTATUS);
+                                let sstatus = self.load_csr(status);
                                 self.mode = match (sstatus >> 8) & 1 {
                                     1 => Mode::Supervisor,
                                     _ => Mode::User,
                                 };
+                                // HS mode returns to a guest if the trap came from one.
+                                if !self.virt && self.csr[HSTATUS] & HSTATUS_SPV != 0 {
+                                    self.virt = true;
+                                    self.store_csr(HSTATUS, self.csr[HSTATUS] & !HSTATUS_SPV);
+                                }
 
                                 match (sstatus >> 5) & 1 {
-                                    1 => self.store_csr(SSTATUS, sstatus | (1 << 1)),
-                                    _ => self.store_csr(SSTATUS, sstatus & !(1 << 1)),
+                                    1 => self.store_csr(status, sstatus | (1 << 1)),
+                                    _ => self.store_csr(status, sstatus & !(1 << 1)),
                                 }
-                                self.store_csr(SSTATUS, self.load_csr(SSTATUS) | (1 << 5));
-                                self.store_csr(SSTATUS, self.load_csr(SSTATUS) & !(1 << 8));
+                                self.store_csr(status, self.load_csr(status) | (1 << 5));
+                                self.store_csr(status, self.load_csr(status) & !(1 << 8));
                             }
                             // MRET
                             (0x2, 0x18) => {
@@ -746,10 +1679,13 @@ impl Cpu {
 
                                 let mstatus = self.load_csr(MSTATUS);
                                 self.mode = match (mstatus >> 11) & 0b11 {
-                                    2 => Mode::Machine,
+                                    3 => Mode::Machine,
                                     1 => Mode::Supervisor,
                                     _ => Mode::User,
                                 };
+                                // MPV returns to a guest, unless to M mode.
+                                self.virt = mstatus & MSTATUS_MPV != 0
+                                    && !matches!(self.mode, Mode::Machine);
 
                                 match (mstatus >> 7) & 1 {
                                     1 => self.store_csr(MSTATUS, mstatus | (1 << 3)),
@@ -757,10 +1693,22 @@ impl Cpu {
                                 }
 
                                 self.store_csr(MSTATUS, self.load_csr(MSTATUS) | (1 << 7));
-                                self.store_csr(MSTATUS, self.load_csr(MSTATUS) & !(3 << 11));
+                                self.store_csr(
+                                    MSTATUS,
+                                    self.load_csr(MSTATUS) & !((3 << 11) | MSTATUS_MPV),
+                                );
                             }
                             // SFENCE.VMA
+                            (_, 0x9)
+                                if self.virt
+                                    && (matches!(self.mode, Mode::User)
+                                        || self.csr[HSTATUS] & HSTATUS_VTVM != 0) =>
+                            {
+                                return Err(Exception::VirtualInstruction);
+                            }
                             (_, 0x9) => (),
+                            // HFENCE.VVMA, HFENCE.GVMA
+                            (_, 0x11) | (_, 0x31) => return self.execute_hypervisor(inst),
                             _ => {
                                 println!(
                                     "Unsupported instruction: opcode {:x} funct3 {:x} funct7 {:x}",
@@ -780,14 +1728,20 @@ impl Cpu {
                     // CSRRS
                     0x2 => {
                         let tmp = self.load_csr(address);
-                        self.store_csr(address, tmp | self.regs[rs1]);
+                        // A CSR isn't written at all when rs1 is x0.
+                        if rs1 != 0 {
+                            self.store_csr(address, tmp | self.regs[rs1]);
+                        }
                         self.regs[rd] = tmp;
                         self.update_paging(address);
                     }
                     // CSRRC
                     0x3 => {
                         let tmp = self.load_csr(address);
-                        self.store_csr(address, tmp & !self.regs[rs1]);
+                        // A CSR isn't written at all when rs1 is x0.
+                        if rs1 != 0 {
+                            self.store_csr(address, tmp & !self.regs[rs1]);
+                        }
                         self.regs[rd] = tmp;
                         self.update_paging(address);
                     }
@@ -802,7 +1756,9 @@ impl Cpu {
                     0x6 => {
                         let uimm = rs1 as u64;
                         let tmp = self.load_csr(address);
-                        self.store_csr(address, tmp | uimm);
+                        if uimm != 0 {
+                            self.store_csr(address, tmp | uimm);
+                        }
                         self.regs[rd] = tmp;
                         self.update_paging(address);
                     }
@@ -810,7 +1766,9 @@ impl Cpu {
                     0x7 => {
                         let uimm = rs1 as u64;
                         let tmp = self.load_csr(address);
-                        self.store_csr(address, tmp & !uimm);
+                        if uimm != 0 {
+                            self.store_csr(address, tmp & !uimm);
+                        }
                         self.regs[rd] = tmp;
                         self.update_paging(address);
                     }
@@ -831,3 +1789,176 @@ impl Cpu {
         Ok(())
     }
 }
+
+impl Snapshot for Cpu {
+    fn save(&self, w: &mut Writer) -> io::Result<()> {
+        w.put_u64(self.parked.len() as u64);
+        w.put_u64(self.hart as u64);
+        for (id, hart) in self.parked.iter().enumerate() {
+            if id == self.hart {
+                Hart {
+                    regs: self.regs,
+                    fregs: self.fregs,
+                    vregs: self.vregs.clone(),
+                    pc: self.pc,
+                    csr: self.csr.clone(),
+                    mode: self.mode,
+                    virt: self.virt,
+                    enable_paging: self.enable_paging,
+                    page_table: self.page_table,
+                    reservation: self.reservation,
+                }
+                .save(w)?;
+            } else {
+                hart.save(w)?;
+            }
+        }
+        w.put_u64(self.icount);
+        self.bus.save(w)?;
+        Ok(())
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        let harts = r.get_u64()?;
+        if harts != self.parked.len() as u64 {
+            return Err(invalid(&format!(
+                "the snapshot has {} harts, not {}",
+                harts,
+                self.parked.len()
+            )));
+        }
+        let running = r.get_u64()? as usize;
+        if running >= self.parked.len() {
+            return Err(invalid("invalid running hart"));
+        }
+        // Park the running hart so that every hart is restored the same way.
+        self.exchange(self.hart);
+        let isa = self.isa;
+        let result: io::Result<()> = self.parked.iter_mut().try_for_each(|hart| {
+            hart.restore(r)?;
+            // Keep the extensions turned off in the snapshot off, as far as this hart
+            // implements them.
+            hart.csr[MISA] = isa.write_misa(isa.misa(), hart.csr[MISA]);
+            Ok(())
+        });
+        if result.is_ok() {
+            self.hart = running;
+        }
+        self.exchange(self.hart);
+        self.next_switch = 0;
+        result?;
+        self.icount = r.get_u64()?;
+        self.bus.restore(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+
+    /// A Zicsr instruction.
+    fn csr(address: usize, rs1: usize, funct3: u32, rd: usize) -> u32 {
+        (address as u32) << 20 | (rs1 as u32) << 15 | funct3 << 12 | (rd as u32) << 7 | 0x73
+    }
+
+    #[test]
+    fn csrrs_and_csrrc_of_zero_dont_write() {
+        let mut machine = Machine::builder().build().unwrap();
+        let cpu = machine.cpu_mut();
+        cpu.commit = Some(Commit::default());
+        cpu.csr[MSCRATCH] = 0x1234;
+        // csrrs, csrrc, csrrsi and csrrci, reading mscratch.
+        for funct3 in [0x2, 0x3, 0x6, 0x7] {
+            cpu.regs[5] = 0;
+            cpu.decode_execute(csr(MSCRATCH, 0, funct3, 5)).unwrap();
+            assert_eq!(cpu.regs[5], 0x1234);
+            let commit = cpu.commit.as_mut().unwrap();
+            assert_eq!(commit.writes.len(), 1, "funct3 {:#x}", funct3);
+            commit.clear();
+        }
+        // With a source, the CSR is written even if its value doesn't change.
+        cpu.regs[6] = 0x1000;
+        cpu.decode_execute(csr(MSCRATCH, 6, 0x2, 5)).unwrap();
+        assert_eq!(cpu.commit.as_ref().unwrap().writes.len(), 2);
+
+        // So the read-only vlenb can be read by csrrs but not written.
+        cpu.csr[MSTATUS] |= MSTATUS_VS;
+        cpu.decode_execute(csr(VLENB, 0, 0x2, 5)).unwrap();
+        assert_eq!(cpu.regs[5], 16);
+        assert_eq!(
+            cpu.decode_execute(csr(VLENB, 6, 0x2, 5)),
+            Err(Exception::IllegalInstruction)
+        );
+        assert_eq!(
+            cpu.decode_execute(csr(VLENB, 1, 0x7, 5)),
+            Err(Exception::IllegalInstruction)
+        );
+    }
+
+    #[test]
+    fn interrupts_are_masked_by_the_mode() {
+        let mut machine = Machine::builder().build().unwrap();
+        let cpu = machine.cpu_mut();
+        cpu.csr[MIE] = MIP_SSIP;
+        cpu.csr[MIP] = MIP_SSIP;
+        // mstatus.MIE masks them in M mode, and sstatus.SIE in S mode.
+        cpu.mode = Mode::Machine;
+        assert!(cpu.check_pending_interrupt().is_none());
+        cpu.mode = Mode::Supervisor;
+        assert!(cpu.check_pending_interrupt().is_none());
+        // Interrupts are always enabled in U mode.
+        cpu.mode = Mode::User;
+        assert!(matches!(
+            cpu.check_pending_interrupt(),
+            Some(Interrupt::SupervisorSoftwareInterrupt)
+        ));
+        assert_eq!(cpu.csr[MIP] & MIP_SSIP, 0);
+
+        cpu.csr[MIP] = MIP_SSIP;
+        cpu.store_csr(SSTATUS, cpu.load_csr(SSTATUS) | 1 << 1);
+        cpu.mode = Mode::Supervisor;
+        assert!(matches!(
+            cpu.check_pending_interrupt(),
+            Some(Interrupt::SupervisorSoftwareInterrupt)
+        ));
+        cpu.csr[MIP] = MIP_SSIP;
+        cpu.store_csr(MSTATUS, cpu.load_csr(MSTATUS) | 1 << 3);
+        cpu.mode = Mode::Machine;
+        assert!(matches!(
+            cpu.check_pending_interrupt(),
+            Some(Interrupt::SupervisorSoftwareInterrupt)
+        ));
+    }
+
+    #[test]
+    fn page_faults_match_the_access() {
+        let mut machine = Machine::builder().build().unwrap();
+        let cpu = machine.cpu_mut();
+        cpu.mode = Mode::Supervisor;
+        // An empty root page table.
+        let root = MEMORY_BASE + 0x10_0000;
+        cpu.csr[SATP] = (8 << 60) | (root / PAGE_SIZE);
+        cpu.update_paging(SATP);
+        assert!(cpu.enable_paging);
+        assert_eq!(cpu.page_table, root);
+        let addr = MEMORY_BASE + 0x1234;
+        for (access_type, fault) in [
+            (AccessType::Instruction, Exception::InstructionPageFault),
+            (AccessType::Load, Exception::LoadPageFault),
+            (AccessType::Store, Exception::StoreAMOPageFault),
+        ] {
+            assert_eq!(cpu.translate(addr, access_type), Err(fault));
+        }
+
+        // A gigapage that maps the memory to itself.
+        let pte = (MEMORY_BASE >> 12) << 10 | 0xcf;
+        cpu.bus.store(root + 8 * (addr >> 30), 64, pte).unwrap();
+        assert_eq!(cpu.translate(addr, AccessType::Load), Ok(addr));
+        assert_eq!(cpu.translate(addr, AccessType::Store), Ok(addr));
+        cpu.csr[SATP] = 0;
+        cpu.update_paging(SATP);
+        assert!(!cpu.enable_paging);
+        assert_eq!(cpu.translate(0x1234, AccessType::Load), Ok(0x1234));
+    }
+}
@@ -0,0 +1,731 @@
+//! The crypto module decodes and executes the scalar cryptography instructions of the NIST suite,
+//! AES (Zkne, Zknd) and SHA-2 (Zknh), and of the ShangMi suite, SM4 (Zksed) and SM3 (Zksh). Like
+//! the bit-manipulation instructions, they share the opcodes OP-IMM and OP with the base
+//! instructions. The AES instructions of RV64 work on half of the 128-bit state: rs1 holds its
+//! columns 0 and 1, rs2 its columns 2 and 3, and rd gets the new columns 0 and 1 or, with the
+//! operands swapped, 2 and 3.
+//!
+//! The seed CSR of Zkr provides entropy to software. It's read from the host, except while
+//! events are recorded or replayed, when a PRNG of the instruction count takes its place so that
+//! the run repeats.
+
+use crate::cpu::Cpu;
+use crate::csr::MHARTID;
+use crate::isa::{ZKND, ZKNE, ZKNH, ZKSED, ZKSH};
+
+use std::fs::File;
+use std::io::Read;
+
+// MSECCFG fields.
+/// Lets U mode access seed.
+pub const MSECCFG_USEED: u64 = 1 << 8;
+/// Lets S mode access seed.
+pub const MSECCFG_SSEED: u64 = 1 << 9;
+
+/// The OPST field of seed when its entropy field holds 16 fresh bits (ES16).
+const SEED_ES16: u64 = 0b10 << 30;
+
+/// A scalar cryptography instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    // Zkne
+    Aes64es,
+    Aes64esm,
+    // Zknd
+    Aes64ds,
+    Aes64dsm,
+    Aes64im,
+    // Zkne and Zknd
+    Aes64ks1i { rnum: u32 },
+    Aes64ks2,
+    // Zknh
+    Sha256sig0,
+    Sha256sig1,
+    Sha256sum0,
+    Sha256sum1,
+    Sha512sig0,
+    Sha512sig1,
+    Sha512sum0,
+    Sha512sum1,
+    // Zksed
+    Sm4ed { bs: u32 },
+    Sm4ks { bs: u32 },
+    // Zksh
+    Sm3p0,
+    Sm3p1,
+}
+
+/// Return the scalar cryptography instruction `inst` encodes, or `None` if it's none.
+pub fn decode(inst: u32) -> Option<Op> {
+    let opcode = inst & 0x0000007f;
+    let funct3 = (inst & 0x00007000) >> 12;
+    let funct7 = (inst & 0xfe000000) >> 25;
+    let imm = inst >> 20;
+
+    let op = match (opcode, funct3) {
+        (0x13, 0x1) => match imm {
+            0x100 => Op::Sha256sum0,
+            0x101 => Op::Sha256sum1,
+            0x102 => Op::Sha256sig0,
+            0x103 => Op::Sha256sig1,
+            0x104 => Op::Sha512sum0,
+            0x105 => Op::Sha512sum1,
+            0x106 => Op::Sha512sig0,
+            0x107 => Op::Sha512sig1,
+            0x108 => Op::Sm3p0,
+            0x109 => Op::Sm3p1,
+            0x300 => Op::Aes64im,
+            // Round numbers above 10 are reserved.
+            _ if imm >> 4 == 0x31 && imm & 0xf <= 0xa => Op::Aes64ks1i { rnum: imm & 0xf },
+            _ => return None,
+        },
+        (0x33, 0x0) => match funct7 {
+            0x19 => Op::Aes64es,
+            0x1b => Op::Aes64esm,
+            0x1d => Op::Aes64ds,
+            0x1f => Op::Aes64dsm,
+            0x3f => Op::Aes64ks2,
+            // The upper two bits are the byte select bs.
+            _ if funct7 & 0x1f == 0x18 => Op::Sm4ed { bs: funct7 >> 5 },
+            _ if funct7 & 0x1f == 0x1a => Op::Sm4ks { bs: funct7 >> 5 },
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(op)
+}
+
+/// Multiply `a` and `b` in GF(2^8) with the AES polynomial x^8 + x^4 + x^3 + x + 1.
+const fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        a = (a << 1) ^ if a & 0x80 != 0 { 0x1b } else { 0 };
+        b >>= 1;
+    }
+    product
+}
+
+/// Return the AES S-box, the multiplicative inverse in GF(2^8) followed by an affine
+/// transformation, and its inverse.
+const fn aes_sboxes() -> ([u8; 256], [u8; 256]) {
+    let mut sbox = [0; 256];
+    let mut inverse = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        // The inverse of x is x^254, and 0 maps to 0.
+        let x = i as u8;
+        let mut power = x;
+        let mut result = 1;
+        let mut exponent = 254;
+        while exponent != 0 {
+            if exponent & 1 != 0 {
+                result = gf_mul(result, power);
+            }
+            power = gf_mul(power, power);
+            exponent >>= 1;
+        }
+        let b = if x == 0 { 0 } else { result };
+        let s =
+            b ^ b.rotate_left(1) ^ b.rotate_left(2) ^ b.rotate_left(3) ^ b.rotate_left(4) ^ 0x63;
+        sbox[i] = s;
+        inverse[s as usize] = x;
+        i += 1;
+    }
+    (sbox, inverse)
+}
+
+const AES_SBOXES: ([u8; 256], [u8; 256]) = aes_sboxes();
+const AES_SBOX: [u8; 256] = AES_SBOXES.0;
+const AES_INVERSE_SBOX: [u8; 256] = AES_SBOXES.1;
+
+/// The round constants of the AES key schedule, by round number. Round 10 has none.
+const AES_RCON: [u32; 11] = [
+    0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36, 0x00,
+];
+
+/// The SM4 S-box.
+const SM4_SBOX: [u8; 256] = [
+    0xd6, 0x90, 0xe9, 0xfe, 0xcc, 0xe1, 0x3d, 0xb7, 0x16, 0xb6, 0x14, 0xc2, 0x28, 0xfb, 0x2c, 0x05,
+    0x2b, 0x67, 0x9a, 0x76, 0x2a, 0xbe, 0x04, 0xc3, 0xaa, 0x44, 0x13, 0x26, 0x49, 0x86, 0x06, 0x99,
+    0x9c, 0x42, 0x50, 0xf4, 0x91, 0xef, 0x98, 0x7a, 0x33, 0x54, 0x0b, 0x43, 0xed, 0xcf, 0xac, 0x62,
+    0xe4, 0xb3, 0x1c, 0xa9, 0xc9, 0x08, 0xe8, 0x95, 0x80, 0xdf, 0x94, 0xfa, 0x75, 0x8f, 0x3f, 0xa6,
+    0x47, 0x07, 0xa7, 0xfc, 0xf3, 0x73, 0x17, 0xba, 0x83, 0x59, 0x3c, 0x19, 0xe6, 0x85, 0x4f, 0xa8,
+    0x68, 0x6b, 0x81, 0xb2, 0x71, 0x64, 0xda, 0x8b, 0xf8, 0xeb, 0x0f, 0x4b, 0x70, 0x56, 0x9d, 0x35,
+    0x1e, 0x24, 0x0e, 0x5e, 0x63, 0x58, 0xd1, 0xa2, 0x25, 0x22, 0x7c, 0x3b, 0x01, 0x21, 0x78, 0x87,
+    0xd4, 0x00, 0x46, 0x57, 0x9f, 0xd3, 0x27, 0x52, 0x4c, 0x36, 0x02, 0xe7, 0xa0, 0xc4, 0xc8, 0x9e,
+    0xea, 0xbf, 0x8a, 0xd2, 0x40, 0xc7, 0x38, 0xb5, 0xa3, 0xf7, 0xf2, 0xce, 0xf9, 0x61, 0x15, 0xa1,
+    0xe0, 0xae, 0x5d, 0xa4, 0x9b, 0x34, 0x1a, 0x55, 0xad, 0x93, 0x32, 0x30, 0xf5, 0x8c, 0xb1, 0xe3,
+    0x1d, 0xf6, 0xe2, 0x2e, 0x82, 0x66, 0xca, 0x60, 0xc0, 0x29, 0x23, 0xab, 0x0d, 0x53, 0x4e, 0x6f,
+    0xd5, 0xdb, 0x37, 0x45, 0xde, 0xfd, 0x8e, 0x2f, 0x03, 0xff, 0x6a, 0x72, 0x6d, 0x6c, 0x5b, 0x51,
+    0x8d, 0x1b, 0xaf, 0x92, 0xbb, 0xdd, 0xbc, 0x7f, 0x11, 0xd9, 0x5c, 0x41, 0x1f, 0x10, 0x5a, 0xd8,
+    0x0a, 0xc1, 0x31, 0x88, 0xa5, 0xcd, 0x7b, 0xbd, 0x2d, 0x74, 0xd0, 0x12, 0xb8, 0xe5, 0xb4, 0xb0,
+    0x89, 0x69, 0x97, 0x4a, 0x0c, 0x96, 0x77, 0x7e, 0x65, 0xb9, 0xf1, 0x09, 0xc5, 0x6e, 0xc6, 0x84,
+    0x18, 0xf0, 0x7d, 0xec, 0x3a, 0xdc, 0x4d, 0x20, 0x79, 0xee, 0x5f, 0x3e, 0xd7, 0xcb, 0x39, 0x48,
+];
+
+/// Replace every byte of `value` by its entry in `sbox`.
+fn substitute(value: u64, sbox: &[u8; 256]) -> u64 {
+    u64::from_le_bytes(value.to_le_bytes().map(|byte| sbox[byte as usize]))
+}
+
+/// Return columns 0 and 1 of ShiftRows, or of its inverse, of the state whose columns 0 and 1 are
+/// `low` and whose columns 2 and 3 are `high`.
+fn shift_rows(low: u64, high: u64, inverse: bool) -> u64 {
+    let state = (high as u128) << 64 | low as u128;
+    (0..8).fold(0, |result, i| {
+        let (row, column) = (i % 4, i / 4);
+        // Row r is rotated left by r columns, or right to undo it.
+        let from = if inverse {
+            (column + 4 - row) % 4
+        } else {
+            (column + row) % 4
+        };
+        let byte = (state >> ((from * 4 + row) * 8)) as u8;
+        result | (byte as u64) << (i * 8)
+    })
+}
+
+/// Return MixColumns, or its inverse, of both columns in `value`.
+fn mix_columns(value: u64, inverse: bool) -> u64 {
+    let matrix: [u8; 4] = if inverse {
+        [14, 11, 13, 9]
+    } else {
+        [2, 3, 1, 1]
+    };
+    let bytes = value.to_le_bytes();
+    let mut result = [0; 8];
+    for (i, out) in result.iter_mut().enumerate() {
+        let (row, column) = (i % 4, i / 4);
+        *out = (0..4).fold(0, |sum, j| {
+            sum ^ gf_mul(matrix[(j + 4 - row) % 4], bytes[column * 4 + j])
+        });
+    }
+    u64::from_le_bytes(result)
+}
+
+impl Op {
+    /// Return the extension of the instruction, a `Z*` constant, or the extensions that have it.
+    pub fn extension(self) -> u64 {
+        use Op::*;
+        match self {
+            Aes64es | Aes64esm => ZKNE,
+            Aes64ds | Aes64dsm | Aes64im => ZKND,
+            Aes64ks1i { .. } | Aes64ks2 => ZKNE | ZKND,
+            Sm4ed { .. } | Sm4ks { .. } => ZKSED,
+            Sm3p0 | Sm3p1 => ZKSH,
+            _ => ZKNH,
+        }
+    }
+
+    /// Return true if the instruction only has the source operand rs1.
+    pub fn is_unary(self) -> bool {
+        !matches!(
+            self,
+            Op::Aes64es
+                | Op::Aes64esm
+                | Op::Aes64ds
+                | Op::Aes64dsm
+                | Op::Aes64ks1i { .. }
+                | Op::Aes64ks2
+                | Op::Sm4ed { .. }
+                | Op::Sm4ks { .. }
+        )
+    }
+
+    /// Return the assembler mnemonic of the instruction.
+    pub fn name(self) -> &'static str {
+        use Op::*;
+        match self {
+            Aes64es => "aes64es",
+            Aes64esm => "aes64esm",
+            Aes64ds => "aes64ds",
+            Aes64dsm => "aes64dsm",
+            Aes64im => "aes64im",
+            Aes64ks1i { .. } => "aes64ks1i",
+            Aes64ks2 => "aes64ks2",
+            Sha256sig0 => "sha256sig0",
+            Sha256sig1 => "sha256sig1",
+            Sha256sum0 => "sha256sum0",
+            Sha256sum1 => "sha256sum1",
+            Sha512sig0 => "sha512sig0",
+            Sha512sig1 => "sha512sig1",
+            Sha512sum0 => "sha512sum0",
+            Sha512sum1 => "sha512sum1",
+            Sm4ed { .. } => "sm4ed",
+            Sm4ks { .. } => "sm4ks",
+            Sm3p0 => "sm3p0",
+            Sm3p1 => "sm3p1",
+        }
+    }
+
+    /// Return the result of the instruction for the values `a` of rs1 and `b` of rs2.
+    pub fn execute(self, a: u64, b: u64) -> u64 {
+        use Op::*;
+        let sext32 = |value: u32| value as i32 as i64 as u64;
+        let w = a as u32;
+        match self {
+            Aes64es => substitute(shift_rows(a, b, false), &AES_SBOX),
+            Aes64esm => mix_columns(substitute(shift_rows(a, b, false), &AES_SBOX), false),
+            Aes64ds => substitute(shift_rows(a, b, true), &AES_INVERSE_SBOX),
+            Aes64dsm => mix_columns(substitute(shift_rows(a, b, true), &AES_INVERSE_SBOX), true),
+            Aes64im => mix_columns(a, true),
+            // Both halves get the same word of the next round key.
+            Aes64ks1i { rnum } => {
+                let word = (a >> 32) as u32;
+                let word = if rnum == 0xa {
+                    word
+                } else {
+                    word.rotate_right(8)
+                };
+                let word = substitute(word as u64, &AES_SBOX) as u32 ^ AES_RCON[rnum as usize];
+                (word as u64) << 32 | word as u64
+            }
+            Aes64ks2 => {
+                let w0 = (a >> 32) as u32 ^ b as u32;
+                let w1 = w0 ^ (b >> 32) as u32;
+                (w1 as u64) << 32 | w0 as u64
+            }
+            Sha256sig0 => sext32(w.rotate_right(7) ^ w.rotate_right(18) ^ (w >> 3)),
+            Sha256sig1 => sext32(w.rotate_right(17) ^ w.rotate_right(19) ^ (w >> 10)),
+            Sha256sum0 => sext32(w.rotate_right(2) ^ w.rotate_right(13) ^ w.rotate_right(22)),
+            Sha256sum1 => sext32(w.rotate_right(6) ^ w.rotate_right(11) ^ w.rotate_right(25)),
+            Sha512sig0 => a.rotate_right(1) ^ a.rotate_right(8) ^ (a >> 7),
+            Sha512sig1 => a.rotate_right(19) ^ a.rotate_right(61) ^ (a >> 6),
+            Sha512sum0 => a.rotate_right(28) ^ a.rotate_right(34) ^ a.rotate_right(39),
+            Sha512sum1 => a.rotate_right(14) ^ a.rotate_right(18) ^ a.rotate_right(41),
+            // One byte of rs2, selected by bs, goes through the S-box and the linear
+            // transformation of the round function or of the key schedule.
+            Sm4ed { bs } | Sm4ks { bs } => {
+                let x = SM4_SBOX[(b >> (bs * 8)) as u8 as usize] as u32;
+                let y = if let Sm4ed { .. } = self {
+                    x ^ (x << 8) ^ (x << 2) ^ (x << 18) ^ ((x & 0x3f) << 26) ^ ((x & 0xc0) << 10)
+                } else {
+                    x ^ ((x & 0x07) << 29)
+                        ^ ((x & 0xfe) << 7)
+                        ^ ((x & 0x01) << 23)
+                        ^ ((x & 0xf8) << 13)
+                };
+                sext32(y.rotate_left(bs * 8) ^ w)
+            }
+            Sm3p0 => sext32(w ^ w.rotate_left(9) ^ w.rotate_left(17)),
+            Sm3p1 => sext32(w ^ w.rotate_left(15) ^ w.rotate_left(23)),
+        }
+    }
+}
+
+impl Cpu {
+    /// Return a value of the seed CSR, which holds 16 bits of entropy each time it's read.
+    pub(crate) fn seed(&self) -> u64 {
+        let mut bytes = [0; 2];
+        let host = self.events.is_none()
+            && File::open("/dev/urandom")
+                .and_then(|mut file| file.read_exact(&mut bytes))
+                .is_ok();
+        let entropy = if host {
+            u16::from_le_bytes(bytes)
+        } else {
+            // splitmix64 of the instruction count and the hart.
+            let mut z = self
+                .icount
+                .wrapping_add(self.csr[MHARTID] << 48)
+                .wrapping_mul(0x9e3779b97f4a7c15);
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            (z ^ (z >> 31)) as u16
+        };
+        SEED_ES16 | entropy as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use Op::*;
+
+    const K256: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+    const K512: [u64; 80] = [
+        0x428a2f98d728ae22,
+        0x7137449123ef65cd,
+        0xb5c0fbcfec4d3b2f,
+        0xe9b5dba58189dbbc,
+        0x3956c25bf348b538,
+        0x59f111f1b605d019,
+        0x923f82a4af194f9b,
+        0xab1c5ed5da6d8118,
+        0xd807aa98a3030242,
+        0x12835b0145706fbe,
+        0x243185be4ee4b28c,
+        0x550c7dc3d5ffb4e2,
+        0x72be5d74f27b896f,
+        0x80deb1fe3b1696b1,
+        0x9bdc06a725c71235,
+        0xc19bf174cf692694,
+        0xe49b69c19ef14ad2,
+        0xefbe4786384f25e3,
+        0x0fc19dc68b8cd5b5,
+        0x240ca1cc77ac9c65,
+        0x2de92c6f592b0275,
+        0x4a7484aa6ea6e483,
+        0x5cb0a9dcbd41fbd4,
+        0x76f988da831153b5,
+        0x983e5152ee66dfab,
+        0xa831c66d2db43210,
+        0xb00327c898fb213f,
+        0xbf597fc7beef0ee4,
+        0xc6e00bf33da88fc2,
+        0xd5a79147930aa725,
+        0x06ca6351e003826f,
+        0x142929670a0e6e70,
+        0x27b70a8546d22ffc,
+        0x2e1b21385c26c926,
+        0x4d2c6dfc5ac42aed,
+        0x53380d139d95b3df,
+        0x650a73548baf63de,
+        0x766a0abb3c77b2a8,
+        0x81c2c92e47edaee6,
+        0x92722c851482353b,
+        0xa2bfe8a14cf10364,
+        0xa81a664bbc423001,
+        0xc24b8b70d0f89791,
+        0xc76c51a30654be30,
+        0xd192e819d6ef5218,
+        0xd69906245565a910,
+        0xf40e35855771202a,
+        0x106aa07032bbd1b8,
+        0x19a4c116b8d2d0c8,
+        0x1e376c085141ab53,
+        0x2748774cdf8eeb99,
+        0x34b0bcb5e19b48a8,
+        0x391c0cb3c5c95a63,
+        0x4ed8aa4ae3418acb,
+        0x5b9cca4f7763e373,
+        0x682e6ff3d6b2b8a3,
+        0x748f82ee5defb2fc,
+        0x78a5636f43172f60,
+        0x84c87814a1f0ab72,
+        0x8cc702081a6439ec,
+        0x90befffa23631e28,
+        0xa4506cebde82bde9,
+        0xbef9a3f7b2c67915,
+        0xc67178f2e372532b,
+        0xca273eceea26619c,
+        0xd186b8c721c0c207,
+        0xeada7dd6cde0eb1e,
+        0xf57d4f7fee6ed178,
+        0x06f067aa72176fba,
+        0x0a637dc5a2c898a6,
+        0x113f9804bef90dae,
+        0x1b710b35131c471b,
+        0x28db77f523047d84,
+        0x32caab7b40c72493,
+        0x3c9ebe0a15c9bebc,
+        0x431d67c49c100d4c,
+        0x4cc5d4becb3e42b6,
+        0x597f299cfc657e2a,
+        0x5fcb6fab3ad6faec,
+        0x6c44198c4a475817,
+    ];
+    const H256: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+    const H512: [u64; 8] = [
+        0x6a09e667f3bcc908,
+        0xbb67ae8584caa73b,
+        0x3c6ef372fe94f82b,
+        0xa54ff53a5f1d36f1,
+        0x510e527fade682d1,
+        0x9b05688c2b3e6c1f,
+        0x1f83d9abfb41bd6b,
+        0x5be0cd19137e2179,
+    ];
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Pad `message` to blocks of `block` bytes, ending with its length in bits in
+    /// `length` big-endian bytes, as SHA-2 and SM3 do.
+    fn pad(message: &[u8], block: usize, length: usize) -> Vec<u8> {
+        let mut padded = message.to_vec();
+        padded.push(0x80);
+        while padded.len() % block != block - length {
+            padded.push(0);
+        }
+        let bits = (message.len() as u128 * 8).to_be_bytes();
+        padded.extend_from_slice(&bits[16 - length..]);
+        padded
+    }
+
+    fn word(bytes: &[u8]) -> u32 {
+        u32::from_be_bytes(bytes.try_into().unwrap())
+    }
+
+    /// Execute the unary instruction `op` on `value`.
+    fn unary(op: Op, value: u32) -> u32 {
+        op.execute(value as u64, 0) as u32
+    }
+
+    #[test]
+    fn decode_known_encodings() {
+        // Encodings from llvm-mc, with rd a0, rs1 a1 and rs2 a2.
+        for (inst, op) in [
+            (0x32c58533, Aes64es),
+            (0x36c58533, Aes64esm),
+            (0x3ac58533, Aes64ds),
+            (0x3ec58533, Aes64dsm),
+            (0x30059513, Aes64im),
+            (0x31a59513, Aes64ks1i { rnum: 10 }),
+            (0x7ec58533, Aes64ks2),
+            (0x10259513, Sha256sig0),
+            (0x10559513, Sha512sum1),
+            (0xf0c58533, Sm4ed { bs: 3 }),
+            (0x74c58533, Sm4ks { bs: 1 }),
+            (0x10859513, Sm3p0),
+            (0x10959513, Sm3p1),
+        ] {
+            assert_eq!(decode(inst), Some(op), "{:#010x}", inst);
+        }
+        // Round number 11 is reserved.
+        assert_eq!(decode(0x31b59513), None);
+    }
+
+    /// Encrypt and decrypt the example of FIPS 197, appendix C.1, with AES-128.
+    #[test]
+    fn aes128() {
+        let key = hex("000102030405060708090a0b0c0d0e0f");
+        let plaintext = hex("00112233445566778899aabbccddeeff");
+        let ciphertext = hex("69c4e0d86a7b0430d8cdb78070b4c55a");
+        let halves = |bytes: &[u8]| {
+            [
+                u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+                u64::from_le_bytes(bytes[8..].try_into().unwrap()),
+            ]
+        };
+
+        let mut keys = vec![halves(&key)];
+        for rnum in 0..10 {
+            let [k0, k1] = keys[rnum as usize];
+            let t = Aes64ks1i { rnum }.execute(k1, 0);
+            let k0 = Aes64ks2.execute(t, k0);
+            let k1 = Aes64ks2.execute(k0, k1);
+            keys.push([k0, k1]);
+        }
+        // The last round key of FIPS 197, appendix A.1, for another key.
+        let mut other = halves(&hex("2b7e151628aed2a6abf7158809cf4f3c"));
+        for rnum in 0..10 {
+            let t = Aes64ks1i { rnum }.execute(other[1], 0);
+            other[0] = Aes64ks2.execute(t, other[0]);
+            other[1] = Aes64ks2.execute(other[0], other[1]);
+        }
+        assert_eq!(other, halves(&hex("d014f9a8c9ee2589e13f0cc8b6630ca6")));
+
+        let round = |op: Op, [s0, s1]: [u64; 2], [k0, k1]: [u64; 2]| {
+            [op.execute(s0, s1) ^ k0, op.execute(s1, s0) ^ k1]
+        };
+        let [p0, p1] = halves(&plaintext);
+        let mut state = [p0 ^ keys[0][0], p1 ^ keys[0][1]];
+        for key in &keys[1..10] {
+            state = round(Aes64esm, state, *key);
+        }
+        state = round(Aes64es, state, keys[10]);
+        assert_eq!(state, halves(&ciphertext));
+
+        // The equivalent inverse cipher, whose round keys go through InvMixColumns.
+        let [c0, c1] = state;
+        state = [c0 ^ keys[10][0], c1 ^ keys[10][1]];
+        for [k0, k1] in keys[1..10].iter().rev() {
+            let key = [Aes64im.execute(*k0, 0), Aes64im.execute(*k1, 0)];
+            state = round(Aes64dsm, state, key);
+        }
+        state = round(Aes64ds, state, keys[0]);
+        assert_eq!(state, halves(&plaintext));
+    }
+
+    #[test]
+    fn sha256() {
+        let padded = pad(b"abc", 64, 8);
+        let mut hash = H256;
+        for block in padded.chunks(64) {
+            let mut w: Vec<u32> = block.chunks(4).map(word).collect();
+            for i in 16..64 {
+                let value = unary(Sha256sig1, w[i - 2])
+                    .wrapping_add(w[i - 7])
+                    .wrapping_add(unary(Sha256sig0, w[i - 15]))
+                    .wrapping_add(w[i - 16]);
+                w.push(value);
+            }
+            let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = hash;
+            for i in 0..64 {
+                let t1 = h
+                    .wrapping_add(unary(Sha256sum1, e))
+                    .wrapping_add((e & f) ^ (!e & g))
+                    .wrapping_add(K256[i])
+                    .wrapping_add(w[i]);
+                let t2 = unary(Sha256sum0, a).wrapping_add((a & b) ^ (a & c) ^ (b & c));
+                (h, g, f, e, d, c, b, a) =
+                    (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+            }
+            for (x, y) in hash.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+                *x = x.wrapping_add(y);
+            }
+        }
+        let digest: Vec<u8> = hash.iter().flat_map(|x| x.to_be_bytes()).collect();
+        assert_eq!(
+            digest,
+            hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        // The W forms sign-extend.
+        assert_eq!(
+            Sha256sum0.execute(1, 0),
+            0x40080400_u32 as i32 as i64 as u64
+        );
+        assert_eq!(Sha256sig0.execute(0xffffffff_00000000, 0), 0);
+    }
+
+    #[test]
+    fn sha512() {
+        let padded = pad(b"abc", 128, 16);
+        let mut hash = H512;
+        for block in padded.chunks(128) {
+            let mut w: Vec<u64> = block
+                .chunks(8)
+                .map(|bytes| u64::from_be_bytes(bytes.try_into().unwrap()))
+                .collect();
+            for i in 16..80 {
+                let value = Sha512sig1
+                    .execute(w[i - 2], 0)
+                    .wrapping_add(w[i - 7])
+                    .wrapping_add(Sha512sig0.execute(w[i - 15], 0))
+                    .wrapping_add(w[i - 16]);
+                w.push(value);
+            }
+            let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = hash;
+            for i in 0..80 {
+                let t1 = h
+                    .wrapping_add(Sha512sum1.execute(e, 0))
+                    .wrapping_add((e & f) ^ (!e & g))
+                    .wrapping_add(K512[i])
+                    .wrapping_add(w[i]);
+                let t2 = Sha512sum0
+                    .execute(a, 0)
+                    .wrapping_add((a & b) ^ (a & c) ^ (b & c));
+                (h, g, f, e, d, c, b, a) =
+                    (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+            }
+            for (x, y) in hash.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+                *x = x.wrapping_add(y);
+            }
+        }
+        let digest: Vec<u8> = hash.iter().flat_map(|x| x.to_be_bytes()).collect();
+        assert_eq!(
+            digest,
+            hex(concat!(
+                "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a",
+                "2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+            ))
+        );
+    }
+
+    /// Hash "abc" with SM3, the example of GB/T 32905-2016.
+    #[test]
+    fn sm3() {
+        let padded = pad(b"abc", 64, 8);
+        let mut v: [u32; 8] = [
+            0x7380166f, 0x4914b2b9, 0x172442d7, 0xda8a0600, 0xa96f30bc, 0x163138aa, 0xe38dee4d,
+            0xb0fb0e4e,
+        ];
+        for block in padded.chunks(64) {
+            let mut w: Vec<u32> = block.chunks(4).map(word).collect();
+            for j in 16..68 {
+                let value = unary(Sm3p1, w[j - 16] ^ w[j - 9] ^ w[j - 3].rotate_left(15))
+                    ^ w[j - 13].rotate_left(7)
+                    ^ w[j - 6];
+                w.push(value);
+            }
+            let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = v;
+            for j in 0..64 {
+                let t: u32 = if j < 16 { 0x79cc4519 } else { 0x7a879d8a };
+                let ss1 = a
+                    .rotate_left(12)
+                    .wrapping_add(e)
+                    .wrapping_add(t.rotate_left(j as u32 % 32))
+                    .rotate_left(7);
+                let ss2 = ss1 ^ a.rotate_left(12);
+                let (ff, gg) = if j < 16 {
+                    (a ^ b ^ c, e ^ f ^ g)
+                } else {
+                    ((a & b) | (a & c) | (b & c), (e & f) | (!e & g))
+                };
+                let tt1 = ff
+                    .wrapping_add(d)
+                    .wrapping_add(ss2)
+                    .wrapping_add(w[j] ^ w[j + 4]);
+                let tt2 = gg.wrapping_add(h).wrapping_add(ss1).wrapping_add(w[j]);
+                (d, c, b, a) = (c, b.rotate_left(9), a, tt1);
+                (h, g, f, e) = (g, f.rotate_left(19), e, unary(Sm3p0, tt2));
+            }
+            for (x, y) in v.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+                *x ^= y;
+            }
+        }
+        let digest: Vec<u8> = v.iter().flat_map(|x| x.to_be_bytes()).collect();
+        assert_eq!(
+            digest,
+            hex("66c7f0f462eeedd9d1f2d46bdc10e4e24167c4875cf2f7a2297da02b8f4ba8e0")
+        );
+    }
+
+    /// Encrypt the example of GB/T 32907-2016 with SM4. The instructions take the words of the
+    /// standard loaded little-endian, as RISC-V loads them: byte 0 of a word is its first byte.
+    #[test]
+    fn sm4() {
+        let words = |bytes: &[u8]| -> Vec<u32> {
+            bytes
+                .chunks(4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+                .collect()
+        };
+        let key = words(&hex("0123456789abcdeffedcba9876543210"));
+        let plaintext = words(&hex("0123456789abcdeffedcba9876543210"));
+        let fk = words(&hex("a3b1bac656aa3350677d9197b27022dc"));
+
+        // Apply the transformation of `op`, one byte of `x` at a time, to `acc`.
+        let transform = |op: fn(u32) -> Op, acc: u32, x: u32| {
+            (0..4).fold(acc, |acc, bs| op(bs).execute(acc as u64, x as u64) as u32)
+        };
+        let mut k: Vec<u32> = key.iter().zip(fk).map(|(k, fk)| k ^ fk).collect();
+        for i in 0..32 {
+            let ck = u32::from_le_bytes([0, 1, 2, 3].map(|j| ((4 * i + j) * 7 % 256) as u8));
+            let x = k[i + 1] ^ k[i + 2] ^ k[i + 3] ^ ck;
+            let next = transform(|bs| Sm4ks { bs }, k[i], x);
+            k.push(next);
+        }
+        let mut x = plaintext;
+        for i in 0..32 {
+            let input = x[i + 1] ^ x[i + 2] ^ x[i + 3] ^ k[i + 4];
+            let next = transform(|bs| Sm4ed { bs }, x[i], input);
+            x.push(next);
+        }
+        let ciphertext: Vec<u8> = x[32..].iter().rev().flat_map(|x| x.to_le_bytes()).collect();
+        assert_eq!(ciphertext, hex("681edf34d206965e86b3e94f536e4246"));
+    }
+}
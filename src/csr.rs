@@ -11,6 +11,7 @@ pub const VSTART: usize = 0x8;
 pub const VXSAT: usize = 0x9;
 pub const VXRM: usize = 0xa;
 pub const VCSR: usize = 0xf;
+pub const SEED: usize = 0x15;
 pub const USCRATCH: usize = 0x40;
 pub const UEPC: usize = 0x41;
 pub const UCAUSE: usize = 0x42;
@@ -135,6 +136,7 @@ pub const PMPADDR12: usize = 0x3bc;
 pub const PMPADDR13: usize = 0x3bd;
 pub const PMPADDR14: usize = 0x3be;
 pub const PMPADDR15: usize = 0x3bf;
+pub const MSECCFG: usize = 0x747;
 pub const TSELECT: usize = 0x7a0;
 pub const TDATA1: usize = 0x7a1;
 pub const TDATA2: usize = 0x7a2;
@@ -276,3 +278,287 @@ pub const MHPMCOUNTER28H: usize = 0xb9c;
 pub const MHPMCOUNTER29H: usize = 0xb9d;
 pub const MHPMCOUNTER30H: usize = 0xb9e;
 pub const MHPMCOUNTER31H: usize = 0xb9f;
+
+/// Return the lower-case name of a CSR, or `"unknown"` if the address is not a standard CSR.
+pub fn csr_name(addr: usize) -> &'static str {
+    match addr {
+        FFLAGS => "fflags",
+        FRM => "frm",
+        FCSR => "fcsr",
+        USTATUS => "ustatus",
+        UIE => "uie",
+        UTVEC => "utvec",
+        VSTART => "vstart",
+        VXSAT => "vxsat",
+        VXRM => "vxrm",
+        VCSR => "vcsr",
+        SEED => "seed",
+        USCRATCH => "uscratch",
+        UEPC => "uepc",
+        UCAUSE => "ucause",
+        UTVAL => "utval",
+        UIP => "uip",
+        CYCLE => "cycle",
+        TIME => "time",
+        INSTRET => "instret",
+        HPMCOUNTER3 => "hpmcounter3",
+        HPMCOUNTER4 => "hpmcounter4",
+        HPMCOUNTER5 => "hpmcounter5",
+        HPMCOUNTER6 => "hpmcounter6",
+        HPMCOUNTER7 => "hpmcounter7",
+        HPMCOUNTER8 => "hpmcounter8",
+        HPMCOUNTER9 => "hpmcounter9",
+        HPMCOUNTER10 => "hpmcounter10",
+        HPMCOUNTER11 => "hpmcounter11",
+        HPMCOUNTER12 => "hpmcounter12",
+        HPMCOUNTER13 => "hpmcounter13",
+        HPMCOUNTER14 => "hpmcounter14",
+        HPMCOUNTER15 => "hpmcounter15",
+        HPMCOUNTER16 => "hpmcounter16",
+        HPMCOUNTER17 => "hpmcounter17",
+        HPMCOUNTER18 => "hpmcounter18",
+        HPMCOUNTER19 => "hpmcounter19",
+        HPMCOUNTER20 => "hpmcounter20",
+        HPMCOUNTER21 => "hpmcounter21",
+        HPMCOUNTER22 => "hpmcounter22",
+        HPMCOUNTER23 => "hpmcounter23",
+        HPMCOUNTER24 => "hpmcounter24",
+        HPMCOUNTER25 => "hpmcounter25",
+        HPMCOUNTER26 => "hpmcounter26",
+        HPMCOUNTER27 => "hpmcounter27",
+        HPMCOUNTER28 => "hpmcounter28",
+        HPMCOUNTER29 => "hpmcounter29",
+        HPMCOUNTER30 => "hpmcounter30",
+        HPMCOUNTER31 => "hpmcounter31",
+        VL => "vl",
+        VTYPE => "vtype",
+        VLENB => "vlenb",
+        SSTATUS => "sstatus",
+        SEDELEG => "sedeleg",
+        SIDELEG => "sideleg",
+        SIE => "sie",
+        STVEC => "stvec",
+        SCOUNTEREN => "scounteren",
+        SSCRATCH => "sscratch",
+        SEPC => "sepc",
+        SCAUSE => "scause",
+        STVAL => "stval",
+        SIP => "sip",
+        SATP => "satp",
+        VSSTATUS => "vsstatus",
+        VSIE => "vsie",
+        VSTVEC => "vstvec",
+        VSSCRATCH => "vsscratch",
+        VSEPC => "vsepc",
+        VSCAUSE => "vscause",
+        VSTVAL => "vstval",
+        VSIP => "vsip",
+        VSATP => "vsatp",
+        HSTATUS => "hstatus",
+        HEDELEG => "hedeleg",
+        HIDELEG => "hideleg",
+        HIE => "hie",
+        HTIMEDELTA => "htimedelta",
+        HCOUNTEREN => "hcounteren",
+        HGEIE => "hgeie",
+        HTVAL => "htval",
+        HIP => "hip",
+        HVIP => "hvip",
+        HTINST => "htinst",
+        HGATP => "hgatp",
+        HGEIP => "hgeip",
+        UTVT => "utvt",
+        UNXTI => "unxti",
+        UINTSTATUS => "uintstatus",
+        USCRATCHCSW => "uscratchcsw",
+        USCRATCHCSWL => "uscratchcswl",
+        STVT => "stvt",
+        SNXTI => "snxti",
+        SINTSTATUS => "sintstatus",
+        SSCRATCHCSW => "sscratchcsw",
+        SSCRATCHCSWL => "sscratchcswl",
+        MTVT => "mtvt",
+        MNXTI => "mnxti",
+        MINTSTATUS => "mintstatus",
+        MSCRATCHCSW => "mscratchcsw",
+        MSCRATCHCSWL => "mscratchcswl",
+        MSTATUS => "mstatus",
+        MISA => "misa",
+        MEDELEG => "medeleg",
+        MIDELEG => "mideleg",
+        MIE => "mie",
+        MTVEC => "mtvec",
+        MCOUNTEREN => "mcounteren",
+        MCOUNTINHIBIT => "mcountinhibit",
+        MSCRATCH => "mscratch",
+        MEPC => "mepc",
+        MCAUSE => "mcause",
+        MTVAL => "mtval",
+        MIP => "mip",
+        MTINST => "mtinst",
+        MTVAL2 => "mtval2",
+        PMPCFG0 => "pmpcfg0",
+        PMPCFG1 => "pmpcfg1",
+        PMPCFG2 => "pmpcfg2",
+        PMPCFG3 => "pmpcfg3",
+        PMPADDR0 => "pmpaddr0",
+        PMPADDR1 => "pmpaddr1",
+        PMPADDR2 => "pmpaddr2",
+        PMPADDR3 => "pmpaddr3",
+        PMPADDR4 => "pmpaddr4",
+        PMPADDR5 => "pmpaddr5",
+        PMPADDR6 => "pmpaddr6",
+        PMPADDR7 => "pmpaddr7",
+        PMPADDR8 => "pmpaddr8",
+        PMPADDR9 => "pmpaddr9",
+        PMPADDR10 => "pmpaddr10",
+        PMPADDR11 => "pmpaddr11",
+        PMPADDR12 => "pmpaddr12",
+        PMPADDR13 => "pmpaddr13",
+        PMPADDR14 => "pmpaddr14",
+        PMPADDR15 => "pmpaddr15",
+        MSECCFG => "mseccfg",
+        TSELECT => "tselect",
+        TDATA1 => "tdata1",
+        TDATA2 => "tdata2",
+        TDATA3 => "tdata3",
+        TINFO => "tinfo",
+        TCONTROL => "tcontrol",
+        MCONTEXT => "mcontext",
+        SCONTEXT => "scontext",
+        DCSR => "dcsr",
+        DPC => "dpc",
+        DSCRATCH0 => "dscratch0",
+        DSCRATCH1 => "dscratch1",
+        MCYCLE => "mcycle",
+        MINSTRET => "minstret",
+        MHPMCOUNTER3 => "mhpmcounter3",
+        MHPMCOUNTER4 => "mhpmcounter4",
+        MHPMCOUNTER5 => "mhpmcounter5",
+        MHPMCOUNTER6 => "mhpmcounter6",
+        MHPMCOUNTER7 => "mhpmcounter7",
+        MHPMCOUNTER8 => "mhpmcounter8",
+        MHPMCOUNTER9 => "mhpmcounter9",
+        MHPMCOUNTER10 => "mhpmcounter10",
+        MHPMCOUNTER11 => "mhpmcounter11",
+        MHPMCOUNTER12 => "mhpmcounter12",
+        MHPMCOUNTER13 => "mhpmcounter13",
+        MHPMCOUNTER14 => "mhpmcounter14",
+        MHPMCOUNTER15 => "mhpmcounter15",
+        MHPMCOUNTER16 => "mhpmcounter16",
+        MHPMCOUNTER17 => "mhpmcounter17",
+        MHPMCOUNTER18 => "mhpmcounter18",
+        MHPMCOUNTER19 => "mhpmcounter19",
+        MHPMCOUNTER20 => "mhpmcounter20",
+        MHPMCOUNTER21 => "mhpmcounter21",
+        MHPMCOUNTER22 => "mhpmcounter22",
+        MHPMCOUNTER23 => "mhpmcounter23",
+        MHPMCOUNTER24 => "mhpmcounter24",
+        MHPMCOUNTER25 => "mhpmcounter25",
+        MHPMCOUNTER26 => "mhpmcounter26",
+        MHPMCOUNTER27 => "mhpmcounter27",
+        MHPMCOUNTER28 => "mhpmcounter28",
+        MHPMCOUNTER29 => "mhpmcounter29",
+        MHPMCOUNTER30 => "mhpmcounter30",
+        MHPMCOUNTER31 => "mhpmcounter31",
+        MHPMEVENT3 => "mhpmevent3",
+        MHPMEVENT4 => "mhpmevent4",
+        MHPMEVENT5 => "mhpmevent5",
+        MHPMEVENT6 => "mhpmevent6",
+        MHPMEVENT7 => "mhpmevent7",
+        MHPMEVENT8 => "mhpmevent8",
+        MHPMEVENT9 => "mhpmevent9",
+        MHPMEVENT10 => "mhpmevent10",
+        MHPMEVENT11 => "mhpmevent11",
+        MHPMEVENT12 => "mhpmevent12",
+        MHPMEVENT13 => "mhpmevent13",
+        MHPMEVENT14 => "mhpmevent14",
+        MHPMEVENT15 => "mhpmevent15",
+        MHPMEVENT16 => "mhpmevent16",
+        MHPMEVENT17 => "mhpmevent17",
+        MHPMEVENT18 => "mhpmevent18",
+        MHPMEVENT19 => "mhpmevent19",
+        MHPMEVENT20 => "mhpmevent20",
+        MHPMEVENT21 => "mhpmevent21",
+        MHPMEVENT22 => "mhpmevent22",
+        MHPMEVENT23 => "mhpmevent23",
+        MHPMEVENT24 => "mhpmevent24",
+        MHPMEVENT25 => "mhpmevent25",
+        MHPMEVENT26 => "mhpmevent26",
+        MHPMEVENT27 => "mhpmevent27",
+        MHPMEVENT28 => "mhpmevent28",
+        MHPMEVENT29 => "mhpmevent29",
+        MHPMEVENT30 => "mhpmevent30",
+        MHPMEVENT31 => "mhpmevent31",
+        MVENDORID => "mvendorid",
+        MARCHID => "marchid",
+        MIMPID => "mimpid",
+        MHARTID => "mhartid",
+        HTIMEDELTAH => "htimedeltah",
+        CYCLEH => "cycleh",
+        TIMEH => "timeh",
+        INSTRETH => "instreth",
+        HPMCOUNTER3H => "hpmcounter3h",
+        HPMCOUNTER4H => "hpmcounter4h",
+        HPMCOUNTER5H => "hpmcounter5h",
+        HPMCOUNTER6H => "hpmcounter6h",
+        HPMCOUNTER7H => "hpmcounter7h",
+        HPMCOUNTER8H => "hpmcounter8h",
+        HPMCOUNTER9H => "hpmcounter9h",
+        HPMCOUNTER10H => "hpmcounter10h",
+        HPMCOUNTER11H => "hpmcounter11h",
+        HPMCOUNTER12H => "hpmcounter12h",
+        HPMCOUNTER13H => "hpmcounter13h",
+        HPMCOUNTER14H => "hpmcounter14h",
+        HPMCOUNTER15H => "hpmcounter15h",
+        HPMCOUNTER16H => "hpmcounter16h",
+        HPMCOUNTER17H => "hpmcounter17h",
+        HPMCOUNTER18H => "hpmcounter18h",
+        HPMCOUNTER19H => "hpmcounter19h",
+        HPMCOUNTER20H => "hpmcounter20h",
+        HPMCOUNTER21H => "hpmcounter21h",
+        HPMCOUNTER22H => "hpmcounter22h",
+        HPMCOUNTER23H => "hpmcounter23h",
+        HPMCOUNTER24H => "hpmcounter24h",
+        HPMCOUNTER25H => "hpmcounter25h",
+        HPMCOUNTER26H => "hpmcounter26h",
+        HPMCOUNTER27H => "hpmcounter27h",
+        HPMCOUNTER28H => "hpmcounter28h",
+        HPMCOUNTER29H => "hpmcounter29h",
+        HPMCOUNTER30H => "hpmcounter30h",
+        HPMCOUNTER31H => "hpmcounter31h",
+        MSTATUSH => "mstatush",
+        MCYCLEH => "mcycleh",
+        MINSTRETH => "minstreth",
+        MHPMCOUNTER3H => "mhpmcounter3h",
+        MHPMCOUNTER4H => "mhpmcounter4h",
+        MHPMCOUNTER5H => "mhpmcounter5h",
+        MHPMCOUNTER6H => "mhpmcounter6h",
+        MHPMCOUNTER7H => "mhpmcounter7h",
+        MHPMCOUNTER8H => "mhpmcounter8h",
+        MHPMCOUNTER9H => "mhpmcounter9h",
+        MHPMCOUNTER10H => "mhpmcounter10h",
+        MHPMCOUNTER11H => "mhpmcounter11h",
+        MHPMCOUNTER12H => "mhpmcounter12h",
+        MHPMCOUNTER13H => "mhpmcounter13h",
+        MHPMCOUNTER14H => "mhpmcounter14h",
+        MHPMCOUNTER15H => "mhpmcounter15h",
+        MHPMCOUNTER16H => "mhpmcounter16h",
+        MHPMCOUNTER17H => "mhpmcounter17h",
+        MHPMCOUNTER18H => "mhpmcounter18h",
+        MHPMCOUNTER19H => "mhpmcounter19h",
+        MHPMCOUNTER20H => "mhpmcounter20h",
+        MHPMCOUNTER21H => "mhpmcounter21h",
+        MHPMCOUNTER22H => "mhpmcounter22h",
+        MHPMCOUNTER23H => "mhpmcounter23h",
+        MHPMCOUNTER24H => "mhpmcounter24h",
+        MHPMCOUNTER25H => "mhpmcounter25h",
+        MHPMCOUNTER26H => "mhpmcounter26h",
+        MHPMCOUNTER27H => "mhpmcounter27h",
+        MHPMCOUNTER28H => "mhpmcounter28h",
+        MHPMCOUNTER29H => "mhpmcounter29h",
+        MHPMCOUNTER30H => "mhpmcounter30h",
+        MHPMCOUNTER31H => "mhpmcounter31h",
+        _ => "unknown",
+    }
+}
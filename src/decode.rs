@@ -0,0 +1,461 @@
+//! The decode module caches decoded instructions. `Cpu::execute` extracts the opcode, the
+//! registers and the immediate of an instruction with bit masks every time it runs it; a
+//! `Decoded` instruction has them extracted once, together with the handler that executes it.
+//!
+//! Handlers exist for the base integer instructions that make up most of what runs. Every other
+//! instruction, including the ones of extensions that misa may turn off, is executed by
+//! `Cpu::execute` from its word, so that both ways of executing an instruction behave the same.
+//!
+//! The `DecodeCache` keeps decoded instructions by their physical address. An entry is valid as
+//! long as the generation of its page in `Memory` hasn't changed, which any write to the page
+//! does, be it a store of a hart or DMA of a device.
+
+use crate::bus::Memory;
+use crate::cpu::Cpu;
+use crate::exception::Exception;
+
+/// Executes a decoded instruction. The pc already points at the next instruction.
+pub type Handler = fn(&mut Cpu, &Decoded) -> Result<(), Exception>;
+
+/// An instruction with its fields extracted.
+#[derive(Clone, Copy)]
+pub struct Decoded {
+    /// The instruction word.
+    pub inst: u32,
+    rd: u8,
+    rs1: u8,
+    rs2: u8,
+    imm: u64,
+    handler: Handler,
+}
+
+impl Decoded {
+    /// Execute the instruction on `cpu`.
+    #[inline]
+    pub fn execute(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        (self.handler)(cpu, self)
+    }
+
+    #[inline]
+    pub(crate) fn rd(&self) -> usize {
+        self.rd as usize
+    }
+
+    #[inline]
+    pub(crate) fn rs1(&self) -> usize {
+        self.rs1 as usize
+    }
+
+    #[inline]
+    pub(crate) fn rs2(&self) -> usize {
+        self.rs2 as usize
+    }
+
+    /// The immediate, sign-extended. It's 0 for instructions without a handler of their own.
+    #[inline]
+    pub(crate) fn imm(&self) -> u64 {
+        self.imm
+    }
+}
+
+/// Decode `inst`.
+pub fn decode(inst: u32) -> Decoded {
+    let opcode = inst & 0x0000007f;
+    let funct3 = (inst & 0x00007000) >> 12;
+    let funct7 = (inst & 0xfe000000) >> 25;
+    // The immediates of the I, S, B, U and J formats.
+    let i_imm = ((inst as i32 as i64) >> 20) as u64;
+    let s_imm = (((inst & 0xfe000000) as i32 as i64 >> 20) as u64) | ((inst >> 7) & 0x1f) as u64;
+    let b_imm = ((inst & 0x80000000) as i32 as i64 >> 19) as u64
+        | ((inst >> 20) & 0x7e0) as u64
+        | ((inst & 0x80) << 4) as u64
+        | ((inst >> 7) & 0x1e) as u64;
+    let u_imm = (inst & 0xfffff000) as i32 as i64 as u64;
+    let j_imm = (((inst & 0x80000000) as i32 as i64 >> 11) as u64)
+        | ((inst >> 20) & 0x7fe) as u64
+        | ((inst >> 9) & 0x800) as u64
+        | (inst & 0xff000) as u64;
+
+    let (imm, handler): (u64, Handler) = match (opcode, funct3, funct7) {
+        (0x03, 0x0, _) => (i_imm, lb),
+        (0x03, 0x1, _) => (i_imm, lh),
+        (0x03, 0x2, _) => (i_imm, lw),
+        (0x03, 0x3, _) => (i_imm, ld),
+        (0x03, 0x4, _) => (i_imm, lbu),
+        (0x03, 0x5, _) => (i_imm, lhu),
+        (0x03, 0x6, _) => (i_imm, lwu),
+        (0x13, 0x0, _) => (i_imm, addi),
+        (0x13, 0x1, 0x00 | 0x01) => (i_imm, slli),
+        (0x13, 0x2, _) => (i_imm, slti),
+        (0x13, 0x3, _) => (i_imm, sltiu),
+        (0x13, 0x4, _) => (i_imm, xori),
+        (0x13, 0x5, 0x00 | 0x01) => (i_imm, srli),
+        (0x13, 0x5, 0x20 | 0x21) => (i_imm, srai),
+        (0x13, 0x6, _) => (i_imm, ori),
+        (0x13, 0x7, _) => (i_imm, andi),
+        (0x17, _, _) => (u_imm, auipc),
+        (0x1b, 0x0, _) => (i_imm, addiw),
+        (0x1b, 0x1, 0x00) => (i_imm, slliw),
+        (0x1b, 0x5, 0x00) => (i_imm, srliw),
+        (0x1b, 0x5, 0x20) => (i_imm, sraiw),
+        (0x23, 0x0, _) => (s_imm, sb),
+        (0x23, 0x1, _) => (s_imm, sh),
+        (0x23, 0x2, _) => (s_imm, sw),
+        (0x23, 0x3, _) => (s_imm, sd),
+        (0x33, 0x0, 0x00) => (0, add),
+        (0x33, 0x0, 0x20) => (0, sub),
+        (0x33, 0x1, 0x00) => (0, sll),
+        (0x33, 0x2, 0x00) => (0, slt),
+        (0x33, 0x3, 0x00) => (0, sltu),
+        (0x33, 0x4, 0x00) => (0, xor),
+        (0x33, 0x5, 0x00) => (0, srl),
+        (0x33, 0x5, 0x20) => (0, sra),
+        (0x33, 0x6, 0x00) => (0, or),
+        (0x33, 0x7, 0x00) => (0, and),
+        (0x37, _, _) => (u_imm, lui),
+        (0x3b, 0x0, 0x00) => (0, addw),
+        (0x3b, 0x0, 0x20) => (0, subw),
+        (0x3b, 0x1, 0x00) => (0, sllw),
+        (0x3b, 0x5, 0x00) => (0, srlw),
+        (0x3b, 0x5, 0x20) => (0, sraw),
+        (0x63, 0x0, _) => (b_imm, beq),
+        (0x63, 0x1, _) => (b_imm, bne),
+        (0x63, 0x4, _) => (b_imm, blt),
+        (0x63, 0x5, _) => (b_imm, bge),
+        (0x63, 0x6, _) => (b_imm, bltu),
+        (0x63, 0x7, _) => (b_imm, bgeu),
+        (0x67, _, _) => (i_imm, jalr),
+        (0x6f, _, _) => (j_imm, jal),
+        _ => (0, fallback),
+    };
+    Decoded {
+        inst,
+        rd: ((inst & 0x00000f80) >> 7) as u8,
+        rs1: ((inst & 0x000f8000) >> 15) as u8,
+        rs2: ((inst & 0x01f00000) >> 20) as u8,
+        imm,
+        handler,
+    }
+}
+
+/// The number of entries of a `DecodeCache`, a power of two.
+const CACHE_ENTRIES: usize = 1 << 15;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    /// The physical address of the instruction, or `u64::MAX` if the entry is empty.
+    address: u64,
+    /// The generation of the page of the instruction when it was decoded.
+    generation: u32,
+    decoded: Decoded,
+}
+
+/// A direct-mapped cache of decoded instructions, indexed by physical address.
+pub struct DecodeCache {
+    entries: Box<[Entry]>,
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        let empty = Entry {
+            address: u64::MAX,
+            generation: 0,
+            decoded: decode(0),
+        };
+        Self {
+            entries: vec![empty; CACHE_ENTRIES].into_boxed_slice(),
+        }
+    }
+
+    #[inline]
+    fn index(address: u64) -> usize {
+        (address >> 2) as usize & (CACHE_ENTRIES - 1)
+    }
+
+    /// Return the instruction at the physical address `address` of `memory` if it's cached and
+    /// still valid.
+    #[inline]
+    pub fn get(&self, memory: &Memory, address: u64) -> Option<Decoded> {
+        let entry = &self.entries[Self::index(address)];
+        if entry.address == address && entry.generation == memory.generation(address) {
+            Some(entry.decoded)
+        } else {
+            None
+        }
+    }
+
+    /// Decode the instruction at the physical address `address` of `memory` and cache it.
+    pub fn fill(&mut self, memory: &Memory, address: u64) -> Result<Decoded, Exception> {
+        // Mark the page first, so that a write after the load below invalidates the entry.
+        let generation = memory.cache_code(address);
+        let decoded = decode(memory.load(address, 32)? as u32);
+        self.entries[Self::index(address)] = Entry {
+            address,
+            generation,
+            decoded,
+        };
+        Ok(decoded)
+    }
+
+    /// Forget all instructions.
+    pub fn flush(&mut self) {
+        for entry in self.entries.iter_mut() {
+            entry.address = u64::MAX;
+        }
+    }
+}
+
+fn fallback(cpu: &mut Cpu, d: &Decoded) -> Result<(), Exception> {
+    cpu.execute(d.inst)
+}
+
+/// The address of a load or store.
+#[inline]
+fn address(cpu: &Cpu, d: &Decoded) -> u64 {
+    cpu.regs[d.rs1()].wrapping_add(d.imm)
+}
+
+fn lb(cpu: &mut Cpu, d: &Decoded) -> Result<(), Exception> {
+    let value = cpu.load(address(cpu, d), 8)?;
+    cpu.regs[d.rd()] = value as i8 as i64 as u64;
+    Ok(())
+}
+
+fn lh(cpu: &mut Cpu, d: &Decoded) -> Result<(), Exception> {
+    let value = cpu.load(address(cpu, d), 16)?;
+    cpu.regs[d.rd()] = value as i16 as i64 as u64;
+    Ok(())
+}
+
+fn lw(cpu: &mut Cpu, d: &Decoded) -> Result<(), Exception> {
+    let value = cpu.load(address(cpu, d), 32)?;
+    cpu.regs[d.rd()] = value as i32 as i64 as u64;
+    Ok(())
+}
+
+fn ld(cpu: &mut Cpu, d: &Decoded) -> Result<(), Exception> {
+    cpu.regs[d.rd()] = cpu.load(address(cpu, d), 64)?;
+    Ok(())
+}
+
+fn lbu(cpu: &mut Cpu, d: &Decoded) -> Result<(), Exception> {
+    cpu.regs[d.rd()] = cpu.load(address(cpu, d), 8)?;
+    Ok(())
+}
+
+fn lhu(cpu: &mut Cpu, d: &Decoded) -> Result<(), Exception> {
+    cpu.regs[d.rd()] = cpu.load(address(cpu, d), 16)?;
+    Ok(())
+}
+
+fn lwu(cpu: &mut Cpu, d: &Decoded) -> Result<(), Exception> {
+    cpu.regs[d.rd()] = cpu.load(address(cpu, d), 32)?;
+    Ok(())
+}
+
+fn sb(cpu: &mut Cpu, d: &Decoded) -> Result<(), Exception> {
+    cpu.store(address(cpu, d), 8, cpu.regs[d.rs2()])
+}
+
+fn sh(cpu: &mut Cpu, d: &Decoded) -> Result<(), Exception> {
+    cpu.store(address(cpu, d), 16, cpu.regs[d.rs2()])
+}
+
+fn sw(cpu: &mut Cpu, d: &Decoded) -> Result<(), Exception> {
+    cpu.store(address(cpu, d), 32, cpu.regs[d.rs2()])
+}
+
+fn sd(cpu: &mut Cpu, d: &Decoded) -> Result<(), Exception> {
+    cpu.store(address(cpu, d), 64, cpu.regs[d.rs2()])
+}
+
+/// Define handlers that set rd to a function of rs1 and the immediate.
+macro_rules! immediate {
+    ($($name:ident: |$rs1:ident, $imm:ident| $value:expr;)*) => {
+        $(
+            fn $name(cpu: &mut Cpu, d: &Decoded) -> Result<(), Exception> {
+                let $rs1 = cpu.regs[d.rs1()];
+                let $imm = d.imm;
+                cpu.regs[d.rd()] = $value;
+                Ok(())
+            }
+        )*
+    };
+}
+
+/// Define handlers that set rd to a function of rs1 and rs2.
+macro_rules! register {
+    ($($name:ident: |$rs1:ident, $rs2:ident| $value:expr;)*) => {
+        $(
+            fn $name(cpu: &mut Cpu, d: &Decoded) -> Result<(), Exception> {
+                let $rs1 = cpu.regs[d.rs1()];
+                let $rs2 = cpu.regs[d.rs2()];
+                cpu.regs[d.rd()] = $value;
+                Ok(())
+            }
+        )*
+    };
+}
+
+/// Define handlers that branch by the immediate if a condition on rs1 and rs2 holds.
+macro_rules! branch {
+    ($($name:ident: |$rs1:ident, $rs2:ident| $taken:expr;)*) => {
+        $(
+            fn $name(cpu: &mut Cpu, d: &Decoded) -> Result<(), Exception> {
+                let $rs1 = cpu.regs[d.rs1()];
+                let $rs2 = cpu.regs[d.rs2()];
+                if $taken {
+                    cpu.pc = cpu.pc.wrapping_sub(4).wrapping_add(d.imm);
+                }
+                Ok(())
+            }
+        )*
+    };
+}
+
+immediate! {
+    addi: |rs1, imm| rs1.wrapping_add(imm);
+    slli: |rs1, imm| rs1 << (imm & 0x3f);
+    slti: |rs1, imm| ((rs1 as i64) < (imm as i64)) as u64;
+    sltiu: |rs1, imm| (rs1 < imm) as u64;
+    xori: |rs1, imm| rs1 ^ imm;
+    srli: |rs1, imm| rs1.wrapping_shr((imm & 0x3f) as u32);
+    srai: |rs1, imm| (rs1 as i64).wrapping_shr((imm & 0x3f) as u32) as u64;
+    ori: |rs1, imm| rs1 | imm;
+    andi: |rs1, imm| rs1 & imm;
+    addiw: |rs1, imm| rs1.wrapping_add(imm) as i32 as i64 as u64;
+    slliw: |rs1, imm| rs1.wrapping_shl((imm & 0x1f) as u32) as i32 as i64 as u64;
+    srliw: |rs1, imm| (rs1 as u32).wrapping_shr((imm & 0x1f) as u32) as i32 as i64 as u64;
+    sraiw: |rs1, imm| (rs1 as i32).wrapping_shr((imm & 0x1f) as u32) as i64 as u64;
+}
+
+register! {
+    add: |rs1, rs2| rs1.wrapping_add(rs2);
+    sub: |rs1, rs2| rs1.wrapping_sub(rs2);
+    sll: |rs1, rs2| rs1.wrapping_shl((rs2 & 0x3f) as u32);
+    slt: |rs1, rs2| ((rs1 as i64) < (rs2 as i64)) as u64;
+    sltu: |rs1, rs2| (rs1 < rs2) as u64;
+    xor: |rs1, rs2| rs1 ^ rs2;
+    srl: |rs1, rs2| rs1.wrapping_shr((rs2 & 0x3f) as u32);
+    sra: |rs1, rs2| (rs1 as i64).wrapping_shr((rs2 & 0x3f) as u32) as u64;
+    or: |rs1, rs2| rs1 | rs2;
+    and: |rs1, rs2| rs1 & rs2;
+    addw: |rs1, rs2| rs1.wrapping_add(rs2) as i32 as i64 as u64;
+    subw: |rs1, rs2| rs1.wrapping_sub(rs2) as i32 as u64;
+    sllw: |rs1, rs2| (rs1 as u32).wrapping_shl((rs2 & 0x1f) as u32) as i32 as u64;
+    srlw: |rs1, rs2| (rs1 as u32).wrapping_shr((rs2 & 0x1f) as u32) as i32 as u64;
+    sraw: |rs1, rs2| ((rs1 as i32) >> ((rs2 & 0x1f) as i32)) as u64;
+}
+
+branch! {
+    beq: |rs1, rs2| rs1 == rs2;
+    bne: |rs1, rs2| rs1 != rs2;
+    blt: |rs1, rs2| (rs1 as i64) < (rs2 as i64);
+    bge: |rs1, rs2| (rs1 as i64) >= (rs2 as i64);
+    bltu: |rs1, rs2| rs1 < rs2;
+    bgeu: |rs1, rs2| rs1 >= rs2;
+}
+
+fn lui(cpu: &mut Cpu, d: &Decoded) -> Result<(), Exception> {
+    cpu.regs[d.rd()] = d.imm;
+    Ok(())
+}
+
+fn auipc(cpu: &mut Cpu, d: &Decoded) -> Result<(), Exception> {
+    cpu.regs[d.rd()] = cpu.pc.wrapping_sub(4).wrapping_add(d.imm);
+    Ok(())
+}
+
+fn jal(cpu: &mut Cpu, d: &Decoded) -> Result<(), Exception> {
+    cpu.regs[d.rd()] = cpu.pc;
+    cpu.pc = cpu.pc.wrapping_sub(4).wrapping_add(d.imm);
+    Ok(())
+}
+
+fn jalr(cpu: &mut Cpu, d: &Decoded) -> Result<(), Exception> {
+    let next = cpu.pc;
+    cpu.pc = cpu.regs[d.rs1()].wrapping_add(d.imm) & !1;
+    cpu.regs[d.rd()] = next;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::MEMORY_BASE;
+    use crate::machine::Machine;
+
+    /// addi x5, x0, `imm`
+    fn addi(imm: u32) -> u32 {
+        (imm << 20) | (5 << 7) | 0x13
+    }
+
+    #[test]
+    fn writes_invalidate_the_page() {
+        let mut memory = Memory::new(0x4000);
+        let mut cache = DecodeCache::new();
+        memory.store(MEMORY_BASE, 32, addi(1) as u64).unwrap();
+        assert!(cache.get(&memory, MEMORY_BASE).is_none());
+        assert_eq!(cache.fill(&memory, MEMORY_BASE).unwrap().inst, addi(1));
+        assert_eq!(cache.get(&memory, MEMORY_BASE).unwrap().inst, addi(1));
+
+        // A store to another page leaves the entry alone, and one to the same page, even to
+        // another word, invalidates it.
+        memory.store(MEMORY_BASE + 0x1000, 32, 0).unwrap();
+        assert!(cache.get(&memory, MEMORY_BASE).is_some());
+        memory.store(MEMORY_BASE + 0xff8, 64, 0).unwrap();
+        assert!(cache.get(&memory, MEMORY_BASE).is_none());
+
+        // So does DMA of a device.
+        cache.fill(&memory, MEMORY_BASE).unwrap();
+        memory
+            .store_bytes(MEMORY_BASE, &addi(2).to_le_bytes())
+            .unwrap();
+        assert!(cache.get(&memory, MEMORY_BASE).is_none());
+        assert_eq!(cache.fill(&memory, MEMORY_BASE).unwrap().inst, addi(2));
+
+        // FENCE.I forgets everything.
+        cache.flush();
+        assert!(cache.get(&memory, MEMORY_BASE).is_none());
+    }
+
+    #[test]
+    fn modified_code_runs() {
+        let mut machine = Machine::builder().build().unwrap();
+        let cpu = machine.cpu_mut();
+        // addi x5, x0, 1; sw x6, 0(x7); fence.i
+        let code = [addi(1), (6 << 20) | (7 << 15) | (0x2 << 12) | 0x23, 0x100f];
+        for (i, inst) in code.iter().enumerate() {
+            cpu.bus
+                .store(MEMORY_BASE + 4 * i as u64, 32, *inst as u64)
+                .unwrap();
+        }
+        cpu.pc = MEMORY_BASE;
+        cpu.regs[6] = addi(2) as u64;
+        cpu.regs[7] = MEMORY_BASE;
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.regs[5], 1);
+
+        // The store of the hart replaced the first instruction.
+        cpu.pc = MEMORY_BASE;
+        cpu.step().unwrap();
+        assert_eq!(cpu.regs[5], 2);
+
+        // And so does DMA.
+        cpu.bus
+            .memory
+            .store_bytes(MEMORY_BASE, &addi(3).to_le_bytes())
+            .unwrap();
+        cpu.pc = MEMORY_BASE;
+        cpu.step().unwrap();
+        assert_eq!(cpu.regs[5], 3);
+    }
+}
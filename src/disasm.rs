@@ -0,0 +1,741 @@
+//! The disasm module turns raw instruction words into assembly text. The output follows the
+//! syntax used by Spike and objdump, e.g. `addi    sp, sp, -16` or `ld      a0, 8(sp)`.
+
+use crate::bitmanip;
+use crate::crypto::{self, Op};
+use crate::csr::csr_name;
+
+/// ABI names of the integer registers x0-x31.
+pub const ABI: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// Join a mnemonic and its operands the way Spike does: the operands start at column 8, or one
+/// space after a longer mnemonic.
+fn format(name: &str, args: &[String]) -> String {
+    if args.is_empty() {
+        return name.to_string();
+    }
+    let pad = if name.len() < 8 { 8 - name.len() } else { 1 };
+    format!("{}{}{}", name, " ".repeat(pad), args.join(", "))
+}
+
+fn x(reg: usize) -> String {
+    ABI[reg].to_string()
+}
+
+fn f(reg: usize) -> String {
+    format!("f{}", reg)
+}
+
+fn v(reg: usize) -> String {
+    format!("v{}", reg)
+}
+
+/// Format a pc-relative target as `pc + 16` or `pc - 8`.
+fn pc_rel(offset: i64) -> String {
+    if offset < 0 {
+        format!("pc - {}", -offset)
+    } else {
+        format!("pc + {}", offset)
+    }
+}
+
+fn csr(addr: usize) -> String {
+    match csr_name(addr) {
+        "unknown" => format!("{:#x}", addr),
+        name => name.to_string(),
+    }
+}
+
+/// Return the disassembly of `inst`. Unknown encodings are shown as `unknown`.
+pub fn disassemble(inst: u32) -> String {
+    let opcode = inst & 0x0000007f;
+    let rd = ((inst & 0x00000f80) >> 7) as usize;
+    let rs1 = ((inst & 0x000f8000) >> 15) as usize;
+    let rs2 = ((inst & 0x01f00000) >> 20) as usize;
+    let funct3 = (inst & 0x00007000) >> 12;
+    let funct7 = (inst & 0xfe000000) >> 25;
+    let imm_i = (inst as i32 as i64) >> 20;
+    let imm_s = ((inst & 0xfe000000) as i32 as i64 >> 20) | ((inst >> 7) & 0x1f) as i64;
+
+    let unknown = || "unknown".to_string();
+    if let Some(op) = bitmanip::decode(inst) {
+        return if op.is_unary() {
+            format(op.name(), &[x(rd), x(rs1)])
+        } else if op.is_immediate() {
+            format(op.name(), &[x(rd), x(rs1), (imm_i & 0x3f).to_string()])
+        } else {
+            format(op.name(), &[x(rd), x(rs1), x(rs2)])
+        };
+    }
+    if let Some(op) = crypto::decode(inst) {
+        return match op {
+            Op::Aes64ks1i { rnum } => format(op.name(), &[x(rd), x(rs1), rnum.to_string()]),
+            Op::Sm4ed { bs } | Op::Sm4ks { bs } => {
+                format(op.name(), &[x(rd), x(rs1), x(rs2), bs.to_string()])
+            }
+            _ if op.is_unary() => format(op.name(), &[x(rd), x(rs1)]),
+            _ => format(op.name(), &[x(rd), x(rs1), x(rs2)]),
+        };
+    }
+    match opcode {
+        0x07 | 0x27 if funct3 == 0x2 || funct3 == 0x3 => {
+            let name = match (opcode, funct3) {
+                (0x07, 0x2) => "flw",
+                (0x07, _) => "fld",
+                (_, 0x2) => "fsw",
+                _ => "fsd",
+            };
+            let (reg, imm) = if opcode == 0x07 {
+                (rd, imm_i)
+            } else {
+                (rs2, imm_s)
+            };
+            format(name, &[f(reg), format!("{}({})", imm, x(rs1))])
+        }
+        0x07 | 0x27 => vector_memory(inst),
+        0x43 | 0x47 | 0x4b | 0x4f | 0x53 => floating_point(inst),
+        0x57 => vector(inst),
+        0x03 => {
+            let name = match funct3 {
+                0x0 => "lb",
+                0x1 => "lh",
+                0x2 => "lw",
+                0x3 => "ld",
+                0x4 => "lbu",
+                0x5 => "lhu",
+                0x6 => "lwu",
+                _ => return unknown(),
+            };
+            format(name, &[x(rd), format!("{}({})", imm_i, x(rs1))])
+        }
+        0x0f => match funct3 {
+            0x0 => "fence".to_string(),
+            0x1 => "fence.i".to_string(),
+            _ => unknown(),
+        },
+        0x13 => {
+            let shamt = (imm_i & 0x3f).to_string();
+            match funct3 {
+                0x0 => format("addi", &[x(rd), x(rs1), imm_i.to_string()]),
+                0x1 if funct7 >> 1 == 0x00 => format("slli", &[x(rd), x(rs1), shamt]),
+                0x1 => unknown(),
+                0x2 => format("slti", &[x(rd), x(rs1), imm_i.to_string()]),
+                0x3 => format("sltiu", &[x(rd), x(rs1), imm_i.to_string()]),
+                0x4 => format("xori", &[x(rd), x(rs1), imm_i.to_string()]),
+                0x5 => match funct7 >> 1 {
+                    0x00 => format("srli", &[x(rd), x(rs1), shamt]),
+                    0x10 => format("srai", &[x(rd), x(rs1), shamt]),
+                    _ => unknown(),
+                },
+                0x6 => format("ori", &[x(rd), x(rs1), imm_i.to_string()]),
+                _ => format("andi", &[x(rd), x(rs1), imm_i.to_string()]),
+            }
+        }
+        0x17 => format("auipc", &[x(rd), format!("{:#x}", inst >> 12)]),
+        0x1b => {
+            let shamt = (imm_i & 0x1f).to_string();
+            match (funct3, funct7) {
+                (0x0, _) => format("addiw", &[x(rd), x(rs1), imm_i.to_string()]),
+                (0x1, 0x00) => format("slliw", &[x(rd), x(rs1), shamt]),
+                (0x5, 0x00) => format("srliw", &[x(rd), x(rs1), shamt]),
+                (0x5, 0x20) => format("sraiw", &[x(rd), x(rs1), shamt]),
+                _ => unknown(),
+            }
+        }
+        0x23 => {
+            let name = match funct3 {
+                0x0 => "sb",
+                0x1 => "sh",
+                0x2 => "sw",
+                0x3 => "sd",
+                _ => return unknown(),
+            };
+            format(name, &[x(rs2), format!("{}({})", imm_s, x(rs1))])
+        }
+        0x2f => {
+            let width = match funct3 {
+                0x2 => "w",
+                0x3 => "d",
+                _ => return unknown(),
+            };
+            let funct5 = (funct7 & 0x7c) >> 2;
+            let op = match funct5 {
+                0x00 => "amoadd",
+                0x01 => "amoswap",
+                0x02 => "lr",
+                0x03 => "sc",
+                0x04 => "amoxor",
+                0x08 => "amoor",
+                0x0c => "amoand",
+                0x10 => "amomin",
+                0x14 => "amomax",
+                0x18 => "amominu",
+                0x1c => "amomaxu",
+                _ => return unknown(),
+            };
+            let ordering = match funct7 & 0x3 {
+                0x3 => ".aqrl",
+                0x2 => ".aq",
+                0x1 => ".rl",
+                _ => "",
+            };
+            let name = format!("{}.{}{}", op, width, ordering);
+            if funct5 == 0x02 {
+                format(&name, &[x(rd), format!("({})", x(rs1))])
+            } else {
+                format(&name, &[x(rd), x(rs2), format!("({})", x(rs1))])
+            }
+        }
+        0x33 => {
+            let name = match (funct3, funct7) {
+                (0x0, 0x00) => "add",
+                (0x0, 0x01) => "mul",
+                (0x0, 0x20) => "sub",
+                (0x1, 0x00) => "sll",
+                (0x1, 0x01) => "mulh",
+                (0x2, 0x00) => "slt",
+                (0x2, 0x01) => "mulhsu",
+                (0x3, 0x00) => "sltu",
+                (0x3, 0x01) => "mulhu",
+                (0x4, 0x00) => "xor",
+                (0x4, 0x01) => "div",
+                (0x5, 0x00) => "srl",
+                (0x5, 0x01) => "divu",
+                (0x5, 0x20) => "sra",
+                (0x6, 0x00) => "or",
+                (0x6, 0x01) => "rem",
+                (0x7, 0x00) => "and",
+                (0x7, 0x01) => "remu",
+                _ => return unknown(),
+            };
+            format(name, &[x(rd), x(rs1), x(rs2)])
+        }
+        0x37 => format("lui", &[x(rd), format!("{:#x}", inst >> 12)]),
+        0x3b => {
+            let name = match (funct3, funct7) {
+                (0x0, 0x00) => "addw",
+                (0x0, 0x01) => "mulw",
+                (0x0, 0x20) => "subw",
+                (0x1, 0x00) => "sllw",
+                (0x4, 0x01) => "divw",
+                (0x5, 0x00) => "srlw",
+                (0x5, 0x01) => "divuw",
+                (0x5, 0x20) => "sraw",
+                (0x6, 0x01) => "remw",
+                (0x7, 0x01) => "remuw",
+                _ => return unknown(),
+            };
+            format(name, &[x(rd), x(rs1), x(rs2)])
+        }
+        0x63 => {
+            let imm = ((inst & 0x80000000) as i32 as i64 >> 19)
+                | ((inst >> 20) & 0x7e0) as i64
+                | ((inst & 0x80) << 4) as i64
+                | ((inst >> 7) & 0x1e) as i64;
+            let name = match funct3 {
+                0x0 => "beq",
+                0x1 => "bne",
+                0x4 => "blt",
+                0x5 => "bge",
+                0x6 => "bltu",
+                0x7 => "bgeu",
+                _ => return unknown(),
+            };
+            format(name, &[x(rs1), x(rs2), pc_rel(imm)])
+        }
+        0x67 => format("jalr", &[x(rd), format!("{}({})", imm_i, x(rs1))]),
+        0x6f => {
+            let imm = ((inst & 0x80000000) as i32 as i64 >> 11)
+                | ((inst >> 20) & 0x7fe) as i64
+                | ((inst >> 9) & 0x800) as i64
+                | (inst & 0xff000) as i64;
+            format("jal", &[x(rd), pc_rel(imm)])
+        }
+        0x73 => {
+            let addr = ((inst & 0xfff00000) >> 20) as usize;
+            match funct3 {
+                0x0 => match (rs2, funct7) {
+                    (0x0, 0x0) => "ecall".to_string(),
+                    (0x1, 0x0) => "ebreak".to_string(),
+                    (0x2, 0x8) => "sret".to_string(),
+                    (0x2, 0x18) => "mret".to_string(),
+                    (0x5, 0x8) => "wfi".to_string(),
+                    (_, 0x9) => format("sfence.vma", &[x(rs1), x(rs2)]),
+                    (_, 0x11) => format("hfence.vvma", &[x(rs1), x(rs2)]),
+                    (_, 0x31) => format("hfence.gvma", &[x(rs1), x(rs2)]),
+                    _ => unknown(),
+                },
+                0x1 => format("csrrw", &[x(rd), csr(addr), x(rs1)]),
+                0x2 => format("csrrs", &[x(rd), csr(addr), x(rs1)]),
+                0x3 => format("csrrc", &[x(rd), csr(addr), x(rs1)]),
+                0x5 => format("csrrwi", &[x(rd), csr(addr), rs1.to_string()]),
+                0x6 => format("csrrsi", &[x(rd), csr(addr), rs1.to_string()]),
+                0x7 => format("csrrci", &[x(rd), csr(addr), rs1.to_string()]),
+                0x4 => {
+                    let address = format!("({})", x(rs1));
+                    let name = match (funct7, rs2) {
+                        (0x30, 0x0) => "hlv.b",
+                        (0x30, 0x1) => "hlv.bu",
+                        (0x32, 0x0) => "hlv.h",
+                        (0x32, 0x1) => "hlv.hu",
+                        (0x32, 0x3) => "hlvx.hu",
+                        (0x34, 0x0) => "hlv.w",
+                        (0x34, 0x1) => "hlv.wu",
+                        (0x34, 0x3) => "hlvx.wu",
+                        (0x36, 0x0) => "hlv.d",
+                        (0x31, _) if rd == 0 => return format("hsv.b", &[x(rs2), address]),
+                        (0x33, _) if rd == 0 => return format("hsv.h", &[x(rs2), address]),
+                        (0x35, _) if rd == 0 => return format("hsv.w", &[x(rs2), address]),
+                        (0x37, _) if rd == 0 => return format("hsv.d", &[x(rs2), address]),
+                        _ => return unknown(),
+                    };
+                    format(name, &[x(rd), address])
+                }
+                _ => unknown(),
+            }
+        }
+        _ => unknown(),
+    }
+}
+
+/// Return the disassembly of an F or D instruction but a load or store.
+fn floating_point(inst: u32) -> String {
+    let opcode = inst & 0x0000007f;
+    let rd = ((inst & 0x00000f80) >> 7) as usize;
+    let rs1 = ((inst & 0x000f8000) >> 15) as usize;
+    let rs2 = ((inst & 0x01f00000) >> 20) as usize;
+    let funct3 = (inst & 0x00007000) >> 12;
+    let format_suffix = match (inst >> 25) & 0x3 {
+        0x0 => "s",
+        0x1 => "d",
+        _ => return "unknown".to_string(),
+    };
+    let op = |name: &str| format!("{}.{}", name, format_suffix);
+    if opcode != 0x53 {
+        let name = match opcode {
+            0x43 => "fmadd",
+            0x47 => "fmsub",
+            0x4b => "fnmsub",
+            _ => "fnmadd",
+        };
+        return format(
+            &op(name),
+            &[f(rd), f(rs1), f(rs2), f((inst >> 27) as usize)],
+        );
+    }
+    // The integer operand or result of conversions and moves.
+    let int = |suffix: u32| match suffix {
+        0x0 => "w",
+        0x1 => "wu",
+        0x2 => "l",
+        _ => "lu",
+    };
+    match inst >> 27 {
+        0x00 => format(&op("fadd"), &[f(rd), f(rs1), f(rs2)]),
+        0x01 => format(&op("fsub"), &[f(rd), f(rs1), f(rs2)]),
+        0x02 => format(&op("fmul"), &[f(rd), f(rs1), f(rs2)]),
+        0x03 => format(&op("fdiv"), &[f(rd), f(rs1), f(rs2)]),
+        0x0b => format(&op("fsqrt"), &[f(rd), f(rs1)]),
+        0x04 if funct3 <= 0x2 => {
+            let name = ["fsgnj", "fsgnjn", "fsgnjx"][funct3 as usize];
+            format(&op(name), &[f(rd), f(rs1), f(rs2)])
+        }
+        0x05 if funct3 <= 0x1 => {
+            let name = ["fmin", "fmax"][funct3 as usize];
+            format(&op(name), &[f(rd), f(rs1), f(rs2)])
+        }
+        0x08 => {
+            let source = if rs2 == 0 { "s" } else { "d" };
+            format(&format!("{}.{}", op("fcvt"), source), &[f(rd), f(rs1)])
+        }
+        0x14 if funct3 <= 0x2 => {
+            let name = ["fle", "flt", "feq"][funct3 as usize];
+            format(&op(name), &[x(rd), f(rs1), f(rs2)])
+        }
+        0x18 if rs2 <= 0x3 => {
+            let name = format!("fcvt.{}.{}", int(rs2 as u32), format_suffix);
+            format(&name, &[x(rd), f(rs1)])
+        }
+        0x1a if rs2 <= 0x3 => {
+            let name = format!("{}.{}", op("fcvt"), int(rs2 as u32));
+            format(&name, &[f(rd), x(rs1)])
+        }
+        0x1c if funct3 == 0x0 => {
+            let width = if format_suffix == "s" { "w" } else { "d" };
+            format(&format!("fmv.x.{}", width), &[x(rd), f(rs1)])
+        }
+        0x1c if funct3 == 0x1 => format(&op("fclass"), &[x(rd), f(rs1)]),
+        0x1e if funct3 == 0x0 => {
+            let width = if format_suffix == "s" { "w" } else { "d" };
+            format(&format!("fmv.{}.x", width), &[f(rd), x(rs1)])
+        }
+        _ => "unknown".to_string(),
+    }
+}
+
+/// Return the operands of a masked vector instruction, with `v0.t` after them if it's masked.
+fn masked(mut args: Vec<String>, inst: u32) -> Vec<String> {
+    if (inst >> 25) & 1 == 0 {
+        args.push("v0.t".to_string());
+    }
+    args
+}
+
+/// Return the disassembly of a vector load or store.
+fn vector_memory(inst: u32) -> String {
+    let store = inst & 0x7f == 0x27;
+    let rd = ((inst & 0x00000f80) >> 7) as usize;
+    let rs1 = ((inst & 0x000f8000) >> 15) as usize;
+    let rs2 = ((inst & 0x01f00000) >> 20) as usize;
+    let eew = match (inst >> 12) & 0x7 {
+        0x0 => 8,
+        0x5 => 16,
+        0x6 => 32,
+        0x7 => 64,
+        _ => return "unknown".to_string(),
+    };
+    let nf = (inst >> 29) + 1;
+    let segment = if nf > 1 {
+        format!("seg{}", nf)
+    } else {
+        String::new()
+    };
+    let prefix = if store { "vs" } else { "vl" };
+    let address = format!("({})", x(rs1));
+    match ((inst >> 26) & 0x3, rs2) {
+        (0x0, 0x08) if store => format(&format!("vs{}r.v", nf), &[v(rd), address]),
+        (0x0, 0x08) => format(&format!("vl{}re{}.v", nf, eew), &[v(rd), address]),
+        (0x0, 0x0b) => format(&format!("{}m.v", prefix), &[v(rd), address]),
+        (0x0, 0x00 | 0x10) => {
+            let first = if rs2 == 0x10 { "ff" } else { "" };
+            let name = format!("{}{}e{}{}.v", prefix, segment, eew, first);
+            format(&name, &masked(vec![v(rd), address], inst))
+        }
+        (0x2, _) => {
+            let name = format!("{}s{}e{}.v", prefix, segment, eew);
+            format(&name, &masked(vec![v(rd), address, x(rs2)], inst))
+        }
+        (0x1 | 0x3, _) => {
+            let order = if (inst >> 26) & 0x3 == 0x1 { "u" } else { "o" };
+            let name = format!("{}{}x{}ei{}.v", prefix, order, segment, eew);
+            format(&name, &masked(vec![v(rd), address, v(rs2)], inst))
+        }
+        _ => "unknown".to_string(),
+    }
+}
+
+/// Return vtype as vsetvli shows it, e.g. `e32, m1, ta, ma`.
+fn vtype(vtype: u32) -> String {
+    let lmul = ["m1", "m2", "m4", "m8", "m?", "mf8", "mf4", "mf2"][(vtype & 0x7) as usize];
+    let tail = if vtype & (1 << 6) != 0 { "ta" } else { "tu" };
+    let mask = if vtype & (1 << 7) != 0 { "ma" } else { "mu" };
+    format!(
+        "e{}, {}, {}, {}",
+        8 << ((vtype >> 3) & 0x7),
+        lmul,
+        tail,
+        mask
+    )
+}
+
+/// How an arithmetic vector instruction shows its operands.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Form {
+    /// vd, vs2, vs1, with the suffix of the category: `.vv`, `.vx`, `.vi` or `.vf`.
+    Binary,
+    /// Like `Binary`, with vs2 of 2 × SEW: `.wv`, `.wx`, `.wi` or `.wf`.
+    Wide,
+    /// vd, vs1, vs2, for the multiply-adds.
+    Accumulate,
+    /// vd, vs2, vs1, v0 as an operand: `.vvm`, `.vxm`, `.vim` or `.vfm`.
+    Carry,
+    /// A reduction: `.vs`.
+    Reduction,
+    /// A mask operation: `.mm`.
+    Mask,
+}
+
+/// Return the disassembly of an OP-V instruction.
+fn vector(inst: u32) -> String {
+    let rd = ((inst & 0x00000f80) >> 7) as usize;
+    let rs1 = ((inst & 0x000f8000) >> 15) as usize;
+    let rs2 = ((inst & 0x01f00000) >> 20) as usize;
+    let funct3 = (inst & 0x00007000) >> 12;
+    let funct6 = inst >> 26;
+    let vm = (inst >> 25) & 1 != 0;
+    let unknown = || "unknown".to_string();
+
+    if funct3 == 0x7 {
+        return match inst >> 30 {
+            0b00 | 0b01 => format("vsetvli", &[x(rd), x(rs1), vtype((inst >> 20) & 0x7ff)]),
+            0b11 => format(
+                "vsetivli",
+                &[x(rd), rs1.to_string(), vtype((inst >> 20) & 0x3ff)],
+            ),
+            _ if inst >> 25 == 0x40 => format("vsetvl", &[x(rd), x(rs1), x(rs2)]),
+            _ => unknown(),
+        };
+    }
+    // The scalar operand and the suffix letter of the category.
+    let (scalar, letter) = match funct3 {
+        0x0..=0x2 => (v(rs1), 'v'),
+        // Shifts, slides and vrgather take an unsigned immediate.
+        0x3 if matches!(funct6, 0x0c | 0x0e | 0x0f | 0x25 | 0x28..=0x2f) => (rs1.to_string(), 'i'),
+        0x3 => ((((rs1 as i64) << 59) >> 59).to_string(), 'i'),
+        0x5 => (f(rs1), 'f'),
+        _ => (x(rs1), 'x'),
+    };
+    let unary =
+        |name: &str, dest: String, source: String| format(name, &masked(vec![dest, source], inst));
+    // The instructions with their own operands.
+    match (funct3, funct6) {
+        (0x0 | 0x3 | 0x4, 0x17) | (0x5, 0x17) if vm => {
+            let name = format!(
+                "{}.v.{}",
+                if funct3 == 0x5 { "vfmv" } else { "vmv" },
+                letter
+            );
+            return format(&name, &[v(rd), scalar]);
+        }
+        (0x3, 0x27) => return format(&format!("vmv{}r.v", rs1 + 1), &[v(rd), v(rs2)]),
+        (0x2, 0x10) => {
+            return match rs1 {
+                0x00 => format("vmv.x.s", &[x(rd), v(rs2)]),
+                0x10 => unary("vcpop.m", x(rd), v(rs2)),
+                0x11 => unary("vfirst.m", x(rd), v(rs2)),
+                _ => unknown(),
+            }
+        }
+        (0x6, 0x10) => return format("vmv.s.x", &[v(rd), x(rs1)]),
+        (0x1, 0x10) => return format("vfmv.f.s", &[f(rd), v(rs2)]),
+        (0x5, 0x10) => return format("vfmv.s.f", &[v(rd), f(rs1)]),
+        (0x2, 0x12) => {
+            let name = match rs1 {
+                0x2 => "vzext.vf8",
+                0x3 => "vsext.vf8",
+                0x4 => "vzext.vf4",
+                0x5 => "vsext.vf4",
+                0x6 => "vzext.vf2",
+                0x7 => "vsext.vf2",
+                _ => return unknown(),
+            };
+            return unary(name, v(rd), v(rs2));
+        }
+        (0x2, 0x14) => {
+            return match rs1 {
+                0x01 => unary("vmsbf.m", v(rd), v(rs2)),
+                0x02 => unary("vmsof.m", v(rd), v(rs2)),
+                0x03 => unary("vmsif.m", v(rd), v(rs2)),
+                0x10 => unary("viota.m", v(rd), v(rs2)),
+                0x11 => format("vid.v", &masked(vec![v(rd)], inst)),
+                _ => unknown(),
+            }
+        }
+        (0x2, 0x17) => return format("vcompress.vm", &[v(rd), v(rs2), v(rs1)]),
+        (0x1, 0x12) => {
+            let name = match rs1 {
+                0x00 => "vfcvt.xu.f.v",
+                0x01 => "vfcvt.x.f.v",
+                0x02 => "vfcvt.f.xu.v",
+                0x03 => "vfcvt.f.x.v",
+                0x06 => "vfcvt.rtz.xu.f.v",
+                0x07 => "vfcvt.rtz.x.f.v",
+                0x08 => "vfwcvt.xu.f.v",
+                0x09 => "vfwcvt.x.f.v",
+                0x0a => "vfwcvt.f.xu.v",
+                0x0b => "vfwcvt.f.x.v",
+                0x0c => "vfwcvt.f.f.v",
+                0x0e => "vfwcvt.rtz.xu.f.v",
+                0x0f => "vfwcvt.rtz.x.f.v",
+                0x10 => "vfncvt.xu.f.w",
+                0x11 => "vfncvt.x.f.w",
+                0x12 => "vfncvt.f.xu.w",
+                0x13 => "vfncvt.f.x.w",
+                0x14 => "vfncvt.f.f.w",
+                0x15 => "vfncvt.rod.f.f.w",
+                0x16 => "vfncvt.rtz.xu.f.w",
+                0x17 => "vfncvt.rtz.x.f.w",
+                _ => return unknown(),
+            };
+            return unary(name, v(rd), v(rs2));
+        }
+        (0x1, 0x13) => {
+            let name = match rs1 {
+                0x00 => "vfsqrt.v",
+                0x04 => "vfrsqrt7.v",
+                0x05 => "vfrec7.v",
+                0x10 => "vfclass.v",
+                _ => return unknown(),
+            };
+            return unary(name, v(rd), v(rs2));
+        }
+        _ => {}
+    }
+    let (name, form) = match (funct3, funct6) {
+        (0x0 | 0x3 | 0x4, _) => match funct6 {
+            0x00 => ("vadd", Form::Binary),
+            0x02 => ("vsub", Form::Binary),
+            0x03 => ("vrsub", Form::Binary),
+            0x04 => ("vminu", Form::Binary),
+            0x05 => ("vmin", Form::Binary),
+            0x06 => ("vmaxu", Form::Binary),
+            0x07 => ("vmax", Form::Binary),
+            0x09 => ("vand", Form::Binary),
+            0x0a => ("vor", Form::Binary),
+            0x0b => ("vxor", Form::Binary),
+            0x0c => ("vrgather", Form::Binary),
+            0x0e if funct3 == 0x0 => ("vrgatherei16", Form::Binary),
+            0x0e => ("vslideup", Form::Binary),
+            0x0f => ("vslidedown", Form::Binary),
+            0x10 => ("vadc", Form::Carry),
+            0x11 if vm => ("vmadc", Form::Binary),
+            0x11 => ("vmadc", Form::Carry),
+            0x12 => ("vsbc", Form::Carry),
+            0x13 if vm => ("vmsbc", Form::Binary),
+            0x13 => ("vmsbc", Form::Carry),
+            0x17 => ("vmerge", Form::Carry),
+            0x18 => ("vmseq", Form::Binary),
+            0x19 => ("vmsne", Form::Binary),
+            0x1a => ("vmsltu", Form::Binary),
+            0x1b => ("vmslt", Form::Binary),
+            0x1c => ("vmsleu", Form::Binary),
+            0x1d => ("vmsle", Form::Binary),
+            0x1e => ("vmsgtu", Form::Binary),
+            0x1f => ("vmsgt", Form::Binary),
+            0x20 => ("vsaddu", Form::Binary),
+            0x21 => ("vsadd", Form::Binary),
+            0x22 => ("vssubu", Form::Binary),
+            0x23 => ("vssub", Form::Binary),
+            0x25 => ("vsll", Form::Binary),
+            0x27 => ("vsmul", Form::Binary),
+            0x28 => ("vsrl", Form::Binary),
+            0x29 => ("vsra", Form::Binary),
+            0x2a => ("vssrl", Form::Binary),
+            0x2b => ("vssra", Form::Binary),
+            0x2c => ("vnsrl", Form::Wide),
+            0x2d => ("vnsra", Form::Wide),
+            0x2e => ("vnclipu", Form::Wide),
+            0x2f => ("vnclip", Form::Wide),
+            0x30 => ("vwredsumu", Form::Reduction),
+            0x31 => ("vwredsum", Form::Reduction),
+            _ => return unknown(),
+        },
+        (0x2 | 0x6, _) => match funct6 {
+            0x00 => ("vredsum", Form::Reduction),
+            0x01 => ("vredand", Form::Reduction),
+            0x02 => ("vredor", Form::Reduction),
+            0x03 => ("vredxor", Form::Reduction),
+            0x04 => ("vredminu", Form::Reduction),
+            0x05 => ("vredmin", Form::Reduction),
+            0x06 => ("vredmaxu", Form::Reduction),
+            0x07 => ("vredmax", Form::Reduction),
+            0x08 => ("vaaddu", Form::Binary),
+            0x09 => ("vaadd", Form::Binary),
+            0x0a => ("vasubu", Form::Binary),
+            0x0b => ("vasub", Form::Binary),
+            0x0e => ("vslide1up", Form::Binary),
+            0x0f => ("vslide1down", Form::Binary),
+            0x18 => ("vmandn", Form::Mask),
+            0x19 => ("vmand", Form::Mask),
+            0x1a => ("vmor", Form::Mask),
+            0x1b => ("vmxor", Form::Mask),
+            0x1c => ("vmorn", Form::Mask),
+            0x1d => ("vmnand", Form::Mask),
+            0x1e => ("vmnor", Form::Mask),
+            0x1f => ("vmxnor", Form::Mask),
+            0x20 => ("vdivu", Form::Binary),
+            0x21 => ("vdiv", Form::Binary),
+            0x22 => ("vremu", Form::Binary),
+            0x23 => ("vrem", Form::Binary),
+            0x24 => ("vmulhu", Form::Binary),
+            0x25 => ("vmul", Form::Binary),
+            0x26 => ("vmulhsu", Form::Binary),
+            0x27 => ("vmulh", Form::Binary),
+            0x29 => ("vmadd", Form::Accumulate),
+            0x2b => ("vnmsub", Form::Accumulate),
+            0x2d => ("vmacc", Form::Accumulate),
+            0x2f => ("vnmsac", Form::Accumulate),
+            0x30 => ("vwaddu", Form::Binary),
+            0x31 => ("vwadd", Form::Binary),
+            0x32 => ("vwsubu", Form::Binary),
+            0x33 => ("vwsub", Form::Binary),
+            0x34 => ("vwaddu.w", Form::Wide),
+            0x35 => ("vwadd.w", Form::Wide),
+            0x36 => ("vwsubu.w", Form::Wide),
+            0x37 => ("vwsub.w", Form::Wide),
+            0x38 => ("vwmulu", Form::Binary),
+            0x3a => ("vwmulsu", Form::Binary),
+            0x3b => ("vwmul", Form::Binary),
+            0x3c => ("vwmaccu", Form::Accumulate),
+            0x3d => ("vwmacc", Form::Accumulate),
+            0x3e => ("vwmaccus", Form::Accumulate),
+            0x3f => ("vwmaccsu", Form::Accumulate),
+            _ => return unknown(),
+        },
+        _ => match funct6 {
+            0x00 => ("vfadd", Form::Binary),
+            0x01 => ("vfredusum", Form::Reduction),
+            0x02 => ("vfsub", Form::Binary),
+            0x03 => ("vfredosum", Form::Reduction),
+            0x04 => ("vfmin", Form::Binary),
+            0x05 => ("vfredmin", Form::Reduction),
+            0x06 => ("vfmax", Form::Binary),
+            0x07 => ("vfredmax", Form::Reduction),
+            0x08 => ("vfsgnj", Form::Binary),
+            0x09 => ("vfsgnjn", Form::Binary),
+            0x0a => ("vfsgnjx", Form::Binary),
+            0x0e => ("vfslide1up", Form::Binary),
+            0x0f => ("vfslide1down", Form::Binary),
+            0x17 => ("vfmerge", Form::Carry),
+            0x18 => ("vmfeq", Form::Binary),
+            0x19 => ("vmfle", Form::Binary),
+            0x1b => ("vmflt", Form::Binary),
+            0x1c => ("vmfne", Form::Binary),
+            0x1d => ("vmfgt", Form::Binary),
+            0x1f => ("vmfge", Form::Binary),
+            0x20 => ("vfdiv", Form::Binary),
+            0x21 => ("vfrdiv", Form::Binary),
+            0x24 => ("vfmul", Form::Binary),
+            0x27 => ("vfrsub", Form::Binary),
+            0x28 => ("vfmadd", Form::Accumulate),
+            0x29 => ("vfnmadd", Form::Accumulate),
+            0x2a => ("vfmsub", Form::Accumulate),
+            0x2b => ("vfnmsub", Form::Accumulate),
+            0x2c => ("vfmacc", Form::Accumulate),
+            0x2d => ("vfnmacc", Form::Accumulate),
+            0x2e => ("vfmsac", Form::Accumulate),
+            0x2f => ("vfnmsac", Form::Accumulate),
+            0x30 => ("vfwadd", Form::Binary),
+            0x31 => ("vfwredusum", Form::Reduction),
+            0x32 => ("vfwsub", Form::Binary),
+            0x33 => ("vfwredosum", Form::Reduction),
+            0x34 => ("vfwadd.w", Form::Wide),
+            0x36 => ("vfwsub.w", Form::Wide),
+            0x38 => ("vfwmul", Form::Binary),
+            0x3c => ("vfwmacc", Form::Accumulate),
+            0x3d => ("vfwnmacc", Form::Accumulate),
+            0x3e => ("vfwmsac", Form::Accumulate),
+            0x3f => ("vfwnmsac", Form::Accumulate),
+            _ => return unknown(),
+        },
+    };
+    // The narrowing shifts and clips and the .w forms read vs2 as wide: .wv, .wx, .wi.
+    let name = match form {
+        Form::Binary | Form::Accumulate => format!("{}.v{}", name, letter),
+        Form::Wide if name.ends_with(".w") => format!("{}{}", name, letter),
+        Form::Wide => format!("{}.w{}", name, letter),
+        Form::Carry => format!("{}.v{}m", name, letter),
+        Form::Reduction => format!("{}.vs", name),
+        Form::Mask => format!("{}.mm", name),
+    };
+    match form {
+        Form::Accumulate => format(&name, &masked(vec![v(rd), scalar, v(rs2)], inst)),
+        Form::Carry => format(&name, &[v(rd), v(rs2), scalar, "v0".to_string()]),
+        Form::Mask => format(&name, &[v(rd), v(rs2), scalar]),
+        _ => format(&name, &masked(vec![v(rd), v(rs2), scalar], inst)),
+    }
+}
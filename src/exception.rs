@@ -4,26 +4,32 @@
 
 use crate::cpu::*;
 use crate::csr::*;
+use crate::isa::MISA_H;
 
 /// Exception is a unusual condition encountered at runtime which
 /// usually relate to instructions in current hardware thread.
 #[repr(u64)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Exception {
-    InstructionAddressMisaligned,
-    InstructionAccessFault,
-    IllegalInstruction,
-    Breakpoint,
-    LoadAddressMisaligned,
-    LoadAccessFault,
-    StoreAMOAddressMisaligned,
-    StoreAMOAccessFault,
-    EnvironmentCallFromUMode,
-    EnvironmentCallFromSMode,
-    EnvironmentCallFromMMode,
-    InstructionPageFault,
-    LoadPageFault,
-    StoreAMOPageFault,
+    InstructionAddressMisaligned = 0,
+    InstructionAccessFault = 1,
+    IllegalInstruction = 2,
+    Breakpoint = 3,
+    LoadAddressMisaligned = 4,
+    LoadAccessFault = 5,
+    StoreAMOAddressMisaligned = 6,
+    StoreAMOAccessFault = 7,
+    EnvironmentCallFromUMode = 8,
+    EnvironmentCallFromSMode = 9,
+    EnvironmentCallFromVSMode = 10,
+    EnvironmentCallFromMMode = 11,
+    InstructionPageFault = 12,
+    LoadPageFault = 13,
+    StoreAMOPageFault = 15,
+    InstructionGuestPageFault = 20,
+    LoadGuestPageFault = 21,
+    VirtualInstruction = 22,
+    StoreAMOGuestPageFault = 23,
 }
 
 impl Exception {
@@ -31,13 +37,45 @@ impl Exception {
     pub fn get_trap(&self, cpu: &mut Cpu) {
         let exception_pc = cpu.pc.wrapping_sub(4);
         let previous_mode = cpu.mode;
+        let previous_virt = cpu.virt;
 
         let cause = *self as u64;
-        if (previous_mode as u8 <= Mode::Supervisor as u8)
-            && ((cpu.load_csr(MEDELEG).wrapping_shr(cause as u32)) & 1 != 0)
+        // A guest-page fault reports the guest physical address, shifted right by 2, in htval or
+        // mtval2.
+        let gpa = match self {
+            Exception::InstructionGuestPageFault
+            | Exception::LoadGuestPageFault
+            | Exception::StoreAMOGuestPageFault => cpu.guest_fault >> 2,
+            _ => 0,
+        };
+        let delegated = (previous_mode as u8 <= Mode::Supervisor as u8)
+            && ((cpu.load_csr(MEDELEG).wrapping_shr(cause as u32)) & 1 != 0);
+        if delegated
+            && previous_virt
+            && ((cpu.load_csr(HEDELEG).wrapping_shr(cause as u32)) & 1 != 0)
         {
+            // Handle the trap in VS mode, with the guest's copies of the supervisor CSRs.
+            cpu.mode = Mode::Supervisor;
+
+            // Set the program counter to VSTVEC.
+            cpu.pc = cpu.load_csr(VSTVEC) & !1;
+
+            cpu.store_csr(VSEPC, exception_pc & !1);
+            cpu.store_csr(VSCAUSE, cause);
+            cpu.store_csr(VSTVAL, 0);
+            // SPIE gets SIE, which is cleared, and SPP the previous mode.
+            let vsstatus = cpu.load_csr(VSSTATUS);
+            cpu.store_csr(
+                VSSTATUS,
+                (vsstatus & !((1 << 1) | (1 << 5) | (1 << 8)))
+                    | (((vsstatus >> 1) & 1) << 5)
+                    | ((previous_mode as u64 & 1) << 8),
+            );
+        } else if delegated {
             // Handle the trap in S mode.
             cpu.mode = Mode::Supervisor;
+            cpu.virt = false;
+            cpu.trap_to_hypervisor(previous_virt, previous_mode, gpa);
 
             // Set the program counter to STVEC.
             cpu.pc = cpu.load_csr(STVEC) & !1;
@@ -61,6 +99,7 @@ impl Exception {
         } else {
             // Handle the trap in M mode.
             cpu.mode = Mode::Machine;
+            cpu.virt = false;
 
             // Set the program counter to MTVEC.
             cpu.pc = cpu.load_csr(MTVEC) & !1;
@@ -77,18 +116,27 @@ impl Exception {
                 },
             );
             cpu.store_csr(MSTATUS, cpu.load_csr(MSTATUS) & !(1 << 3));
-            cpu.store_csr(MSTATUS, cpu.load_csr(MSTATUS) & !(0b11 << 11));
+            // Save the previous mode to MPP and V to MPV, so that MRET returns to them.
+            cpu.store_csr(
+                MSTATUS,
+                (cpu.load_csr(MSTATUS) & !((0b11 << 11) | MSTATUS_MPV | MSTATUS_GVA))
+                    | ((previous_mode as u64) << 11)
+                    | if previous_virt { MSTATUS_MPV } else { 0 },
+            );
+            if cpu.enabled(MISA_H) {
+                cpu.store_csr(MTVAL2, gpa);
+            }
         }
     }
 
     pub fn is_fatal(&self) -> bool {
-        match self {
+        matches!(
+            self,
             Exception::InstructionAddressMisaligned
-            | Exception::InstructionAccessFault
-            | Exception::LoadAccessFault
-            | Exception::StoreAMOAddressMisaligned
-            | Exception::StoreAMOAccessFault => true,
-            _ => false,
-        }
+                | Exception::InstructionAccessFault
+                | Exception::LoadAccessFault
+                | Exception::StoreAMOAddressMisaligned
+                | Exception::StoreAMOAccessFault
+        )
     }
 }
@@ -0,0 +1,1075 @@
+//! The float module does IEEE 754 arithmetic the way RISC-V specifies it, for the F and D
+//! extensions and the floating-point instructions of the vector extension.
+//!
+//! The host can't round to any mode but the nearest, and its exception flags aren't accessible,
+//! so operations are done in software: the exact result of an operation on finite operands is
+//! computed as an integer significand and a power of two, with the bits that don't fit collapsed
+//! into a sticky bit, and then rounded to the format once. Results that are NaN are always the
+//! canonical NaN, and the exception flags accrue in an `Env` like they do in fflags.
+
+use std::ops::Neg;
+
+/// Exception flags, as in fflags.
+pub const NX: u64 = 1 << 0;
+pub const UF: u64 = 1 << 1;
+pub const OF: u64 = 1 << 2;
+pub const DZ: u64 = 1 << 3;
+pub const NV: u64 = 1 << 4;
+
+/// Rounding modes, encoded as in frm and the rm field of instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    /// To nearest, ties to even.
+    NearestEven = 0,
+    TowardZero = 1,
+    Down = 2,
+    Up = 3,
+    /// To nearest, ties away from zero.
+    NearestMax = 4,
+}
+
+impl Rounding {
+    /// Return the rounding mode encoded as `bits`, or `None` if the encoding is reserved.
+    pub fn from_bits(bits: u64) -> Option<Rounding> {
+        match bits {
+            0 => Some(Rounding::NearestEven),
+            1 => Some(Rounding::TowardZero),
+            2 => Some(Rounding::Down),
+            3 => Some(Rounding::Up),
+            4 => Some(Rounding::NearestMax),
+            _ => None,
+        }
+    }
+}
+
+/// A binary floating-point format of the host: f32 or f64. Only the encoding and comparisons
+/// of the host are used.
+pub trait Float: Copy + PartialEq + PartialOrd + Neg<Output = Self> {
+    /// The width of the format.
+    const BITS: u32;
+    /// The number of fraction bits.
+    const FRACTION: u32;
+
+    fn from_bits(bits: u64) -> Self;
+    fn to_bits(self) -> u64;
+
+    fn sign_bit() -> u64 {
+        1 << (Self::BITS - 1)
+    }
+
+    /// The exponent bias, which is also the largest exponent.
+    fn bias() -> i32 {
+        (1 << (Self::BITS - Self::FRACTION - 2)) - 1
+    }
+
+    fn zero() -> Self {
+        Self::from_bits(0)
+    }
+
+    fn infinity() -> Self {
+        Self::from_bits(((1 << (Self::BITS - Self::FRACTION - 1)) - 1) << Self::FRACTION)
+    }
+
+    /// The largest finite value.
+    fn max_value() -> Self {
+        Self::from_bits(Self::infinity().to_bits() - 1)
+    }
+
+    /// The quiet NaN RISC-V returns for every operation whose result is NaN.
+    fn canonical_nan() -> Self {
+        Self::from_bits(Self::infinity().to_bits() | 1 << (Self::FRACTION - 1))
+    }
+
+    fn is_nan(self) -> bool {
+        self.to_bits() & !Self::sign_bit() > Self::infinity().to_bits()
+    }
+
+    fn is_infinite(self) -> bool {
+        self.to_bits() & !Self::sign_bit() == Self::infinity().to_bits()
+    }
+
+    fn is_zero(self) -> bool {
+        self.to_bits() & !Self::sign_bit() == 0
+    }
+
+    /// Return true for a subnormal value, whose exponent field is 0.
+    fn is_subnormal(self) -> bool {
+        !self.is_zero() && self.to_bits() & Self::infinity().to_bits() == 0
+    }
+
+    fn is_sign_negative(self) -> bool {
+        self.to_bits() & Self::sign_bit() != 0
+    }
+
+    /// Return true for a signaling NaN, a NaN whose most significant fraction bit is clear.
+    fn is_signaling(self) -> bool {
+        self.is_nan() && self.to_bits() & 1 << (Self::FRACTION - 1) == 0
+    }
+
+    /// Return the value with the sign of `sign`.
+    fn with_sign_of(self, sign: Self) -> Self {
+        Self::from_bits((self.to_bits() & !Self::sign_bit()) | (sign.to_bits() & Self::sign_bit()))
+    }
+}
+
+impl Float for f32 {
+    const BITS: u32 = 32;
+    const FRACTION: u32 = 23;
+
+    fn from_bits(bits: u64) -> Self {
+        f32::from_bits(bits as u32)
+    }
+
+    fn to_bits(self) -> u64 {
+        f32::to_bits(self) as u64
+    }
+}
+
+impl Float for f64 {
+    const BITS: u32 = 64;
+    const FRACTION: u32 = 52;
+
+    fn from_bits(bits: u64) -> Self {
+        f64::from_bits(bits)
+    }
+
+    fn to_bits(self) -> u64 {
+        f64::to_bits(self)
+    }
+}
+
+/// A finite value, `significand` × 2^`exponent` with a sign. A zero significand is a zero.
+#[derive(Debug, Clone, Copy)]
+struct Exact {
+    negative: bool,
+    significand: u128,
+    exponent: i32,
+}
+
+impl Exact {
+    /// Return the value of the finite `value`.
+    fn of<F: Float>(value: F) -> Exact {
+        let bits = value.to_bits();
+        let biased = ((bits & !F::sign_bit()) >> F::FRACTION) as i32;
+        let fraction = (bits & ((1 << F::FRACTION) - 1)) as u128;
+        let (significand, biased) = match biased {
+            // Subnormals have the exponent of the smallest normals.
+            0 => (fraction, 1),
+            _ => (fraction | 1 << F::FRACTION, biased),
+        };
+        Exact {
+            negative: value.is_sign_negative(),
+            significand,
+            exponent: biased - F::bias() - F::FRACTION as i32,
+        }
+    }
+
+    /// Return the same value with the most significant bit of the significand at bit 125, so
+    /// that there are two bits of headroom for sums.
+    fn normalize(self) -> Exact {
+        if self.significand == 0 {
+            return self;
+        }
+        let shift = self.significand.leading_zeros() as i32 - 2;
+        Exact {
+            significand: if shift >= 0 {
+                self.significand << shift
+            } else {
+                sticky_shift(self.significand, -shift)
+            },
+            exponent: self.exponent - shift,
+            ..self
+        }
+    }
+
+    fn is_zero(self) -> bool {
+        self.significand == 0
+    }
+}
+
+/// Shift `value` right by `shift` bits, setting the least significant bit if a set bit is
+/// shifted out. The result is rounded the same as the exact value as long as the rounding
+/// position is at least two bits higher.
+fn sticky_shift(value: u128, shift: i32) -> u128 {
+    if shift >= 128 {
+        return (value != 0) as u128;
+    }
+    let shifted = value >> shift;
+    shifted | (shifted << shift != value) as u128
+}
+
+/// Return `value` shifted right by `shift` bits and rounded to `rm`, and whether it was
+/// inexact. `negative` is the sign of the value.
+fn shift_round(value: u128, shift: i32, negative: bool, rm: Rounding) -> (u128, bool) {
+    if shift <= 0 {
+        return (value << -shift, false);
+    }
+    // The bits shifted out, and the value of half a unit in the last place they're compared to.
+    let (kept, rest, half) = match shift {
+        129.. => (0, (value != 0) as u128, 2),
+        128 => (0, value, 1 << 127),
+        _ => (value >> shift, value & ((1 << shift) - 1), 1 << (shift - 1)),
+    };
+    let inexact = rest != 0;
+    let up = match rm {
+        Rounding::NearestEven => rest > half || (rest == half && kept & 1 != 0),
+        Rounding::NearestMax => rest >= half,
+        Rounding::TowardZero => false,
+        Rounding::Down => negative && inexact,
+        Rounding::Up => !negative && inexact,
+    };
+    (kept + up as u128, inexact)
+}
+
+/// Return the integer square root of `value`, rounded down.
+fn isqrt(value: u128) -> u128 {
+    // Newton's method converges from above, starting just above the estimate of the host.
+    let mut root = (value as f64).sqrt() as u128 + (1 << 12);
+    loop {
+        let next = (root + value / root) / 2;
+        if next >= root {
+            return root;
+        }
+        root = next;
+    }
+}
+
+/// The rounding mode and the accrued exception flags of floating-point operations.
+#[derive(Debug, Clone, Copy)]
+pub struct Env {
+    pub rm: Rounding,
+    /// `NX`, `UF`, `OF`, `DZ` and `NV` flags.
+    pub flags: u64,
+}
+
+impl Env {
+    pub fn new(rm: Rounding) -> Env {
+        Env { rm, flags: 0 }
+    }
+
+    /// Return the canonical NaN for an operation with a NaN operand, which is invalid if one
+    /// of them is signaling.
+    fn propagate<F: Float>(&mut self, operands: &[F]) -> F {
+        if operands.iter().any(|x| x.is_signaling()) {
+            self.flags |= NV;
+        }
+        F::canonical_nan()
+    }
+
+    fn invalid<F: Float>(&mut self) -> F {
+        self.flags |= NV;
+        F::canonical_nan()
+    }
+
+    /// Round the exact `value` to the format.
+    fn round<F: Float>(&mut self, value: Exact) -> F {
+        let sign = if value.negative { F::sign_bit() } else { 0 };
+        if value.is_zero() {
+            return F::from_bits(sign);
+        }
+        let fraction = F::FRACTION as i32;
+        let emin = 1 - F::bias();
+        // The value is in [2^e, 2^(e + 1)).
+        let e = value.exponent + 127 - value.significand.leading_zeros() as i32;
+        // The unit in the last place of the result, which subnormals share.
+        let ulp = e.max(emin) - fraction;
+        let (significand, inexact) = shift_round(
+            value.significand,
+            ulp - value.exponent,
+            value.negative,
+            self.rm,
+        );
+        // The significand includes the implicit bit, which adds 1 to the exponent field.
+        // Carries into the exponent, from subnormals to normals too, just work.
+        let bits = (((ulp + fraction + F::bias() - 1) as u128) << fraction) + significand;
+        if bits >= F::infinity().to_bits() as u128 {
+            self.flags |= OF | NX;
+            let to_max = match self.rm {
+                Rounding::TowardZero => true,
+                Rounding::Down => !value.negative,
+                Rounding::Up => value.negative,
+                _ => false,
+            };
+            let magnitude = if to_max {
+                F::max_value()
+            } else {
+                F::infinity()
+            };
+            return F::from_bits(sign | magnitude.to_bits());
+        }
+        if inexact {
+            self.flags |= NX;
+            // A result is tiny if it would be below the smallest normal after rounding with an
+            // unbounded exponent.
+            if e < emin - 1 {
+                self.flags |= UF;
+            } else if e == emin - 1 {
+                let (unbounded, _) = shift_round(
+                    value.significand,
+                    e - fraction - value.exponent,
+                    value.negative,
+                    self.rm,
+                );
+                if unbounded < 1 << (fraction + 1) {
+                    self.flags |= UF;
+                }
+            }
+        }
+        F::from_bits(sign | bits as u64)
+    }
+
+    /// Return the sum of the exact values `a` and `b`, rounded.
+    fn sum<F: Float>(&mut self, a: Exact, b: Exact) -> F {
+        if a.is_zero() && b.is_zero() {
+            // The sum of zeros of opposite signs is -0 only when rounding down.
+            let negative = if a.negative == b.negative {
+                a.negative
+            } else {
+                self.rm == Rounding::Down
+            };
+            return F::from_bits(if negative { F::sign_bit() } else { 0 });
+        }
+        if b.is_zero() {
+            return self.round(a);
+        }
+        if a.is_zero() {
+            return self.round(b);
+        }
+        let (a, b) = (a.normalize(), b.normalize());
+        // The significands have the same width, so the larger exponent has the larger value.
+        let (a, b) = if (b.exponent, b.significand) > (a.exponent, a.significand) {
+            (b, a)
+        } else {
+            (a, b)
+        };
+        let smaller = sticky_shift(b.significand, a.exponent - b.exponent);
+        let significand = if a.negative == b.negative {
+            a.significand + smaller
+        } else {
+            a.significand - smaller
+        };
+        if significand == 0 {
+            // An exact zero difference is -0 only when rounding down.
+            return F::from_bits(if self.rm == Rounding::Down {
+                F::sign_bit()
+            } else {
+                0
+            });
+        }
+        self.round(Exact { significand, ..a })
+    }
+
+    pub fn add<F: Float>(&mut self, a: F, b: F) -> F {
+        if a.is_nan() || b.is_nan() {
+            return self.propagate(&[a, b]);
+        }
+        if a.is_infinite() || b.is_infinite() {
+            if a.is_infinite() && b.is_infinite() && a.is_sign_negative() != b.is_sign_negative() {
+                return self.invalid();
+            }
+            return if a.is_infinite() { a } else { b };
+        }
+        self.sum(Exact::of(a), Exact::of(b))
+    }
+
+    pub fn sub<F: Float>(&mut self, a: F, b: F) -> F {
+        if b.is_nan() {
+            return self.propagate(&[a, b]);
+        }
+        self.add(a, -b)
+    }
+
+    /// Return the exact product of the finite `a` and `b`.
+    fn product<F: Float>(a: F, b: F) -> Exact {
+        let (a, b) = (Exact::of(a), Exact::of(b));
+        Exact {
+            negative: a.negative != b.negative,
+            significand: a.significand * b.significand,
+            exponent: a.exponent + b.exponent,
+        }
+    }
+
+    pub fn mul<F: Float>(&mut self, a: F, b: F) -> F {
+        if a.is_nan() || b.is_nan() {
+            return self.propagate(&[a, b]);
+        }
+        let negative = a.is_sign_negative() != b.is_sign_negative();
+        if a.is_infinite() || b.is_infinite() {
+            if a.is_zero() || b.is_zero() {
+                return self.invalid();
+            }
+            return F::infinity().with_sign_of(if negative { -F::zero() } else { F::zero() });
+        }
+        self.round(Self::product(a, b))
+    }
+
+    pub fn div<F: Float>(&mut self, a: F, b: F) -> F {
+        if a.is_nan() || b.is_nan() {
+            return self.propagate(&[a, b]);
+        }
+        let sign = if a.is_sign_negative() != b.is_sign_negative() {
+            -F::zero()
+        } else {
+            F::zero()
+        };
+        if (a.is_infinite() && b.is_infinite()) || (a.is_zero() && b.is_zero()) {
+            return self.invalid();
+        }
+        if a.is_infinite() {
+            return F::infinity().with_sign_of(sign);
+        }
+        if b.is_infinite() || a.is_zero() {
+            return F::zero().with_sign_of(sign);
+        }
+        if b.is_zero() {
+            self.flags |= DZ;
+            return F::infinity().with_sign_of(sign);
+        }
+        // With the dividend at bit 125 and the divisor at bit 52, the quotient has at least 72
+        // bits, enough to round it.
+        let dividend = Exact::of(a).normalize();
+        let divisor = Exact::of(b);
+        let shift = divisor.significand.leading_zeros() as i32 - 75;
+        let divisor_significand = divisor.significand << shift;
+        let quotient = dividend.significand / divisor_significand;
+        let remainder = dividend.significand % divisor_significand;
+        self.round(Exact {
+            negative: sign.is_sign_negative(),
+            significand: quotient | (remainder != 0) as u128,
+            exponent: dividend.exponent - (divisor.exponent - shift),
+        })
+    }
+
+    pub fn sqrt<F: Float>(&mut self, a: F) -> F {
+        if a.is_nan() {
+            return self.propagate(&[a]);
+        }
+        if a.is_zero() {
+            return a;
+        }
+        if a.is_sign_negative() {
+            return self.invalid();
+        }
+        if a.is_infinite() {
+            return a;
+        }
+        // The square root of a significand at bit 125 or 126 with an even exponent has at least
+        // 62 bits.
+        let mut value = Exact::of(a).normalize();
+        if value.exponent & 1 != 0 {
+            value.significand <<= 1;
+            value.exponent -= 1;
+        }
+        let root = isqrt(value.significand);
+        self.round(Exact {
+            negative: false,
+            significand: root | (root * root != value.significand) as u128,
+            exponent: value.exponent / 2,
+        })
+    }
+
+    /// Return `a * b + c` with a single rounding.
+    pub fn fma<F: Float>(&mut self, a: F, b: F, c: F) -> F {
+        // The product of infinity and zero is invalid even when the addend is a quiet NaN.
+        if (a.is_infinite() && b.is_zero()) || (a.is_zero() && b.is_infinite()) {
+            return self.invalid();
+        }
+        if a.is_nan() || b.is_nan() || c.is_nan() {
+            return self.propagate(&[a, b, c]);
+        }
+        let negative = a.is_sign_negative() != b.is_sign_negative();
+        if a.is_infinite() || b.is_infinite() {
+            if c.is_infinite() && c.is_sign_negative() != negative {
+                return self.invalid();
+            }
+            return F::infinity().with_sign_of(if negative { -F::zero() } else { F::zero() });
+        }
+        if c.is_infinite() {
+            return c;
+        }
+        self.sum(Self::product(a, b), Exact::of(c))
+    }
+
+    /// Return the smaller operand, or the other operand if one is NaN. -0 is smaller than +0.
+    pub fn min<F: Float>(&mut self, a: F, b: F) -> F {
+        self.min_max(a, b, true)
+    }
+
+    /// Return the larger operand, or the other operand if one is NaN.
+    pub fn max<F: Float>(&mut self, a: F, b: F) -> F {
+        self.min_max(a, b, false)
+    }
+
+    fn min_max<F: Float>(&mut self, a: F, b: F, min: bool) -> F {
+        if a.is_signaling() || b.is_signaling() {
+            self.flags |= NV;
+        }
+        match (a.is_nan(), b.is_nan()) {
+            (true, true) => F::canonical_nan(),
+            (true, false) => b,
+            (false, true) => a,
+            _ if a.is_zero() && b.is_zero() => {
+                if a.is_sign_negative() == min {
+                    a
+                } else {
+                    b
+                }
+            }
+            _ if (a < b) == min => a,
+            _ => b,
+        }
+    }
+
+    /// Compare for equality. Only signaling NaNs are invalid.
+    pub fn eq<F: Float>(&mut self, a: F, b: F) -> bool {
+        if a.is_signaling() || b.is_signaling() {
+            self.flags |= NV;
+        }
+        a == b
+    }
+
+    /// Compare for less than. Any NaN is invalid.
+    pub fn lt<F: Float>(&mut self, a: F, b: F) -> bool {
+        if a.is_nan() || b.is_nan() {
+            self.flags |= NV;
+        }
+        a < b
+    }
+
+    /// Compare for less than or equal. Any NaN is invalid.
+    pub fn le<F: Float>(&mut self, a: F, b: F) -> bool {
+        if a.is_nan() || b.is_nan() {
+            self.flags |= NV;
+        }
+        a <= b
+    }
+
+    /// Convert `value` to another format.
+    pub fn convert<F: Float, T: Float>(&mut self, value: F) -> T {
+        if value.is_nan() {
+            self.propagate(&[value]);
+            return T::canonical_nan();
+        }
+        let sign = if value.is_sign_negative() {
+            -T::zero()
+        } else {
+            T::zero()
+        };
+        if value.is_infinite() {
+            return T::infinity().with_sign_of(sign);
+        }
+        self.round(Exact::of(value))
+    }
+
+    /// Convert `value` to another format, rounding to odd: toward zero, and then setting the
+    /// least significant bit if the result is inexact.
+    pub fn convert_odd<F: Float, T: Float>(&mut self, value: F) -> T {
+        let mut env = Env::new(Rounding::TowardZero);
+        let result: T = env.convert(value);
+        self.flags |= env.flags;
+        if env.flags & NX != 0 && !result.is_infinite() {
+            T::from_bits(result.to_bits() | 1)
+        } else {
+            result
+        }
+    }
+
+    /// Convert the integer `value` to a float.
+    pub fn from_int<F: Float>(&mut self, value: i128) -> F {
+        self.round(Exact {
+            negative: value < 0,
+            significand: value.unsigned_abs(),
+            exponent: 0,
+        })
+    }
+
+    /// Convert `value` to an integer between `min` and `max`, rounding it to the rounding
+    /// mode. NaN, infinities and values out of range are invalid and saturate; NaN converts to
+    /// `max`.
+    pub fn to_int<F: Float>(&mut self, value: F, min: i128, max: i128) -> i128 {
+        if value.is_nan() {
+            self.flags |= NV;
+            return max;
+        }
+        let exact = Exact::of(value);
+        // Any value that large is out of range.
+        if value.is_infinite() || exact.exponent > 64 {
+            self.flags |= NV;
+            return if value.is_sign_negative() { min } else { max };
+        }
+        let (magnitude, inexact) =
+            shift_round(exact.significand, -exact.exponent, exact.negative, self.rm);
+        let integer = if exact.negative {
+            -(magnitude as i128)
+        } else {
+            magnitude as i128
+        };
+        if integer < min || integer > max {
+            self.flags |= NV;
+            return integer.clamp(min, max);
+        }
+        if inexact {
+            self.flags |= NX;
+        }
+        integer
+    }
+}
+
+/// Return the class of `value` as fclass does: one of ten bits for -infinity, negative normal,
+/// negative subnormal, -0, +0, positive subnormal, positive normal, +infinity, signaling NaN and
+/// quiet NaN.
+pub fn classify<F: Float>(value: F) -> u64 {
+    let negative = value.is_sign_negative();
+    let bit = if value.is_nan() {
+        if value.is_signaling() {
+            8
+        } else {
+            9
+        }
+    } else if value.is_infinite() {
+        if negative {
+            0
+        } else {
+            7
+        }
+    } else if value.is_zero() {
+        if negative {
+            3
+        } else {
+            4
+        }
+    } else if value.is_subnormal() {
+        if negative {
+            2
+        } else {
+            5
+        }
+    } else if negative {
+        1
+    } else {
+        6
+    };
+    1 << bit
+}
+
+/// Return the sign-injection of `a` with the sign of `b`: the sign of `b` for fsgnj, its
+/// opposite for fsgnjn and the exclusive or with the sign of `a` for fsgnjx. `funct` is 0, 1
+/// or 2 for them.
+pub fn sign_inject<F: Float>(a: F, b: F, funct: u32) -> F {
+    let sign = F::sign_bit();
+    let b_sign = b.to_bits() & sign;
+    let new = match funct {
+        0 => b_sign,
+        1 => b_sign ^ sign,
+        _ => (a.to_bits() ^ b_sign) & sign,
+    };
+    F::from_bits((a.to_bits() & !sign) | new)
+}
+
+const REC7: [u8; 128] = [
+    127, 125, 123, 121, 119, 117, 116, 114, 112, 110, 109, 107, 105, 104, 102, 100, 99, 97, 96, 94,
+    93, 91, 90, 88, 87, 85, 84, 83, 81, 80, 79, 77, 76, 75, 74, 72, 71, 70, 69, 68, 66, 65, 64, 63,
+    62, 61, 60, 59, 58, 57, 56, 55, 54, 53, 52, 51, 50, 49, 48, 47, 46, 45, 44, 43, 42, 41, 40, 40,
+    39, 38, 37, 36, 35, 35, 34, 33, 32, 31, 31, 30, 29, 28, 28, 27, 26, 25, 25, 24, 23, 23, 22, 21,
+    21, 20, 19, 19, 18, 17, 17, 16, 15, 15, 14, 14, 13, 12, 12, 11, 11, 10, 9, 9, 8, 8, 7, 7, 6, 5,
+    5, 4, 4, 3, 3, 2, 2, 1, 1, 0,
+];
+
+/// The 7-bit estimates of the reciprocal square root, by the least significant bit of the
+/// exponent and the 6 most significant fraction bits.
+const RSQRT7: [u8; 128] = [
+    52, 51, 50, 48, 47, 46, 44, 43, 42, 41, 40, 39, 38, 36, 35, 34, 33, 32, 31, 30, 30, 29, 28, 27,
+    26, 25, 24, 23, 23, 22, 21, 20, 19, 19, 18, 17, 16, 16, 15, 14, 14, 13, 12, 12, 11, 10, 10, 9,
+    9, 8, 7, 7, 6, 6, 5, 4, 4, 3, 3, 2, 2, 1, 1, 0, 127, 125, 123, 121, 119, 118, 116, 114, 113,
+    111, 109, 108, 106, 105, 103, 102, 100, 99, 97, 96, 95, 93, 92, 91, 90, 88, 87, 86, 85, 84, 83,
+    82, 80, 79, 78, 77, 76, 75, 74, 73, 72, 71, 70, 70, 69, 68, 67, 66, 65, 64, 63, 63, 62, 61, 60,
+    59, 59, 58, 57, 56, 56, 55, 54, 53,
+];
+
+/// Split a finite, nonzero `value` into its sign, its biased exponent and its fraction. The
+/// exponent of subnormals is 0 or negative, as if they were normalized.
+fn decompose<F: Float>(value: F) -> (bool, i64, u64) {
+    let bits = value.to_bits();
+    let fraction_mask = (1u64 << F::FRACTION) - 1;
+    let mut exponent = ((bits & !F::sign_bit()) >> F::FRACTION) as i64;
+    let mut fraction = bits & fraction_mask;
+    if exponent == 0 {
+        while fraction & (1 << F::FRACTION) == 0 {
+            fraction <<= 1;
+            exponent -= 1;
+        }
+        exponent += 1;
+        fraction &= fraction_mask;
+    }
+    (value.is_sign_negative(), exponent, fraction)
+}
+
+/// Return an estimate of the reciprocal of `value` to 7 bits, as vfrec7.v does.
+pub fn rec7<F: Float>(env: &mut Env, value: F) -> F {
+    if value.is_nan() {
+        return env.propagate(&[value]);
+    }
+    if value.is_infinite() {
+        return F::zero().with_sign_of(value);
+    }
+    if value.is_zero() {
+        env.flags |= DZ;
+        return F::infinity().with_sign_of(value);
+    }
+    let bias = F::bias() as i64;
+    let (negative, exponent, fraction) = decompose(value);
+    if exponent < -1 {
+        // The reciprocal of a small subnormal overflows.
+        env.flags |= OF | NX;
+        let to_max = match env.rm {
+            Rounding::TowardZero => true,
+            Rounding::Down => !negative,
+            Rounding::Up => negative,
+            _ => false,
+        };
+        let result = if to_max {
+            F::max_value()
+        } else {
+            F::infinity()
+        };
+        return result.with_sign_of(value);
+    }
+    let index = (fraction >> (F::FRACTION - 7)) as usize;
+    let mut out_exponent = 2 * bias - 1 - exponent;
+    let mut out_fraction = (REC7[index] as u64) << (F::FRACTION - 7);
+    if out_exponent <= 0 {
+        // The result is subnormal.
+        out_fraction = (out_fraction | 1 << F::FRACTION) >> (1 - out_exponent);
+        out_exponent = 0;
+    }
+    let sign = if negative { F::sign_bit() } else { 0 };
+    F::from_bits(sign | (out_exponent as u64) << F::FRACTION | out_fraction)
+}
+
+/// Return an estimate of the reciprocal square root of `value` to 7 bits, as vfrsqrt7.v does.
+pub fn rsqrt7<F: Float>(env: &mut Env, value: F) -> F {
+    if value.is_nan() {
+        return env.propagate(&[value]);
+    }
+    if value.is_zero() {
+        env.flags |= DZ;
+        return F::infinity().with_sign_of(value);
+    }
+    if value.is_sign_negative() {
+        return env.invalid();
+    }
+    if value.is_infinite() {
+        return F::zero();
+    }
+    let bias = F::bias() as i64;
+    let (_, exponent, fraction) = decompose(value);
+    let index = (((exponent & 1) as u64) << 6 | fraction >> (F::FRACTION - 6)) as usize;
+    let out_exponent = (3 * bias - 1 - exponent) / 2;
+    let out_fraction = (RSQRT7[index] as u64) << (F::FRACTION - 7);
+    F::from_bits((out_exponent as u64) << F::FRACTION | out_fraction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Rounding::*;
+
+    /// Run `f` in a fresh environment with rounding mode `rm`, and return its result and flags.
+    fn run<T>(rm: Rounding, f: impl FnOnce(&mut Env) -> T) -> (T, u64) {
+        let mut env = Env::new(rm);
+        let result = f(&mut env);
+        (result, env.flags)
+    }
+
+    /// Run an operation on f32 values, and return the bits of the result and the flags.
+    fn run32(rm: Rounding, f: impl FnOnce(&mut Env) -> f32) -> (u32, u64) {
+        let (result, flags) = run(rm, f);
+        (result.to_bits(), flags)
+    }
+
+    fn snan32() -> f32 {
+        f32::from_bits(0x7f80_0001)
+    }
+
+    fn qnan32() -> f32 {
+        f32::from_bits(0x7fc0_1234)
+    }
+
+    const CANONICAL32: u32 = 0x7fc0_0000;
+
+    #[test]
+    fn rounding_modes() {
+        // 1 + half an ulp is a tie.
+        let half_ulp = f32::from_bits(0x3380_0000);
+        let up = 0x3f80_0001;
+        for (rm, positive, negative) in [
+            (NearestEven, 0x3f80_0000, 0xbf80_0000),
+            (TowardZero, 0x3f80_0000, 0xbf80_0000),
+            (Down, 0x3f80_0000, 0xbf80_0001),
+            (Up, up, 0xbf80_0000),
+            (NearestMax, up, 0xbf80_0001),
+        ] {
+            assert_eq!(
+                run32(rm, |e| e.add(1.0, half_ulp)),
+                (positive, NX),
+                "{:?}",
+                rm
+            );
+            assert_eq!(
+                run32(rm, |e| e.add(-1.0, -half_ulp)),
+                (negative, NX),
+                "{:?}",
+                rm
+            );
+        }
+        // An overflow rounds to infinity or to the largest finite value.
+        for (rm, result) in [
+            (NearestEven, f32::INFINITY),
+            (TowardZero, f32::MAX),
+            (Down, f32::MAX),
+            (Up, f32::INFINITY),
+            (NearestMax, f32::INFINITY),
+        ] {
+            assert_eq!(
+                run32(rm, |e| e.mul(f32::MAX, 2.0)),
+                (result.to_bits(), OF | NX),
+                "{:?}",
+                rm
+            );
+        }
+        assert_eq!(Rounding::from_bits(4), Some(NearestMax));
+        assert_eq!(Rounding::from_bits(5), None);
+        assert_eq!(Rounding::from_bits(7), None);
+    }
+
+    #[test]
+    fn signed_zero() {
+        let (neg, pos) = ((-0.0f32).to_bits(), 0.0f32.to_bits());
+        assert_eq!(run32(NearestEven, |e| e.add(0.0, -0.0)), (pos, 0));
+        assert_eq!(run32(Down, |e| e.add(0.0, -0.0)), (neg, 0));
+        assert_eq!(run32(NearestEven, |e| e.add(-0.0, -0.0)), (neg, 0));
+        assert_eq!(run32(NearestEven, |e| e.sub(1.5, 1.5)), (pos, 0));
+        assert_eq!(run32(Down, |e| e.sub(1.5, 1.5)), (neg, 0));
+        assert_eq!(run32(NearestEven, |e| e.mul(-0.0, 5.0)), (neg, 0));
+        assert_eq!(run32(NearestEven, |e| e.div(0.0, -5.0)), (neg, 0));
+        assert_eq!(run32(NearestEven, |e| e.sqrt(-0.0)), (neg, 0));
+        assert_eq!(run32(NearestEven, |e| e.min(0.0, -0.0)), (neg, 0));
+        assert_eq!(run32(NearestEven, |e| e.max(-0.0, 0.0)), (pos, 0));
+        assert_eq!(run32(NearestEven, |e| e.convert(-0.0f64)), (neg, 0));
+        assert_eq!(run32(Down, |e| e.from_int(0)), (pos, 0));
+        assert_eq!(run(NearestEven, |e| e.eq(0.0f32, -0.0)), (true, 0));
+        assert_eq!(run(NearestEven, |e| e.lt(-0.0f32, 0.0)), (false, 0));
+        assert_eq!(
+            run(NearestEven, |e| e.to_int(-0.0f32, 0, u32::MAX as i128)),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn nan_results_are_canonical() {
+        for nan in [snan32(), qnan32(), -qnan32()] {
+            assert_eq!(run32(NearestEven, |e| e.add(nan, 1.0)).0, CANONICAL32);
+            assert_eq!(run32(NearestEven, |e| e.sqrt(nan)).0, CANONICAL32);
+            assert_eq!(run32(NearestEven, |e| e.convert(nan)).0, CANONICAL32);
+        }
+        assert_eq!(
+            run(NearestEven, |e| e.convert::<f32, f64>(qnan32()))
+                .0
+                .to_bits(),
+            0x7ff8_0000_0000_0000
+        );
+        assert_eq!(
+            run32(NearestEven, |e| e.min(qnan32(), qnan32())).0,
+            CANONICAL32
+        );
+        assert_eq!(classify(snan32()), 1 << 8);
+        assert_eq!(classify(qnan32()), 1 << 9);
+    }
+
+    #[test]
+    fn flags_of_each_operation() {
+        let tiny = f32::from_bits(0x0080_0000);
+        let cases = [
+            (
+                "add NX",
+                run32(NearestEven, |e| e.add(1.0, 1e-10)),
+                (0x3f80_0000, NX),
+            ),
+            (
+                "add OF",
+                run32(NearestEven, |e| e.add(f32::MAX, f32::MAX)),
+                (0x7f80_0000, OF | NX),
+            ),
+            (
+                "add NV",
+                run32(NearestEven, |e| e.add(f32::INFINITY, f32::NEG_INFINITY)),
+                (CANONICAL32, NV),
+            ),
+            (
+                "add sNaN",
+                run32(NearestEven, |e| e.add(snan32(), 1.0)),
+                (CANONICAL32, NV),
+            ),
+            (
+                "add qNaN",
+                run32(NearestEven, |e| e.add(qnan32(), 1.0)),
+                (CANONICAL32, 0),
+            ),
+            (
+                "sub OF",
+                run32(NearestEven, |e| e.sub(-f32::MAX, f32::MAX)),
+                (0xff80_0000, OF | NX),
+            ),
+            (
+                "sub sNaN",
+                run32(NearestEven, |e| e.sub(1.0, snan32())),
+                (CANONICAL32, NV),
+            ),
+            (
+                "mul UF",
+                run32(NearestEven, |e| e.mul(tiny, 0.3)),
+                (0x0026_6666, UF | NX),
+            ),
+            (
+                "mul exact subnormal",
+                run32(NearestEven, |e| e.mul(tiny, 0.5)),
+                (0x0040_0000, 0),
+            ),
+            (
+                "mul NV",
+                run32(NearestEven, |e| e.mul(0.0, f32::INFINITY)),
+                (CANONICAL32, NV),
+            ),
+            (
+                "div NX",
+                run32(NearestEven, |e| e.div(1.0, 3.0)),
+                (0x3eaa_aaab, NX),
+            ),
+            (
+                "div DZ",
+                run32(NearestEven, |e| e.div(-1.0, 0.0)),
+                (0xff80_0000, DZ),
+            ),
+            (
+                "div NV",
+                run32(NearestEven, |e| e.div(0.0, 0.0)),
+                (CANONICAL32, NV),
+            ),
+            (
+                "div UF",
+                run32(NearestEven, |e| e.div(tiny, 3.0)),
+                (0x002a_aaab, UF | NX),
+            ),
+            (
+                "sqrt exact",
+                run32(NearestEven, |e| e.sqrt(4.0)),
+                (0x4000_0000, 0),
+            ),
+            (
+                "sqrt NX",
+                run32(NearestEven, |e| e.sqrt(2.0)),
+                (0x3fb5_04f3, NX),
+            ),
+            (
+                "sqrt NV",
+                run32(NearestEven, |e| e.sqrt(-1.0)),
+                (CANONICAL32, NV),
+            ),
+            // The fused result is exact, where a separate multiply would overflow.
+            (
+                "fma exact",
+                run32(NearestEven, |e| e.fma(f32::MAX, 2.0, -f32::MAX)),
+                (f32::MAX.to_bits(), 0),
+            ),
+            (
+                "fma NV",
+                run32(NearestEven, |e| e.fma(0.0, f32::INFINITY, qnan32())),
+                (CANONICAL32, NV),
+            ),
+            (
+                "fma inf",
+                run32(NearestEven, |e| {
+                    e.fma(f32::INFINITY, 1.0, f32::NEG_INFINITY)
+                }),
+                (CANONICAL32, NV),
+            ),
+            (
+                "min sNaN",
+                run32(NearestEven, |e| e.min(snan32(), 1.0)),
+                (0x3f80_0000, NV),
+            ),
+            (
+                "max qNaN",
+                run32(NearestEven, |e| e.max(2.0, qnan32())),
+                (0x4000_0000, 0),
+            ),
+            (
+                "convert OF",
+                run32(NearestEven, |e| e.convert(1e300f64)),
+                (0x7f80_0000, OF | NX),
+            ),
+            (
+                "convert UF",
+                run32(NearestEven, |e| e.convert(1e-300f64)),
+                (0, UF | NX),
+            ),
+            (
+                "from_int NX",
+                run32(NearestEven, |e| e.from_int(u64::MAX as i128)),
+                (0x5f80_0000, NX),
+            ),
+        ];
+        for (name, result, expected) in cases {
+            assert_eq!(result, expected, "{}", name);
+        }
+
+        let compare = |f: fn(&mut Env, f32, f32) -> bool, a, b| run(NearestEven, |e| f(e, a, b));
+        assert_eq!(compare(Env::eq, qnan32(), 1.0), (false, 0));
+        assert_eq!(compare(Env::eq, snan32(), 1.0), (false, NV));
+        assert_eq!(compare(Env::lt, qnan32(), 1.0), (false, NV));
+        assert_eq!(compare(Env::le, 1.0, qnan32()), (false, NV));
+        assert_eq!(compare(Env::le, 1.0, 1.0), (true, 0));
+    }
+
+    #[test]
+    fn conversions_to_integers_saturate() {
+        let (i32_min, i32_max) = (i32::MIN as i128, i32::MAX as i128);
+        let (u32_max, u64_max) = (u32::MAX as i128, u64::MAX as i128);
+        let w = |value: f64| run(NearestEven, |e| e.to_int(value, i32_min, i32_max));
+        assert_eq!(w(f64::NAN), (i32_max, NV));
+        assert_eq!(w(f64::INFINITY), (i32_max, NV));
+        assert_eq!(w(f64::NEG_INFINITY), (i32_min, NV));
+        assert_eq!(w(2147483648.0), (i32_max, NV));
+        assert_eq!(w(-2147483649.0), (i32_min, NV));
+        assert_eq!(w(-2147483648.0), (i32_min, 0));
+        assert_eq!(w(2147483647.4), (i32_max, NX));
+        assert_eq!(w(1e300), (i32_max, NV));
+
+        let w_rm = |value: f32, rm| run(rm, |e| e.to_int(value, i32_min, i32_max));
+        let wu = |value: f32, rm| run(rm, |e| e.to_int(value, 0, u32_max));
+        assert_eq!(wu(-1.0, NearestEven), (0, NV));
+        // A negative value that rounds to 0 is only inexact.
+        assert_eq!(wu(-0.4, NearestEven), (0, NX));
+        assert_eq!(wu(-0.4, Down), (0, NV));
+        assert_eq!(wu(4294967296.0, NearestEven), (u32_max, NV));
+        assert_eq!(
+            run(NearestEven, |e| e.to_int(
+                1.8446744073709552e19f64,
+                0,
+                u64_max
+            )),
+            (u64_max, NV)
+        );
+
+        for (rm, positive, negative) in [
+            (NearestEven, 2, -2),
+            (TowardZero, 2, -2),
+            (Down, 2, -3),
+            (Up, 3, -2),
+            (NearestMax, 3, -3),
+        ] {
+            assert_eq!(w_rm(2.5, rm), (positive, NX), "{:?}", rm);
+            assert_eq!(w_rm(-2.5, rm), (negative, NX), "{:?}", rm);
+        }
+    }
+}
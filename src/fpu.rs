@@ -0,0 +1,382 @@
+//! The fpu module executes the instructions of the F and D extensions: single- and
+//! double-precision loads and stores, arithmetic, conversions and moves. The arithmetic itself
+//! is done by the float module.
+//!
+//! The floating-point registers are 64 bits wide. A single-precision value is kept NaN-boxed,
+//! with the upper 32 bits set, and a register that doesn't hold one reads as the canonical NaN.
+
+use crate::cpu::{Cpu, MSTATUS_FS};
+use crate::csr::{FFLAGS, FRM};
+use crate::exception::Exception;
+use crate::float::{self, Env, Float, Rounding};
+use crate::isa::MISA_D;
+
+/// The upper half of a NaN-boxed single-precision value.
+const NAN_BOX: u64 = 0xffffffff_00000000;
+
+impl Cpu {
+    /// Return the floating-point register `index` as a value of format `F`.
+    pub(crate) fn freg<F: Float>(&self, index: usize) -> F {
+        let bits = self.fregs[index];
+        if F::BITS == 32 && bits & NAN_BOX != NAN_BOX {
+            return F::canonical_nan();
+        }
+        F::from_bits(bits)
+    }
+
+    /// Set the floating-point register `index` to `value`, NaN-boxing a single-precision value.
+    pub(crate) fn set_freg<F: Float>(&mut self, index: usize, value: F) {
+        self.fregs[index] = match F::BITS {
+            32 => NAN_BOX | value.to_bits(),
+            _ => value.to_bits(),
+        };
+        self.set_dirty(MSTATUS_FS);
+    }
+
+    /// Return the rounding mode the rm field `rm` selects, which is frm's if it's 7 (dynamic).
+    /// A reserved rounding mode is an illegal instruction.
+    pub(crate) fn rounding(&self, rm: u32) -> Result<Rounding, Exception> {
+        let rm = if rm == 0x7 { self.csr[FRM] } else { rm as u64 };
+        Rounding::from_bits(rm).ok_or(Exception::IllegalInstruction)
+    }
+
+    /// Accrue the exception flags raised in `env` to fflags.
+    pub(crate) fn accrue(&mut self, env: &Env) {
+        if env.flags == 0 {
+            return;
+        }
+        self.csr[FFLAGS] |= env.flags;
+        self.set_dirty(MSTATUS_FS);
+        if let Some(commit) = &mut self.commit {
+            commit.write_csr(FFLAGS, self.csr[FFLAGS]);
+        }
+    }
+
+    /// Execute an instruction of the F or D extension.
+    pub(crate) fn execute_fp(&mut self, inst: u32) -> Result<(), Exception> {
+        let opcode = inst & 0x0000007f;
+        let rd = ((inst & 0x00000f80) >> 7) as usize;
+        let rs1 = ((inst & 0x000f8000) >> 15) as usize;
+        let rs2 = ((inst & 0x01f00000) >> 20) as usize;
+        let rs3 = (inst >> 27) as usize;
+        let funct3 = (inst & 0x00007000) >> 12;
+        let funct7 = (inst & 0xfe000000) >> 25;
+
+        if !self.fp_enabled() {
+            return Err(Exception::IllegalInstruction);
+        }
+        // The format: S, D, H or Q. Loads and stores encode it as their width.
+        let double = match opcode {
+            0x07 | 0x27 => funct3 == 0x3,
+            _ => match funct7 & 0x3 {
+                0x0 => false,
+                0x1 => true,
+                _ => {
+                    println!(
+                        "Unsupported instruction: opcode {:x} funct3 {:x} funct7 {:x}",
+                        opcode, funct3, funct7
+                    );
+                    return Err(Exception::IllegalInstruction);
+                }
+            },
+        };
+        if double && !self.enabled(MISA_D) {
+            return Err(Exception::IllegalInstruction);
+        }
+
+        match opcode {
+            // FLW, FLD
+            0x07 => {
+                let imm = ((inst as i32 as i64) >> 20) as u64;
+                let address = self.regs[rs1].wrapping_add(imm);
+                if double {
+                    let value = self.load(address, 64)?;
+                    self.set_freg(rd, f64::from_bits(value));
+                } else {
+                    let value = self.load(address, 32)?;
+                    self.set_freg(rd, f32::from_bits(value as u32));
+                }
+            }
+            // FSW, FSD store the register as it is, NaN-boxed or not.
+            0x27 => {
+                let imm = (((inst & 0xfe000000) as i32 as i64 >> 20) as u64)
+                    | ((inst >> 7) & 0x1f) as u64;
+                let address = self.regs[rs1].wrapping_add(imm);
+                let size = if double { 64 } else { 32 };
+                self.store(address, size, self.fregs[rs2])?;
+            }
+            0x43 | 0x47 | 0x4b | 0x4f => {
+                // FMADD, FMSUB, FNMSUB and FNMADD negate the product and the addend.
+                let negate_product = opcode == 0x4b || opcode == 0x4f;
+                let negate_addend = opcode == 0x47 || opcode == 0x4f;
+                let mut env = Env::new(self.rounding(funct3)?);
+                if double {
+                    let result = fma::<f64>(
+                        self,
+                        &mut env,
+                        [rs1, rs2, rs3],
+                        negate_product,
+                        negate_addend,
+                    );
+                    self.set_freg(rd, result);
+                } else {
+                    let result = fma::<f32>(
+                        self,
+                        &mut env,
+                        [rs1, rs2, rs3],
+                        negate_product,
+                        negate_addend,
+                    );
+                    self.set_freg(rd, result);
+                }
+                self.accrue(&env);
+            }
+            // FCVT.S.D, FCVT.D.S
+            0x53 if funct7 >> 2 == 0x08 => {
+                let mut env = Env::new(self.rounding(funct3)?);
+                match (double, rs2) {
+                    (false, 1) if self.enabled(MISA_D) => {
+                        let result: f32 = env.convert(self.freg::<f64>(rs1));
+                        self.set_freg(rd, result);
+                    }
+                    (true, 0) => {
+                        let result: f64 = env.convert(self.freg::<f32>(rs1));
+                        self.set_freg(rd, result);
+                    }
+                    _ => return Err(Exception::IllegalInstruction),
+                }
+                self.accrue(&env);
+            }
+            0x53 if double => return self.execute_op_fp::<f64>(inst),
+            0x53 => return self.execute_op_fp::<f32>(inst),
+            _ => {
+                println!("Unsupported instruction: opcode {:x}", opcode);
+                return Err(Exception::IllegalInstruction);
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute an OP-FP instruction of format `F`, but a conversion between formats.
+    fn execute_op_fp<F: Float>(&mut self, inst: u32) -> Result<(), Exception> {
+        let rd = ((inst & 0x00000f80) >> 7) as usize;
+        let rs1 = ((inst & 0x000f8000) >> 15) as usize;
+        let rs2 = ((inst & 0x01f00000) >> 20) as usize;
+        let funct3 = (inst & 0x00007000) >> 12;
+        let funct5 = inst >> 27;
+
+        let a: F = self.freg(rs1);
+        let b: F = self.freg(rs2);
+        let unsupported = || {
+            println!(
+                "Unsupported instruction: opcode {:x} funct3 {:x} funct7 {:x}",
+                0x53,
+                funct3,
+                inst >> 25
+            );
+            Err(Exception::IllegalInstruction)
+        };
+        match funct5 {
+            // FADD, FSUB, FMUL, FDIV, FSQRT
+            0x00 | 0x01 | 0x02 | 0x03 | 0x0b => {
+                let mut env = Env::new(self.rounding(funct3)?);
+                let result = match funct5 {
+                    0x00 => env.add(a, b),
+                    0x01 => env.sub(a, b),
+                    0x02 => env.mul(a, b),
+                    0x03 => env.div(a, b),
+                    _ if rs2 == 0 => env.sqrt(a),
+                    _ => return unsupported(),
+                };
+                self.accrue(&env);
+                self.set_freg(rd, result);
+            }
+            // FSGNJ, FSGNJN, FSGNJX
+            0x04 if funct3 <= 0x2 => self.set_freg(rd, float::sign_inject(a, b, funct3)),
+            // FMIN, FMAX
+            0x05 if funct3 <= 0x1 => {
+                let mut env = Env::new(Rounding::NearestEven);
+                let result = if funct3 == 0 {
+                    env.min(a, b)
+                } else {
+                    env.max(a, b)
+                };
+                self.accrue(&env);
+                self.set_freg(rd, result);
+            }
+            // FLE, FLT, FEQ
+            0x14 if funct3 <= 0x2 => {
+                let mut env = Env::new(Rounding::NearestEven);
+                let result = match funct3 {
+                    0x0 => env.le(a, b),
+                    0x1 => env.lt(a, b),
+                    _ => env.eq(a, b),
+                };
+                self.accrue(&env);
+                self.regs[rd] = result as u64;
+            }
+            // FCVT.W, FCVT.WU, FCVT.L, FCVT.LU
+            0x18 => {
+                let mut env = Env::new(self.rounding(funct3)?);
+                let value = match rs2 {
+                    0x0 => env.to_int(a, i32::MIN as i128, i32::MAX as i128) as i32 as u64,
+                    // The 32-bit result is sign-extended even if it's unsigned.
+                    0x1 => env.to_int(a, 0, u32::MAX as i128) as i32 as u64,
+                    0x2 => env.to_int(a, i64::MIN as i128, i64::MAX as i128) as u64,
+                    0x3 => env.to_int(a, 0, u64::MAX as i128) as u64,
+                    _ => return unsupported(),
+                };
+                self.accrue(&env);
+                self.regs[rd] = value;
+            }
+            // FCVT from W, WU, L, LU
+            0x1a => {
+                let x = self.regs[rs1];
+                let value = match rs2 {
+                    0x0 => x as i32 as i128,
+                    0x1 => x as u32 as i128,
+                    0x2 => x as i64 as i128,
+                    0x3 => x as i128,
+                    _ => return unsupported(),
+                };
+                let mut env = Env::new(self.rounding(funct3)?);
+                let result: F = env.from_int(value);
+                self.accrue(&env);
+                self.set_freg(rd, result);
+            }
+            // FMV.X.W and FMV.X.D move the bits as they are.
+            0x1c if funct3 == 0x0 && rs2 == 0 => {
+                self.regs[rd] = match F::BITS {
+                    32 => self.fregs[rs1] as i32 as u64,
+                    _ => self.fregs[rs1],
+                };
+            }
+            // FCLASS
+            0x1c if funct3 == 0x1 && rs2 == 0 => self.regs[rd] = float::classify(a),
+            // FMV.W.X, FMV.D.X
+            0x1e if funct3 == 0x0 && rs2 == 0 => self.set_freg(
+                rd,
+                F::from_bits(self.regs[rs1] & (u64::MAX >> (64 - F::BITS))),
+            ),
+            _ => return unsupported(),
+        }
+        Ok(())
+    }
+}
+
+/// Return the fused multiply-add of the registers `rs` of `cpu`, rs1 × rs2 + rs3, with the
+/// product or the addend negated.
+fn fma<F: Float>(
+    cpu: &Cpu,
+    env: &mut Env,
+    rs: [usize; 3],
+    negate_product: bool,
+    negate_addend: bool,
+) -> F {
+    let a: F = cpu.freg(rs[0]);
+    let b: F = cpu.freg(rs[1]);
+    let c: F = cpu.freg(rs[2]);
+    let a = if negate_product { -a } else { a };
+    let c = if negate_addend { -c } else { c };
+    env.fma(a, b, c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csr::MSTATUS;
+    use crate::machine::Machine;
+
+    const CANONICAL_NAN: u64 = 0xffffffff_7fc00000;
+
+    /// Return a machine whose hart has the FPU on.
+    fn machine() -> Machine {
+        let mut machine = Machine::builder().build().unwrap();
+        machine.cpu_mut().csr[MSTATUS] |= MSTATUS_FS;
+        machine
+    }
+
+    /// An OP-FP instruction.
+    fn op(funct7: u32, rs2: usize, rs1: usize, funct3: u32, rd: usize) -> u32 {
+        funct7 << 25
+            | (rs2 as u32) << 20
+            | (rs1 as u32) << 15
+            | funct3 << 12
+            | (rd as u32) << 7
+            | 0x53
+    }
+
+    #[test]
+    fn single_precision_values_are_nan_boxed() {
+        let mut machine = machine();
+        let cpu = machine.cpu_mut();
+        cpu.regs[10] = 0xdeadbeef_3f800000;
+        // fmv.w.x f1, a0
+        cpu.decode_execute(op(0x78, 0, 10, 0, 1)).unwrap();
+        assert_eq!(cpu.fregs[1], NAN_BOX | 0x3f800000);
+        // fadd.s f2, f1, f1
+        cpu.decode_execute(op(0x00, 1, 1, 0, 2)).unwrap();
+        assert_eq!(cpu.fregs[2], NAN_BOX | 0x40000000);
+        // fcvt.s.d f3, f4 writes a boxed single.
+        cpu.fregs[4] = 2.5f64.to_bits();
+        cpu.decode_execute(op(0x20, 1, 4, 0, 3)).unwrap();
+        assert_eq!(cpu.fregs[3], NAN_BOX | 2.5f32.to_bits() as u64);
+    }
+
+    #[test]
+    fn unboxed_values_read_as_the_canonical_nan() {
+        let mut machine = machine();
+        let cpu = machine.cpu_mut();
+        // 1.0 without the upper half set.
+        cpu.fregs[1] = 0x3f800000;
+        cpu.fregs[2] = NAN_BOX | 0x3f800000;
+        // fadd.s f3, f1, f2
+        cpu.decode_execute(op(0x00, 2, 1, 0, 3)).unwrap();
+        assert_eq!(cpu.fregs[3], CANONICAL_NAN);
+        // The canonical NaN is quiet.
+        assert_eq!(cpu.csr[FFLAGS], 0);
+        // fsgnjn.s f4, f1, f1
+        cpu.decode_execute(op(0x10, 1, 1, 1, 4)).unwrap();
+        assert_eq!(cpu.fregs[4], CANONICAL_NAN | 1 << 31);
+        // fclass.s a0, f1 says quiet NaN.
+        cpu.decode_execute(op(0x70, 0, 1, 1, 10)).unwrap();
+        assert_eq!(cpu.regs[10], 1 << 9);
+        // fcvt.d.s f5, f1
+        cpu.decode_execute(op(0x21, 0, 1, 0, 5)).unwrap();
+        assert_eq!(cpu.fregs[5], 0x7ff80000_00000000);
+        // fmv.x.w a1, f1 moves the bits as they are.
+        cpu.fregs[1] = 0x12345678_80000000;
+        cpu.decode_execute(op(0x70, 0, 1, 0, 11)).unwrap();
+        assert_eq!(cpu.regs[11], 0xffffffff_80000000);
+    }
+
+    #[test]
+    fn flags_accrue_in_fflags() {
+        let mut machine = machine();
+        let cpu = machine.cpu_mut();
+        cpu.fregs[1] = NAN_BOX | 1.0f32.to_bits() as u64;
+        cpu.fregs[2] = NAN_BOX;
+        cpu.fregs[3] = NAN_BOX | 3.0f32.to_bits() as u64;
+        // fdiv.s f4, f1, f2 divides by zero.
+        cpu.decode_execute(op(0x0c, 2, 1, 0, 4)).unwrap();
+        assert_eq!(cpu.csr[FFLAGS], float::DZ);
+        // fdiv.s f4, f1, f3 is inexact, and the flags accrue.
+        cpu.decode_execute(op(0x0c, 3, 1, 0, 4)).unwrap();
+        assert_eq!(cpu.csr[FFLAGS], float::DZ | float::NX);
+        // A reserved rounding mode is illegal, in the instruction or in frm.
+        assert!(cpu.decode_execute(op(0x0c, 3, 1, 5, 4)).is_err());
+        cpu.csr[FRM] = 6;
+        assert!(cpu.decode_execute(op(0x0c, 3, 1, 7, 4)).is_err());
+    }
+
+    #[test]
+    fn the_fpu_can_be_off() {
+        let mut machine = machine();
+        let cpu = machine.cpu_mut();
+        cpu.csr[MSTATUS] &= !MSTATUS_FS;
+        assert_eq!(
+            cpu.decode_execute(op(0x00, 1, 1, 0, 2)),
+            Err(Exception::IllegalInstruction)
+        );
+    }
+}
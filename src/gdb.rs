@@ -0,0 +1,759 @@
+//! The gdb module implements a GDB remote serial protocol stub, so that the guest can be debugged
+//! with `target remote :<port>` from `riscv64-unknown-elf-gdb`.
+//!
+//! Besides breakpoints, watchpoints and single-stepping, the stub supports executing backwards:
+//! `reverse-stepi`, `reverse-continue` and watchpoints that trigger in reverse, e.g. to find the
+//! last instruction that wrote a corrupted variable. Going back in time restores a checkpoint of
+//! the `Timeline` and executes forward again with the recorded UART input. Output the guest has
+//! already printed is not printed again.
+//!
+//! Some of this is also available as monitor commands, see `monitor help`.
+//!
+//! Every hart is a thread to GDB, hart n being thread n + 1. Selecting a thread only chooses
+//! whose registers GDB sees: the harts still take turns as they do without a debugger, so a
+//! single step executes one instruction of whichever hart's turn it is, and the stop reply names
+//! that hart.
+
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use crate::cpu::Cpu;
+use crate::disasm::ABI;
+use crate::reverse::Timeline;
+
+/// The number of instructions executed between two checks for a Ctrl-C from GDB.
+const INTERRUPT_POLL: u64 = 0x10000;
+/// Signal numbers used in stop replies.
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+const MONITOR_HELP: &str = "\
+icount                  print the number of instructions executed
+goto <icount>           go to the state after <icount> instructions
+reverse-step [n]        go back by n instructions (default 1)
+reverse-continue        go back to the previous breakpoint or watchpoint hit
+checkpoints             list the checkpoints kept for reverse execution
+Commands that move in time don't tell GDB; run `maintenance flush register-cache` afterwards.
+";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+impl WatchKind {
+    fn name(self) -> &'static str {
+        match self {
+            WatchKind::Write => "watch",
+            WatchKind::Read => "rwatch",
+            WatchKind::Access => "awatch",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Watchpoint {
+    kind: WatchKind,
+    addr: u64,
+    len: u64,
+}
+
+/// Why execution stopped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stop {
+    /// A single step finished.
+    Step,
+    /// A breakpoint was reached.
+    Breakpoint,
+    /// A watchpoint was hit by the access to the address.
+    Watch(WatchKind, u64),
+    /// GDB sent a Ctrl-C.
+    Interrupted,
+    /// Reverse execution reached the first state that can be restored.
+    HistoryStart,
+    /// The guest raised a fatal exception.
+    Fault,
+}
+
+/// A GDB stub connected to a debugger.
+pub struct GdbStub {
+    stream: TcpStream,
+    timeline: Timeline,
+    breakpoints: BTreeSet<u64>,
+    watchpoints: Vec<Watchpoint>,
+    /// The furthest instruction count executed. UART output before it has been seen already.
+    frontier: u64,
+}
+
+impl GdbStub {
+    /// Create a stub for the connection `stream` that can go back to the current state of `cpu`.
+    pub fn new(stream: TcpStream, cpu: &Cpu, interval: u64) -> io::Result<Self> {
+        Ok(Self {
+            stream,
+            timeline: Timeline::new(cpu, interval)?,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            frontier: cpu.icount,
+        })
+    }
+
+    /// Serve the debugger until it detaches, kills the target or disconnects.
+    pub fn serve(&mut self, cpu: &mut Cpu) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            let reply = match packet.as_bytes().first() {
+                Some(b'k') => return Ok(()),
+                Some(b'D') => {
+                    self.write_packet("OK")?;
+                    return Ok(());
+                }
+                _ => self.handle(cpu, &packet)?,
+            };
+            self.write_packet(&reply)?;
+        }
+        Ok(())
+    }
+
+    /// Handle one packet and return the reply.
+    fn handle(&mut self, cpu: &mut Cpu, packet: &str) -> io::Result<String> {
+        let (command, args) = packet.split_at(1);
+        let reply = match command {
+            "?" => stop_reply(cpu, Stop::Step),
+            "g" => (0..33).map(|i| hex_u64(read_register(cpu, i))).collect(),
+            "G" => {
+                self.modify(cpu)?;
+                for (i, value) in args.as_bytes().chunks(16).take(33).enumerate() {
+                    match parse_le(std::str::from_utf8(value).unwrap_or("")) {
+                        Some(value) => write_register(cpu, i, value),
+                        None => return Ok("E01".to_string()),
+                    }
+                }
+                "OK".to_string()
+            }
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(i) if i <= 32 => hex_u64(read_register(cpu, i)),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(i, value)| {
+                    Some((usize::from_str_radix(i, 16).ok()?, parse_le(value)?))
+                });
+                match parsed {
+                    Some((i, value)) if i <= 32 => {
+                        self.modify(cpu)?;
+                        write_register(cpu, i, value);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => match parse_range(args) {
+                Some((addr, len)) => {
+                    let mut reply = String::new();
+                    for i in 0..len {
+                        match cpu.peek(addr.wrapping_add(i)) {
+                            Ok(byte) => reply.push_str(&format!("{:02x}", byte)),
+                            Err(_) if i > 0 => break,
+                            Err(_) => return Ok("E14".to_string()),
+                        }
+                    }
+                    reply
+                }
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let parsed = args
+                    .split_once(':')
+                    .and_then(|(range, data)| Some((parse_range(range)?, hex_decode(data)?)));
+                match parsed {
+                    Some(((addr, _), data)) => {
+                        self.modify(cpu)?;
+                        let written = data
+                            .iter()
+                            .enumerate()
+                            .all(|(i, byte)| cpu.poke(addr.wrapping_add(i as u64), *byte).is_ok());
+                        if written {
+                            "OK".to_string()
+                        } else {
+                            "E14".to_string()
+                        }
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "c" | "s" => {
+                if !args.is_empty() {
+                    return Ok("E01".to_string());
+                }
+                let stop = self.resume(cpu, command == "s")?;
+                stop_reply(cpu, stop)
+            }
+            "b" => match args {
+                "s" => {
+                    let stop = self.reverse_step(cpu, 1)?;
+                    stop_reply(cpu, stop)
+                }
+                "c" => {
+                    let stop = self.reverse_continue(cpu)?;
+                    stop_reply(cpu, stop)
+                }
+                _ => String::new(),
+            },
+            "Z" | "z" => self.breakpoint(command == "Z", args),
+            "H" => {
+                // `Hg` selects the thread for register accesses, `Hc` the one to resume, which
+                // the turns of the harts decide.
+                match args.split_at(1) {
+                    ("g", thread) => match parse_thread(cpu, thread) {
+                        Some(Some(hart)) => {
+                            cpu.switch_to(hart);
+                            "OK".to_string()
+                        }
+                        Some(None) => "OK".to_string(),
+                        None => "E01".to_string(),
+                    },
+                    ("c", thread) if parse_thread(cpu, thread).is_some() => "OK".to_string(),
+                    _ => "E01".to_string(),
+                }
+            }
+            "T" => match parse_thread(cpu, args) {
+                Some(_) => "OK".to_string(),
+                None => "E01".to_string(),
+            },
+            "q" => self.query(cpu, args)?,
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    /// Handle a query packet.
+    fn query(&mut self, cpu: &mut Cpu, args: &str) -> io::Result<String> {
+        let reply = if args.starts_with("Supported") {
+            "PacketSize=4000;qXfer:features:read+;swbreak+;ReverseStep+;ReverseContinue+"
+                .to_string()
+        } else if let Some(annex) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            match parse_range(annex) {
+                Some((offset, len)) => {
+                    let xml = target_xml();
+                    let start = (offset as usize).min(xml.len());
+                    let end = (start + len as usize).min(xml.len());
+                    let prefix = if end == xml.len() { 'l' } else { 'm' };
+                    format!("{}{}", prefix, &xml[start..end])
+                }
+                None => "E01".to_string(),
+            }
+        } else if args == "Attached" {
+            "1".to_string()
+        } else if args == "C" {
+            format!("QC{:x}", cpu.hart() + 1)
+        } else if args == "fThreadInfo" {
+            let threads: Vec<String> = (1..=cpu.harts()).map(|t| format!("{:x}", t)).collect();
+            format!("m{}", threads.join(","))
+        } else if let Some(thread) = args.strip_prefix("ThreadExtraInfo,") {
+            match parse_thread(cpu, thread) {
+                Some(Some(hart)) => hex_encode(format!("hart {}", hart).as_bytes()),
+                _ => "E01".to_string(),
+            }
+        } else if args == "sThreadInfo" {
+            "l".to_string()
+        } else if let Some(command) = args.strip_prefix("Rcmd,") {
+            let command = hex_decode(command).unwrap_or_default();
+            let output = self.monitor(cpu, &String::from_utf8_lossy(&command))?;
+            self.write_packet(&format!("O{}", hex_encode(output.as_bytes())))?;
+            "OK".to_string()
+        } else {
+            String::new()
+        };
+        Ok(reply)
+    }
+
+    /// Run a monitor command and return its output.
+    fn monitor(&mut self, cpu: &mut Cpu, command: &str) -> io::Result<String> {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        let output = match words.as_slice() {
+            ["icount"] => format!("{}\n", cpu.icount),
+            ["goto", icount] => match icount.parse::<u64>() {
+                Ok(icount) if icount < self.timeline.origin() => format!(
+                    "the history starts at instruction {}\n",
+                    self.timeline.origin()
+                ),
+                Ok(icount) => {
+                    if self.resume_until(cpu, icount)? == Stop::Fault {
+                        format!("stopped by a fatal exception at {}\n", cpu.icount)
+                    } else {
+                        format!("at instruction {}\n", cpu.icount)
+                    }
+                }
+                Err(_) => "usage: goto <icount>\n".to_string(),
+            },
+            ["reverse-step"] | ["reverse-step", _] => {
+                let count = words.get(1).map_or(Ok(1), |n| n.parse::<u64>());
+                match count {
+                    Ok(count) => {
+                        self.reverse_step(cpu, count)?;
+                        format!("at instruction {}, pc {:#x}\n", cpu.icount, cpu.pc)
+                    }
+                    Err(_) => "usage: reverse-step [n]\n".to_string(),
+                }
+            }
+            ["reverse-continue"] => {
+                let stop = self.reverse_continue(cpu)?;
+                let reason = match stop {
+                    Stop::Breakpoint => "a breakpoint".to_string(),
+                    Stop::Watch(kind, addr) => format!("a {} at {:#x}", kind.name(), addr),
+                    _ => "the start of the history".to_string(),
+                };
+                format!(
+                    "stopped by {} at instruction {}, pc {:#x}\n",
+                    reason, cpu.icount, cpu.pc
+                )
+            }
+            ["checkpoints"] => self
+                .timeline
+                .checkpoints()
+                .map(|icount| format!("{}\n", icount))
+                .collect(),
+            _ => MONITOR_HELP.to_string(),
+        };
+        Ok(output)
+    }
+
+    /// Insert or remove a breakpoint or watchpoint.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let fields: Vec<&str> = args.split(',').collect();
+        let (kind, addr, len) = match fields.as_slice() {
+            [kind, addr, len, ..] => match (
+                u64::from_str_radix(addr, 16),
+                u64::from_str_radix(len.split(';').next().unwrap_or(""), 16),
+            ) {
+                (Ok(addr), Ok(len)) => (*kind, addr, len),
+                _ => return "E01".to_string(),
+            },
+            _ => return "E01".to_string(),
+        };
+        let watch = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+                return "OK".to_string();
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+        if insert {
+            self.watchpoints.push(Watchpoint {
+                kind: watch,
+                addr,
+                len,
+            });
+        } else if let Some(i) = self
+            .watchpoints
+            .iter()
+            .position(|w| w.kind == watch && w.addr == addr && w.len == len)
+        {
+            self.watchpoints.remove(i);
+        }
+        "OK".to_string()
+    }
+
+    /// The debugger is about to change the state of `cpu`, so the recorded future is no longer
+    /// valid.
+    fn modify(&mut self, cpu: &mut Cpu) -> io::Result<()> {
+        if let Some(events) = &mut cpu.events {
+            events.truncate(cpu.icount);
+        }
+        self.timeline.truncate(cpu.icount);
+        self.frontier = cpu.icount;
+        self.timeline.checkpoint(cpu)
+    }
+
+    /// Execute one instruction forward. Return why execution must stop, if it must.
+    fn step(&mut self, cpu: &mut Cpu) -> io::Result<Option<Stop>> {
+        self.timeline.tick(cpu)?;
+        cpu.bus.set_silent(cpu.icount < self.frontier);
+        let result = cpu.step();
+        self.frontier = self.frontier.max(cpu.icount);
+        if result.is_err() {
+            return Ok(Some(Stop::Fault));
+        }
+        Ok(self.watch_hit(cpu))
+    }
+
+    /// Return the watchpoint hit by the instruction that just executed, if any.
+    fn watch_hit(&self, cpu: &Cpu) -> Option<Stop> {
+        let commit = cpu.commit.as_ref()?;
+        let overlaps = |w: &Watchpoint, addr: u64, bits: usize| {
+            addr < w.addr + w.len && w.addr < addr + (bits as u64 / 8).max(1)
+        };
+        for w in self.watchpoints.iter() {
+            if w.kind != WatchKind::Write {
+                if let Some((addr, _)) =
+                    commit.loads.iter().find(|(a, bits)| overlaps(w, *a, *bits))
+                {
+                    let kind = if w.kind == WatchKind::Read {
+                        WatchKind::Read
+                    } else {
+                        WatchKind::Access
+                    };
+                    return Some(Stop::Watch(kind, *addr));
+                }
+            }
+            if w.kind != WatchKind::Read {
+                if let Some((addr, _, _)) = commit
+                    .stores
+                    .iter()
+                    .find(|(a, _, bits)| overlaps(w, *a, *bits))
+                {
+                    return Some(Stop::Watch(w.kind, *addr));
+                }
+            }
+        }
+        None
+    }
+
+    /// Execute forward until a breakpoint, a watchpoint, a Ctrl-C or a single step.
+    fn resume(&mut self, cpu: &mut Cpu, single: bool) -> io::Result<Stop> {
+        loop {
+            if let Some(stop) = self.step(cpu)? {
+                return Ok(stop);
+            }
+            if single {
+                return Ok(Stop::Step);
+            }
+            if self.breakpoints.contains(&cpu.pc) {
+                return Ok(Stop::Breakpoint);
+            }
+            if cpu.icount.is_multiple_of(INTERRUPT_POLL) && self.interrupted()? {
+                return Ok(Stop::Interrupted);
+            }
+        }
+    }
+
+    /// Go to the state after `icount` instructions, backwards or forwards.
+    fn resume_until(&mut self, cpu: &mut Cpu, icount: u64) -> io::Result<Stop> {
+        if icount <= self.frontier {
+            self.timeline.seek(cpu, icount)?;
+            return Ok(Stop::Step);
+        }
+        self.timeline.seek(cpu, self.frontier)?;
+        while cpu.icount < icount {
+            if let Some(Stop::Fault) = self.step(cpu)? {
+                return Ok(Stop::Fault);
+            }
+        }
+        Ok(Stop::Step)
+    }
+
+    /// Go back by `count` instructions.
+    fn reverse_step(&mut self, cpu: &mut Cpu, count: u64) -> io::Result<Stop> {
+        let origin = self.timeline.origin();
+        if cpu.icount <= origin {
+            return Ok(Stop::HistoryStart);
+        }
+        let target = cpu.icount.saturating_sub(count).max(origin);
+        self.timeline.seek(cpu, target)?;
+        Ok(Stop::Step)
+    }
+
+    /// Go back to the last state before the current one where a breakpoint or watchpoint hit.
+    fn reverse_continue(&mut self, cpu: &mut Cpu) -> io::Result<Stop> {
+        let origin = self.timeline.origin();
+        let mut end = cpu.icount;
+        // A watchpoint hit by the step into `end` only counts if `end` isn't the current state.
+        let mut include_end = false;
+        while end > origin {
+            let start = self.timeline.before(end).unwrap_or(origin);
+            if let Some((icount, stop)) = self.scan(cpu, start, end, include_end)? {
+                self.timeline.seek(cpu, icount)?;
+                return Ok(stop);
+            }
+            end = start;
+            include_end = true;
+        }
+        self.timeline.seek(cpu, origin)?;
+        Ok(Stop::HistoryStart)
+    }
+
+    /// Execute from instruction `start` to `end` and return the last state before `end` where
+    /// a breakpoint or watchpoint hit.
+    fn scan(
+        &mut self,
+        cpu: &mut Cpu,
+        start: u64,
+        end: u64,
+        include_end: bool,
+    ) -> io::Result<Option<(u64, Stop)>> {
+        self.timeline.seek(cpu, start)?;
+        let mut hit = None;
+        if self.breakpoints.contains(&cpu.pc) {
+            hit = Some((cpu.icount, Stop::Breakpoint));
+        }
+        cpu.bus.set_silent(true);
+        while cpu.icount < end {
+            if cpu.step().is_err() {
+                break;
+            }
+            if cpu.icount == end && !include_end {
+                break;
+            }
+            if let Some(stop) = self.watch_hit(cpu) {
+                hit = Some((cpu.icount, stop));
+            } else if cpu.icount < end && self.breakpoints.contains(&cpu.pc) {
+                hit = Some((cpu.icount, Stop::Breakpoint));
+            }
+        }
+        Ok(hit)
+    }
+
+    /// Return true if GDB sent a Ctrl-C.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        let mut byte = [0];
+        self.stream.set_nonblocking(true)?;
+        let result = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(1) => Ok(byte[0] == 0x03),
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read the next packet and acknowledge it. Return `None` when GDB disconnects.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        let mut byte = [0];
+        loop {
+            // Skip acknowledgements and stray Ctrl-Cs until the start of a packet.
+            loop {
+                if self.stream.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+            let mut data = Vec::new();
+            loop {
+                if self.stream.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum)?;
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|c| u8::from_str_radix(c, 16).ok());
+            if expected == Some(data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    /// Send a packet. GDB's acknowledgement is skipped by the next `read_packet`.
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(self.stream, "${}#{:02x}", data, checksum)?;
+        self.stream.flush()
+    }
+}
+
+/// Return the stop reply packet for `stop`, which happened on the running hart of `cpu`.
+fn stop_reply(cpu: &Cpu, stop: Stop) -> String {
+    let (signal, reason) = match stop {
+        Stop::Step => (SIGTRAP, String::new()),
+        Stop::Breakpoint => (SIGTRAP, "swbreak:;".to_string()),
+        Stop::Watch(kind, addr) => (SIGTRAP, format!("{}:{:x};", kind.name(), addr)),
+        Stop::Interrupted => (SIGINT, String::new()),
+        Stop::HistoryStart => (SIGTRAP, "replaylog:begin;".to_string()),
+        Stop::Fault => (SIGSEGV, String::new()),
+    };
+    format!("T{:02x}{}thread:{:x};", signal, reason, cpu.hart() + 1)
+}
+
+/// Parse a thread id. Return `Some(None)` for "any thread" or "all threads", and `None` if the
+/// thread doesn't exist.
+fn parse_thread(cpu: &Cpu, thread: &str) -> Option<Option<usize>> {
+    match thread {
+        "0" | "-1" => Some(None),
+        _ => match usize::from_str_radix(thread, 16) {
+            Ok(id) if (1..=cpu.harts()).contains(&id) => Some(Some(id - 1)),
+            _ => None,
+        },
+    }
+}
+
+/// Return the GDB register `i`: x0-x31, then the pc.
+fn read_register(cpu: &Cpu, i: usize) -> u64 {
+    match i {
+        32 => cpu.pc,
+        _ => cpu.reg(i),
+    }
+}
+
+fn write_register(cpu: &mut Cpu, i: usize, value: u64) {
+    match i {
+        32 => cpu.pc = value,
+        _ => cpu.set_reg(i, value),
+    }
+}
+
+/// Describe the registers of the target to GDB.
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><architecture>riscv:rv64</architecture>\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">",
+    );
+    for (i, name) in ABI.iter().enumerate() {
+        let kind = match *name {
+            "ra" => "code_ptr",
+            "sp" | "gp" | "tp" => "data_ptr",
+            _ => "int",
+        };
+        xml.push_str(&format!(
+            "<reg name=\"{}\" bitsize=\"64\" type=\"{}\" regnum=\"{}\"/>",
+            name, kind, i
+        ));
+    }
+    xml.push_str("<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"32\"/>");
+    xml.push_str("</feature></target>");
+    xml
+}
+
+/// Encode `value` as 16 hex digits in target (little-endian) byte order.
+fn hex_u64(value: u64) -> String {
+    hex_encode(&value.to_le_bytes())
+}
+
+/// Parse a register value in target byte order.
+fn parse_le(hex: &str) -> Option<u64> {
+    let bytes = hex_decode(hex)?;
+    if bytes.len() != 8 {
+        return None;
+    }
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes);
+    Some(u64::from_le_bytes(value))
+}
+
+/// Parse `addr,length`.
+fn parse_range(args: &str) -> Option<(u64, u64)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((
+        u64::from_str_radix(addr, 16).ok()?,
+        u64::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::EventLog;
+    use crate::testing::{counting_machine, COUNTER, COUNTER_STORE};
+    use std::net::TcpListener;
+
+    /// Return both ends of a TCP connection: the stub's and GDB's.
+    fn connect() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let gdb = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stub, _) = listener.accept().unwrap();
+        (stub, gdb)
+    }
+
+    #[test]
+    fn packets_are_checksummed() {
+        let (stream, mut gdb) = connect();
+        let machine = counting_machine();
+        let mut stub = GdbStub::new(stream, machine.cpu(), 4).unwrap();
+
+        // An acknowledgement is skipped, and a packet with a wrong checksum is asked for again.
+        gdb.write_all(b"+$g#00$qC#b4").unwrap();
+        assert_eq!(stub.read_packet().unwrap().as_deref(), Some("qC"));
+        let mut acks = [0; 2];
+        gdb.read_exact(&mut acks).unwrap();
+        assert_eq!(&acks, b"-+");
+
+        stub.write_packet("OK").unwrap();
+        let mut packet = [0; 6];
+        gdb.read_exact(&mut packet).unwrap();
+        assert_eq!(&packet, b"$OK#9a");
+
+        drop(gdb);
+        assert_eq!(stub.read_packet().unwrap(), None);
+    }
+
+    #[test]
+    fn reverse_execution_stops_at_breakpoints_and_watchpoints() {
+        let (stream, _gdb) = connect();
+        let mut machine = counting_machine();
+        let cpu = machine.cpu_mut();
+        cpu.events = Some(EventLog::new());
+        let mut stub = GdbStub::new(stream, cpu, 4).unwrap();
+        let mut handle = |cpu: &mut Cpu, packet: &str| stub.handle(cpu, packet).unwrap();
+
+        for _ in 0..20 {
+            assert_eq!(handle(cpu, "s"), "T05thread:1;");
+        }
+        assert_eq!(cpu.icount, 20);
+
+        // The store runs at instructions 3n + 2.
+        let breakpoint = format!("{:x},4", COUNTER_STORE);
+        assert_eq!(handle(cpu, &format!("Z0,{}", breakpoint)), "OK");
+        assert_eq!(handle(cpu, "bc"), "T05swbreak:;thread:1;");
+        assert_eq!((cpu.icount, cpu.pc), (17, COUNTER_STORE));
+        assert_eq!(handle(cpu, "bc"), "T05swbreak:;thread:1;");
+        assert_eq!(cpu.icount, 14);
+        assert_eq!(handle(cpu, &format!("z0,{}", breakpoint)), "OK");
+
+        // A write watchpoint stops after the store.
+        let watchpoint = format!("{:x},8", COUNTER);
+        let hit = format!("T05watch:{:x};thread:1;", COUNTER);
+        assert_eq!(handle(cpu, &format!("Z2,{}", watchpoint)), "OK");
+        assert_eq!(handle(cpu, "bc"), hit);
+        assert_eq!(cpu.icount, 12);
+        assert_eq!(cpu.bus.memory.load(COUNTER, 64).unwrap(), 4);
+        assert_eq!(handle(cpu, "bs"), "T05thread:1;");
+        assert_eq!(cpu.icount, 11);
+        assert_eq!(cpu.bus.memory.load(COUNTER, 64).unwrap(), 3);
+        assert_eq!(handle(cpu, "c"), hit);
+        assert_eq!(cpu.icount, 12);
+
+        // Without a hit, reverse execution stops at the start of the history.
+        assert_eq!(handle(cpu, &format!("z2,{}", watchpoint)), "OK");
+        assert_eq!(handle(cpu, "bc"), "T05replaylog:begin;thread:1;");
+        assert_eq!(cpu.icount, 0);
+        assert_eq!(handle(cpu, "bs"), "T05replaylog:begin;thread:1;");
+
+        assert_eq!(handle(cpu, "Z2,zz,8"), "E01");
+        assert_eq!(handle(cpu, "Z9,0,4"), "");
+        assert_eq!(handle(cpu, "b?"), "");
+    }
+}
@@ -0,0 +1,403 @@
+//! The hypervisor module implements the H extension. A guest runs in VS or VU mode, which are S
+//! and U mode with the virtualization mode V set. It sees the VS CSRs in place of the supervisor
+//! ones, and its addresses are translated in two stages: the VS-stage of vsatp maps them to
+//! guest physical addresses, which the G-stage of hgatp maps to physical ones.
+
+use crate::cpu::{AccessType, Cpu, Mode, Stage, PAGE_SIZE};
+use crate::csr::*;
+use crate::exception::Exception;
+use crate::isa::MISA_H;
+
+use std::mem;
+
+// HSTATUS fields.
+/// Set if a trap into HS mode wrote a guest virtual address to stval.
+pub const HSTATUS_GVA: u64 = 1 << 6;
+/// Set if a trap into HS mode came from a guest, which SRET then returns to.
+pub const HSTATUS_SPV: u64 = 1 << 7;
+/// The mode of the guest when it trapped into HS mode, which HLV and HSV access memory as.
+pub const HSTATUS_SPVP: u64 = 1 << 8;
+/// Lets U mode execute HLV, HLVX and HSV.
+pub const HSTATUS_HU: u64 = 1 << 9;
+/// Makes SFENCE.VMA and accesses to satp in VS mode virtual-instruction exceptions.
+pub const HSTATUS_VTVM: u64 = 1 << 20;
+/// Makes WFI in VS mode a virtual-instruction exception.
+pub const HSTATUS_VTW: u64 = 1 << 21;
+/// Makes SRET in VS mode a virtual-instruction exception.
+pub const HSTATUS_VTSR: u64 = 1 << 22;
+/// The read-only VSXL field: a guest's XLEN is 64.
+pub const HSTATUS_VSXL: u64 = 2 << 32;
+/// The fields of hstatus that can be written.
+pub const HSTATUS_WRITABLE: u64 = HSTATUS_GVA
+    | HSTATUS_SPV
+    | HSTATUS_SPVP
+    | HSTATUS_HU
+    | HSTATUS_VTVM
+    | HSTATUS_VTW
+    | HSTATUS_VTSR;
+
+/// The exceptions hedeleg can delegate to a guest. Environment calls from HS and VS mode, the
+/// guest-page faults and virtual instructions are always handled by the hypervisor.
+pub const HEDELEG_WRITABLE: u64 = 0xb1ff;
+
+/// The MODE of satp, vsatp and hgatp for Sv39, and Sv39x4 for hgatp.
+const MODE_SV39: u64 = 8;
+/// The PPN field of satp, vsatp and hgatp.
+const PPN: u64 = (1 << 44) - 1;
+
+impl Cpu {
+    /// Translate the guest virtual address `addr` to a physical address, by the VS-stage and
+    /// then the G-stage, whose pages must permit `permission`.
+    pub(crate) fn translate_guest(
+        &mut self,
+        addr: u64,
+        access_type: AccessType,
+        permission: AccessType,
+    ) -> Result<u64, Exception> {
+        let vsatp = self.csr[VSATP];
+        let gpa = match vsatp >> 60 {
+            MODE_SV39 => self.walk(
+                (vsatp & PPN) * PAGE_SIZE,
+                addr,
+                access_type,
+                permission,
+                Stage::VirtualSupervisor,
+            )?,
+            _ => addr,
+        };
+        self.translate_physical(gpa, access_type, permission)
+    }
+
+    /// Translate the guest physical address `gpa` to a physical address by the G-stage, whose
+    /// page must permit `permission`.
+    pub(crate) fn translate_physical(
+        &mut self,
+        gpa: u64,
+        access_type: AccessType,
+        permission: AccessType,
+    ) -> Result<u64, Exception> {
+        let hgatp = self.csr[HGATP];
+        match hgatp >> 60 {
+            MODE_SV39 => self.walk(
+                (hgatp & PPN) * PAGE_SIZE,
+                gpa,
+                access_type,
+                permission,
+                Stage::Guest,
+            ),
+            _ => Ok(gpa),
+        }
+    }
+
+    /// Return the CSR that an access to `address` goes to: a guest accesses the VS CSRs in place
+    /// of the supervisor ones.
+    pub(crate) fn guest_csr(&self, address: usize) -> usize {
+        if !self.virt {
+            return address;
+        }
+        match address {
+            SSTATUS => VSSTATUS,
+            SIE => VSIE,
+            STVEC => VSTVEC,
+            SSCRATCH => VSSCRATCH,
+            SEPC => VSEPC,
+            SCAUSE => VSCAUSE,
+            STVAL => VSTVAL,
+            SIP => VSIP,
+            SATP => VSATP,
+            _ => address,
+        }
+    }
+
+    /// Record in hstatus and htval a trap into HS mode. If it came from a guest in `mode`, SPV
+    /// and SPVP remember to return to it. `gpa` is the guest physical address of a guest-page
+    /// fault shifted right by 2, or 0.
+    pub(crate) fn trap_to_hypervisor(&mut self, virt: bool, mode: Mode, gpa: u64) {
+        if !self.enabled(MISA_H) {
+            return;
+        }
+        let mut hstatus = self.load_csr(HSTATUS) & !(HSTATUS_GVA | HSTATUS_SPV);
+        if virt {
+            hstatus = (hstatus & !HSTATUS_SPVP)
+                | HSTATUS_SPV
+                | if let Mode::Supervisor = mode {
+                    HSTATUS_SPVP
+                } else {
+                    0
+                };
+        }
+        self.store_csr(HSTATUS, hstatus);
+        self.store_csr(HTVAL, gpa);
+    }
+
+    /// Execute a hypervisor load or store (HLV, HLVX, HSV), which accesses memory like the guest
+    /// does, or a hypervisor fence (HFENCE.VVMA, HFENCE.GVMA).
+    pub(crate) fn execute_hypervisor(&mut self, inst: u32) -> Result<(), Exception> {
+        let opcode = inst & 0x0000007f;
+        let rd = ((inst & 0x00000f80) >> 7) as usize;
+        let rs1 = ((inst & 0x000f8000) >> 15) as usize;
+        let rs2 = ((inst & 0x01f00000) >> 20) as usize;
+        let funct3 = (inst & 0x00007000) >> 12;
+        let funct7 = (inst & 0xfe000000) >> 25;
+        let unsupported = || {
+            println!(
+                "Unsupported instruction: opcode {:x} funct3 {:x} funct7 {:x}",
+                opcode, funct3, funct7
+            );
+            Exception::IllegalInstruction
+        };
+
+        if !self.enabled(MISA_H) {
+            return Err(unsupported());
+        }
+        // A guest can't use them, and U mode can only load and store if hstatus.HU lets it.
+        if self.virt {
+            return Err(Exception::VirtualInstruction);
+        }
+        if let Mode::User = self.mode {
+            if funct3 == 0x0 || self.csr[HSTATUS] & HSTATUS_HU == 0 {
+                return Err(Exception::IllegalInstruction);
+            }
+        }
+
+        if funct3 == 0x0 {
+            // There are no TLBs to flush, as for SFENCE.VMA.
+            return match funct7 {
+                0x11 | 0x31 => Ok(()),
+                _ => Err(unsupported()),
+            };
+        }
+        // The size of the access, and whether a load sign-extends the value.
+        let (size, signed) = match (funct7, rs2) {
+            // HLV.B, HLV.BU
+            (0x30, 0x0) => (8, true),
+            (0x30, 0x1) => (8, false),
+            // HLV.H, HLV.HU, HLVX.HU
+            (0x32, 0x0) => (16, true),
+            (0x32, 0x1 | 0x3) => (16, false),
+            // HLV.W, HLV.WU, HLVX.WU
+            (0x34, 0x0) => (32, true),
+            (0x34, 0x1 | 0x3) => (32, false),
+            // HLV.D
+            (0x36, 0x0) => (64, false),
+            // HSV.B, HSV.H, HSV.W, HSV.D
+            (0x31, _) if rd == 0 => (8, false),
+            (0x33, _) if rd == 0 => (16, false),
+            (0x35, _) if rd == 0 => (32, false),
+            (0x37, _) if rd == 0 => (64, false),
+            _ => return Err(unsupported()),
+        };
+        // HLVX reads pages that can be executed rather than read. The VS-stage checks no other
+        // permissions, so the mode in hstatus.SPVP makes no difference.
+        let addr = self.regs[rs1];
+        let virt = mem::replace(&mut self.virt, true);
+        let result = if funct7 & 1 == 0 {
+            let value = match rs2 {
+                0x3 => self.load_executable(addr, size),
+                _ => self.load(addr, size),
+            };
+            value.map(|value| {
+                self.regs[rd] = match (size, signed) {
+                    (8, true) => value as i8 as i64 as u64,
+                    (16, true) => value as i16 as i64 as u64,
+                    (32, true) => value as i32 as i64 as u64,
+                    _ => value,
+                }
+            })
+        } else {
+            self.store(addr, size, self.regs[rs2])
+        };
+        self.virt = virt;
+        result
+    }
+
+    /// Load a value like HLVX, from a guest page that can be executed.
+    fn load_executable(&mut self, addr: u64, size: usize) -> Result<u64, Exception> {
+        let p_addr = self.translate_guest(addr, AccessType::Load, AccessType::Instruction)?;
+        let value = self.bus.load(p_addr, size)?;
+        if let Some(commit) = &mut self.commit {
+            commit.loads.push((addr, size));
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::MEMORY_BASE;
+    use crate::cpu::MSTATUS_MXR;
+    use crate::machine::Machine;
+
+    /// The root page table of the G-stage, which is 16 KiB for Sv39x4.
+    const G_ROOT: u64 = MEMORY_BASE + 0x10_0000;
+    /// The guest physical address of the root page table of the VS-stage.
+    const VS_ROOT: u64 = 0x20_0000;
+    /// A guest virtual address, which the VS-stage maps to `GPA`.
+    const VA: u64 = 0x8000_1234;
+    const GPA: u64 = 0x1234;
+
+    /// The flags of a leaf PTE: V, R, W, X, U, A and D.
+    const V: u64 = 1 << 0;
+    const R: u64 = 1 << 1;
+    const W: u64 = 1 << 2;
+    const X: u64 = 1 << 3;
+    const U: u64 = 1 << 4;
+    const AD: u64 = 3 << 6;
+
+    /// Set up a guest whose first guest physical gigabyte the G-stage maps to the memory with
+    /// the leaf `g_flags`, and whose VS-stage maps the gigabyte at `VA` to it with `vs_flags`.
+    fn guest(machine: &mut Machine, g_flags: u64, vs_flags: u64) -> &mut Cpu {
+        let cpu = machine.cpu_mut();
+        cpu.mode = Mode::Supervisor;
+        cpu.virt = true;
+        cpu.csr[HGATP] = (MODE_SV39 << 60) | (G_ROOT / PAGE_SIZE);
+        cpu.bus
+            .store(G_ROOT, 64, ((MEMORY_BASE >> 12) << 10) | g_flags)
+            .unwrap();
+        cpu.csr[VSATP] = (MODE_SV39 << 60) | (VS_ROOT / PAGE_SIZE);
+        cpu.bus
+            .store(MEMORY_BASE + VS_ROOT + 8 * (VA >> 30), 64, vs_flags)
+            .unwrap();
+        cpu
+    }
+
+    #[test]
+    fn both_stages_translate() {
+        let mut machine = Machine::builder().build().unwrap();
+        let cpu = guest(&mut machine, V | R | W | X | U | AD, V | R | W | X | AD);
+        for access_type in [AccessType::Instruction, AccessType::Load, AccessType::Store] {
+            assert_eq!(cpu.translate(VA, access_type), Ok(MEMORY_BASE + GPA));
+        }
+        // Without a VS-stage, the guest's addresses are guest physical ones.
+        cpu.csr[VSATP] = 0;
+        assert_eq!(cpu.translate(GPA, AccessType::Load), Ok(MEMORY_BASE + GPA));
+    }
+
+    #[test]
+    fn the_g_stage_checks_permissions() {
+        let mut machine = Machine::builder().build().unwrap();
+        let cpu = guest(&mut machine, V | R | U | AD, V | R | W | X | AD);
+        assert_eq!(cpu.translate(VA, AccessType::Load), Ok(MEMORY_BASE + GPA));
+        assert_eq!(
+            cpu.translate(VA, AccessType::Store),
+            Err(Exception::StoreAMOGuestPageFault)
+        );
+        assert_eq!(cpu.guest_fault, GPA);
+        assert_eq!(
+            cpu.translate(VA, AccessType::Instruction),
+            Err(Exception::InstructionGuestPageFault)
+        );
+
+        // Every leaf of the G-stage must be a U-mode page.
+        let cpu = guest(&mut machine, V | R | W | X | AD, V | R | W | X | AD);
+        assert_eq!(
+            cpu.translate(VA, AccessType::Load),
+            Err(Exception::LoadGuestPageFault)
+        );
+
+        // The VS-stage page table must be readable, but the fault is one of the original access.
+        let cpu = guest(&mut machine, V | X | U | AD, V | R | W | X | AD);
+        assert_eq!(
+            cpu.translate(VA, AccessType::Store),
+            Err(Exception::StoreAMOGuestPageFault)
+        );
+        assert_eq!(cpu.guest_fault, VS_ROOT + 8 * (VA >> 30));
+        // MXR makes the executable pages readable.
+        cpu.csr[VSATP] = 0;
+        assert_eq!(
+            cpu.translate(GPA, AccessType::Load),
+            Err(Exception::LoadGuestPageFault)
+        );
+        cpu.csr[MSTATUS] |= MSTATUS_MXR;
+        assert_eq!(cpu.translate(GPA, AccessType::Load), Ok(MEMORY_BASE + GPA));
+    }
+
+    #[test]
+    fn sv39x4_has_41_bit_addresses() {
+        let mut machine = Machine::builder().build().unwrap();
+        let cpu = guest(&mut machine, V | R | W | X | U | AD, 0);
+        cpu.csr[VSATP] = 0;
+        // The root page table has 2048 entries.
+        let gpa = (1 << 40) | GPA;
+        cpu.bus
+            .store(
+                G_ROOT + 8 * (gpa >> 30),
+                64,
+                ((MEMORY_BASE >> 12) << 10) | V | R | W | X | U | AD,
+            )
+            .unwrap();
+        assert_eq!(cpu.translate(gpa, AccessType::Load), Ok(MEMORY_BASE + GPA));
+        assert_eq!(
+            cpu.translate(1 << 41, AccessType::Load),
+            Err(Exception::LoadGuestPageFault)
+        );
+        assert_eq!(cpu.guest_fault, 1 << 41);
+    }
+
+    #[test]
+    fn guest_page_faults_report_the_guest_physical_address() {
+        let mut machine = Machine::builder().build().unwrap();
+        let cpu = guest(&mut machine, V | R | U | AD, V | R | W | X | AD);
+        // Delegated to HS mode, the fault goes to htval.
+        cpu.csr[MEDELEG] = 1 << Exception::StoreAMOGuestPageFault as u64;
+        let fault = cpu.translate(VA, AccessType::Store).unwrap_err();
+        fault.get_trap(cpu);
+        assert!(!cpu.virt);
+        assert_eq!(cpu.load_csr(SCAUSE), 23);
+        assert_eq!(cpu.load_csr(HTVAL), GPA >> 2);
+        assert_eq!(cpu.load_csr(HTINST), 0);
+        assert_ne!(cpu.load_csr(HSTATUS) & HSTATUS_SPV, 0);
+
+        // Otherwise, to mtval2.
+        let cpu = guest(&mut machine, V | R | U | AD, V | R | W | X | AD);
+        cpu.csr[MEDELEG] = 0;
+        let fault = cpu.translate(VA, AccessType::Instruction).unwrap_err();
+        fault.get_trap(cpu);
+        assert_eq!(cpu.load_csr(MCAUSE), 20);
+        assert_eq!(cpu.load_csr(MTVAL2), GPA >> 2);
+        assert_eq!(cpu.load_csr(MTINST), 0);
+    }
+
+    #[test]
+    fn hlvx_reads_executable_pages() {
+        // HLVX.WU a0, (a1)
+        let hlvx = (0x34 << 25) | (0x3 << 20) | (11 << 15) | (0x4 << 12) | (10 << 7) | 0x73;
+        // HLV.WU a0, (a1)
+        let hlv = hlvx & !(0x2 << 20);
+        let mut machine = Machine::builder().build().unwrap();
+        let cpu = guest(&mut machine, V | X | U | AD, V | R | W | X | AD);
+        cpu.virt = false;
+        cpu.bus.store(MEMORY_BASE + GPA, 32, 0x1234_5678).unwrap();
+        cpu.regs[11] = VA;
+        // The VS-stage page table must still be readable.
+        assert_eq!(
+            cpu.execute_hypervisor(hlvx),
+            Err(Exception::LoadGuestPageFault)
+        );
+        cpu.csr[VSATP] = 0;
+        cpu.regs[11] = GPA;
+        assert_eq!(cpu.execute_hypervisor(hlvx), Ok(()));
+        assert_eq!(cpu.regs[10], 0x1234_5678);
+        assert!(!cpu.virt);
+        assert_eq!(
+            cpu.execute_hypervisor(hlv),
+            Err(Exception::LoadGuestPageFault)
+        );
+
+        // Both stages must let the page be executed.
+        let cpu = guest(&mut machine, V | R | U | AD, V | R | W | X | AD);
+        cpu.virt = false;
+        cpu.csr[VSATP] = 0;
+        cpu.regs[11] = GPA;
+        assert_eq!(
+            cpu.execute_hypervisor(hlvx),
+            Err(Exception::LoadGuestPageFault)
+        );
+        let cpu = guest(&mut machine, V | R | X | U | AD, V | R | W | AD);
+        cpu.virt = false;
+        cpu.regs[11] = VA;
+        assert_eq!(cpu.execute_hypervisor(hlvx), Err(Exception::LoadPageFault));
+        assert_eq!(cpu.execute_hypervisor(hlv), Ok(()));
+    }
+}
@@ -10,31 +10,67 @@ use crate::csr::*;
 /// control.
 #[repr(u64)]
 #[derive(Debug, Clone, Copy)]
+#[allow(clippy::enum_variant_names)]
 pub enum Interrupt {
     UserSoftwareInterrupt = 0,
     SupervisorSoftwareInterrupt = 1,
+    VirtualSupervisorSoftwareInterrupt = 2,
     MachineSoftwareInterrupt = 3,
     UserTimerInterrupt = 4,
     SupervisorTimerInterrupt = 5,
+    VirtualSupervisorTimerInterrupt = 6,
     MachineTimerInterrupt = 7,
     UserExternalInterrupt = 8,
     SupervisorExternalInterrupt = 9,
+    VirtualSupervisorExternalInterrupt = 10,
     MachineExternalInterrupt = 11,
 }
 
 impl Interrupt {
     /// Handle trap from current exception.
     pub fn get_trap(&self, cpu: &mut Cpu) {
-        let exception_pc = cpu.pc.wrapping_sub(4);
+        // Interrupts are taken before the instruction at pc is fetched, so it's executed after
+        // the trap returns.
+        let exception_pc = cpu.pc;
         let previous_mode = cpu.mode;
+        let previous_virt = cpu.virt;
 
         // Set the interrupt bit.
         let cause = *self as u64 | (1 << 63);
-        if (previous_mode as u8 <= Mode::Supervisor as u8)
-            && ((cpu.load_csr(MEDELEG).wrapping_shr(cause as u32)) & 1 != 0)
+        let delegated = (previous_mode as u8 <= Mode::Supervisor as u8)
+            && ((cpu.load_csr(MIDELEG).wrapping_shr(cause as u32)) & 1 != 0);
+        if delegated
+            && previous_virt
+            && ((cpu.load_csr(HIDELEG).wrapping_shr(cause as u32)) & 1 != 0)
         {
+            // Handle the trap in VS mode. The guest sees the VS-level interrupts as the
+            // supervisor ones, whose codes are one less.
+            cpu.mode = Mode::Supervisor;
+            let cause = cause - 1;
+
+            // Set the program counter to VSTVEC.
+            let vector = match cpu.load_csr(VSTVEC) & 1 {
+                1 => 4 * cause, // vectored mode
+                _ => 0,         // direct mode
+            };
+            cpu.pc = (cpu.load_csr(VSTVEC) & !1) + vector;
+
+            cpu.store_csr(VSEPC, exception_pc & !1);
+            cpu.store_csr(VSCAUSE, cause);
+            cpu.store_csr(VSTVAL, 0);
+            // SPIE gets SIE, which is cleared, and SPP the previous mode.
+            let vsstatus = cpu.load_csr(VSSTATUS);
+            cpu.store_csr(
+                VSSTATUS,
+                (vsstatus & !((1 << 1) | (1 << 5) | (1 << 8)))
+                    | (((vsstatus >> 1) & 1) << 5)
+                    | ((previous_mode as u64 & 1) << 8),
+            );
+        } else if delegated {
             // Handle the trap in S mode.
             cpu.mode = Mode::Supervisor;
+            cpu.virt = false;
+            cpu.trap_to_hypervisor(previous_virt, previous_mode, 0);
 
             // Set the program counter to STVEC.
             let vector = match cpu.load_csr(STVEC) & 1 {
@@ -62,6 +98,7 @@ impl Interrupt {
         } else {
             // Handle the trap in M mode.
             cpu.mode = Mode::Machine;
+            cpu.virt = false;
 
             // Set the program counter to MTVEC.
             let vector = match cpu.load_csr(MTVEC) & 1 {
@@ -82,7 +119,13 @@ impl Interrupt {
                 },
             );
             cpu.store_csr(MSTATUS, cpu.load_csr(MSTATUS) & !(1 << 3));
-            cpu.store_csr(MSTATUS, cpu.load_csr(MSTATUS) & !(0b11 << 11));
+            // Save the previous mode to MPP and V to MPV, so that MRET returns to them.
+            cpu.store_csr(
+                MSTATUS,
+                (cpu.load_csr(MSTATUS) & !((0b11 << 11) | MSTATUS_MPV | MSTATUS_GVA))
+                    | ((previous_mode as u64) << 11)
+                    | if previous_virt { MSTATUS_MPV } else { 0 },
+            );
         }
     }
 }
@@ -0,0 +1,414 @@
+//! The isa module parses ISA strings like `rv64ima_zicsr` into the set of extensions a hart
+//! implements. Single-letter extensions are kept as `misa` bits, multi-letter ones as a bitmask of
+//! `Z*` constants, so that the decoder can check them cheaply.
+//!
+//! The vector extensions also configure the vector unit: V and the embedded Zve* subsets set
+//! ELEN and the minimum VLEN, and `zvl<N>b` raises VLEN to N bits.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// The MXL field of misa for RV64, in bits 63:62.
+pub const MISA_MXL_64: u64 = 2 << 62;
+/// The misa bits of single-letter extensions.
+pub const MISA_A: u64 = letter(b'a');
+pub const MISA_D: u64 = letter(b'd');
+pub const MISA_F: u64 = letter(b'f');
+pub const MISA_H: u64 = letter(b'h');
+pub const MISA_I: u64 = letter(b'i');
+pub const MISA_M: u64 = letter(b'm');
+pub const MISA_S: u64 = letter(b's');
+pub const MISA_U: u64 = letter(b'u');
+pub const MISA_V: u64 = letter(b'v');
+
+/// Multi-letter extensions.
+pub const ZICSR: u64 = 1 << 0;
+pub const ZIFENCEI: u64 = 1 << 1;
+pub const ZBA: u64 = 1 << 2;
+pub const ZBB: u64 = 1 << 3;
+pub const ZBC: u64 = 1 << 4;
+pub const ZBS: u64 = 1 << 5;
+/// The vector extensions for embedded processors, which V implies. Every hart with a vector unit
+/// has Zve32x.
+pub const ZVE32X: u64 = 1 << 6;
+pub const ZVE32F: u64 = 1 << 7;
+pub const ZVE64X: u64 = 1 << 8;
+pub const ZVE64F: u64 = 1 << 9;
+pub const ZVE64D: u64 = 1 << 10;
+/// The scalar cryptography extensions: bit manipulation for cryptography (Zbkb, Zbkc, Zbkx), the
+/// NIST algorithms AES (Zkne, Zknd) and SHA-2 (Zknh), the ShangMi algorithms SM4 (Zksed) and SM3
+/// (Zksh), and the entropy source (Zkr).
+pub const ZBKB: u64 = 1 << 11;
+pub const ZBKC: u64 = 1 << 12;
+pub const ZBKX: u64 = 1 << 13;
+pub const ZKND: u64 = 1 << 14;
+pub const ZKNE: u64 = 1 << 15;
+pub const ZKNH: u64 = 1 << 16;
+pub const ZKSED: u64 = 1 << 17;
+pub const ZKSH: u64 = 1 << 18;
+pub const ZKR: u64 = 1 << 19;
+
+/// The single-letter extensions honga implements, in canonical order.
+const LETTERS: &str = "imafdvh";
+/// The multi-letter extensions honga implements, in canonical order.
+const EXTENSIONS: &[(&str, u64)] = &[
+    ("zicsr", ZICSR),
+    ("zifencei", ZIFENCEI),
+    ("zba", ZBA),
+    ("zbb", ZBB),
+    ("zbc", ZBC),
+    ("zbkb", ZBKB),
+    ("zbkc", ZBKC),
+    ("zbkx", ZBKX),
+    ("zbs", ZBS),
+    ("zknd", ZKND),
+    ("zkne", ZKNE),
+    ("zknh", ZKNH),
+    ("zkr", ZKR),
+    ("zksed", ZKSED),
+    ("zksh", ZKSH),
+    ("zve32f", ZVE32F),
+    ("zve32x", ZVE32X),
+    ("zve64d", ZVE64D),
+    ("zve64f", ZVE64F),
+    ("zve64x", ZVE64X),
+];
+/// The extensions that stand for a group of others.
+const GROUPS: &[(&str, u64)] = &[
+    ("zkn", ZBKB | ZBKC | ZBKX | ZKNE | ZKND | ZKNH),
+    ("zks", ZBKB | ZBKC | ZBKX | ZKSED | ZKSH),
+];
+/// The vector extensions with the ones they imply, and the minimum VLEN they require.
+const VECTOR: &[(u64, u64, usize)] = &[
+    (ZVE32X, ZVE32X, 32),
+    (ZVE32F, ZVE32F | ZVE32X, 32),
+    (ZVE64X, ZVE64X | ZVE32X, 64),
+    (ZVE64F, ZVE64F | ZVE64X | ZVE32F | ZVE32X, 64),
+    (ZVE64D, ZVE64D | ZVE64F | ZVE64X | ZVE32F | ZVE32X, 64),
+];
+/// The minimum VLEN of V.
+const V_VLEN: usize = 128;
+/// The maximum VLEN, which the vector extension allows.
+const MAX_VLEN: usize = 65536;
+/// The single-letter extensions of the ISA manual, in canonical order.
+const KNOWN_LETTERS: &str = "iemafdqlcbkjtpvnh";
+/// The single-letter extensions a write to misa may turn off. Only M and A are WARL-writable:
+/// F, D and V stay as the ISA string set them, so that misa never turns off the floating-point or
+/// vector state while mstatus.FS or mstatus.VS still tracks it.
+const WRITABLE: u64 = MISA_M | MISA_A;
+
+/// Return the misa bit of the single-letter extension `c`.
+pub const fn letter(c: u8) -> u64 {
+    1 << (c - b'a')
+}
+
+/// The extensions of a hart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Isa {
+    /// Single-letter extensions as misa bits.
+    letters: u64,
+    /// Multi-letter extensions as `Z*` bits, including the ones other extensions imply.
+    extensions: u64,
+    /// The width of a vector register in bits, or 0 without a vector unit.
+    vlen: usize,
+}
+
+impl Default for Isa {
+    /// Everything honga implements.
+    fn default() -> Self {
+        Self {
+            letters: LETTERS.bytes().fold(0, |bits, c| bits | letter(c)),
+            extensions: EXTENSIONS.iter().fold(0, |bits, (_, bit)| bits | bit),
+            vlen: V_VLEN,
+        }
+    }
+}
+
+impl Isa {
+    /// Return true if the multi-letter extension `extension`, a `Z*` constant, is implemented.
+    #[inline]
+    pub fn has(&self, extension: u64) -> bool {
+        self.extensions & extension != 0
+    }
+
+    /// Return the width of a vector register in bits, or 0 if there's no vector unit.
+    pub fn vlen(&self) -> usize {
+        self.vlen
+    }
+
+    /// Return the maximum width of a vector element in bits, or 0 if there's no vector unit.
+    pub fn elen(&self) -> usize {
+        if self.has(ZVE64X) {
+            64
+        } else if self.has(ZVE32X) {
+            32
+        } else {
+            0
+        }
+    }
+
+    /// Return the minimum VLEN of the vector extensions.
+    fn min_vlen(&self) -> usize {
+        if self.letters & MISA_V != 0 {
+            return V_VLEN;
+        }
+        VECTOR
+            .iter()
+            .filter(|(bit, _, _)| self.has(*bit))
+            .map(|(_, _, vlen)| *vlen)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Return the reset value of misa: RV64, the single-letter extensions, and the supervisor and
+    /// user modes.
+    pub fn misa(&self) -> u64 {
+        MISA_MXL_64 | self.letters | MISA_S | MISA_U
+    }
+
+    /// Return the value misa has after writing `value` to it while it is `old`. Only M and A, if
+    /// this ISA implements them, are writable; the rest is read-only.
+    pub fn write_misa(&self, old: u64, value: u64) -> u64 {
+        let writable = self.letters & WRITABLE;
+        (old & !writable) | (value & writable)
+    }
+}
+
+impl FromStr for Isa {
+    type Err = String;
+
+    fn from_str(isa: &str) -> Result<Self, String> {
+        let lower = isa.to_ascii_lowercase();
+        let rest = match lower.strip_prefix("rv64") {
+            Some(rest) => rest,
+            None if lower.starts_with("rv32") || lower.starts_with("rv128") => {
+                return Err(format!("`{}`: honga only emulates RV64", isa))
+            }
+            None => return Err(format!("`{}` is not an ISA string like `rv64ima`", isa)),
+        };
+        let unimplemented = |extension: &str| {
+            format!(
+                "`{}`: the {} extension isn't implemented; honga implements {}",
+                isa,
+                extension,
+                Isa::default()
+            )
+        };
+
+        let mut parts = rest.split('_');
+        let letters = parts.next().unwrap_or_default();
+        // The first multi-letter extension may follow the single letters without an underscore.
+        let (letters, first) = match letters.find(['z', 's', 'x']) {
+            Some(i) => letters.split_at(i),
+            None => (letters, ""),
+        };
+        let letters = match letters.strip_prefix('g') {
+            // G is IMAFD with Zicsr and Zifencei.
+            Some(rest) => format!("imafd{}", rest),
+            None if letters.starts_with('i') => letters.to_string(),
+            None if letters.starts_with('e') => {
+                return Err(format!("`{}`: RV64E is not supported", isa))
+            }
+            None => return Err(format!("`{}` must start with rv64i or rv64g", isa)),
+        };
+        // Zicsr and Zifencei are implied, as in ISA strings from before they were split out of the
+        // base ISA.
+        let mut result = Isa {
+            letters: 0,
+            extensions: ZICSR | ZIFENCEI,
+            vlen: 0,
+        };
+        let mut zvl = 0;
+        for c in letters.chars() {
+            if LETTERS.contains(c) {
+                result.letters |= letter(c as u8);
+            } else if KNOWN_LETTERS.contains(c) {
+                return Err(unimplemented(&c.to_ascii_uppercase().to_string()));
+            } else {
+                return Err(format!("`{}`: unknown extension `{}`", isa, c));
+            }
+        }
+        for extension in std::iter::once(first).chain(parts) {
+            if extension.is_empty() {
+                continue;
+            }
+            if let Some(vlen) = extension
+                .strip_prefix("zvl")
+                .and_then(|rest| rest.strip_suffix('b'))
+            {
+                zvl = match vlen.parse::<usize>() {
+                    Ok(vlen) if vlen.is_power_of_two() && (32..=MAX_VLEN).contains(&vlen) => {
+                        zvl.max(vlen)
+                    }
+                    _ => {
+                        return Err(format!(
+                            "`{}`: VLEN must be a power of two from 32 to {}, not `{}`",
+                            isa, MAX_VLEN, vlen
+                        ))
+                    }
+                };
+                continue;
+            }
+            match EXTENSIONS
+                .iter()
+                .chain(GROUPS)
+                .find(|(name, _)| *name == extension)
+            {
+                Some((_, bit)) => result.extensions |= bit,
+                None => return Err(unimplemented(extension)),
+            }
+        }
+
+        if result.letters & MISA_D != 0 && result.letters & MISA_F == 0 {
+            return Err(format!("`{}`: D requires F", isa));
+        }
+        if result.letters & MISA_V != 0 {
+            // V is Zve64d with a VLEN of at least 128.
+            if result.letters & MISA_D == 0 {
+                return Err(format!("`{}`: V requires F and D", isa));
+            }
+            result.extensions |= ZVE64D;
+        }
+        for (bit, implied, _) in VECTOR {
+            if result.has(*bit) {
+                result.extensions |= implied;
+            }
+        }
+        if result.has(ZVE32F) && result.letters & MISA_F == 0 {
+            return Err(format!("`{}`: Zve32f and Zve64f require F", isa));
+        }
+        if result.has(ZVE64D) && result.letters & MISA_D == 0 {
+            return Err(format!("`{}`: Zve64d requires D", isa));
+        }
+        let min_vlen = result.min_vlen();
+        if zvl != 0 && min_vlen == 0 {
+            return Err(format!("`{}`: Zvl*b requires a vector extension", isa));
+        }
+        result.vlen = zvl.max(min_vlen);
+        Ok(result)
+    }
+}
+
+impl fmt::Display for Isa {
+    /// Format the ISA as a canonical ISA string.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rv64")?;
+        for c in KNOWN_LETTERS.bytes() {
+            if self.letters & letter(c) != 0 {
+                write!(f, "{}", c as char)?;
+            }
+        }
+        // Vector extensions that V or another vector extension implies are left out.
+        let mut implied = if self.letters & MISA_V != 0 {
+            ZVE64D
+        } else {
+            0
+        };
+        for (bit, implies, _) in VECTOR {
+            if self.has(*bit) {
+                implied |= implies & !bit;
+            }
+        }
+        for (name, bit) in EXTENSIONS {
+            if self.extensions & bit != 0 && implied & bit == 0 {
+                write!(f, "_{}", name)?;
+            }
+        }
+        if self.vlen > self.min_vlen() {
+            write!(f, "_zvl{}b", self.vlen)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(isa: &str) -> Isa {
+        isa.parse().unwrap()
+    }
+
+    #[test]
+    fn g_expands_to_imafd() {
+        let g = parse("rv64g");
+        assert_eq!(g, parse("rv64imafd_zicsr_zifencei"));
+        assert_eq!(
+            g.misa(),
+            MISA_MXL_64 | MISA_I | MISA_M | MISA_A | MISA_F | MISA_D | MISA_S | MISA_U
+        );
+        assert!(g.has(ZICSR) && g.has(ZIFENCEI));
+        assert_eq!(parse("RV64GV"), parse("rv64imafdv"));
+        assert!("rv64gc".parse::<Isa>().is_err());
+        assert!("rv32g".parse::<Isa>().is_err());
+        assert!("rv64e".parse::<Isa>().is_err());
+        assert!("rv64ma".parse::<Isa>().is_err());
+    }
+
+    #[test]
+    fn multi_letter_extensions() {
+        let isa = parse("rv64ima_zba_zbb");
+        assert!(isa.has(ZBA) && isa.has(ZBB) && !isa.has(ZBC));
+        // The first one may follow the single letters without an underscore.
+        assert_eq!(parse("rv64imazba_zbb"), isa);
+        assert_eq!(parse("rv64ima_zbb__zba_"), isa);
+        let zkn = parse("rv64i_zkn");
+        assert!(zkn.has(ZKNE) && zkn.has(ZKND) && zkn.has(ZKNH) && zkn.has(ZBKB));
+        assert!(!zkn.has(ZKSED));
+        assert!("rv64i_zfoo".parse::<Isa>().is_err());
+        assert!("rv64iy".parse::<Isa>().is_err());
+    }
+
+    #[test]
+    fn dependencies_are_checked() {
+        assert!("rv64imad".parse::<Isa>().is_err());
+        assert!("rv64imafv".parse::<Isa>().is_err());
+        let v = parse("rv64imafdv");
+        assert!(v.has(ZVE64D) && v.has(ZVE32X));
+        assert_eq!((v.vlen(), v.elen()), (128, 64));
+
+        assert!("rv64i_zve32f".parse::<Isa>().is_err());
+        assert!("rv64if_zve64d".parse::<Isa>().is_err());
+        let zve32f = parse("rv64if_zve32f");
+        assert!(zve32f.has(ZVE32X) && !zve32f.has(ZVE64X));
+        assert_eq!((zve32f.vlen(), zve32f.elen()), (32, 32));
+        assert_eq!(parse("rv64i").vlen(), 0);
+
+        assert_eq!(parse("rv64i_zve64x_zvl256b").vlen(), 256);
+        assert_eq!(parse("rv64imafdv_zvl64b").vlen(), 128);
+        assert!("rv64i_zvl256b".parse::<Isa>().is_err());
+        assert!("rv64i_zve32x_zvl48b".parse::<Isa>().is_err());
+        assert!("rv64i_zve32x_zvl131072b".parse::<Isa>().is_err());
+    }
+
+    #[test]
+    fn display_round_trips() {
+        assert_eq!(parse("rv64gv").to_string(), "rv64imafdv_zicsr_zifencei");
+        assert_eq!(
+            parse("rv64if_zve32f_zvl64b").to_string(),
+            "rv64if_zicsr_zifencei_zve32f_zvl64b"
+        );
+        for isa in [
+            Isa::default().to_string().as_str(),
+            "rv64i",
+            "rv64ima_zbb_zks",
+            "rv64i_zve64x_zvl1024b",
+        ] {
+            let parsed = parse(isa);
+            assert_eq!(parse(&parsed.to_string()), parsed, "{}", isa);
+        }
+    }
+
+    #[test]
+    fn only_m_and_a_are_writable() {
+        let isa = Isa::default();
+        let misa = isa.misa();
+        let cleared = isa.write_misa(misa, 0);
+        assert_eq!(cleared, misa & !(MISA_M | MISA_A));
+        assert_eq!(isa.write_misa(cleared, u64::MAX), misa);
+        // An extension the ISA leaves out can't be turned on.
+        let ia = parse("rv64ia");
+        assert_eq!(ia.write_misa(ia.misa(), u64::MAX), ia.misa());
+        assert_eq!(ia.write_misa(ia.misa(), 0), ia.misa() & !MISA_A);
+    }
+}
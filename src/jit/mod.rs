@@ -0,0 +1,1168 @@
+//! The jit module translates hot blocks of guest instructions to x86-64 code.
+//!
+//! A block starts at the pc and ends after a branch or a jump, before an instruction the JIT
+//! doesn't translate, or at the end of the page. Only the base integer instructions, MUL and
+//! FENCE are translated. Everything else, CSR accesses and system instructions included, ends
+//! the block and is left to the interpreter.
+//!
+//! Guest registers stay in the `Cpu`, and translated code reads and writes them there. Loads and
+//! stores call back into Rust, which translates the address like the interpreter does. If the
+//! access would trap or reach a device, the block exits before the instruction, so that the
+//! interpreter executes it and raises the trap or performs the MMIO access.
+//!
+//! A block is only run if it ends before the next event that could change what the interpreter
+//! does between two instructions: the timer reaching a mtimecmp, the next hart's turn or the
+//! instruction limit. Interrupts are taken between blocks, so a run gives the same results as
+//! with the interpreter. `Engine::Compare` checks that by executing every block both ways.
+//!
+//! Blocks are kept by their virtual and physical addresses. The fetch address is translated
+//! through the page table every time a block is entered, as the interpreter does for every
+//! instruction; there is no TLB whose entries could go stale. Like the decode cache, a block is
+//! valid as long as the generation of its page in `Memory` is unchanged. A store to the
+//! instructions of the running block ends the block after the store, so self-modifying code
+//! takes effect at the next instruction. FENCE.I makes every block compare its instructions with
+//! memory before it runs again.
+
+mod x86;
+
+use std::fmt;
+use std::io;
+use std::ptr;
+use std::str::FromStr;
+
+use crate::bus::{Memory, MEMORY_BASE};
+use crate::cpu::{AccessType, Cpu};
+use crate::csr::MISA;
+use crate::decode::decode;
+use crate::isa::MISA_M;
+use crate::trace::Commit;
+
+use x86::*;
+
+/// True if the JIT can run on this host.
+pub const SUPPORTED: bool = cfg!(all(target_arch = "x86_64", target_os = "linux"));
+
+/// The number of times a block is interpreted before it's translated.
+const HOT: u32 = 8;
+/// The most instructions in a block.
+const MAX_BLOCK: u64 = 64;
+/// The number of entries of the block table, a power of two.
+const BLOCK_ENTRIES: usize = 1 << 14;
+/// The size of the executable memory of a `Jit`. Once full, all blocks are thrown away.
+const CODE_SIZE: usize = 16 << 20;
+
+/// How the harts execute instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Engine {
+    /// Interpret every instruction.
+    #[default]
+    Interpreter,
+    /// Translate hot blocks to host code.
+    Jit,
+    /// Execute every translated block with the JIT and again with the interpreter, and stop at
+    /// the first difference.
+    Compare,
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "interpreter" => Ok(Engine::Interpreter),
+            "jit" => Ok(Engine::Jit),
+            "compare" => Ok(Engine::Compare),
+            _ => Err(format!(
+                "unknown engine `{}`; expected `interpreter`, `jit` or `compare`",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Engine::Interpreter => write!(f, "interpreter"),
+            Engine::Jit => write!(f, "jit"),
+            Engine::Compare => write!(f, "compare"),
+        }
+    }
+}
+
+/// What became of a block entry.
+enum Code {
+    /// Interpreted this many times so far.
+    Cold(u32),
+    /// Translated to host code at this offset of the executable memory.
+    Translated { entry: usize, len: u64 },
+    /// The first instruction isn't translated.
+    Untranslatable,
+}
+
+struct Block {
+    pc: u64,
+    p_pc: u64,
+    /// The generation of the page when the instructions were read.
+    generation: u32,
+    /// The instructions the block was made of.
+    words: Box<[u32]>,
+    code: Code,
+}
+
+/// A store of a translated block, kept to compare it with the interpreter and to undo it.
+struct Store {
+    addr: u64,
+    p_addr: u64,
+    size: usize,
+    value: u64,
+    old: u64,
+}
+
+/// What translated code passes to the functions it calls.
+struct Context {
+    cpu: *mut Cpu,
+    /// The stores of the block, if they're compared with the interpreter.
+    stores: Option<Vec<Store>>,
+}
+
+/// A translated block: its arguments are the registers of the `Cpu`, the context and where to
+/// write the number of instructions retired. It returns the next pc.
+type BlockFn = unsafe extern "sysv64" fn(*mut u64, *mut Context, *mut u64) -> u64;
+
+/// The value returned by `load`, in rax and rdx.
+#[repr(C)]
+struct Loaded {
+    value: u64,
+    ok: u64,
+}
+
+/// What `store` returns in rax.
+const STORED: u64 = 0;
+/// The store wrote to the instructions of the running block, which must stop after it.
+const STORED_CODE: u64 = 1;
+const NOT_STORED: u64 = 2;
+
+/// Return the physical address of the RAM that a load or a store at `addr` accesses, or `None`
+/// if the access would trap or reach a device.
+fn ram(cpu: &mut Cpu, addr: u64, access_type: AccessType) -> Option<u64> {
+    let p_addr = cpu.translate(addr, access_type).ok()?;
+    if p_addr >= MEMORY_BASE && p_addr - MEMORY_BASE < cpu.bus.memory.size() {
+        Some(p_addr)
+    } else {
+        None
+    }
+}
+
+/// Load for a translated LB, LH, LW, LD, LBU, LHU or LWU, by `funct3`.
+extern "sysv64" fn load(context: *mut Context, addr: u64, funct3: u64) -> Loaded {
+    // Safety: translated code is only run by `Jit::run`, with a context whose `cpu` is valid and
+    // not otherwise borrowed.
+    let cpu = unsafe { &mut *(*context).cpu };
+    let failed = Loaded { value: 0, ok: 0 };
+    let p_addr = match ram(cpu, addr, AccessType::Load) {
+        Some(p_addr) => p_addr,
+        None => return failed,
+    };
+    let value = match cpu.bus.load(p_addr, 8 << (funct3 & 3)) {
+        Ok(value) => value,
+        Err(_) => return failed,
+    };
+    let value = match funct3 {
+        0 => value as i8 as i64 as u64,
+        1 => value as i16 as i64 as u64,
+        2 => value as i32 as i64 as u64,
+        _ => value,
+    };
+    Loaded { value, ok: 1 }
+}
+
+/// Store for a translated SB, SH, SW or SD, by `funct3`. `start` and `end` are the physical
+/// addresses of the running block.
+extern "sysv64" fn store(
+    context: *mut Context,
+    addr: u64,
+    value: u64,
+    funct3: u64,
+    start: u64,
+    end: u64,
+) -> u64 {
+    // Safety: as for `load`.
+    let context = unsafe { &mut *context };
+    let cpu = unsafe { &mut *context.cpu };
+    let size = 8 << funct3;
+    let p_addr = match ram(cpu, addr, AccessType::Store) {
+        Some(p_addr) => p_addr,
+        None => return NOT_STORED,
+    };
+    let old = match &context.stores {
+        Some(_) => cpu.bus.load(p_addr, size).unwrap_or(0),
+        None => 0,
+    };
+    if cpu.bus.store(p_addr, size, value).is_err() {
+        return NOT_STORED;
+    }
+    if let Some(stores) = &mut context.stores {
+        let mask = if size >= 64 { !0 } else { (1 << size) - 1 };
+        stores.push(Store {
+            addr,
+            p_addr,
+            size,
+            value: value & mask,
+            old,
+        });
+    }
+    if p_addr < end && p_addr + size as u64 / 8 > start {
+        STORED_CODE
+    } else {
+        STORED
+    }
+}
+
+/// Where a translated block leaves: after `retired` instructions, at `pc`.
+struct Exit {
+    fixups: Vec<Fixup>,
+    retired: u64,
+    pc: u64,
+}
+
+/// Translates the instructions of a block.
+struct Translator {
+    asm: Assembler,
+    exits: Vec<Exit>,
+    /// Jumps to the epilogue, which puts the retired count in place and returns.
+    returns: Vec<Fixup>,
+}
+
+impl Translator {
+    /// Load the guest register `reg` into `host`.
+    fn get(&mut self, host: u8, reg: usize) {
+        if reg == 0 {
+            self.asm.alu(Alu::Xor, false, host, host);
+        } else {
+            self.asm.load(host, RBX, reg as i32 * 8);
+        }
+    }
+
+    /// Write `host` to the guest register `reg`. Writes to x0 are dropped.
+    fn set(&mut self, reg: usize, host: u8) {
+        if reg != 0 {
+            self.asm.store(RBX, reg as i32 * 8, host);
+        }
+    }
+
+    /// Leave the block after `retired` instructions at `pc`.
+    fn exit(&mut self, fixup: Fixup, retired: u64, pc: u64) {
+        match self
+            .exits
+            .iter_mut()
+            .find(|exit| exit.retired == retired && exit.pc == pc)
+        {
+            Some(exit) => exit.fixups.push(fixup),
+            None => self.exits.push(Exit {
+                fixups: vec![fixup],
+                retired,
+                pc,
+            }),
+        }
+    }
+
+    /// Call `target` with the context as the first argument.
+    fn call(&mut self, target: u64) {
+        self.asm.mov(RDI, R12);
+        self.asm.mov_imm(RAX, target);
+        self.asm.call(RAX);
+    }
+
+    /// Put the address of a load or store, rs1 plus `imm`, in rsi.
+    fn address(&mut self, rs1: usize, imm: u64) {
+        self.get(RSI, rs1);
+        if imm != 0 {
+            self.asm.alu_imm(Alu::Add, true, RSI, imm as i32);
+        }
+    }
+
+    /// Translate the instruction `inst` at `pc`, the `index`th of the block that starts at the
+    /// physical address `start` and is at most `len` instructions long. Return whether it ends
+    /// the block, or `None` if it isn't translated.
+    fn instruction(
+        &mut self,
+        inst: u32,
+        pc: u64,
+        index: u64,
+        start: u64,
+        len: u64,
+        misa: u64,
+    ) -> Option<bool> {
+        let d = decode(inst);
+        let (rd, rs1, rs2, imm) = (d.rd(), d.rs1(), d.rs2(), d.imm());
+        let opcode = inst & 0x0000007f;
+        let funct3 = (inst & 0x00007000) >> 12;
+        let funct7 = (inst & 0xfe000000) >> 25;
+        let next = pc.wrapping_add(4);
+        let retired = index + 1;
+
+        match (opcode, funct3, funct7) {
+            (0x03, 0x0..=0x6, _) => {
+                self.address(rs1, imm);
+                self.asm.mov_imm(RDX, funct3 as u64);
+                self.call(load as *const () as u64);
+                self.asm.test(RDX, RDX);
+                let failed = self.asm.jcc(Cond::E);
+                self.exit(failed, index, pc);
+                self.set(rd, RAX);
+            }
+            (0x0f, 0x0, _) => self.asm.mfence(),
+            (0x13, 0x0 | 0x2 | 0x3 | 0x4 | 0x6 | 0x7, _) if rd == 0 => {}
+            (0x13, 0x0 | 0x2 | 0x3 | 0x4 | 0x6 | 0x7, _) => {
+                self.get(RAX, rs1);
+                let imm = imm as i32;
+                match funct3 {
+                    0x0 => self.asm.alu_imm(Alu::Add, true, RAX, imm),
+                    0x2 | 0x3 => {
+                        self.asm.alu_imm(Alu::Cmp, true, RAX, imm);
+                        self.asm
+                            .set_rax(if funct3 == 0x2 { Cond::L } else { Cond::B });
+                    }
+                    0x4 => self.asm.alu_imm(Alu::Xor, true, RAX, imm),
+                    0x6 => self.asm.alu_imm(Alu::Or, true, RAX, imm),
+                    _ => self.asm.alu_imm(Alu::And, true, RAX, imm),
+                }
+                self.set(rd, RAX);
+            }
+            (0x13, 0x1, 0x00 | 0x01) | (0x13, 0x5, 0x00 | 0x01 | 0x20 | 0x21) if rd == 0 => {}
+            (0x13, 0x1, 0x00 | 0x01) | (0x13, 0x5, 0x00 | 0x01 | 0x20 | 0x21) => {
+                let op = match (funct3, funct7 & 0x20) {
+                    (0x1, _) => Shift::Shl,
+                    (_, 0x00) => Shift::Shr,
+                    _ => Shift::Sar,
+                };
+                self.get(RAX, rs1);
+                self.asm.shift_imm(op, true, RAX, (imm & 0x3f) as u8);
+                self.set(rd, RAX);
+            }
+            (0x17, _, _) => {
+                self.asm.mov_imm(RAX, pc.wrapping_add(imm));
+                self.set(rd, RAX);
+            }
+            (0x1b, 0x0, _) | (0x1b, 0x1, 0x00) | (0x1b, 0x5, 0x00 | 0x20) if rd == 0 => {}
+            (0x1b, 0x0, _) => {
+                self.get(RAX, rs1);
+                self.asm.alu_imm(Alu::Add, false, RAX, imm as i32);
+                self.asm.movsxd(RAX, RAX);
+                self.set(rd, RAX);
+            }
+            (0x1b, 0x1, 0x00) | (0x1b, 0x5, 0x00 | 0x20) => {
+                let op = match (funct3, funct7) {
+                    (0x1, _) => Shift::Shl,
+                    (_, 0x00) => Shift::Shr,
+                    _ => Shift::Sar,
+                };
+                self.get(RAX, rs1);
+                self.asm.shift_imm(op, false, RAX, (imm & 0x1f) as u8);
+                self.asm.movsxd(RAX, RAX);
+                self.set(rd, RAX);
+            }
+            (0x23, 0x0..=0x3, _) => {
+                self.address(rs1, imm);
+                self.get(RDX, rs2);
+                self.asm.mov_imm(RCX, funct3 as u64);
+                self.asm.mov_imm(R8, start);
+                self.asm.mov_imm(R9, start + len * 4);
+                self.call(store as *const () as u64);
+                self.asm.test(RAX, RAX);
+                let stored = self.asm.jcc(Cond::E);
+                self.asm.alu_imm(Alu::Cmp, true, RAX, STORED_CODE as i32);
+                let code = self.asm.jcc(Cond::E);
+                self.exit(code, retired, next);
+                let failed = self.asm.jmp();
+                self.exit(failed, index, pc);
+                let here = self.asm.here();
+                self.asm.bind(stored, here);
+            }
+            (0x33, _, 0x00 | 0x20)
+            | (0x33, 0x0, 0x01)
+            | (0x3b, 0x0 | 0x1 | 0x5, 0x00)
+            | (0x3b, 0x0 | 0x5, 0x20) => {
+                if funct7 == 0x01 && misa & MISA_M == 0 {
+                    return None;
+                }
+                let wide = opcode == 0x33;
+                let op = match (funct3, funct7) {
+                    (0x0, 0x00) => Some(Alu::Add),
+                    (0x0, 0x20) => Some(Alu::Sub),
+                    (0x4, 0x00) => Some(Alu::Xor),
+                    (0x6, 0x00) => Some(Alu::Or),
+                    (0x7, 0x00) => Some(Alu::And),
+                    (0x2 | 0x3, 0x00) | (0x1, 0x00) | (0x5, _) | (0x0, 0x01) => None,
+                    _ => return None,
+                };
+                if rd == 0 {
+                    return Some(false);
+                }
+                self.get(RAX, rs1);
+                self.get(RCX, rs2);
+                match (op, funct3, funct7) {
+                    (Some(op), _, _) => self.asm.alu(op, wide, RAX, RCX),
+                    (None, 0x0, _) => self.asm.imul(RAX, RCX),
+                    (None, 0x1, _) => self.asm.shift_cl(Shift::Shl, wide, RAX),
+                    (None, 0x5, 0x00) => self.asm.shift_cl(Shift::Shr, wide, RAX),
+                    (None, 0x5, _) => self.asm.shift_cl(Shift::Sar, wide, RAX),
+                    (None, _, _) => {
+                        self.asm.alu(Alu::Cmp, true, RAX, RCX);
+                        self.asm
+                            .set_rax(if funct3 == 0x2 { Cond::L } else { Cond::B });
+                    }
+                }
+                if !wide {
+                    self.asm.movsxd(RAX, RAX);
+                }
+                self.set(rd, RAX);
+            }
+            (0x37, _, _) => {
+                self.asm.mov_imm(RAX, imm);
+                self.set(rd, RAX);
+            }
+            (0x63, 0x0 | 0x1 | 0x4..=0x7, _) => {
+                let cond = match funct3 {
+                    0x0 => Cond::E,
+                    0x1 => Cond::Ne,
+                    0x4 => Cond::L,
+                    0x5 => Cond::Ge,
+                    0x6 => Cond::B,
+                    _ => Cond::Ae,
+                };
+                self.get(RAX, rs1);
+                self.get(RCX, rs2);
+                self.asm.alu(Alu::Cmp, true, RAX, RCX);
+                let taken = self.asm.jcc(cond);
+                self.exit(taken, retired, pc.wrapping_add(imm));
+                let not_taken = self.asm.jmp();
+                self.exit(not_taken, retired, next);
+                return Some(true);
+            }
+            (0x67, _, _) => {
+                self.get(RAX, rs1);
+                if imm != 0 {
+                    self.asm.alu_imm(Alu::Add, true, RAX, imm as i32);
+                }
+                self.asm.alu_imm(Alu::And, true, RAX, -2);
+                if rd != 0 {
+                    self.asm.mov_imm(RCX, next);
+                    self.set(rd, RCX);
+                }
+                // The next pc is in rax already.
+                self.asm.store_imm(R13, 0, retired as i32);
+                let jump = self.asm.jmp();
+                self.returns.push(jump);
+                return Some(true);
+            }
+            (0x6f, _, _) => {
+                if rd != 0 {
+                    self.asm.mov_imm(RAX, next);
+                    self.set(rd, RAX);
+                }
+                let jump = self.asm.jmp();
+                self.exit(jump, retired, pc.wrapping_add(imm));
+                return Some(true);
+            }
+            _ => return None,
+        }
+        Some(false)
+    }
+
+    /// Translate `words`, the instructions at `pc` and the physical address `p_pc`. Return the
+    /// code and the number of instructions translated, or `None` if the first one isn't.
+    fn block(words: &[u32], pc: u64, p_pc: u64, misa: u64) -> Option<(Vec<u8>, u64)> {
+        let mut translator = Translator {
+            asm: Assembler::default(),
+            exits: Vec::new(),
+            returns: Vec::new(),
+        };
+        // The registers, the context and the retired count stay in callee-saved registers.
+        // Three pushes also align the stack for calls.
+        let asm = &mut translator.asm;
+        asm.push(RBX);
+        asm.push(R12);
+        asm.push(R13);
+        asm.mov(RBX, RDI);
+        asm.mov(R12, RSI);
+        asm.mov(R13, RDX);
+
+        let len = words.len() as u64;
+        let mut count = 0;
+        let mut ended = false;
+        for (index, &inst) in words.iter().enumerate() {
+            let index = index as u64;
+            let inst_pc = pc.wrapping_add(index * 4);
+            match translator.instruction(inst, inst_pc, index, p_pc, len, misa) {
+                Some(end) => {
+                    count += 1;
+                    if end {
+                        ended = true;
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        if count == 0 {
+            return None;
+        }
+        if !ended {
+            let jump = translator.asm.jmp();
+            translator.exit(jump, count, pc.wrapping_add(count * 4));
+        }
+
+        let Translator {
+            mut asm,
+            exits,
+            mut returns,
+        } = translator;
+        for exit in exits {
+            let here = asm.here();
+            for fixup in exit.fixups {
+                asm.bind(fixup, here);
+            }
+            asm.store_imm(R13, 0, exit.retired as i32);
+            asm.mov_imm(RAX, exit.pc);
+            returns.push(asm.jmp());
+        }
+        let epilogue = asm.here();
+        for fixup in returns {
+            asm.bind(fixup, epilogue);
+        }
+        asm.pop(R13);
+        asm.pop(R12);
+        asm.pop(RBX);
+        asm.ret();
+        Some((asm.code, count))
+    }
+}
+
+/// A dynamic binary translator for the harts of one `Cpu`.
+pub struct Jit {
+    code: CodeMemory,
+    blocks: Box<[Option<Block>]>,
+    /// The misa the blocks were translated for.
+    misa: u64,
+    compare: bool,
+    /// The first difference found between the JIT and the interpreter.
+    mismatch: Option<String>,
+}
+
+impl Jit {
+    /// Create a JIT. If `compare` is true, every block is also executed by the interpreter.
+    pub fn new(compare: bool) -> io::Result<Jit> {
+        Ok(Jit {
+            code: CodeMemory::new(CODE_SIZE)?,
+            blocks: (0..BLOCK_ENTRIES).map(|_| None).collect(),
+            misa: 0,
+            compare,
+            mismatch: None,
+        })
+    }
+
+    /// Return true if blocks are executed by the interpreter too.
+    pub fn compares(&self) -> bool {
+        self.compare
+    }
+
+    /// Return the first difference found between the JIT and the interpreter.
+    pub fn mismatch(&self) -> Option<&str> {
+        self.mismatch.as_deref()
+    }
+
+    /// Throw away all blocks.
+    pub fn flush(&mut self) {
+        for block in self.blocks.iter_mut() {
+            *block = None;
+        }
+        self.code.clear();
+    }
+
+    /// Make every block compare its instructions with memory before it runs again.
+    pub fn invalidate(&mut self) {
+        for block in self.blocks.iter_mut().flatten() {
+            // No page instructions were read from has generation 0.
+            block.generation = 0;
+        }
+    }
+
+    fn index(pc: u64) -> usize {
+        (pc >> 2) as usize & (BLOCK_ENTRIES - 1)
+    }
+
+    /// Read the instructions of a block at the physical address `p_pc`: up to the end of the
+    /// page, at most `MAX_BLOCK` of them.
+    fn read(memory: &Memory, p_pc: u64) -> Vec<u32> {
+        let page_end = (p_pc | 0xfff) + 1;
+        let end = page_end
+            .min(p_pc + MAX_BLOCK * 4)
+            .min(MEMORY_BASE + memory.size());
+        (p_pc..end)
+            .step_by(4)
+            .map(|addr| memory.load(addr, 32).unwrap_or(0) as u32)
+            .collect()
+    }
+
+    /// Return true if the instructions of `block` are still in memory. A write to the page
+    /// that left them as they were, e.g. to data next to them, doesn't invalidate the block.
+    fn revalidate(memory: &Memory, block: &mut Block) -> bool {
+        // Mark the page first, so that a write after the comparison invalidates the block.
+        let generation = memory.cache_code(block.p_pc);
+        let same =
+            block.words.iter().enumerate().all(|(i, &word)| {
+                memory.load(block.p_pc + i as u64 * 4, 32).ok() == Some(word as u64)
+            });
+        if same {
+            block.generation = generation;
+        }
+        same
+    }
+
+    /// Return the host code and the length of the block at `pc`, translating it if it's hot.
+    fn lookup(&mut self, memory: &Memory, pc: u64, p_pc: u64) -> Option<(usize, u64)> {
+        let block = match &mut self.blocks[Self::index(pc)] {
+            Some(block) if block.pc == pc && block.p_pc == p_pc => block,
+            entry => {
+                *entry = Some(Block {
+                    pc,
+                    p_pc,
+                    generation: 0,
+                    words: Box::new([]),
+                    code: Code::Cold(1),
+                });
+                return None;
+            }
+        };
+        if let Code::Cold(hits) = &mut block.code {
+            *hits += 1;
+            if *hits < HOT {
+                return None;
+            }
+        } else if block.generation != memory.generation(p_pc) && !Self::revalidate(memory, block) {
+            block.code = Code::Cold(1);
+            return None;
+        }
+        if let Code::Translated { entry, len } = block.code {
+            return Some((entry, len));
+        }
+        if let Code::Untranslatable = block.code {
+            return None;
+        }
+
+        // Mark the page first, so that a write after the instructions are read invalidates the
+        // block.
+        block.generation = memory.cache_code(p_pc);
+        let words = Self::read(memory, p_pc);
+        let (code, len) = match Translator::block(&words, pc, p_pc, self.misa) {
+            Some(translated) => translated,
+            None => {
+                block.words = words[..1].into();
+                block.code = Code::Untranslatable;
+                return None;
+            }
+        };
+        match self.code.add(&code) {
+            Some(entry) => {
+                block.words = words[..len as usize].into();
+                block.code = Code::Translated { entry, len };
+                Some((entry, len))
+            }
+            None => {
+                // Start over with empty memory. The block is translated again once it's hot
+                // again.
+                self.flush();
+                None
+            }
+        }
+    }
+
+    /// Execute the block at the pc of `cpu` if it's translated and at most `budget`
+    /// instructions long. Return true if it was executed, with the pc and the instruction count
+    /// updated. The block stops early before an instruction that traps or accesses a device,
+    /// and nothing happens if that's its first one.
+    pub fn run(&mut self, cpu: &mut Cpu, budget: u64) -> bool {
+        if budget == 0 || cpu.commit.is_some() || cpu.events.is_some() {
+            return false;
+        }
+        if cpu.csr[MISA] != self.misa {
+            self.flush();
+            self.misa = cpu.csr[MISA];
+        }
+        let pc = cpu.pc;
+        let p_pc = match cpu.translate(pc, AccessType::Instruction) {
+            Ok(p_pc) => p_pc,
+            Err(_) => return false,
+        };
+        if p_pc < MEMORY_BASE || p_pc - MEMORY_BASE >= cpu.bus.memory.size() {
+            return false;
+        }
+        let entry = match self.lookup(&cpu.bus.memory, pc, p_pc) {
+            Some((entry, len)) if len <= budget => entry,
+            _ => return false,
+        };
+
+        let before = if self.compare {
+            Some((cpu.regs, cpu.pc))
+        } else {
+            None
+        };
+        let cpu_ptr: *mut Cpu = cpu;
+        let mut context = Context {
+            cpu: cpu_ptr,
+            stores: before.map(|_| Vec::new()),
+        };
+        let mut retired = 0;
+        // Safety: the block was translated by `Translator::block` into memory that is still
+        // mapped, and follows the System V calling convention. It only accesses the registers
+        // of the `Cpu` and calls `load` and `store` with the context.
+        let next = unsafe {
+            let regs = ptr::addr_of_mut!((*cpu_ptr).regs) as *mut u64;
+            (self.code.function(entry))(regs, &mut context, &mut retired)
+        };
+        if retired == 0 {
+            return false;
+        }
+        cpu.pc = next;
+        cpu.icount += retired;
+        if let (Some((regs, pc)), Some(stores)) = (before, context.stores) {
+            self.check(cpu, regs, pc, retired, stores);
+        }
+        true
+    }
+
+    /// Undo the `retired` instructions that the JIT executed, starting with `regs` at `pc`,
+    /// execute them again with the interpreter and compare the results. The interpreter's
+    /// results are kept.
+    fn check(&mut self, cpu: &mut Cpu, regs: [u64; 32], pc: u64, retired: u64, stores: Vec<Store>) {
+        let jit_regs = cpu.regs;
+        let jit_pc = cpu.pc;
+        for store in stores.iter().rev() {
+            let _ = cpu.bus.store(store.p_addr, store.size, store.old);
+        }
+        cpu.regs = regs;
+        cpu.pc = pc;
+        cpu.icount -= retired;
+
+        cpu.commit = Some(Commit::default());
+        let mut trapped = None;
+        for n in 0..retired {
+            let at = cpu.pc;
+            match cpu.execute_next() {
+                Ok(Some(_)) => {}
+                Ok(None) | Err(_) => {
+                    trapped = Some((n, at));
+                    break;
+                }
+            }
+        }
+        let commit = cpu.commit.take().unwrap_or_default();
+        if self.mismatch.is_some() {
+            return;
+        }
+
+        let block = format!("in the block at {:#x} of {} instructions", pc, retired);
+        self.mismatch = if let Some((n, at)) = trapped {
+            Some(format!(
+                "{}, the interpreter trapped at {:#x} after {} instructions",
+                block, at, n
+            ))
+        } else if jit_pc != cpu.pc {
+            Some(format!(
+                "{}, the JIT ends at {:#x} and the interpreter at {:#x}",
+                block, jit_pc, cpu.pc
+            ))
+        } else if let Some(reg) = (1..32).find(|&reg| jit_regs[reg] != cpu.regs[reg]) {
+            Some(format!(
+                "{}, the JIT sets x{} to {:#x} and the interpreter to {:#x}",
+                block, reg, jit_regs[reg], cpu.regs[reg]
+            ))
+        } else {
+            let jit_stores: Vec<(u64, u64, usize)> = stores
+                .iter()
+                .map(|store| (store.addr, store.value, store.size))
+                .collect();
+            if jit_stores != commit.stores {
+                Some(format!(
+                    "{}, the JIT stores {:x?} and the interpreter {:x?}",
+                    block, jit_stores, commit.stores
+                ))
+            } else {
+                None
+            }
+        };
+    }
+}
+
+/// Memory that translated blocks are written to and executed from.
+struct CodeMemory {
+    base: *mut u8,
+    size: usize,
+    used: usize,
+}
+
+// Safety: the memory is owned by the `CodeMemory`, and only accessed through `&mut self`.
+unsafe impl Send for CodeMemory {}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod host {
+    use std::os::raw::{c_int, c_long, c_void};
+
+    pub const PROT_READ: c_int = 1;
+    pub const PROT_WRITE: c_int = 2;
+    pub const PROT_EXEC: c_int = 4;
+    pub const MAP_PRIVATE: c_int = 0x02;
+    pub const MAP_ANONYMOUS: c_int = 0x20;
+    pub const MAP_NORESERVE: c_int = 0x4000;
+
+    extern "C" {
+        pub fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: c_int,
+            flags: c_int,
+            fd: c_int,
+            offset: c_long,
+        ) -> *mut c_void;
+        pub fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+}
+
+impl CodeMemory {
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    fn new(size: usize) -> io::Result<CodeMemory> {
+        use host::*;
+        // Safety: an anonymous mapping doesn't alias any memory of the process.
+        let base = unsafe {
+            mmap(
+                ptr::null_mut(),
+                size,
+                PROT_READ | PROT_WRITE | PROT_EXEC,
+                MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if base as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(CodeMemory {
+            base: base as *mut u8,
+            size,
+            used: 0,
+        })
+    }
+
+    #[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+    fn new(_size: usize) -> io::Result<CodeMemory> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the JIT needs an x86-64 Linux host",
+        ))
+    }
+
+    /// Copy `code` into the memory and return its offset, or `None` if it's full.
+    fn add(&mut self, code: &[u8]) -> Option<usize> {
+        // Start blocks at 16-byte boundaries, as the processor fetches them.
+        let offset = (self.used + 15) & !15;
+        if offset + code.len() > self.size {
+            return None;
+        }
+        // Safety: the range is within the mapping, and no block there runs while it's written.
+        unsafe { ptr::copy_nonoverlapping(code.as_ptr(), self.base.add(offset), code.len()) };
+        self.used = offset + code.len();
+        Some(offset)
+    }
+
+    fn clear(&mut self) {
+        self.used = 0;
+    }
+
+    /// Return the block at `offset` as a function.
+    ///
+    /// # Safety
+    ///
+    /// `offset` must have been returned by `add` since the last `clear`.
+    unsafe fn function(&self, offset: usize) -> BlockFn {
+        std::mem::transmute::<*mut u8, BlockFn>(self.base.add(offset))
+    }
+}
+
+impl Drop for CodeMemory {
+    fn drop(&mut self) {
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        // Safety: the mapping was created by `new` and no block runs any more.
+        unsafe {
+            host::munmap(self.base as *mut _, self.size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exception::Exception;
+    use crate::machine::{Exit, Machine};
+
+    /// The number of times the loop of a test program runs, enough for its blocks to be
+    /// translated and run a few times.
+    const ITERATIONS: i32 = 3 * HOT as i32;
+    /// Where the test programs keep their data, right before a page boundary.
+    const DATA: u64 = MEMORY_BASE + 0x1ff8;
+
+    fn r(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+        funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+    }
+
+    fn i(imm: i32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+        (imm as u32) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+    }
+
+    fn s(imm: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+        let imm = imm as u32;
+        (imm >> 5 & 0x7f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1f) << 7 | 0x23
+    }
+
+    fn b(imm: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+        let imm = imm as u32;
+        (imm >> 12 & 1) << 31
+            | (imm >> 5 & 0x3f) << 25
+            | rs2 << 20
+            | rs1 << 15
+            | funct3 << 12
+            | (imm >> 1 & 0xf) << 8
+            | (imm >> 11 & 1) << 7
+            | 0x63
+    }
+
+    fn lui(rd: u32, imm: u32) -> u32 {
+        imm << 12 | rd << 7 | 0x37
+    }
+
+    fn addi(rd: u32, rs1: u32, imm: i32) -> u32 {
+        i(imm, rs1, 0, rd, 0x13)
+    }
+
+    /// Put `DATA` in `rd`.
+    fn data(rd: u32) -> Vec<u32> {
+        vec![
+            addi(rd, 0, 1),
+            i(31, rd, 1, rd, 0x13),
+            lui(30, 2),
+            r(0, 30, rd, 0, rd, 0x33),
+            addi(rd, rd, -8),
+        ]
+    }
+
+    /// Run `setup`, then `body` `ITERATIONS` times with x31 counting down, then spin.
+    fn program(setup: &[u32], body: &[u32]) -> Vec<u8> {
+        let mut words = setup.to_vec();
+        words.push(addi(31, 0, ITERATIONS));
+        words.extend_from_slice(body);
+        words.push(addi(31, 31, -1));
+        words.push(b(-4 * (body.len() as i32 + 1), 0, 31, 1));
+        // j .
+        words.push(0x6f);
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    /// Run `program` for `count` instructions with each engine, and check that they stop the
+    /// same way with the same registers, pc, instruction count and data. Return the exit.
+    fn differential(program: &[u8], count: u64) -> Exit {
+        let run = |engine| {
+            let mut machine = Machine::builder()
+                .kernel(program.to_vec())
+                .engine(engine)
+                .build()
+                .unwrap();
+            let exit = machine.run_for(count);
+            let mut data = [0; 32];
+            machine.read_memory(DATA - 8, &mut data).unwrap();
+            (
+                exit,
+                machine.pc(),
+                machine.icount(),
+                machine.cpu().regs,
+                data,
+            )
+        };
+        let expected = run(Engine::Interpreter);
+        for engine in [Engine::Jit, Engine::Compare] {
+            let (exit, pc, icount, regs, data) = run(engine);
+            assert_eq!(exit, expected.0, "{}", engine);
+            assert_eq!(pc, expected.1, "{}", engine);
+            assert_eq!(icount, expected.2, "{}", engine);
+            assert_eq!(regs, expected.3, "{}", engine);
+            assert_eq!(data, expected.4, "{}", engine);
+        }
+        expected.0
+    }
+
+    #[test]
+    fn alu() {
+        if !SUPPORTED {
+            return;
+        }
+        let setup = [
+            lui(10, 0x12345),
+            addi(10, 10, 0x678),
+            lui(11, 0xfedcb),
+            addi(11, 11, -0x123),
+            addi(12, 0, -7),
+        ];
+        let body = [
+            r(0x00, 11, 10, 0, 13, 0x33), // add x13, x10, x11
+            r(0x20, 12, 13, 0, 14, 0x33), // sub x14, x13, x12
+            r(0x00, 10, 14, 4, 15, 0x33), // xor x15, x14, x10
+            r(0x00, 11, 15, 6, 16, 0x33), // or x16, x15, x11
+            r(0x00, 13, 16, 7, 17, 0x33), // and x17, x16, x13
+            r(0x00, 10, 11, 2, 18, 0x33), // slt x18, x11, x10
+            r(0x00, 10, 11, 3, 19, 0x33), // sltu x19, x11, x10
+            i(-8, 12, 2, 20, 0x13),       // slti x20, x12, -8
+            i(-1, 12, 3, 21, 0x13),       // sltiu x21, x12, -1
+            i(-1, 17, 4, 22, 0x13),       // xori x22, x17, -1
+            i(0x7ff, 22, 6, 23, 0x13),    // ori x23, x22, 0x7ff
+            i(-0x800, 23, 7, 24, 0x13),   // andi x24, x23, -0x800
+            r(0x01, 14, 13, 0, 25, 0x33), // mul x25, x13, x14
+            0x00001d17,                   // auipc x26, 1
+            lui(27, 0x80000),             // lui x27, 0x80000
+            r(0x00, 25, 10, 0, 10, 0x33), // add x10, x10, x25
+            addi(11, 11, -1),             // addi x11, x11, -1
+            r(0x00, 11, 10, 0, 0, 0x33),  // add x0, x10, x11
+        ];
+        let exit = differential(&program(&setup, &body), 2000);
+        assert_eq!(exit, Exit::InstructionLimit);
+    }
+
+    #[test]
+    fn shifts() {
+        if !SUPPORTED {
+            return;
+        }
+        let setup = [addi(10, 0, -0x1234), addi(11, 0, 67), lui(12, 0x80000)];
+        let body = [
+            r(0x00, 11, 10, 1, 13, 0x33),   // sll x13, x10, x11
+            r(0x00, 11, 10, 5, 14, 0x33),   // srl x14, x10, x11
+            r(0x20, 11, 10, 5, 15, 0x33),   // sra x15, x10, x11
+            i(63, 10, 1, 16, 0x13),         // slli x16, x10, 63
+            i(0, 10, 5, 17, 0x13),          // srli x17, x10, 0
+            i(0x400 | 33, 12, 5, 18, 0x13), // srai x18, x12, 33
+            i(63, 12, 5, 19, 0x13),         // srli x19, x12, 63
+            r(0x00, 31, 11, 0, 11, 0x33),   // add x11, x11, x31
+        ];
+        let exit = differential(&program(&setup, &body), 2000);
+        assert_eq!(exit, Exit::InstructionLimit);
+    }
+
+    #[test]
+    fn word_operations() {
+        if !SUPPORTED {
+            return;
+        }
+        let setup = [
+            lui(10, 0x7ffff),
+            addi(10, 10, 0x7ff),
+            addi(11, 0, 1),
+            lui(12, 0x80000),
+        ];
+        let body = [
+            r(0x00, 10, 10, 0, 13, 0x3b),   // addw x13, x10, x10
+            r(0x20, 10, 12, 0, 14, 0x3b),   // subw x14, x12, x10
+            r(0x00, 11, 10, 1, 15, 0x3b),   // sllw x15, x10, x11
+            r(0x00, 11, 12, 5, 16, 0x3b),   // srlw x16, x12, x11
+            r(0x20, 11, 12, 5, 17, 0x3b),   // sraw x17, x12, x11
+            i(0x7ff, 10, 0, 18, 0x1b),      // addiw x18, x10, 0x7ff
+            i(31, 10, 1, 19, 0x1b),         // slliw x19, x10, 31
+            i(31, 12, 5, 20, 0x1b),         // srliw x20, x12, 31
+            i(0x400 | 31, 12, 5, 21, 0x1b), // sraiw x21, x12, 31
+            addi(11, 11, 13),               // addi x11, x11, 13
+            r(0x00, 13, 10, 0, 10, 0x3b),   // addw x10, x10, x13
+        ];
+        let exit = differential(&program(&setup, &body), 2000);
+        assert_eq!(exit, Exit::InstructionLimit);
+    }
+
+    #[test]
+    fn branches() {
+        if !SUPPORTED {
+            return;
+        }
+        let body = [
+            i(1, 31, 7, 10, 0x13), // andi x10, x31, 1
+            b(8, 0, 10, 0),        // beq x10, x0, 8
+            addi(11, 11, 1),
+            i(10, 31, 2, 12, 0x13), // slti x12, x31, 10
+            b(8, 0, 12, 1),         // bne x12, x0, 8
+            addi(13, 13, 1),
+            addi(14, 31, -15),
+            b(8, 0, 14, 4), // blt x14, x0, 8
+            addi(15, 15, 1),
+            b(8, 0, 14, 5), // bge x14, x0, 8
+            addi(16, 16, 1),
+            b(8, 31, 14, 6), // bltu x14, x31, 8
+            addi(17, 17, 1),
+            b(8, 31, 14, 7), // bgeu x14, x31, 8
+            addi(18, 18, 1),
+            0x008009ef, // jal x19, 8
+            addi(20, 20, 1),
+        ];
+        let exit = differential(&program(&[], &body), 2000);
+        assert_eq!(exit, Exit::InstructionLimit);
+    }
+
+    #[test]
+    fn loads_and_stores_across_a_page_boundary() {
+        if !SUPPORTED {
+            return;
+        }
+        let mut setup = data(10);
+        setup.extend_from_slice(&[lui(12, 0xabcde), addi(12, 12, -0x10)]);
+        let body = [
+            r(0x00, 31, 12, 0, 12, 0x33), // add x12, x12, x31
+            s(0, 12, 10, 3),              // sd x12, 0(x10)
+            s(8, 12, 10, 3),              // sd x12, 8(x10), on the next page
+            s(12, 12, 10, 2),             // sw x12, 12(x10)
+            s(6, 12, 10, 1),              // sh x12, 6(x10)
+            s(7, 31, 10, 0),              // sb x31, 7(x10)
+            i(0, 10, 3, 13, 0x03),        // ld x13, 0(x10)
+            i(8, 10, 3, 14, 0x03),        // ld x14, 8(x10)
+            i(4, 10, 2, 15, 0x03),        // lw x15, 4(x10)
+            i(12, 10, 6, 16, 0x03),       // lwu x16, 12(x10)
+            i(6, 10, 1, 17, 0x03),        // lh x17, 6(x10)
+            i(6, 10, 5, 18, 0x03),        // lhu x18, 6(x10)
+            i(7, 10, 0, 19, 0x03),        // lb x19, 7(x10)
+            i(8, 10, 4, 20, 0x03),        // lbu x20, 8(x10)
+            i(4, 10, 3, 21, 0x03),        // ld x21, 4(x10), across the boundary
+            s(2, 13, 10, 3),              // sd x13, 2(x10), across the boundary
+            r(0x00, 13, 22, 0, 22, 0x33), // add x22, x22, x13
+        ];
+        let exit = differential(&program(&setup, &body), 2000);
+        assert_eq!(exit, Exit::InstructionLimit);
+    }
+
+    #[test]
+    fn faulting_load() {
+        if !SUPPORTED {
+            return;
+        }
+        // The load reads DATA, except in the 5th iteration from the end where it reads 0.
+        let body = [
+            addi(20, 31, -5),
+            i(1, 20, 3, 20, 0x13),        // sltiu x20, x20, 1
+            addi(20, 20, -1),             // x20 = 0 in the 5th iteration, all ones otherwise
+            r(0x00, 10, 20, 7, 20, 0x33), // and x20, x20, x10
+            i(0, 20, 3, 21, 0x03),        // ld x21, 0(x20)
+            r(0x00, 21, 22, 0, 22, 0x33), // add x22, x22, x21
+        ];
+        let exit = differential(&program(&data(10), &body), 2000);
+        assert_eq!(
+            exit,
+            Exit::Fault {
+                exception: Exception::LoadAccessFault,
+                pc: MEMORY_BASE + 4 * (data(10).len() as u64 + 1 + 4)
+            }
+        );
+    }
+}
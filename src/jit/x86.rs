@@ -0,0 +1,377 @@
+//! The x86 module encodes the few x86-64 instructions that translated blocks are made of.
+//! See the Intel 64 and IA-32 Architectures Software Developer's Manual, Volume 2.
+
+pub const RAX: u8 = 0;
+pub const RCX: u8 = 1;
+pub const RDX: u8 = 2;
+pub const RBX: u8 = 3;
+pub const RSI: u8 = 6;
+pub const RDI: u8 = 7;
+pub const R8: u8 = 8;
+pub const R9: u8 = 9;
+pub const R12: u8 = 12;
+pub const R13: u8 = 13;
+
+/// Arithmetic and logic operations, by the value of the reg field of their immediate forms.
+#[derive(Clone, Copy)]
+pub enum Alu {
+    Add = 0,
+    Or = 1,
+    And = 4,
+    Sub = 5,
+    Xor = 6,
+    Cmp = 7,
+}
+
+/// Shifts, by the value of their reg field.
+#[derive(Clone, Copy)]
+pub enum Shift {
+    Shl = 4,
+    Shr = 5,
+    Sar = 7,
+}
+
+/// Condition codes of jcc and setcc.
+#[derive(Clone, Copy)]
+pub enum Cond {
+    /// Below, unsigned.
+    B = 0x2,
+    /// Above or equal, unsigned.
+    Ae = 0x3,
+    E = 0x4,
+    Ne = 0x5,
+    /// Less, signed.
+    L = 0xc,
+    /// Greater or equal, signed.
+    Ge = 0xd,
+}
+
+/// The position of a rel32 operand that is patched once its target is known.
+#[derive(Clone, Copy)]
+pub struct Fixup(usize);
+
+/// Machine code under construction.
+#[derive(Default)]
+pub struct Assembler {
+    pub code: Vec<u8>,
+}
+
+impl Assembler {
+    /// Return the offset of the next instruction.
+    pub fn here(&self) -> usize {
+        self.code.len()
+    }
+
+    fn byte(&mut self, byte: u8) {
+        self.code.push(byte);
+    }
+
+    fn imm32(&mut self, value: i32) {
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Emit a REX prefix if one is needed for a 64-bit operand size or an extended register.
+    fn rex(&mut self, wide: bool, reg: u8, rm: u8) {
+        let rex = 0x40 | (wide as u8) << 3 | (reg >> 3) << 2 | (rm >> 3);
+        if rex != 0x40 {
+            self.byte(rex);
+        }
+    }
+
+    /// Emit a ModRM byte that selects the register `rm`.
+    fn direct(&mut self, reg: u8, rm: u8) {
+        self.byte(0xc0 | (reg & 7) << 3 | (rm & 7));
+    }
+
+    /// Emit a ModRM byte and a displacement that select the memory at `base + disp`. `base`
+    /// can't be rsp or r12, which would need a SIB byte.
+    fn indirect(&mut self, reg: u8, base: u8, disp: i32) {
+        debug_assert!(base & 7 != 4);
+        if disp == 0 && base & 7 != 5 {
+            self.byte((reg & 7) << 3 | (base & 7));
+        } else if disp as i8 as i32 == disp {
+            self.byte(0x40 | (reg & 7) << 3 | (base & 7));
+            self.byte(disp as u8);
+        } else {
+            self.byte(0x80 | (reg & 7) << 3 | (base & 7));
+            self.imm32(disp);
+        }
+    }
+
+    /// mov dst, [base + disp]
+    pub fn load(&mut self, dst: u8, base: u8, disp: i32) {
+        self.rex(true, dst, base);
+        self.byte(0x8b);
+        self.indirect(dst, base, disp);
+    }
+
+    /// mov [base + disp], src
+    pub fn store(&mut self, base: u8, disp: i32, src: u8) {
+        self.rex(true, src, base);
+        self.byte(0x89);
+        self.indirect(src, base, disp);
+    }
+
+    /// mov qword [base + disp], imm, with `imm` sign-extended.
+    pub fn store_imm(&mut self, base: u8, disp: i32, imm: i32) {
+        self.rex(true, 0, base);
+        self.byte(0xc7);
+        self.indirect(0, base, disp);
+        self.imm32(imm);
+    }
+
+    /// mov dst, src
+    pub fn mov(&mut self, dst: u8, src: u8) {
+        self.rex(true, src, dst);
+        self.byte(0x89);
+        self.direct(src, dst);
+    }
+
+    /// Load `imm` into `dst` with the shortest encoding.
+    pub fn mov_imm(&mut self, dst: u8, imm: u64) {
+        if imm <= u32::MAX as u64 {
+            // Writing a 32-bit register clears the upper half.
+            self.rex(false, 0, dst);
+            self.byte(0xb8 | (dst & 7));
+            self.imm32(imm as u32 as i32);
+        } else if imm as i64 as i32 as i64 == imm as i64 {
+            self.rex(true, 0, dst);
+            self.byte(0xc7);
+            self.direct(0, dst);
+            self.imm32(imm as i32);
+        } else {
+            self.rex(true, 0, dst);
+            self.byte(0xb8 | (dst & 7));
+            self.code.extend_from_slice(&imm.to_le_bytes());
+        }
+    }
+
+    /// `op dst, src` on 64-bit registers, or on their lower halves if `wide` is false.
+    pub fn alu(&mut self, op: Alu, wide: bool, dst: u8, src: u8) {
+        self.rex(wide, src, dst);
+        self.byte((op as u8) << 3 | 1);
+        self.direct(src, dst);
+    }
+
+    /// `op dst, imm` with `imm` sign-extended, or on the lower half if `wide` is false.
+    pub fn alu_imm(&mut self, op: Alu, wide: bool, dst: u8, imm: i32) {
+        self.rex(wide, 0, dst);
+        if imm as i8 as i32 == imm {
+            self.byte(0x83);
+            self.direct(op as u8, dst);
+            self.byte(imm as u8);
+        } else {
+            self.byte(0x81);
+            self.direct(op as u8, dst);
+            self.imm32(imm);
+        }
+    }
+
+    /// Shift `dst` by `amount` bits.
+    pub fn shift_imm(&mut self, op: Shift, wide: bool, dst: u8, amount: u8) {
+        self.rex(wide, 0, dst);
+        self.byte(0xc1);
+        self.direct(op as u8, dst);
+        self.byte(amount);
+    }
+
+    /// Shift `dst` by cl, which the processor masks to the operand size like RISC-V does.
+    pub fn shift_cl(&mut self, op: Shift, wide: bool, dst: u8) {
+        self.rex(wide, 0, dst);
+        self.byte(0xd3);
+        self.direct(op as u8, dst);
+    }
+
+    /// movsxd dst, src: sign-extend the lower half of `src`.
+    pub fn movsxd(&mut self, dst: u8, src: u8) {
+        self.rex(true, dst, src);
+        self.byte(0x63);
+        self.direct(dst, src);
+    }
+
+    /// Set rax to 1 if `cond` holds and to 0 otherwise.
+    pub fn set_rax(&mut self, cond: Cond) {
+        // setcc al; movzx eax, al
+        self.code
+            .extend_from_slice(&[0x0f, 0x90 | cond as u8, 0xc0, 0x0f, 0xb6, 0xc0]);
+    }
+
+    /// imul dst, src
+    pub fn imul(&mut self, dst: u8, src: u8) {
+        self.rex(true, dst, src);
+        self.code.extend_from_slice(&[0x0f, 0xaf]);
+        self.direct(dst, src);
+    }
+
+    /// test a, b
+    pub fn test(&mut self, a: u8, b: u8) {
+        self.rex(true, b, a);
+        self.byte(0x85);
+        self.direct(b, a);
+    }
+
+    /// call the address in `target`.
+    pub fn call(&mut self, target: u8) {
+        self.rex(false, 0, target);
+        self.byte(0xff);
+        self.direct(2, target);
+    }
+
+    pub fn push(&mut self, reg: u8) {
+        self.rex(false, 0, reg);
+        self.byte(0x50 | (reg & 7));
+    }
+
+    pub fn pop(&mut self, reg: u8) {
+        self.rex(false, 0, reg);
+        self.byte(0x58 | (reg & 7));
+    }
+
+    pub fn ret(&mut self) {
+        self.byte(0xc3);
+    }
+
+    pub fn mfence(&mut self) {
+        self.code.extend_from_slice(&[0x0f, 0xae, 0xf0]);
+    }
+
+    /// Jump if `cond` holds, to where the returned fixup is bound.
+    pub fn jcc(&mut self, cond: Cond) -> Fixup {
+        self.code.extend_from_slice(&[0x0f, 0x80 | cond as u8]);
+        self.imm32(0);
+        Fixup(self.here() - 4)
+    }
+
+    /// Jump to where the returned fixup is bound.
+    pub fn jmp(&mut self) -> Fixup {
+        self.byte(0xe9);
+        self.imm32(0);
+        Fixup(self.here() - 4)
+    }
+
+    /// Make the jump of `fixup` go to the offset `target`.
+    pub fn bind(&mut self, fixup: Fixup, target: usize) {
+        let rel = target as i64 - (fixup.0 as i64 + 4);
+        self.code[fixup.0..fixup.0 + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RBP: u8 = 5;
+
+    fn encode(f: impl FnOnce(&mut Assembler)) -> Vec<u8> {
+        let mut asm = Assembler::default();
+        f(&mut asm);
+        asm.code
+    }
+
+    #[test]
+    fn mov_imm_takes_the_shortest_form() {
+        // mov r32, imm32, which clears the upper half.
+        assert_eq!(
+            encode(|a| a.mov_imm(RAX, 0x12345678)),
+            [0xb8, 0x78, 0x56, 0x34, 0x12]
+        );
+        assert_eq!(
+            encode(|a| a.mov_imm(R9, 1)),
+            [0x41, 0xb9, 0x01, 0x00, 0x00, 0x00]
+        );
+        // mov r/m64, imm32, sign-extended.
+        assert_eq!(
+            encode(|a| a.mov_imm(RAX, -16i64 as u64)),
+            [0x48, 0xc7, 0xc0, 0xf0, 0xff, 0xff, 0xff]
+        );
+        // mov r64, imm64.
+        assert_eq!(
+            encode(|a| a.mov_imm(RAX, 1 << 32)),
+            [0x48, 0xb8, 0, 0, 0, 0, 1, 0, 0, 0]
+        );
+        assert_eq!(
+            encode(|a| a.mov_imm(R8, 0x1122334455667788)),
+            [0x49, 0xb8, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]
+        );
+    }
+
+    #[test]
+    fn indirect_takes_the_shortest_displacement() {
+        assert_eq!(encode(|a| a.load(RAX, RBX, 0)), [0x48, 0x8b, 0x03]);
+        assert_eq!(encode(|a| a.load(RAX, RBX, 8)), [0x48, 0x8b, 0x43, 0x08]);
+        assert_eq!(encode(|a| a.load(RAX, RBX, -8)), [0x48, 0x8b, 0x43, 0xf8]);
+        assert_eq!(
+            encode(|a| a.load(RAX, RBX, 31 * 8)),
+            [0x48, 0x8b, 0x83, 0xf8, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            encode(|a| a.store(RBX, 0x12345, R9)),
+            [0x4c, 0x89, 0x8b, 0x45, 0x23, 0x01, 0x00]
+        );
+    }
+
+    #[test]
+    fn indirect_through_rbp_or_r13_has_a_displacement() {
+        // Without a displacement, their encoding means rip-relative.
+        assert_eq!(encode(|a| a.load(RAX, RBP, 0)), [0x48, 0x8b, 0x45, 0x00]);
+        assert_eq!(encode(|a| a.load(RCX, R13, 0)), [0x49, 0x8b, 0x4d, 0x00]);
+        assert_eq!(
+            encode(|a| a.store_imm(R13, 16, -1)),
+            [0x49, 0xc7, 0x45, 0x10, 0xff, 0xff, 0xff, 0xff]
+        );
+    }
+
+    #[test]
+    fn operations_on_registers() {
+        assert_eq!(encode(|a| a.alu(Alu::Xor, false, RAX, RAX)), [0x31, 0xc0]);
+        assert_eq!(
+            encode(|a| a.alu(Alu::Sub, true, R8, RCX)),
+            [0x49, 0x29, 0xc8]
+        );
+        assert_eq!(
+            encode(|a| a.alu_imm(Alu::Add, true, RSI, 8)),
+            [0x48, 0x83, 0xc6, 0x08]
+        );
+        assert_eq!(
+            encode(|a| a.alu_imm(Alu::Cmp, true, RAX, 1000)),
+            [0x48, 0x81, 0xf8, 0xe8, 0x03, 0x00, 0x00]
+        );
+        assert_eq!(
+            encode(|a| a.shift_imm(Shift::Sar, false, RAX, 3)),
+            [0xc1, 0xf8, 0x03]
+        );
+        assert_eq!(
+            encode(|a| a.shift_cl(Shift::Shl, true, RAX)),
+            [0x48, 0xd3, 0xe0]
+        );
+        assert_eq!(encode(|a| a.movsxd(RAX, RAX)), [0x48, 0x63, 0xc0]);
+        assert_eq!(encode(|a| a.imul(RAX, RCX)), [0x48, 0x0f, 0xaf, 0xc1]);
+        assert_eq!(encode(|a| a.mov(RDI, R12)), [0x4c, 0x89, 0xe7]);
+        assert_eq!(
+            encode(|a| a.set_rax(Cond::L)),
+            [0x0f, 0x9c, 0xc0, 0x0f, 0xb6, 0xc0]
+        );
+        assert_eq!(encode(|a| a.call(RAX)), [0xff, 0xd0]);
+        assert_eq!(encode(|a| a.push(R12)), [0x41, 0x54]);
+        assert_eq!(encode(|a| a.pop(RBX)), [0x5b]);
+    }
+
+    #[test]
+    fn jumps_are_bound_relative_to_their_end() {
+        let code = encode(|a| {
+            let forward = a.jcc(Cond::E);
+            a.ret();
+            let here = a.here();
+            a.bind(forward, here);
+        });
+        assert_eq!(code, [0x0f, 0x84, 0x01, 0x00, 0x00, 0x00, 0xc3]);
+
+        let code = encode(|a| {
+            let top = a.here();
+            a.ret();
+            let backward = a.jmp();
+            a.bind(backward, top);
+        });
+        assert_eq!(code, [0xc3, 0xe9, 0xfa, 0xff, 0xff, 0xff]);
+    }
+}
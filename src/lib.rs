@@ -0,0 +1,33 @@
+//! Honga is a 64-bit RISC-V emulator that can run Unix-like OSes like xv6. The library lets the
+//! emulator be embedded e.g. in test harnesses; start with `Machine`.
+
+pub mod bitmanip;
+pub mod boot;
+pub mod bus;
+pub mod config;
+pub mod cpu;
+pub mod crypto;
+pub mod csr;
+pub mod decode;
+pub mod disasm;
+pub mod exception;
+pub mod float;
+pub mod fpu;
+pub mod gdb;
+pub mod hypervisor;
+pub mod interrupt;
+pub mod isa;
+pub mod jit;
+pub mod lockstep;
+pub mod machine;
+pub mod replay;
+pub mod reverse;
+pub mod snapshot;
+#[cfg(test)]
+mod testing;
+mod toml;
+pub mod trace;
+pub mod vector;
+
+pub use config::Config;
+pub use machine::{Exit, Machine, MachineBuilder};
@@ -0,0 +1,571 @@
+//! The lockstep module checks honga against a reference execution trace, such as a commit log
+//! written by Spike with `--log-commits` or by honga itself. After every retired instruction the
+//! architectural state of the `Cpu` is compared with the state described by the trace, and the
+//! first difference stops the emulator with a report.
+//!
+//! The trace only tells which registers and CSRs an instruction wrote, so the checker keeps a
+//! shadow copy of every value the trace has mentioned so far. Registers that the trace has never
+//! written are not compared, which lets both sides start from a different reset state.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use crate::cpu::Cpu;
+use crate::csr::csr_name;
+use crate::disasm::{disassemble, ABI};
+use crate::trace::{format_commit, Commit};
+
+/// The number of reference lines shown before a mismatch.
+const CONTEXT_LINES: usize = 8;
+
+/// One retired instruction described by a trace line.
+#[derive(Debug, Default, Clone)]
+pub struct Record {
+    /// The line number in the trace, starting at 1.
+    pub line: usize,
+    pub hart: u64,
+    pub mode: Option<u8>,
+    pub pc: u64,
+    pub inst: u32,
+    pub commit: Commit,
+}
+
+fn parse_hex(token: &str) -> Option<u64> {
+    let digits = token.strip_prefix("0x")?;
+    u64::from_str_radix(digits, 16).ok()
+}
+
+/// Parse one commit-log line. Return `None` for lines that do not describe a retired
+/// instruction, e.g. the exception messages that Spike prints with `-l`.
+pub fn parse_line(text: &str, line: usize) -> Option<Record> {
+    // Drop the disassembly that honga appends to its own log.
+    let text = match text.find(" ; ") {
+        Some(i) => &text[..i],
+        None => text,
+    };
+    let mut tokens = text.split_whitespace().peekable();
+    if tokens.next()? != "core" {
+        return None;
+    }
+    let hart = tokens.next()?.trim_end_matches(':').parse().ok()?;
+
+    // The privilege mode is optional in older Spike versions.
+    let mut mode = None;
+    while !tokens.peek()?.starts_with("0x") {
+        let field = tokens.next()?.parse().ok()?;
+        mode.get_or_insert(field);
+    }
+    let pc = parse_hex(tokens.next()?)?;
+    let inst = tokens.next()?;
+    let inst = parse_hex(inst.strip_prefix('(')?.strip_suffix(')')?)? as u32;
+
+    let mut commit = Commit::default();
+    while let Some(token) = tokens.next() {
+        if token == "mem" {
+            let addr = parse_hex(tokens.next()?)?;
+            match tokens.peek() {
+                Some(value) if value.starts_with("0x") => {
+                    let bits = (value.len() - 2) * 4;
+                    let value = parse_hex(tokens.next()?)?;
+                    commit.write_mem(addr, value, bits);
+                }
+                _ => commit.loads.push((addr, 0)),
+            }
+        } else if let Some(reg) = token.strip_prefix('x') {
+            let value = parse_hex(tokens.next()?)?;
+            commit.write_reg(reg.parse().ok()?, value);
+        } else if let Some(csr) = token.strip_prefix('c') {
+            let addr = csr.split('_').next()?.parse().ok()?;
+            let value = parse_hex(tokens.next()?)?;
+            commit.write_csr(addr, value);
+        } else {
+            // Floating-point and vector registers are not modelled. Skip their value.
+            tokens.next();
+        }
+    }
+
+    Some(Record {
+        line,
+        hart,
+        mode,
+        pc,
+        inst,
+        commit,
+    })
+}
+
+/// A difference between honga and the reference trace.
+#[derive(Debug)]
+pub struct Mismatch {
+    /// A short description of each difference.
+    pub reasons: Vec<String>,
+    /// The offending reference record.
+    pub expected: Record,
+    /// The commit-log line honga produced for the instruction.
+    pub actual: String,
+    /// The instruction honga executed.
+    pub inst: u32,
+    /// Registers as (index, honga's value, the reference value if known).
+    pub regs: Vec<(usize, u64, Option<u64>)>,
+    /// CSRs written by the instruction as (address, honga's value, the reference value).
+    pub csrs: Vec<(usize, u64, u64)>,
+    /// Reference lines that led up to the mismatch.
+    pub context: Vec<String>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "lockstep mismatch:")?;
+        for reason in self.reasons.iter() {
+            writeln!(f, "  {}", reason)?;
+        }
+        writeln!(f, "previous reference lines:")?;
+        for line in self.context.iter() {
+            writeln!(f, "    {}", line)?;
+        }
+        writeln!(
+            f,
+            "expected (line {}):\n  - {}",
+            self.expected.line,
+            format_record(&self.expected)
+        )?;
+        writeln!(
+            f,
+            "actual:\n  + {} ; {}",
+            self.actual,
+            disassemble(self.inst)
+        )?;
+
+        writeln!(f, "registers (honga / reference):")?;
+        for (i, actual, expected) in self.regs.iter() {
+            match expected {
+                Some(expected) if expected != actual => writeln!(
+                    f,
+                    "! x{:<2}({:>4}) {:#018x} / {:#018x}",
+                    i, ABI[*i], actual, expected
+                )?,
+                Some(_) => writeln!(f, "  x{:<2}({:>4}) {:#018x}", i, ABI[*i], actual)?,
+                None => writeln!(f, "  x{:<2}({:>4}) {:#018x} / ?", i, ABI[*i], actual)?,
+            }
+        }
+        if !self.csrs.is_empty() {
+            writeln!(f, "csrs (honga / reference):")?;
+        }
+        for (addr, actual, expected) in self.csrs.iter() {
+            let marker = if actual != expected { '!' } else { ' ' };
+            writeln!(
+                f,
+                "{} {:<10} {:#018x} / {:#018x}",
+                marker,
+                csr_name(*addr),
+                actual,
+                expected
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn format_record(record: &Record) -> String {
+    let mut text = format!(
+        "core{:4}: {} {:#018x} ({:#010x})",
+        record.hart,
+        record.mode.map_or("?".to_string(), |m| m.to_string()),
+        record.pc,
+        record.inst
+    );
+    for (key, value) in record.commit.writes.iter() {
+        match key & 0xf {
+            0 => text.push_str(&format!(" x{:<2} {:#018x}", key >> 4, value)),
+            _ => text.push_str(&format!(
+                " c{}_{} {:#018x}",
+                key >> 4,
+                csr_name((key >> 4) as usize),
+                value
+            )),
+        }
+    }
+    for (addr, _) in record.commit.loads.iter() {
+        text.push_str(&format!(" mem {:#018x}", addr));
+    }
+    for (addr, value, _) in record.commit.stores.iter() {
+        text.push_str(&format!(" mem {:#018x} {:#x}", addr, value));
+    }
+    text
+}
+
+/// A checker that runs honga in lockstep with a reference trace.
+pub struct Lockstep {
+    lines: io::Lines<BufReader<File>>,
+    line: usize,
+    /// The next record, read ahead while synchronizing with the start of the trace.
+    pending: Option<Record>,
+    /// Register values of every hart from the trace; `None` if the trace hasn't written the
+    /// register yet.
+    regs: Vec<[Option<u64>; 32]>,
+    context: VecDeque<String>,
+    /// The number of instructions checked so far.
+    pub checked: u64,
+    /// True once an instruction retired after the end of the trace.
+    pub ended: bool,
+}
+
+impl Lockstep {
+    /// Open the reference trace at `path`.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            lines: BufReader::new(File::open(path)?).lines(),
+            line: 0,
+            pending: None,
+            regs: Vec::new(),
+            context: VecDeque::new(),
+            checked: 0,
+            ended: false,
+        })
+    }
+
+    /// Read the next retired instruction from the trace.
+    fn next_record(&mut self) -> io::Result<Option<Record>> {
+        if let Some(record) = self.pending.take() {
+            return Ok(Some(record));
+        }
+        for text in &mut self.lines {
+            let text = text?;
+            self.line += 1;
+            if let Some(record) = parse_line(&text, self.line) {
+                if self.context.len() == CONTEXT_LINES {
+                    self.context.pop_front();
+                }
+                self.context.push_back(text);
+                return Ok(Some(record));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Skip the part of the trace before `pc`, e.g. the boot ROM that Spike runs before jumping
+    /// to the kernel. Return the number of records skipped.
+    pub fn synchronize(&mut self, pc: u64) -> io::Result<u64> {
+        let mut skipped = 0;
+        while let Some(record) = self.next_record()? {
+            if record.pc == pc {
+                self.pending = Some(record);
+                break;
+            }
+            // Values written before the start are still the reference state.
+            self.apply(&record);
+            skipped += 1;
+        }
+        // Only the line at the start, if there's one, leads up to what's checked.
+        let before = match self.pending {
+            Some(_) => self.context.len().saturating_sub(1),
+            None => self.context.len(),
+        };
+        self.context.drain(..before);
+        Ok(skipped)
+    }
+
+    fn apply(&mut self, record: &Record) {
+        let hart = record.hart as usize;
+        if self.regs.len() <= hart {
+            self.regs.resize(hart + 1, [None; 32]);
+        }
+        for (key, value) in record.commit.writes.iter() {
+            if key & 0xf == 0 {
+                self.regs[hart][(key >> 4) as usize] = Some(*value);
+            }
+        }
+    }
+
+    /// Compare the instruction `inst` at `pc` that has just retired on `cpu` with the next
+    /// record of the trace.
+    pub fn check(&mut self, cpu: &Cpu, pc: u64, inst: u32) -> io::Result<Result<(), Mismatch>> {
+        let commit = match &cpu.commit {
+            Some(commit) => commit,
+            None => return Ok(Ok(())),
+        };
+        // Every instruction of the trace matched if it ends here.
+        let expected = match self.next_record()? {
+            Some(record) => record,
+            None => {
+                self.ended = true;
+                return Ok(Ok(()));
+            }
+        };
+        let mut reasons = Vec::new();
+        if expected.hart != cpu.hart() as u64 {
+            reasons.push(format!("hart: {} != {}", cpu.hart(), expected.hart));
+        }
+        if expected.pc != pc {
+            reasons.push(format!("pc: {:#x} != {:#x}", pc, expected.pc));
+        }
+        if expected.inst != inst {
+            reasons.push(format!(
+                "instruction: {:#010x} != {:#010x}",
+                inst, expected.inst
+            ));
+        }
+        if let Some(mode) = expected.mode {
+            if mode != cpu.mode as u8 {
+                reasons.push(format!("privilege mode: {} != {}", cpu.mode as u8, mode));
+            }
+        }
+        self.apply(&expected);
+
+        let loads: Vec<u64> = commit.loads.iter().map(|(addr, _)| *addr).collect();
+        let expected_loads: Vec<u64> = expected
+            .commit
+            .loads
+            .iter()
+            .map(|(addr, _)| *addr)
+            .collect();
+        if loads != expected_loads {
+            reasons.push(format!(
+                "memory reads: {:x?} != {:x?}",
+                loads, expected_loads
+            ));
+        }
+        let stores: Vec<(u64, u64)> = commit.stores.iter().map(|s| (s.0, s.1)).collect();
+        let expected_stores: Vec<(u64, u64)> =
+            expected.commit.stores.iter().map(|s| (s.0, s.1)).collect();
+        if stores != expected_stores {
+            reasons.push(format!(
+                "memory writes: {:x?} != {:x?}",
+                stores, expected_stores
+            ));
+        }
+
+        let regs = self.regs.get(cpu.hart()).copied().unwrap_or([None; 32]);
+        for (i, expected) in regs.iter().enumerate() {
+            if let Some(expected) = expected {
+                if cpu.reg(i) != *expected {
+                    reasons.push(format!("x{}: {:#x} != {:#x}", i, cpu.reg(i), expected));
+                }
+            }
+        }
+        // Many CSRs are views of others or change on their own, so only the CSRs written by
+        // this instruction are compared.
+        let csrs: Vec<(usize, u64, u64)> = expected
+            .commit
+            .writes
+            .iter()
+            .filter(|(key, _)| *key & 0xf != 0)
+            .map(|(key, value)| {
+                let addr = (key >> 4) as usize;
+                (addr, cpu.load_csr(addr), *value)
+            })
+            .collect();
+        for (addr, actual, expected) in csrs.iter() {
+            if actual != expected {
+                reasons.push(format!(
+                    "{}: {:#x} != {:#x}",
+                    csr_name(*addr),
+                    actual,
+                    expected
+                ));
+            }
+        }
+
+        self.checked += 1;
+        if reasons.is_empty() {
+            return Ok(Ok(()));
+        }
+        Ok(Err(Mismatch {
+            reasons,
+            actual: format_commit(cpu, cpu.hart() as u64, pc, inst, commit),
+            inst,
+            regs: (0..32).map(|i| (i, cpu.reg(i), regs[i])).collect(),
+            csrs,
+            context: self
+                .context
+                .iter()
+                .take(self.context.len().saturating_sub(1))
+                .cloned()
+                .collect(),
+            expected,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::MEMORY_BASE;
+    use crate::csr::MSCRATCH;
+    use crate::machine::Machine;
+    use crate::testing::TempDir;
+    use std::fs;
+
+    #[test]
+    fn spike_lines() {
+        let record = parse_line(
+            "core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000",
+            1,
+        )
+        .unwrap();
+        assert_eq!((record.hart, record.mode), (0, Some(3)));
+        assert_eq!((record.pc, record.inst), (0x8000_0000, 0x297));
+        assert_eq!(record.commit.writes.len(), 1);
+        assert_eq!(record.commit.writes.get(&(5 << 4)), Some(&0x8000_0000));
+
+        // A load, with honga's disassembly after it.
+        let record = parse_line(
+            "core   1: 1 0x0000000080000010 (0x0182b283) x5  0x0000000000000007 \
+             mem 0x0000000080000018 ; ld t0, 24(t0)",
+            7,
+        )
+        .unwrap();
+        assert_eq!((record.line, record.hart, record.mode), (7, 1, Some(1)));
+        assert_eq!(record.commit.loads, [(0x8000_0018, 0)]);
+        assert!(record.commit.stores.is_empty());
+
+        // A store of a word, and a CSR write, by an older Spike that leaves out the mode.
+        let record = parse_line(
+            "core   0: 0x0000000080000014 (0x00b2a023) mem 0x0000000080001000 0xdeadbeef",
+            2,
+        )
+        .unwrap();
+        assert_eq!(record.mode, None);
+        assert_eq!(record.commit.stores, [(0x8000_1000, 0xdeadbeef, 32)]);
+        let record = parse_line(
+            "core   0: 3 0x0000000080000004 (0x34029073) c832_mscratch 0x0000000000000001",
+            3,
+        )
+        .unwrap();
+        let mut commit = Commit::default();
+        commit.write_csr(MSCRATCH, 1);
+        assert_eq!(record.commit.writes, commit.writes);
+
+        // Floating-point registers are skipped.
+        let record = parse_line(
+            "core   0: 3 0x0000000080000008 (0xf2000053) f0  0x0000000000000000 \
+             x1  0x0000000000000002",
+            4,
+        )
+        .unwrap();
+        assert_eq!(record.commit.writes.get(&(1 << 4)), Some(&2));
+        assert_eq!(record.commit.writes.len(), 1);
+
+        // Exceptions and other messages aren't instructions.
+        assert!(parse_line(
+            "core   0: exception trap_illegal_instruction, epc 0x0000000080000000",
+            5
+        )
+        .is_none());
+        assert!(parse_line("core   0:           tval 0x0000000000000000", 6).is_none());
+        assert!(parse_line("bbl loader", 7).is_none());
+    }
+
+    /// A checker of the reference `trace`, in a file in `dir`.
+    fn lockstep(dir: &TempDir, trace: &str) -> Lockstep {
+        let path = dir.join("trace.log");
+        fs::write(&path, trace).unwrap();
+        Lockstep::open(&path).unwrap()
+    }
+
+    /// Execute `inst` on the hart of `machine` as if it were at `pc`, and return the mismatch
+    /// if it doesn't check.
+    fn check(
+        machine: &mut Machine,
+        lockstep: &mut Lockstep,
+        pc: u64,
+        inst: u32,
+    ) -> Option<Mismatch> {
+        let cpu = machine.cpu_mut();
+        cpu.commit.as_mut().unwrap().clear();
+        cpu.decode_execute(inst).unwrap();
+        lockstep.check(cpu, pc, inst).unwrap().err()
+    }
+
+    fn machine() -> Machine {
+        Machine::builder().trace(true).build().unwrap()
+    }
+
+    // addi t0, zero, 42
+    const ADDI: u32 = 0x02a00293;
+    // sd t0, 0(t1)
+    const SD: u32 = 0x00533023;
+    // csrw mscratch, t0
+    const CSRW: u32 = 0x34029073;
+
+    #[test]
+    fn registers_memory_and_csrs_are_compared() {
+        let dir = TempDir::new("lockstep-compare");
+        let mut lockstep = lockstep(
+            &dir,
+            "core   0: 3 0x0000000080000000 (0x02a00293) x5  0x000000000000002a\n\
+             core   0: 3 0x0000000080000004 (0x00533023) mem 0x0000000080001000 0x000000000000002a\n\
+             core   0: 3 0x0000000080000008 (0x34029073) c832_mscratch 0x000000000000002a\n",
+        );
+        let mut machine = machine();
+        machine.cpu_mut().regs[6] = MEMORY_BASE + 0x1000;
+        assert!(check(&mut machine, &mut lockstep, MEMORY_BASE, ADDI).is_none());
+        assert!(check(&mut machine, &mut lockstep, MEMORY_BASE + 4, SD).is_none());
+        assert!(check(&mut machine, &mut lockstep, MEMORY_BASE + 8, CSRW).is_none());
+        assert_eq!(lockstep.checked, 3);
+        assert!(!lockstep.ended);
+        // An instruction after the end of the trace ends the run without a mismatch.
+        assert!(check(&mut machine, &mut lockstep, MEMORY_BASE + 12, ADDI).is_none());
+        assert!(lockstep.ended);
+        assert_eq!(lockstep.checked, 3);
+    }
+
+    #[test]
+    fn differences_are_reported() {
+        let dir = TempDir::new("lockstep-differences");
+        let mut lockstep = lockstep(
+            &dir,
+            "core   0: 3 0x0000000080000000 (0x02a00293) x5  0x000000000000002b\n\
+             core   0: 3 0x0000000080000004 (0x00533023) mem 0x0000000080001008 0x000000000000002a\n\
+             core   0: 3 0x0000000080000008 (0x34029073) c832_mscratch 0x0000000000000000\n",
+        );
+        let mut machine = machine();
+        machine.cpu_mut().regs[6] = MEMORY_BASE + 0x1000;
+        let mismatch = check(&mut machine, &mut lockstep, MEMORY_BASE, ADDI).unwrap();
+        assert_eq!(mismatch.reasons, ["x5: 0x2a != 0x2b"]);
+        assert_eq!(mismatch.expected.line, 1);
+        assert_eq!(mismatch.regs[5], (5, 0x2a, Some(0x2b)));
+        assert!(mismatch.to_string().contains("! x5 "));
+
+        // The reference value of x5 stays, so it differs after every instruction.
+        let mismatch = check(&mut machine, &mut lockstep, MEMORY_BASE + 4, SD).unwrap();
+        assert_eq!(
+            mismatch.reasons,
+            [
+                "memory writes: [(80001000, 2a)] != [(80001008, 2a)]",
+                "x5: 0x2a != 0x2b",
+            ]
+        );
+        machine.cpu_mut().regs[5] = 0x2b;
+        let mismatch = check(&mut machine, &mut lockstep, MEMORY_BASE + 8, CSRW).unwrap();
+        assert_eq!(mismatch.reasons, ["mscratch: 0x2b != 0x0"]);
+        assert_eq!(mismatch.csrs, [(MSCRATCH, 0x2b, 0)]);
+    }
+
+    #[test]
+    fn the_start_of_the_trace_is_skipped() {
+        let dir = TempDir::new("lockstep-synchronize");
+        let mut lockstep = lockstep(
+            &dir,
+            "core   0: 3 0x0000000000001000 (0x00000297) x5  0x0000000000001000\n\
+             core   0: exception trap_illegal_instruction, epc 0x0000000000001004\n\
+             core   0: 3 0x0000000000001004 (0x02028593) x11 0x0000000000001020\n\
+             core   0: 3 0x0000000080000000 (0x02a00293) x5  0x000000000000002a\n\
+             core   0: 3 0x0000000080000004 (0x02a00293) x5  0x000000000000002a\n",
+        );
+        assert_eq!(lockstep.synchronize(MEMORY_BASE).unwrap(), 2);
+        let mut machine = machine();
+        machine.cpu_mut().regs[11] = 0x1020;
+        assert!(check(&mut machine, &mut lockstep, MEMORY_BASE, ADDI).is_none());
+        // a1 was written before the start, and is still compared.
+        machine.cpu_mut().regs[11] = 0;
+        let mismatch = check(&mut machine, &mut lockstep, MEMORY_BASE + 4, ADDI).unwrap();
+        assert_eq!(mismatch.reasons, ["x11: 0x0 != 0x1020"]);
+        // Only the lines since the start are context.
+        assert_eq!(mismatch.context.len(), 1);
+        assert!(mismatch.context[0].contains("0x0000000080000000"));
+    }
+}
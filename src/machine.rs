@@ -0,0 +1,866 @@
+//! The machine module is the entry point for embedding honga: a `Machine` is a CPU with its bus
+//! and devices that can be stepped, run for a number of instructions or run until a condition
+//! holds, and inspected in between.
+//!
+//! ```no_run
+//! use honga::{Exit, Machine};
+//!
+//! let kernel = std::fs::read("xv6-kernel.bin").unwrap();
+//! let disk = std::fs::read("xv6-fs.img").unwrap();
+//! let mut machine = Machine::builder().kernel(kernel).disk(disk).build().unwrap();
+//! machine.add_breakpoint(0x8000_0000);
+//! match machine.run_for(1_000_000) {
+//!     Exit::Breakpoint(pc) => println!("breakpoint at {:#x}", pc),
+//!     exit => println!("{}", exit),
+//! }
+//! ```
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use crate::boot::{self, Images};
+use crate::bus::{
+    open_image, open_network, Block, Bus, Clint, Console, Device, DeviceId, Finisher, Memory,
+    MemoryOverlay, Net, Plic, Qcow2, Rng, RngBackend, Storage, Uart, UartBackend, Virtio,
+    VirtioDevice, VirtioTransport, CLINT_BASE, CLINT_SIZE, FINISHER_BASE, FINISHER_SIZE,
+    MEMORY_SIZE, PLIC_BASE, PLIC_SIZE, TIMEBASE_FREQUENCY, UART_BASE, UART_IRQ, UART_SIZE,
+    VIRTIO_BASE, VIRTIO_IRQ, VIRTIO_SIZE, VIRTIO_SLOTS,
+};
+use crate::config::{Config, DiskBackend, Profile};
+use crate::cpu::{Cpu, QUANTUM};
+use crate::exception::Exception;
+use crate::isa::Isa;
+use crate::jit::Engine;
+use crate::replay::EventLog;
+use crate::snapshot::{Reader, Snapshot};
+use crate::trace::Commit;
+
+/// The Ethernet address of the first network card, as in QEMU. The next cards count up.
+const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+/// The seed of the entropy device in runs that record, replay or debug, unless one is given.
+const RNG_SEED: u64 = 0;
+
+/// Why a `Machine` stopped running.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Exit {
+    /// The pc reached a breakpoint. The instruction there hasn't been executed yet.
+    Breakpoint(u64),
+    /// The guest raised an exception that can't be handled, e.g. an access to unmapped memory.
+    Fault { exception: Exception, pc: u64 },
+    /// The guest powered the machine off through the test finisher with an exit status.
+    Poweroff(u32),
+    /// The instruction limit of the machine or of `Machine::run_for` was reached.
+    InstructionLimit,
+    /// The condition passed to `Machine::run_until` holds.
+    Condition,
+    /// The run no longer matches the event log it replays.
+    Diverged(String),
+    /// The JIT and the interpreter executed a block differently with `Engine::Compare`.
+    Mismatch(String),
+}
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Exit::Breakpoint(pc) => write!(f, "breakpoint at {:#x}", pc),
+            Exit::Fault { exception, pc } => {
+                write!(f, "fatal exception {:?} at {:#x}", exception, pc)
+            }
+            Exit::Poweroff(status) => write!(f, "powered off with status {}", status),
+            Exit::InstructionLimit => write!(f, "instruction limit reached"),
+            Exit::Condition => write!(f, "condition reached"),
+            Exit::Diverged(divergence) => write!(f, "replay diverged: {}", divergence),
+            Exit::Mismatch(mismatch) => {
+                write!(f, "the JIT differs from the interpreter {}", mismatch)
+            }
+        }
+    }
+}
+
+/// A device added to the default ones: name, base address, size, IRQ and the device.
+type ExtraDevice = (String, u64, u64, Option<u64>, Box<dyn Device>);
+
+/// A builder for a `Machine`.
+pub struct MachineBuilder {
+    profile: Profile,
+    ram: u64,
+    images: Images,
+    /// The devices of the virtio slots, in order.
+    virtio_devices: Vec<Box<dyn VirtioDevice>>,
+    virtio: VirtioTransport,
+    uart: UartBackend,
+    timebase: u64,
+    isa: Isa,
+    harts: usize,
+    quantum: u64,
+    engine: Engine,
+    devices: Vec<ExtraDevice>,
+    snapshot: Option<Vec<u8>>,
+    events: Option<EventLog>,
+    trace: bool,
+    instruction_limit: Option<u64>,
+}
+
+impl Default for MachineBuilder {
+    fn default() -> Self {
+        Self {
+            profile: Profile::Virt,
+            ram: MEMORY_SIZE,
+            images: Images::default(),
+            virtio_devices: Vec::new(),
+            virtio: VirtioTransport::Legacy,
+            uart: UartBackend::Stdio,
+            timebase: TIMEBASE_FREQUENCY,
+            isa: Isa::default(),
+            harts: 1,
+            quantum: QUANTUM,
+            engine: Engine::Interpreter,
+            devices: Vec::new(),
+            snapshot: None,
+            events: None,
+            trace: false,
+            instruction_limit: None,
+        }
+    }
+}
+
+/// Read the file at `path`, saying what it was meant to be if that fails.
+fn read(what: &str, path: &Path) -> io::Result<Vec<u8>> {
+    fs::read(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("can't read the {} {}: {}", what, path.display(), e),
+        )
+    })
+}
+
+impl MachineBuilder {
+    /// Set up the machine described by `config`, reading its images and disks and opening its
+    /// networks. The tracing, debugging and replay options are left to the caller.
+    pub fn from_config(config: &Config) -> io::Result<Self> {
+        config.validate()?;
+        let read_optional = |what, path: &Option<PathBuf>| match path {
+            Some(path) => read(what, path).map(Some),
+            None => Ok(None),
+        };
+        let mut builder = Self {
+            profile: config.profile,
+            ram: config.ram,
+            images: Images {
+                kernel: read_optional("kernel", &config.kernel)?.unwrap_or_default(),
+                firmware: read_optional("firmware", &config.firmware)?,
+                initrd: read_optional("initrd", &config.initrd)?,
+                dtb: read_optional("device tree", &config.dtb)?,
+            },
+            virtio: config.virtio,
+            uart: config.uart.clone(),
+            timebase: config.timebase,
+            isa: config
+                .isa
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            harts: config.harts,
+            quantum: config.quantum,
+            engine: config.engine,
+            ..Self::default()
+        };
+        for disk in config.disks.iter() {
+            let disk_error = |what: &Path, e: io::Error| {
+                io::Error::new(
+                    e.kind(),
+                    format!("can't open the disk {}: {}", what.display(), e),
+                )
+            };
+            let open = |read_only| {
+                open_image(&disk.path, read_only).map_err(|e| disk_error(&disk.path, e))
+            };
+            let storage: Box<dyn Storage> = match &disk.backend {
+                // A qcow2 image is read through its tables.
+                DiskBackend::Memory => Box::new(
+                    open(true)?
+                        .contents()
+                        .map_err(|e| disk_error(&disk.path, e))?,
+                ),
+                DiskBackend::File => open(false)?,
+                DiskBackend::ReadOnly => open(true)?,
+                DiskBackend::Snapshot => Box::new(MemoryOverlay::new(open(true)?)),
+                DiskBackend::Overlay(overlay) => {
+                    // Check the base first, so that its errors name it.
+                    open(true)?;
+                    Box::new(
+                        Qcow2::overlay(overlay, &disk.path).map_err(|e| disk_error(overlay, e))?,
+                    )
+                }
+            };
+            builder = builder.disk_storage(storage);
+        }
+        for (index, nic) in config.nics.iter().enumerate() {
+            let network = open_network(&nic.backend, nic.pcap.as_ref()).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("can't open the network card {}: {}", index, e),
+                )
+            })?;
+            let mut mac = DEFAULT_MAC;
+            mac[5] = mac[5].wrapping_add(index as u8);
+            let device = Net::new(nic.mac.unwrap_or(mac), network);
+            builder = builder.virtio_device(Box::new(device));
+        }
+        if let Some(console) = &config.console {
+            let device = Console::open(&console.backend, &console.ports).map_err(|e| {
+                io::Error::new(e.kind(), format!("can't open the virtio console: {}", e))
+            })?;
+            builder = builder.virtio_device(Box::new(device));
+        }
+        if let Some(backend) = config.rng {
+            // Runs that repeat must see the same bytes again.
+            let backend = match backend {
+                RngBackend::Host
+                    if config.gdb.is_some()
+                        || config.record.is_some()
+                        || config.replay.is_some() =>
+                {
+                    RngBackend::Seeded(RNG_SEED)
+                }
+                backend => backend,
+            };
+            let device = Rng::open(backend).map_err(|e| {
+                io::Error::new(e.kind(), format!("can't open the entropy device: {}", e))
+            })?;
+            builder = builder.virtio_device(Box::new(device));
+        }
+        if let Some(path) = &config.restore {
+            builder.snapshot = Some(read("snapshot", path)?);
+        }
+        Ok(builder)
+    }
+
+    /// Choose the devices and their layout.
+    pub fn profile(mut self, profile: Profile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Set the size of the RAM in bytes.
+    pub fn ram(mut self, size: u64) -> Self {
+        self.ram = size;
+        self
+    }
+
+    /// Load `kernel` at the start of RAM, where execution starts, or after the firmware.
+    pub fn kernel(mut self, kernel: Vec<u8>) -> Self {
+        self.images.kernel = kernel;
+        self
+    }
+
+    /// Load `firmware` at the start of RAM, where execution starts. The kernel is loaded
+    /// `boot::KERNEL_OFFSET` bytes later.
+    pub fn firmware(mut self, firmware: Vec<u8>) -> Self {
+        self.images.firmware = Some(firmware);
+        self
+    }
+
+    /// Load `initrd` at the end of RAM, below the device tree.
+    pub fn initrd(mut self, initrd: Vec<u8>) -> Self {
+        self.images.initrd = Some(initrd);
+        self
+    }
+
+    /// Load the device tree blob `dtb` at the end of RAM and pass its address in a1.
+    pub fn dtb(mut self, dtb: Vec<u8>) -> Self {
+        self.images.dtb = Some(dtb);
+        self
+    }
+
+    /// Attach a virtio block device with the contents `disk`.
+    pub fn disk(self, disk: Vec<u8>) -> Self {
+        self.disk_storage(Box::new(disk))
+    }
+
+    /// Attach a virtio block device whose contents `storage` keeps, e.g. a `FileStorage`.
+    pub fn disk_storage(self, storage: Box<dyn Storage>) -> Self {
+        self.virtio_device(Box::new(Block::new(storage)))
+    }
+
+    /// Attach a virtio device of any type. Devices take the virtio slots of the virt machine in
+    /// the order they're attached, and there are `VIRTIO_SLOTS` of them.
+    pub fn virtio_device(mut self, device: Box<dyn VirtioDevice>) -> Self {
+        self.virtio_devices.push(device);
+        self
+    }
+
+    /// Give the virtio devices the legacy or the modern virtio-mmio interface.
+    pub fn virtio(mut self, transport: VirtioTransport) -> Self {
+        self.virtio = transport;
+        self
+    }
+
+    /// Connect the console UART to `backend`.
+    pub fn uart(mut self, backend: UartBackend) -> Self {
+        self.uart = backend;
+        self
+    }
+
+    /// Set the frequency of mtime in Hz.
+    pub fn timebase(mut self, frequency: u64) -> Self {
+        self.timebase = frequency;
+        self
+    }
+
+    /// Set the extensions of the hart. By default, it has all extensions honga implements.
+    pub fn isa(mut self, isa: Isa) -> Self {
+        self.isa = isa;
+        self
+    }
+
+    /// Set the number of harts. Each has its own mhartid, CSRs and interrupt lines; they share
+    /// the RAM and the devices.
+    pub fn harts(mut self, harts: usize) -> Self {
+        self.harts = harts;
+        self
+    }
+
+    /// Let each hart execute `quantum` instructions before the next one takes its turn.
+    pub fn quantum(mut self, quantum: u64) -> Self {
+        self.quantum = quantum;
+        self
+    }
+
+    /// Execute instructions with `engine`.
+    pub fn engine(mut self, engine: Engine) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    /// Map `device` at `base..base + size` in addition to the devices of the profile. If `irq`
+    /// is set, the device's interrupt is routed to that PLIC source.
+    pub fn device(
+        mut self,
+        name: &str,
+        base: u64,
+        size: u64,
+        irq: Option<u64>,
+        device: Box<dyn Device>,
+    ) -> Self {
+        self.devices
+            .push((name.to_string(), base, size, irq, device));
+        self
+    }
+
+    /// Start from the state saved in a snapshot instead of the boot images and disks. The
+    /// machine must have the same RAM size and devices as the one the snapshot was taken of.
+    pub fn snapshot(mut self, snapshot: Vec<u8>) -> Self {
+        self.snapshot = Some(snapshot);
+        self
+    }
+
+    /// Record external events to or replay them from `events`.
+    pub fn events(mut self, events: EventLog) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Collect the side effects of every instruction, see `Machine::commit`.
+    pub fn trace(mut self, trace: bool) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Stop with `Exit::InstructionLimit` once `limit` instructions have been executed.
+    pub fn instruction_limit(mut self, limit: u64) -> Self {
+        self.instruction_limit = Some(limit);
+        self
+    }
+
+    pub fn build(self) -> io::Result<Machine> {
+        let mut memory = Memory::new(self.ram);
+        let layout = boot::load(&mut memory, self.images)?;
+        let mut bus = Bus::with_memory(memory, self.harts);
+        register_devices(
+            &mut bus,
+            self.profile,
+            &self.uart,
+            self.timebase,
+            self.harts,
+            self.virtio_devices,
+            self.virtio,
+        )?;
+        for (name, base, size, irq, device) in self.devices {
+            bus.register(&name, base, size, irq, device)?;
+        }
+
+        let mut cpu = Cpu::new(bus, self.isa);
+        // Every hart starts at the same pc with its id in a0 and the device tree in a1, as on
+        // the QEMU virt machine.
+        for hart in (0..cpu.harts()).rev() {
+            cpu.switch_to(hart);
+            cpu.set_reg(2, layout.top);
+            cpu.set_reg(11, layout.dtb.unwrap_or(0));
+        }
+        cpu.set_quantum(self.quantum);
+        cpu.set_engine(self.engine)?;
+        if let Some(data) = self.snapshot {
+            let mut reader = Reader::new(&data)?;
+            cpu.restore(&mut reader)
+                .and_then(|_| reader.finish())
+                .map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!(
+                            "{}; the machine must have the RAM size and virtio devices of the one \
+                             the snapshot was taken of",
+                            e
+                        ),
+                    )
+                })?;
+        }
+        cpu.events = self.events;
+        if self.trace {
+            cpu.commit = Some(Commit::default());
+        }
+        Ok(Machine {
+            finisher: cpu.bus.find::<Finisher>(),
+            cpu,
+            breakpoints: BTreeSet::new(),
+            instruction_limit: self.instruction_limit,
+            retired: None,
+        })
+    }
+}
+
+/// Map the devices of `profile`. Their order is the order of the snapshot format.
+fn register_devices(
+    bus: &mut Bus,
+    profile: Profile,
+    uart: &UartBackend,
+    timebase: u64,
+    harts: usize,
+    virtio_devices: Vec<Box<dyn VirtioDevice>>,
+    virtio: VirtioTransport,
+) -> io::Result<()> {
+    let clint = Box::new(Clint::with_timebase(timebase, harts));
+    bus.register("clint", CLINT_BASE, CLINT_SIZE, None, clint)?;
+    let finisher = Box::new(Finisher::new());
+    bus.register("finisher", FINISHER_BASE, FINISHER_SIZE, None, finisher)?;
+    let uart = Box::new(Uart::with_backend(uart)?);
+    if profile == Profile::Bare {
+        if !virtio_devices.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the bare profile has no virtio slots",
+            ));
+        }
+        bus.register("uart", UART_BASE, UART_SIZE, None, uart)?;
+        return Ok(());
+    }
+    if virtio_devices.len() > VIRTIO_SLOTS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} virtio devices, but the virt machine has {} virtio slots",
+                virtio_devices.len(),
+                VIRTIO_SLOTS
+            ),
+        ));
+    }
+
+    bus.register(
+        "plic",
+        PLIC_BASE,
+        PLIC_SIZE,
+        None,
+        Box::new(Plic::with_harts(harts)),
+    )?;
+    bus.register("uart", UART_BASE, UART_SIZE, Some(UART_IRQ), uart)?;
+    // The first slot always holds a device, an empty disk if there are none, as the virt
+    // machine always did.
+    let virtio_devices = if virtio_devices.is_empty() {
+        vec![Box::new(Block::new(Box::new(Vec::new()))) as Box<dyn VirtioDevice>]
+    } else {
+        virtio_devices
+    };
+    for (n, device) in virtio_devices.into_iter().enumerate() {
+        let n = n as u64;
+        bus.register(
+            &format!("virtio{}", n),
+            VIRTIO_BASE + n * VIRTIO_SIZE,
+            VIRTIO_SIZE,
+            Some(VIRTIO_IRQ + n),
+            Box::new(Virtio::with_device(device, virtio)),
+        )?;
+    }
+    Ok(())
+}
+
+/// An emulated RISC-V machine.
+pub struct Machine {
+    cpu: Cpu,
+    /// The test finisher the guest powers off with.
+    finisher: Option<DeviceId>,
+    breakpoints: BTreeSet<u64>,
+    instruction_limit: Option<u64>,
+    /// The pc and the word of the instruction retired by the last step.
+    retired: Option<(u64, u32)>,
+}
+
+impl Machine {
+    pub fn builder() -> MachineBuilder {
+        MachineBuilder::default()
+    }
+
+    /// Execute one instruction, or take one trap. With the JIT, and without breakpoints, a whole
+    /// block of instructions may be executed instead. Return why the machine must stop, if it
+    /// must.
+    #[inline]
+    pub fn step(&mut self) -> Option<Exit> {
+        self.step_before(u64::MAX)
+    }
+
+    /// Step like `step`, without executing instruction `end` or beyond, so that a block of the
+    /// JIT stops right before it.
+    #[inline]
+    pub fn step_before(&mut self, end: u64) -> Option<Exit> {
+        let end = end.min(self.instruction_limit.unwrap_or(u64::MAX));
+        if self.cpu.icount >= end {
+            return Some(Exit::InstructionLimit);
+        }
+        self.retired = None;
+        let pc = self.cpu.pc;
+        let result = if self.breakpoints.is_empty() {
+            self.cpu.step_block(end)
+        } else {
+            self.cpu.step()
+        };
+        match result {
+            Ok(retired) => self.retired = retired,
+            Err(exception) => return Some(Exit::Fault { exception, pc }),
+        }
+        if let Some(status) = self
+            .finisher
+            .and_then(|id| self.cpu.bus.get::<Finisher>(id)?.poweroff())
+        {
+            return Some(Exit::Poweroff(status));
+        }
+        if let Some(divergence) = self.cpu.events.as_ref().and_then(|e| e.divergence()) {
+            return Some(Exit::Diverged(divergence.to_string()));
+        }
+        if let Some(mismatch) = self.cpu.jit_mismatch() {
+            return Some(Exit::Mismatch(mismatch.to_string()));
+        }
+        if self.breakpoints.contains(&self.cpu.pc) {
+            return Some(Exit::Breakpoint(self.cpu.pc));
+        }
+        None
+    }
+
+    /// Execute until the machine stops on its own, with every hart on a host thread of its own.
+    /// The harts run concurrently, so unlike `run`, the run isn't deterministic. Breakpoints
+    /// and event logs are ignored, and the instruction limit only holds approximately.
+    ///
+    /// It only pays off with a host core for every hart. Otherwise the host preempts a hart in
+    /// the middle of a critical section, and the others spin on its lock for whole host time
+    /// slices, where `run` would switch harts after a quantum.
+    pub fn run_threads(&mut self) -> Exit {
+        if self.cpu.harts() == 1 {
+            return self.run();
+        }
+        // Stop within this many instructions of another hart stopping.
+        const CHECK: u64 = 4096;
+        let harts = self.cpu.harts() as u64;
+        let limit = self
+            .instruction_limit
+            .map(|limit| limit.saturating_sub(self.cpu.icount) / harts);
+        let finisher = self.finisher;
+        let stop = AtomicBool::new(false);
+        let mut cpus = self.cpu.split();
+        let exits: Vec<Option<Exit>> = thread::scope(|scope| {
+            let handles: Vec<_> = cpus
+                .iter_mut()
+                .map(|cpu| {
+                    let stop = &stop;
+                    scope.spawn(move || {
+                        let start = cpu.icount;
+                        let mut next_check = start + CHECK;
+                        let exit = loop {
+                            let pc = cpu.pc;
+                            if let Err(exception) = cpu.step_block(u64::MAX) {
+                                break Some(Exit::Fault { exception, pc });
+                            }
+                            if cpu.icount < next_check {
+                                continue;
+                            }
+                            next_check = cpu.icount + CHECK;
+                            if stop.load(Ordering::Relaxed) {
+                                break None;
+                            }
+                            if let Some(status) = finisher.and_then(|id| {
+                                cpu.bus
+                                    .with::<Finisher, _>(id, |finisher| finisher.poweroff())?
+                            }) {
+                                break Some(Exit::Poweroff(status));
+                            }
+                            if limit.is_some_and(|limit| cpu.icount - start >= limit) {
+                                break Some(Exit::InstructionLimit);
+                            }
+                        };
+                        stop.store(true, Ordering::Relaxed);
+                        exit
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("a hart thread panicked"))
+                .collect()
+        });
+        self.cpu.join(cpus);
+        // Report the first hart that stopped for a reason of its own.
+        exits
+            .into_iter()
+            .flatten()
+            .next()
+            .unwrap_or(Exit::InstructionLimit)
+    }
+
+    /// Execute at most `count` instructions.
+    pub fn run_for(&mut self, count: u64) -> Exit {
+        let end = self.cpu.icount.saturating_add(count);
+        loop {
+            if let Some(exit) = self.step_before(end) {
+                return exit;
+            }
+        }
+    }
+
+    /// Execute until `condition` holds after an instruction.
+    pub fn run_until(&mut self, mut condition: impl FnMut(&Machine) -> bool) -> Exit {
+        loop {
+            if let Some(exit) = self.step() {
+                return exit;
+            }
+            if condition(self) {
+                return Exit::Condition;
+            }
+        }
+    }
+
+    /// Execute until the machine stops on its own.
+    pub fn run(&mut self) -> Exit {
+        loop {
+            if let Some(exit) = self.step() {
+                return exit;
+            }
+        }
+    }
+
+    /// Stop before executing the instruction at `pc`.
+    pub fn add_breakpoint(&mut self, pc: u64) {
+        self.breakpoints.insert(pc);
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: u64) {
+        self.breakpoints.remove(&pc);
+    }
+
+    /// Return the pc and the word of the instruction retired by the last step, or `None` if it
+    /// trapped.
+    pub fn retired(&self) -> Option<(u64, u32)> {
+        self.retired
+    }
+
+    /// Return the side effects of the last instruction if the machine was built with `trace`.
+    pub fn commit(&self) -> Option<&Commit> {
+        self.cpu.commit.as_ref()
+    }
+
+    /// Return the number of harts.
+    pub fn harts(&self) -> usize {
+        self.cpu.harts()
+    }
+
+    /// Return the id of the hart that the register and CSR accessors refer to.
+    pub fn hart(&self) -> usize {
+        self.cpu.hart()
+    }
+
+    /// Make the register and CSR accessors refer to hart `id`. Which hart runs next doesn't
+    /// change.
+    pub fn switch_to(&mut self, id: usize) {
+        self.cpu.switch_to(id);
+    }
+
+    /// Return the number of instructions executed so far.
+    pub fn icount(&self) -> u64 {
+        self.cpu.icount
+    }
+
+    pub fn pc(&self) -> u64 {
+        self.cpu.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u64) {
+        self.cpu.pc = pc;
+    }
+
+    /// Return the integer register `index`.
+    pub fn reg(&self, index: usize) -> u64 {
+        self.cpu.reg(index)
+    }
+
+    pub fn set_reg(&mut self, index: usize, value: u64) {
+        self.cpu.set_reg(index, value);
+    }
+
+    /// Return the CSR at `addr`.
+    pub fn csr(&self, addr: usize) -> u64 {
+        self.cpu.load_csr(addr)
+    }
+
+    pub fn set_csr(&mut self, addr: usize, value: u64) {
+        self.cpu.store_csr(addr, value);
+    }
+
+    /// Read physical memory at `addr` into `buf`. Device registers are read as well, which may
+    /// have side effects.
+    pub fn read_memory(&self, addr: u64, buf: &mut [u8]) -> Result<(), Exception> {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = self.cpu.bus.load(addr.wrapping_add(i as u64), 8)? as u8;
+        }
+        Ok(())
+    }
+
+    /// Write `data` to physical memory at `addr`.
+    pub fn write_memory(&mut self, addr: u64, data: &[u8]) -> Result<(), Exception> {
+        for (i, byte) in data.iter().enumerate() {
+            self.cpu
+                .bus
+                .store(addr.wrapping_add(i as u64), 8, *byte as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Return the bus with the devices of the machine.
+    pub fn bus(&self) -> &Bus {
+        &self.cpu.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        &mut self.cpu.bus
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::MEMORY_BASE;
+
+    /// The number of times each hart increments the counters.
+    const ROUNDS: u64 = 2000;
+    /// Where the program keeps its counters: one for AMOADD, one for LR/SC and one for the harts
+    /// that are done.
+    const COUNTERS: u64 = MEMORY_BASE + 0x1000;
+
+    fn i_type(opcode: u32, funct3: u32, rd: u32, rs1: u32, imm: i32) -> u32 {
+        ((imm as u32 & 0xfff) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+    }
+
+    fn amo(funct5: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+        (funct5 << 27) | (rs2 << 20) | (rs1 << 15) | (0x3 << 12) | (rd << 7) | 0x2f
+    }
+
+    fn branch(funct3: u32, rs1: u32, rs2: u32, offset: i32) -> u32 {
+        let imm = offset as u32;
+        (((imm >> 12) & 1) << 31)
+            | (((imm >> 5) & 0x3f) << 25)
+            | (rs2 << 20)
+            | (rs1 << 15)
+            | (funct3 << 12)
+            | (((imm >> 1) & 0xf) << 8)
+            | (((imm >> 11) & 1) << 7)
+            | 0x63
+    }
+
+    /// A program for every hart: increment the first counter with AMOADD and the second with
+    /// LR/SC `ROUNDS` times, and power off once all harts are done.
+    fn counting_program(harts: u64) -> Vec<u8> {
+        let addi = |rd, rs1, imm| i_type(0x13, 0x0, rd, rs1, imm);
+        let code = [
+            // auipc t1, 1: the counters
+            (1 << 12) | (6 << 7) | 0x17,
+            addi(5, 0, ROUNDS as i32),
+            addi(7, 0, 1),
+            // amoadd.d zero, t2, (t1)
+            amo(0x00, 0, 6, 7),
+            addi(31, 6, 8),
+            // lr.d t3, (t6); addi t3, t3, 1; sc.d t4, t3, (t6); bnez t4, lr
+            amo(0x02, 28, 31, 0),
+            addi(28, 28, 1),
+            amo(0x03, 29, 31, 28),
+            branch(0x1, 29, 0, -12),
+            addi(5, 5, -1),
+            branch(0x1, 5, 0, -28),
+            // amoadd.d t3, t2, 16(t1); the last hart powers off, the others spin.
+            addi(31, 6, 16),
+            amo(0x00, 28, 31, 7),
+            addi(29, 0, harts as i32 - 1),
+            branch(0x1, 28, 29, 20),
+            // lui t5, FINISHER_BASE; lui t4, 5; addi t4, t4, 0x555; sw t4, 0(t5)
+            ((FINISHER_BASE as u32) & 0xfffff000) | (30 << 7) | 0x37,
+            (5 << 12) | (29 << 7) | 0x37,
+            addi(29, 29, 0x555),
+            (29 << 20) | (30 << 15) | (0x2 << 12) | 0x23,
+            // j .
+            0x6f,
+        ];
+        code.iter().flat_map(|inst| inst.to_le_bytes()).collect()
+    }
+
+    fn counting_machine(harts: usize, quantum: u64) -> Machine {
+        Machine::builder()
+            .kernel(counting_program(harts as u64))
+            .harts(harts)
+            .quantum(quantum)
+            .build()
+            .unwrap()
+    }
+
+    fn counters(machine: &Machine) -> [u64; 3] {
+        let memory = &machine.cpu.bus.memory;
+        [0, 8, 16].map(|offset| memory.load(COUNTERS + offset, 64).unwrap())
+    }
+
+    #[test]
+    fn harts_taking_turns_count_together() {
+        // Short turns hand the harts off between an LR and its SC.
+        for quantum in [1, 3, 7, 1000] {
+            let mut machine = counting_machine(3, quantum);
+            assert!(matches!(machine.run(), Exit::Poweroff(0)));
+            assert_eq!(
+                counters(&machine),
+                [3 * ROUNDS, 3 * ROUNDS, 3],
+                "{}",
+                quantum
+            );
+        }
+    }
+
+    #[test]
+    fn harts_on_threads_count_together() {
+        let mut machine = counting_machine(2, QUANTUM);
+        assert!(matches!(machine.run_threads(), Exit::Poweroff(0)));
+        assert_eq!(counters(&machine), [2 * ROUNDS, 2 * ROUNDS, 2]);
+    }
+}
@@ -1,61 +1,186 @@
-mod bus;
-mod cpu;
-mod csr;
-mod exception;
-mod interrupt;
+use honga::config::{Scheduler, HELP, USAGE};
+use honga::gdb::GdbStub;
+use honga::lockstep::Lockstep;
+use honga::replay::{fnv1a, EventLog};
+use honga::snapshot::{Snapshot, Writer};
+use honga::trace::CommitLog;
+use honga::{Config, Exit, Machine, MachineBuilder};
 
-use crate::cpu::Cpu;
+use std::path::Path;
 
-use std::io::prelude::*;
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        print!("{}", HELP);
+        return;
+    }
+    let config = match Config::from_args(args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!(
+                "honga: {}\n{}\nTry `honga --help` for more information.",
+                e, USAGE
+            );
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = run(config) {
+        eprintln!("honga: {}", e);
+        std::process::exit(1);
+    }
+}
 
-fn main() -> std::io::Result<()> {
-    // Read binary to memory.
-    let args: Vec<String> = std::env::args().collect();
-    if (args.len() != 2) && (args.len() != 3) {
-        panic!("Usage: cargo run <filename> <(option) image>");
-    }
-    let mut file = std::fs::File::open(&args[1])?;
-    let mut binary = Vec::new();
-    file.read_to_end(&mut binary)?;
+fn run(config: Config) -> std::io::Result<()> {
+    let mut commit_log = match &config.log_commits {
+        Some(path) => Some(CommitLog::create(path, config.disasm)?),
+        None => None,
+    };
 
-    let mut image = Vec::new();
-    if args.len() == 3 {
-        let mut file = std::fs::File::open(&args[2])?;
-        file.read_to_end(&mut image)?;
+    let mut machine = MachineBuilder::from_config(&config)?
+        .trace(commit_log.is_some() || config.lockstep.is_some() || config.gdb.is_some())
+        .build()?;
+    if config.record.is_some() || config.replay.is_some() {
+        // Identify the initial state so that a log is only replayed on the run it came from.
+        let mut writer = Writer::new();
+        machine.cpu().save(&mut writer)?;
+        let hash = fnv1a(writer.as_bytes());
+        machine.cpu_mut().events = Some(match (&config.record, &config.replay) {
+            (Some(path), _) => EventLog::record(path, hash)?,
+            (_, Some(path)) => EventLog::replay(path, hash)?,
+            _ => unreachable!(),
+        });
     }
-
-    let mut cpu = Cpu::new(binary, image);
-    // Instruction cycle
-    loop {
-        // Fetch instruction
-        let inst = match cpu.fetch() {
-            Ok(i) => i,
-            Err(e) => {
-                e.get_trap(&mut cpu);
-                if e.is_fatal() {
-                    break;
-                }
-                0
+    let mut lockstep = match &config.lockstep {
+        Some(path) => {
+            let mut lockstep = Lockstep::open(path)?;
+            let skipped = lockstep.synchronize(machine.pc())?;
+            if skipped > 0 {
+                eprintln!("lockstep: skipped {} reference instructions", skipped);
             }
-        };
-
-        // Add 4 to the program counter
-        cpu.pc += 4;
+            Some(lockstep)
+        }
+        None => None,
+    };
+    if let Some(port) = config.gdb {
+        // Reverse execution needs all input in the event log, and watchpoints need the memory
+        // accesses of each instruction.
+        let cpu = machine.cpu_mut();
+        cpu.events.get_or_insert_with(EventLog::new);
+        let listener = std::net::TcpListener::bind(("127.0.0.1", port))?;
+        eprintln!("waiting for GDB on port {}", port);
+        let (stream, _) = listener.accept()?;
+        GdbStub::new(stream, cpu, config.checkpoint_interval)?.serve(cpu)?;
+        if let Some(events) = &mut cpu.events {
+            events.finish()?;
+        }
+        cpu.dump_registers();
+        cpu.dump_csr();
+        return Ok(());
+    }
+    // Instruction cycle
+    let exit = if config.scheduler == Scheduler::Threads {
+        let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+        if cores < machine.harts() {
+            eprintln!(
+                "{} harts on {} host core(s): the threads scheduler is likely slower than \
+                 round-robin",
+                machine.harts(),
+                cores
+            );
+        }
+        Some(machine.run_threads())
+    } else {
+        step(&mut machine, &config, &mut commit_log, &mut lockstep)?
+    };
+    if let Some(exit) = &exit {
+        eprintln!("{}", exit);
+    }
+    if let Some(log) = &mut commit_log {
+        log.flush()?;
+    }
+    if let Some(events) = &mut machine.cpu_mut().events {
+        events.finish()?;
+    }
+    if let (Some(path), None) = (&config.save_snapshot, config.save_at) {
+        save_snapshot(&mut machine, path)?;
+    }
+    // Running to the end of the reference trace is a clean end of a lockstep run.
+    if lockstep.is_some_and(|lockstep| lockstep.ended) {
+        return Ok(());
+    }
+    machine.cpu().dump_registers();
+    machine.cpu().dump_csr();
+    match exit {
+        Some(Exit::Poweroff(status)) => std::process::exit(status as i32),
+        // A lockstep mismatch fails the run, so that scripts can tell.
+        None => std::process::exit(1),
+        _ => {}
+    }
+    Ok(())
+}
 
-        // Decode & Execute
-        if let Err(e) = cpu.decode_execute(inst) {
-            e.get_trap(&mut cpu);
-            if e.is_fatal() {
-                break;
+/// Step the harts in turn until the machine stops, writing the commit log and checking against
+/// the reference as it goes. Return `None` if the run stopped on a lockstep mismatch or at the
+/// end of the reference trace.
+fn step(
+    machine: &mut Machine,
+    config: &Config,
+    commit_log: &mut Option<CommitLog>,
+    lockstep: &mut Option<Lockstep>,
+) -> std::io::Result<Option<Exit>> {
+    loop {
+        if config.save_at == Some(machine.icount()) {
+            if let Some(path) = &config.save_snapshot {
+                save_snapshot(machine, path)?;
             }
         }
 
-        match cpu.check_pending_interrupt() {
-            Some(interrupt) => interrupt.get_trap(&mut cpu),
-            None => {}
+        // Blocks of the JIT stop at the instruction the snapshot is saved at.
+        let end = match config.save_at {
+            Some(save_at) if machine.icount() < save_at => save_at,
+            _ => u64::MAX,
+        };
+        if let Some(exit) = machine.step_before(end) {
+            return Ok(Some(exit));
+        }
+        if let Some((pc, inst)) = machine.retired() {
+            if let Some(log) = commit_log {
+                log.write(machine.cpu(), pc, inst)?;
+            }
+            if let Some(lockstep) = lockstep {
+                if let Err(mismatch) = lockstep.check(machine.cpu(), pc, inst)? {
+                    eprintln!(
+                        "{}after {} matching instructions",
+                        mismatch,
+                        lockstep.checked - 1
+                    );
+                    return Ok(None);
+                }
+                if lockstep.ended {
+                    eprintln!(
+                        "lockstep: the reference trace ended after {} matching instructions",
+                        lockstep.checked
+                    );
+                    return Ok(None);
+                }
+            }
         }
     }
-    cpu.dump_registers();
-    cpu.dump_csr();
+}
+
+/// Save the whole machine state to the snapshot file at `path`.
+fn save_snapshot(machine: &mut Machine, path: &Path) -> std::io::Result<()> {
+    // mtime is advanced before each instruction, and a block of the JIT may have executed
+    // several since. Bring it up to date so that both engines save the same state.
+    let icount = machine.icount();
+    machine.cpu_mut().bus.update_timer(icount);
+    let mut writer = Writer::new();
+    machine.cpu().save(&mut writer)?;
+    writer.write_to(path)?;
+    eprintln!(
+        "saved a snapshot to {} at instruction {}",
+        path.display(),
+        machine.icount()
+    );
     Ok(())
 }
@@ -0,0 +1,279 @@
+//! The replay module records the nondeterministic inputs of a run and replays them later, so that
+//! a run can be reproduced exactly.
+//!
+//! Everything the guest sees is a function of its initial state and of the external events it
+//! receives. The only external events are bytes arriving on the UART, which are delivered to the
+//! guest at an instruction boundary by the emulator thread. An event log stores each event with
+//! the instruction count it was delivered at, and replaying the log delivers the same events at the
+//! same instruction counts.
+//!
+//! The log is a text file:
+//!
+//! ```text
+//! honga-events 1
+//! state 9c1f0d3a5e7b2481
+//! 120394812 uart 0x6c
+//! 120394907 uart 0x73
+//! ```
+//!
+//! The `state` line is a hash of the machine state the recording started from. Replaying against
+//! a different kernel, disk image or snapshot is rejected. Once every event has been replayed,
+//! input is read from stdin again.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::snapshot::invalid;
+
+/// The first line of every event log.
+pub const EVENTS_MAGIC: &str = "honga-events";
+/// The version of the event log format.
+pub const EVENTS_VERSION: u32 = 1;
+
+/// An external event delivered to the guest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// A byte was put into the UART receive holding register.
+    UartInput(u8),
+}
+
+/// Return the 64-bit FNV-1a hash of `bytes`.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+/// A log of external events. Every event delivered to the guest is kept in memory, so that a
+/// part of the run can be executed again, e.g. for reverse debugging.
+#[derive(Default)]
+pub struct EventLog {
+    /// The file new events are recorded to, if any.
+    out: Option<BufWriter<File>>,
+    /// Events delivered so far and events loaded from a log, ordered by instruction count.
+    events: Vec<(u64, Event)>,
+    /// The index of the next event to deliver from `events`. Once all of them are delivered,
+    /// events come from the host again.
+    cursor: usize,
+    /// A description of the first point where the run diverged from the log.
+    divergence: Option<String>,
+    /// The error that stopped recording, which `finish` returns.
+    error: Option<io::Error>,
+}
+
+impl EventLog {
+    /// Create a log that is only kept in memory.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start recording to the file at `path` for a run that starts from the state `hash`.
+    pub fn record(path: &Path, hash: u64) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "{} {}", EVENTS_MAGIC, EVENTS_VERSION)?;
+        writeln!(out, "state {:016x}", hash)?;
+        out.flush()?;
+        Ok(Self {
+            out: Some(out),
+            ..Self::new()
+        })
+    }
+
+    /// Load the event log at `path` to replay it on a run that starts from the state `hash`.
+    pub fn replay(path: &Path, hash: u64) -> io::Result<Self> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let header = lines.next().transpose()?.unwrap_or_default();
+        if header != format!("{} {}", EVENTS_MAGIC, EVENTS_VERSION) {
+            return Err(invalid(&format!(
+                "{} is not a version {} event log",
+                path.display(),
+                EVENTS_VERSION
+            )));
+        }
+        let state = lines.next().transpose()?.unwrap_or_default();
+        let recorded = state
+            .strip_prefix("state ")
+            .and_then(|hash| u64::from_str_radix(hash, 16).ok())
+            .ok_or_else(|| invalid("missing state line in the event log"))?;
+        if recorded != hash {
+            return Err(invalid(
+                "the event log was recorded from a different initial state",
+            ));
+        }
+
+        let mut events = Vec::new();
+        for (i, line) in lines.enumerate() {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let event = match fields.as_slice() {
+                [icount, "uart", byte] => icount.parse().ok().and_then(|icount| {
+                    let byte = u8::from_str_radix(byte.trim_start_matches("0x"), 16).ok()?;
+                    Some((icount, Event::UartInput(byte)))
+                }),
+                [] => continue,
+                _ => None,
+            };
+            events.push(
+                event.ok_or_else(|| {
+                    invalid(&format!("malformed event on line {}: {}", i + 3, line))
+                })?,
+            );
+        }
+        Ok(Self {
+            events,
+            ..Self::new()
+        })
+    }
+
+    /// Return a description of how the run diverged from the replayed log, if it did.
+    pub fn divergence(&self) -> Option<&str> {
+        self.divergence.as_deref()
+    }
+
+    /// Return the error that stopped recording to the file, if one did. Call it when the run
+    /// ends.
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Prepare to run again from instruction `icount`: events delivered from then on are
+    /// replayed from the log.
+    pub fn rewind(&mut self, icount: u64) {
+        self.cursor = self.events.partition_point(|(at, _)| *at < icount);
+        self.divergence = None;
+    }
+
+    /// Forget the events from instruction `icount` on, because the run has been changed and
+    /// they won't happen again.
+    pub fn truncate(&mut self, icount: u64) {
+        self.rewind(icount);
+        self.events.truncate(self.cursor);
+    }
+
+    /// Return the UART byte to deliver at instruction `icount`. Recorded events are replayed
+    /// first. After that the byte comes from `live` and is added to the log.
+    pub fn uart_input(&mut self, icount: u64, live: impl FnOnce() -> Option<u8>) -> Option<u8> {
+        match self.events.get(self.cursor) {
+            Some((at, Event::UartInput(byte))) if *at == icount => {
+                self.cursor += 1;
+                Some(*byte)
+            }
+            Some((at, _)) => {
+                if *at < icount && self.divergence.is_none() {
+                    self.divergence = Some(format!(
+                        "the event at instruction {} could not be delivered before instruction {}",
+                        at, icount
+                    ));
+                }
+                None
+            }
+            None => {
+                let byte = live()?;
+                self.events.push((icount, Event::UartInput(byte)));
+                self.cursor += 1;
+                if let Some(out) = &mut self.out {
+                    // Flush right away so that the log survives the emulator being killed. The
+                    // run goes on without the file if it can't be written.
+                    if let Err(e) =
+                        writeln!(out, "{} uart {:#04x}", icount, byte).and_then(|_| out.flush())
+                    {
+                        self.error = Some(io::Error::new(
+                            e.kind(),
+                            format!("failed to write the event log: {}", e),
+                        ));
+                        self.out = None;
+                    }
+                }
+                Some(byte)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{UartBackend, UART_BASE};
+    use crate::machine::Machine;
+    use crate::snapshot::{Snapshot, Writer};
+    use crate::testing::TempDir;
+
+    #[test]
+    fn recorded_input_is_replayed_at_the_same_instruction() {
+        let dir = TempDir::new("replay-record");
+        let path = dir.join("input.log");
+        let mut log = EventLog::record(&path, 0x1234).unwrap();
+        assert_eq!(log.uart_input(10, || None), None);
+        assert_eq!(log.uart_input(100, || Some(b'l')), Some(b'l'));
+        assert_eq!(log.uart_input(250, || Some(b's')), Some(b's'));
+        log.finish().unwrap();
+        drop(log);
+
+        assert!(EventLog::replay(&path, 0x4321).is_err());
+        let mut log = EventLog::replay(&path, 0x1234).unwrap();
+        let live = || panic!("input must come from the log");
+        assert_eq!(log.uart_input(99, live), None);
+        assert_eq!(log.uart_input(100, live), Some(b'l'));
+        assert_eq!(log.uart_input(101, live), None);
+        assert_eq!(log.uart_input(250, live), Some(b's'));
+        // Then input is live again.
+        assert_eq!(log.uart_input(300, || Some(b'x')), Some(b'x'));
+        assert!(log.divergence().is_none());
+
+        // An event that can't be delivered at its instruction is a divergence.
+        let mut log = EventLog::replay(&path, 0x1234).unwrap();
+        assert_eq!(log.uart_input(101, live), None);
+        assert!(log.divergence().is_some());
+    }
+
+    #[test]
+    fn replayed_bytes_reach_the_uart() {
+        // j .
+        let mut machine = Machine::builder()
+            .kernel(0x6f_u32.to_le_bytes().to_vec())
+            .uart(UartBackend::Null)
+            .build()
+            .unwrap();
+        let mut writer = Writer::new();
+        machine.cpu().save(&mut writer).unwrap();
+        let hash = fnv1a(writer.as_bytes());
+        let dir = TempDir::new("replay-uart");
+        let path = dir.join("input.log");
+        std::fs::write(
+            &path,
+            format!(
+                "{} {}\nstate {:016x}\n10 uart 0x68\n20 uart 0x69\n",
+                EVENTS_MAGIC, EVENTS_VERSION, hash
+            ),
+        )
+        .unwrap();
+        machine.cpu_mut().events = Some(EventLog::replay(&path, hash).unwrap());
+
+        // Read the received byte if the data-ready bit of the LSR is set, which clears it.
+        let received = |machine: &mut Machine| {
+            let bus = &mut machine.cpu_mut().bus;
+            match bus.load(UART_BASE + 5, 8).unwrap() & 1 {
+                0 => None,
+                _ => Some(bus.load(UART_BASE, 8).unwrap() as u8),
+            }
+        };
+        machine.run_for(10);
+        assert_eq!(received(&mut machine), None);
+        machine.run_for(1);
+        assert_eq!(received(&mut machine), Some(b'h'));
+        machine.run_for(9);
+        assert_eq!(received(&mut machine), None);
+        machine.run_for(1);
+        assert_eq!(received(&mut machine), Some(b'i'));
+        let events = machine.cpu_mut().events.as_mut().unwrap();
+        assert!(events.divergence().is_none());
+        events.finish().unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+//! The reverse module lets a debugger move backwards in time. The machine state is saved in
+//! memory every `interval` instructions, and all external events are kept in the `EventLog`.
+//! Since execution is deterministic given those events, any earlier instruction count can be
+//! reached by restoring the last checkpoint before it and executing forward again.
+
+use std::io;
+
+use crate::cpu::Cpu;
+use crate::snapshot::{Reader, Snapshot, Writer};
+
+/// The default number of instructions between two checkpoints.
+pub const CHECKPOINT_INTERVAL: u64 = 10_000_000;
+/// The maximum number of checkpoints kept. When there are more, every other checkpoint is
+/// dropped and the interval doubles.
+const CHECKPOINT_LIMIT: usize = 64;
+
+/// Checkpoints of a run, ordered by instruction count.
+pub struct Timeline {
+    checkpoints: Vec<(u64, Vec<u8>)>,
+    interval: u64,
+}
+
+impl Timeline {
+    /// Create a timeline that starts with a checkpoint of the current state of `cpu`.
+    pub fn new(cpu: &Cpu, interval: u64) -> io::Result<Self> {
+        let mut timeline = Self {
+            checkpoints: Vec::new(),
+            interval: interval.max(1),
+        };
+        timeline.checkpoint(cpu)?;
+        Ok(timeline)
+    }
+
+    /// Return the earliest instruction count that can be reached.
+    pub fn origin(&self) -> u64 {
+        self.checkpoints[0].0
+    }
+
+    /// Return the instruction counts of all checkpoints.
+    pub fn checkpoints(&self) -> impl Iterator<Item = u64> + '_ {
+        self.checkpoints.iter().map(|(icount, _)| *icount)
+    }
+
+    /// Save the state of `cpu`, replacing an existing checkpoint at the same instruction.
+    pub fn checkpoint(&mut self, cpu: &Cpu) -> io::Result<()> {
+        let mut writer = Writer::new();
+        cpu.save(&mut writer)?;
+        let state = writer.as_bytes().to_vec();
+        match self
+            .checkpoints
+            .binary_search_by_key(&cpu.icount, |(icount, _)| *icount)
+        {
+            Ok(i) => self.checkpoints[i].1 = state,
+            Err(i) => self.checkpoints.insert(i, (cpu.icount, state)),
+        }
+
+        if self.checkpoints.len() > CHECKPOINT_LIMIT {
+            self.interval *= 2;
+            let interval = self.interval;
+            let origin = self.origin();
+            self.checkpoints
+                .retain(|(icount, _)| *icount == origin || icount % interval == 0);
+        }
+        Ok(())
+    }
+
+    /// Save the state of `cpu` if a checkpoint is due at its current instruction.
+    pub fn tick(&mut self, cpu: &Cpu) -> io::Result<()> {
+        if !cpu.icount.is_multiple_of(self.interval) {
+            return Ok(());
+        }
+        let due = self
+            .checkpoints
+            .binary_search_by_key(&cpu.icount, |(icount, _)| *icount)
+            .is_err();
+        if due {
+            self.checkpoint(cpu)?;
+        }
+        Ok(())
+    }
+
+    /// Forget all checkpoints after instruction `icount`, because the run has been changed.
+    pub fn truncate(&mut self, icount: u64) {
+        self.checkpoints.retain(|(at, _)| *at <= icount);
+    }
+
+    /// Return the instruction count of the last checkpoint before `icount`.
+    pub fn before(&self, icount: u64) -> Option<u64> {
+        self.checkpoints
+            .iter()
+            .rev()
+            .map(|(at, _)| *at)
+            .find(|at| *at < icount)
+    }
+
+    /// Restore `cpu` to the last checkpoint at or before `icount`.
+    pub fn restore(&self, cpu: &mut Cpu, icount: u64) -> io::Result<()> {
+        let (at, state) = self
+            .checkpoints
+            .iter()
+            .rev()
+            .find(|(at, _)| *at <= icount)
+            .unwrap_or(&self.checkpoints[0]);
+        let mut reader = Reader::new(state)?;
+        cpu.restore(&mut reader)?;
+        if let Some(events) = &mut cpu.events {
+            events.rewind(*at);
+        }
+        Ok(())
+    }
+
+    /// Bring `cpu` to the state it had at instruction `icount` by restoring a checkpoint and
+    /// executing forward. The target is clamped to the origin of the timeline.
+    pub fn seek(&mut self, cpu: &mut Cpu, icount: u64) -> io::Result<()> {
+        let icount = icount.max(self.origin());
+        if icount < cpu.icount || self.before(icount + 1) > Some(cpu.icount) {
+            self.restore(cpu, icount)?;
+        }
+        let silent = cpu.bus.console().is_some_and(|uart| uart.silent);
+        cpu.bus.set_silent(true);
+        while cpu.icount < icount {
+            self.tick(cpu)?;
+            if cpu.step().is_err() {
+                break;
+            }
+        }
+        cpu.bus.set_silent(silent);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{counting_machine, COUNTER};
+
+    /// Run `cpu` to instruction `end`, ticking `timeline`, and return the pc and x5 before every
+    /// instruction.
+    fn run(cpu: &mut Cpu, timeline: &mut Timeline, end: u64) -> Vec<(u64, u64)> {
+        let mut states = Vec::new();
+        while cpu.icount < end {
+            states.push((cpu.pc, cpu.reg(5)));
+            timeline.tick(cpu).unwrap();
+            cpu.step().unwrap();
+        }
+        states
+    }
+
+    #[test]
+    fn checkpoints_are_placed_every_interval() {
+        let mut machine = counting_machine();
+        let cpu = machine.cpu_mut();
+        let mut timeline = Timeline::new(cpu, 10).unwrap();
+        run(cpu, &mut timeline, 35);
+        assert_eq!(timeline.checkpoints().collect::<Vec<_>>(), [0, 10, 20, 30]);
+        assert_eq!(timeline.before(25), Some(20));
+        assert_eq!(timeline.before(20), Some(10));
+        assert_eq!(timeline.before(0), None);
+
+        timeline.truncate(15);
+        assert_eq!(timeline.checkpoints().collect::<Vec<_>>(), [0, 10]);
+
+        // Past the limit, every other checkpoint goes and the interval doubles.
+        let mut timeline = Timeline::new(cpu, 1).unwrap();
+        let start = cpu.icount;
+        run(cpu, &mut timeline, start + 2 * CHECKPOINT_LIMIT as u64);
+        let checkpoints: Vec<u64> = timeline.checkpoints().collect();
+        assert!(checkpoints.len() <= CHECKPOINT_LIMIT);
+        assert_eq!(checkpoints[0], start);
+        assert!(checkpoints[1..].iter().all(|icount| icount % 2 == 0));
+        assert!(timeline.interval > 1);
+    }
+
+    #[test]
+    fn seek_goes_back_and_forth() {
+        let mut machine = counting_machine();
+        let cpu = machine.cpu_mut();
+        let mut timeline = Timeline::new(cpu, 10).unwrap();
+        let states = run(cpu, &mut timeline, 40);
+        for icount in [15, 3, 33, 0, 39, 10] {
+            timeline.seek(cpu, icount).unwrap();
+            assert_eq!(cpu.icount, icount);
+            assert_eq!((cpu.pc, cpu.reg(5)), states[icount as usize], "{}", icount);
+            // Memory comes back too: the counter holds the number of stores so far.
+            assert_eq!(cpu.bus.memory.load(COUNTER, 64).unwrap(), icount / 3);
+        }
+    }
+}
@@ -0,0 +1,372 @@
+//! The snapshot module saves the complete machine state to a file and restores it later, so that
+//! a run can resume e.g. from an already booted kernel.
+//!
+//! A snapshot file starts with the magic `HONGASNP` and a format version, followed by the state of
+//! the CPU and of every device on the bus. All numbers are little-endian. Large byte arrays such
+//! as RAM and disk contents are stored page by page: pages that are entirely zero are left out,
+//! pages filled with a single byte value are stored as that value, and other pages are
+//! compressed with PackBits run-length encoding.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The first bytes of every snapshot file.
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"HONGASNP";
+/// The version of the snapshot format. Bump it whenever the saved state changes.
+pub const SNAPSHOT_VERSION: u32 = 6;
+/// The granularity of sparse byte arrays.
+const CHUNK_SIZE: usize = 4096;
+/// A chunk of a sparse byte array that repeats a single byte.
+const CHUNK_FILL: u8 = 0;
+/// A chunk of a sparse byte array that is compressed with PackBits.
+const CHUNK_PACKED: u8 = 1;
+
+/// State that can be saved to and restored from a snapshot.
+pub trait Snapshot {
+    fn save(&self, w: &mut Writer) -> io::Result<()>;
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()>;
+}
+
+/// Return an error for a malformed snapshot.
+pub fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A buffer that snapshot data is serialized into.
+#[derive(Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    /// Create a writer that starts with the snapshot header.
+    pub fn new() -> Self {
+        let mut w = Self { buf: Vec::new() };
+        w.buf.extend_from_slice(SNAPSHOT_MAGIC);
+        w.put_u32(SNAPSHOT_VERSION);
+        w
+    }
+
+    pub fn put_u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn put_bool(&mut self, value: bool) {
+        self.buf.push(value as u8);
+    }
+
+    pub fn put_u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn put_u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Write a length-prefixed byte array.
+    pub fn put_bytes(&mut self, bytes: &[u8]) {
+        self.put_u64(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Write a byte array that is mostly uniform, e.g. RAM. Zero chunks are left out and chunks
+    /// of a single repeated byte are stored as that byte.
+    pub fn put_sparse(&mut self, bytes: &[u8]) {
+        self.put_u64(bytes.len() as u64);
+        for (i, chunk) in bytes.chunks(CHUNK_SIZE).enumerate() {
+            let first = chunk[0];
+            let uniform = chunk.iter().all(|b| *b == first);
+            if uniform && first == 0 {
+                continue;
+            }
+            self.put_u64(i as u64);
+            if uniform {
+                self.put_u8(CHUNK_FILL);
+                self.put_u8(first);
+            } else {
+                self.put_u8(CHUNK_PACKED);
+                pack_bits(chunk, &mut self.buf);
+            }
+        }
+        self.put_u64(u64::MAX);
+    }
+
+    /// Return the serialized snapshot.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Write the snapshot to the file at `path`.
+    pub fn write_to(self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.buf)
+    }
+}
+
+/// A cursor over serialized snapshot data.
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Create a reader over `data` and check the snapshot header.
+    pub fn new(data: &'a [u8]) -> io::Result<Self> {
+        let mut r = Self { data, pos: 0 };
+        if r.take(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+            return Err(invalid("not a honga snapshot"));
+        }
+        let version = r.get_u32()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(invalid(&format!(
+                "unsupported snapshot version {} (expected {})",
+                version, SNAPSHOT_VERSION
+            )));
+        }
+        Ok(r)
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() - self.pos < len {
+            return Err(invalid("truncated snapshot"));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn get_u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn get_bool(&mut self) -> io::Result<bool> {
+        Ok(self.get_u8()? != 0)
+    }
+
+    pub fn get_u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn get_u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Read a length-prefixed byte array.
+    pub fn get_bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.get_u64()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    /// Read a byte array written by `Writer::put_sparse`, of at most `max` bytes, the size of
+    /// the device it's restored to.
+    pub fn get_sparse(&mut self, max: u64) -> io::Result<Vec<u8>> {
+        let len = self.get_u64()?;
+        if len > max {
+            return Err(invalid("sparse array larger than its device"));
+        }
+        let len = len as usize;
+        let mut bytes = Vec::new();
+        bytes
+            .try_reserve_exact(len)
+            .map_err(|_| invalid("sparse array too large"))?;
+        bytes.resize(len, 0);
+        loop {
+            let index = self.get_u64()?;
+            if index == u64::MAX {
+                break;
+            }
+            let start = match (index as usize).checked_mul(CHUNK_SIZE) {
+                Some(start) if start < len => start,
+                _ => return Err(invalid("chunk out of range")),
+            };
+            let end = std::cmp::min(start + CHUNK_SIZE, len);
+            match self.get_u8()? {
+                CHUNK_FILL => {
+                    let value = self.get_u8()?;
+                    bytes[start..end].iter_mut().for_each(|b| *b = value);
+                }
+                CHUNK_PACKED => self.unpack_bits(&mut bytes[start..end])?,
+                _ => return Err(invalid("unknown chunk kind")),
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Decode PackBits data until `out` is full.
+    fn unpack_bits(&mut self, out: &mut [u8]) -> io::Result<()> {
+        let mut pos = 0;
+        while pos < out.len() {
+            let control = self.get_u8()?;
+            let len = if control < 128 {
+                control as usize + 1
+            } else {
+                257 - control as usize
+            };
+            if pos + len > out.len() {
+                return Err(invalid("run exceeds chunk"));
+            }
+            if control < 128 {
+                out[pos..pos + len].copy_from_slice(self.take(len)?);
+            } else {
+                let value = self.get_u8()?;
+                out[pos..pos + len].iter_mut().for_each(|b| *b = value);
+            }
+            pos += len;
+        }
+        Ok(())
+    }
+
+    /// Return an error unless all data has been read.
+    pub fn finish(&self) -> io::Result<()> {
+        if self.pos != self.data.len() {
+            return Err(invalid("trailing data in snapshot"));
+        }
+        Ok(())
+    }
+}
+
+/// Append `data` encoded with PackBits to `out`. A control byte n < 128 is followed by n + 1
+/// literal bytes, and a control byte n >= 128 by one byte that repeats 257 - n times.
+fn pack_bits(data: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < data.len() {
+        let mut run = 1;
+        while i + run < data.len() && run < 128 && data[i + run] == data[i] {
+            run += 1;
+        }
+        if run >= 3 {
+            out.push((257 - run) as u8);
+            out.push(data[i]);
+            i += run;
+            continue;
+        }
+        // Collect literals until the next run of three equal bytes.
+        let start = i;
+        while i < data.len() && i - start < 128 {
+            if i + 2 < data.len() && data[i] == data[i + 1] && data[i] == data[i + 2] {
+                break;
+            }
+            i += 1;
+        }
+        out.push((i - start - 1) as u8);
+        out.extend_from_slice(&data[start..i]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode `data` with PackBits and decode it again.
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let mut packed = Vec::new();
+        pack_bits(data, &mut packed);
+        let mut r = Reader {
+            data: &packed,
+            pos: 0,
+        };
+        let mut out = vec![0; data.len()];
+        r.unpack_bits(&mut out).unwrap();
+        r.finish().unwrap();
+        out
+    }
+
+    /// Write `bytes` as a sparse array and read them back from a device of `max` bytes.
+    fn sparse_round_trip(bytes: &[u8], max: u64) -> io::Result<Vec<u8>> {
+        let mut w = Writer::new();
+        w.put_sparse(bytes);
+        let mut r = Reader::new(w.as_bytes())?;
+        let bytes = r.get_sparse(max)?;
+        r.finish()?;
+        Ok(bytes)
+    }
+
+    #[test]
+    fn pack_bits_round_trips() {
+        assert!(round_trip(&[]).is_empty());
+        // Runs of up to 128 bytes take two bytes, and longer ones are split.
+        for len in [3, 127, 128, 129, 300] {
+            let data = vec![0xab; len];
+            let mut packed = Vec::new();
+            pack_bits(&data, &mut packed);
+            assert_eq!(packed.len(), 2 * len.div_ceil(128), "run of {}", len);
+            assert_eq!(round_trip(&data), data);
+        }
+        // Literals, also more than 128 of them, and literals between runs.
+        let literals: Vec<u8> = (0..300).map(|i| (i * 7) as u8).collect();
+        assert_eq!(round_trip(&literals), literals);
+        let mixed = [&[1, 2][..], &[5; 200], &[3, 3, 4], &[6; 3], &[7]].concat();
+        assert_eq!(round_trip(&mixed), mixed);
+    }
+
+    #[test]
+    fn corrupt_pack_bits_are_rejected() {
+        let mut out = [0; 4];
+        // A literal run longer than the output.
+        let mut r = Reader {
+            data: &[4, 1, 2, 3, 4, 5],
+            pos: 0,
+        };
+        assert!(r.unpack_bits(&mut out).is_err());
+        // A repeated run longer than the output.
+        let mut r = Reader {
+            data: &[(257 - 5) as u8, 9],
+            pos: 0,
+        };
+        assert!(r.unpack_bits(&mut out).is_err());
+        // Literals cut short.
+        let mut r = Reader {
+            data: &[3, 1, 2],
+            pos: 0,
+        };
+        assert!(r.unpack_bits(&mut out).is_err());
+    }
+
+    #[test]
+    fn sparse_arrays_round_trip() {
+        assert!(sparse_round_trip(&[], 0).unwrap().is_empty());
+        // Zero, filled and packed chunks, and a last chunk that ends partway.
+        let mut bytes = vec![0; 3 * CHUNK_SIZE + 100];
+        bytes[CHUNK_SIZE..2 * CHUNK_SIZE].fill(0x55);
+        bytes[2 * CHUNK_SIZE + 10] = 1;
+        bytes[3 * CHUNK_SIZE + 99] = 2;
+        let len = bytes.len() as u64;
+        assert_eq!(sparse_round_trip(&bytes, len).unwrap(), bytes);
+        // A device too small for the array.
+        assert!(sparse_round_trip(&bytes, len - 1).is_err());
+    }
+
+    #[test]
+    fn corrupt_sparse_arrays_are_rejected() {
+        let sparse = |len: u64, index: u64, chunk: &[u8]| {
+            let mut w = Writer::new();
+            w.put_u64(len);
+            w.put_u64(index);
+            w.buf.extend_from_slice(chunk);
+            w.put_u64(u64::MAX);
+            let mut r = Reader::new(w.as_bytes()).unwrap();
+            r.get_sparse(u64::MAX)
+        };
+        assert_eq!(
+            sparse(CHUNK_SIZE as u64, 0, &[CHUNK_FILL, 7]).unwrap(),
+            vec![7; CHUNK_SIZE]
+        );
+        // A chunk past the end, also one whose offset overflows.
+        assert!(sparse(CHUNK_SIZE as u64, 1, &[CHUNK_FILL, 7]).is_err());
+        assert!(sparse(CHUNK_SIZE as u64, u64::MAX / 2, &[CHUNK_FILL, 7]).is_err());
+        // An unknown kind of chunk.
+        assert!(sparse(CHUNK_SIZE as u64, 0, &[9, 7]).is_err());
+        // A length that can't be allocated.
+        assert!(sparse(u64::MAX / 2, 0, &[CHUNK_FILL, 7]).is_err());
+        // A stream that ends early.
+        let mut w = Writer::new();
+        w.put_sparse(&[1, 2, 3]);
+        let data = w.as_bytes();
+        let mut r = Reader::new(&data[..data.len() - 1]).unwrap();
+        assert!(r.get_sparse(3).is_err());
+    }
+}
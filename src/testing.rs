@@ -0,0 +1,59 @@
+//! The testing module holds helpers that the unit tests of several modules share.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::bus::{UartBackend, MEMORY_BASE};
+use crate::machine::Machine;
+
+/// A directory of its own for a test, removed when it's dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Create an empty directory for the test `name`, which must be unique among the tests.
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("honga-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    /// Return the path of `name` in the directory.
+    pub fn join(&self, name: impl AsRef<Path>) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Where the program of `counting_machine` stores its counter.
+pub const COUNTER: u64 = MEMORY_BASE + 0x1000;
+/// The address of the store of `counting_machine`.
+pub const COUNTER_STORE: u64 = MEMORY_BASE + 8;
+
+/// Return a traced machine with 1 MiB of RAM that counts in x5 forever and stores every count to `COUNTER`.
+/// Instruction 0 sets x6 to `COUNTER`; from then on the loop `addi`, `sd`, `j` repeats, so the
+/// store is at `COUNTER_STORE` and runs as instructions 3n + 2, leaving x5 = n + 1.
+pub fn counting_machine() -> Machine {
+    let code: [u32; 4] = [
+        // auipc t1, 1
+        (1 << 12) | (6 << 7) | 0x17,
+        // addi t0, t0, 1
+        (1 << 20) | (5 << 15) | (5 << 7) | 0x13,
+        // sd t0, 0(t1)
+        (5 << 20) | (6 << 15) | (0x3 << 12) | 0x23,
+        // j -8
+        0xff9ff06f,
+    ];
+    Machine::builder()
+        .kernel(code.iter().flat_map(|inst| inst.to_le_bytes()).collect())
+        .ram(0x10_0000)
+        .uart(UartBackend::Null)
+        .trace(true)
+        .build()
+        .unwrap()
+}
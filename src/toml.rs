@@ -0,0 +1,316 @@
+//! The toml module parses the subset of TOML that machine configurations use: tables, arrays of
+//! tables, bare keys, strings, integers, booleans and arrays. Everything is kept in file order
+//! with line numbers, so that errors can point at the offending line.
+
+/// A value on the right-hand side of `key = value`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// Return the TOML name of the type of the value, for error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) => "an array",
+        }
+    }
+}
+
+/// A `key = value` pair and the line it's on.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub key: String,
+    pub value: Value,
+    pub line: usize,
+}
+
+/// A `[table]` or an element of an `[[array]]` of tables. The root table has an empty name.
+#[derive(Debug, Clone)]
+pub struct Table {
+    pub name: String,
+    /// True if the header was `[[name]]`.
+    pub array: bool,
+    pub line: usize,
+    pub entries: Vec<Entry>,
+}
+
+/// A syntax error: the line number and a description.
+pub type Error = (usize, String);
+
+/// Parse `text` into the root table followed by the other tables in file order.
+pub fn parse(text: &str) -> Result<Vec<Table>, Error> {
+    let mut parser = Parser {
+        text: text.as_bytes(),
+        pos: 0,
+        line: 1,
+    };
+    let mut tables = vec![Table {
+        name: String::new(),
+        array: false,
+        line: 1,
+        entries: Vec::new(),
+    }];
+    loop {
+        parser.skip_blank();
+        let line = parser.line;
+        match parser.peek() {
+            None => break,
+            Some(b'[') => {
+                let table = parser.header()?;
+                let redefined = !table.array && tables.iter().any(|t| t.name == table.name)
+                    || table.array && tables.iter().any(|t| t.name == table.name && !t.array);
+                if redefined || table.name.is_empty() {
+                    return Err((line, format!("table `{}` is defined twice", table.name)));
+                }
+                tables.push(table);
+            }
+            Some(_) => {
+                let entry = parser.entry()?;
+                let table = tables.last_mut().unwrap();
+                if table.entries.iter().any(|e| e.key == entry.key) {
+                    return Err((line, format!("key `{}` is defined twice", entry.key)));
+                }
+                table.entries.push(entry);
+            }
+        }
+        parser.end_of_line()?;
+    }
+    Ok(tables)
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == b'\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, Error> {
+        Err((self.line, message.into()))
+    }
+
+    /// Skip spaces and tabs.
+    fn skip_space(&mut self) {
+        while let Some(b' ' | b'\t') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    /// Skip whitespace, newlines and comments.
+    fn skip_blank(&mut self) {
+        loop {
+            match self.peek() {
+                Some(b' ' | b'\t' | b'\r' | b'\n') => {
+                    self.next();
+                }
+                Some(b'#') => {
+                    while !matches!(self.peek(), None | Some(b'\n')) {
+                        self.pos += 1;
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Expect nothing but a comment until the end of the line.
+    fn end_of_line(&mut self) -> Result<(), Error> {
+        self.skip_space();
+        match self.peek() {
+            None | Some(b'\n' | b'#') => Ok(()),
+            Some(b'\r') if self.text.get(self.pos + 1) == Some(&b'\n') => Ok(()),
+            Some(c) => self.error(format!("unexpected `{}` after the value", c as char)),
+        }
+    }
+
+    fn key(&mut self) -> Result<String, Error> {
+        let start = self.pos;
+        while let Some(b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'_' | b'-') = self.peek() {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return match self.peek() {
+                Some(b'"' | b'\'') => self.error("quoted keys are not supported"),
+                _ => self.error("expected a key"),
+            };
+        }
+        let key = String::from_utf8_lossy(&self.text[start..self.pos]).into_owned();
+        self.skip_space();
+        if self.peek() == Some(b'.') {
+            return self.error("dotted keys are not supported");
+        }
+        Ok(key)
+    }
+
+    fn header(&mut self) -> Result<Table, Error> {
+        let line = self.line;
+        self.next();
+        let array = self.peek() == Some(b'[');
+        if array {
+            self.next();
+        }
+        self.skip_space();
+        let name = self.key()?;
+        for _ in 0..(1 + array as usize) {
+            if self.next() != Some(b']') {
+                return self.error(format!("expected `]` after the table name `{}`", name));
+            }
+        }
+        Ok(Table {
+            name,
+            array,
+            line,
+            entries: Vec::new(),
+        })
+    }
+
+    fn entry(&mut self) -> Result<Entry, Error> {
+        let line = self.line;
+        let key = self.key()?;
+        if self.next() != Some(b'=') {
+            return Err((line, format!("expected `=` after `{}`", key)));
+        }
+        self.skip_space();
+        let value = self.value()?;
+        Ok(Entry { key, value, line })
+    }
+
+    fn value(&mut self) -> Result<Value, Error> {
+        match self.peek() {
+            Some(b'"') => self.basic_string().map(Value::String),
+            Some(b'\'') => self.literal_string().map(Value::String),
+            Some(b'[') => self.array().map(Value::Array),
+            Some(b'+' | b'-' | b'0'..=b'9') => self.integer().map(Value::Integer),
+            Some(b'a'..=b'z') => {
+                let start = self.pos;
+                while let Some(b'a'..=b'z') = self.peek() {
+                    self.pos += 1;
+                }
+                match &self.text[start..self.pos] {
+                    b"true" => Ok(Value::Boolean(true)),
+                    b"false" => Ok(Value::Boolean(false)),
+                    b"inf" | b"nan" => self.error("floats are not supported"),
+                    word => self.error(format!(
+                        "expected a value, found `{}`; strings must be quoted",
+                        String::from_utf8_lossy(word)
+                    )),
+                }
+            }
+            Some(b'{') => self.error("inline tables are not supported"),
+            _ => self.error("expected a value"),
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String, Error> {
+        self.next();
+        if self.text[self.pos..].starts_with(b"\"\"") {
+            return self.error("multi-line strings are not supported");
+        }
+        let mut string = Vec::new();
+        loop {
+            match self.next() {
+                None | Some(b'\n') => return self.error("unterminated string"),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    let c = match self.next() {
+                        Some(b'n') => b'\n',
+                        Some(b't') => b'\t',
+                        Some(b'r') => b'\r',
+                        Some(b'\\') => b'\\',
+                        Some(b'"') => b'"',
+                        _ => return self.error("unsupported escape sequence in string"),
+                    };
+                    string.push(c);
+                }
+                Some(c) => string.push(c),
+            }
+        }
+        String::from_utf8(string).or_else(|_| self.error("string is not valid UTF-8"))
+    }
+
+    fn literal_string(&mut self) -> Result<String, Error> {
+        self.next();
+        let start = self.pos;
+        loop {
+            match self.next() {
+                None | Some(b'\n') => return self.error("unterminated string"),
+                Some(b'\'') => break,
+                Some(_) => {}
+            }
+        }
+        String::from_utf8(self.text[start..self.pos - 1].to_vec())
+            .or_else(|_| self.error("string is not valid UTF-8"))
+    }
+
+    fn integer(&mut self) -> Result<i64, Error> {
+        let start = self.pos;
+        while let Some(b'+' | b'-' | b'_' | b'.' | b'0'..=b'9' | b'a'..=b'z' | b'A'..=b'Z') =
+            self.peek()
+        {
+            self.pos += 1;
+        }
+        let word = String::from_utf8_lossy(&self.text[start..self.pos]).replace('_', "");
+        let (negative, digits) = match word.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, word.strip_prefix('+').unwrap_or(&word)),
+        };
+        let (radix, digits) = match digits.get(..2) {
+            Some("0x") => (16, &digits[2..]),
+            Some("0o") => (8, &digits[2..]),
+            Some("0b") => (2, &digits[2..]),
+            _ => (10, digits),
+        };
+        let value = match i64::from_str_radix(digits, radix) {
+            Ok(value) if digits.starts_with(|c: char| c.is_ascii_alphanumeric()) => value,
+            _ if radix == 10 && digits.contains(['.', 'e', 'E']) => {
+                return self.error("floats are not supported")
+            }
+            _ => {
+                return self.error(format!(
+                    "invalid number `{}`; sizes with a suffix must be quoted, e.g. \"128M\"",
+                    word
+                ))
+            }
+        };
+        Ok(if negative { -value } else { value })
+    }
+
+    fn array(&mut self) -> Result<Vec<Value>, Error> {
+        self.next();
+        let mut values = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.peek() == Some(b']') {
+                self.next();
+                return Ok(values);
+            }
+            values.push(self.value()?);
+            self.skip_blank();
+            match self.next() {
+                Some(b',') => {}
+                Some(b']') => return Ok(values),
+                _ => return self.error("expected `,` or `]` in array"),
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! The trace module writes a commit log: one line per retired instruction, in the format of
+//! Spike's `--log-commits` so that the two logs can be compared line by line.
+//!
+//! A line looks like:
+//!
+//! ```text
+//! core   0: 3 0x0000000080000010 (0x00b53023) mem 0x0000000080001000 0x0000000000000000
+//! ```
+//!
+//! i.e. the privilege mode, the pc, the instruction word, every register and CSR written, and
+//! every memory address accessed. Stores are followed by the stored value. By default the
+//! disassembly of the instruction is appended after ` ; `, which can be turned off to get lines
+//! that are byte-identical to Spike's.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::cpu::Cpu;
+use crate::csr::csr_name;
+use crate::disasm::disassemble;
+
+/// Kind of a register written by an instruction. The value is the low nibble of a key in
+/// `Commit::writes`, the same encoding that Spike uses to order its log.
+const KIND_X: u64 = 0;
+const KIND_CSR: u64 = 4;
+
+/// Architectural side effects of the instruction currently being executed.
+#[derive(Debug, Default, Clone)]
+pub struct Commit {
+    /// Register and CSR writes keyed by `(index << 4) | kind`.
+    pub writes: BTreeMap<u64, u64>,
+    /// Memory reads: (virtual address, size in bits).
+    pub loads: Vec<(u64, usize)>,
+    /// Memory writes: (virtual address, value, size in bits).
+    pub stores: Vec<(u64, u64, usize)>,
+}
+
+impl Commit {
+    /// Forget the side effects of the previous instruction.
+    pub fn clear(&mut self) {
+        self.writes.clear();
+        self.loads.clear();
+        self.stores.clear();
+    }
+
+    /// Record a write to the integer register `reg`. Writes to x0 are not recorded.
+    pub fn write_reg(&mut self, reg: usize, value: u64) {
+        if reg != 0 {
+            self.writes.insert(((reg as u64) << 4) | KIND_X, value);
+        }
+    }
+
+    /// Record a memory write of the low `size` bits of `value`.
+    pub fn write_mem(&mut self, addr: u64, value: u64, size: usize) {
+        let mask = if size >= 64 { !0 } else { (1 << size) - 1 };
+        self.stores.push((addr, value & mask, size));
+    }
+
+    /// Record a write to the CSR at `addr`.
+    pub fn write_csr(&mut self, addr: usize, value: u64) {
+        self.writes.insert(((addr as u64) << 4) | KIND_CSR, value);
+    }
+}
+
+/// Return the destination register of `inst` if the instruction writes an integer register.
+pub fn destination(inst: u32) -> Option<usize> {
+    let opcode = inst & 0x7f;
+    let rd = ((inst >> 7) & 0x1f) as usize;
+    let funct3 = (inst >> 12) & 0x7;
+    match opcode {
+        0x03 | 0x13 | 0x17 | 0x1b | 0x2f | 0x33 | 0x37 | 0x3b | 0x67 | 0x6f => Some(rd),
+        0x73 if funct3 != 0 => Some(rd),
+        // The comparisons, conversions to integers, fmv.x and fclass.
+        0x53 if matches!(inst >> 27, 0x14 | 0x18 | 0x1c) => Some(rd),
+        // vsetvl*, and vmv.x.s, vcpop.m and vfirst.m.
+        0x57 if funct3 == 0x7 || (funct3 == 0x2 && inst >> 26 == 0x10) => Some(rd),
+        _ => None,
+    }
+}
+
+/// Format `value` as a hexadecimal number with as many digits as `bits` needs, like Spike.
+fn hex(bits: usize, value: u64) -> String {
+    match bits {
+        8 => format!("0x{:02x}", value as u8),
+        16 => format!("0x{:04x}", value as u16),
+        32 => format!("0x{:08x}", value as u32),
+        _ => format!("0x{:016x}", value),
+    }
+}
+
+/// Format one commit-log line for the instruction `inst` at `pc` that has just retired on `cpu`.
+/// The line has no trailing newline.
+pub fn format_commit(cpu: &Cpu, hart: u64, pc: u64, inst: u32, commit: &Commit) -> String {
+    let mut line = format!(
+        "core{:4}: {} {} ({})",
+        hart,
+        cpu.mode as u8,
+        hex(64, pc),
+        hex(32, inst as u64)
+    );
+    for (key, value) in commit.writes.iter() {
+        let index = key >> 4;
+        match key & 0xf {
+            KIND_CSR => line.push_str(&format!(
+                " c{}_{} {}",
+                index,
+                csr_name(index as usize),
+                hex(64, *value)
+            )),
+            _ => line.push_str(&format!(" x{:<2} {}", index, hex(64, *value))),
+        }
+    }
+    for (addr, _) in commit.loads.iter() {
+        line.push_str(&format!(" mem {}", hex(64, *addr)));
+    }
+    for (addr, value, size) in commit.stores.iter() {
+        line.push_str(&format!(" mem {} {}", hex(64, *addr), hex(*size, *value)));
+    }
+    line
+}
+
+/// A commit log written to a file.
+pub struct CommitLog {
+    out: BufWriter<File>,
+    /// Append the disassembly of each instruction to its line.
+    disassemble: bool,
+}
+
+impl CommitLog {
+    /// Create the log file at `path`, truncating an existing one.
+    pub fn create(path: &Path, disassemble: bool) -> io::Result<Self> {
+        Ok(Self {
+            out: BufWriter::new(File::create(path)?),
+            disassemble,
+        })
+    }
+
+    /// Write the line for the instruction `inst` at `pc` that has just retired on `cpu`.
+    pub fn write(&mut self, cpu: &Cpu, pc: u64, inst: u32) -> io::Result<()> {
+        let commit = match &cpu.commit {
+            Some(commit) => commit,
+            None => return Ok(()),
+        };
+        let line = format_commit(cpu, cpu.hart() as u64, pc, inst, commit);
+        if self.disassemble {
+            writeln!(self.out, "{} ; {}", line, disassemble(inst))
+        } else {
+            writeln!(self.out, "{}", line)
+        }
+    }
+
+    /// Flush buffered lines to the file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
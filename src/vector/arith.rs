@@ -0,0 +1,646 @@
+//! Integer, fixed-point, mask, reduction and permutation instructions: OPIVV, OPIVX, OPIVI,
+//! OPMVV and OPMVX.
+
+use super::{mask, require, sext, Inst, Operand, VType};
+use crate::cpu::Cpu;
+use crate::csr::{VL, VSTART, VXRM, VXSAT};
+use crate::exception::Exception;
+
+/// Return `value` shifted right by `shift` bits and rounded according to the fixed-point
+/// rounding mode `vxrm`: to nearest up, to nearest even, down or to odd.
+fn roundoff(value: i128, shift: usize, vxrm: u64) -> i128 {
+    if shift == 0 {
+        return value;
+    }
+    let lsb = (value >> shift) & 1;
+    let half = (value >> (shift - 1)) & 1;
+    let rest = (value & ((1 << (shift - 1)) - 1) != 0) as i128;
+    let increment = match vxrm & 0x3 {
+        0 => half,
+        1 => half & (rest | lsb),
+        2 => 0,
+        _ => (lsb ^ 1) & (half | rest),
+    };
+    (value >> shift) + increment
+}
+
+/// Return `value` saturated to a signed integer of `eew` bits, and set `sat` if it saturates.
+fn clamp_signed(value: i128, eew: usize, sat: &mut bool) -> u64 {
+    let max = (1i128 << (eew - 1)) - 1;
+    let min = -(1i128 << (eew - 1));
+    if value > max || value < min {
+        *sat = true;
+    }
+    value.clamp(min, max) as u64 & mask(eew)
+}
+
+/// Return `value` saturated to an unsigned integer of `eew` bits, and set `sat` if it saturates.
+fn clamp_unsigned(value: i128, eew: usize, sat: &mut bool) -> u64 {
+    let max = mask(eew) as i128;
+    if value > max || value < 0 {
+        *sat = true;
+    }
+    value.clamp(0, max) as u64
+}
+
+impl Cpu {
+    /// Execute an OPIVV, OPIVX or OPIVI instruction.
+    pub(super) fn execute_opi(&mut self, inst: &Inst, t: VType) -> Result<(), Exception> {
+        let sew = t.sew;
+        let m = mask(sew);
+        let (vv, vi) = (inst.funct3 == 0x0, inst.funct3 == 0x3);
+        let x = self.regs[inst.vs1];
+        let b = match inst.funct3 {
+            0x0 => Operand::Vector(inst.vs1),
+            0x4 => Operand::Scalar(x & m),
+            _ => Operand::Scalar(sext(inst.vs1 as u64, 5) as u64 & m),
+        };
+        // Shifts, slides and vrgather take an unsigned immediate, and the whole of x[rs1].
+        let unsigned = match inst.funct3 {
+            0x0 => b,
+            0x4 => Operand::Scalar(x),
+            _ => Operand::Scalar(inst.vs1 as u64),
+        };
+        let offset = if vi { inst.vs1 as u64 } else { x };
+        let same = [sew; 3];
+        let masked = !inst.vm;
+        let vxrm = self.csr[VXRM];
+        let mut sat = false;
+        let result = match inst.funct6 {
+            // vadd, vsub, vrsub
+            0x00 => self.elementwise(inst, t, same, b, masked, |a, b, _, _| a.wrapping_add(b) & m),
+            0x02 if !vi => {
+                self.elementwise(inst, t, same, b, masked, |a, b, _, _| a.wrapping_sub(b) & m)
+            }
+            0x03 if !vv => {
+                self.elementwise(inst, t, same, b, masked, |a, b, _, _| b.wrapping_sub(a) & m)
+            }
+            // vminu, vmin, vmaxu, vmax
+            0x04 if !vi => self.elementwise(inst, t, same, b, masked, |a, b, _, _| a.min(b)),
+            0x05 if !vi => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                sext(a, sew).min(sext(b, sew)) as u64 & m
+            }),
+            0x06 if !vi => self.elementwise(inst, t, same, b, masked, |a, b, _, _| a.max(b)),
+            0x07 if !vi => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                sext(a, sew).max(sext(b, sew)) as u64 & m
+            }),
+            // vand, vor, vxor
+            0x09 => self.elementwise(inst, t, same, b, masked, |a, b, _, _| a & b),
+            0x0a => self.elementwise(inst, t, same, b, masked, |a, b, _, _| a | b),
+            0x0b => self.elementwise(inst, t, same, b, masked, |a, b, _, _| a ^ b),
+            // vrgather, vrgatherei16
+            0x0c => self.gather(inst, t, unsigned, sew),
+            0x0e if vv => self.gather(inst, t, b, 16),
+            // vslideup, vslidedown
+            0x0e => self.slide_up(inst, t, offset),
+            0x0f if !vv => self.slide_down(inst, t, offset),
+            // vadc takes the carry from v0, and vmadc too unless it's unmasked.
+            0x10 if masked => self.elementwise(inst, t, same, b, false, |a, b, _, carry| {
+                a.wrapping_add(b).wrapping_add(carry as u64) & m
+            }),
+            0x11 => self.compare(inst, t, sew, b, false, |a, b, carry| {
+                let sum = a as u128 + b as u128 + (masked && carry) as u128;
+                (sum >> sew) & 1 != 0
+            }),
+            // vsbc, vmsbc
+            0x12 if !vi && masked => {
+                self.elementwise(inst, t, same, b, false, |a, b, _, borrow| {
+                    a.wrapping_sub(b).wrapping_sub(borrow as u64) & m
+                })
+            }
+            0x13 if !vi => self.compare(inst, t, sew, b, false, |a, b, borrow| {
+                (a as u128) < b as u128 + (masked && borrow) as u128
+            }),
+            // vmerge, and vmv.v if it's unmasked.
+            0x17 if masked => self.elementwise(
+                inst,
+                t,
+                same,
+                b,
+                false,
+                |a, b, _, select| {
+                    if select {
+                        b
+                    } else {
+                        a
+                    }
+                },
+            ),
+            0x17 => {
+                require(inst.vs2 == 0)?;
+                self.elementwise(inst, t, same, b, false, |_, b, _, _| b)
+            }
+            // The integer comparisons.
+            0x18 => self.compare(inst, t, sew, b, masked, |a, b, _| a == b),
+            0x19 => self.compare(inst, t, sew, b, masked, |a, b, _| a != b),
+            0x1a if !vi => self.compare(inst, t, sew, b, masked, |a, b, _| a < b),
+            0x1b if !vi => self.compare(inst, t, sew, b, masked, |a, b, _| {
+                sext(a, sew) < sext(b, sew)
+            }),
+            0x1c => self.compare(inst, t, sew, b, masked, |a, b, _| a <= b),
+            0x1d => self.compare(inst, t, sew, b, masked, |a, b, _| {
+                sext(a, sew) <= sext(b, sew)
+            }),
+            0x1e if !vv => self.compare(inst, t, sew, b, masked, |a, b, _| a > b),
+            0x1f if !vv => self.compare(inst, t, sew, b, masked, |a, b, _| {
+                sext(a, sew) > sext(b, sew)
+            }),
+            // vsaddu, vsadd, vssubu, vssub
+            0x20 => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                clamp_unsigned(a as i128 + b as i128, sew, &mut sat)
+            }),
+            0x21 => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                clamp_signed(sext(a, sew) as i128 + sext(b, sew) as i128, sew, &mut sat)
+            }),
+            0x22 if !vi => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                clamp_unsigned(a as i128 - b as i128, sew, &mut sat)
+            }),
+            0x23 if !vi => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                clamp_signed(sext(a, sew) as i128 - sext(b, sew) as i128, sew, &mut sat)
+            }),
+            // vsll
+            0x25 => self.elementwise(inst, t, same, unsigned, masked, |a, b, _, _| {
+                (a << (b as usize & (sew - 1))) & m
+            }),
+            // vsmul
+            0x27 if !vi => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                let product = sext(a, sew) as i128 * sext(b, sew) as i128;
+                clamp_signed(roundoff(product, sew - 1, vxrm), sew, &mut sat)
+            }),
+            // vsrl, vsra
+            0x28 => self.elementwise(inst, t, same, unsigned, masked, |a, b, _, _| {
+                a >> (b as usize & (sew - 1))
+            }),
+            0x29 => self.elementwise(inst, t, same, unsigned, masked, |a, b, _, _| {
+                (sext(a, sew) >> (b as usize & (sew - 1))) as u64 & m
+            }),
+            // vssrl, vssra
+            0x2a => self.elementwise(inst, t, same, unsigned, masked, |a, b, _, _| {
+                roundoff(a as i128, b as usize & (sew - 1), vxrm) as u64 & m
+            }),
+            0x2b => self.elementwise(inst, t, same, unsigned, masked, |a, b, _, _| {
+                roundoff(sext(a, sew) as i128, b as usize & (sew - 1), vxrm) as u64 & m
+            }),
+            // vnsrl, vnsra, vnclipu, vnclip
+            0x2c..=0x2f => {
+                require(2 * sew <= self.isa.elen())?;
+                let wide = 2 * sew;
+                let eew = [sew, wide, sew];
+                let funct6 = inst.funct6;
+                self.elementwise(inst, t, eew, unsigned, masked, |a, b, _, _| {
+                    let shift = b as usize & (wide - 1);
+                    match funct6 {
+                        0x2c => (a >> shift) & m,
+                        0x2d => (sext(a, wide) >> shift) as u64 & m,
+                        0x2e => clamp_unsigned(roundoff(a as i128, shift, vxrm), sew, &mut sat),
+                        _ => {
+                            let value = roundoff(sext(a, wide) as i128, shift, vxrm);
+                            clamp_signed(value, sew, &mut sat)
+                        }
+                    }
+                })
+            }
+            // vwredsumu, vwredsum
+            0x30 if vv => self.reduce(inst, t, sew, 2 * sew, |acc, a| acc.wrapping_add(a)),
+            0x31 if vv => self.reduce(inst, t, sew, 2 * sew, |acc, a| {
+                acc.wrapping_add(sext(a, sew) as u64) & mask(2 * sew)
+            }),
+            _ => {
+                println!(
+                    "Unsupported instruction: opcode {:x} funct3 {:x} funct6 {:x}",
+                    0x57, inst.funct3, inst.funct6
+                );
+                Err(Exception::IllegalInstruction)
+            }
+        };
+        if sat {
+            self.csr[VXSAT] = 1;
+            if let Some(commit) = &mut self.commit {
+                commit.write_csr(VXSAT, 1);
+            }
+        }
+        result
+    }
+
+    /// Execute an OPMVV or OPMVX instruction.
+    pub(super) fn execute_opm(&mut self, inst: &Inst, t: VType) -> Result<(), Exception> {
+        let sew = t.sew;
+        let m = mask(sew);
+        let vv = inst.funct3 == 0x2;
+        let x = self.regs[inst.vs1];
+        let b = if vv {
+            Operand::Vector(inst.vs1)
+        } else {
+            Operand::Scalar(x & m)
+        };
+        let same = [sew; 3];
+        let wide = 2 * sew;
+        let wm = mask(wide.min(64));
+        let masked = !inst.vm;
+        let vxrm = self.csr[VXRM];
+        let signed = |value: u64| sext(value, sew) as i128;
+        match inst.funct6 {
+            // vredsum, vredand, vredor, vredxor, vredminu, vredmin, vredmaxu, vredmax
+            0x00..=0x07 if vv => {
+                let funct6 = inst.funct6;
+                self.reduce(inst, t, sew, sew, |acc, a| match funct6 {
+                    0x00 => acc.wrapping_add(a) & m,
+                    0x01 => acc & a,
+                    0x02 => acc | a,
+                    0x03 => acc ^ a,
+                    0x04 => acc.min(a),
+                    0x05 => sext(acc, sew).min(sext(a, sew)) as u64 & m,
+                    0x06 => acc.max(a),
+                    _ => sext(acc, sew).max(sext(a, sew)) as u64 & m,
+                })
+            }
+            // vaaddu, vaadd, vasubu, vasub
+            0x08 => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                roundoff(a as i128 + b as i128, 1, vxrm) as u64 & m
+            }),
+            0x09 => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                roundoff(signed(a) + signed(b), 1, vxrm) as u64 & m
+            }),
+            0x0a => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                roundoff(a as i128 - b as i128, 1, vxrm) as u64 & m
+            }),
+            0x0b => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                roundoff(signed(a) - signed(b), 1, vxrm) as u64 & m
+            }),
+            // vslide1up, vslide1down
+            0x0e if !vv => self.slide1_up(inst, t, x & m),
+            0x0f if !vv => self.slide1_down(inst, t, x & m),
+            0x10 if vv => self.execute_vwxunary0(inst, t),
+            // vmv.s.x
+            0x10 if inst.vs2 == 0 && inst.vm => {
+                let (start, vl) = self.body();
+                if start < vl {
+                    self.set_velement(inst.vd, 0, sew, x);
+                }
+                Ok(())
+            }
+            // vzext, vsext
+            0x12 if vv && (0x2..=0x7).contains(&inst.vs1) => {
+                let factor = 8 >> ((inst.vs1 - 2) / 2);
+                let extend_signed = inst.vs1 & 1 != 0;
+                let eew = sew / factor;
+                require(eew >= 8)?;
+                let b = Operand::Scalar(0);
+                self.elementwise(inst, t, [sew, eew, sew], b, masked, |a, _, _, _| {
+                    if extend_signed {
+                        sext(a, eew) as u64 & m
+                    } else {
+                        a
+                    }
+                })
+            }
+            0x14 if vv => self.execute_vmunary0(inst, t),
+            0x17 if vv && inst.vm => self.compress(inst, t),
+            // vmandn, vmand, vmor, vmxor, vmorn, vmnand, vmnor, vmxnor
+            0x18..=0x1f if vv && inst.vm => {
+                let (start, vl) = self.body();
+                for i in start..vl {
+                    let a = self.vmask(inst.vs2, i);
+                    let b = self.vmask(inst.vs1, i);
+                    let bit = match inst.funct6 {
+                        0x18 => a & !b,
+                        0x19 => a & b,
+                        0x1a => a | b,
+                        0x1b => a ^ b,
+                        0x1c => a | !b,
+                        0x1d => !(a & b),
+                        0x1e => !(a | b),
+                        _ => !(a ^ b),
+                    };
+                    self.set_vmask(inst.vd, i, bit);
+                }
+                Ok(())
+            }
+            // vdivu, vdiv, vremu, vrem divide by zero and overflow without trapping.
+            0x20 => self.elementwise(inst, t, same, b, masked, |a, b, _, _| match b {
+                0 => m,
+                _ => a / b,
+            }),
+            0x21 => self.elementwise(inst, t, same, b, masked, |a, b, _, _| match b {
+                0 => m,
+                _ => sext(a, sew).wrapping_div(sext(b, sew)) as u64 & m,
+            }),
+            0x22 => self.elementwise(inst, t, same, b, masked, |a, b, _, _| match b {
+                0 => a,
+                _ => a % b,
+            }),
+            0x23 => self.elementwise(inst, t, same, b, masked, |a, b, _, _| match b {
+                0 => a,
+                _ => sext(a, sew).wrapping_rem(sext(b, sew)) as u64 & m,
+            }),
+            // vmulhu, vmul, vmulhsu, vmulh
+            0x24 => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                ((a as u128 * b as u128) >> sew) as u64 & m
+            }),
+            0x25 => self.elementwise(inst, t, same, b, masked, |a, b, _, _| a.wrapping_mul(b) & m),
+            0x26 => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                ((signed(a) * b as i128) >> sew) as u64 & m
+            }),
+            0x27 => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                ((signed(a) * signed(b)) >> sew) as u64 & m
+            }),
+            // vmadd, vnmsub, vmacc, vnmsac
+            0x29 => self.elementwise(inst, t, same, b, masked, |a, b, d, _| {
+                b.wrapping_mul(d).wrapping_add(a) & m
+            }),
+            0x2b => self.elementwise(inst, t, same, b, masked, |a, b, d, _| {
+                a.wrapping_sub(b.wrapping_mul(d)) & m
+            }),
+            0x2d => self.elementwise(inst, t, same, b, masked, |a, b, d, _| {
+                d.wrapping_add(b.wrapping_mul(a)) & m
+            }),
+            0x2f => self.elementwise(inst, t, same, b, masked, |a, b, d, _| {
+                d.wrapping_sub(b.wrapping_mul(a)) & m
+            }),
+            // The widening instructions.
+            0x30..=0x3f => {
+                require(wide <= self.isa.elen())?;
+                let narrow = [wide, sew, sew];
+                match inst.funct6 {
+                    // vwaddu, vwadd, vwsubu, vwsub
+                    0x30 => self.elementwise(inst, t, narrow, b, masked, |a, b, _, _| a + b),
+                    0x31 => self.elementwise(inst, t, narrow, b, masked, |a, b, _, _| {
+                        (signed(a) + signed(b)) as u64 & wm
+                    }),
+                    0x32 => self.elementwise(inst, t, narrow, b, masked, |a, b, _, _| {
+                        a.wrapping_sub(b) & wm
+                    }),
+                    0x33 => self.elementwise(inst, t, narrow, b, masked, |a, b, _, _| {
+                        (signed(a) - signed(b)) as u64 & wm
+                    }),
+                    // vwaddu.w, vwadd.w, vwsubu.w, vwsub.w
+                    0x34..=0x37 => {
+                        let funct6 = inst.funct6;
+                        let eew = [wide, wide, sew];
+                        self.elementwise(inst, t, eew, b, masked, |a, b, _, _| {
+                            let b = if funct6 & 1 != 0 {
+                                sext(b, sew) as u64
+                            } else {
+                                b
+                            };
+                            if funct6 < 0x36 {
+                                a.wrapping_add(b) & wm
+                            } else {
+                                a.wrapping_sub(b) & wm
+                            }
+                        })
+                    }
+                    // vwmulu, vwmulsu, vwmul
+                    0x38 => self.elementwise(inst, t, narrow, b, masked, |a, b, _, _| {
+                        a.wrapping_mul(b) & wm
+                    }),
+                    0x3a => self.elementwise(inst, t, narrow, b, masked, |a, b, _, _| {
+                        (signed(a) * b as i128) as u64 & wm
+                    }),
+                    0x3b => self.elementwise(inst, t, narrow, b, masked, |a, b, _, _| {
+                        (signed(a) * signed(b)) as u64 & wm
+                    }),
+                    // vwmaccu, vwmacc, vwmaccus, vwmaccsu
+                    0x3c => self.elementwise(inst, t, narrow, b, masked, |a, b, d, _| {
+                        d.wrapping_add(a.wrapping_mul(b)) & wm
+                    }),
+                    0x3d => self.elementwise(inst, t, narrow, b, masked, |a, b, d, _| {
+                        d.wrapping_add((signed(a) * signed(b)) as u64) & wm
+                    }),
+                    0x3e if !vv => self.elementwise(inst, t, narrow, b, masked, |a, b, d, _| {
+                        d.wrapping_add((signed(a) * b as i128) as u64) & wm
+                    }),
+                    0x3f => self.elementwise(inst, t, narrow, b, masked, |a, b, d, _| {
+                        d.wrapping_add((a as i128 * signed(b)) as u64) & wm
+                    }),
+                    _ => Err(Exception::IllegalInstruction),
+                }
+            }
+            _ => {
+                println!(
+                    "Unsupported instruction: opcode {:x} funct3 {:x} funct6 {:x}",
+                    0x57, inst.funct3, inst.funct6
+                );
+                Err(Exception::IllegalInstruction)
+            }
+        }
+    }
+
+    /// Execute vmv.x.s, vcpop.m or vfirst.m, which write an integer register.
+    fn execute_vwxunary0(&mut self, inst: &Inst, t: VType) -> Result<(), Exception> {
+        let vl = self.csr[VL] as usize;
+        let value = match inst.vs1 {
+            0x00 if inst.vm => sext(self.velement(inst.vs2, 0, t.sew), t.sew) as u64,
+            0x10 | 0x11 => {
+                require(self.csr[VSTART] == 0)?;
+                let mut set =
+                    (0..vl).filter(|&i| self.active(!inst.vm, i) && self.vmask(inst.vs2, i));
+                if inst.vs1 == 0x10 {
+                    set.count() as u64
+                } else {
+                    set.next().map_or(u64::MAX, |i| i as u64)
+                }
+            }
+            _ => return Err(Exception::IllegalInstruction),
+        };
+        self.regs[inst.vd] = value;
+        Ok(())
+    }
+
+    /// Execute vmsbf.m, vmsof.m, vmsif.m, viota.m or vid.v.
+    fn execute_vmunary0(&mut self, inst: &Inst, t: VType) -> Result<(), Exception> {
+        let masked = !inst.vm;
+        require(!masked || inst.vd != 0)?;
+        let (start, vl) = self.body();
+        match inst.vs1 {
+            // vmsbf, vmsof, vmsif set the bits before, at, or up to the first set one.
+            0x01..=0x03 => {
+                require(start == 0 && inst.vd != inst.vs2)?;
+                let mut found = false;
+                for i in 0..vl {
+                    if !self.active(masked, i) {
+                        continue;
+                    }
+                    let first = !found && self.vmask(inst.vs2, i);
+                    let bit = match inst.vs1 {
+                        0x01 => !found && !first,
+                        0x02 => first,
+                        _ => !found,
+                    };
+                    found |= first;
+                    self.set_vmask(inst.vd, i, bit);
+                }
+            }
+            // viota
+            0x10 => {
+                require(start == 0)?;
+                let regs = self.check_group(t, inst.vd, t.sew)?;
+                require(inst.vs2 < inst.vd || inst.vs2 >= inst.vd + regs)?;
+                let mut count = 0;
+                for i in 0..vl {
+                    if !self.active(masked, i) {
+                        continue;
+                    }
+                    self.set_velement(inst.vd, i, t.sew, count);
+                    count += self.vmask(inst.vs2, i) as u64;
+                }
+            }
+            // vid
+            0x11 if inst.vs2 == 0 => {
+                self.check_group(t, inst.vd, t.sew)?;
+                for i in start..vl {
+                    if self.active(masked, i) {
+                        self.set_velement(inst.vd, i, t.sew, i as u64);
+                    }
+                }
+            }
+            _ => return Err(Exception::IllegalInstruction),
+        }
+        Ok(())
+    }
+
+    /// Execute vcompress.vm: pack the elements of vs2 whose bit is set in vs1 into vd.
+    fn compress(&mut self, inst: &Inst, t: VType) -> Result<(), Exception> {
+        let sew = t.sew;
+        require(self.csr[VSTART] == 0)?;
+        let regs = self.check_group(t, inst.vd, sew)?;
+        self.check_group(t, inst.vs2, sew)?;
+        Self::check_disjoint(t, inst.vd, sew, inst.vs2, sew)?;
+        require(inst.vs1 < inst.vd || inst.vs1 >= inst.vd + regs)?;
+        let vl = self.csr[VL] as usize;
+        let mut packed = 0;
+        for i in 0..vl {
+            if self.vmask(inst.vs1, i) {
+                let value = self.velement(inst.vs2, i, sew);
+                self.set_velement(inst.vd, packed, sew, value);
+                packed += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute vrgather or vrgatherei16: set each element of vd to the element of vs2 that
+    /// `index` selects, a vector of `index_eew` bits or a scalar, or to 0 if it's past VLMAX.
+    fn gather(
+        &mut self,
+        inst: &Inst,
+        t: VType,
+        index: Operand,
+        index_eew: usize,
+    ) -> Result<(), Exception> {
+        let sew = t.sew;
+        self.check_group(t, inst.vd, sew)?;
+        self.check_group(t, inst.vs2, sew)?;
+        Self::check_disjoint(t, inst.vd, sew, inst.vs2, sew)?;
+        if let Operand::Vector(vs1) = index {
+            self.check_group(t, vs1, index_eew)?;
+            Self::check_disjoint(t, inst.vd, sew, vs1, index_eew)?;
+        }
+        require(inst.vm || inst.vd != 0)?;
+        let vlmax = t.vlmax(self.isa.vlen()) as u64;
+        let (start, vl) = self.body();
+        for i in start..vl {
+            if !self.active(!inst.vm, i) {
+                continue;
+            }
+            let index = self.operand(index, i, index_eew);
+            let value = if index < vlmax {
+                self.velement(inst.vs2, index as usize, sew)
+            } else {
+                0
+            };
+            self.set_velement(inst.vd, i, sew, value);
+        }
+        Ok(())
+    }
+
+    /// Execute vslideup: move the elements of vs2 up by `offset` into vd.
+    fn slide_up(&mut self, inst: &Inst, t: VType, offset: u64) -> Result<(), Exception> {
+        let sew = t.sew;
+        self.check_group(t, inst.vd, sew)?;
+        self.check_group(t, inst.vs2, sew)?;
+        Self::check_disjoint(t, inst.vd, sew, inst.vs2, sew)?;
+        require(inst.vm || inst.vd != 0)?;
+        let (start, vl) = self.body();
+        let first = offset.max(start as u64).min(vl as u64) as usize;
+        for i in first..vl {
+            if self.active(!inst.vm, i) {
+                let value = self.velement(inst.vs2, i - offset as usize, sew);
+                self.set_velement(inst.vd, i, sew, value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute vslidedown: move the elements of vs2 down by `offset` into vd, with zeroes past
+    /// VLMAX.
+    fn slide_down(&mut self, inst: &Inst, t: VType, offset: u64) -> Result<(), Exception> {
+        let sew = t.sew;
+        self.check_group(t, inst.vd, sew)?;
+        self.check_group(t, inst.vs2, sew)?;
+        require(inst.vm || inst.vd != 0)?;
+        let vlmax = t.vlmax(self.isa.vlen()) as u64;
+        let (start, vl) = self.body();
+        for i in start..vl {
+            if !self.active(!inst.vm, i) {
+                continue;
+            }
+            let value = match offset.checked_add(i as u64) {
+                Some(index) if index < vlmax => self.velement(inst.vs2, index as usize, sew),
+                _ => 0,
+            };
+            self.set_velement(inst.vd, i, sew, value);
+        }
+        Ok(())
+    }
+
+    /// Execute vslide1up or vfslide1up: move the elements of vs2 up by one into vd, and put
+    /// `value` in element 0.
+    pub(super) fn slide1_up(&mut self, inst: &Inst, t: VType, value: u64) -> Result<(), Exception> {
+        let sew = t.sew;
+        self.check_group(t, inst.vd, sew)?;
+        self.check_group(t, inst.vs2, sew)?;
+        Self::check_disjoint(t, inst.vd, sew, inst.vs2, sew)?;
+        require(inst.vm || inst.vd != 0)?;
+        let (start, vl) = self.body();
+        for i in start..vl {
+            if !self.active(!inst.vm, i) {
+                continue;
+            }
+            let element = if i == 0 {
+                value
+            } else {
+                self.velement(inst.vs2, i - 1, sew)
+            };
+            self.set_velement(inst.vd, i, sew, element);
+        }
+        Ok(())
+    }
+
+    /// Execute vslide1down or vfslide1down: move the elements of vs2 down by one into vd, and
+    /// put `value` in element vl - 1.
+    pub(super) fn slide1_down(
+        &mut self,
+        inst: &Inst,
+        t: VType,
+        value: u64,
+    ) -> Result<(), Exception> {
+        let sew = t.sew;
+        self.check_group(t, inst.vd, sew)?;
+        self.check_group(t, inst.vs2, sew)?;
+        require(inst.vm || inst.vd != 0)?;
+        let (start, vl) = self.body();
+        for i in start..vl {
+            if !self.active(!inst.vm, i) {
+                continue;
+            }
+            let element = if i + 1 < vl {
+                self.velement(inst.vs2, i + 1, sew)
+            } else {
+                value
+            };
+            self.set_velement(inst.vd, i, sew, element);
+        }
+        Ok(())
+    }
+}
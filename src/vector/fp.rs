@@ -0,0 +1,320 @@
+//! Floating-point instructions: OPFVV and OPFVF. Elements of 32 bits need Zve32f and elements
+//! of 64 bits Zve64d; there's no half precision.
+
+use super::{mask, require, sext, Inst, Operand, VType};
+use crate::cpu::Cpu;
+use crate::exception::Exception;
+use crate::float::{self, Env, Float, Rounding};
+use crate::isa::{ZVE32F, ZVE64D};
+
+/// What the elements a conversion reads or writes are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Unsigned,
+    Signed,
+    Float,
+}
+
+/// Convert the bits of an element of format `F`.
+fn value<F: Float>(bits: u64) -> F {
+    F::from_bits(bits)
+}
+
+/// Convert `bits`, an element of `from_eew` bits, to an element of `to_eew` bits. `odd` rounds
+/// to odd, for vfncvt.rod.f.f.w.
+fn convert(env: &mut Env, bits: u64, from: (Kind, usize), to: (Kind, usize), odd: bool) -> u64 {
+    match (from, to) {
+        ((Kind::Float, 32), (Kind::Float, _)) => env.convert::<f32, f64>(value(bits)).to_bits(),
+        ((Kind::Float, _), (Kind::Float, _)) if odd => {
+            Float::to_bits(env.convert_odd::<f64, f32>(value(bits)))
+        }
+        ((Kind::Float, _), (Kind::Float, _)) => {
+            Float::to_bits(env.convert::<f64, f32>(value(bits)))
+        }
+        ((Kind::Float, width), (kind, eew)) => {
+            let (min, max) = match kind {
+                Kind::Signed => (-(1i128 << (eew - 1)), (1i128 << (eew - 1)) - 1),
+                _ => (0, mask(eew) as i128),
+            };
+            let integer = match width {
+                32 => env.to_int(value::<f32>(bits), min, max),
+                _ => env.to_int(value::<f64>(bits), min, max),
+            };
+            integer as u64 & mask(eew)
+        }
+        ((kind, eew), (_, width)) => {
+            let integer = match kind {
+                Kind::Signed => sext(bits, eew) as i128,
+                _ => bits as i128,
+            };
+            match width {
+                32 => Float::to_bits(env.from_int::<f32>(integer)),
+                _ => env.from_int::<f64>(integer).to_bits(),
+            }
+        }
+    }
+}
+
+impl Cpu {
+    /// Return true if the vector unit supports floating-point elements of `eew` bits.
+    fn vector_float(&self, eew: usize) -> bool {
+        match eew {
+            32 => self.isa.has(ZVE32F),
+            64 => self.isa.has(ZVE64D),
+            _ => false,
+        }
+    }
+
+    /// Execute an OPFVV or OPFVF instruction.
+    pub(super) fn execute_opf(&mut self, inst: &Inst, t: VType) -> Result<(), Exception> {
+        require(self.fp_enabled())?;
+        let rm = self.rounding(0x7)?;
+        // The conversions also work on integers narrower than the floats.
+        if inst.funct6 == 0x12 && inst.funct3 == 0x1 {
+            return self.execute_vfunary0(inst, t, rm);
+        }
+        require(self.vector_float(t.sew))?;
+        match t.sew {
+            32 => self.execute_opf_sew::<f32>(inst, t, rm),
+            _ => self.execute_opf_sew::<f64>(inst, t, rm),
+        }
+    }
+
+    /// Execute an OPFVV or OPFVF instruction on elements of format `F`.
+    fn execute_opf_sew<F: Float>(
+        &mut self,
+        inst: &Inst,
+        t: VType,
+        rm: Rounding,
+    ) -> Result<(), Exception> {
+        let sew = t.sew;
+        let (vv, vf) = (inst.funct3 == 0x1, inst.funct3 == 0x5);
+        let scalar = self.freg::<F>(inst.vs1).to_bits();
+        let b = if vf {
+            Operand::Scalar(scalar)
+        } else {
+            Operand::Vector(inst.vs1)
+        };
+        let same = [sew; 3];
+        let masked = !inst.vm;
+        let mut env = Env::new(rm);
+        let env = &mut env;
+        let f = value::<F>;
+        let result = match inst.funct6 {
+            // vfadd, vfsub, vfmin, vfmax
+            0x00 => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                env.add(f(a), f(b)).to_bits()
+            }),
+            0x02 => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                env.sub(f(a), f(b)).to_bits()
+            }),
+            0x04 => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                env.min(f(a), f(b)).to_bits()
+            }),
+            0x06 => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                env.max(f(a), f(b)).to_bits()
+            }),
+            // vfredusum and vfredosum both sum in order, vfredmin, vfredmax
+            0x01 | 0x03 if vv => {
+                self.reduce(inst, t, sew, sew, |acc, a| env.add(f(acc), f(a)).to_bits())
+            }
+            0x05 if vv => self.reduce(inst, t, sew, sew, |acc, a| env.min(f(acc), f(a)).to_bits()),
+            0x07 if vv => self.reduce(inst, t, sew, sew, |acc, a| env.max(f(acc), f(a)).to_bits()),
+            // vfsgnj, vfsgnjn, vfsgnjx
+            0x08..=0x0a => {
+                let funct = inst.funct6 - 0x08;
+                self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                    float::sign_inject(f(a), f(b), funct).to_bits()
+                })
+            }
+            // vfslide1up, vfslide1down
+            0x0e if vf => self.slide1_up(inst, t, scalar),
+            0x0f if vf => self.slide1_down(inst, t, scalar),
+            // vfmv.f.s
+            0x10 if vv && inst.vs1 == 0 && inst.vm => {
+                let element = self.velement(inst.vs2, 0, sew);
+                self.set_freg(inst.vd, f(element));
+                Ok(())
+            }
+            // vfmv.s.f
+            0x10 if vf && inst.vs2 == 0 && inst.vm => {
+                let (start, vl) = self.body();
+                if start < vl {
+                    self.set_velement(inst.vd, 0, sew, scalar);
+                }
+                Ok(())
+            }
+            0x13 if vv => match inst.vs1 {
+                // vfsqrt, vfrsqrt7, vfrec7, vfclass
+                0x00 => self.elementwise(inst, t, same, b, masked, |a, _, _, _| {
+                    env.sqrt(f(a)).to_bits()
+                }),
+                0x04 => self.elementwise(inst, t, same, b, masked, |a, _, _, _| {
+                    float::rsqrt7(env, f(a)).to_bits()
+                }),
+                0x05 => self.elementwise(inst, t, same, b, masked, |a, _, _, _| {
+                    float::rec7(env, f(a)).to_bits()
+                }),
+                0x10 => {
+                    self.elementwise(inst, t, same, b, masked, |a, _, _, _| float::classify(f(a)))
+                }
+                _ => Err(Exception::IllegalInstruction),
+            },
+            // vfmerge, and vfmv.v.f if it's unmasked.
+            0x17 if vf && masked => {
+                self.elementwise(
+                    inst,
+                    t,
+                    same,
+                    b,
+                    false,
+                    |a, b, _, select| {
+                        if select {
+                            b
+                        } else {
+                            a
+                        }
+                    },
+                )
+            }
+            0x17 if vf => {
+                require(inst.vs2 == 0)?;
+                self.elementwise(inst, t, same, b, false, |_, b, _, _| b)
+            }
+            // vmfeq, vmfle, vmflt, vmfne, vmfgt, vmfge
+            0x18 => self.compare(inst, t, sew, b, masked, |a, b, _| env.eq(f(a), f(b))),
+            0x19 => self.compare(inst, t, sew, b, masked, |a, b, _| env.le(f(a), f(b))),
+            0x1b => self.compare(inst, t, sew, b, masked, |a, b, _| env.lt(f(a), f(b))),
+            0x1c => self.compare(inst, t, sew, b, masked, |a, b, _| !env.eq(f(a), f(b))),
+            0x1d if vf => self.compare(inst, t, sew, b, masked, |a, b, _| env.lt(f(b), f(a))),
+            0x1f if vf => self.compare(inst, t, sew, b, masked, |a, b, _| env.le(f(b), f(a))),
+            // vfdiv, vfrdiv, vfmul, vfrsub
+            0x20 => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                env.div(f(a), f(b)).to_bits()
+            }),
+            0x21 if vf => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                env.div(f(b), f(a)).to_bits()
+            }),
+            0x24 => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                env.mul(f(a), f(b)).to_bits()
+            }),
+            0x27 if vf => self.elementwise(inst, t, same, b, masked, |a, b, _, _| {
+                env.sub(f(b), f(a)).to_bits()
+            }),
+            // vfmadd, vfnmadd, vfmsub, vfnmsub multiply vd, and vfmacc, vfnmacc, vfmsac, vfnmsac
+            // add to it.
+            0x28..=0x2f => {
+                let funct6 = inst.funct6;
+                self.elementwise(inst, t, same, b, masked, |a, b, d, _| {
+                    let (product, addend) = if funct6 < 0x2c { (d, a) } else { (a, d) };
+                    let (product, addend) = (f(product), f(addend));
+                    let negate_product = funct6 & 1 != 0;
+                    let negate_addend = funct6 & 0x3 == 0x1 || funct6 & 0x3 == 0x2;
+                    let multiplier = if negate_product { -f(b) } else { f(b) };
+                    let addend = if negate_addend { -addend } else { addend };
+                    env.fma(multiplier, product, addend).to_bits()
+                })
+            }
+            0x30..=0x3f if sew == 32 => {
+                require(self.vector_float(64))?;
+                self.execute_opf_wide(inst, t, b, env)
+            }
+            _ => {
+                println!(
+                    "Unsupported instruction: opcode {:x} funct3 {:x} funct6 {:x}",
+                    0x57, inst.funct3, inst.funct6
+                );
+                Err(Exception::IllegalInstruction)
+            }
+        };
+        self.accrue(env);
+        result
+    }
+
+    /// Execute a widening instruction, from single to double precision.
+    fn execute_opf_wide(
+        &mut self,
+        inst: &Inst,
+        t: VType,
+        b: Operand,
+        env: &mut Env,
+    ) -> Result<(), Exception> {
+        let vv = inst.funct3 == 0x1;
+        let masked = !inst.vm;
+        let narrow = [64, 32, 32];
+        let funct6 = inst.funct6;
+        // Widening a single-precision value to double precision is exact.
+        let widen = |env: &mut Env, bits: u64| env.convert::<f32, f64>(value(bits));
+        match funct6 {
+            // vfwadd, vfwsub, vfwmul
+            0x30 | 0x32 | 0x38 => self.elementwise(inst, t, narrow, b, masked, |a, b, _, _| {
+                let (a, b) = (widen(env, a), widen(env, b));
+                match funct6 {
+                    0x30 => env.add(a, b),
+                    0x32 => env.sub(a, b),
+                    _ => env.mul(a, b),
+                }
+                .to_bits()
+            }),
+            // vfwredusum, vfwredosum
+            0x31 | 0x33 if vv => self.reduce(inst, t, 32, 64, |acc, a| {
+                let a = widen(env, a);
+                env.add(value::<f64>(acc), a).to_bits()
+            }),
+            // vfwadd.w, vfwsub.w
+            0x34 | 0x36 => self.elementwise(inst, t, [64, 64, 32], b, masked, |a, b, _, _| {
+                let b = widen(env, b);
+                match funct6 {
+                    0x34 => env.add(value::<f64>(a), b),
+                    _ => env.sub(value::<f64>(a), b),
+                }
+                .to_bits()
+            }),
+            // vfwmacc, vfwnmacc, vfwmsac, vfwnmsac
+            0x3c..=0x3f => self.elementwise(inst, t, narrow, b, masked, |a, b, d, _| {
+                let (a, b) = (widen(env, a), widen(env, b));
+                let multiplier = if funct6 & 1 != 0 { -b } else { b };
+                let addend = match funct6 & 0x3 {
+                    0x1 | 0x2 => -value::<f64>(d),
+                    _ => value::<f64>(d),
+                };
+                env.fma(multiplier, a, addend).to_bits()
+            }),
+            _ => Err(Exception::IllegalInstruction),
+        }
+    }
+
+    /// Execute a conversion: vfcvt, vfwcvt or vfncvt.
+    fn execute_vfunary0(&mut self, inst: &Inst, t: VType, rm: Rounding) -> Result<(), Exception> {
+        let sew = t.sew;
+        // The group of the encoding is single-width, widening or narrowing, and the operation
+        // is the same in each.
+        let (group, op) = (inst.vs1 >> 3, inst.vs1 & 0x7);
+        let (from_eew, to_eew) = match group {
+            0 if op != 4 && op != 5 => (sew, sew),
+            1 if op != 5 => (sew, 2 * sew),
+            2 => (2 * sew, sew),
+            _ => return Err(Exception::IllegalInstruction),
+        };
+        require(from_eew.max(to_eew) <= self.isa.elen())?;
+        let (from, to) = match op {
+            0 | 6 => (Kind::Float, Kind::Unsigned),
+            1 | 7 => (Kind::Float, Kind::Signed),
+            2 => (Kind::Unsigned, Kind::Float),
+            3 => (Kind::Signed, Kind::Float),
+            _ => (Kind::Float, Kind::Float),
+        };
+        require(from != Kind::Float || self.vector_float(from_eew))?;
+        require(to != Kind::Float || self.vector_float(to_eew))?;
+        // The rtz variants round toward zero whatever frm is.
+        let mut env = Env::new(if op >= 6 { Rounding::TowardZero } else { rm });
+        let (from, to) = ((from, from_eew), (to, to_eew));
+        let odd = op == 5;
+        let eew = [to_eew, from_eew, sew];
+        let result = self.elementwise(inst, t, eew, Operand::Scalar(0), !inst.vm, |a, _, _, _| {
+            convert(&mut env, a, from, to, odd)
+        });
+        self.accrue(&env);
+        result
+    }
+}
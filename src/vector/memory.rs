@@ -0,0 +1,197 @@
+//! Vector loads and stores: unit-stride, strided and indexed, with segments of up to eight
+//! fields, and the whole-register, mask and fault-only-first variants.
+
+use super::{registers, require, Inst, VType};
+use crate::cpu::Cpu;
+use crate::csr::{VL, VSTART};
+use crate::exception::Exception;
+
+/// How a load or store finds the address of each element.
+#[derive(Debug, Clone, Copy)]
+enum Addressing {
+    /// Consecutive elements, and fault-only-first if `first` is set.
+    Unit { first: bool },
+    /// Elements `stride` bytes apart.
+    Strided { stride: u64 },
+    /// Elements at the offsets in vs2, which have `eew` bits.
+    Indexed { eew: usize },
+}
+
+impl Cpu {
+    /// Execute a vector load (LOAD-FP) or store (STORE-FP).
+    pub(super) fn execute_vector_memory(&mut self, inst: u32) -> Result<(), Exception> {
+        let opcode = inst & 0x0000007f;
+        let funct3 = (inst & 0x00007000) >> 12;
+        let rs2 = ((inst & 0x01f00000) >> 20) as usize;
+        let fields = Inst {
+            funct6: inst >> 26,
+            funct3,
+            vm: (inst >> 25) & 1 != 0,
+            vd: ((inst & 0x00000f80) >> 7) as usize,
+            vs1: ((inst & 0x000f8000) >> 15) as usize,
+            vs2: rs2,
+        };
+        let store = opcode == 0x27;
+        let nf = (inst >> 29) as usize + 1;
+        let mew = (inst >> 28) & 1;
+        let mop = (inst >> 26) & 0x3;
+        let eew = match funct3 {
+            0x0 => 8,
+            0x5 => 16,
+            0x6 => 32,
+            _ => 64,
+        };
+        if mew != 0 || eew > self.isa.elen() {
+            return Err(Exception::IllegalInstruction);
+        }
+        let addressing = match (mop, rs2) {
+            // Whole registers and masks ignore vtype.
+            (0x0, 0x08) => return self.execute_whole_register(&fields, store, nf, eew),
+            (0x0, 0x0b) => return self.execute_mask_memory(&fields, store, nf, eew),
+            (0x0, 0x00) => Addressing::Unit { first: false },
+            (0x0, 0x10) if !store => Addressing::Unit { first: true },
+            (0x2, _) => Addressing::Strided {
+                stride: self.regs[rs2],
+            },
+            (0x1 | 0x3, _) => Addressing::Indexed { eew },
+            _ => {
+                println!(
+                    "Unsupported instruction: opcode {:x} mop {:x} lumop {:x}",
+                    opcode, mop, rs2
+                );
+                return Err(Exception::IllegalInstruction);
+            }
+        };
+        let t = self.vtype()?;
+        self.execute_strided(&fields, t, store, nf, addressing, eew)
+    }
+
+    /// Execute a unit-stride, strided or indexed load or store of `nf` fields. The elements are
+    /// of `eew` bits, or of SEW for indexed ones.
+    fn execute_strided(
+        &mut self,
+        inst: &Inst,
+        t: VType,
+        store: bool,
+        nf: usize,
+        addressing: Addressing,
+        eew: usize,
+    ) -> Result<(), Exception> {
+        let data_eew = match addressing {
+            Addressing::Indexed { .. } => t.sew,
+            _ => eew,
+        };
+        let regs = registers(t.emul8(data_eew)?);
+        require(inst.vd.is_multiple_of(regs) && nf * regs <= 8 && inst.vd + nf * regs <= 32)?;
+        if let Addressing::Indexed { eew } = addressing {
+            self.check_group(t, inst.vs2, eew)?;
+            if !store {
+                for field in 0..nf {
+                    Self::check_overlap(t, inst.vd + field * regs, data_eew, inst.vs2, eew)?;
+                }
+            }
+        }
+        require(store || inst.vm || inst.vd != 0)?;
+        let base = self.regs[inst.vs1];
+        let bytes = (data_eew / 8) as u64;
+        let (start, vl) = self.body();
+        for i in start..vl {
+            if !self.active(!inst.vm, i) {
+                continue;
+            }
+            let address = match addressing {
+                Addressing::Unit { .. } => base.wrapping_add(i as u64 * nf as u64 * bytes),
+                Addressing::Strided { stride } => {
+                    base.wrapping_add((i as u64).wrapping_mul(stride))
+                }
+                Addressing::Indexed { eew } => base.wrapping_add(self.velement(inst.vs2, i, eew)),
+            };
+            for field in 0..nf {
+                let address = address.wrapping_add(field as u64 * bytes);
+                let reg = inst.vd + field * regs;
+                let result = if store {
+                    let value = self.velement(reg, i, data_eew);
+                    self.store(address, data_eew, value)
+                } else {
+                    self.load(address, data_eew)
+                        .map(|value| self.set_velement(reg, i, data_eew, value))
+                };
+                if let Err(exception) = result {
+                    // Fault-only-first loads trim vl instead of trapping past element 0.
+                    if let Addressing::Unit { first: true } = addressing {
+                        if i > 0 {
+                            self.csr[VL] = i as u64;
+                            if let Some(commit) = &mut self.commit {
+                                commit.write_csr(VL, i as u64);
+                            }
+                            return Ok(());
+                        }
+                    }
+                    self.csr[VSTART] = i as u64;
+                    return Err(exception);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute vl<nf>re<eew>.v or vs<nf>r.v, which load or store `nf` whole registers.
+    fn execute_whole_register(
+        &mut self,
+        inst: &Inst,
+        store: bool,
+        nf: usize,
+        eew: usize,
+    ) -> Result<(), Exception> {
+        require(inst.vm && nf.is_power_of_two() && inst.vd.is_multiple_of(nf))?;
+        require(!store || eew == 8)?;
+        let base = self.regs[inst.vs1];
+        let bytes = eew / 8;
+        let elements = nf * self.vlenb() / bytes;
+        let (start, _) = self.body();
+        for i in start..elements {
+            let address = base.wrapping_add((i * bytes) as u64);
+            let result = if store {
+                let value = self.velement(inst.vd, i, eew);
+                self.store(address, eew, value)
+            } else {
+                self.load(address, eew)
+                    .map(|value| self.set_velement(inst.vd, i, eew, value))
+            };
+            if let Err(exception) = result {
+                self.csr[VSTART] = i as u64;
+                return Err(exception);
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute vlm.v or vsm.v, which load or store the ceil(vl / 8) bytes of a mask.
+    fn execute_mask_memory(
+        &mut self,
+        inst: &Inst,
+        store: bool,
+        nf: usize,
+        eew: usize,
+    ) -> Result<(), Exception> {
+        require(inst.vm && nf == 1 && eew == 8)?;
+        self.vtype()?;
+        let base = self.regs[inst.vs1];
+        let (start, vl) = self.body();
+        for i in start..vl.div_ceil(8) {
+            let address = base.wrapping_add(i as u64);
+            let result = if store {
+                let value = self.velement(inst.vd, i, 8);
+                self.store(address, 8, value)
+            } else {
+                self.load(address, 8)
+                    .map(|value| self.set_velement(inst.vd, i, 8, value))
+            };
+            if let Err(exception) = result {
+                self.csr[VSTART] = i as u64;
+                return Err(exception);
+            }
+        }
+        Ok(())
+    }
+}
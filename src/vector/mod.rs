@@ -0,0 +1,543 @@
+//! The vector module executes the instructions of the vector extension, RVV 1.0, and of its
+//! subsets for embedded processors, Zve*: configuration, loads and stores, and integer,
+//! fixed-point, floating-point, mask, reduction and permutation instructions.
+//!
+//! The register file has 32 registers of VLEN bits, which the ISA string configures together
+//! with ELEN, the widest element. Elements that are masked off or past vl are always left
+//! undisturbed, which both the undisturbed and the agnostic policies allow. An instruction that
+//! traps part way, e.g. on a page fault, leaves the index of the element in vstart, so that it
+//! resumes there.
+
+mod arith;
+mod fp;
+mod memory;
+
+use crate::cpu::{Cpu, MSTATUS_VS};
+use crate::csr::{VL, VSTART, VTYPE};
+use crate::exception::Exception;
+
+/// The vtype of an unsupported configuration: only vill is set.
+pub const VTYPE_VILL: u64 = 1 << 63;
+
+/// A supported vector configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VType {
+    /// The selected element width in bits.
+    pub sew: usize,
+    /// The register group multiplier in eighths, from 1 for LMUL=1/8 to 64 for LMUL=8.
+    pub lmul8: usize,
+    /// Tail agnostic.
+    pub ta: bool,
+    /// Mask agnostic.
+    pub ma: bool,
+}
+
+impl VType {
+    /// Decode `vtype`, or return `None` if it's not supported with ELEN `elen`.
+    pub fn decode(vtype: u64, elen: usize) -> Option<VType> {
+        // Reserved bits and vill.
+        if vtype >> 8 != 0 {
+            return None;
+        }
+        let vsew = (vtype >> 3) & 0x7;
+        if vsew > 3 {
+            return None;
+        }
+        let sew = 8 << vsew;
+        let lmul8 = match vtype & 0x7 {
+            0 => 8,
+            1 => 16,
+            2 => 32,
+            3 => 64,
+            5 => 1,
+            6 => 2,
+            7 => 4,
+            _ => return None,
+        };
+        // A fractional LMUL must leave room for an element of ELEN × LMUL.
+        if sew * 8 > elen * lmul8 {
+            return None;
+        }
+        Some(VType {
+            sew,
+            lmul8,
+            ta: vtype & (1 << 6) != 0,
+            ma: vtype & (1 << 7) != 0,
+        })
+    }
+
+    /// Return VLMAX, the number of elements in a register group, for VLEN `vlen`.
+    pub fn vlmax(self, vlen: usize) -> usize {
+        vlen * self.lmul8 / 8 / self.sew
+    }
+
+    /// Return the EMUL in eighths of a register group of elements of `eew` bits, or an illegal
+    /// instruction if it's out of range.
+    fn emul8(self, eew: usize) -> Result<usize, Exception> {
+        let emul8 = eew * self.lmul8 / self.sew;
+        if (1..=64).contains(&emul8) && (eew * self.lmul8).is_multiple_of(self.sew) {
+            Ok(emul8)
+        } else {
+            Err(Exception::IllegalInstruction)
+        }
+    }
+}
+
+/// Return the number of registers of a register group with EMUL `emul8` eighths.
+fn registers(emul8: usize) -> usize {
+    (emul8 / 8).max(1)
+}
+
+/// Return an illegal instruction unless `condition` holds.
+fn require(condition: bool) -> Result<(), Exception> {
+    if condition {
+        Ok(())
+    } else {
+        Err(Exception::IllegalInstruction)
+    }
+}
+
+/// Return the mask of the low `eew` bits.
+fn mask(eew: usize) -> u64 {
+    u64::MAX >> (64 - eew)
+}
+
+/// Return the low `eew` bits of `value`, sign-extended.
+fn sext(value: u64, eew: usize) -> i64 {
+    ((value << (64 - eew)) as i64) >> (64 - eew)
+}
+
+/// The fields of an OP-V instruction.
+#[derive(Debug, Clone, Copy)]
+struct Inst {
+    funct6: u32,
+    funct3: u32,
+    /// The instruction is unmasked.
+    vm: bool,
+    /// vd or rd.
+    vd: usize,
+    /// vs1, rs1 or the immediate.
+    vs1: usize,
+    vs2: usize,
+}
+
+/// The second source operand of an instruction: vs1, or a scalar or an immediate that every
+/// element uses.
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    Vector(usize),
+    Scalar(u64),
+}
+
+impl Cpu {
+    fn vlenb(&self) -> usize {
+        self.isa.vlen() / 8
+    }
+
+    /// Return the element `index` of `eew` bits of the register group at `reg`.
+    pub(crate) fn velement(&self, reg: usize, index: usize, eew: usize) -> u64 {
+        let bytes = eew / 8;
+        let start = reg * self.vlenb() + index * bytes;
+        let mut value = [0; 8];
+        value[..bytes].copy_from_slice(&self.vregs[start..start + bytes]);
+        u64::from_le_bytes(value)
+    }
+
+    /// Set the element `index` of `eew` bits of the register group at `reg` to the low bits of
+    /// `value`.
+    pub(crate) fn set_velement(&mut self, reg: usize, index: usize, eew: usize, value: u64) {
+        let bytes = eew / 8;
+        let start = reg * self.vlenb() + index * bytes;
+        self.vregs[start..start + bytes].copy_from_slice(&value.to_le_bytes()[..bytes]);
+    }
+
+    /// Return the bit `index` of the mask register `reg`.
+    pub(crate) fn vmask(&self, reg: usize, index: usize) -> bool {
+        (self.vregs[reg * self.vlenb() + index / 8] >> (index % 8)) & 1 != 0
+    }
+
+    /// Set the bit `index` of the mask register `reg`.
+    pub(crate) fn set_vmask(&mut self, reg: usize, index: usize, bit: bool) {
+        let byte = &mut self.vregs[reg * self.vlenb() + index / 8];
+        *byte = (*byte & !(1 << (index % 8))) | ((bit as u8) << (index % 8));
+    }
+
+    /// Return true if element `index` is active: if the instruction isn't `masked` or the
+    /// bit of the element is set in v0.
+    fn active(&self, masked: bool, index: usize) -> bool {
+        !masked || self.vmask(0, index)
+    }
+
+    /// Return the current vector configuration, or an illegal instruction if vill is set.
+    fn vtype(&self) -> Result<VType, Exception> {
+        VType::decode(self.csr[VTYPE], self.isa.elen()).ok_or(Exception::IllegalInstruction)
+    }
+
+    /// Return vstart and vl, the range of elements an instruction works on.
+    fn body(&self) -> (usize, usize) {
+        (self.csr[VSTART] as usize, self.csr[VL] as usize)
+    }
+
+    /// Return the value of `operand` for element `index` of `eew` bits.
+    fn operand(&self, operand: Operand, index: usize, eew: usize) -> u64 {
+        match operand {
+            Operand::Vector(reg) => self.velement(reg, index, eew),
+            Operand::Scalar(value) => value,
+        }
+    }
+
+    /// Check that the register group at `reg` of elements of `eew` bits is aligned to its
+    /// size, and return the size.
+    fn check_group(&self, t: VType, reg: usize, eew: usize) -> Result<usize, Exception> {
+        let regs = registers(t.emul8(eew)?);
+        require(reg.is_multiple_of(regs))?;
+        Ok(regs)
+    }
+
+    /// Check that the destination group at `vd` of `vd_eew` bits may overlap the source group at
+    /// `vs` of `vs_eew` bits: if they have the same width, if the destination is narrower and
+    /// overlaps the lowest-numbered part of the source, or if it's wider, the source has at
+    /// least one register, and it overlaps the highest-numbered part of the destination.
+    fn check_overlap(
+        t: VType,
+        vd: usize,
+        vd_eew: usize,
+        vs: usize,
+        vs_eew: usize,
+    ) -> Result<(), Exception> {
+        let (vd_emul8, vs_emul8) = (t.emul8(vd_eew)?, t.emul8(vs_eew)?);
+        let (vd_regs, vs_regs) = (registers(vd_emul8), registers(vs_emul8));
+        if vd + vd_regs <= vs || vs + vs_regs <= vd || vd_eew == vs_eew {
+            return Ok(());
+        }
+        if vd_eew < vs_eew {
+            require(vd == vs)
+        } else {
+            require(vs_emul8 >= 8 && vd + vd_regs == vs + vs_regs)
+        }
+    }
+
+    /// Check that a mask destination `vd` may overlap the source group at `vs` of `vs_eew`
+    /// bits: only if it's the lowest-numbered register.
+    fn check_mask_overlap(t: VType, vd: usize, vs: usize, vs_eew: usize) -> Result<(), Exception> {
+        let vs_regs = registers(t.emul8(vs_eew)?);
+        require(vd <= vs || vs + vs_regs <= vd)
+    }
+
+    /// Check that the register groups at `a` of `a_eew` bits and at `b` of `b_eew` bits don't
+    /// overlap at all.
+    fn check_disjoint(
+        t: VType,
+        a: usize,
+        a_eew: usize,
+        b: usize,
+        b_eew: usize,
+    ) -> Result<(), Exception> {
+        let a_regs = registers(t.emul8(a_eew)?);
+        let b_regs = registers(t.emul8(b_eew)?);
+        require(a + a_regs <= b || b + b_regs <= a)
+    }
+
+    /// Execute an instruction element by element. `eew` are the widths of vd, vs2 and vs1 in
+    /// bits. `f` computes an element from the ones of vs2, `b`, and vd, and the bit of v0 that
+    /// instructions like vadc take as an operand. The instruction is `masked` by v0 unless it
+    /// takes v0 as an operand.
+    fn elementwise(
+        &mut self,
+        inst: &Inst,
+        t: VType,
+        eew: [usize; 3],
+        b: Operand,
+        masked: bool,
+        mut f: impl FnMut(u64, u64, u64, bool) -> u64,
+    ) -> Result<(), Exception> {
+        let [vd_eew, vs2_eew, vs1_eew] = eew;
+        self.check_group(t, inst.vd, vd_eew)?;
+        self.check_group(t, inst.vs2, vs2_eew)?;
+        Self::check_overlap(t, inst.vd, vd_eew, inst.vs2, vs2_eew)?;
+        if let Operand::Vector(vs1) = b {
+            self.check_group(t, vs1, vs1_eew)?;
+            Self::check_overlap(t, inst.vd, vd_eew, vs1, vs1_eew)?;
+        }
+        // Only a mask or a scalar may be written over the mask.
+        require(!masked && inst.vm || inst.vd != 0)?;
+        let (start, vl) = self.body();
+        for i in start..vl {
+            if !self.active(masked, i) {
+                continue;
+            }
+            let a = self.velement(inst.vs2, i, vs2_eew);
+            let b = self.operand(b, i, vs1_eew);
+            let d = self.velement(inst.vd, i, vd_eew);
+            let carry = self.vmask(0, i);
+            let value = f(a, b, d, carry);
+            self.set_velement(inst.vd, i, vd_eew, value);
+        }
+        Ok(())
+    }
+
+    /// Execute an instruction that writes a mask, like the comparisons, element by element.
+    /// `eew` is the width of vs2 and vs1 in bits. `f` computes the bit of an element from the
+    /// ones of vs2 and `b`, and the bit of v0.
+    fn compare(
+        &mut self,
+        inst: &Inst,
+        t: VType,
+        eew: usize,
+        b: Operand,
+        masked: bool,
+        mut f: impl FnMut(u64, u64, bool) -> bool,
+    ) -> Result<(), Exception> {
+        self.check_group(t, inst.vs2, eew)?;
+        Self::check_mask_overlap(t, inst.vd, inst.vs2, eew)?;
+        if let Operand::Vector(vs1) = b {
+            self.check_group(t, vs1, eew)?;
+            Self::check_mask_overlap(t, inst.vd, vs1, eew)?;
+        }
+        let (start, vl) = self.body();
+        for i in start..vl {
+            if !self.active(masked, i) {
+                continue;
+            }
+            let a = self.velement(inst.vs2, i, eew);
+            let b = self.operand(b, i, eew);
+            let bit = f(a, b, self.vmask(0, i));
+            self.set_vmask(inst.vd, i, bit);
+        }
+        Ok(())
+    }
+
+    /// Execute a reduction: fold the active elements of vs2, of `eew` bits, into element 0 of
+    /// vs1, of `acc_eew` bits, with `f`, and write the result to element 0 of vd.
+    fn reduce(
+        &mut self,
+        inst: &Inst,
+        t: VType,
+        eew: usize,
+        acc_eew: usize,
+        mut f: impl FnMut(u64, u64) -> u64,
+    ) -> Result<(), Exception> {
+        require(self.csr[VSTART] == 0)?;
+        require(acc_eew <= self.isa.elen())?;
+        self.check_group(t, inst.vs2, eew)?;
+        let vl = self.csr[VL] as usize;
+        if vl == 0 {
+            return Ok(());
+        }
+        let mut acc = self.velement(inst.vs1, 0, acc_eew);
+        for i in 0..vl {
+            if self.active(!inst.vm, i) {
+                acc = f(acc, self.velement(inst.vs2, i, eew));
+            }
+        }
+        self.set_velement(inst.vd, 0, acc_eew, acc);
+        Ok(())
+    }
+
+    /// Execute a vector instruction, if the hart has a vector unit and it's on.
+    pub(crate) fn execute_vector(&mut self, inst: u32) -> Result<(), Exception> {
+        let opcode = inst & 0x0000007f;
+        let funct3 = (inst & 0x00007000) >> 12;
+        if !self.vector_enabled() {
+            return Err(Exception::IllegalInstruction);
+        }
+        // The state may change even if the instruction traps part way.
+        self.set_dirty(MSTATUS_VS);
+        let fields = Inst {
+            funct6: inst >> 26,
+            funct3,
+            vm: (inst >> 25) & 1 != 0,
+            vd: ((inst & 0x00000f80) >> 7) as usize,
+            vs1: ((inst & 0x000f8000) >> 15) as usize,
+            vs2: ((inst & 0x01f00000) >> 20) as usize,
+        };
+        let result = match (opcode, funct3) {
+            (0x57, 0x7) => self.execute_vsetvl(inst),
+            // vmv<nr>r.v copies whole registers whatever vtype is.
+            (0x57, 0x3) if fields.funct6 == 0x27 => self.execute_vmvr(&fields),
+            (0x57, 0x0 | 0x3 | 0x4) => {
+                let t = self.vtype()?;
+                self.execute_opi(&fields, t)
+            }
+            (0x57, 0x2 | 0x6) => {
+                let t = self.vtype()?;
+                self.execute_opm(&fields, t)
+            }
+            (0x57, _) => {
+                let t = self.vtype()?;
+                self.execute_opf(&fields, t)
+            }
+            _ => self.execute_vector_memory(inst),
+        };
+        result?;
+        self.csr[VSTART] = 0;
+        Ok(())
+    }
+
+    /// Execute vsetvli, vsetivli or vsetvl: set vtype, and vl from the application vector
+    /// length and VLMAX.
+    fn execute_vsetvl(&mut self, inst: u32) -> Result<(), Exception> {
+        let rd = ((inst & 0x00000f80) >> 7) as usize;
+        let rs1 = ((inst & 0x000f8000) >> 15) as usize;
+        let rs2 = ((inst & 0x01f00000) >> 20) as usize;
+        let (vtype, immediate) = match inst >> 30 {
+            // vsetvli
+            0b00 | 0b01 => ((inst >> 20) as u64 & 0x7ff, None),
+            // vsetivli, whose AVL is an immediate in rs1.
+            0b11 => ((inst >> 20) as u64 & 0x3ff, Some(rs1 as u64)),
+            // vsetvl
+            _ if inst >> 25 == 0x40 => (self.regs[rs2], None),
+            _ => return Err(Exception::IllegalInstruction),
+        };
+        let (vtype, vl) = match VType::decode(vtype, self.isa.elen()) {
+            Some(t) => {
+                let vlmax = t.vlmax(self.isa.vlen()) as u64;
+                let avl = match immediate {
+                    Some(avl) => avl,
+                    None if rs1 != 0 => self.regs[rs1],
+                    None if rd != 0 => u64::MAX,
+                    // Keep vl, as long as VLMAX allows.
+                    None => self.csr[VL],
+                };
+                (vtype, avl.min(vlmax))
+            }
+            None => (VTYPE_VILL, 0),
+        };
+        self.csr[VL] = vl;
+        self.csr[VTYPE] = vtype;
+        self.regs[rd] = vl;
+        if let Some(commit) = &mut self.commit {
+            commit.write_csr(VL, vl);
+            commit.write_csr(VTYPE, vtype);
+        }
+        Ok(())
+    }
+
+    /// Execute vmv<nr>r.v, which copies `nr` whole registers.
+    fn execute_vmvr(&mut self, inst: &Inst) -> Result<(), Exception> {
+        let nr = inst.vs1 + 1;
+        require(inst.vm && nr.is_power_of_two() && nr <= 8)?;
+        require(inst.vd.is_multiple_of(nr) && inst.vs2.is_multiple_of(nr))?;
+        // vstart counts elements of SEW bits, which are bytes if vtype is illegal.
+        let eew = self.vtype().map_or(8, |t| t.sew);
+        let elements = nr * self.vlenb() * 8 / eew;
+        let (start, _) = self.body();
+        for i in start..elements {
+            let value = self.velement(inst.vs2, i, eew);
+            self.set_velement(inst.vd, i, eew, value);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csr::MSTATUS;
+    use crate::machine::Machine;
+
+    /// vtype for SEW `vsew` and LMUL `vlmul`, as encoded.
+    fn vtype(vsew: u32, vlmul: u32) -> u32 {
+        vsew << 3 | vlmul
+    }
+
+    fn vsetvli(rd: usize, rs1: usize, vtype: u32) -> u32 {
+        vtype << 20 | (rs1 as u32) << 15 | 0x7 << 12 | (rd as u32) << 7 | 0x57
+    }
+
+    fn vsetivli(rd: usize, avl: u32, vtype: u32) -> u32 {
+        0b11 << 30 | vtype << 20 | avl << 15 | 0x7 << 12 | (rd as u32) << 7 | 0x57
+    }
+
+    fn vsetvl(rd: usize, rs1: usize, rs2: usize) -> u32 {
+        0x40 << 25 | (rs2 as u32) << 20 | (rs1 as u32) << 15 | 0x7 << 12 | (rd as u32) << 7 | 0x57
+    }
+
+    /// Return a machine whose hart has VLEN 128 and ELEN 64, with its vector unit on.
+    fn machine() -> Machine {
+        let mut machine = Machine::builder().build().unwrap();
+        machine.cpu_mut().csr[MSTATUS] |= MSTATUS_VS;
+        assert_eq!(machine.cpu().isa.vlen(), 128);
+        machine
+    }
+
+    /// Execute `inst` with AVL `avl` in a1, and return vl, vtype and rd, which is a0.
+    fn set(machine: &mut Machine, inst: u32, avl: u64) -> (u64, u64, u64) {
+        let cpu = machine.cpu_mut();
+        cpu.regs[11] = avl;
+        cpu.decode_execute(inst).unwrap();
+        (cpu.csr[VL], cpu.csr[VTYPE], cpu.regs[10])
+    }
+
+    #[test]
+    fn vl_is_avl_up_to_vlmax() {
+        let mut machine = machine();
+        let m = &mut machine;
+        // e8, m1: VLMAX is 16.
+        assert_eq!(set(m, vsetvli(10, 11, vtype(0, 0)), 5), (5, 0, 5));
+        assert_eq!(set(m, vsetvli(10, 11, vtype(0, 0)), 17), (16, 0, 16));
+        assert_eq!(set(m, vsetvli(10, 11, vtype(0, 0)), u64::MAX), (16, 0, 16));
+        assert_eq!(set(m, vsetvli(10, 11, vtype(0, 0)), 0), (0, 0, 0));
+        // e32, m8: VLMAX is 32.
+        let t = vtype(2, 3) as u64;
+        assert_eq!(set(m, vsetvli(10, 11, t as u32), 100), (32, t, 32));
+        // e8, mf8: VLMAX is 2.
+        let t = vtype(0, 5) as u64;
+        assert_eq!(set(m, vsetvli(10, 11, t as u32), 100), (2, t, 2));
+        // The policy bits are kept.
+        let t = 0xc0 | vtype(3, 1) as u64;
+        assert_eq!(set(m, vsetvli(10, 11, t as u32), 3), (3, t, 3));
+        // vsetivli takes AVL from its immediate.
+        assert_eq!(set(m, vsetivli(10, 31, vtype(1, 0)), 0), (8, 8, 8));
+        assert_eq!(set(m, vsetivli(10, 3, vtype(1, 0)), 0), (3, 8, 3));
+        // vsetvl takes vtype from rs2.
+        machine.cpu_mut().regs[12] = vtype(3, 0) as u64;
+        assert_eq!(set(&mut machine, vsetvl(10, 11, 12), 9), (2, 24, 2));
+    }
+
+    #[test]
+    fn rs1_x0_asks_for_vlmax_or_keeps_vl() {
+        let mut machine = machine();
+        let m = &mut machine;
+        // With rd set, AVL is infinite.
+        assert_eq!(set(m, vsetvli(10, 0, vtype(1, 1)), 0), (16, 9, 16));
+        assert_eq!(set(m, vsetvli(10, 11, vtype(0, 0)), 5), (5, 0, 5));
+        // With rd x0 too, vl is kept and only vtype changes.
+        m.cpu_mut().regs[10] = 0x1234;
+        assert_eq!(set(m, vsetvli(0, 0, vtype(1, 0)), 0), (5, 8, 0x1234));
+    }
+
+    #[test]
+    fn unsupported_vtype_sets_vill() {
+        let mut machine = machine();
+        let unsupported = [
+            // SEW 128.
+            vtype(4, 0),
+            // The reserved LMUL.
+            vtype(0, 4),
+            // e64 with mf8 leaves no room for an element.
+            vtype(3, 5),
+            // e16 with mf8, e32 with mf4.
+            vtype(1, 5),
+            vtype(2, 6),
+            // A reserved bit.
+            1 << 8 | vtype(0, 0),
+        ];
+        for t in unsupported {
+            set(&mut machine, vsetvli(10, 11, vtype(0, 0)), 5);
+            assert_eq!(
+                set(&mut machine, vsetvli(10, 11, t), 5),
+                (0, VTYPE_VILL, 0),
+                "vtype {:#x}",
+                t
+            );
+        }
+        // vill itself, given to vsetvl.
+        machine.cpu_mut().regs[12] = VTYPE_VILL | vtype(0, 0) as u64;
+        assert_eq!(set(&mut machine, vsetvl(10, 11, 12), 5), (0, VTYPE_VILL, 0));
+        assert!(VType::decode(VTYPE_VILL, 64).is_none());
+        // e32 with mf2 needs ELEN 64.
+        assert!(VType::decode(vtype(2, 7) as u64, 64).is_some());
+        assert!(VType::decode(vtype(2, 7) as u64, 32).is_none());
+    }
+}